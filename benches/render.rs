@@ -0,0 +1,41 @@
+//! Throughput of the RGB generation on images of several sizes up to 10000 x 10000, with the
+//! exponentials interpolated in lookup tables and computed exactly, and with the dithered
+//! quantizations. Run with `cargo bench --bench render`.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ndarray::Array2;
+use std::hint::black_box;
+use virtualhe::{ColorEncoding, Dither, Stain, DEFAULT_BETA};
+
+const SIZES: [usize; 3] = [1_000, 4_000, 10_000];
+
+fn render(c: &mut Criterion) {
+    let stains = DEFAULT_BETA.map(|beta| Stain { beta, k: 2.5 });
+    let mut group = c.benchmark_group("render");
+    group.sample_size(10);
+    for size in SIZES {
+        // Gradients that cover the whole range of scaled intensities
+        let nucleus = Array2::from_shape_fn((size, size), |(y, x)| ((x * 7 + y * 13) % 1000) as f32 / 999.0);
+        let eosin = Array2::from_shape_fn((size, size), |(y, x)| ((x * 3 + y * 11) % 997) as f32 / 996.0);
+        let channels = [nucleus.view(), eosin.view()];
+        group.throughput(Throughput::Elements((size * size) as u64));
+        for encoding in [ColorEncoding::Srgb, ColorEncoding::Linear] {
+            for exact in [false, true] {
+                let mode = if exact { "exact" } else { "tables" };
+                let id = BenchmarkId::new(format!("{}/{}", encoding.name(), mode), size);
+                group.bench_function(id, |b| {
+                    b.iter(|| virtualhe::render_views_as::<u8>(black_box(&channels), &stains, encoding, exact, Dither::None).unwrap())
+                });
+            }
+        }
+        for dither in [Dither::Ordered, Dither::FloydSteinberg] {
+            let id = BenchmarkId::new(format!("srgb/tables/{}", dither.name()), size);
+            group.bench_function(id, |b| {
+                b.iter(|| virtualhe::render_views_as::<u8>(black_box(&channels), &stains, ColorEncoding::Srgb, false, dither).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, render);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+//! Throughput of the percentile scaling of `scale_with` on channels of several sizes up to
+//! 10000 x 10000, with and without a floor percentile. Run with `cargo bench --bench scale`.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use ndarray::Array2;
+use virtualhe::ScaleOptions;
+
+const SIZES: [usize; 3] = [1_000, 4_000, 10_000];
+
+fn scale(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scale");
+    group.sample_size(10);
+    for size in SIZES {
+        // Normalized 16bit values in a pattern that does not repeat within a row
+        let channel = Array2::from_shape_fn((size, size), |(y, x)| ((x * 7919 + y * 104_729) % 65_536) as f32 / 65_535.0);
+        group.throughput(Throughput::Elements((size * size) as u64));
+        for floor_percentile in [None, Some(1.0)] {
+            let options = ScaleOptions {
+                floor_percentile,
+                ..ScaleOptions::default()
+            };
+            let id = BenchmarkId::new(if floor_percentile.is_some() { "floor" } else { "saturation" }, size);
+            // The channel is scaled in place, every iteration scales a fresh copy
+            group.bench_function(id, |b| {
+                b.iter_batched_ref(
+                    || channel.clone(),
+                    |channel| virtualhe::scale_with(channel, &options).expect("the channel has a range"),
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scale);
+criterion_main!(benches);
@@ -0,0 +1,68 @@
+#ifndef VIRTUALHE_H
+#define VIRTUALHE_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Status of a call of the C interface.
+typedef enum VheStatus {
+  // The call succeeded.
+  VHE_STATUS_OK = 0,
+  // A pointer argument was null.
+  VHE_STATUS_NULL_POINTER = 1,
+  // The size or parameters are invalid.
+  VHE_STATUS_INVALID_PARAMS = 2,
+  // A channel could not be scaled, e.g. as it holds no finite values.
+  VHE_STATUS_SCALE = 3,
+  // The rendering failed unexpectedly.
+  VHE_STATUS_INTERNAL = 4,
+} VheStatus;
+
+// Parameters of `vhe_render`, the defaults of the command line from `vhe_default_params`.
+typedef struct VheParams {
+  // K factor of both channels, non-negative.
+  float k;
+  // Beta coefficients: hematoxylin and eosin, each (red, green, blue), non-negative.
+  float betas[2][3];
+  // Saturation percentiles of the nucleus and eosin channels in (0, 100].
+  float percentiles[2];
+} VheParams;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// The parameters the command line renders with by default.
+struct VheParams vhe_default_params(void);
+
+// Message of the last failed call on the calling thread, or null if none failed. The string
+// belongs to the library and stays valid until the next failed call on the thread.
+const char *vhe_last_error_message(void);
+
+// Render a virtual H&E image from nucleus and eosin channels of `width` x `height` floats in row
+// order, normalized by their data maximum and scaled by the saturation percentiles of `params`
+// as the command line does for floating point images, into `out_rgb`, which holds
+// `width * height * 3` bytes of RGB pixels in row order. A null `params` renders with the
+// defaults of `vhe_default_params`.
+//
+// # Safety
+//
+// `nucleus` and `eosin` must point to `width * height` readable floats, `out_rgb` to
+// `width * height * 3` writable bytes, and `params` must be null or point to a `VheParams`.
+enum VheStatus vhe_render(const float *nucleus,
+                          const float *eosin,
+                          size_t width,
+                          size_t height,
+                          const struct VheParams *params,
+                          uint8_t *out_rgb);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* VIRTUALHE_H */
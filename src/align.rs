@@ -0,0 +1,173 @@
+//! Registration of the eosin channel onto the nucleus channel by a translation, against the
+//! colored fringes at nucleus boundaries of channels acquired in sequential scans, and resampling
+//! of channels acquired at different resolutions onto a common grid.
+use crate::filter::bin_average;
+use log::debug;
+use ndarray::parallel::prelude::*;
+use ndarray::{Array2, Axis};
+
+/// Default search radius of `estimate_shift` in pixels.
+pub const DEFAULT_MAX_SHIFT: usize = 32;
+
+/// Long edge in pixels the images are downsampled to for the coarse search of `estimate_shift`,
+/// they are downsampled by at least 2.
+const COARSE_SIZE: usize = 512;
+
+/// Edge length of the central window the coarse shift is refined over at full resolution.
+const REFINE_SIZE: usize = 1024;
+
+/// Translate an image by `[dx, dy]` pixels, so that the value at (x, y) moves to (x + dx, y + dy).
+/// Fractional shifts are interpolated bilinearly, pixels shifted in from outside the image are 0.
+///
+/// Rows are computed in parallel.
+pub fn shift(image: &Array2<f32>, [dx, dy]: [f32; 2]) -> Array2<f32> {
+    let (rows, cols) = image.dim();
+    let at = |x: i64, y: i64| {
+        if x < 0 || y < 0 || x >= cols as i64 || y >= rows as i64 {
+            0.0
+        } else {
+            image[[y as usize, x as usize]]
+        }
+    };
+    let mut shifted = Array2::zeros((rows, cols));
+    shifted
+        .axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(y, mut row)| {
+            let sy = y as f32 - dy;
+            let (y0, ty) = (sy.floor() as i64, sy - sy.floor());
+            for (x, v) in row.iter_mut().enumerate() {
+                let sx = x as f32 - dx;
+                let (x0, tx) = (sx.floor() as i64, sx - sx.floor());
+                // Neighbors without weight are skipped so that integer shifts copy NaN values exactly
+                let mut value = 0.0;
+                for (oy, wy) in [(0, 1.0 - ty), (1, ty)] {
+                    for (ox, wx) in [(0, 1.0 - tx), (1, tx)] {
+                        let w = wy * wx;
+                        if w > 0.0 {
+                            value += w * at(x0 + ox, y0 + oy);
+                        }
+                    }
+                }
+                *v = value;
+            }
+        });
+    shifted
+}
+
+/// Resample an image bilinearly to `width` x `height` pixels spanning the same field of view,
+/// with the pixel centers of both grids aligned and the borders clamped.
+///
+/// Rows are computed in parallel.
+pub fn resample(image: &Array2<f32>, width: usize, height: usize) -> Array2<f32> {
+    let (rows, cols) = image.dim();
+    let mut resampled = Array2::zeros((height, width));
+    if rows == 0 || cols == 0 {
+        return resampled;
+    }
+    // Source coordinate of the center of output pixel i along an axis, and its neighbors
+    let source = |i: usize, from: usize, to: usize| {
+        let s = ((i as f32 + 0.5) * from as f32 / to as f32 - 0.5).clamp(0.0, (from - 1) as f32);
+        let i0 = s.floor() as usize;
+        (i0, (i0 + 1).min(from - 1), s - i0 as f32)
+    };
+    let columns: Vec<_> = (0..width).map(|x| source(x, cols, width)).collect();
+    resampled
+        .axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(y, mut row)| {
+            let (y0, y1, ty) = source(y, rows, height);
+            let (top, bottom) = (image.row(y0), image.row(y1));
+            for (v, &(x0, x1, tx)) in row.iter_mut().zip(&columns) {
+                let upper = top[x0] + tx * (top[x1] - top[x0]);
+                let lower = bottom[x0] + tx * (bottom[x1] - bottom[x0]);
+                *v = upper + ty * (lower - upper);
+            }
+        });
+    resampled
+}
+
+/// Estimate the integer translation `[dx, dy]` that `shift` has to apply to `moving` to align it
+/// with `reference`, by maximizing their normalized cross-correlation over shifts of at most
+/// `max_shift` pixels along each axis.
+///
+/// The images are searched on block-averaged copies with a long edge of at most 512 pixels
+/// first, and the coarse shift is refined at full resolution over a central window of 1024
+/// pixels. Non-finite values are left out.
+///
+/// # Panics
+///
+/// Panics if the images differ in size.
+pub fn estimate_shift(reference: &Array2<f32>, moving: &Array2<f32>, max_shift: usize) -> [i32; 2] {
+    assert_eq!(reference.dim(), moving.dim(), "images must have the same size");
+    let (rows, cols) = reference.dim();
+    let factor = rows.max(cols).div_ceil(COARSE_SIZE).max(2);
+
+    // Coarse search over the whole downsampled images
+    let (coarse_reference, coarse_moving) = (bin_average(reference, factor), bin_average(moving, factor));
+    let radius = max_shift.div_ceil(factor) as i64;
+    let window = [0, 0, coarse_reference.ncols(), coarse_reference.nrows()];
+    let [dx, dy] = best_shift(&coarse_reference, &coarse_moving, window, candidates([0, 0], radius, radius));
+
+    // Full resolution search around the coarse shift over the central window
+    let (center, radius) = ([dx * factor as i64, dy * factor as i64], factor as i64);
+    let (width, height) = (cols.min(REFINE_SIZE), rows.min(REFINE_SIZE));
+    let window = [(cols - width) / 2, (rows - height) / 2, (cols + width) / 2, (rows + height) / 2];
+    let [dx, dy] = best_shift(reference, moving, window, candidates(center, radius, max_shift as i64));
+    debug!("estimated shift {},{} (coarse search downsampled by {})", dx, dy, factor);
+    [dx as i32, dy as i32]
+}
+
+/// Shifts within `radius` of `center` that are at most `max_shift` along each axis.
+fn candidates([cx, cy]: [i64; 2], radius: i64, max_shift: i64) -> Vec<[i64; 2]> {
+    let range = |c: i64| (c - radius).max(-max_shift)..=(c + radius).min(max_shift);
+    range(cy).flat_map(|dy| range(cx).map(move |dx| [dx, dy])).collect()
+}
+
+/// The candidate shift with the highest normalized cross-correlation, the first of equal ones.
+fn best_shift(reference: &Array2<f32>, moving: &Array2<f32>, window: [usize; 4], candidates: Vec<[i64; 2]>) -> [i64; 2] {
+    candidates
+        .into_par_iter()
+        .map(|shift| (correlation(reference, moving, window, shift), shift))
+        .reduce(|| (f64::NEG_INFINITY, [0, 0]), |a, b| if b.0 > a.0 { b } else { a })
+        .1
+}
+
+/// Normalized cross-correlation of `reference` within `window` (x0, y0, x1, y1) and `moving`
+/// shifted by `[dx, dy]`, over the pixels where both are finite. Minus infinity without variance.
+fn correlation(reference: &Array2<f32>, moving: &Array2<f32>, [x0, y0, x1, y1]: [usize; 4], [dx, dy]: [i64; 2]) -> f64 {
+    let (rows, cols) = moving.dim();
+    // Pixel (x, y) of the reference meets pixel (x - dx, y - dy) of the moving image
+    let clip = |start: usize, end: usize, d: i64, size: usize| {
+        ((start as i64).max(d).max(0), (end as i64).min(size as i64 + d))
+    };
+    let (xa, xb) = clip(x0, x1, dx, cols);
+    let (ya, yb) = clip(y0, y1, dy, rows);
+    if xa >= xb || ya >= yb {
+        return f64::NEG_INFINITY;
+    }
+
+    let (mut n, mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+    for y in ya..yb {
+        let a = reference.row(y as usize);
+        let b = moving.row((y - dy) as usize);
+        for x in xa..xb {
+            let (a, b) = (f64::from(a[x as usize]), f64::from(b[(x - dx) as usize]));
+            if a.is_finite() && b.is_finite() {
+                n += 1.0;
+                sa += a;
+                sb += b;
+                saa += a * a;
+                sbb += b * b;
+                sab += a * b;
+            }
+        }
+    }
+    let variance = (saa - sa * sa / n) * (sbb - sb * sb / n);
+    if n < 2.0 || variance <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    (sab - sa * sb / n) / variance.sqrt()
+}
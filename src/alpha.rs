@@ -0,0 +1,89 @@
+//! RGBA output with a transparent background, so that virtual H&E images can be composited over
+//! other layers of a figure. The alpha channel is derived from the rendered colors or from a
+//! tissue mask, glass is fully transparent with either rule.
+use crate::{Error, OutputSample};
+use ndarray::parallel::prelude::*;
+use ndarray::{Array2, Array3, ArrayView3, Axis};
+
+/// How the alpha channel of RGBA output is derived.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AlphaRule {
+    /// From the darkest channel of each pixel: white is transparent, and stained pixels are as
+    /// opaque as their absorption, with colors such that the image over white is unchanged.
+    #[default]
+    Luminance,
+    /// From a tissue mask: tissue is opaque and everything else transparent.
+    Mask,
+}
+
+impl AlphaRule {
+    /// Name of the rule as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlphaRule::Luminance => "luminance",
+            AlphaRule::Mask => "mask",
+        }
+    }
+}
+
+impl std::str::FromStr for AlphaRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "luminance" => Ok(AlphaRule::Luminance),
+            "mask" => Ok(AlphaRule::Mask),
+            _ => Err(format!("unknown alpha rule '{}', expected one of: luminance, mask", s)),
+        }
+    }
+}
+
+/// Add an alpha channel to a (row, column, RGB) image by `AlphaRule::Luminance`: a pixel whose
+/// darkest channel is `m` gets alpha `1 - m`, and its colors are unmixed from white as
+/// `1 - (1 - c) / alpha`, so that compositing the image over white gives back the input.
+///
+/// Rows are computed in parallel.
+pub fn luminance_alpha<T: OutputSample>(rgb: ArrayView3<T>) -> Array3<T> {
+    let (height, width) = (rgb.shape()[0], rgb.shape()[1]);
+    let max = ((1u32 << T::BITS) - 1) as f32;
+    let mut out = Array3::<T>::from_elem((height, width, 4), T::default());
+    out.axis_iter_mut(Axis(0)).into_par_iter().zip(rgb.axis_iter(Axis(0))).for_each(|(mut row, pixels)| {
+        for (mut pixel, rgb) in row.outer_iter_mut().zip(pixels.outer_iter()) {
+            let color = [0, 1, 2].map(|channel| rgb[channel].into() as f32 / max);
+            let alpha = 1.0 - color.iter().fold(1.0f32, |min, &c| min.min(c));
+            for (channel, c) in color.into_iter().enumerate() {
+                pixel[channel] = T::quantize(if alpha > 0.0 { 1.0 - (1.0 - c) / alpha } else { 1.0 });
+            }
+            pixel[3] = T::quantize(alpha);
+        }
+    });
+    out
+}
+
+/// Add an alpha channel to a (row, column, RGB) image by `AlphaRule::Mask`, opaque where `mask`
+/// is set and transparent elsewhere.
+///
+/// Fails if the mask differs in size from the image.
+pub fn mask_alpha<T: OutputSample>(rgb: ArrayView3<T>, mask: &Array2<bool>) -> Result<Array3<T>, Error> {
+    let (height, width) = (rgb.shape()[0], rgb.shape()[1]);
+    if mask.dim() != (height, width) {
+        return Err(Error::InvalidOptions(format!(
+            "the alpha mask is {}x{} but the image is {}x{}",
+            mask.ncols(),
+            mask.nrows(),
+            width,
+            height
+        )));
+    }
+    let (opaque, transparent) = (T::quantize(1.0), T::quantize(0.0));
+    let mut out = Array3::<T>::from_elem((height, width, 4), T::default());
+    out.axis_iter_mut(Axis(0)).into_par_iter().enumerate().for_each(|(y, mut row)| {
+        for (x, mut pixel) in row.outer_iter_mut().enumerate() {
+            for channel in 0..3 {
+                pixel[channel] = rgb[[y, x, channel]];
+            }
+            pixel[3] = if mask[[y, x]] { opaque } else { transparent };
+        }
+    });
+    Ok(out)
+}
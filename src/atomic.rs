@@ -0,0 +1,120 @@
+//! Atomic output writes: an output file is encoded into a temporary file in the directory of its
+//! final path and renamed onto it once complete, so that an interrupted or failed write never
+//! leaves a truncated file at the final path, and so is an output directory such as an OME-Zarr
+//! store. Temporary files are registered while they are written, for an interrupt handler to
+//! delete them with `remove_pending`. Resumable writes use a partial file of a fixed name instead,
+//! which a later run can continue, see `journal`.
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+/// Temporary files being written.
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Number of temporary files created by the process, which makes their names unique.
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Hidden temporary path next to `path`, with its extension so that the format of the output is
+/// still taken from it.
+fn temporary_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let id = format!("{}-{}", std::process::id(), CREATED.fetch_add(1, Ordering::Relaxed));
+    match path.extension() {
+        Some(extension) => path.with_file_name(format!(".{}.tmp-{}.{}", stem, id, extension.to_string_lossy())),
+        None => path.with_file_name(format!(".{}.tmp-{}", stem, id)),
+    }
+}
+
+/// Write the file at `path` by calling `write` with a temporary path and renaming the temporary
+/// file onto `path` when it succeeds. The temporary file is deleted when it fails.
+pub fn write<F>(path: &Path, write: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&Path) -> Result<(), Box<dyn Error>>,
+{
+    let temporary = temporary_path(path);
+    PENDING.lock().unwrap_or_else(PoisonError::into_inner).push(temporary.clone());
+    let result = write(&temporary).and_then(|()| Ok(fs::rename(&temporary, path)?));
+    if result.is_err() {
+        remove(&temporary);
+    }
+    PENDING.lock().unwrap_or_else(PoisonError::into_inner).retain(|pending| *pending != temporary);
+    result
+}
+
+/// Flush the buffer of a temporary file and the file to the disk, so that a failed write of the
+/// last bytes, e.g. to a full disk, fails the write instead of renaming a truncated file onto the
+/// output, as dropping the writer would.
+pub(crate) fn finish(writer: BufWriter<File>) -> io::Result<()> {
+    writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()
+}
+
+/// Write the file at `path` resumably by calling `write` with the paths of its partial file and
+/// of the journal of the partial file, hidden next to `path` and the same for every run, and
+/// renaming the partial file onto `path` when it succeeds. With `resume`, the partial file and
+/// journal left by an earlier run are kept for `write` to continue, and kept when it fails, so
+/// that a later run continues them in turn. Otherwise they are deleted when it fails, and by
+/// `remove_pending`. The journal is deleted when it succeeds.
+pub fn write_resumable<F>(path: &Path, resume: bool, write: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&Path, &Path) -> Result<(), Box<dyn Error>>,
+{
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let partial = match path.extension() {
+        Some(extension) => path.with_file_name(format!(".{}.partial.{}", stem, extension.to_string_lossy())),
+        None => path.with_file_name(format!(".{}.partial", stem)),
+    };
+    let journal = path.with_file_name(format!(".{}.journal", path.file_name().unwrap_or_default().to_string_lossy()));
+    if !resume {
+        PENDING.lock().unwrap_or_else(PoisonError::into_inner).extend([partial.clone(), journal.clone()]);
+    }
+    let result = write(&partial, &journal).and_then(|()| Ok(fs::rename(&partial, path)?));
+    if result.is_ok() || !resume {
+        remove(&journal);
+    }
+    if result.is_err() && !resume {
+        remove(&partial);
+    }
+    if !resume {
+        let mut pending = PENDING.lock().unwrap_or_else(PoisonError::into_inner);
+        pending.retain(|pending| *pending != partial && *pending != journal);
+    }
+    result
+}
+
+/// Check that the temporary file of `path` can be created, by creating and deleting it.
+pub fn check_writable(path: &Path) -> io::Result<()> {
+    let temporary = temporary_path(path);
+    File::create(&temporary)?;
+    fs::remove_file(&temporary)
+}
+
+/// Register a temporary file or directory that is not an output, e.g. of downloaded inputs, for
+/// `remove_pending` to delete until it is released with `release`.
+pub fn hold(path: &Path) {
+    PENDING.lock().unwrap_or_else(PoisonError::into_inner).push(path.to_path_buf());
+}
+
+/// Delete a temporary file or directory registered with `hold`.
+pub fn release(path: &Path) {
+    remove(path);
+    PENDING.lock().unwrap_or_else(PoisonError::into_inner).retain(|pending| pending != path);
+}
+
+/// Delete the temporary files being written, for an interrupt handler before the process exits.
+pub fn remove_pending() {
+    for path in PENDING.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+        remove(path);
+    }
+}
+
+/// Delete a temporary file or directory, if it was created.
+fn remove(path: &Path) {
+    if path.is_dir() {
+        drop(fs::remove_dir_all(path));
+    } else {
+        drop(fs::remove_file(path));
+    }
+}
@@ -0,0 +1,214 @@
+//! Decompression of Blosc (version 1 format) buffers, the default compressor of Zarr stores.
+//!
+//! Blocks compressed with BloscLZ, LZ4, zlib or Zstandard and byte-shuffled or unshuffled data
+//! are supported, Snappy and bit shuffling are not.
+use flate2::read::ZlibDecoder;
+use std::io::Read;
+
+/// Size of the Blosc header in bytes.
+const HEADER_BYTES: usize = 16;
+
+/// Data is byte-shuffled.
+const FLAG_SHUFFLE: u8 = 0x1;
+/// Data is stored uncompressed after the header.
+const FLAG_MEMCPYED: u8 = 0x2;
+/// Data is bit-shuffled.
+const FLAG_BITSHUFFLE: u8 = 0x4;
+/// Blocks are not split into one stream per byte of the type.
+const FLAG_DONT_SPLIT: u8 = 0x10;
+
+/// Largest match distance of BloscLZ with an 8bit offset extension, longer distances use 16bits.
+const BLOSCLZ_MAX_DISTANCE: usize = 8191;
+
+/// Largest type size whose blocks are split into streams.
+const MAX_SPLITS: usize = 16;
+/// Smallest number of elements per block for blocks to be split into streams, by encoders that do
+/// not record `FLAG_DONT_SPLIT`.
+const MIN_BUFFER_SIZE: usize = 128;
+
+/// Decompress a Blosc buffer.
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < HEADER_BYTES {
+        return Err(format!("blosc buffer of {} bytes is shorter than its header", data.len()));
+    }
+    let flags = data[2];
+    let typesize = usize::from(data[3]).max(1);
+    let nbytes = read_u32(data, 4)? as usize;
+    let blocksize = read_u32(data, 8)? as usize;
+
+    if flags & FLAG_MEMCPYED != 0 {
+        return data
+            .get(HEADER_BYTES..HEADER_BYTES + nbytes)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| "blosc buffer is truncated".to_string());
+    }
+    if flags & FLAG_BITSHUFFLE != 0 {
+        return Err("bit-shuffled blosc data is not supported".to_string());
+    }
+    if blocksize == 0 {
+        return Err("blosc block size is zero".to_string());
+    }
+    let codec = match flags >> 5 {
+        0 => Codec::BloscLz,
+        1 => Codec::Lz4,
+        3 => Codec::Zlib,
+        4 => Codec::Zstd,
+        2 => return Err("snappy compressed blosc data is not supported, use blosclz, lz4, zlib or zstd".to_string()),
+        code => return Err(format!("unknown blosc compressor code {}", code)),
+    };
+
+    let mut out = vec![0u8; nbytes];
+    for (index, block) in out.chunks_mut(blocksize).enumerate() {
+        let start = read_u32(data, HEADER_BYTES + index * 4)? as usize;
+        let leftover = block.len() < blocksize;
+        let split = flags & FLAG_DONT_SPLIT == 0
+            && !leftover
+            && typesize <= MAX_SPLITS
+            && blocksize / typesize >= MIN_BUFFER_SIZE;
+        let streams = if split { typesize } else { 1 };
+
+        // Each stream is prefixed by its compressed size, equal to the decompressed size if stored raw
+        let mut decoded = vec![0u8; block.len()];
+        let mut position = start;
+        for stream in decoded.chunks_mut(block.len() / streams) {
+            let size = read_u32(data, position)? as usize;
+            position += 4;
+            let compressed = data
+                .get(position..position + size)
+                .ok_or_else(|| "blosc buffer is truncated".to_string())?;
+            if size == stream.len() {
+                stream.copy_from_slice(compressed);
+            } else {
+                codec.decompress(compressed, stream)?;
+            }
+            position += size;
+        }
+
+        if flags & FLAG_SHUFFLE != 0 && typesize > 1 {
+            unshuffle(&decoded, block, typesize);
+        } else {
+            block.copy_from_slice(&decoded);
+        }
+    }
+    Ok(out)
+}
+
+/// Compressor of Blosc blocks.
+#[derive(Debug, Clone, Copy)]
+enum Codec {
+    BloscLz,
+    Lz4,
+    Zlib,
+    Zstd,
+}
+
+impl Codec {
+    /// Decompress a stream into `out`, which must be filled exactly.
+    fn decompress(self, compressed: &[u8], out: &mut [u8]) -> Result<(), String> {
+        let written = match self {
+            Codec::BloscLz => blosclz_decompress(compressed, out)?,
+            Codec::Lz4 => lz4_flex::block::decompress_into(compressed, out).map_err(|e| e.to_string())?,
+            Codec::Zlib => read_into(ZlibDecoder::new(compressed), out)?,
+            Codec::Zstd => {
+                let decoder = ruzstd::decoding::StreamingDecoder::new(compressed).map_err(|e| e.to_string())?;
+                read_into(decoder, out)?
+            }
+        };
+        if written != out.len() {
+            return Err(format!("blosc stream decompressed to {} bytes, expected {}", written, out.len()));
+        }
+        Ok(())
+    }
+}
+
+/// Decompress a BloscLZ stream into `out`, returning the number of bytes written.
+///
+/// The stream is a sequence of literal runs and back references, each introduced by a control
+/// byte whose top three bits hold the match length (0 for literals) and low five bits the literal
+/// count or the high bits of the match distance.
+fn blosclz_decompress(input: &[u8], out: &mut [u8]) -> Result<usize, String> {
+    let corrupt = || "blosclz stream is corrupt".to_string();
+    let byte = |ip: &mut usize| -> Result<usize, String> {
+        let value = *input.get(*ip).ok_or_else(corrupt)?;
+        *ip += 1;
+        Ok(usize::from(value))
+    };
+    if input.is_empty() {
+        return Ok(0);
+    }
+    let (mut ip, mut op) = (0, 0);
+    let mut ctrl = byte(&mut ip)? & 31;
+    loop {
+        if ctrl >= 32 {
+            // Match, lengths from 9 continue in extension bytes and the longest 8bit distance is
+            // followed by a 16bit distance
+            let mut len = (ctrl >> 5) - 1;
+            let high = (ctrl & 31) << 8;
+            if len == 6 {
+                loop {
+                    let code = byte(&mut ip)?;
+                    len += code;
+                    if code != 255 {
+                        break;
+                    }
+                }
+            }
+            let code = byte(&mut ip)?;
+            len += 3;
+            let distance = if code == 255 && high == 31 << 8 {
+                (byte(&mut ip)? << 8) + byte(&mut ip)? + BLOSCLZ_MAX_DISTANCE + 1
+            } else {
+                high + code + 1
+            };
+            if op + len > out.len() || distance > op {
+                return Err(corrupt());
+            }
+            // Byte by byte, as the match may overlap the output it produces
+            for i in op..op + len {
+                out[i] = out[i - distance];
+            }
+            op += len;
+        } else {
+            let literal = input.get(ip..ip + ctrl + 1).ok_or_else(corrupt)?;
+            out.get_mut(op..op + literal.len()).ok_or_else(corrupt)?.copy_from_slice(literal);
+            ip += literal.len();
+            op += literal.len();
+        }
+        if ip >= input.len() {
+            return Ok(op);
+        }
+        ctrl = byte(&mut ip)?;
+    }
+}
+
+/// Read a decompressing reader into `out`, returning the number of bytes read.
+fn read_into(mut reader: impl Read, out: &mut [u8]) -> Result<usize, String> {
+    let mut filled = 0;
+    while filled < out.len() {
+        match reader.read(&mut out[filled..]).map_err(|e| e.to_string())? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Undo byte shuffling: the shuffled block holds the first byte of every element, then the second
+/// byte of every element, and so on, trailing bytes that do not make up an element are copied as is.
+fn unshuffle(shuffled: &[u8], out: &mut [u8], typesize: usize) {
+    let elements = shuffled.len() / typesize;
+    for byte in 0..typesize {
+        for element in 0..elements {
+            out[element * typesize + byte] = shuffled[byte * elements + element];
+        }
+    }
+    let tail = elements * typesize;
+    out[tail..].copy_from_slice(&shuffled[tail..]);
+}
+
+/// Little-endian u32 at `offset`.
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().expect("slice of 4 bytes")))
+        .ok_or_else(|| "blosc buffer is truncated".to_string())
+}
@@ -0,0 +1,159 @@
+//! C interface of the capi feature, for plugins and wrappers in C, C++ or Java that render
+//! channels they hold in memory through the shared library. The header `include/virtualhe.h` is
+//! generated from this module with cbindgen (see cbindgen.toml).
+//!
+//! The functions report failures by a status code, and the message of the last failure of the
+//! calling thread is kept for `vhe_last_error_message`.
+use crate::{normalize_value, resolve_input_max, scale_with, LoadOptions, Params, ScaleOptions, DEFAULT_BETA};
+use ndarray::parallel::prelude::*;
+use ndarray::{ArrayView2, Zip};
+use std::cell::RefCell;
+use std::ffi::{c_char, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// Status of a call of the C interface.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VheStatus {
+    /// The call succeeded.
+    Ok = 0,
+    /// A pointer argument was null.
+    NullPointer = 1,
+    /// The size or parameters are invalid.
+    InvalidParams = 2,
+    /// A channel could not be scaled, e.g. as it holds no finite values.
+    Scale = 3,
+    /// The rendering failed unexpectedly.
+    Internal = 4,
+}
+
+/// Parameters of `vhe_render`, the defaults of the command line from `vhe_default_params`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VheParams {
+    /// K factor of both channels, non-negative.
+    pub k: f32,
+    /// Beta coefficients: hematoxylin and eosin, each (red, green, blue), non-negative.
+    pub betas: [[f32; 3]; 2],
+    /// Saturation percentiles of the nucleus and eosin channels in (0, 100].
+    pub percentiles: [f32; 2],
+}
+
+impl Default for VheParams {
+    fn default() -> Self {
+        VheParams {
+            k: 2.5,
+            betas: DEFAULT_BETA,
+            percentiles: [ScaleOptions::default().percentile; 2],
+        }
+    }
+}
+
+thread_local! {
+    /// Message of the last failed call of the thread.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Keep `message` as the last error of the thread and return `status`.
+fn fail(status: VheStatus, message: String) -> VheStatus {
+    let message = CString::new(message.replace('\0', " ")).expect("interior NUL bytes are replaced");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    status
+}
+
+/// The parameters the command line renders with by default.
+#[no_mangle]
+pub extern "C" fn vhe_default_params() -> VheParams {
+    VheParams::default()
+}
+
+/// Message of the last failed call on the calling thread, or null if none failed. The string
+/// belongs to the library and stays valid until the next failed call on the thread.
+#[no_mangle]
+pub extern "C" fn vhe_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Render a virtual H&E image from nucleus and eosin channels of `width` x `height` floats in row
+/// order, normalized by their data maximum and scaled by the saturation percentiles of `params`
+/// as the command line does for floating point images, into `out_rgb`, which holds
+/// `width * height * 3` bytes of RGB pixels in row order. A null `params` renders with the
+/// defaults of `vhe_default_params`.
+///
+/// # Safety
+///
+/// `nucleus` and `eosin` must point to `width * height` readable floats, `out_rgb` to
+/// `width * height * 3` writable bytes, and `params` must be null or point to a `VheParams`.
+#[no_mangle]
+pub unsafe extern "C" fn vhe_render(
+    nucleus: *const f32,
+    eosin: *const f32,
+    width: usize,
+    height: usize,
+    params: *const VheParams,
+    out_rgb: *mut u8,
+) -> VheStatus {
+    if nucleus.is_null() || eosin.is_null() || out_rgb.is_null() {
+        return fail(VheStatus::NullPointer, "nucleus, eosin and out_rgb must not be null".to_string());
+    }
+    let params = if params.is_null() { VheParams::default() } else { *params };
+    // Slices of a channel must not exceed isize::MAX bytes
+    let Some(pixels) = width.checked_mul(height).filter(|&pixels| pixels <= isize::MAX as usize / 4) else {
+        return fail(VheStatus::InvalidParams, format!("image size {}x{} is too large", width, height));
+    };
+    let view = |channel| ArrayView2::from_shape((height, width), std::slice::from_raw_parts(channel, pixels));
+    let (nucleus, eosin) = (view(nucleus).expect("sized by the shape"), view(eosin).expect("sized by the shape"));
+    let out_rgb = std::slice::from_raw_parts_mut(out_rgb, pixels * 3);
+    // Panics must not unwind into the caller
+    match panic::catch_unwind(AssertUnwindSafe(|| render(nucleus, eosin, &params, out_rgb))) {
+        Ok(Ok(())) => VheStatus::Ok,
+        Ok(Err((status, message))) => fail(status, message),
+        Err(_) => fail(VheStatus::Internal, "the rendering panicked".to_string()),
+    }
+}
+
+/// Validate the parameters, then scale and render the channels of `vhe_render` into `out_rgb`.
+fn render<'a>(
+    nucleus: ArrayView2<'a, f32>,
+    eosin: ArrayView2<'a, f32>,
+    params: &VheParams,
+    out_rgb: &mut [u8],
+) -> Result<(), (VheStatus, String)> {
+    let invalid = |message: String| (VheStatus::InvalidParams, message);
+    if !params.k.is_finite() || params.k < 0.0 {
+        return Err(invalid(format!("k must be non-negative, got {}", params.k)));
+    }
+    for percentile in params.percentiles {
+        if !(percentile > 0.0 && percentile <= 100.0) {
+            return Err(invalid(format!("percentile must be in (0, 100], got {}", percentile)));
+        }
+    }
+    if params.betas.iter().flatten().any(|v| !v.is_finite() || *v < 0.0) {
+        return Err(invalid(format!("beta values must be non-negative, got {:?}", params.betas)));
+    }
+    let render_params = Params {
+        beta: params.betas,
+        ..Params::default().with_k(params.k)
+    };
+
+    let mut channels = [(nucleus, params.percentiles[0], "nucleus"), (eosin, params.percentiles[1], "eosin")].map(
+        |(view, percentile, name)| {
+            let data_max = || view.into_par_iter().copied().filter(|v| v.is_finite()).reduce(|| 0.0, f32::max);
+            let input_max =
+                resolve_input_max(&LoadOptions::default(), None, data_max).expect("the default input range resolves");
+            (Zip::from(view).par_map_collect(|&v| normalize_value(v, input_max)), percentile, name)
+        },
+    );
+    for (channel, percentile, name) in &mut channels {
+        let options = ScaleOptions {
+            percentile: *percentile,
+            ..ScaleOptions::default()
+        };
+        scale_with(channel, &options).map_err(|e| (VheStatus::Scale, format!("{}: {}", name, e)))?;
+    }
+    let [(nucleus, _, _), (eosin, _, _)] = channels;
+    let rgb = crate::render(nucleus, eosin, &render_params);
+    out_rgb.iter_mut().zip(rgb.iter()).for_each(|(out, &v)| *out = v);
+    Ok(())
+}
@@ -0,0 +1,387 @@
+//! Batch runs of --batch and --tile-list: pairs matched by filename pattern are rendered on
+//! several jobs, with per-pair windows or, with --shared-norm, windows shared by the whole batch.
+use super::checks::{check_disk_space, check_output, check_output_dirs, check_overwrite, tiled_fallback};
+use super::job::{fix_range, Job};
+use super::parse::{format_range, Normalization};
+use super::progress::Progress;
+use super::provenance;
+use super::render::render_pair;
+use crate::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use virtualhe::histogram::{Histogram, HISTOGRAM_BINS};
+use virtualhe::Error;
+
+/// Output filename pattern of batch runs.
+pub(crate) const DEFAULT_OUTPUT_PATTERN: &str = "{id}.tif";
+
+/// Id matched by the `{id}` placeholder of a filename pattern.
+pub(crate) fn match_pattern(pattern: &str, name: &str) -> Option<String> {
+    let (prefix, suffix) = pattern.split_once("{id}")?;
+    let id = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+/// Render every pair of images of the batch directory matched by the nucleus and eosin patterns
+/// into the output directory, skipping unmatched files and continuing after failed pairs.
+pub(crate) fn run_batch(args: &Args, job: &Job) -> Result<(), Box<dyn std::error::Error>> {
+    let progress = &job.progress;
+    let input_dir = Path::new(args.batch_dir.as_deref().expect("checked by the caller"));
+    let output_dir = Path::new(args.output_dir.as_deref().expect("required by --batch-dir"));
+    let nucleus_pattern = args.nucleus_pattern.as_deref().expect("required by --batch-dir");
+    let eosin_pattern = args.eosin_pattern.as_deref().expect("required by --batch-dir");
+    let output_pattern = args.output_pattern.as_deref().unwrap_or(DEFAULT_OUTPUT_PATTERN);
+
+    // Directories are listed too, as OME-Zarr inputs are directories
+    let mut names = Vec::new();
+    for entry in fs::read_dir(input_dir).map_err(|e| format!("{}: {}", input_dir.display(), e))? {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+
+    // Pair the files by id, in id order
+    let mut pairs: BTreeMap<String, (Option<String>, Option<String>)> = BTreeMap::new();
+    let mut skipped = 0;
+    for name in names {
+        let nucleus_id = match_pattern(nucleus_pattern, &name);
+        let eosin_id = match_pattern(eosin_pattern, &name);
+        if nucleus_id.is_none() && eosin_id.is_none() {
+            progress.println(format!("Skipping {}: matches neither --nucleus-pattern nor --eosin-pattern", name));
+            skipped += 1;
+        }
+        if let Some(id) = nucleus_id {
+            pairs.entry(id).or_default().0 = Some(name.clone());
+        }
+        if let Some(id) = eosin_id {
+            pairs.entry(id).or_default().1 = Some(name);
+        }
+    }
+    let mut matched = Vec::new();
+    for (id, pair) in pairs {
+        match pair {
+            (Some(nucleus), Some(eosin)) => matched.push((id, nucleus, eosin)),
+            (Some(name), None) => {
+                progress.println(format!("Skipping {}: no eosin image {}", name, eosin_pattern.replace("{id}", &id)));
+                skipped += 1;
+            }
+            (None, Some(name)) => {
+                progress.println(format!("Skipping {}: no nucleus image {}", name, nucleus_pattern.replace("{id}", &id)));
+                skipped += 1;
+            }
+            (None, None) => unreachable!("every id has at least one file"),
+        }
+    }
+    if matched.is_empty() {
+        return Err(format!("no pairs of images matched in {}", input_dir.display()).into());
+    }
+    let items: Vec<_> = matched
+        .into_iter()
+        .map(|(id, nucleus, eosin)| BatchItem {
+            output: output_dir.join(output_pattern.replace("{id}", &id)).to_string_lossy().into_owned(),
+            nucleus: input_dir.join(nucleus).to_string_lossy().into_owned(),
+            eosin: input_dir.join(eosin).to_string_lossy().into_owned(),
+            id,
+        })
+        .collect();
+    fs::create_dir_all(output_dir).map_err(|e| format!("{}: {}", output_dir.display(), e))?;
+    check_batch_outputs(args, job, &items)?;
+
+    // Global normalization selects fixed ranges from the percentiles over all inputs in a first pass
+    let global;
+    let job = match args.normalize.unwrap_or_default() {
+        Normalization::Image => job,
+        Normalization::Global => {
+            let nucleus_paths: Vec<_> = items.iter().map(|item| &item.nucleus).collect();
+            let eosin_paths: Vec<_> = items.iter().map(|item| &item.eosin).collect();
+            let nucleus = progress.phase("Computing global nucleus thresholds", || {
+                virtualhe::reference_window(&nucleus_paths, &job.nucleus_options, &job.nucleus_scale)
+            })?;
+            let eosin = progress.phase("Computing global eosin thresholds", || {
+                virtualhe::reference_window(&eosin_paths, &job.eosin_options, &job.eosin_scale)
+            })?;
+            progress.println(format!(
+                "Using global ranges: nucleus {}, eosin {}",
+                format_range(nucleus),
+                format_range(eosin)
+            ));
+            global = with_ranges(job, nucleus, eosin);
+            &global
+        }
+    };
+    render_batch(args, job, &items, skipped)
+}
+
+/// A pair of a batch run and the path its output is saved to.
+pub(crate) struct BatchItem {
+    /// Name of the pair in messages.
+    pub(crate) id: String,
+    pub(crate) nucleus: String,
+    pub(crate) eosin: String,
+    pub(crate) output: String,
+}
+
+/// Refuse existing outputs and check the output directories, free disk space and memory of a
+/// batch before any pair is rendered.
+pub(crate) fn check_batch_outputs(args: &Args, job: &Job, items: &[BatchItem]) -> Result<(), Box<dyn std::error::Error>> {
+    for item in items {
+        check_overwrite(args, &item.output)?;
+        tiled_fallback(args, job, &item.nucleus, &item.eosin, &item.output)?;
+    }
+    check_output_dirs(args, &items.iter().map(|item| item.output.as_str()).collect::<Vec<_>>())?;
+    let pairs: Vec<_> = items.iter().map(|item| (item.nucleus.as_str(), item.output.as_str())).collect();
+    check_disk_space(args, &job.nucleus_options, &pairs);
+    Ok(())
+}
+
+/// The job with both channels scaled by fixed windows in input units.
+pub(crate) fn with_ranges(job: &Job, nucleus: [f32; 2], eosin: [f32; 2]) -> Job {
+    let mut fixed = job.clone();
+    fix_range(&mut fixed.nucleus_options, &mut fixed.nucleus_scale, nucleus);
+    fix_range(&mut fixed.eosin_options, &mut fixed.eosin_scale, eosin);
+    fixed
+}
+
+/// Render the pairs of a batch, --jobs at a time, continuing after failed pairs, and report how
+/// many succeeded, failed and were skipped (`skipped` files that matched no pair). An interrupt
+/// abandons the pairs being rendered, whose outputs are deleted, and starts no others, while the
+/// outputs of the pairs rendered before are kept. With --resume the pairs completed by an earlier
+/// run are not rendered again.
+pub(crate) fn render_batch(args: &Args, job: &Job, items: &[BatchItem], skipped: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let progress = &job.progress;
+    let all = items.len();
+    let items: Vec<_> =
+        items.iter().filter(|item| !args.render.resume || !batch_item_completed(args, progress, item)).collect();
+    let items = &items[..];
+    let complete = if args.render.resume { format!(", {} already complete", all - items.len()) } else { String::new() };
+    // Each job renders with its own thread pool, so that the jobs together use the threads of the
+    // global pool once
+    let jobs = (args.jobs as usize).min(items.len()).max(1);
+    let threads = rayon::current_num_threads().div_ceil(jobs);
+    let next = AtomicUsize::new(0);
+    let errors = Mutex::new(BTreeMap::new());
+    let (finished, abandoned) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    let overall = progress.images(items.len());
+    std::thread::scope(|scope| -> Result<(), Box<dyn std::error::Error>> {
+        for _ in 0..jobs {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build()?;
+            let (next, errors, finished, abandoned, overall) = (&next, &errors, &finished, &abandoned, &overall);
+            scope.spawn(move || loop {
+                if virtualhe::interrupt::requested() {
+                    break;
+                }
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+                let id = &item.id;
+                progress.println(format!("[{}/{}] {}", index + 1, items.len(), id));
+                // Errors are sent back from the pool as messages, telling whether an interrupt abandoned the pair
+                let result = pool.install(|| {
+                    render_pair(args, job, &progress.for_image(id), &item.nucleus, &item.eosin, &item.output)
+                        .map_err(|e| (matches!(e.downcast_ref::<Error>(), Some(Error::Interrupted(_))), e.to_string()))
+                });
+                match result {
+                    Ok(()) => {}
+                    Err((true, e)) => {
+                        progress.bars.suspend(|| eprintln!("Abandoned {}: {}", id, e));
+                        abandoned.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    Err((false, e)) => {
+                        progress.bars.suspend(|| eprintln!("Failed {}: {}", id, e));
+                        errors.lock().expect("no job panicked").insert(index, (id, e));
+                    }
+                }
+                finished.fetch_add(1, Ordering::Relaxed);
+                overall.inc(1);
+            });
+        }
+        Ok(())
+    })?;
+    overall.finish_and_clear();
+    let failures: Vec<_> = errors.into_inner().expect("no job panicked").into_values().collect();
+
+    let (finished, abandoned) = (finished.into_inner(), abandoned.into_inner());
+    if virtualhe::interrupt::requested() {
+        println!(
+            "Batch interrupted: {} succeeded, {} failed, {} abandoned, {} not started{}, {} files skipped",
+            finished - failures.len(),
+            failures.len(),
+            abandoned,
+            items.len() - finished - abandoned,
+            complete,
+            skipped
+        );
+    } else {
+        println!(
+            "Batch finished: {} succeeded, {} failed{}, {} files skipped",
+            items.len() - failures.len(),
+            failures.len(),
+            complete,
+            skipped
+        );
+    }
+    for (id, error) in &failures {
+        println!("  {}: {}", id, error);
+    }
+    if virtualhe::interrupt::requested() {
+        return Err(Error::Interrupted(format!("batch interrupted after {} of {} pairs", finished, items.len())).into());
+    }
+    if !failures.is_empty() {
+        return Err(format!("{} of {} pairs failed", failures.len(), items.len()).into());
+    }
+    Ok(())
+}
+
+/// Whether the output of a pair of the batch was completed by an earlier run, for --resume, telling
+/// why it is rendered again otherwise if it exists.
+pub(crate) fn batch_item_completed(args: &Args, progress: &Progress, item: &BatchItem) -> bool {
+    let mut inputs = vec![("nucleus", item.nucleus.as_str())];
+    if args.render.eosin_inputs.is_empty() {
+        inputs.push(("eosin", item.eosin.as_str()));
+    }
+    match provenance::completed(args, &item.output, &inputs) {
+        Ok(()) => {
+            progress.println(format!("Skipping {}: {} is complete", item.id, item.output));
+            true
+        }
+        Err(_) if !Path::new(&item.output).exists() => false,
+        Err(reason) => {
+            progress.println(format!("Rendering {} again: {}", item.id, reason));
+            false
+        }
+    }
+}
+
+/// Version of the layout of --save-norm files.
+pub(crate) const NORM_SCHEMA_VERSION: u32 = 1;
+
+/// Histograms of both channels over the tiles of --shared-norm, as saved by --save-norm.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SharedNorm {
+    pub(crate) schema_version: u32,
+    pub(crate) nucleus: Histogram,
+    pub(crate) eosin: Histogram,
+}
+
+/// Render the tiles of a --shared-norm list with the windows the percentiles select from the
+/// histograms over all of them, accumulated in a first pass or read with --load-norm.
+pub(crate) fn run_shared_norm(args: &Args, job: &Job) -> Result<(), Box<dyn std::error::Error>> {
+    let progress = &job.progress;
+    let list = args.shared_norm.as_deref().expect("checked by the caller");
+    let items = read_tile_list(list)?;
+    for item in &items {
+        check_output(args, &item.output)?;
+    }
+    check_batch_outputs(args, job, &items)?;
+
+    let norm = match &args.load_norm {
+        Some(path) => {
+            let norm = read_norm(path)?;
+            progress.println(format!("Read the histograms of {} tiles from {}", norm.nucleus.images, path));
+            norm
+        }
+        None => {
+            // One tile is decoded at a time, only the histograms are kept
+            let nucleus_paths: Vec<_> = items.iter().map(|item| &item.nucleus).collect();
+            let eosin_paths: Vec<_> = items.iter().map(|item| &item.eosin).collect();
+            let nucleus = progress.phase("Accumulating nucleus histogram", || {
+                virtualhe::histogram::accumulate_histogram(&nucleus_paths, &job.nucleus_options)
+            })?;
+            let eosin = progress.phase("Accumulating eosin histogram", || {
+                virtualhe::histogram::accumulate_histogram(&eosin_paths, &job.eosin_options)
+            })?;
+            SharedNorm {
+                schema_version: NORM_SCHEMA_VERSION,
+                nucleus,
+                eosin,
+            }
+        }
+    };
+    if let Some(path) = &args.save_norm {
+        let json = serde_json::to_string(&norm)?;
+        virtualhe::atomic::write(Path::new(path), |temporary| Ok(fs::write(temporary, json)?)).map_err(|e| Error::Write {
+            path: path.into(),
+            message: format!("{}: {}", path, e),
+        })?;
+        progress.println(format!("Histograms saved to: {}", path));
+    }
+
+    let source = Path::new(args.load_norm.as_deref().unwrap_or(list));
+    let nucleus = norm.nucleus.window(source, &job.nucleus_scale)?;
+    let eosin = norm.eosin.window(source, &job.eosin_scale)?;
+    progress.println(format!("Using shared ranges: nucleus {}, eosin {}", format_range(nucleus), format_range(eosin)));
+    render_batch(args, &with_ranges(job, nucleus, eosin), &items, 0)
+}
+
+/// Read the tiles of a --shared-norm list: lines of nucleus, eosin and output paths relative to
+/// the directory of the list, separated by tabs if the line has any and by whitespace otherwise.
+pub(crate) fn read_tile_list(path: &str) -> Result<Vec<BatchItem>, Error> {
+    let text = fs::read_to_string(path).map_err(|source| Error::Open {
+        path: path.into(),
+        source,
+    })?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let resolve = |relative: &str| dir.join(relative).to_string_lossy().into_owned();
+    let mut items = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = match line.contains('\t') {
+            true => line.split('\t').map(str::trim).filter(|field| !field.is_empty()).collect(),
+            false => line.split_whitespace().collect(),
+        };
+        let [nucleus, eosin, output] = fields[..] else {
+            return Err(Error::Decode {
+                path: path.into(),
+                message: format!(
+                    "{}:{}: expected the nucleus, eosin and output paths of a tile, got {} fields",
+                    path,
+                    number + 1,
+                    fields.len()
+                ),
+            });
+        };
+        items.push(BatchItem {
+            id: output.to_string(),
+            nucleus: resolve(nucleus),
+            eosin: resolve(eosin),
+            output: resolve(output),
+        });
+    }
+    if items.is_empty() {
+        return Err(Error::InvalidOptions(format!("{} lists no tiles", path)));
+    }
+    Ok(items)
+}
+
+/// Read the histograms of a --save-norm file.
+pub(crate) fn read_norm(path: &str) -> Result<SharedNorm, Error> {
+    let decode_error = |message: String| Error::Decode {
+        path: path.into(),
+        message: format!("{}: {}", path, message),
+    };
+    let text = fs::read_to_string(path).map_err(|source| Error::Open {
+        path: path.into(),
+        source,
+    })?;
+    let norm: SharedNorm = serde_json::from_str(&text).map_err(|e| decode_error(e.to_string()))?;
+    if norm.schema_version != NORM_SCHEMA_VERSION {
+        return Err(decode_error(format!(
+            "unsupported schema_version {}, expected {}",
+            norm.schema_version, NORM_SCHEMA_VERSION
+        )));
+    }
+    for histogram in [&norm.nucleus, &norm.eosin] {
+        if histogram.counts.len() != HISTOGRAM_BINS {
+            return Err(decode_error(format!("histogram has {} bins, expected {}", histogram.counts.len(), HISTOGRAM_BINS)));
+        }
+    }
+    Ok(norm)
+}
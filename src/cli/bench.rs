@@ -0,0 +1,155 @@
+//! Benchmark of the `bench` subcommand, for performance numbers that can be compared across
+//! machines and versions: a synthetic pair of 16bit images of the requested size, as written by
+//! `generate-test-data` with seed 0, is generated in memory and written once to a temporary directory, then rendered several times as the command
+//! line renders a pair, and the durations of the phases of the renders are summarized.
+use super::job::Job;
+use super::render::render_pair;
+use super::synthetic::{self, parse_size};
+use crate::{run_args, Args, RenderParams};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Width and height of the synthetic images by default, larger than those of generate-test-data.
+const DEFAULT_SIZE: &str = "4096";
+
+/// Timed renders by default.
+const DEFAULT_ITERATIONS: u32 = 5;
+
+/// Arguments of the `bench` subcommand.
+#[derive(clap::Args, Debug)]
+pub(crate) struct BenchArgs {
+    /// Size of the synthetic images in pixels, WIDTHxHEIGHT or one edge length for a square.
+    #[arg(long, value_name = "WIDTHxHEIGHT", default_value = DEFAULT_SIZE, value_parser = parse_size)]
+    size: (usize, usize),
+    /// Number of timed renders.
+    #[arg(long, default_value_t = DEFAULT_ITERATIONS, value_parser = clap::value_parser!(u32).range(1..))]
+    iterations: u32,
+    /// Number of renders before the timed ones, which are not counted, e.g. to fill the file cache.
+    #[arg(long, default_value_t = 1)]
+    warmup: u32,
+    /// Extension of the output file, selecting its format (e.g., tif, png, jpg or ome.zarr).
+    #[arg(long, value_name = "EXT", default_value = "tif")]
+    extension: String,
+    #[command(flatten)]
+    render: RenderParams,
+}
+
+/// Options of the `bench` subcommand.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Bench {
+    width: usize,
+    height: usize,
+    iterations: u32,
+    warmup: u32,
+    extension: String,
+}
+
+impl BenchArgs {
+    /// The arguments of the subcommand as those of the invocation without one.
+    pub(crate) fn into_args(self) -> Args {
+        let (width, height) = self.size;
+        Args {
+            bench: Some(Bench {
+                width,
+                height,
+                iterations: self.iterations,
+                warmup: self.warmup,
+                extension: self.extension.trim_start_matches('.').to_string(),
+            }),
+            ..Args::new(self.render)
+        }
+    }
+}
+
+/// Temporary directory of the benchmark, removed with its contents when dropped.
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            log::warn!("{}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Generate and write the synthetic pair, then render it with the options of the command line.
+pub(crate) fn run(args: &Args, bench: &Bench) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = TempDir(std::env::temp_dir().join(format!("virtualhe-bench-{}", std::process::id())));
+    fs::create_dir_all(&dir.0).map_err(|e| format!("{}: {}", dir.0.display(), e))?;
+    let (nucleus, eosin) = synthetic::pair(bench.width, bench.height, 0);
+    let path = |name: &str| dir.0.join(name).to_string_lossy().into_owned();
+    let (nucleus_path, eosin_path) = (path("nucleus.tif"), path("eosin.tif"));
+    synthetic::write(&nucleus, 16, Path::new(&nucleus_path))?;
+    synthetic::write(&eosin, 16, Path::new(&eosin_path))?;
+
+    let args = Args {
+        nucleus: Some(nucleus_path),
+        eosin: Some(eosin_path),
+        output: Some(path(&format!("vhe.{}", bench.extension))),
+        ..args.clone()
+    };
+    run_args(&args)
+}
+
+/// Render the pair of the benchmark, prepared by `run_args` from the arguments of `run`, and print
+/// the durations of its phases.
+pub(crate) fn render(
+    args: &Args,
+    job: &Job,
+    bench: &Bench,
+    nucleus_path: &str,
+    eosin_path: &str,
+    output_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let megapixels = (bench.width * bench.height) as f64 / 1e6;
+    println!(
+        "Rendering {} x {} pixels ({:.1} MP) {} times after {} warm-up render(s) on {} thread(s)",
+        bench.width,
+        bench.height,
+        megapixels,
+        bench.iterations,
+        bench.warmup,
+        rayon::current_num_threads()
+    );
+    // Durations of each phase over the timed renders, in the order the phases first ran
+    let mut phases: Vec<(String, Vec<Duration>)> = Vec::new();
+    let mut totals = Vec::new();
+    for run in 0..bench.warmup + bench.iterations {
+        let progress = job.progress.for_image(&format!("run {}", run + 1));
+        let start = Instant::now();
+        render_pair(args, job, &progress, nucleus_path, eosin_path, output_path)?;
+        let total = start.elapsed();
+        if run < bench.warmup {
+            println!("Warm-up {} of {}: {:.3} s", run + 1, bench.warmup, total.as_secs_f64());
+            continue;
+        }
+        println!("Render {} of {}: {:.3} s", run + 1 - bench.warmup, bench.iterations, total.as_secs_f64());
+        totals.push(total);
+        // A phase that runs several times in one render, e.g. for each component, counts once with
+        // the sum of its durations
+        let count = totals.len();
+        for (name, duration) in progress.phases() {
+            match phases.iter_mut().find(|(phase, _)| *phase == name) {
+                Some((_, durations)) if durations.len() == count => *durations.last_mut().expect("not empty") += duration,
+                Some((_, durations)) => durations.push(duration),
+                None => phases.push((name, vec![duration])),
+            }
+        }
+    }
+
+    println!();
+    let width = phases.iter().map(|(name, _)| name.len()).max().unwrap_or_default().max("Phase".len());
+    println!("{:<width$} {:>10} {:>10} {:>10} {:>10}", "Phase", "mean (s)", "min (s)", "max (s)", "MP/s");
+    let row = |name: &str, durations: &[Duration]| {
+        let seconds: Vec<f64> = durations.iter().map(Duration::as_secs_f64).collect();
+        let mean = seconds.iter().sum::<f64>() / seconds.len() as f64;
+        let (min, max) = seconds.iter().fold((f64::INFINITY, 0.0f64), |(min, max), &s| (min.min(s), max.max(s)));
+        println!("{:<width$} {:>10.3} {:>10.3} {:>10.3} {:>10.1}", name, mean, min, max, megapixels / mean);
+    };
+    for (name, durations) in &phases {
+        row(name, durations);
+    }
+    row("Total", &totals);
+    Ok(())
+}
@@ -0,0 +1,524 @@
+//! Checks of the options before anything is read: combinations of flags and subcommands, output
+//! paths and formats, and whether the estimated output and memory fit the disk and --max-memory.
+use super::batch::DEFAULT_OUTPUT_PATTERN;
+use super::job::{percentiles, Job, Paths};
+use super::output::output_files;
+use crate::Args;
+use image::ImageFormat;
+use std::fs;
+use std::path::{Path, PathBuf};
+use virtualhe::alpha::AlphaRule;
+use virtualhe::dzi::DziFormat;
+use virtualhe::{Dither, Error, LoadOptions, OutputDepth, StreamFormat, TiffCompression};
+
+/// Check the options that are not available in batch runs, with statistics, in previews, in served
+/// renders, in benchmarks or with --normalize.
+/// The options of `RenderParams` cannot name the arguments of the modes in their conflicts, as
+/// the subcommands without a mode have no such arguments.
+pub(crate) fn check_modes(args: &Args) -> Result<(), Error> {
+    let render = &args.render;
+    let (channels, eosin_inputs) = (!render.channels.is_empty(), !render.eosin_inputs.is_empty());
+    let (no_eosin, format, extra_channel) = (render.no_eosin, render.format.is_some(), render.extra_channel.is_some());
+    let modes = [
+        (
+            args.is_batch(),
+            "in batch runs",
+            vec![
+                ("--channel", channels),
+                ("--eosin", eosin_inputs),
+                ("--mask", render.mask.is_some()),
+                ("--auto-mask", render.auto_mask),
+                ("--annotations", render.annotations.is_some()),
+                ("--no-eosin", no_eosin),
+                ("--swap-channels", render.swap_channels),
+                ("--format", format),
+                ("--extra-channel", extra_channel),
+            ],
+        ),
+        (
+            args.stats_only,
+            "with statistics",
+            vec![
+                ("--channel", channels),
+                ("--no-eosin", no_eosin),
+                ("--save-components", render.save_components.is_some()),
+                ("--thumbnail", render.thumbnail.is_some()),
+                ("--output-dzi", render.output_dzi.is_some()),
+                ("--output-dicom", render.output_dicom.is_some()),
+                ("--scale-bar", render.scale_bar.is_some()),
+                ("--annotations", render.annotations.is_some()),
+                ("--rgba", render.rgba),
+                ("--gpu", render.gpu),
+                ("--format", format),
+                ("--extra-channel", extra_channel),
+            ],
+        ),
+        (
+            args.preview.is_some(),
+            "in previews",
+            vec![
+                ("--channel", channels),
+                ("--no-eosin", no_eosin),
+                ("--tiled", render.tiled),
+                ("--stack", render.stack),
+                ("--pyramid", render.pyramid),
+                ("--output-zarr", render.output_zarr),
+                ("--save-components", render.save_components.is_some()),
+                ("--output-dzi", render.output_dzi.is_some()),
+                ("--output-dicom", render.output_dicom.is_some()),
+                ("--scale-bar", render.scale_bar.is_some()),
+                ("--annotations", render.annotations.is_some()),
+                ("--rgba", render.rgba),
+                ("--output-depth 16", render.output_depth == OutputDepth::Sixteen),
+                ("--checksum", render.checksum),
+            ],
+        ),
+        (
+            args.is_serving(),
+            "in served renders",
+            vec![
+                ("--channel", channels),
+                ("--eosin", eosin_inputs),
+                ("--no-eosin", no_eosin),
+                ("--mask", render.mask.is_some()),
+                ("--annotations", render.annotations.is_some()),
+                ("--extra-channel", extra_channel),
+                ("--tiled", render.tiled),
+                ("--stack", render.stack),
+                ("--output-zarr", render.output_zarr),
+                ("--save-components", render.save_components.is_some()),
+                ("--thumbnail", render.thumbnail.is_some()),
+                ("--output-dzi", render.output_dzi.is_some()),
+                ("--output-dicom", render.output_dicom.is_some()),
+                ("--format", format),
+                ("--checksum", render.checksum),
+            ],
+        ),
+        (
+            args.bench.is_some(),
+            "in benchmarks",
+            vec![
+                ("--channel", channels),
+                ("--eosin", eosin_inputs),
+                ("--no-eosin", no_eosin),
+                ("--nucleus-channel", render.nucleus_channel.is_some()),
+                ("--eosin-channel", render.eosin_channel.is_some()),
+                ("--raw-dims", render.raw_dims.is_some()),
+                ("--mask", render.mask.is_some()),
+                ("--extra-channel", extra_channel),
+                ("--stack", render.stack),
+                ("--format", format),
+            ],
+        ),
+        (
+            args.normalize.is_some(),
+            "with --normalize",
+            vec![("--channel", channels), ("--eosin", eosin_inputs), ("--no-normalize", render.no_normalize)],
+        ),
+    ];
+    for (active, mode, options) in modes {
+        if let Some((flag, _)) = options.into_iter().find(|(_, given)| active && *given) {
+            return Err(Error::InvalidOptions(format!("{} is not available {}", flag, mode)));
+        }
+    }
+    Ok(())
+}
+
+/// Check that the encoder options fit the format of `output_path`.
+pub(crate) fn check_output(args: &Args, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // Output to stdout has no extension to infer the format from
+    let output_format = match (output_path == virtualhe::STDIO_PATH, args.render.format) {
+        (true, _) if args.render.tiled || args.render.stack || args.render.output_zarr => {
+            return Err(
+                Error::InvalidOptions("output to stdout is not available with --tiled, --stack or --output-zarr".to_string())
+                    .into(),
+            )
+        }
+        (true, Some(StreamFormat::Tiff)) => Some(ImageFormat::Tiff),
+        (true, Some(StreamFormat::Png)) => Some(ImageFormat::Png),
+        (true, None) => {
+            return Err(Error::InvalidOptions("output to stdout requires --format tiff or png".to_string()).into())
+        }
+        (false, Some(_)) => {
+            return Err(Error::InvalidOptions(format!(
+                "--format requires - as the output path, the format of {} is taken from its extension",
+                output_path
+            ))
+            .into())
+        }
+        (false, None) => ImageFormat::from_path(output_path).ok(),
+    };
+    if args.render.output_depth == OutputDepth::Sixteen
+        && !matches!(output_format, Some(ImageFormat::Tiff | ImageFormat::Png))
+        && !args.render.output_zarr
+    {
+        return Err(Error::InvalidOptions(format!("--output-depth 16 requires a TIFF or PNG output, got {}", output_path)).into());
+    }
+    if args.render.rgba && !matches!(output_format, Some(ImageFormat::Tiff | ImageFormat::Png)) {
+        let hint = if output_format == Some(ImageFormat::Jpeg) { ", JPEG has no alpha channel" } else { "" };
+        return Err(Error::InvalidOptions(format!("--rgba requires a TIFF or PNG output{}, got {}", hint, output_path)).into());
+    }
+    if args.render.alpha_rule == Some(AlphaRule::Mask) && args.render.mask.is_none() && !args.render.auto_mask {
+        return Err(Error::InvalidOptions("--alpha-rule mask requires --mask or --auto-mask".to_string()).into());
+    }
+    if args.render.checksum && output_path == virtualhe::STDIO_PATH {
+        return Err(Error::InvalidOptions("--checksum requires an output file, not stdout".to_string()).into());
+    }
+    if args.render.output_dzi.is_some() && output_path == virtualhe::STDIO_PATH {
+        return Err(Error::InvalidOptions("--output-dzi requires an output file, not stdout".to_string()).into());
+    }
+    if args.render.output_dicom.is_some() && output_path == virtualhe::STDIO_PATH {
+        return Err(Error::InvalidOptions("--output-dicom requires an output file, not stdout".to_string()).into());
+    }
+    let dzi_jpeg = args.render.output_dzi.is_some() && args.render.dzi_format.unwrap_or_default() == DziFormat::Jpeg;
+    if args.render.jpeg_quality.is_some() && output_format != Some(ImageFormat::Jpeg) && !dzi_jpeg {
+        return Err(Error::InvalidOptions(format!("--jpeg-quality requires a JPEG output, got {}", output_path)).into());
+    }
+    if args.render.pyramid && output_format != Some(ImageFormat::Tiff) {
+        return Err(Error::InvalidOptions(format!("--pyramid requires a TIFF output, got {}", output_path)).into());
+    }
+    if args.render.ome && output_format != Some(ImageFormat::Tiff) && !args.render.tiled {
+        return Err(Error::InvalidOptions(format!("--ome requires a TIFF output, got {}", output_path)).into());
+    }
+    if args.render.bigtiff && output_format != Some(ImageFormat::Tiff) && !args.render.tiled {
+        return Err(Error::InvalidOptions(format!("--bigtiff requires a TIFF output, got {}", output_path)).into());
+    }
+    let render = &args.render;
+    if render.compression.is_some() && output_format != Some(ImageFormat::Tiff) && !render.tiled && !render.output_zarr {
+        return Err(Error::InvalidOptions(format!("--compression requires a TIFF output, got {}", output_path)).into());
+    }
+    Ok(())
+}
+
+/// Refuse to replace an existing output without --force or --resume, checking the first image of
+/// a series and the thumbnail along with the output.
+pub(crate) fn check_overwrite(args: &Args, output_path: &str) -> Result<(), Error> {
+    if args.render.force || args.render.resume {
+        return Ok(());
+    }
+    match output_files(args, output_path).into_iter().find(|path| Path::new(path).exists()) {
+        Some(path) => Err(Error::InvalidOptions(format!("{} exists, use --force to overwrite it", path))),
+        None => Ok(()),
+    }
+}
+
+/// Check that the outputs can be written before any input is decoded: create the missing
+/// directories of the output files with --create-dirs, and create a temporary file in each.
+pub(crate) fn check_output_dirs(args: &Args, output_paths: &[&str]) -> Result<(), Error> {
+    let mut checked = Vec::new();
+    for path in output_paths.iter().flat_map(|output_path| output_files(args, output_path)) {
+        let dir = Path::new(&path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        if checked.contains(&dir.to_path_buf()) {
+            continue;
+        }
+        checked.push(dir.to_path_buf());
+        let write_error = |message: String| Error::Write {
+            path: PathBuf::from(&path),
+            message: format!("{}: {}", path, message),
+        };
+        if !dir.is_dir() {
+            if !args.render.create_dirs {
+                return Err(write_error(format!("directory {} does not exist, use --create-dirs to create it", dir.display())));
+            }
+            fs::create_dir_all(dir).map_err(|e| write_error(format!("cannot create directory {}: {}", dir.display(), e)))?;
+        }
+        virtualhe::atomic::check_writable(Path::new(&path))
+            .map_err(|e| write_error(format!("cannot create files in {}: {}", dir.display(), e)))?;
+    }
+    Ok(())
+}
+
+/// Estimated size in bytes of an output rendered from an input of `width` x `height` pixels,
+/// from the output depth and a compression ratio on the safe side for tissue images.
+pub(crate) fn estimated_output_size(args: &Args, output_path: &str, (width, height): (usize, usize)) -> u64 {
+    let render = &args.render;
+    let (width, height) = match render.roi {
+        Some(roi) => (roi.width.min(width), roi.height.min(height)),
+        None => (width, height),
+    };
+    let factor = render.downsample.unwrap_or(1) as usize;
+    let bytes = (width.div_ceil(factor) * height.div_ceil(factor) * 3 * (render.output_depth.bits() as usize / 8)) as f64;
+    let ratio = match ImageFormat::from_path(output_path) {
+        _ if render.output_zarr || render.format == Some(StreamFormat::Tiff) => 1.0,
+        Ok(ImageFormat::Tiff) if render.compression == Some(TiffCompression::None) => 1.0,
+        Ok(ImageFormat::Jpeg) => 0.2,
+        _ => 0.7,
+    };
+    // Lower resolutions add a third to a pyramid
+    let levels = if render.pyramid || render.output_zarr { 4.0 / 3.0 } else { 1.0 };
+    (bytes * ratio * levels) as u64
+}
+
+/// Warn when the estimated size of the outputs rendered from `inputs` exceeds the free space in
+/// the directory of the first output. Inputs whose size is not known from their header, and
+/// stacks, are left out.
+pub(crate) fn check_disk_space(args: &Args, options: &LoadOptions, inputs: &[(&str, &str)]) {
+    if args.render.stack {
+        return;
+    }
+    let Some((_, first_output)) = inputs.iter().find(|(_, output_path)| *output_path != virtualhe::STDIO_PATH) else {
+        return;
+    };
+    let size: u64 = inputs
+        .iter()
+        .filter_map(|(input, output_path)| {
+            virtualhe::input_dimensions(input, options).map(|size| estimated_output_size(args, output_path, size))
+        })
+        .sum();
+    let dir = Path::new(first_output).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let Some(available) = available_space(dir) else {
+        return;
+    };
+    log::debug!("{}: outputs estimated at {} bytes, {} bytes available", dir.display(), size, available);
+    if size > available {
+        log::warn!(
+            "The outputs are estimated at {:.1} GB but only {:.1} GB are free in {}",
+            size as f64 / 1e9,
+            available as f64 / 1e9,
+            dir.display()
+        );
+    }
+}
+
+/// Space in bytes available to unprivileged users on the file system of `dir`.
+#[cfg(unix)]
+pub(crate) fn available_space(dir: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    // SAFETY: the path is NUL-terminated and statvfs only writes into the zeroed struct
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Space in bytes available on the file system of `dir`, not known on this platform.
+#[cfg(not(unix))]
+pub(crate) fn available_space(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// Memory in bytes of --max-memory that one render may use, a share of it for each of the pairs of
+/// a batch rendered at the same time.
+pub(crate) fn memory_budget(args: &Args) -> Option<u64> {
+    let jobs = if args.batch_dir.is_some() || args.shared_norm.is_some() { u64::from(args.jobs) } else { 1 };
+    args.render.max_memory.map(|budget| budget / jobs)
+}
+
+/// Estimated memory in bytes of a whole-image and of a tiled render of inputs of `width` x
+/// `height` pixels, on the safe side. A whole-image render holds the decoded samples of every
+/// channel, counted at 16 bits, next to their 32bit float values, and the output samples with the
+/// copy they are encoded from. A tiled render holds the same for a band of tiles, the values of the
+/// percentiles kept exactly and, for OME-Zarr, a row of chunks of each level.
+pub(crate) fn estimated_memory(args: &Args, (width, height): (usize, usize)) -> (u64, u64) {
+    let render = &args.render;
+    let channels = render.channels.len().max(2) as u64;
+    let pixels = (width * height) as u64;
+    let (output_width, output_height) = match render.roi {
+        Some(roi) => (roi.width.min(width), roi.height.min(height)),
+        None => (width, height),
+    };
+    let factor = render.downsample.unwrap_or(1) as usize;
+    let output_pixels = (output_width.div_ceil(factor) * output_height.div_ceil(factor)) as u64;
+    let output_bytes = u64::from(render.output_depth.bits() / 8) * if render.rgba { 4 } else { 3 };
+    let whole_image = channels * pixels * 6 + output_pixels * output_bytes * 2;
+
+    let tile = u64::from(render.tile_size.unwrap_or(virtualhe::tiled::DEFAULT_TILE_SIZE));
+    let band = (tile * width as u64).min(pixels);
+    let exact = pixels.min(virtualhe::quantile::DEFAULT_EXACT_LIMIT as u64) * 4;
+    let chunk = u64::from(render.zarr_chunk_size.unwrap_or(virtualhe::DEFAULT_ZARR_CHUNK_SIZE));
+    let chunks = if render.output_zarr { chunk * width as u64 * output_bytes * 2 } else { 0 };
+    (whole_image, 2 * band * 6 + band * output_bytes * 2 + exact + chunks)
+}
+
+/// Amount of memory for messages, in MiB or GiB as --max-memory takes it.
+pub(crate) fn format_memory(bytes: u64) -> String {
+    if bytes < 1 << 30 {
+        format!("{:.0} MiB", bytes as f64 / f64::from(1u32 << 20))
+    } else {
+        format!("{:.2} GiB", bytes as f64 / f64::from(1u32 << 30))
+    }
+}
+
+/// Why a pair cannot be rendered tile by tile, for --max-memory: an input or output that is not a
+/// TIFF file, or an option that --tiled does not support.
+pub(crate) fn tiled_blocker(args: &Args, nucleus_path: &str, eosin_path: &str, output_path: &str) -> Option<String> {
+    let render = &args.render;
+    let options = [
+        (render.stack, "--stack"),
+        (!render.channels.is_empty(), "--channels"),
+        (!render.eosin_inputs.is_empty(), "--eosin-input"),
+        (render.no_eosin, "--no-eosin"),
+        (render.mask.is_some() || render.auto_mask, "a mask"),
+        (render.raw_dims.is_some(), "--raw-dims"),
+        (render.resample_to.is_some(), "--resample-to"),
+        (render.pyramid, "--pyramid"),
+        (render.save_components.is_some(), "--save-components"),
+        (render.thumbnail.is_some(), "--thumbnail"),
+        (render.scale_bar.is_some(), "--scale-bar"),
+        (render.annotations.is_some(), "--annotations"),
+        (render.rgba, "--rgba"),
+        (render.gpu, "--gpu"),
+        (render.checksum, "--checksum"),
+        (render.auto_k, "--auto-k"),
+        (render.flatfield_nucleus.is_some() || render.flatfield_eosin.is_some(), "flat-field correction"),
+        (render.darkfield.is_some(), "--darkfield"),
+        (render.shift_eosin.is_some() || render.auto_align, "aligning the channels"),
+        (render.roi.is_some(), "--roi"),
+        (render.crosstalk != [0.0, 0.0], "--crosstalk"),
+        (render.despeckle.is_some(), "--despeckle"),
+        (render.blur_nucleus > 0.0 || render.blur_eosin > 0.0, "blurring"),
+        (render.downsample.is_some_and(|factor| factor > 1), "--downsample"),
+        (render.equalize.is_some(), "--equalize"),
+        (render.auto_contrast.is_some(), "--auto-contrast"),
+        (render.extra_channel.is_some(), "--extra-channel"),
+        (render.dither == Dither::FloydSteinberg, "--dither floyd-steinberg"),
+    ];
+    if let Some((_, option)) = options.iter().find(|(set, _)| *set) {
+        return Some(format!("{} is not supported tile by tile", option));
+    }
+    for path in [nucleus_path, eosin_path] {
+        if ImageFormat::from_path(path).ok() != Some(ImageFormat::Tiff) || !Path::new(path).is_file() {
+            return Some(format!("the input {} is not a TIFF file", path));
+        }
+    }
+    let tiff = ImageFormat::from_path(output_path).ok() == Some(ImageFormat::Tiff);
+    if !render.output_zarr && (output_path == virtualhe::STDIO_PATH || !tiff) {
+        return Some(format!("the output {} is not a TIFF file or an OME-Zarr store", output_path));
+    }
+    None
+}
+
+/// Check the estimated memory of a render against --max-memory, returning why the pair is rendered
+/// tile by tile without --tiled if a whole-image render would need more than the budget. Fails when
+/// the render does not fit the budget either way. Inputs whose size is not known from their header
+/// are not checked.
+pub(crate) fn tiled_fallback(
+    args: &Args,
+    job: &Job,
+    nucleus_path: &str,
+    eosin_path: &str,
+    output_path: &str,
+) -> Result<Option<String>, Error> {
+    let (Some(budget), false) = (memory_budget(args), args.render.stack) else {
+        return Ok(None);
+    };
+    let Some(size) = virtualhe::input_dimensions(nucleus_path, &job.nucleus_options) else {
+        log::debug!("{}: size not known before decoding, not checked against --max-memory", nucleus_path);
+        return Ok(None);
+    };
+    let (whole_image, tiled) = estimated_memory(args, size);
+    log::debug!(
+        "{}: estimated at {} as a whole image and {} tile by tile, {} available",
+        output_path,
+        format_memory(whole_image),
+        format_memory(tiled),
+        format_memory(budget)
+    );
+    let tiled_fits = tiled <= budget;
+    if args.render.tiled && !tiled_fits {
+        return Err(Error::InvalidOptions(format!(
+            "{}: rendering tile by tile needs an estimated {}, more than the {} of --max-memory, use a smaller --tile-size",
+            output_path,
+            format_memory(tiled),
+            format_memory(budget)
+        )));
+    }
+    if args.render.tiled || whole_image <= budget {
+        return Ok(None);
+    }
+    let needs = format!(
+        "needs an estimated {}, more than the {} of --max-memory",
+        format_memory(whole_image),
+        format_memory(budget)
+    );
+    match tiled_blocker(args, nucleus_path, eosin_path, output_path) {
+        Some(blocker) => Err(Error::InvalidOptions(format!(
+            "{}: the whole image {} and cannot be rendered tile by tile: {}",
+            output_path, needs, blocker
+        ))),
+        None if !tiled_fits => Err(Error::InvalidOptions(format!(
+            "{}: the whole image {} and rendering tile by tile needs {}, use a smaller --tile-size",
+            output_path,
+            needs,
+            format_memory(tiled)
+        ))),
+        None => Ok(Some(format!("the whole image {}, tiles need {}", needs, format_memory(tiled)))),
+    }
+}
+
+/// Check the paths and options of a run before any input is read: that stdin is read at most once,
+/// that the percentiles leave a window, and that the encoder options fit the output format, in a
+/// batch that of the output pattern.
+pub(crate) fn check_args(args: &Args, paths: Option<&Paths>) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(thumbnail) = args.render.thumbnail.as_ref().filter(|_| args.is_batch()) {
+        if !thumbnail.path.contains("{name}") {
+            return Err(Error::InvalidOptions(format!(
+                "--thumbnail in batch runs needs {{name}} in the path to tell the thumbnails apart, got {}",
+                thumbnail.path
+            ))
+            .into());
+        }
+    }
+
+    // stdin can be read only once
+    let inputs = match paths {
+        Some((nucleus, eosin, _)) if args.render.eosin_inputs.is_empty() => vec![nucleus.as_str(), eosin.as_str()],
+        Some((nucleus, _, _)) => vec![nucleus.as_str()],
+        None => Vec::new(),
+    };
+    let stdin_inputs = inputs
+        .into_iter()
+        .chain(args.render.eosin_inputs.iter().map(|input| input.path.as_str()))
+        .chain(args.render.channels.iter().map(|channel| channel.path.as_str()))
+        .chain(args.render.extra_channel.as_deref())
+        .filter(|path| *path == virtualhe::STDIO_PATH)
+        .count();
+    if stdin_inputs > 1 {
+        return Err(Error::InvalidOptions("only one input can be read from stdin".to_string()).into());
+    }
+    if stdin_inputs == 1 && (args.render.tiled || args.render.stack) {
+        return Err(Error::InvalidOptions("input from stdin is not available with --tiled or --stack".to_string()).into());
+    }
+
+    let weighted = args.render.eosin_inputs.iter().filter(|input| input.weight.is_some()).count();
+    if weighted != 0 && weighted != args.render.eosin_inputs.len() {
+        return Err(Error::InvalidOptions("give weights for all --eosin inputs or for none".to_string()).into());
+    }
+
+    // Validate the scaling windows before reading any images
+    for (name, (percentile, floor)) in ["nucleus", "eosin"].into_iter().zip(percentiles(args)) {
+        if let Some(floor) = floor {
+            if floor >= percentile {
+                return Err(Error::InvalidOptions(format!(
+                    "{} floor percentile ({}) must be below the saturation percentile ({})",
+                    name, floor, percentile
+                ))
+                .into());
+            }
+        }
+    }
+
+    // Check that the encoder options fit the output format, in a batch that of the output pattern
+    match paths {
+        Some(_) if args.stats_only => {}
+        Some((_, _, output_path)) => {
+            check_output(args, output_path)?;
+            check_overwrite(args, output_path)?;
+            check_output_dirs(args, &[output_path])?;
+        }
+        // The outputs of --shared-norm are checked as the list is read
+        None if args.shared_norm.is_some() => {}
+        None => check_output(args, args.output_pattern.as_deref().unwrap_or(DEFAULT_OUTPUT_PATTERN))?,
+    }
+    if args.render.output_zarr && matches!(args.render.compression, Some(TiffCompression::Lzw | TiffCompression::Zstd)) {
+        return Err(Error::InvalidOptions("--output-zarr supports --compression none or deflate".to_string()).into());
+    }
+    if args.render.gpu && !cfg!(feature = "gpu") {
+        return Err(Error::InvalidOptions("--gpu requires a build with the gpu feature".to_string()).into());
+    }
+    args.render.compression.unwrap_or_default().check_supported().map_err(Error::InvalidOptions)?;
+    Ok(())
+}
@@ -0,0 +1,187 @@
+//! Checksums of --checksum and the `verify` subcommand: the SHA-256 of the samples of a rendered
+//! image before encoding, which does not depend on the byte layout of the file, e.g. its
+//! compression or strips, and the SHA-256 of the encoded file, both printed and recorded in the
+//! provenance file. `verify` hashes the file and the samples it decodes to again and compares them
+//! with the provenance file, so that corruption in transit, bit rot, or a re-encoding that changed
+//! the pixels can be told apart from a lossless re-encoding that only changed the file.
+use super::provenance;
+use image::{DynamicImage, ImageReader};
+use ndarray::Array3;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use virtualhe::{Error, OutputSample};
+
+/// Samples hashed at a time, converted to little-endian bytes.
+const HASH_CHUNK: usize = 1 << 16;
+
+/// Arguments of the `verify` subcommand.
+#[derive(clap::Args, Debug)]
+pub(crate) struct VerifyArgs {
+    /// Path to the rendered image.
+    output: String,
+    /// Path to its provenance file [default: <output>.json].
+    #[arg(long)]
+    provenance: Option<String>,
+}
+
+/// Checksums of a rendered image as recorded in its provenance file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Checksum {
+    /// SHA-256 of the samples before encoding, row by row and RGB or RGBA for each pixel, 16bit
+    /// samples in little-endian byte order.
+    raw_sha256: String,
+    width: usize,
+    height: usize,
+    samples_per_pixel: usize,
+    bits_per_sample: u16,
+    /// SHA-256 of the encoded file.
+    file_sha256: Option<String>,
+}
+
+impl Checksum {
+    /// Checksum of the samples of a rendered (row, column, RGB) or (row, column, RGBA) array,
+    /// without the hash of the file it is not encoded into yet.
+    pub(crate) fn of_samples<T: OutputSample>(rgb: &Array3<T>) -> Self {
+        let (height, width, samples_per_pixel) = rgb.dim();
+        let raw_sha256 = match rgb.as_slice() {
+            Some(samples) => raw_sha256(samples),
+            None => raw_sha256(rgb.as_standard_layout().as_slice().expect("standard layout")),
+        };
+        Checksum {
+            raw_sha256,
+            width,
+            height,
+            samples_per_pixel,
+            bits_per_sample: T::BITS,
+            file_sha256: None,
+        }
+    }
+
+    /// Size and hash of the samples, for messages.
+    fn samples(&self) -> String {
+        format!(
+            "{} x {} pixels of {} {}bit samples with SHA-256 {}",
+            self.width, self.height, self.samples_per_pixel, self.bits_per_sample, self.raw_sha256
+        )
+    }
+
+    /// The checksum with the hash of the file the image was encoded into at `path`.
+    pub(crate) fn with_file(self, path: &str) -> Result<Self, Error> {
+        Ok(Checksum {
+            file_sha256: provenance::sha256(path)?,
+            ..self
+        })
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SHA-256 of the samples: {}", self.raw_sha256)?;
+        if let Some(file_sha256) = &self.file_sha256 {
+            write!(f, "\nSHA-256 of the file: {}", file_sha256)?;
+        }
+        Ok(())
+    }
+}
+
+/// SHA-256 of samples as lowercase hex, each sample in little-endian byte order.
+fn raw_sha256<T: OutputSample>(samples: &[T]) -> String {
+    let bytes = usize::from(T::BITS / 8);
+    let mut hasher = Sha256::new();
+    let mut buffer = Vec::with_capacity(HASH_CHUNK * bytes);
+    for chunk in samples.chunks(HASH_CHUNK) {
+        buffer.clear();
+        buffer.extend(chunk.iter().flat_map(|&v| v.into().to_le_bytes().into_iter().take(bytes)));
+        hasher.update(&buffer);
+    }
+    provenance::hex(&hasher.finalize())
+}
+
+/// Verify the file and the decoded samples of a rendered image against the checksum of its
+/// provenance file, failing if either differs. The samples of JPEG outputs are not compared, as
+/// lossy encoding does not keep them.
+pub(crate) fn verify(args: &VerifyArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let provenance_path = args.provenance.clone().unwrap_or_else(|| provenance::path_for(&args.output));
+    let open_error = |source| Error::Open {
+        path: provenance_path.clone().into(),
+        source,
+    };
+    let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&provenance_path).map_err(open_error)?)
+        .map_err(|e| Error::Decode {
+            path: provenance_path.clone().into(),
+            message: format!("{}: {}", provenance_path, e),
+        })?;
+    let expected: Checksum = match json.get("checksum") {
+        Some(checksum) if !checksum.is_null() => serde_json::from_value(checksum.clone()).map_err(|e| Error::Decode {
+            path: provenance_path.clone().into(),
+            message: format!("{}: checksum: {}", provenance_path, e),
+        })?,
+        _ => {
+            return Err(Error::InvalidOptions(format!(
+                "{} holds no checksum, render {} with --checksum",
+                provenance_path, args.output
+            ))
+            .into())
+        }
+    };
+
+    let mut failures = Vec::new();
+    let file_sha256 = provenance::sha256(&args.output)?;
+    match (&expected.file_sha256, &file_sha256) {
+        (Some(expected), Some(actual)) if expected == actual => println!("File: ok"),
+        (Some(expected), actual) => {
+            println!("File: MISMATCH, SHA-256 {} instead of {}", actual.as_deref().unwrap_or("none"), expected);
+            failures.push("file");
+        }
+        (None, _) => println!("File: no checksum recorded"),
+    }
+
+    let open_error = |source| Error::Open {
+        path: args.output.clone().into(),
+        source,
+    };
+    let reader = ImageReader::open(&args.output).map_err(open_error)?.with_guessed_format().map_err(open_error)?;
+    if reader.format() == Some(image::ImageFormat::Jpeg) {
+        println!("Samples: not verified, JPEG encoding does not keep them");
+    } else {
+        let image = reader.decode().map_err(|e| Error::Decode {
+            path: args.output.clone().into(),
+            message: format!("{}: {}", args.output, e),
+        })?;
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let (raw_sha256, samples_per_pixel, bits_per_sample) = match &image {
+            DynamicImage::ImageRgb8(image) => (raw_sha256(image.as_raw()), 3, 8),
+            DynamicImage::ImageRgba8(image) => (raw_sha256(image.as_raw()), 4, 8),
+            DynamicImage::ImageRgb16(image) => (raw_sha256(image.as_raw()), 3, 16),
+            DynamicImage::ImageRgba16(image) => (raw_sha256(image.as_raw()), 4, 16),
+            other => {
+                return Err(Error::UnsupportedFormat {
+                    path: args.output.clone().into(),
+                    message: format!("{}: {:?} is not a rendered RGB or RGBA image", args.output, other.color()),
+                }
+                .into())
+            }
+        };
+        let actual = Checksum {
+            raw_sha256,
+            width,
+            height,
+            samples_per_pixel,
+            bits_per_sample,
+            file_sha256: expected.file_sha256.clone(),
+        };
+        if actual == expected {
+            println!("Samples: ok, {}", actual.samples());
+        } else {
+            println!("Samples: MISMATCH, {} instead of {}", actual.samples(), expected.samples());
+            failures.push("samples");
+        }
+    }
+    if !failures.is_empty() {
+        let verb = if failures.len() == 1 && failures[0] == "file" { "does" } else { "do" };
+        return Err(format!("{}: the {} {} not match {}", args.output, failures.join(" and the "), verb, provenance_path).into());
+    }
+    println!("{} matches {}", args.output, provenance_path);
+    Ok(())
+}
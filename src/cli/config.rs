@@ -0,0 +1,563 @@
+//! Parameter files read with --config: rendering settings in TOML, or JSON for `.json` files, named
+//! as the command-line flags with underscores. Flags given on the command line take precedence.
+use super::parse::{
+    format_pixel_size, format_rgb, parse_beta, parse_clahe_clip, parse_clahe_tile_size, parse_crosstalk,
+    parse_despeckle, parse_downsample, parse_floor_percentile, parse_gamma, parse_input_max, parse_k, parse_percentile,
+    parse_pixel_size, parse_range, parse_saturation, parse_shift, parse_sigma, parse_tile_size, parse_tolerance,
+    parse_transmittance, InputChannel, ResampleTarget,
+};
+use crate::RenderParams;
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::path::Path;
+use virtualhe::alpha::AlphaRule;
+use virtualhe::{
+    AutoContrast, ColorEncoding, Dither, Equalization, Error, NanPolicy, OutputDepth, PercentileMethod, Profile,
+    RgbChannel, TiffCompression,
+};
+
+/// Commented template written by --write-default-config, every setting at its default.
+pub(crate) const DEFAULT_CONFIG: &str = r#"# virtualhe parameter file, use with --config params.toml
+# Settings are named as the command-line flags, flags on the command line take precedence.
+
+# Color profile preset selecting the beta matrix and k (see --list-profiles)
+# profile = "he-classic"
+
+# K factors, k sets both channels [default: from profile]
+# k = 2.5
+# k_nucleus = 2.5
+# k_eosin = 2.5
+
+# Estimate the k of the channels without k_nucleus or k_eosin so that their median tissue pixel
+# renders at the target transmittance in the green channel
+# auto_k = false
+# target_transmittance = 0.55
+
+# Beta coefficients as [red, green, blue] [default: from profile]
+# beta_hematoxylin = [0.86, 1.0, 0.3]
+# beta_eosin = [0.05, 1.0, 0.544]
+
+# K factor and beta coefficients of an --extra-channel [default: DAB]
+# extra_k = 2.5
+# extra_beta = [0.268, 0.57, 0.776]
+
+# Saturation percentiles in (0, 100], percentile sets both channels
+# percentile = 99.999
+# percentile_nucleus = 99.999
+# percentile_eosin = 99.999
+
+# Background floor percentiles in [0, 100) [default: none]
+# floor_percentile = 1.0
+# floor_percentile_nucleus = 1.0
+# floor_percentile_eosin = 1.0
+
+# Fixed intensity windows as [min, max] in input units, used instead of the percentiles [default: none]
+# nucleus_range = [100.0, 4000.0]
+# eosin_range = [100.0, 4000.0]
+
+# Bleed-through coefficients [a, b] subtracted before scaling: eosin - a * nucleus and nucleus - b * eosin
+# crosstalk = [0.0, 0.0]
+
+# Translation [dx, dy] in pixels registering the eosin channel onto the nucleus channel, or its
+# estimate by cross-correlation [default: none]
+# shift_eosin = [2.0, -1.5]
+# auto_align = false
+
+# Edge length of a median filter against hot pixels, 3 or 5, and the only channel to apply it to
+# [default: none, both channels]
+# despeckle = 3
+# despeckle_channel = "eosin"
+
+# Standard deviation in pixels of a Gaussian blur before scaling, 0 is off
+# blur_nucleus = 0.0
+# blur_eosin = 0.0
+
+# Factor both channels are downsampled by after decoding, averaging blocks of pixels [default: 1]
+# downsample = 8
+
+# Gamma applied as v^(1/gamma) after scaling, above 1 brightens dim signal
+# gamma_nucleus = 1.0
+# gamma_eosin = 1.0
+
+# Histogram equalization before the percentile scaling, "global" or "clahe", the only channel to
+# apply it to, and the tile size and clip limit of CLAHE [default: none, both channels]
+# equalize = "clahe"
+# equalize_channel = "eosin"
+# clahe_tile_size = 64
+# clahe_clip = 2.0
+
+# Skip the percentile scaling of channels without a fixed range, for inputs normalized upstream
+# no_normalize = false
+
+# Select the window of each channel from its 1st to 99.9th percentile over the tissue pixels above
+# its Otsu threshold, instead of the percentiles [default: none]
+# auto_contrast = "otsu"
+
+# Compute the percentiles over a tissue mask from an Otsu threshold of the eosin channel
+# auto_mask = false
+
+# Percentage of saturated pixels of a channel above which to warn, or to fail [default: no limit]
+# saturation_warning = 1.0
+# strict_saturation = 0.5
+
+# NaN handling: zero, error, or ignore
+# nan_policy = "zero"
+
+# Values at the percentiles: linear (interpolated, as numpy), lower, nearest, or legacy
+# percentile_method = "linear"
+
+# Input range, at most one of input_max, input_bits and auto_range [default: container maximum]
+# input_max = 4095.0
+# input_bits = 12
+# auto_range = false
+
+# Invert pre-inverted inputs with a bright background to max - v before the normalization
+# invert_nucleus = false
+# invert_eosin = false
+
+# Channel of RGB(A) inputs: r, g or b [default: convert to grayscale]
+# nucleus_rgb_channel = "b"
+# eosin_rgb_channel = "g"
+
+# Crop both channels to their overlapping region when their sizes differ
+# crop_to_common = false
+
+# Resample channels of different resolutions onto the grid of nucleus, eosin, or a size "WxH",
+# unless their aspect ratios differ by more than the tolerance in percent [default: none]
+# resample_to = "nucleus"
+# aspect_tolerance = 1.0
+
+# Encoding of the output colors: srgb, or linear for the transmittance values as they are
+# color_encoding = "srgb"
+
+# Evaluate the exponentials of the color model directly instead of interpolating them in lookup tables
+# exact = false
+
+# Dithering of the quantization to the output samples: none, ordered, or floyd-steinberg
+# dither = "none"
+
+# Output bits per channel: 8, or 16 for TIFF and PNG outputs
+# output_depth = 8
+
+# RGBA PNG or TIFF outputs with transparent glass, the alpha channel derived by luminance or from
+# the tissue mask of --mask or auto_mask
+# rgba = false
+# alpha_rule = "luminance"
+
+# Compression of TIFF outputs: none, lzw, deflate, or zstd
+# compression = "deflate"
+
+# Quality of JPEG outputs in 1..=100
+# jpeg_quality = 90
+
+# Tile edge length in pixels, a multiple of 16 [default: 2048 for --tiled, 512 for --pyramid]
+# tile_size = 512
+
+# Physical pixel size in micrometers, or [x, y] for non-square pixels [default: from the nucleus TIFF]
+# pixel_size_um = 0.325
+"#;
+
+/// Settings of a parameter file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    profile: Option<String>,
+    k: Option<f32>,
+    k_nucleus: Option<f32>,
+    k_eosin: Option<f32>,
+    auto_k: Option<bool>,
+    target_transmittance: Option<f32>,
+    beta_hematoxylin: Option<[f32; 3]>,
+    beta_eosin: Option<[f32; 3]>,
+    extra_k: Option<f32>,
+    extra_beta: Option<[f32; 3]>,
+    percentile: Option<f32>,
+    percentile_nucleus: Option<f32>,
+    percentile_eosin: Option<f32>,
+    floor_percentile: Option<f32>,
+    floor_percentile_nucleus: Option<f32>,
+    floor_percentile_eosin: Option<f32>,
+    nucleus_range: Option<[f32; 2]>,
+    eosin_range: Option<[f32; 2]>,
+    crosstalk: Option<[f32; 2]>,
+    shift_eosin: Option<[f32; 2]>,
+    auto_align: Option<bool>,
+    despeckle: Option<usize>,
+    despeckle_channel: Option<String>,
+    blur_nucleus: Option<f32>,
+    blur_eosin: Option<f32>,
+    downsample: Option<u32>,
+    gamma_nucleus: Option<f32>,
+    gamma_eosin: Option<f32>,
+    equalize: Option<String>,
+    equalize_channel: Option<String>,
+    clahe_tile_size: Option<usize>,
+    clahe_clip: Option<f32>,
+    no_normalize: Option<bool>,
+    auto_contrast: Option<String>,
+    auto_mask: Option<bool>,
+    saturation_warning: Option<f32>,
+    strict_saturation: Option<f32>,
+    nan_policy: Option<String>,
+    percentile_method: Option<String>,
+    input_max: Option<f32>,
+    input_bits: Option<u8>,
+    auto_range: Option<bool>,
+    invert_nucleus: Option<bool>,
+    invert_eosin: Option<bool>,
+    nucleus_rgb_channel: Option<String>,
+    eosin_rgb_channel: Option<String>,
+    crop_to_common: Option<bool>,
+    resample_to: Option<String>,
+    aspect_tolerance: Option<f32>,
+    color_encoding: Option<String>,
+    exact: Option<bool>,
+    dither: Option<String>,
+    output_depth: Option<u8>,
+    rgba: Option<bool>,
+    alpha_rule: Option<String>,
+    compression: Option<String>,
+    jpeg_quality: Option<u8>,
+    tile_size: Option<u32>,
+    pixel_size_um: Option<PixelSize>,
+}
+
+/// Pixel size of a parameter file, one size for both axes or an [x, y] pair.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PixelSize {
+    Square(f32),
+    Axes([f32; 2]),
+}
+
+impl std::fmt::Display for PixelSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PixelSize::Square(size) => write!(f, "{}", size),
+            PixelSize::Axes(axes) => f.write_str(&format_pixel_size(*axes)),
+        }
+    }
+}
+
+impl Config {
+    /// Read a parameter file, JSON if its extension is `.json` and TOML otherwise.
+    pub(crate) fn load(path: &str) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path).map_err(|source| Error::Open {
+            path: path.into(),
+            source,
+        })?;
+        let json = Path::new(path).extension().is_some_and(|e| e.eq_ignore_ascii_case("json"));
+        Self::parse(&text, json, path)
+    }
+
+    /// Parse the settings of a parameter file, JSON or TOML, named `source` in errors.
+    pub(crate) fn parse(text: &str, json: bool, source: &str) -> Result<Self, Error> {
+        let config = if json {
+            serde_json::from_str(text).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(text).map_err(|e| e.to_string())
+        };
+        config.map_err(|e| Error::InvalidOptions(format!("{}: {}", source, e.trim_end())))
+    }
+
+    /// Apply the settings to the parsed arguments, except for those given on the command line.
+    ///
+    /// Values are checked as the flags of the same name. A flag that sets both channels, or
+    /// the profile, also takes precedence over the per-channel settings it implies.
+    pub(crate) fn apply(self, args: &mut RenderParams, matches: &ArgMatches) -> Result<(), Error> {
+        let cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        let set = Setter { matches };
+
+        // The input range flags exclude each other, so one on the command line replaces them all
+        let range = [self.input_max.is_some(), self.input_bits.is_some(), self.auto_range == Some(true)];
+        if range.iter().filter(|set| **set).count() > 1 {
+            return Err(Error::InvalidOptions(
+                "parameter file sets more than one of input_max, input_bits and auto_range".to_string(),
+            ));
+        }
+        if !(cli("input_max") || cli("input_bits") || cli("auto_range")) {
+            set.value(&mut args.input_max, "input_max", self.input_max, |s| parse_input_max(s).map(Some))?;
+            set.value(&mut args.input_bits, "input_bits", self.input_bits, |s| {
+                s.parse::<u8>().ok().filter(|bits| (1..=32).contains(bits)).map(Some).ok_or_else(|| {
+                    format!("input bits must be in 1..=32, got {}", s)
+                })
+            })?;
+            set.value(&mut args.auto_range, "auto_range", self.auto_range, parse_bool)?;
+        }
+        set.value(&mut args.invert_nucleus, "invert_nucleus", self.invert_nucleus, parse_bool)?;
+        set.value(&mut args.invert_eosin, "invert_eosin", self.invert_eosin, parse_bool)?;
+
+        // The profile supplies k and beta, so a profile on the command line replaces them too
+        set.value(&mut args.profile, "profile", self.profile, str::parse::<Profile>)?;
+        if !cli("profile") {
+            if !cli("k") {
+                set.value(&mut args.k_nucleus, "k_nucleus", self.k_nucleus, |s| parse_k(s).map(Some))?;
+                set.value(&mut args.k_eosin, "k_eosin", self.k_eosin, |s| parse_k(s).map(Some))?;
+            }
+            set.value(&mut args.k, "k", self.k, |s| parse_k(s).map(Some))?;
+            let beta = |s: &str| parse_beta(s).map(Some);
+            set.value(&mut args.beta_hematoxylin, "beta_hematoxylin", self.beta_hematoxylin.map(format_rgb), beta)?;
+            set.value(&mut args.beta_eosin, "beta_eosin", self.beta_eosin.map(format_rgb), beta)?;
+        }
+        // -k on the command line replaces the estimate, which it excludes
+        if !cli("k") {
+            set.value(&mut args.auto_k, "auto_k", self.auto_k, parse_bool)?;
+        }
+        set.value(&mut args.target_transmittance, "target_transmittance", self.target_transmittance, |s| {
+            parse_transmittance(s).map(Some)
+        })?;
+        set.value(&mut args.extra_k, "extra_k", self.extra_k, |s| parse_k(s).map(Some))?;
+        set.value(&mut args.extra_beta, "extra_beta", self.extra_beta.map(format_rgb), |s| parse_beta(s).map(Some))?;
+
+        if !cli("percentile") {
+            let percentile = |s: &str| parse_percentile(s).map(Some);
+            set.value(&mut args.percentile_nucleus, "percentile_nucleus", self.percentile_nucleus, percentile)?;
+            set.value(&mut args.percentile_eosin, "percentile_eosin", self.percentile_eosin, percentile)?;
+        }
+        set.value(&mut args.percentile, "percentile", self.percentile, parse_percentile)?;
+        let floor = |s: &str| parse_floor_percentile(s).map(Some);
+        if !cli("floor_percentile") {
+            set.value(&mut args.floor_percentile_nucleus, "floor_percentile_nucleus", self.floor_percentile_nucleus, floor)?;
+            set.value(&mut args.floor_percentile_eosin, "floor_percentile_eosin", self.floor_percentile_eosin, floor)?;
+        }
+        set.value(&mut args.floor_percentile, "floor_percentile", self.floor_percentile, floor)?;
+
+        // A fixed window replaces the percentiles of its channel, so percentiles on the command line replace it
+        let range = |s: &str| parse_range(s).map(Some);
+        let format_range = |[min, max]: [f32; 2]| format!("{},{}", min, max);
+        if !(cli("percentile_nucleus") || cli("floor_percentile_nucleus")) {
+            set.value(&mut args.nucleus_range, "nucleus_range", self.nucleus_range.map(format_range), range)?;
+        }
+        if !(cli("percentile_eosin") || cli("floor_percentile_eosin")) {
+            set.value(&mut args.eosin_range, "eosin_range", self.eosin_range.map(format_range), range)?;
+        }
+        set.value(&mut args.crosstalk, "crosstalk", self.crosstalk.map(format_range), parse_crosstalk)?;
+        // A shift on the command line replaces the estimate and the other way around
+        if !cli("auto_align") {
+            set.value(&mut args.shift_eosin, "shift_eosin", self.shift_eosin.map(format_range), |s| {
+                parse_shift(s).map(Some)
+            })?;
+        }
+        if !cli("shift_eosin") {
+            set.value(&mut args.auto_align, "auto_align", self.auto_align, parse_bool)?;
+        }
+        set.value(&mut args.despeckle, "despeckle", self.despeckle, |s| parse_despeckle(s).map(Some))?;
+        set.value(&mut args.despeckle_channel, "despeckle_channel", self.despeckle_channel, |s| {
+            s.parse::<InputChannel>().map(Some)
+        })?;
+        set.value(&mut args.blur_nucleus, "blur_nucleus", self.blur_nucleus, parse_sigma)?;
+        set.value(&mut args.blur_eosin, "blur_eosin", self.blur_eosin, parse_sigma)?;
+        set.value(&mut args.downsample, "downsample", self.downsample, |s| parse_downsample(s).map(Some))?;
+        set.value(&mut args.gamma_nucleus, "gamma_nucleus", self.gamma_nucleus, parse_gamma)?;
+        set.value(&mut args.gamma_eosin, "gamma_eosin", self.gamma_eosin, parse_gamma)?;
+        set.value(&mut args.equalize, "equalize", self.equalize, |s| s.parse::<Equalization>().map(Some))?;
+        set.value(&mut args.equalize_channel, "equalize_channel", self.equalize_channel, |s| {
+            s.parse::<InputChannel>().map(Some)
+        })?;
+        set.value(&mut args.clahe_tile_size, "clahe_tile_size", self.clahe_tile_size, parse_clahe_tile_size)?;
+        set.value(&mut args.clahe_clip, "clahe_clip", self.clahe_clip, parse_clahe_clip)?;
+        // Percentiles on the command line replace --no-normalize and --auto-contrast, which exclude them
+        let percentiles = [
+            "percentile",
+            "percentile_nucleus",
+            "percentile_eosin",
+            "floor_percentile",
+            "floor_percentile_nucleus",
+            "floor_percentile_eosin",
+        ];
+        if !percentiles.iter().any(|id| cli(id)) {
+            set.value(&mut args.no_normalize, "no_normalize", self.no_normalize, parse_bool)?;
+            set.value(&mut args.auto_contrast, "auto_contrast", self.auto_contrast, |s| s.parse::<AutoContrast>().map(Some))?;
+        }
+        // A mask file on the command line replaces the automatic mask
+        if !cli("mask") {
+            set.value(&mut args.auto_mask, "auto_mask", self.auto_mask, parse_bool)?;
+        }
+        set.value(&mut args.saturation_warning, "saturation_warning", self.saturation_warning, parse_saturation)?;
+        set.value(&mut args.strict_saturation, "strict_saturation", self.strict_saturation, |s| {
+            parse_saturation(s).map(Some)
+        })?;
+        set.value(&mut args.nan_policy, "nan_policy", self.nan_policy, str::parse::<NanPolicy>)?;
+        set.value(&mut args.percentile_method, "percentile_method", self.percentile_method, str::parse::<PercentileMethod>)?;
+        let rgb_channel = |s: &str| s.parse::<RgbChannel>().map(Some);
+        set.value(&mut args.nucleus_rgb_channel, "nucleus_rgb_channel", self.nucleus_rgb_channel, rgb_channel)?;
+        set.value(&mut args.eosin_rgb_channel, "eosin_rgb_channel", self.eosin_rgb_channel, rgb_channel)?;
+        set.value(&mut args.crop_to_common, "crop_to_common", self.crop_to_common, parse_bool)?;
+        // Cropping on the command line replaces the resampling
+        if !cli("crop_to_common") {
+            set.value(&mut args.resample_to, "resample_to", self.resample_to, |s| s.parse::<ResampleTarget>().map(Some))?;
+        }
+        set.value(&mut args.aspect_tolerance, "aspect_tolerance", self.aspect_tolerance, parse_tolerance)?;
+        set.value(&mut args.color_encoding, "color_encoding", self.color_encoding, str::parse::<ColorEncoding>)?;
+        set.value(&mut args.exact, "exact", self.exact, parse_bool)?;
+        set.value(&mut args.dither, "dither", self.dither, str::parse::<Dither>)?;
+        set.value(&mut args.output_depth, "output_depth", self.output_depth, str::parse::<OutputDepth>)?;
+        set.value(&mut args.rgba, "rgba", self.rgba, parse_bool)?;
+        set.value(&mut args.alpha_rule, "alpha_rule", self.alpha_rule, |s| s.parse::<AlphaRule>().map(Some))?;
+        set.value(&mut args.compression, "compression", self.compression, |s| {
+            s.parse::<TiffCompression>().map(Some)
+        })?;
+        set.value(&mut args.jpeg_quality, "jpeg_quality", self.jpeg_quality, |s| {
+            s.parse::<u8>().ok().filter(|quality| (1..=100).contains(quality)).map(Some).ok_or_else(|| {
+                format!("JPEG quality must be in 1..=100, got {}", s)
+            })
+        })?;
+        set.value(&mut args.tile_size, "tile_size", self.tile_size, |s| parse_tile_size(s).map(Some))?;
+        set.value(&mut args.pixel_size_um, "pixel_size_um", self.pixel_size_um, |s| parse_pixel_size(s).map(Some))?;
+        Ok(())
+    }
+}
+
+/// Assigns parameter file values to the arguments that were not given on the command line.
+struct Setter<'a> {
+    matches: &'a ArgMatches,
+}
+
+impl Setter<'_> {
+    /// Set `target` from `value`, parsed as the value of the flag `id` would be.
+    fn value<T, V: ToString>(
+        &self,
+        target: &mut T,
+        id: &str,
+        value: Option<V>,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<(), Error> {
+        let Some(value) = value else {
+            return Ok(());
+        };
+        if self.matches.value_source(id) == Some(ValueSource::CommandLine) {
+            return Ok(());
+        }
+        *target = parse(&value.to_string()).map_err(|e| Error::InvalidOptions(format!("parameter file {}: {}", id, e)))?;
+        Ok(())
+    }
+}
+
+/// Parse a boolean setting.
+fn parse_bool(s: &str) -> Result<bool, String> {
+    s.parse::<bool>().map_err(|e| format!("invalid value '{}': {}", s, e))
+}
+
+/// The effective rendering settings of the arguments, as a parameter file.
+pub(crate) fn effective(args: &RenderParams) -> String {
+    let preset = args.profile.params();
+    let mut out = String::new();
+    let mut line = |key: &str, value: String| writeln!(out, "{} = {}", key, value).expect("writing to a String");
+    line("profile", format!("\"{}\"", args.profile.name()));
+    // The estimate replaces the k of the channels without an explicit one
+    if args.auto_k {
+        line("auto_k", "true".to_string());
+        line("target_transmittance", args.target_transmittance.unwrap_or(virtualhe::DEFAULT_TARGET_TRANSMITTANCE).to_string());
+        if let Some(k) = args.k_nucleus {
+            line("k_nucleus", k.to_string());
+        }
+        if let Some(k) = args.k_eosin {
+            line("k_eosin", k.to_string());
+        }
+    } else {
+        line("k_nucleus", args.k_nucleus.or(args.k).unwrap_or(preset.k_nucleus).to_string());
+        line("k_eosin", args.k_eosin.or(args.k).unwrap_or(preset.k_eosin).to_string());
+    }
+    let beta = |values: [f32; 3]| format!("[{}]", format_rgb(values).replace(',', ", "));
+    line("beta_hematoxylin", beta(args.beta_hematoxylin.unwrap_or(preset.beta[0])));
+    line("beta_eosin", beta(args.beta_eosin.unwrap_or(preset.beta[1])));
+    if args.extra_channel.is_some() {
+        line("extra_k", args.extra_k.unwrap_or(preset.k_extra).to_string());
+        line("extra_beta", beta(args.extra_beta.unwrap_or(preset.beta_extra)));
+    }
+    // Fixed windows and --no-normalize replace the percentiles
+    if args.nucleus_range.is_none() && !args.no_normalize {
+        line("percentile_nucleus", args.percentile_nucleus.unwrap_or(args.percentile).to_string());
+        if let Some(floor) = args.floor_percentile_nucleus.or(args.floor_percentile) {
+            line("floor_percentile_nucleus", floor.to_string());
+        }
+    }
+    if args.eosin_range.is_none() && !args.no_normalize {
+        line("percentile_eosin", args.percentile_eosin.unwrap_or(args.percentile).to_string());
+        if let Some(floor) = args.floor_percentile_eosin.or(args.floor_percentile) {
+            line("floor_percentile_eosin", floor.to_string());
+        }
+    }
+    if let Some([min, max]) = args.nucleus_range {
+        line("nucleus_range", format!("[{}, {}]", min, max));
+    }
+    if let Some([min, max]) = args.eosin_range {
+        line("eosin_range", format!("[{}, {}]", min, max));
+    }
+    line("crosstalk", format!("[{}, {}]", args.crosstalk[0], args.crosstalk[1]));
+    if let Some([dx, dy]) = args.shift_eosin {
+        line("shift_eosin", format!("[{}, {}]", dx, dy));
+    }
+    line("auto_align", args.auto_align.to_string());
+    if let Some(size) = args.despeckle {
+        line("despeckle", size.to_string());
+        if let Some(channel) = args.despeckle_channel {
+            line("despeckle_channel", format!("\"{}\"", channel.name()));
+        }
+    }
+    line("blur_nucleus", args.blur_nucleus.to_string());
+    line("blur_eosin", args.blur_eosin.to_string());
+    line("downsample", args.downsample.unwrap_or(1).to_string());
+    line("gamma_nucleus", args.gamma_nucleus.to_string());
+    line("gamma_eosin", args.gamma_eosin.to_string());
+    if let Some(equalization) = args.equalize {
+        line("equalize", format!("\"{}\"", equalization.name()));
+        if let Some(channel) = args.equalize_channel {
+            line("equalize_channel", format!("\"{}\"", channel.name()));
+        }
+        if matches!(equalization, Equalization::Clahe { .. }) {
+            line("clahe_tile_size", args.clahe_tile_size.to_string());
+            line("clahe_clip", args.clahe_clip.to_string());
+        }
+    }
+    line("no_normalize", args.no_normalize.to_string());
+    if let Some(method) = args.auto_contrast {
+        line("auto_contrast", format!("\"{}\"", method.name()));
+    }
+    line("auto_mask", args.auto_mask.to_string());
+    line("saturation_warning", args.saturation_warning.to_string());
+    if let Some(limit) = args.strict_saturation {
+        line("strict_saturation", limit.to_string());
+    }
+    line("nan_policy", format!("\"{}\"", args.nan_policy.name()));
+    line("percentile_method", format!("\"{}\"", args.percentile_method.name()));
+    if let Some(max) = args.input_max {
+        line("input_max", max.to_string());
+    }
+    if let Some(bits) = args.input_bits {
+        line("input_bits", bits.to_string());
+    }
+    line("auto_range", args.auto_range.to_string());
+    line("invert_nucleus", args.invert_nucleus.to_string());
+    line("invert_eosin", args.invert_eosin.to_string());
+    if let Some(channel) = args.nucleus_rgb_channel {
+        line("nucleus_rgb_channel", format!("\"{}\"", channel.name()));
+    }
+    if let Some(channel) = args.eosin_rgb_channel {
+        line("eosin_rgb_channel", format!("\"{}\"", channel.name()));
+    }
+    line("crop_to_common", args.crop_to_common.to_string());
+    if let Some(target) = args.resample_to {
+        line("resample_to", format!("\"{}\"", target));
+    }
+    line("aspect_tolerance", args.aspect_tolerance.to_string());
+    line("color_encoding", format!("\"{}\"", args.color_encoding.name()));
+    line("exact", args.exact.to_string());
+    line("dither", format!("\"{}\"", args.dither.name()));
+    line("output_depth", args.output_depth.bits().to_string());
+    line("rgba", args.rgba.to_string());
+    if args.rgba {
+        line("alpha_rule", format!("\"{}\"", args.alpha_rule.unwrap_or_default().name()));
+    }
+    line("compression", format!("\"{}\"", args.compression.unwrap_or_default().name()));
+    line("jpeg_quality", args.jpeg_quality.unwrap_or(virtualhe::DEFAULT_JPEG_QUALITY).to_string());
+    if let Some(size) = args.tile_size {
+        line("tile_size", size.to_string());
+    }
+    if let Some([x, y]) = args.pixel_size_um {
+        line("pixel_size_um", if x == y { x.to_string() } else { format!("[{}, {}]", x, y) });
+    }
+    out
+}
@@ -0,0 +1,421 @@
+//! Settings of a run, resolved once from the arguments and shared by every pair it renders: the
+//! input and output paths, the load, scale and color options, and the fixed or reference windows.
+use super::checks::{check_disk_space, tiled_fallback};
+use super::parse::{format_range, parse_range, ByteOrder, InputChannel, RoiStats};
+use super::progress::Progress;
+use crate::Args;
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+use virtualhe::overlay::Polygon;
+use virtualhe::{
+    Equalization, Error, InputRange, LoadOptions, Params, RawLayout, ScaleOptions, Thresholds, TiffCompression,
+};
+
+/// Settings shared by every image rendered in one run.
+#[derive(Clone)]
+pub(crate) struct Job {
+    pub(crate) params: Params,
+    pub(crate) nucleus_options: LoadOptions,
+    pub(crate) eosin_options: LoadOptions,
+    pub(crate) nucleus_scale: ScaleOptions,
+    pub(crate) eosin_scale: ScaleOptions,
+    pub(crate) extra_options: LoadOptions,
+    pub(crate) extra_scale: ScaleOptions,
+    pub(crate) compression: TiffCompression,
+    /// Polygons of --annotations in input pixel coordinates.
+    pub(crate) annotations: Vec<Polygon>,
+    pub(crate) progress: Progress,
+    /// GPU the RGB image is generated on with --gpu, None without an adapter.
+    #[cfg(feature = "gpu")]
+    pub(crate) gpu: Option<virtualhe::gpu::Gpu>,
+}
+
+/// Scale a channel with a fixed window in input units. The channel is normalized by the upper
+/// bound of the window, so that the window spans the normalized intensities from MIN / MAX to 1.
+pub(crate) fn fix_range(load: &mut LoadOptions, scale: &mut ScaleOptions, [min, max]: [f32; 2]) {
+    load.range = InputRange::Max(max);
+    scale.window = Some(Thresholds {
+        floor: min / max,
+        ceiling: 1.0,
+    });
+}
+
+/// Fixed window in input units that a channel is scaled with, if any.
+pub(crate) fn fixed_range(load: &LoadOptions, scale: &ScaleOptions) -> Option<[f32; 2]> {
+    match (load.range, scale.window) {
+        (InputRange::Max(max), Some(window)) => Some([window.floor * max, window.ceiling * max]),
+        _ => None,
+    }
+}
+
+/// Scaling window of one channel in a --reference-stats file.
+#[derive(Deserialize)]
+pub(crate) struct ReferenceChannel {
+    floor: f32,
+    ceiling: f32,
+}
+
+/// Statistics written by --stats-only --json, of which --reference-stats uses the windows.
+#[derive(Deserialize)]
+pub(crate) struct ReferenceStats {
+    nucleus: ReferenceChannel,
+    eosin: ReferenceChannel,
+}
+
+/// Read the nucleus and eosin windows in input units from a --reference-stats file.
+pub(crate) fn read_reference_stats(path: &str) -> Result<([f32; 2], [f32; 2]), Error> {
+    let decode_error = |message: String| Error::Decode {
+        path: path.into(),
+        message: format!("{}: {}", path, message),
+    };
+    let text = fs::read_to_string(path).map_err(|source| Error::Open {
+        path: path.into(),
+        source,
+    })?;
+    let stats: ReferenceStats = serde_json::from_str(&text).map_err(|e| decode_error(e.to_string()))?;
+    let nucleus = parse_range(&format_range([stats.nucleus.floor, stats.nucleus.ceiling])).map_err(decode_error)?;
+    let eosin = parse_range(&format_range([stats.eosin.floor, stats.eosin.ceiling])).map_err(decode_error)?;
+    Ok((nucleus, eosin))
+}
+
+/// The color model parameters of the profile, with those given on the command line overriding it.
+pub(crate) fn color_params(args: &Args) -> Params {
+    let preset = args.render.profile.params();
+    Params {
+        k_nucleus: args.render.k_nucleus.or(args.render.k).unwrap_or(preset.k_nucleus),
+        k_eosin: args.render.k_eosin.or(args.render.k).unwrap_or(preset.k_eosin),
+        beta: [
+            args.render.beta_hematoxylin.unwrap_or(preset.beta[0]),
+            args.render.beta_eosin.unwrap_or(preset.beta[1]),
+        ],
+        k_extra: args.render.extra_k.unwrap_or(preset.k_extra),
+        beta_extra: args.render.extra_beta.unwrap_or(preset.beta_extra),
+        encoding: args.render.color_encoding,
+        exact: args.render.exact,
+        dither: args.render.dither,
+    }
+}
+
+/// Open the GPU for --gpu, warning that the image is generated on the CPU without an adapter.
+#[cfg(feature = "gpu")]
+fn open_gpu(progress: &Progress) -> Option<virtualhe::gpu::Gpu> {
+    let gpu = virtualhe::gpu::Gpu::new();
+    match &gpu {
+        Some(gpu) => progress.println(format!("Using GPU: {}", gpu.name())),
+        None => log::warn!("no GPU adapter found, generating the RGB image on the CPU"),
+    }
+    gpu
+}
+
+/// Nucleus, eosin and output paths of a run of one pair, the eosin path empty when there is no
+/// eosin input and the output path empty with --stats-only.
+pub(crate) type Paths = (String, String, String);
+
+/// Paths of the pair the positional arguments give, None for the runs of several pairs.
+pub(crate) fn paths(args: &Args) -> Result<Option<Paths>, Error> {
+    // Positional arguments are required by clap unless listing profiles or running a batch, a single
+    // multichannel input is followed directly by the output path, or by nothing with --stats-only
+    let paths = match (&args.batch_dir, &args.nucleus, &args.eosin, &args.output) {
+        _ if args.shared_norm.is_some() || args.watch.is_some() => None,
+        (Some(_), _, _, _) => None,
+        // --channel replaces both inputs, so the only positional argument is the output path
+        (None, Some(output), None, None) if !args.render.channels.is_empty() => {
+            Some((String::new(), String::new(), output.clone()))
+        }
+        (None, Some(nucleus), Some(output), None) if !args.render.eosin_inputs.is_empty() => {
+            let eosin: Vec<&str> = args.render.eosin_inputs.iter().map(|input| input.path.as_str()).collect();
+            Some((nucleus.clone(), eosin.join("+"), output.clone()))
+        }
+        (None, Some(nucleus), None, None) if !args.render.eosin_inputs.is_empty() && args.stats_only => {
+            let eosin: Vec<&str> = args.render.eosin_inputs.iter().map(|input| input.path.as_str()).collect();
+            Some((nucleus.clone(), eosin.join("+"), String::new()))
+        }
+        (None, _, Some(_), Some(_)) if !args.render.eosin_inputs.is_empty() => {
+            return Err(Error::InvalidOptions(
+                "--eosin replaces the eosin input, give the nucleus input and the output path".to_string(),
+            ))
+        }
+        (None, Some(nucleus), Some(output), None) if args.render.no_eosin => {
+            Some((nucleus.clone(), String::new(), output.clone()))
+        }
+        (None, _, Some(_), Some(_)) if args.render.no_eosin => {
+            return Err(Error::InvalidOptions(
+                "--no-eosin leaves out the eosin input, give the nucleus input and the output path".to_string(),
+            ))
+        }
+        (None, _, Some(_), _) if !args.render.channels.is_empty() => {
+            return Err(Error::InvalidOptions(
+                "--channel replaces the nucleus and eosin inputs, give only the output path".to_string(),
+            ))
+        }
+        (None, Some(_), Some(_), Some(_)) if args.stats_only => {
+            return Err(Error::InvalidOptions("--stats-only writes no output image, leave out the output path".to_string()))
+        }
+        (None, Some(nucleus), Some(eosin), None) if args.stats_only => Some((nucleus.clone(), eosin.clone(), String::new())),
+        (None, Some(input), None, None) if args.stats_only => {
+            if args.render.eosin_channel.is_none() {
+                return Err(Error::InvalidOptions(
+                    "--eosin-channel is required when reading both channels from one multichannel image"
+                        .to_string(),
+                ));
+            }
+            Some((input.clone(), input.clone(), String::new()))
+        }
+        (None, Some(nucleus), Some(eosin), Some(output)) => Some((nucleus.clone(), eosin.clone(), output.clone())),
+        (None, Some(input), Some(output), None) => {
+            if args.render.eosin_channel.is_none() {
+                return Err(Error::InvalidOptions(
+                    "--eosin-channel is required when reading both channels from one multichannel image"
+                        .to_string(),
+                ));
+            }
+            Some((input.clone(), input.clone(), output.clone()))
+        }
+        _ => unreachable!("input and output paths are required"),
+    };
+    Ok(match paths {
+        Some((nucleus, eosin, output)) if args.render.swap_channels => {
+            log::debug!("swapped channels: nucleus {}, eosin {}", eosin, nucleus);
+            Some((eosin, nucleus, output))
+        }
+        paths => paths,
+    })
+}
+
+/// Saturation and floor percentiles of the nucleus and eosin channels, those of --percentile and
+/// --floor-percentile unless a channel has its own.
+pub(crate) fn percentiles(args: &Args) -> [(f32, Option<f32>); 2] {
+    let render = &args.render;
+    [
+        (render.percentile_nucleus.unwrap_or(render.percentile), render.floor_percentile_nucleus.or(render.floor_percentile)),
+        (render.percentile_eosin.unwrap_or(render.percentile), render.floor_percentile_eosin.or(render.floor_percentile)),
+    ]
+}
+
+impl Job {
+    /// Settings of the run from the arguments, with the windows of reference images computed,
+    /// after checking the output size and memory of the pair at `paths` from its header.
+    pub(crate) fn new(args: &Args, paths: Option<&Paths>) -> Result<Self, Error> {
+        // Lines go to stderr when stdout carries the output image
+        let to_stdout = paths.is_some_and(|(_, _, output_path)| output_path == virtualhe::STDIO_PATH);
+        // Renders of the server print nothing, as their requests run at the same time, and those of
+        // a benchmark only its summary
+        let progress = Progress::new(
+            args.render.quiet || args.render.verbose > 0 || args.is_serving() || args.bench.is_some(),
+            !args.json && !args.is_serving() && args.bench.is_none(),
+            to_stdout,
+        );
+
+        // Color model
+        let params = color_params(args);
+        if !args.render.channels.is_empty() {
+            for channel in &args.render.channels {
+                let stain = channel.stain;
+                progress.println(format!("Using channel {}: k {}, beta (r,g,b): {:?}", channel.path, stain.k, stain.beta));
+            }
+        } else if !args.stats_only {
+            progress.println(format!("Using profile: {}", args.render.profile.name()));
+            let k = |explicit: Option<f32>, k: f32| match explicit {
+                None if args.render.auto_k => "estimated".to_string(),
+                _ => k.to_string(),
+            };
+            progress.println(format!(
+                "Using k nucleus: {}, k eosin: {}",
+                k(args.render.k_nucleus, params.k_nucleus),
+                k(args.render.k_eosin, params.k_eosin)
+            ));
+            progress.println(format!("Using beta hematoxylin (r,g,b): {:?}", params.beta[0]));
+            progress.println(format!("Using beta eosin (r,g,b): {:?}", params.beta[1]));
+            if args.render.extra_channel.is_some() {
+                progress.println(format!("Using k extra: {}, beta extra (r,g,b): {:?}", params.k_extra, params.beta_extra));
+            }
+        }
+        log::debug!(
+            "color model: profile {}, k nucleus {}, k eosin {}, beta hematoxylin {:?}, beta eosin {:?}, {} encoding",
+            args.render.profile.name(),
+            params.k_nucleus,
+            params.k_eosin,
+            params.beta[0],
+            params.beta[1],
+            params.encoding.name()
+        );
+
+        let range = match (args.render.input_max, args.render.input_bits, args.render.auto_range) {
+            (Some(max), _, _) => InputRange::Max(max),
+            (_, Some(bits), _) => InputRange::Bits(bits),
+            (_, _, true) => InputRange::Auto,
+            _ => InputRange::Container,
+        };
+        let raw = args.render.raw_dims.map(|(width, height)| RawLayout {
+            width,
+            height,
+            sample: args.render.raw_dtype,
+            big_endian: args.render.raw_endian == ByteOrder::Big,
+        });
+        let equalize = args.render.equalize.map(|equalization| match equalization {
+            Equalization::Clahe { .. } => Equalization::Clahe {
+                tile_size: args.render.clahe_tile_size,
+                clip_limit: args.render.clahe_clip,
+            },
+            global => global,
+        });
+        let auto_contrast = |scale: ScaleOptions| match args.render.auto_contrast {
+            Some(method) => scale.with_auto_contrast(method),
+            None => scale,
+        };
+        let annotations = match &args.render.annotations {
+            Some(path) => {
+                let polygons = progress.phase("Reading annotations", || virtualhe::overlay::read_geojson(path))?;
+                progress.println(format!("Read {} annotation polygons from {}", polygons.len(), path));
+                polygons
+            }
+            None => Vec::new(),
+        };
+        let [(percentile_nucleus, floor_nucleus), (percentile_eosin, floor_eosin)] = percentiles(args);
+        let mut job = Job {
+            params,
+            nucleus_options: LoadOptions {
+                rgb_channel: args.render.nucleus_rgb_channel,
+                range,
+                channel: args.render.nucleus_channel.clone(),
+                page: args.render.nucleus_page,
+                level: args.render.level,
+                scene: args.render.scene,
+                z: args.render.z,
+                flat_field: args.render.flatfield_nucleus.as_ref().map(PathBuf::from),
+                dark_field: args.render.darkfield.as_ref().map(PathBuf::from),
+                roi: None,
+                invert: args.render.invert_nucleus,
+                downsample: args.render.downsample.unwrap_or(1) as usize,
+                despeckle: args.render.despeckle.filter(|_| args.render.despeckle_channel != Some(InputChannel::Eosin)),
+                blur_sigma: args.render.blur_nucleus,
+                raw,
+            },
+            eosin_options: LoadOptions {
+                rgb_channel: args.render.eosin_rgb_channel,
+                range,
+                channel: args.render.eosin_channel.clone(),
+                page: args.render.eosin_page,
+                level: args.render.level,
+                scene: args.render.scene,
+                z: args.render.z,
+                flat_field: args.render.flatfield_eosin.as_ref().map(PathBuf::from),
+                dark_field: args.render.darkfield.as_ref().map(PathBuf::from),
+                roi: None,
+                invert: args.render.invert_eosin,
+                downsample: args.render.downsample.unwrap_or(1) as usize,
+                despeckle: args.render.despeckle.filter(|_| args.render.despeckle_channel != Some(InputChannel::Nucleus)),
+                blur_sigma: args.render.blur_eosin,
+                raw,
+            },
+            nucleus_scale: auto_contrast(ScaleOptions {
+                percentile: percentile_nucleus,
+                floor_percentile: floor_nucleus,
+                nan_policy: args.render.nan_policy,
+                percentile_method: args.render.percentile_method,
+                integer_input_max: None,
+                equalize: equalize.filter(|_| args.render.equalize_channel != Some(InputChannel::Eosin)),
+                auto_contrast: None,
+                window: None,
+                gamma: args.render.gamma_nucleus,
+            }),
+            eosin_scale: auto_contrast(ScaleOptions {
+                percentile: percentile_eosin,
+                floor_percentile: floor_eosin,
+                nan_policy: args.render.nan_policy,
+                percentile_method: args.render.percentile_method,
+                integer_input_max: None,
+                equalize: equalize.filter(|_| args.render.equalize_channel != Some(InputChannel::Nucleus)),
+                auto_contrast: None,
+                window: None,
+                gamma: args.render.gamma_eosin,
+            }),
+            extra_options: LoadOptions {
+                range,
+                raw,
+                level: args.render.level,
+                scene: args.render.scene,
+                z: args.render.z,
+                downsample: args.render.downsample.unwrap_or(1) as usize,
+                ..LoadOptions::default()
+            },
+            extra_scale: auto_contrast(ScaleOptions {
+                percentile: args.render.percentile,
+                floor_percentile: args.render.floor_percentile,
+                nan_policy: args.render.nan_policy,
+                percentile_method: args.render.percentile_method,
+                integer_input_max: None,
+                equalize: None,
+                auto_contrast: None,
+                window: None,
+                gamma: 1.0,
+            }),
+            compression: args.render.compression.unwrap_or_default(),
+            annotations,
+            #[cfg(feature = "gpu")]
+            gpu: args.render.gpu.then(|| open_gpu(&progress)).flatten(),
+            progress,
+        };
+
+        // The free space and memory are checked against the output size from the input header before
+        // decoding
+        if let Some((nucleus_path, eosin_path, output_path)) = paths.filter(|_| !args.stats_only) {
+            match args.render.channels.first() {
+                Some(channel) => check_disk_space(args, &job.extra_options, &[(&channel.path, output_path)]),
+                None => check_disk_space(args, &job.nucleus_options, &[(nucleus_path, output_path)]),
+            }
+            let input = args.render.channels.first().map_or(nucleus_path.as_str(), |channel| channel.path.as_str());
+            tiled_fallback(args, &job, input, eosin_path, output_path)?;
+        }
+
+        // Fixed windows are given directly, or selected by the percentiles of reference images
+        let (nucleus_range, eosin_range) = match (&args.render.reference, &args.render.reference_stats) {
+            (Some((nucleus, eosin)), _) => {
+                let nucleus = job.progress.phase("Computing reference nucleus thresholds", || {
+                    virtualhe::reference_window(&[nucleus], &job.nucleus_options, &job.nucleus_scale)
+                })?;
+                let eosin = job.progress.phase("Computing reference eosin thresholds", || {
+                    virtualhe::reference_window(&[eosin], &job.eosin_options, &job.eosin_scale)
+                })?;
+                (Some(nucleus), Some(eosin))
+            }
+            (None, Some(path)) => {
+                let (nucleus, eosin) = read_reference_stats(path)?;
+                (Some(nucleus), Some(eosin))
+            }
+            (None, None) => (args.render.nucleus_range, args.render.eosin_range),
+        };
+        if let Some(range) = nucleus_range {
+            fix_range(&mut job.nucleus_options, &mut job.nucleus_scale, range);
+        }
+        if let Some(range) = eosin_range {
+            fix_range(&mut job.eosin_options, &mut job.eosin_scale, range);
+        }
+
+        // The inputs are cropped while decoding, unless the percentiles are computed over the whole
+        // image and the scaled channels are cropped instead. Reference images keep their full size.
+        if args.render.roi_stats.unwrap_or_default() == RoiStats::Roi {
+            job.nucleus_options.roi = args.render.roi;
+            job.eosin_options.roi = args.render.roi;
+            job.extra_options.roi = args.render.roi;
+        }
+
+        // Without normalization the channels keep the values of the bit depth normalization, those
+        // with a fixed range keep it
+        if args.render.no_normalize {
+            let unscaled = Some(Thresholds { floor: 0.0, ceiling: 1.0 });
+            job.nucleus_scale.window = job.nucleus_scale.window.or(unscaled);
+            job.eosin_scale.window = job.eosin_scale.window.or(unscaled);
+            job.extra_scale.window = unscaled;
+        }
+        if args.render.reference.is_some() || args.render.reference_stats.is_some() {
+            job.progress.println(format!(
+                "Using reference ranges: nucleus {}, eosin {}",
+                format_range(nucleus_range.expect("set by the reference")),
+                format_range(eosin_range.expect("set by the reference"))
+            ));
+        }
+        Ok(job)
+    }
+}
@@ -0,0 +1,20 @@
+//! Modes of the command line tool beyond parsing its arguments: the checks of the options, the
+//! settings of a run, the renders of a pair, of a batch and of a watched directory, the outputs
+//! written next to the image, and the subcommands.
+pub(crate) mod batch;
+pub(crate) mod bench;
+pub(crate) mod checks;
+pub(crate) mod checksum;
+pub(crate) mod config;
+pub(crate) mod job;
+pub(crate) mod output;
+pub(crate) mod parse;
+pub(crate) mod progress;
+pub(crate) mod provenance;
+pub(crate) mod render;
+#[cfg(feature = "server")]
+pub(crate) mod server;
+pub(crate) mod staging;
+pub(crate) mod stats;
+pub(crate) mod synthetic;
+pub(crate) mod watch;
@@ -0,0 +1,446 @@
+//! Files written for a rendered pair: the image in the chosen format, the channel components,
+//! annotations and provenance next to it, and the color lookup table of --export-lut.
+use super::checks::{check_output, check_overwrite};
+use super::checksum::Checksum;
+use super::job::{color_params, fixed_range, Job};
+use super::parse::{format_pixel_size, format_range, format_rgb};
+use super::progress::Progress;
+use super::provenance::{self, Input};
+use crate::Args;
+use ndarray::{Array2, Array3, ArrayView2};
+use std::fs;
+use std::path::{Path, PathBuf};
+use virtualhe::alpha::AlphaRule;
+use virtualhe::dicom::DicomOptions;
+use virtualhe::dzi::DziOptions;
+use virtualhe::ome::OmeMetadata;
+use virtualhe::overlay::{PolygonStyle, ScaleBar};
+use virtualhe::stack::StackOutput;
+use virtualhe::{
+    ColorEncoding, Dither, Equalization, Error, OutputDepth, OutputSample, Params, SaveOptions, Stain, ZarrOptions,
+};
+
+/// Write the color lookup table of the color model to `path`, as a CSV table with one row per
+/// pair of intensities for a .csv path and as an RGB image otherwise.
+pub(crate) fn export_lut(args: &Args, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let params = color_params(args);
+    let size = args.lut_size as usize;
+    let csv = Path::new(path).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    if !csv {
+        check_output(args, path)?;
+    }
+    check_overwrite(args, path)?;
+    match args.render.output_depth {
+        OutputDepth::Eight => write_lut(virtualhe::color_lut::<u8>(size, &params), path, csv, args)?,
+        OutputDepth::Sixteen => write_lut(virtualhe::color_lut::<u16>(size, &params), path, csv, args)?,
+    }
+    println!("Color lookup table saved to: {}", path);
+    Ok(())
+}
+
+/// Encode a color lookup table as an image, or write it as a CSV table.
+pub(crate) fn write_lut<T: OutputSample>(lut: Array3<T>, path: &str, csv: bool, args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    if !csv {
+        let options = SaveOptions {
+            jpeg_quality: args.render.jpeg_quality.unwrap_or(virtualhe::DEFAULT_JPEG_QUALITY),
+            compression: args.render.compression.unwrap_or_default(),
+            ..SaveOptions::default()
+        };
+        return Ok(virtualhe::save_with(lut, path, &options)?);
+    }
+    let (rows, cols, _) = lut.dim();
+    let step = 1.0 / (rows - 1) as f32;
+    let mut table = String::from("nucleus,eosin,red,green,blue\n");
+    for i in 0..rows {
+        for j in 0..cols {
+            let [r, g, b]: [u32; 3] = [0, 1, 2].map(|c| lut[[i, j, c]].into());
+            table.push_str(&format!("{},{},{},{},{}\n", i as f32 * step, j as f32 * step, r, g, b));
+        }
+    }
+    fs::write(path, table).map_err(|e| Error::Write {
+        path: path.into(),
+        message: format!("{}: {}", path, e),
+    })?;
+    Ok(())
+}
+
+/// Paths written for the output `output_path`: the output, or the first image of a series, the
+/// thumbnail, the Deep Zoom descriptor, the DICOM image and the provenance file. None for output to
+/// stdout.
+pub(crate) fn output_files(args: &Args, output_path: &str) -> Vec<String> {
+    if output_path == virtualhe::STDIO_PATH {
+        return Vec::new();
+    }
+    let mut paths = vec![match args.render.stack_output {
+        Some(StackOutput::Series) if args.render.stack => {
+            virtualhe::stack::series_path(Path::new(output_path), 0).to_string_lossy().into_owned()
+        }
+        _ => output_path.to_string(),
+    }];
+    paths.extend(args.render.thumbnail.as_ref().map(|thumbnail| thumbnail.path_for(output_path)));
+    paths.extend(dzi_path(args, output_path).map(|path| path.to_string_lossy().into_owned()));
+    paths.extend(dicom_path(args, output_path).map(|path| path.to_string_lossy().into_owned()));
+    if provenance::enabled(args, output_path) {
+        paths.push(provenance::path_for(output_path));
+    }
+    paths
+}
+
+/// Path of the --output-dzi descriptor of the image saved to `output_path`, named after its file
+/// stem.
+pub(crate) fn dzi_path(args: &Args, output_path: &str) -> Option<PathBuf> {
+    let name = Path::new(output_path).file_stem().unwrap_or_default().to_string_lossy();
+    args.render.output_dzi.as_ref().map(|dir| Path::new(dir).join(format!("{}.dzi", name)))
+}
+
+/// Layout and encoding of the --output-dzi tiles.
+pub(crate) fn dzi_options(args: &Args) -> DziOptions {
+    DziOptions {
+        tile_size: args.render.dzi_tile_size.unwrap_or(virtualhe::dzi::DEFAULT_DZI_TILE_SIZE),
+        overlap: args.render.dzi_overlap.unwrap_or(virtualhe::dzi::DEFAULT_DZI_OVERLAP),
+        format: args.render.dzi_format.unwrap_or_default(),
+        jpeg_quality: args.render.jpeg_quality.unwrap_or(virtualhe::DEFAULT_JPEG_QUALITY),
+    }
+}
+
+/// Path of the --output-dicom image of the image saved to `output_path`, named after its file stem.
+pub(crate) fn dicom_path(args: &Args, output_path: &str) -> Option<PathBuf> {
+    let name = Path::new(output_path).file_stem().unwrap_or_default().to_string_lossy();
+    args.render.output_dicom.as_ref().map(|dir| Path::new(dir).join(format!("{}.dcm", name)))
+}
+
+/// Layout and identifying attributes of the --output-dicom image of a render with the color
+/// `encoding` and the pixel size of its outputs.
+pub(crate) fn dicom_options(args: &Args, encoding: ColorEncoding, pixel_size_um: Option<[f32; 2]>) -> Result<DicomOptions, Error> {
+    let pixel_size_um = pixel_size_um.ok_or_else(|| {
+        Error::InvalidOptions("--output-dicom requires the pixel size of the inputs or --pixel-size-um".to_string())
+    })?;
+    let render = &args.render;
+    Ok(DicomOptions {
+        encoding,
+        patient_name: render.patient_name.clone(),
+        patient_id: render.patient_id.clone(),
+        study_uid: render.study_uid.clone(),
+        accession_number: render.accession_number.clone(),
+        container_id: render.container_id.clone(),
+        ..DicomOptions::new(render.dicom_tile_size.unwrap_or(virtualhe::dicom::DEFAULT_DICOM_TILE_SIZE), pixel_size_um)
+    })
+}
+
+/// Encoder options of the outputs, recording the rendering parameters of `annotations` in TIFF
+/// outputs, in the OME metadata with --ome. The pixel size is taken from the TIFF metadata of the
+/// first of `inputs` unless given.
+pub(crate) fn save_options(
+    args: &Args,
+    job: &Job,
+    progress: &Progress,
+    inputs: &[&str],
+    annotations: impl FnOnce() -> Vec<(String, String)>,
+) -> SaveOptions {
+    // TIFF resolution tags, OME-TIFF and OME-Zarr metadata record the pixel size
+    let factor = args.render.downsample.unwrap_or(1) as f32;
+    let pixel_size_um = args
+        .render
+        .pixel_size_um
+        .or_else(|| input_pixel_size(inputs))
+        .map(|size| size.map(|size| size * factor));
+    if let Some(size) = pixel_size_um {
+        progress.println(format!("Using pixel size: {} um", format_pixel_size(size)));
+    }
+    let parameters = annotations();
+    let ome = args.render.ome.then(|| OmeMetadata {
+        pixel_size_um,
+        annotations: parameters.clone(),
+    });
+    let zarr = args.render.output_zarr.then(|| ZarrOptions {
+        chunk_size: args.render.zarr_chunk_size.unwrap_or(virtualhe::DEFAULT_ZARR_CHUNK_SIZE),
+        pixel_size_um,
+    });
+    SaveOptions {
+        jpeg_quality: args.render.jpeg_quality.unwrap_or(virtualhe::DEFAULT_JPEG_QUALITY),
+        compression: job.compression,
+        pyramid_tile_size: args
+            .render
+            .pyramid
+            .then(|| args.render.tile_size.unwrap_or(virtualhe::DEFAULT_PYRAMID_TILE_SIZE)),
+        bigtiff: args.render.bigtiff,
+        ome,
+        pixel_size_um,
+        parameters,
+        zarr,
+    }
+}
+
+/// Pixel size of the first of `inputs` from its TIFF metadata, warning about inputs with another size.
+pub(crate) fn input_pixel_size(inputs: &[&str]) -> Option<[f32; 2]> {
+    let (first, others) = inputs.split_first()?;
+    let size = virtualhe::ome::pixel_size_from_tiff(first)?;
+    for other in others.iter().filter(|other| *other != first) {
+        let Some(other_size) = virtualhe::ome::pixel_size_from_tiff(other) else {
+            continue;
+        };
+        // Resolution tags are rationals, sizes within 0.1% count as equal
+        if size.iter().zip(other_size).any(|(a, b)| (a - b).abs() > 1e-3 * a.max(b)) {
+            log::warn!(
+                "{} has a pixel size of {} um but {} has {} um, using the pixel size of {}",
+                first,
+                format_pixel_size(size),
+                other,
+                format_pixel_size(other_size),
+                first
+            );
+        }
+    }
+    Some(size)
+}
+
+/// Generate the RGB image of scaled channels with their stains, on the GPU with --gpu.
+pub(crate) fn generate<T: OutputSample>(job: &Job, channels: &[ArrayView2<f32>], stains: &[Stain]) -> Result<Array3<T>, Error> {
+    #[cfg(feature = "gpu")]
+    if let Some(gpu) = &job.gpu {
+        match gpu.render_views_as(channels, stains, job.params.encoding, job.params.dither) {
+            Ok(rgb) => return Ok(rgb),
+            Err(e) => log::warn!("GPU rendering failed, generating the RGB image on the CPU: {}", e),
+        }
+    }
+    virtualhe::render_views_as(channels, stains, job.params.encoding, job.params.exact, job.params.dither)
+}
+
+/// Add the alpha channel of --rgba to a rendered image, from the `tissue` mask with --alpha-rule
+/// mask, draw the annotations and scale bar into it and save it to `output_path`, and its
+/// thumbnail if requested. Returns the checksum of the saved image with --checksum.
+pub(crate) fn save_rendered<T: OutputSample>(
+    args: &Args,
+    job: &Job,
+    progress: &Progress,
+    rgb: Array3<T>,
+    output_path: &str,
+    save_options: &SaveOptions,
+    tissue: Option<&Array2<bool>>,
+) -> Result<Option<Checksum>, Box<dyn std::error::Error>> {
+    progress.check_interrupt(output_path)?;
+    let mut rgb = rgb;
+    if args.render.rgba {
+        // Overlays are drawn opaque onto the transparent image
+        rgb = progress.phase("Adding alpha channel", || match (args.render.alpha_rule.unwrap_or_default(), tissue) {
+            (AlphaRule::Luminance, _) => Ok(virtualhe::alpha::luminance_alpha(rgb.view())),
+            (AlphaRule::Mask, Some(mask)) => virtualhe::alpha::mask_alpha(rgb.view(), mask),
+            (AlphaRule::Mask, None) => {
+                Err(Error::InvalidOptions("--alpha-rule mask requires --mask or --auto-mask".to_string()))
+            }
+        })?;
+    }
+    if !job.annotations.is_empty() {
+        // Annotations are drawn on the input frame
+        let [x0, y0] = args.render.roi.map_or([0.0, 0.0], |roi| [roi.x as f64, roi.y as f64]);
+        let factor = f64::from(args.render.downsample.unwrap_or(1));
+        let polygons: Vec<_> =
+            job.annotations.iter().map(|polygon| polygon.map(|[x, y]| [(x - x0) / factor, (y - y0) / factor])).collect();
+        let style = PolygonStyle {
+            color: args.render.annotation_color.unwrap_or([0, 255, 0]),
+            width: args.render.annotation_width.unwrap_or(2),
+            fill: args.render.annotation_fill,
+        };
+        progress.phase("Drawing annotations", || virtualhe::overlay::draw_polygons(&mut rgb, &polygons, &style));
+    }
+    if let Some((length_um, corner)) = args.render.scale_bar {
+        let [pixel_size_um, _] = save_options.pixel_size_um.ok_or_else(|| {
+            Error::InvalidOptions("--scale-bar requires the pixel size of the inputs or --pixel-size-um".to_string())
+        })?;
+        let bar = ScaleBar {
+            length_um,
+            corner,
+            color: args.render.scale_bar_color.unwrap_or_default(),
+        };
+        virtualhe::overlay::draw_scale_bar(&mut rgb, &bar, pixel_size_um)?;
+    }
+
+    // The thumbnail is taken from the rendered image before the encoder consumes it
+    let thumbnail = args.render.thumbnail.as_ref().map(|thumbnail| {
+        let preview = progress.phase("Generating thumbnail", || virtualhe::thumbnail(rgb.view(), thumbnail.max_size));
+        (thumbnail.path_for(output_path), preview)
+    });
+    // As is the Deep Zoom image, written before the output for the same reason
+    if let Some(path) = dzi_path(args, output_path) {
+        let options = dzi_options(args);
+        progress.phase("Writing Deep Zoom tiles", || virtualhe::dzi::write_dzi(rgb.view(), &path, &options))?;
+        progress.println(format!("Deep Zoom image saved to: {}", path.display()));
+    }
+    if let Some(path) = dicom_path(args, output_path) {
+        let options = dicom_options(args, job.params.encoding, save_options.pixel_size_um)?;
+        progress.phase("Writing DICOM frames", || virtualhe::dicom::write_dicom(rgb.view(), &path, &options))?;
+        progress.println(format!("DICOM image saved to: {}", path.display()));
+    }
+    let checksum = args.render.checksum.then(|| progress.phase("Hashing samples", || Checksum::of_samples(&rgb)));
+    match args.render.format.filter(|_| output_path == virtualhe::STDIO_PATH) {
+        Some(format) => progress.phase("Encoding", || virtualhe::save_to_stdout(rgb, format, save_options))?,
+        None => progress.phase("Encoding", || virtualhe::save_with(rgb, output_path, save_options))?,
+    }
+    if let Some((path, preview)) = thumbnail {
+        let options = SaveOptions {
+            jpeg_quality: save_options.jpeg_quality,
+            ..SaveOptions::default()
+        };
+        progress.phase("Encoding thumbnail", || virtualhe::save_with(preview, &path, &options))?;
+        progress.println(format!("Thumbnail saved to: {}", path));
+    }
+    Ok(match checksum {
+        Some(checksum) => Some(progress.phase("Hashing output", || checksum.with_file(output_path))?),
+        None => None,
+    })
+}
+
+/// Render each scaled channel alone as its stain against white and save it as a TIFF named after
+/// the stain in `dir`, prefixed with the file stem of the output in a batch.
+pub(crate) fn save_components(
+    args: &Args,
+    job: &Job,
+    progress: &Progress,
+    dir: &str,
+    output_path: &str,
+    components: &[(&str, ArrayView2<f32>, Stain)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir, e))?;
+    let prefix = match args.is_batch() {
+        true => format!("{}_", Path::new(output_path).file_stem().unwrap_or_default().to_string_lossy()),
+        false => String::new(),
+    };
+    let options = SaveOptions {
+        compression: job.compression,
+        ..SaveOptions::default()
+    };
+    let (encoding, exact, dither) = (job.params.encoding, job.params.exact, job.params.dither);
+    for (name, channel, stain) in components {
+        let path = Path::new(dir).join(format!("{}{}.tiff", prefix, name));
+        let (channels, stains) = ([channel.view()], [*stain]);
+        match args.render.output_depth {
+            OutputDepth::Eight => {
+                let rgb = progress.phase("Generating component", || virtualhe::render_views_as::<u8>(&channels, &stains, encoding, exact, dither))?;
+                progress.phase("Encoding component", || virtualhe::save_with(rgb, &path, &options))?
+            }
+            OutputDepth::Sixteen => {
+                let rgb = progress.phase("Generating component", || virtualhe::render_views_as::<u16>(&channels, &stains, encoding, exact, dither))?;
+                progress.phase("Encoding component", || virtualhe::save_with(rgb, &path, &options))?
+            }
+        }
+        progress.println(format!("Component {} saved to: {}", name, path.display()));
+    }
+    Ok(())
+}
+
+/// Rendering parameters of a nucleus and eosin pair rendered with `params` recorded in TIFF outputs.
+pub(crate) fn pair_annotations(args: &Args, job: &Job, params: &Params) -> Vec<(String, String)> {
+    let mut annotations = vec![
+        ("profile".to_string(), args.render.profile.name().to_string()),
+        ("k_nucleus".to_string(), params.k_nucleus.to_string()),
+        ("k_eosin".to_string(), params.k_eosin.to_string()),
+        ("beta_hematoxylin".to_string(), format_rgb(params.beta[0])),
+        ("beta_eosin".to_string(), format_rgb(params.beta[1])),
+        ("color_encoding".to_string(), params.encoding.name().to_string()),
+    ];
+    if params.dither != Dither::None {
+        annotations.push(("dither".to_string(), params.dither.name().to_string()));
+    }
+    if args.render.extra_channel.is_some() {
+        annotations.push(("k_extra".to_string(), params.k_extra.to_string()));
+        annotations.push(("beta_extra".to_string(), format_rgb(params.beta_extra)));
+    }
+    if let Some(roi) = args.render.roi {
+        annotations.push(("roi".to_string(), roi.to_string()));
+        annotations.push(("roi_stats".to_string(), args.render.roi_stats.unwrap_or_default().name().to_string()));
+    }
+    for (name, invert) in [("nucleus", args.render.invert_nucleus), ("eosin", args.render.invert_eosin)] {
+        if invert {
+            annotations.push((format!("invert_{}", name), "true".to_string()));
+        }
+    }
+    if let Some(target) = args.render.resample_to {
+        annotations.push(("resample_to".to_string(), target.to_string()));
+    }
+    if let Some([dx, dy]) = args.render.shift_eosin {
+        annotations.push(("shift_eosin".to_string(), format!("{},{}", dx, dy)));
+    }
+    if args.render.auto_align {
+        annotations.push(("auto_align".to_string(), "true".to_string()));
+    }
+    if let Some(factor) = args.render.downsample {
+        annotations.push(("downsample".to_string(), factor.to_string()));
+    }
+    if args.render.crosstalk != [0.0, 0.0] {
+        annotations.push(("crosstalk".to_string(), format!("{},{}", args.render.crosstalk[0], args.render.crosstalk[1])));
+    }
+    for (name, load, scale) in [
+        ("nucleus", &job.nucleus_options, &job.nucleus_scale),
+        ("eosin", &job.eosin_options, &job.eosin_scale),
+    ] {
+        if let Some(flat) = &load.flat_field {
+            annotations.push((format!("flatfield_{}", name), flat.display().to_string()));
+        }
+        if let Some(dark) = &load.dark_field {
+            annotations.push((format!("darkfield_{}", name), dark.display().to_string()));
+        }
+        if let Some(size) = load.despeckle {
+            annotations.push((format!("despeckle_{}", name), size.to_string()));
+        }
+        if load.blur_sigma > 0.0 {
+            annotations.push((format!("blur_{}", name), load.blur_sigma.to_string()));
+        }
+        annotations.push((format!("gamma_{}", name), scale.gamma.to_string()));
+        match scale.equalize {
+            Some(Equalization::Clahe { tile_size, clip_limit }) => {
+                annotations.push((format!("equalize_{}", name), "clahe".to_string()));
+                annotations.push((format!("clahe_tile_size_{}", name), tile_size.to_string()));
+                annotations.push((format!("clahe_clip_{}", name), clip_limit.to_string()));
+            }
+            Some(equalization) => annotations.push((format!("equalize_{}", name), equalization.name().to_string())),
+            None => {}
+        }
+        if let Some(range) = fixed_range(load, scale) {
+            annotations.push((format!("range_{}", name), format_range(range)));
+            continue;
+        }
+        if scale.window.is_some() {
+            annotations.push((format!("scaling_{}", name), "none".to_string()));
+            continue;
+        }
+        annotations.push((format!("percentile_{}", name), scale.percentile.to_string()));
+        if let Some(floor) = scale.floor_percentile {
+            annotations.push((format!("floor_percentile_{}", name), floor.to_string()));
+        }
+        annotations.push((format!("percentile_method_{}", name), scale.percentile_method.name().to_string()));
+        if let Some(method) = scale.auto_contrast {
+            annotations.push((format!("auto_contrast_{}", name), method.name().to_string()));
+        }
+    }
+    annotations
+}
+
+/// The nucleus and eosin inputs of a pair as files, for renders that do not decode them whole.
+pub(crate) fn pair_files(args: &Args, nucleus_path: &str, eosin_path: &str) -> Vec<Input> {
+    let mut inputs = vec![Input::file("nucleus", nucleus_path)];
+    if args.render.eosin_inputs.is_empty() {
+        inputs.push(Input::file("eosin", eosin_path));
+    }
+    inputs
+}
+
+/// Write the provenance file of a pair rendered from `inputs` and the auxiliary inputs with the
+/// hematoxylin and eosin stains, and the extra stain if `extra`, with the `checksum` of the output.
+pub(crate) fn write_pair_provenance(
+    args: &Args,
+    progress: &Progress,
+    output_path: &str,
+    params: &Params,
+    extra: bool,
+    mut inputs: Vec<Input>,
+    checksum: Option<Checksum>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !provenance::enabled(args, output_path) {
+        return Ok(());
+    }
+    inputs.extend(provenance::auxiliary_inputs(args));
+    let [hematoxylin, eosin, extra_stain] = params.stains();
+    let mut stains = vec![("hematoxylin", hematoxylin), ("eosin", eosin)];
+    stains.extend(extra.then_some(("extra", extra_stain)));
+    provenance::write(args, progress, output_path, params, &stains, inputs, checksum)
+}
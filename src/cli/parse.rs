@@ -0,0 +1,537 @@
+//! Parsers of the values of command-line flags, e.g. ranges, colors, regions of interest and
+//! channel selections, the types they parse into, and their formatting in messages.
+use std::path::Path;
+use virtualhe::overlay::Corner;
+use virtualhe::{Roi, Stain};
+
+/// Parse the edge length of the median filter.
+pub(crate) fn parse_despeckle(s: &str) -> Result<usize, String> {
+    match s {
+        "3" => Ok(3),
+        "5" => Ok(5),
+        _ => Err(format!("median filter size must be 3 or 5, got {}", s)),
+    }
+}
+
+/// Parse a positive downsampling factor.
+pub(crate) fn parse_downsample(s: &str) -> Result<u32, String> {
+    let factor = s.parse::<u32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if factor == 0 {
+        return Err(format!("downsampling factor must be positive, got {}", s));
+    }
+    Ok(factor)
+}
+
+/// Parse a non-negative blur sigma.
+pub(crate) fn parse_sigma(s: &str) -> Result<f32, String> {
+    let sigma = s.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if !sigma.is_finite() || sigma < 0.0 {
+        return Err(format!("sigma must be non-negative, got {}", s));
+    }
+    Ok(sigma)
+}
+
+/// Parse a positive gamma.
+pub(crate) fn parse_gamma(s: &str) -> Result<f32, String> {
+    let gamma = s.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if !gamma.is_finite() || gamma <= 0.0 {
+        return Err(format!("gamma must be positive, got {}", s));
+    }
+    Ok(gamma)
+}
+
+/// Parse a positive edge length of the tiles of CLAHE.
+pub(crate) fn parse_clahe_tile_size(s: &str) -> Result<usize, String> {
+    let size = s.parse::<usize>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if size == 0 {
+        return Err(format!("tile size must be positive, got {}", s));
+    }
+    Ok(size)
+}
+
+/// Parse a clip limit of CLAHE of at least 1.
+pub(crate) fn parse_clahe_clip(s: &str) -> Result<f32, String> {
+    let limit = s.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if !limit.is_finite() || limit < 1.0 {
+        return Err(format!("clip limit must be at least 1, got {}", s));
+    }
+    Ok(limit)
+}
+
+/// Parse a non-negative k factor.
+pub(crate) fn parse_k(s: &str) -> Result<f32, String> {
+    let k = s.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if !k.is_finite() || k < 0.0 {
+        return Err(format!("k must be non-negative, got {}", s));
+    }
+    Ok(k)
+}
+
+/// Parse a transmittance in (0, 1).
+pub(crate) fn parse_transmittance(s: &str) -> Result<f32, String> {
+    let transmittance = s.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if !(transmittance > 0.0 && transmittance < 1.0) {
+        return Err(format!("transmittance must be in (0, 1), got {}", s));
+    }
+    Ok(transmittance)
+}
+
+/// Parse a positive input maximum.
+pub(crate) fn parse_input_max(s: &str) -> Result<f32, String> {
+    let max = s.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if !max.is_finite() || max <= 0.0 {
+        return Err(format!("input max must be positive, got {}", s));
+    }
+    Ok(max)
+}
+
+/// Parse a percentile in (0, 100].
+pub(crate) fn parse_percentile(s: &str) -> Result<f32, String> {
+    let percentile = s.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if !(percentile > 0.0 && percentile <= 100.0) {
+        return Err(format!("percentile must be in (0, 100], got {}", s));
+    }
+    Ok(percentile)
+}
+
+/// Parse a floor percentile in [0, 100).
+pub(crate) fn parse_floor_percentile(s: &str) -> Result<f32, String> {
+    let percentile = s.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if !(0.0..100.0).contains(&percentile) {
+        return Err(format!("floor percentile must be in [0, 100), got {}", s));
+    }
+    Ok(percentile)
+}
+
+/// Parse a tile size, a positive multiple of 16.
+pub(crate) fn parse_tile_size(s: &str) -> Result<u32, String> {
+    let size = s.parse::<u32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if size == 0 || !size.is_multiple_of(16) {
+        return Err(format!("tile size must be a positive multiple of 16, got {}", s));
+    }
+    Ok(size)
+}
+
+/// Parse a positive amount of memory in bytes, with an optional K, M, G or T suffix for powers of
+/// 1024 and an optional B after it.
+pub(crate) fn parse_memory(s: &str) -> Result<u64, String> {
+    let upper = s.trim().to_ascii_uppercase();
+    let number = upper.strip_suffix('B').unwrap_or(&upper);
+    let (number, unit) = match number.char_indices().last() {
+        Some((i, unit @ ('K' | 'M' | 'G' | 'T'))) => {
+            let power = "KMGT".find(unit).expect("a unit") + 1;
+            (&number[..i], 1u64 << (10 * power))
+        }
+        _ => (number, 1),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok((value * unit as f64) as u64),
+        _ => Err(format!("expected a positive size such as 8G, 512M or 1073741824, got {}", s)),
+    }
+}
+
+/// Parse a positive pixel size as X or X,Y, the same size for both axes when only X is given.
+pub(crate) fn parse_pixel_size(s: &str) -> Result<[f32; 2], String> {
+    let parse = |value: &str| {
+        let size = value.trim().parse::<f32>().map_err(|e| format!("invalid value '{}': {}", value, e))?;
+        if !size.is_finite() || size <= 0.0 {
+            return Err(format!("pixel size must be positive, got {}", value));
+        }
+        Ok(size)
+    };
+    match s.split_once(',') {
+        Some((x, y)) => Ok([parse(x)?, parse(y)?]),
+        None => parse(s).map(|size| [size, size]),
+    }
+}
+
+/// Format a pixel size as X, or X,Y when the axes differ.
+pub(crate) fn format_pixel_size([x, y]: [f32; 2]) -> String {
+    if x == y {
+        x.to_string()
+    } else {
+        format!("{},{}", x, y)
+    }
+}
+
+/// Parse reference image paths given as NUCLEUS,EOSIN.
+pub(crate) fn parse_reference(s: &str) -> Result<(String, String), String> {
+    match s.split_once(',') {
+        Some((nucleus, eosin)) if !nucleus.is_empty() && !eosin.is_empty() => Ok((nucleus.to_string(), eosin.to_string())),
+        _ => Err(format!("expected NUCLEUS,EOSIN, got {}", s)),
+    }
+}
+
+/// Parse a fixed intensity window, two comma-separated values with 0 <= MIN < MAX.
+pub(crate) fn parse_range(s: &str) -> Result<[f32; 2], String> {
+    let (min, max) = s.split_once(',').ok_or_else(|| format!("expected MIN,MAX, got {}", s))?;
+    let parse = |v: &str| v.trim().parse::<f32>().map_err(|e| format!("invalid value '{}': {}", v, e));
+    let (min, max) = (parse(min)?, parse(max)?);
+    if !(min.is_finite() && max.is_finite() && 0.0 <= min && min < max) {
+        return Err(format!("range must satisfy 0 <= MIN < MAX, got {}", s));
+    }
+    Ok([min, max])
+}
+
+/// Parse an input channel given as PATH:R,G,B:K, split from the right so that paths may hold colons.
+pub(crate) fn parse_channel(s: &str) -> Result<ChannelSpec, String> {
+    let mut parts = s.rsplitn(3, ':');
+    let (Some(k), Some(beta), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(format!("expected PATH:R,G,B:K, got {}", s));
+    };
+    if path.is_empty() {
+        return Err(format!("expected PATH:R,G,B:K, got {}", s));
+    }
+    Ok(ChannelSpec {
+        path: path.to_string(),
+        stain: Stain {
+            beta: parse_beta(beta)?,
+            k: parse_k(k)?,
+        },
+    })
+}
+
+/// Parse an eosin input given as PATH[:WEIGHT], a suffix that is not a number is part of the path.
+pub(crate) fn parse_eosin_input(s: &str) -> Result<EosinInput, String> {
+    let (path, weight) = match s.rsplit_once(':') {
+        Some((path, weight)) if weight.parse::<f32>().is_ok() => (path, Some(weight.parse::<f32>().expect("checked above"))),
+        _ => (s, None),
+    };
+    if path.is_empty() {
+        return Err(format!("expected PATH[:WEIGHT], got {}", s));
+    }
+    if let Some(weight) = weight.filter(|w| !w.is_finite() || *w < 0.0) {
+        return Err(format!("eosin weight must be non-negative, got {}", weight));
+    }
+    Ok(EosinInput {
+        path: path.to_string(),
+        weight,
+    })
+}
+
+/// Parse the synthetic eosin of --no-eosin given as constant:V with V in [0, 1].
+pub(crate) fn parse_synthetic_eosin(s: &str) -> Result<f32, String> {
+    let value = s.strip_prefix("constant:").ok_or_else(|| format!("expected constant:V, got {}", s))?;
+    let value = value.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", value, e))?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("synthetic eosin must be in [0, 1], got {}", value));
+    }
+    Ok(value)
+}
+
+/// Parse bleed-through coefficients given as A,B in [0, 1].
+pub(crate) fn parse_crosstalk(s: &str) -> Result<[f32; 2], String> {
+    let (a, b) = s.split_once(',').ok_or_else(|| format!("expected A,B, got {}", s))?;
+    let parse = |v: &str| v.trim().parse::<f32>().map_err(|e| format!("invalid value '{}': {}", v, e));
+    let crosstalk = [parse(a)?, parse(b)?];
+    if !crosstalk.iter().all(|c| (0.0..=1.0).contains(c)) {
+        return Err(format!("crosstalk coefficients must be in [0, 1], got {}", s));
+    }
+    Ok(crosstalk)
+}
+
+/// Parse a translation given as DX,DY in pixels.
+pub(crate) fn parse_shift(s: &str) -> Result<[f32; 2], String> {
+    let (dx, dy) = s.split_once(',').ok_or_else(|| format!("expected DX,DY, got {}", s))?;
+    let parse = |v: &str| v.trim().parse::<f32>().map_err(|e| format!("invalid value '{}': {}", v, e));
+    let shift = [parse(dx)?, parse(dy)?];
+    if !shift.iter().all(|d| d.is_finite()) {
+        return Err(format!("shift must be finite, got {}", s));
+    }
+    Ok(shift)
+}
+
+/// Parse a region of interest given as X,Y,WIDTH,HEIGHT in pixels.
+pub(crate) fn parse_roi(s: &str) -> Result<Roi, String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<usize>().map_err(|e| format!("invalid value '{}': {}", v, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let [x, y, width, height] = values[..] else {
+        return Err(format!("expected X,Y,WIDTH,HEIGHT, got {}", s));
+    };
+    if width == 0 || height == 0 {
+        return Err(format!("region of interest must not be empty, got {}", s));
+    }
+    Ok(Roi { x, y, width, height })
+}
+
+/// Parse raw input dimensions given as WIDTHxHEIGHT.
+pub(crate) fn parse_raw_dims(s: &str) -> Result<(usize, usize), String> {
+    let (width, height) = s.split_once('x').ok_or_else(|| format!("expected WIDTHxHEIGHT, got {}", s))?;
+    let parse = |v: &str| v.trim().parse::<usize>().map_err(|e| format!("invalid value '{}': {}", v, e));
+    let (width, height) = (parse(width)?, parse(height)?);
+    if width == 0 || height == 0 {
+        return Err(format!("raw dimensions must be positive, got {}", s));
+    }
+    Ok((width, height))
+}
+
+/// Parse a thumbnail given as PATH[:MAXDIM] with a PNG or JPEG path, a suffix that is not a number
+/// is part of the path.
+pub(crate) fn parse_thumbnail(s: &str) -> Result<Thumbnail, String> {
+    let (path, max_size) = match s.rsplit_once(':') {
+        Some((path, size)) if size.parse::<usize>().is_ok() => (path, size.parse::<usize>().expect("checked above")),
+        _ => (s, virtualhe::DEFAULT_THUMBNAIL_SIZE),
+    };
+    if max_size == 0 {
+        return Err(format!("thumbnail size must be positive, got {}", s));
+    }
+    let extension = Path::new(path).extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+    if !matches!(extension.as_deref(), Some("png" | "jpg" | "jpeg")) {
+        return Err(format!("thumbnail must be a PNG or JPEG path, got {}", path));
+    }
+    Ok(Thumbnail {
+        path: path.to_string(),
+        max_size,
+    })
+}
+
+/// Parse a scale bar given as LENGTH_UM[:CORNER] with a positive length.
+pub(crate) fn parse_scale_bar(s: &str) -> Result<(f32, Corner), String> {
+    let (length, corner) = match s.split_once(':') {
+        Some((length, corner)) => (length, corner.parse()?),
+        None => (s, Corner::default()),
+    };
+    let length = length.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", length, e))?;
+    if !length.is_finite() || length <= 0.0 {
+        return Err(format!("scale bar length must be positive, got {}", s));
+    }
+    Ok((length, corner))
+}
+
+/// Parse an 8bit RGB color given as R,G,B.
+pub(crate) fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let values: Vec<_> = s.split(',').map(|v| v.trim().parse::<u8>()).collect();
+    match values[..] {
+        [Ok(r), Ok(g), Ok(b)] => Ok([r, g, b]),
+        _ => Err(format!("expected R,G,B with values in 0..=255, got {}", s)),
+    }
+}
+
+/// Parse an opacity in [0, 1].
+pub(crate) fn parse_alpha(s: &str) -> Result<f32, String> {
+    let alpha = s.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if !(0.0..=1.0).contains(&alpha) {
+        return Err(format!("opacity must be in [0, 1], got {}", s));
+    }
+    Ok(alpha)
+}
+
+/// Parse a non-negative tolerance in percent, with or without a trailing %.
+pub(crate) fn parse_tolerance(s: &str) -> Result<f32, String> {
+    let value = s.strip_suffix('%').unwrap_or(s);
+    let percent = value.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if !percent.is_finite() || percent < 0.0 {
+        return Err(format!("tolerance must be a non-negative percentage, got {}", s));
+    }
+    Ok(percent)
+}
+
+/// Parse a percentage of pixels in [0, 100], with or without a trailing %.
+pub(crate) fn parse_saturation(s: &str) -> Result<f32, String> {
+    let value = s.strip_suffix('%').unwrap_or(s);
+    let percent = value.parse::<f32>().map_err(|e| format!("invalid value '{}': {}", s, e))?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("saturation must be in [0, 100] percent, got {}", s));
+    }
+    Ok(percent)
+}
+
+/// Parse a filename pattern holding the `{id}` placeholder exactly once.
+pub(crate) fn parse_pattern(s: &str) -> Result<String, String> {
+    if s.matches("{id}").count() != 1 {
+        return Err(format!("pattern must contain {{id}} exactly once, got {}", s));
+    }
+    Ok(s.to_string())
+}
+
+/// Format red, green, and blue coefficients as comma-separated values, as accepted by --beta-*.
+pub(crate) fn format_rgb(values: [f32; 3]) -> String {
+    format!("{},{},{}", values[0], values[1], values[2])
+}
+
+/// Parse three comma-separated non-negative floats (e.g., 0.86,1.0,0.30).
+pub(crate) fn parse_beta(s: &str) -> Result<[f32; 3], String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<f32>().map_err(|e| format!("invalid value '{}': {}", v, e)))
+        .collect::<Result<Vec<f32>, String>>()?;
+    let beta: [f32; 3] = values
+        .try_into()
+        .map_err(|v: Vec<f32>| format!("expected 3 comma-separated values (R,G,B), got {}", v.len()))?;
+    if beta.iter().any(|v| !v.is_finite() || *v < 0.0) {
+        return Err(format!("beta values must be non-negative, got {}", s));
+    }
+    Ok(beta)
+}
+
+/// Input channel of --channel with the stain it is rendered as.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ChannelSpec {
+    pub(crate) path: String,
+    pub(crate) stain: Stain,
+}
+
+/// Eosin input of --eosin with its weight in the sum.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct EosinInput {
+    pub(crate) path: String,
+    pub(crate) weight: Option<f32>,
+}
+
+/// Preview of --thumbnail written next to the rendered image.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Thumbnail {
+    /// Path of the thumbnail, in which {name} stands for the file stem of the output.
+    pub(crate) path: String,
+    pub(crate) max_size: usize,
+}
+
+impl Thumbnail {
+    /// Path of the thumbnail of the image saved to `output_path`.
+    pub(crate) fn path_for(&self, output_path: &str) -> String {
+        let name = Path::new(output_path).file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+        self.path.replace("{name}", &name)
+    }
+}
+
+/// Input channel selected by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InputChannel {
+    Nucleus,
+    Eosin,
+}
+
+impl InputChannel {
+    /// Name of the channel as used on the command line.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            InputChannel::Nucleus => "nucleus",
+            InputChannel::Eosin => "eosin",
+        }
+    }
+}
+
+impl std::str::FromStr for InputChannel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nucleus" => Ok(InputChannel::Nucleus),
+            "eosin" => Ok(InputChannel::Eosin),
+            _ => Err(format!("unknown channel '{}', expected one of: nucleus, eosin", s)),
+        }
+    }
+}
+
+/// How the images of a batch are normalized.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Normalization {
+    /// Percentiles of each image.
+    #[default]
+    Image,
+    /// Percentiles over all images of the batch.
+    Global,
+}
+
+impl std::str::FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "image" => Ok(Normalization::Image),
+            "global" => Ok(Normalization::Global),
+            _ => Err(format!("unknown normalization '{}', expected one of: image, global", s)),
+        }
+    }
+}
+
+/// Grid that --resample-to resamples the channels onto.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ResampleTarget {
+    /// The grid of this channel, the other one is resampled.
+    Channel(InputChannel),
+    /// A fixed size in pixels, both channels are resampled.
+    Size { width: usize, height: usize },
+}
+
+impl std::fmt::Display for ResampleTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResampleTarget::Channel(channel) => write!(f, "{}", channel.name()),
+            ResampleTarget::Size { width, height } => write!(f, "{}x{}", width, height),
+        }
+    }
+}
+
+impl std::str::FromStr for ResampleTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(channel) = s.parse::<InputChannel>() {
+            return Ok(ResampleTarget::Channel(channel));
+        }
+        let size = s.split_once('x').and_then(|(w, h)| Some((w.parse::<usize>().ok()?, h.parse::<usize>().ok()?)));
+        match size {
+            Some((width, height)) if width > 0 && height > 0 => Ok(ResampleTarget::Size { width, height }),
+            _ => Err(format!("unknown grid '{}', expected one of: nucleus, eosin, or a size WxH", s)),
+        }
+    }
+}
+
+/// Byte order of multi-byte samples of raw inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl std::str::FromStr for ByteOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "le" => Ok(ByteOrder::Little),
+            "be" => Ok(ByteOrder::Big),
+            _ => Err(format!("unknown byte order '{}', expected one of: le, be", s)),
+        }
+    }
+}
+
+/// Pixels the percentiles are computed over when cropping to --roi.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum RoiStats {
+    /// Only the region of interest.
+    #[default]
+    Roi,
+    /// The whole image.
+    Full,
+}
+
+impl RoiStats {
+    /// Name of the setting as used on the command line.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            RoiStats::Roi => "roi",
+            RoiStats::Full => "full",
+        }
+    }
+}
+
+impl std::str::FromStr for RoiStats {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "roi" => Ok(RoiStats::Roi),
+            "full" => Ok(RoiStats::Full),
+            _ => Err(format!("unknown ROI statistics '{}', expected one of: roi, full", s)),
+        }
+    }
+}
+
+/// Format a fixed window as MIN,MAX, as accepted by --nucleus-range and --eosin-range.
+pub(crate) fn format_range([min, max]: [f32; 2]) -> String {
+    format!("{},{}", min, max)
+}
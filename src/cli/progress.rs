@@ -0,0 +1,137 @@
+//! Progress of a run on stderr: the reading and channel summaries, the progress bars of the
+//! stages of a render, and the checksums printed once an image is written.
+use super::checksum::Checksum;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use virtualhe::{ChannelInfo, Error, LoadOptions};
+
+/// Report which file, and which page or channel of it, is being read.
+pub(crate) fn print_reading(progress: &Progress, path: &str, options: &LoadOptions) {
+    let path = if path == virtualhe::STDIO_PATH { "stdin" } else { path };
+    match (options.page, &options.channel) {
+        (Some(page), _) => progress.println(format!("Reading {} page {}", path, page)),
+        (None, Some(channel)) => progress.println(format!("Reading {} channel {}", path, channel)),
+        (None, None) => progress.println(format!("Reading {}", path)),
+    }
+}
+
+/// Name of the output in messages, stdout for -.
+pub(crate) fn output_name(output_path: &str) -> &str {
+    if output_path == virtualhe::STDIO_PATH {
+        "stdout"
+    } else {
+        output_path
+    }
+}
+
+/// Report the decoded size, pixel format, and normalization of a channel.
+pub(crate) fn print_channel_info(progress: &Progress, info: &ChannelInfo) {
+    progress.println(format!(
+        "  {}x{} {}, normalized by {}",
+        info.width, info.height, info.pixel_format, info.input_max
+    ));
+}
+
+/// Progress bars of the rendering phases on stderr, hidden with --quiet or when stderr is not a
+/// terminal. Lines printed through it are kept clear of the bars.
+#[derive(Clone)]
+pub(crate) struct Progress {
+    pub(crate) bars: MultiProgress,
+    /// Id of the image in batch runs, shown before its phases.
+    prefix: Option<String>,
+    /// Whether lines are printed, not with --json so that stdout holds only the JSON.
+    lines: bool,
+    /// Whether lines are printed to stderr, when the output image is written to stdout.
+    stderr: bool,
+    /// Start of the run, or of the image in batch runs.
+    start: Instant,
+    /// Names and durations of the phases run since the start, for --provenance.
+    phases: Arc<Mutex<Vec<(String, Duration)>>>,
+}
+
+impl Progress {
+    pub(crate) fn new(quiet: bool, lines: bool, stderr: bool) -> Self {
+        let target = if quiet { ProgressDrawTarget::hidden() } else { ProgressDrawTarget::stderr() };
+        Progress {
+            bars: MultiProgress::with_draw_target(target),
+            prefix: None,
+            lines,
+            stderr,
+            start: Instant::now(),
+            phases: Arc::default(),
+        }
+    }
+
+    /// Progress of the image `id` of a batch, timed from now.
+    pub(crate) fn for_image(&self, id: &str) -> Self {
+        Progress {
+            bars: self.bars.clone(),
+            prefix: Some(id.to_string()),
+            lines: self.lines,
+            stderr: self.stderr,
+            start: Instant::now(),
+            phases: Arc::default(),
+        }
+    }
+
+    /// Print a line to stdout above the bars, or to stderr.
+    pub(crate) fn println(&self, line: impl std::fmt::Display) {
+        match (self.lines, self.stderr) {
+            (true, false) => self.bars.suspend(|| println!("{}", line)),
+            (true, true) => self.bars.suspend(|| eprintln!("{}", line)),
+            (false, _) => {}
+        }
+    }
+
+    /// Run one phase of a render with a spinner showing its name and elapsed time.
+    pub(crate) fn phase<R>(&self, name: &str, run: impl FnOnce() -> R) -> R {
+        let style = ProgressStyle::with_template("{spinner} {prefix}{msg} [{elapsed}]").expect("valid template");
+        let bar = self.bars.add(ProgressBar::new_spinner().with_style(style).with_message(name.to_string()));
+        if let Some(prefix) = &self.prefix {
+            bar.set_prefix(format!("{}: ", prefix));
+        }
+        bar.enable_steady_tick(Duration::from_millis(100));
+        let start = Instant::now();
+        let result = run();
+        bar.finish_and_clear();
+        log::debug!("{}{} took {:.2?}", bar.prefix(), name, start.elapsed());
+        self.phases.lock().expect("phases are recorded without panicking").push((name.to_string(), start.elapsed()));
+        result
+    }
+
+    /// Fail with `Error::Interrupted` if an interrupt requested the render of `output_path` to stop,
+    /// telling the last phase it finished.
+    pub(crate) fn check_interrupt(&self, output_path: &str) -> Result<(), Error> {
+        virtualhe::interrupt::check(|| {
+            let phase = match self.phases().last() {
+                Some((name, _)) => format!("after the phase '{}'", name),
+                None => "before the first phase".to_string(),
+            };
+            format!("{}: interrupted {} [{:.1?}], nothing was saved", output_name(output_path), phase, self.elapsed())
+        })
+    }
+
+    /// Time since the start of the run, or of the image in batch runs.
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Names and durations of the phases run so far, in the order they finished.
+    pub(crate) fn phases(&self) -> Vec<(String, Duration)> {
+        self.phases.lock().expect("phases are recorded without panicking").clone()
+    }
+
+    /// Overall bar of the `len` images of a batch.
+    pub(crate) fn images(&self, len: usize) -> ProgressBar {
+        let style = ProgressStyle::with_template("{bar:40} {pos}/{len} images [{elapsed}, eta {eta}]").expect("valid template");
+        self.bars.add(ProgressBar::new(len as u64).with_style(style))
+    }
+}
+
+/// Print the checksums of --checksum of a saved output.
+pub(crate) fn print_checksum(progress: &Progress, checksum: Option<&Checksum>) {
+    if let Some(checksum) = checksum {
+        progress.println(checksum.to_string());
+    }
+}
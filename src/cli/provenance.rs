@@ -0,0 +1,282 @@
+//! Provenance files written by --provenance next to each output as `<output>.json`, recording the
+//! inputs, the scaling and color model a virtual image was rendered with, and how long it took.
+//!
+//! The layout is versioned by `schema_version`: fields may be added within a version, a field that
+//! changes its meaning or is removed increments it.
+use super::checksum::Checksum;
+use super::progress::Progress;
+use super::{config, staging};
+use crate::Args;
+use rayon::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use virtualhe::{ChannelInfo, Error, Params, ScaleOptions, Stain, Thresholds};
+
+/// Version of the layout of provenance files.
+const SCHEMA_VERSION: u32 = 1;
+
+/// Size of the reads of the input hashes.
+const HASH_BUFFER: usize = 1 << 20;
+
+/// Contents of a provenance file.
+#[derive(Serialize)]
+struct Provenance<'a> {
+    schema_version: u32,
+    tool: &'static str,
+    version: &'static str,
+    output: &'a str,
+    inputs: Vec<Input>,
+    color_model: ColorModel,
+    /// Checksums of the output of --checksum, null without.
+    checksum: Option<Checksum>,
+    /// Effective rendering settings as in a parameter file, see --print-config.
+    settings: serde_json::Value,
+    phases: Vec<Phase>,
+    total_seconds: f64,
+}
+
+/// An input file, with its decoded size and scaling for the channels that are rendered.
+#[derive(Serialize)]
+pub(crate) struct Input {
+    /// What the input is used as: nucleus, eosin, eosin_input, channel, extra, mask, annotations,
+    /// flatfield_nucleus, flatfield_eosin, darkfield, reference_nucleus, reference_eosin or reference_stats.
+    role: &'static str,
+    path: String,
+    /// SHA-256 of the file, null for stdin, directories and inputs combined from several files.
+    sha256: Option<String>,
+    #[serde(flatten)]
+    decoded: Option<Decoded>,
+}
+
+/// Size, bit depth and scaling of a decoded channel, intensities in input units.
+#[derive(Serialize)]
+struct Decoded {
+    width: usize,
+    height: usize,
+    pixel_format: String,
+    bits_per_sample: Option<u32>,
+    input_max: f32,
+    /// Percentiles of the floor and ceiling, null with a fixed window.
+    floor_percentile: Option<f32>,
+    percentile: Option<f32>,
+    floor: f32,
+    ceiling: f32,
+    /// Method of --auto-contrast that selected the pixels of the percentiles, if any.
+    auto_contrast: Option<&'static str>,
+    gamma: f32,
+}
+
+/// Color model the channels were rendered with, one stain per rendered channel.
+#[derive(Serialize)]
+struct ColorModel {
+    color_encoding: &'static str,
+    exact: bool,
+    dither: &'static str,
+    stains: Vec<StainRecord>,
+}
+
+#[derive(Serialize)]
+struct StainRecord {
+    name: String,
+    k: f32,
+    beta: [f32; 3],
+}
+
+/// Duration of a phase of the render, in the order the phases finished.
+#[derive(Serialize)]
+struct Phase {
+    name: String,
+    seconds: f64,
+}
+
+impl Input {
+    /// An input file that is not rendered as a channel of its own.
+    pub(crate) fn file(role: &'static str, path: &str) -> Self {
+        Input {
+            role,
+            path: path.to_string(),
+            sha256: None,
+            decoded: None,
+        }
+    }
+
+    /// A rendered channel, scaled with `scale` by `thresholds`.
+    pub(crate) fn channel(role: &'static str, path: &str, info: &ChannelInfo, scale: &ScaleOptions, thresholds: Thresholds) -> Self {
+        let bits = info.pixel_format.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+        Input {
+            decoded: Some(Decoded {
+                width: info.width,
+                height: info.height,
+                pixel_format: info.pixel_format.clone(),
+                bits_per_sample: bits.trim_end_matches('F').parse().ok(),
+                input_max: info.input_max,
+                floor_percentile: scale.window.is_none().then(|| scale.floor_percentile.unwrap_or(0.0)),
+                percentile: scale.window.is_none().then_some(scale.percentile),
+                floor: thresholds.floor * info.input_max,
+                ceiling: thresholds.ceiling * info.input_max,
+                auto_contrast: scale.auto_contrast.filter(|_| scale.window.is_none()).map(|method| method.name()),
+                gamma: scale.gamma,
+            }),
+            ..Input::file(role, path)
+        }
+    }
+}
+
+/// The inputs of a run besides the rendered channels: eosin inputs, extra channel, mask,
+/// flat-fields and references.
+pub(crate) fn auxiliary_inputs(args: &Args) -> Vec<Input> {
+    let render = &args.render;
+    let mut inputs: Vec<_> = render.eosin_inputs.iter().map(|input| Input::file("eosin_input", &input.path)).collect();
+    let files = [
+        ("extra", render.extra_channel.as_deref()),
+        ("mask", render.mask.as_deref()),
+        ("annotations", render.annotations.as_deref()),
+        ("flatfield_nucleus", render.flatfield_nucleus.as_deref()),
+        ("flatfield_eosin", render.flatfield_eosin.as_deref()),
+        ("darkfield", render.darkfield.as_deref()),
+        ("reference_nucleus", render.reference.as_ref().map(|(nucleus, _)| nucleus.as_str())),
+        ("reference_eosin", render.reference.as_ref().map(|(_, eosin)| eosin.as_str())),
+        ("reference_stats", render.reference_stats.as_deref()),
+    ];
+    inputs.extend(files.into_iter().filter_map(|(role, path)| path.map(|path| Input::file(role, path))));
+    inputs
+}
+
+/// Whether a provenance file is written for the output `output_path`.
+pub(crate) fn enabled(args: &Args, output_path: &str) -> bool {
+    (args.render.provenance || !args.render.no_provenance) && output_path != virtualhe::STDIO_PATH && args.preview.is_none()
+}
+
+/// Path of the provenance file of `output_path`.
+pub(crate) fn path_for(output_path: &str) -> String {
+    format!("{}.json", output_path)
+}
+
+/// Hash the inputs and write the provenance file of the image saved to `output_path`, rendered
+/// with `stains` named after the channels, with its `checksum` if computed.
+pub(crate) fn write(
+    args: &Args,
+    progress: &Progress,
+    output_path: &str,
+    params: &Params,
+    stains: &[(&str, Stain)],
+    mut inputs: Vec<Input>,
+    checksum: Option<Checksum>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    progress.phase("Hashing inputs", || {
+        inputs.par_iter_mut().try_for_each(|input| -> Result<(), Error> {
+            input.sha256 = sha256(&input.path)?;
+            Ok(())
+        })
+    })?;
+    // Inputs and outputs at URLs are recorded by their URL rather than their temporary files
+    inputs.iter_mut().for_each(|input| input.path = staging::source(&input.path));
+    let output = staging::source(output_path);
+    let settings: toml::Table = toml::from_str(&config::effective(&args.render))?;
+    let provenance = Provenance {
+        schema_version: SCHEMA_VERSION,
+        tool: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        output: &output,
+        inputs,
+        color_model: ColorModel {
+            color_encoding: params.encoding.name(),
+            exact: params.exact,
+            dither: params.dither.name(),
+            stains: stains
+                .iter()
+                .map(|(name, stain)| StainRecord {
+                    name: name.to_string(),
+                    k: stain.k,
+                    beta: stain.beta,
+                })
+                .collect(),
+        },
+        checksum,
+        settings: serde_json::to_value(settings)?,
+        phases: progress
+            .phases()
+            .into_iter()
+            .map(|(name, duration)| Phase {
+                name,
+                seconds: duration.as_secs_f64(),
+            })
+            .collect(),
+        total_seconds: progress.elapsed().as_secs_f64(),
+    };
+    let path = path_for(output_path);
+    let json = serde_json::to_string_pretty(&provenance)?;
+    virtualhe::atomic::write(Path::new(&path), |temporary| Ok(std::fs::write(temporary, json)?)).map_err(|e| Error::Write {
+        path: path.clone().into(),
+        message: format!("{}: {}", path, e),
+    })?;
+    progress.println(format!("Provenance saved to: {}", path));
+    Ok(())
+}
+
+/// Check that the output at `output_path` was completed by an earlier run, for --resume: its
+/// provenance file records the output, the current settings and the `inputs`, given as roles and
+/// paths, that were not modified since, and the hash of the output file if one was recorded.
+/// Returns why the output is not complete otherwise.
+pub(crate) fn completed(args: &Args, output_path: &str, inputs: &[(&str, &str)]) -> Result<(), String> {
+    let path = path_for(output_path);
+    if !Path::new(output_path).exists() {
+        return Err(format!("{} does not exist", output_path));
+    }
+    let modified = |path: &str| std::fs::metadata(path).and_then(|metadata| metadata.modified());
+    let written = modified(&path).map_err(|_| format!("{} does not exist", path))?;
+    let invalid = |e: &dyn std::fmt::Display| format!("{} is not a valid provenance file: {}", path, e);
+    let text = std::fs::read_to_string(&path).map_err(|e| invalid(&e))?;
+    let provenance: serde_json::Value = serde_json::from_str(&text).map_err(|e| invalid(&e))?;
+    if provenance["schema_version"] != SCHEMA_VERSION || provenance["output"] != output_path {
+        return Err(invalid(&"another schema version or output"));
+    }
+    let settings: toml::Table = toml::from_str(&config::effective(&args.render)).map_err(|e| e.to_string())?;
+    if provenance["settings"] != serde_json::to_value(settings).map_err(|e| e.to_string())? {
+        return Err(format!("{} was rendered with other settings", output_path));
+    }
+    let recorded = provenance["inputs"].as_array().map_or(&[][..], Vec::as_slice);
+    for &(role, input) in inputs {
+        if !recorded.iter().any(|recorded| recorded["role"] == role && recorded["path"] == input) {
+            return Err(format!("{} was rendered from another {} input", output_path, role));
+        }
+        if modified(input).map_err(|e| format!("{}: {}", input, e))? > written {
+            return Err(format!("{} was modified after {} was rendered", input, output_path));
+        }
+    }
+    if let Some(expected) = provenance["checksum"]["file_sha256"].as_str() {
+        if sha256(output_path).map_err(|e| e.to_string())?.as_deref() != Some(expected) {
+            return Err(format!("{} does not match the SHA-256 of {}", output_path, path));
+        }
+    }
+    Ok(())
+}
+
+/// SHA-256 of a file as lowercase hex, None for stdin and paths that are not files.
+pub(crate) fn sha256(path: &str) -> Result<Option<String>, Error> {
+    if path == virtualhe::STDIO_PATH || !Path::new(path).is_file() {
+        return Ok(None);
+    }
+    let open_error = |source| Error::Open {
+        path: path.into(),
+        source,
+    };
+    let mut file = File::open(path).map_err(open_error)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; HASH_BUFFER];
+    loop {
+        match file.read(&mut buffer).map_err(open_error)? {
+            0 => break,
+            n => hasher.update(&buffer[..n]),
+        }
+    }
+    Ok(Some(hex(&hasher.finalize())))
+}
+
+/// Lowercase hex of the bytes of a hash.
+pub(crate) fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,721 @@
+//! Rendering of a pair: loading and resampling the inputs, the render itself, tiled or in memory,
+//! and the variants of a single channel, of the nucleus only and of a preview sheet.
+use super::checks::tiled_fallback;
+use super::job::Job;
+use super::output::{
+    dicom_options, dicom_path, dzi_options, dzi_path, generate, pair_annotations, pair_files, save_components,
+    save_options, save_rendered, write_pair_provenance,
+};
+use super::parse::{format_rgb, EosinInput, InputChannel, ResampleTarget, RoiStats};
+use super::progress::{output_name, print_channel_info, print_checksum, print_reading, Progress};
+use super::provenance::{self, Input};
+use crate::{Args, Preview};
+use ndarray::Array2;
+use std::path::Path;
+use virtualhe::alpha::AlphaRule;
+use virtualhe::stack::{StackChannel, StackOptions, StackOutput};
+use virtualhe::tiled::{TiledChannel, TiledOptions};
+use virtualhe::{ChannelInfo, Dither, Error, OutputDepth, Params, Roi, SaveOptions, ScaleOptions, Stain};
+
+/// Decoded and normalized channel with the details of how it was read.
+pub(crate) type Channel = (Array2<f32>, ChannelInfo);
+
+/// Nucleus and eosin channels of a pair, with the shift applied to the eosin channel if any.
+pub(crate) type Pair = (Channel, Channel, Option<[f32; 2]>);
+
+/// Read the nucleus and eosin channels of a pair, cropped to a common size and registered if
+/// requested.
+pub(crate) fn load_pair(
+    args: &Args,
+    job: &Job,
+    progress: &Progress,
+    nucleus_path: &str,
+    eosin_path: &str,
+) -> Result<Pair, Box<dyn std::error::Error>> {
+    // Read images into ndarray, the nucleus and eosin channels on separate threads. They are
+    // reported once both are read so that the lines of the channels stay apart
+    let eosin_paths: Vec<&str> = match args.render.eosin_inputs.is_empty() {
+        true => vec![eosin_path],
+        false => args.render.eosin_inputs.iter().map(|input| input.path.as_str()).collect(),
+    };
+    let (nucleus, eosin_channels) = rayon::join(
+        || progress.phase("Decoding nucleus", || virtualhe::load_channel_with(nucleus_path, &job.nucleus_options)),
+        || {
+            eosin_paths
+                .iter()
+                .map(|path| progress.phase("Decoding eosin", || virtualhe::load_channel_with(path, &job.eosin_options)))
+                .collect::<Result<Vec<_>, _>>()
+        },
+    );
+    let (mut nucleus, nucleus_info) = nucleus?;
+    let eosin_channels = eosin_channels?;
+    print_reading(progress, nucleus_path, &job.nucleus_options);
+    print_channel_info(progress, &nucleus_info);
+    for (path, (_, info)) in eosin_paths.iter().zip(&eosin_channels) {
+        print_reading(progress, path, &job.eosin_options);
+        print_channel_info(progress, info);
+    }
+
+    let (mut eosin, eosin_info) = if args.render.eosin_inputs.is_empty() {
+        eosin_channels.into_iter().next().expect("one eosin channel")
+    } else {
+        combine_eosin_inputs(progress, &args.render.eosin_inputs, eosin_channels)?
+    };
+
+    // Check that the channels line up before any processing starts
+    if let Some(target) = args.render.resample_to {
+        (nucleus, eosin) = resample_pair(args, progress, target, (nucleus_path, nucleus), (eosin_path, eosin))?;
+    }
+    if nucleus.dim() != eosin.dim() {
+        if !args.render.crop_to_common {
+            return Err(Error::ShapeMismatch {
+                nucleus: nucleus_path.into(),
+                nucleus_size: (nucleus.ncols(), nucleus.nrows()),
+                eosin: eosin_path.into(),
+                eosin_size: (eosin.ncols(), eosin.nrows()),
+                plane: None,
+            }
+            .into());
+        }
+        (nucleus, eosin) = virtualhe::crop_to_common(nucleus, eosin);
+        progress.println(format!("Cropped channels to common size {}x{}", nucleus.ncols(), nucleus.nrows()));
+    }
+    let shift = if args.render.auto_align {
+        let [dx, dy] = progress.phase("Aligning eosin", || {
+            virtualhe::align::estimate_shift(&nucleus, &eosin, virtualhe::align::DEFAULT_MAX_SHIFT)
+        });
+        Some([dx as f32, dy as f32])
+    } else {
+        args.render.shift_eosin
+    };
+    if let Some([dx, dy]) = shift {
+        progress.println(format!("Using eosin shift: {},{}", dx, dy));
+        if [dx, dy] != [0.0, 0.0] {
+            eosin = progress.phase("Shifting eosin", || virtualhe::align::shift(&eosin, [dx, dy]));
+        }
+    }
+    if args.render.crosstalk != [0.0, 0.0] {
+        let [a, b] = args.render.crosstalk;
+        log::debug!("subtracting crosstalk: eosin - {} * nucleus, nucleus - {} * eosin", a, b);
+        virtualhe::subtract_crosstalk(&mut nucleus, &mut eosin, args.render.crosstalk);
+    }
+    Ok(((nucleus, nucleus_info), (eosin, eosin_info), shift))
+}
+
+/// Resample the channels of a pair onto the grid of --resample-to, failing if their aspect ratios
+/// differ by more than --aspect-tolerance.
+pub(crate) fn resample_pair(
+    args: &Args,
+    progress: &Progress,
+    target: ResampleTarget,
+    (nucleus_path, nucleus): (&str, Array2<f32>),
+    (eosin_path, eosin): (&str, Array2<f32>),
+) -> Result<(Array2<f32>, Array2<f32>), Error> {
+    let (width, height) = match target {
+        ResampleTarget::Channel(InputChannel::Nucleus) => (nucleus.ncols(), nucleus.nrows()),
+        ResampleTarget::Channel(InputChannel::Eosin) => (eosin.ncols(), eosin.nrows()),
+        ResampleTarget::Size { width, height } => (width, height),
+    };
+    let aspect = |image: &Array2<f32>| image.ncols() as f32 / image.nrows().max(1) as f32;
+    let target_aspect = width as f32 / height as f32;
+    for (path, image) in [(nucleus_path, &nucleus), (eosin_path, &eosin)] {
+        let difference = (aspect(image) / target_aspect - 1.0).abs() * 100.0;
+        if difference > args.render.aspect_tolerance {
+            return Err(Error::InvalidOptions(format!(
+                "{} is {}x{}, its aspect ratio differs from the {}x{} grid by {:.2}%, more than --aspect-tolerance {}% (different fields of view?)",
+                path,
+                image.ncols(),
+                image.nrows(),
+                width,
+                height,
+                difference,
+                args.render.aspect_tolerance
+            )));
+        }
+    }
+
+    let resample = |name: &str, path: &str, image: Array2<f32>| {
+        if image.dim() == (height, width) {
+            return image;
+        }
+        progress.println(format!("Resampling {} {} from {}x{} to {}x{}", name, path, image.ncols(), image.nrows(), width, height));
+        progress.phase("Resampling", || virtualhe::align::resample(&image, width, height))
+    };
+    Ok((resample("nucleus", nucleus_path, nucleus), resample("eosin", eosin_path, eosin)))
+}
+
+/// Combine the decoded --eosin inputs into their weighted sum, with the details of the first.
+pub(crate) fn combine_eosin_inputs(progress: &Progress, inputs: &[EosinInput], channels: Vec<Channel>) -> Result<Channel, Error> {
+    let mut images: Vec<Array2<f32>> = Vec::with_capacity(inputs.len());
+    let mut first_info = None;
+    for (input, (image, info)) in inputs.iter().zip(channels) {
+        if let Some(first) = images.first() {
+            if image.dim() != first.dim() {
+                return Err(Error::SizeMismatch {
+                    input: inputs[0].path.clone().into(),
+                    input_size: (first.ncols(), first.nrows()),
+                    other: input.path.clone().into(),
+                    other_size: (image.ncols(), image.nrows()),
+                    kind: "eosin input",
+                });
+            }
+        }
+        images.push(image);
+        first_info.get_or_insert(info);
+    }
+
+    // Without weights the inputs count equally
+    let weights: Vec<f32> =
+        inputs.iter().map(|input| input.weight.unwrap_or(1.0 / inputs.len() as f32)).collect();
+    log::debug!("combining {} eosin inputs with weights {:?}", inputs.len(), weights);
+    let eosin = progress.phase("Combining eosin inputs", || virtualhe::weighted_sum(&images, &weights));
+    Ok((eosin, first_info.expect("at least one eosin input")))
+}
+
+/// Read and scale the extra channel, which must have the size of the nucleus channel.
+pub(crate) fn load_extra(
+    args: &Args,
+    job: &Job,
+    progress: &Progress,
+    path: &str,
+    nucleus_path: &str,
+    nucleus: &Array2<f32>,
+    mask: Option<&Array2<bool>>,
+) -> Result<Array2<f32>, Box<dyn std::error::Error>> {
+    print_reading(progress, path, &job.extra_options);
+    let (mut extra, info) =
+        progress.phase("Decoding extra channel", || virtualhe::load_channel_with(path, &job.extra_options))?;
+    print_channel_info(progress, &info);
+    if extra.dim() != nucleus.dim() {
+        return Err(Error::SizeMismatch {
+            input: nucleus_path.into(),
+            input_size: (nucleus.ncols(), nucleus.nrows()),
+            other: path.into(),
+            other_size: (extra.ncols(), extra.nrows()),
+            kind: "extra channel",
+        }
+        .into());
+    }
+    let thresholds = progress.phase("Computing extra channel percentiles", || {
+        virtualhe::scale_with_mask(&mut extra, &integer_scale(&job.extra_scale, &info), mask).map_err(|e| scale_error(path, e))
+    })?;
+    log::debug!("{}: floor {} and ceiling {}", path, thresholds.floor, thresholds.ceiling);
+    check_saturation(args, path, &job.extra_scale, virtualhe::saturated_fraction(&extra))?;
+    Ok(extra)
+}
+
+/// Tissue mask of --mask or --auto-mask that the percentiles of a pair are computed over, if any.
+pub(crate) fn load_mask(
+    args: &Args,
+    job: &Job,
+    progress: &Progress,
+    nucleus_path: &str,
+    nucleus: &Array2<f32>,
+    eosin: &Array2<f32>,
+) -> Result<Option<Array2<bool>>, Box<dyn std::error::Error>> {
+    let mask = if let Some(path) = &args.render.mask {
+        let mut mask = progress.phase("Reading mask", || virtualhe::mask::read_mask(path))?;
+        // The mask is drawn on the input frame
+        if let Some(roi) = job.nucleus_options.roi {
+            mask = roi.crop(&mask).map_err(|e| Error::InvalidOptions(format!("{}: {}", path, e)))?;
+        }
+        if job.nucleus_options.downsample > 1 {
+            mask = virtualhe::mask::downsample_mask(&mask, job.nucleus_options.downsample);
+        }
+        if mask.dim() != nucleus.dim() {
+            return Err(Error::SizeMismatch {
+                input: nucleus_path.into(),
+                input_size: (nucleus.ncols(), nucleus.nrows()),
+                other: path.into(),
+                other_size: (mask.ncols(), mask.nrows()),
+                kind: "mask",
+            }
+            .into());
+        }
+        mask
+    } else if args.render.auto_mask {
+        let threshold = progress.phase("Computing tissue mask", || virtualhe::mask::otsu_threshold(eosin));
+        progress.println(format!("Using Otsu threshold of the eosin channel: {}", threshold));
+        virtualhe::mask::threshold_mask(eosin, threshold)
+    } else {
+        return Ok(None);
+    };
+    let tissue = mask.iter().filter(|&&m| m).count();
+    progress.println(format!("  mask selects {:.2}% of pixels", 100.0 * tissue as f64 / mask.len().max(1) as f64));
+    Ok(Some(mask))
+}
+
+/// Crop a scaled channel to --roi when its percentiles are computed over the whole image.
+pub(crate) fn crop_scaled<T: Clone>(args: &Args, path: &str, image: Array2<T>) -> Result<Array2<T>, Error> {
+    match args.render.roi {
+        Some(roi) if args.render.roi_stats == Some(RoiStats::Full) => {
+            // The region is given in the frame of the input images
+            let roi = roi.downsampled(args.render.downsample.unwrap_or(1) as usize);
+            roi.crop(&image).map_err(|e| Error::InvalidOptions(format!("{}: {}", path, e)))
+        }
+        _ => Ok(image),
+    }
+}
+
+/// Error of scaling the channel read from `path`.
+pub(crate) fn scale_error(path: &str, error: Box<dyn std::error::Error>) -> Error {
+    Error::Scale {
+        path: path.into(),
+        message: format!("{}: {}", path, error),
+    }
+}
+
+/// Scaling options of the job for a channel decoded as `info`, whose percentiles are counted in a
+/// histogram if its values are integers.
+pub(crate) fn integer_scale(scale: &ScaleOptions, info: &ChannelInfo) -> ScaleOptions {
+    scale.clone().with_integer_input_max(info.input_max)
+}
+
+/// Log the fraction of saturated pixels of a channel, warn above --saturation-warning and fail
+/// above --strict-saturation.
+pub(crate) fn check_saturation(args: &Args, path: &str, scale: &ScaleOptions, fraction: f32) -> Result<(), Error> {
+    let percent = fraction * 100.0;
+    let hint = if scale.window.is_some() { "try a higher range maximum" } else { "try a higher --percentile" };
+    log::debug!("{}: {:.4}% of pixels saturate", path, percent);
+    if let Some(limit) = args.render.strict_saturation {
+        if percent > limit {
+            return Err(Error::Scale {
+                path: path.into(),
+                message: format!(
+                    "{}: {:.4}% of pixels saturate, more than --strict-saturation {}% ({})",
+                    path, percent, limit, hint
+                ),
+            });
+        }
+    }
+    if percent > args.render.saturation_warning {
+        log::warn!(
+            "{}: {:.4}% of pixels saturate, more than {}% ({})",
+            path,
+            percent,
+            args.render.saturation_warning,
+            hint
+        );
+    }
+    Ok(())
+}
+
+/// Render the virtual image of the --channel inputs and save it to `output_path`.
+pub(crate) fn render_channels(args: &Args, job: &Job, progress: &Progress, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let first = &args.render.channels[0].path;
+    let paths: Vec<&str> = args.render.channels.iter().map(|channel| channel.path.as_str()).collect();
+    let save_options = save_options(args, job, progress, &paths, || {
+        let mut annotations = vec![("color_encoding".to_string(), job.params.encoding.name().to_string())];
+        if job.params.dither != Dither::None {
+            annotations.push(("dither".to_string(), job.params.dither.name().to_string()));
+        }
+        for (index, channel) in args.render.channels.iter().enumerate() {
+            annotations.push((format!("channel_{}", index), channel.path.clone()));
+            annotations.push((format!("k_{}", index), channel.stain.k.to_string()));
+            annotations.push((format!("beta_{}", index), format_rgb(channel.stain.beta)));
+        }
+        annotations.push(("percentile".to_string(), job.extra_scale.percentile.to_string()));
+        annotations.push(("gamma".to_string(), job.extra_scale.gamma.to_string()));
+        if let Some(floor) = job.extra_scale.floor_percentile {
+            annotations.push(("floor_percentile".to_string(), floor.to_string()));
+        }
+        annotations.push(("percentile_method".to_string(), job.extra_scale.percentile_method.name().to_string()));
+        if let Some(method) = job.extra_scale.auto_contrast {
+            annotations.push(("auto_contrast".to_string(), method.name().to_string()));
+        }
+        if let Some(roi) = args.render.roi {
+            annotations.push(("roi".to_string(), roi.to_string()));
+            annotations.push(("roi_stats".to_string(), args.render.roi_stats.unwrap_or_default().name().to_string()));
+        }
+        annotations
+    });
+
+    // Every channel is scaled on its own with the options of the extra channel, --percentile and
+    // --floor-percentile, without the gamma or equalization of the nucleus and eosin channels
+    let mut channels: Vec<Array2<f32>> = Vec::with_capacity(args.render.channels.len());
+    let mut inputs = Vec::with_capacity(args.render.channels.len());
+    for channel in &args.render.channels {
+        print_reading(progress, &channel.path, &job.extra_options);
+        let (mut image, info) = progress.phase("Decoding", || virtualhe::load_channel_with(&channel.path, &job.extra_options))?;
+        print_channel_info(progress, &info);
+        if let Some(first_image) = channels.first() {
+            if image.dim() != first_image.dim() {
+                return Err(Error::SizeMismatch {
+                    input: first.into(),
+                    input_size: (first_image.ncols(), first_image.nrows()),
+                    other: channel.path.clone().into(),
+                    other_size: (image.ncols(), image.nrows()),
+                    kind: "channel",
+                }
+                .into());
+            }
+        }
+        let thresholds = progress.phase("Computing percentiles", || {
+            virtualhe::scale_with(&mut image, &integer_scale(&job.extra_scale, &info)).map_err(|e| scale_error(&channel.path, e))
+        })?;
+        check_saturation(args, &channel.path, &job.extra_scale, virtualhe::saturated_fraction(&image))?;
+        channels.push(image);
+        inputs.push(Input::channel("channel", &channel.path, &info, &job.extra_scale, thresholds));
+    }
+
+    let channels = channels
+        .into_iter()
+        .zip(&args.render.channels)
+        .map(|(image, channel)| crop_scaled(args, &channel.path, image))
+        .collect::<Result<Vec<_>, _>>()?;
+    let stains: Vec<Stain> = args.render.channels.iter().map(|channel| channel.stain).collect();
+    let views: Vec<_> = channels.iter().map(|channel| channel.view()).collect();
+    let checksum = match args.render.output_depth {
+        OutputDepth::Eight => {
+            let rgb = progress.phase("Generating RGB", || generate::<u8>(job, &views, &stains))?;
+            save_rendered(args, job, progress, rgb, output_path, &save_options, None)?
+        }
+        OutputDepth::Sixteen => {
+            let rgb = progress.phase("Generating RGB", || generate::<u16>(job, &views, &stains))?;
+            save_rendered(args, job, progress, rgb, output_path, &save_options, None)?
+        }
+    };
+    progress.println(format!("Virtual image saved to: {}", output_name(output_path)));
+    print_checksum(progress, checksum.as_ref());
+
+    if provenance::enabled(args, output_path) {
+        inputs.extend(provenance::auxiliary_inputs(args));
+        let names: Vec<_> = (0..stains.len()).map(|index| format!("channel_{}", index)).collect();
+        let stains: Vec<_> = names.iter().map(String::as_str).zip(stains).collect();
+        provenance::write(args, progress, output_path, &job.params, &stains, inputs, checksum)?;
+    }
+    Ok(())
+}
+
+/// Render the nucleus channel as hematoxylin on a white background, or on the flat tint of
+/// --synthetic-eosin, and save it to `output_path`.
+pub(crate) fn render_nucleus_only(
+    args: &Args,
+    job: &Job,
+    progress: &Progress,
+    nucleus_path: &str,
+    output_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let params = &job.params;
+    let save_options = save_options(args, job, progress, &[nucleus_path], || {
+        // Only the eosin color model applies to a synthetic eosin
+        let mut annotations: Vec<_> = pair_annotations(args, job, params)
+            .into_iter()
+            .filter(|(key, _)| {
+                let synthetic = args.render.synthetic_eosin.is_some();
+                !key.ends_with("_eosin") || (synthetic && (key == "k_eosin" || key == "beta_eosin"))
+            })
+            .collect();
+        let eosin = args.render.synthetic_eosin.map(|v| format!("constant:{}", v)).unwrap_or_else(|| "none".to_string());
+        annotations.push(("eosin".to_string(), eosin));
+        annotations
+    });
+
+    print_reading(progress, nucleus_path, &job.nucleus_options);
+    let (mut nucleus, nucleus_info) =
+        progress.phase("Decoding nucleus", || virtualhe::load_channel_with(nucleus_path, &job.nucleus_options))?;
+    print_channel_info(progress, &nucleus_info);
+    let thresholds = progress.phase("Computing percentiles", || {
+        virtualhe::scale_with(&mut nucleus, &integer_scale(&job.nucleus_scale, &nucleus_info))
+            .map_err(|e| scale_error(nucleus_path, e))
+    })?;
+    let nucleus = crop_scaled(args, nucleus_path, nucleus)?;
+    check_saturation(args, nucleus_path, &job.nucleus_scale, virtualhe::saturated_fraction(&nucleus))?;
+
+    // Only the hematoxylin term, unless a flat eosin is added
+    let eosin = args.render.synthetic_eosin.map(|value| Array2::from_elem(nucleus.dim(), value));
+    let mut channels = vec![nucleus.view()];
+    channels.extend(eosin.as_ref().map(|eosin| eosin.view()));
+    let stains = &params.stains()[..channels.len()];
+    let checksum = match args.render.output_depth {
+        OutputDepth::Eight => {
+            let rgb = progress.phase("Generating RGB", || generate::<u8>(job, &channels, stains))?;
+            save_rendered(args, job, progress, rgb, output_path, &save_options, None)?
+        }
+        OutputDepth::Sixteen => {
+            let rgb = progress.phase("Generating RGB", || generate::<u16>(job, &channels, stains))?;
+            save_rendered(args, job, progress, rgb, output_path, &save_options, None)?
+        }
+    };
+    progress.println(format!("Virtual H&E image saved to: {}", output_name(output_path)));
+    print_checksum(progress, checksum.as_ref());
+
+    if provenance::enabled(args, output_path) {
+        let mut inputs = vec![Input::channel("nucleus", nucleus_path, &nucleus_info, &job.nucleus_scale, thresholds)];
+        inputs.extend(provenance::auxiliary_inputs(args));
+        let stains: Vec<_> = ["hematoxylin", "eosin"].into_iter().zip(stains.iter().copied()).collect();
+        provenance::write(args, progress, output_path, params, &stains, inputs, checksum)?;
+    }
+    Ok(())
+}
+
+/// Render the scaled channels of a pair, and the extra channel after them, with each k value of a
+/// preview, cropped to the center unless cropped to --roi already, and save the renders side by
+/// side as a contact sheet.
+pub(crate) fn render_preview(
+    args: &Args,
+    job: &Job,
+    params: &Params,
+    progress: &Progress,
+    preview: &Preview,
+    channels: Vec<Array2<f32>>,
+    output_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (height, width) = channels[0].dim();
+    let field = Roi {
+        x: width.saturating_sub(preview.size) / 2,
+        y: height.saturating_sub(preview.size) / 2,
+        width: width.min(preview.size),
+        height: height.min(preview.size),
+    };
+    let crop = |image: &Array2<f32>| match args.render.roi {
+        Some(_) => Ok(image.clone()),
+        None => field.crop(image),
+    };
+    let channels = channels.iter().map(crop).collect::<Result<Vec<_>, _>>()?;
+    let views: Vec<_> = channels.iter().map(|channel| channel.view()).collect();
+
+    // One tile with the k of the color model without k values
+    let models: Vec<_> = if !preview.k_values.is_empty() {
+        preview.k_values.iter().map(|&k| (format!("k={}", k), params.with_k(k))).collect()
+    } else if params.k_nucleus == params.k_eosin {
+        vec![(format!("k={}", params.k_nucleus), *params)]
+    } else {
+        vec![(format!("k={}-{}", params.k_nucleus, params.k_eosin), *params)]
+    };
+    let tiles = progress.phase("Generating RGB", || {
+        models
+            .into_iter()
+            .map(|(label, params)| Ok((label, generate::<u8>(job, &views, &params.stains()[..views.len()])?)))
+            .collect::<Result<Vec<_>, Error>>()
+    })?;
+    let columns = (tiles.len() as f64).sqrt().ceil() as usize;
+    let sheet = virtualhe::montage::contact_sheet(&tiles, columns);
+    let options = SaveOptions {
+        jpeg_quality: args.render.jpeg_quality.unwrap_or(virtualhe::DEFAULT_JPEG_QUALITY),
+        compression: job.compression,
+        ..SaveOptions::default()
+    };
+    save_rendered(args, job, progress, sheet, output_path, &options, None)?;
+    progress.println(format!("Preview of {} k values saved to: {}", tiles.len(), output_name(output_path)));
+    Ok(())
+}
+
+/// Render the virtual H&E image of one pair of channel images and save it to `output_path`.
+/// The color model with the k of the channels without an explicit k estimated from the scaled
+/// channels for --auto-k, unchanged without it.
+pub(crate) fn estimate_k(args: &Args, params: &Params, progress: &Progress, nucleus: &Array2<f32>, eosin: &Array2<f32>) -> Params {
+    if !args.render.auto_k {
+        return *params;
+    }
+    let target = args.render.target_transmittance.unwrap_or(virtualhe::DEFAULT_TARGET_TRANSMITTANCE);
+    let mut params = *params;
+    let channels = [
+        ("nucleus", args.render.k_nucleus, nucleus, params.beta[0][1], &mut params.k_nucleus),
+        ("eosin", args.render.k_eosin, eosin, params.beta[1][1], &mut params.k_eosin),
+    ];
+    for (name, explicit, image, beta, k) in channels {
+        if explicit.is_some() {
+            continue;
+        }
+        match progress.phase("Estimating k", || virtualhe::auto_k(image, beta, target)) {
+            Some(estimate) => {
+                *k = estimate;
+                progress.println(format!("Using estimated k {}: {}", name, estimate));
+            }
+            None => log::warn!("No tissue to estimate the k of the {} channel from, using k {}", name, k),
+        }
+    }
+    params
+}
+
+pub(crate) fn render_pair(
+    args: &Args,
+    job: &Job,
+    progress: &Progress,
+    nucleus_path: &str,
+    eosin_path: &str,
+    output_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let params = &job.params;
+
+    let fallback = tiled_fallback(args, job, nucleus_path, eosin_path, output_path)?;
+    if let Some(reason) = &fallback {
+        progress.println(format!("Rendering {} tile by tile: {}", output_name(output_path), reason));
+    }
+    if args.render.tiled || fallback.is_some() {
+        let save_options = save_options(args, job, progress, &[nucleus_path, eosin_path], || pair_annotations(args, job, params));
+        let nucleus = TiledChannel {
+            path: Path::new(nucleus_path),
+            load: &job.nucleus_options,
+            scale: &job.nucleus_scale,
+        };
+        let eosin = TiledChannel {
+            path: Path::new(eosin_path),
+            load: &job.eosin_options,
+            scale: &job.eosin_scale,
+        };
+        let dicom = match dicom_path(args, output_path) {
+            Some(path) => Some((path, dicom_options(args, params.encoding, save_options.pixel_size_um)?)),
+            None => None,
+        };
+        let options = TiledOptions {
+            tile_size: args.render.tile_size.unwrap_or(virtualhe::tiled::DEFAULT_TILE_SIZE),
+            crop_to_common: args.render.crop_to_common,
+            output_depth: args.render.output_depth,
+            compression: job.compression,
+            bigtiff: args.render.bigtiff,
+            ome: save_options.ome,
+            pixel_size_um: save_options.pixel_size_um,
+            parameters: save_options.parameters,
+            zarr: save_options.zarr,
+            resume: args.render.resume,
+            dzi: dzi_path(args, output_path).map(|path| (path, dzi_options(args))),
+            dicom,
+        };
+        let dzi = options.dzi.as_ref().map(|(path, _)| path.display().to_string());
+        let dicom = options.dicom.as_ref().map(|(path, _)| path.display().to_string());
+        progress.phase("Calculating and saving vH&E tile by tile", || {
+            virtualhe::tiled::render_tiled(&nucleus, &eosin, params, Path::new(output_path), &options)
+        })?;
+        progress.println(format!("Virtual H&E image saved to: {}", output_name(output_path)));
+        if let Some(path) = dzi {
+            progress.println(format!("Deep Zoom image saved to: {}", path));
+        }
+        if let Some(path) = dicom {
+            progress.println(format!("DICOM image saved to: {}", path));
+        }
+        return write_pair_provenance(args, progress, output_path, params, false, pair_files(args, nucleus_path, eosin_path), None);
+    }
+
+    if args.render.stack {
+        let save_options = save_options(args, job, progress, &[nucleus_path, eosin_path], || pair_annotations(args, job, params));
+        let nucleus = StackChannel {
+            path: Path::new(nucleus_path),
+            load: &job.nucleus_options,
+            scale: &job.nucleus_scale,
+        };
+        let eosin = StackChannel {
+            path: Path::new(eosin_path),
+            load: &job.eosin_options,
+            scale: &job.eosin_scale,
+        };
+        let options = StackOptions {
+            output: args.render.stack_output.unwrap_or_default(),
+            scaling: args.render.stack_scaling.unwrap_or_default(),
+            crop_to_common: args.render.crop_to_common,
+            output_depth: args.render.output_depth,
+        };
+        let planes = progress.phase("Calculating and saving vH&E plane by plane", || {
+            virtualhe::stack::render_stack(&nucleus, &eosin, params, Path::new(output_path), &options, &save_options)
+        })?;
+        match options.output {
+            StackOutput::Multipage => {
+                progress.println(format!("Virtual H&E stack of {} planes saved to: {}", planes, output_path))
+            }
+            StackOutput::Series => progress.println(format!(
+                "Virtual H&E stack of {} planes saved to: {}",
+                planes,
+                virtualhe::stack::series_path(Path::new(output_path), 0).display()
+            )),
+        }
+        return write_pair_provenance(args, progress, output_path, params, false, pair_files(args, nucleus_path, eosin_path), None);
+    }
+
+    let ((mut nucleus, nucleus_info), (mut eosin, eosin_info), _) = load_pair(args, job, progress, nucleus_path, eosin_path)?;
+    let mask = load_mask(args, job, progress, nucleus_path, &nucleus, &eosin)?;
+    progress.check_interrupt(output_path)?;
+
+    // Apply histogram scaling
+    let thresholds = progress.phase("Computing percentiles", || -> Result<_, Error> {
+        Ok([
+            virtualhe::scale_with_mask(&mut nucleus, &integer_scale(&job.nucleus_scale, &nucleus_info), mask.as_ref())
+                .map_err(|e| scale_error(nucleus_path, e))?,
+            virtualhe::scale_with_mask(&mut eosin, &integer_scale(&job.eosin_scale, &eosin_info), mask.as_ref())
+                .map_err(|e| scale_error(eosin_path, e))?,
+        ])
+    })?;
+    for ((path, info, scale), thresholds) in [
+        (nucleus_path, &nucleus_info, &job.nucleus_scale),
+        (eosin_path, &eosin_info, &job.eosin_scale),
+    ]
+    .into_iter()
+    .zip(thresholds)
+    {
+        if scale.window.is_some() {
+            log::debug!(
+                "{}: fixed range from {} to {} (intensities {} and {})",
+                path,
+                thresholds.floor,
+                thresholds.ceiling,
+                thresholds.floor * info.input_max,
+                thresholds.ceiling * info.input_max
+            );
+            continue;
+        }
+        log::debug!(
+            "{}: floor {} and ceiling {} at percentiles {} and {} (intensities {} and {})",
+            path,
+            thresholds.floor,
+            thresholds.ceiling,
+            scale.floor_percentile.unwrap_or(0.0),
+            scale.percentile,
+            thresholds.floor * info.input_max,
+            thresholds.ceiling * info.input_max
+        );
+    }
+    let extra = match &args.render.extra_channel {
+        Some(path) => Some(crop_scaled(args, path, load_extra(args, job, progress, path, nucleus_path, &nucleus, mask.as_ref())?)?),
+        None => None,
+    };
+    let nucleus = crop_scaled(args, nucleus_path, nucleus)?;
+    let eosin = crop_scaled(args, eosin_path, eosin)?;
+    let tissue = match mask {
+        Some(mask) if args.render.alpha_rule == Some(AlphaRule::Mask) => Some(crop_scaled(args, nucleus_path, mask)?),
+        _ => None,
+    };
+    check_saturation(args, nucleus_path, &job.nucleus_scale, virtualhe::saturated_fraction(&nucleus))?;
+    check_saturation(args, eosin_path, &job.eosin_scale, virtualhe::saturated_fraction(&eosin))?;
+    progress.check_interrupt(output_path)?;
+
+    let params = &estimate_k(args, params, progress, &nucleus, &eosin);
+
+    if let Some(preview) = &args.preview {
+        let channels = [nucleus, eosin].into_iter().chain(extra).collect();
+        return render_preview(args, job, params, progress, preview, channels, output_path);
+    }
+    let save_options = save_options(args, job, progress, &[nucleus_path, eosin_path], || pair_annotations(args, job, params));
+
+    let [hematoxylin, eosin_stain, extra_stain] = params.stains();
+    if let Some(dir) = &args.render.save_components {
+        let mut components = vec![("hematoxylin", nucleus.view(), hematoxylin), ("eosin", eosin.view(), eosin_stain)];
+        if let Some(extra) = &extra {
+            components.push(("extra", extra.view(), extra_stain));
+        }
+        save_components(args, job, progress, dir, output_path, &components)?;
+    }
+
+    // Generate virtual H&E image
+    let mut channels = vec![nucleus.view(), eosin.view()];
+    channels.extend(extra.as_ref().map(|extra| extra.view()));
+    let stains = &[hematoxylin, eosin_stain, extra_stain][..channels.len()];
+    let checksum = match args.render.output_depth {
+        OutputDepth::Eight => {
+            let rgb = progress.phase("Generating RGB", || generate::<u8>(job, &channels, stains))?;
+            save_rendered(args, job, progress, rgb, output_path, &save_options, tissue.as_ref())?
+        }
+        OutputDepth::Sixteen => {
+            let rgb = progress.phase("Generating RGB", || generate::<u16>(job, &channels, stains))?;
+            save_rendered(args, job, progress, rgb, output_path, &save_options, tissue.as_ref())?
+        }
+    };
+    progress.println(format!("Virtual H&E image saved to: {}", output_name(output_path)));
+    print_checksum(progress, checksum.as_ref());
+
+    let [nucleus_thresholds, eosin_thresholds] = thresholds;
+    let inputs = vec![
+        Input::channel("nucleus", nucleus_path, &nucleus_info, &job.nucleus_scale, nucleus_thresholds),
+        Input::channel("eosin", eosin_path, &eosin_info, &job.eosin_scale, eosin_thresholds),
+    ];
+    write_pair_provenance(args, progress, output_path, params, extra.is_some(), inputs, checksum)
+}
@@ -0,0 +1,484 @@
+//! HTTP rendering service of the `serve` subcommand, for viewers that request virtual H&E images
+//! without running the command line tool. A request is rendered as the command line renders a
+//! pair, into a temporary directory that is removed once the image is sent.
+//!
+//! - `POST /render` takes a multipart/form-data body with the files `nucleus` and `eosin` and the
+//!   optional fields `params` and `format`, or a JSON body `{"nucleus": PATH, "eosin": PATH,
+//!   "params": {...}, "format": "png"}` with paths relative to the directory of --root.
+//! - `GET /schema` describes the parameters, the formats and the defaults of the server.
+//!
+//! `params` is a JSON object of settings as in a parameter file. The options given to `serve`
+//! take precedence over them as the command line does over a parameter file, so they are fixed
+//! for every request. `format` is png (the default), tiff or jpeg. Failed requests are answered
+//! with a JSON object `{"error": MESSAGE}`.
+use super::config;
+use crate::{run_args, Args, Cli, RenderParams};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+use tiny_http::{Header, Method, Request, Response, Server};
+use virtualhe::Error;
+
+/// Address and port `serve` listens on by default.
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+/// Requests rendered at the same time by default.
+const DEFAULT_MAX_RENDERS: u32 = 1;
+
+/// Largest request body in MB accepted by default.
+const DEFAULT_MAX_UPLOAD: u64 = 1024;
+
+/// Output formats of the renders, with their file extension and content type.
+const FORMATS: [(&str, &str, &str); 3] = [("png", "png", "image/png"), ("tiff", "tif", "image/tiff"), ("jpeg", "jpg", "image/jpeg")];
+
+/// Arguments of the `serve` subcommand.
+#[derive(clap::Args, Debug)]
+pub(crate) struct ServeArgs {
+    /// Address and port to listen on.
+    #[arg(long, value_name = "ADDR", default_value = DEFAULT_LISTEN)]
+    listen: String,
+    /// Directory the paths of JSON requests are resolved in, which they cannot leave. Without it only uploaded images are rendered.
+    #[arg(long, value_name = "DIR")]
+    root: Option<String>,
+    /// Number of POST /render requests rendered at the same time. Further requests wait without their body being read until a render finishes, while GET /schema is answered at once. The renders share the CPU threads, so a higher limit shortens the wait of small requests rather than the time of each render.
+    #[arg(long, default_value_t = DEFAULT_MAX_RENDERS, value_parser = clap::value_parser!(u32).range(1..))]
+    max_renders: u32,
+    /// Largest request body accepted, in MB.
+    #[arg(long, value_name = "MB", default_value_t = DEFAULT_MAX_UPLOAD, value_parser = clap::value_parser!(u64).range(1..))]
+    max_upload: u64,
+    #[command(flatten)]
+    render: RenderParams,
+}
+
+/// Options of the `serve` subcommand.
+#[derive(Debug, Clone)]
+pub(crate) struct Serve {
+    listen: String,
+    root: Option<String>,
+    max_renders: usize,
+    /// Largest request body in bytes.
+    max_upload: u64,
+}
+
+impl ServeArgs {
+    /// The arguments of the subcommand as those of the invocation without one.
+    pub(crate) fn into_args(self) -> Args {
+        Args {
+            serve: Some(Serve {
+                listen: self.listen,
+                root: self.root,
+                max_renders: self.max_renders as usize,
+                max_upload: self.max_upload << 20,
+            }),
+            ..Args::new(self.render)
+        }
+    }
+}
+
+/// Body of a JSON request of `POST /render`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PathRequest {
+    nucleus: String,
+    eosin: String,
+    #[serde(default)]
+    params: Option<serde_json::Value>,
+    #[serde(default)]
+    format: Option<String>,
+}
+
+/// Description of the parameters answered by `GET /schema`.
+#[derive(Serialize)]
+struct Schema {
+    formats: Vec<&'static str>,
+    parameters: Vec<Parameter>,
+    /// Effective settings of a request without parameters, as printed by --print-config.
+    defaults: serde_json::Value,
+}
+
+/// A setting of the `params` of a request.
+#[derive(Serialize)]
+struct Parameter {
+    name: &'static str,
+    /// Command-line flag of the setting, whose help is `help`.
+    flag: String,
+    help: String,
+    /// Whether the setting was given to `serve`, which ignores it in requests.
+    fixed: bool,
+}
+
+/// A failed request, answered with its status and a JSON error message.
+struct Failure {
+    status: u16,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Failure {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for Failure {
+    /// Invalid parameters are the client's fault, inputs that cannot be rendered are unprocessable.
+    fn from(error: Box<dyn std::error::Error>) -> Self {
+        let status = match error.downcast_ref::<Error>() {
+            Some(Error::InvalidOptions(_)) => 400,
+            Some(
+                Error::Decode { .. }
+                | Error::UnsupportedFormat { .. }
+                | Error::ShapeMismatch { .. }
+                | Error::SizeMismatch { .. }
+                | Error::Scale { .. },
+            ) => 422,
+            Some(Error::Open { .. } | Error::Write { .. } | Error::Interrupted(_)) | None => 500,
+        };
+        Failure::new(status, error.to_string())
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(error: Error) -> Self {
+        Box::<dyn std::error::Error>::from(error).into()
+    }
+}
+
+/// Counting semaphore of the render slots.
+struct Slots {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A render slot, released when dropped.
+struct Slot<'a>(&'a Slots);
+
+impl Slots {
+    /// Wait for a free slot.
+    fn acquire(&self) -> Slot<'_> {
+        let free = self.free.lock().expect("no render panicked holding the slots");
+        let mut free = self.released.wait_while(free, |free| *free == 0).expect("no render panicked holding the slots");
+        *free -= 1;
+        Slot(self)
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().expect("no render panicked holding the slots") += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Temporary directory of a request, removed with its contents when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(id: u64) -> Result<Self, Failure> {
+        let path = std::env::temp_dir().join(format!("virtualhe-serve-{}-{}", std::process::id(), id));
+        fs::create_dir_all(&path).map_err(|e| Failure::new(500, format!("{}: {}", path.display(), e)))?;
+        Ok(TempDir(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            log::warn!("{}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// The service shared by the threads of the requests.
+struct Service<'a> {
+    /// Arguments of `serve`, which every request starts from.
+    args: &'a Args,
+    /// Matches of `serve`, telling the options given on its command line.
+    matches: &'a ArgMatches,
+    /// Canonical --root.
+    root: Option<PathBuf>,
+    max_upload: u64,
+    slots: Slots,
+    requests: AtomicU64,
+}
+
+/// Serve renders over HTTP until the process is interrupted, each request on a thread of its own
+/// and at most --max-renders of them rendering at the same time.
+pub(crate) fn run(args: &Args, serve: &Serve, matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let root = match &serve.root {
+        Some(root) => Some(fs::canonicalize(root).map_err(|source| Error::Open {
+            path: root.into(),
+            source,
+        })?),
+        None => None,
+    };
+    let server = Server::http(&serve.listen).map_err(|e| format!("{}: {}", serve.listen, e))?;
+    let address = server.server_addr().to_ip().map_or_else(|| serve.listen.clone(), |address| address.to_string());
+    println!("Serving on http://{}, rendering {} request(s) at a time", address, serve.max_renders);
+    let service = Service {
+        args,
+        matches,
+        root,
+        max_upload: serve.max_upload,
+        slots: Slots {
+            free: Mutex::new(serve.max_renders),
+            released: Condvar::new(),
+        },
+        requests: AtomicU64::new(0),
+    };
+    std::thread::scope(|scope| {
+        for request in server.incoming_requests() {
+            let service = &service;
+            scope.spawn(move || service.respond(request));
+        }
+    });
+    Ok(())
+}
+
+impl Service<'_> {
+    /// Answer a request and print a line with its status and duration.
+    fn respond(&self, mut request: Request) {
+        let start = Instant::now();
+        let (method, url) = (request.method().clone(), request.url().to_string());
+        let endpoint = url.split('?').next().unwrap_or_default();
+        let response = match (&method, endpoint) {
+            (Method::Get, "/schema") => self.schema().map(|schema| (schema, "application/json")),
+            (Method::Post, "/render") => self.render(&mut request),
+            (_, "/schema" | "/render") => Err(Failure::new(405, format!("{} is not allowed on {}", method, endpoint))),
+            _ => Err(Failure::new(404, format!("no endpoint {}, expected POST /render or GET /schema", endpoint))),
+        };
+        let (status, body, content_type) = match response {
+            Ok((body, content_type)) => (200, body, content_type),
+            Err(failure) => {
+                let body = serde_json::json!({ "error": failure.message }).to_string().into_bytes();
+                (failure.status, body, "application/json")
+            }
+        };
+        println!("{} {} {} in {:.2?}", method, url, status, start.elapsed());
+        let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).expect("valid header");
+        if let Err(e) = request.respond(Response::from_data(body).with_status_code(status).with_header(header)) {
+            log::warn!("{} {}: {}", method, url, e);
+        }
+    }
+
+    /// The description of the parameters of `GET /schema`.
+    fn schema(&self) -> Result<Vec<u8>, Failure> {
+        let command = Cli::command();
+        let render = command.find_subcommand("render").expect("render is a subcommand");
+        let parameters = setting_names()
+            .map(|name| {
+                let arg = render.get_arguments().find(|arg| arg.get_id() == name).expect("settings are named as flags");
+                Parameter {
+                    name,
+                    flag: match (arg.get_long(), arg.get_short()) {
+                        (Some(long), _) => format!("--{}", long),
+                        (None, Some(short)) => format!("-{}", short),
+                        (None, None) => name.to_string(),
+                    },
+                    help: arg.get_help().map(ToString::to_string).unwrap_or_default(),
+                    fixed: self.matches.value_source(name) == Some(ValueSource::CommandLine),
+                }
+            })
+            .collect();
+        let defaults: toml::Table =
+            toml::from_str(&config::effective(&self.args.render)).map_err(|e| Failure::new(500, e.to_string()))?;
+        let schema = Schema {
+            formats: FORMATS.iter().map(|(name, _, _)| *name).collect(),
+            parameters,
+            defaults: serde_json::to_value(defaults).map_err(|e| Failure::new(500, e.to_string()))?,
+        };
+        serde_json::to_vec_pretty(&schema).map_err(|e| Failure::new(500, e.to_string()))
+    }
+
+    /// Render the images of a `POST /render` request, once a slot is free, and return the encoded
+    /// image with its content type.
+    fn render(&self, request: &mut Request) -> Result<(Vec<u8>, &'static str), Failure> {
+        let _slot = self.slots.acquire();
+        let dir = TempDir::new(self.requests.fetch_add(1, Ordering::Relaxed))?;
+        let content_type = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Content-Type"))
+            .map(|header| header.value.as_str().to_string())
+            .unwrap_or_default();
+        let body = self.read_body(request)?;
+
+        let (nucleus, eosin, params, format) = if content_type.starts_with("multipart/form-data") {
+            let boundary = content_type
+                .split(';')
+                .find_map(|parameter| parameter.trim().strip_prefix("boundary="))
+                .map(|boundary| boundary.trim_matches('"'))
+                .ok_or_else(|| Failure::new(400, "multipart/form-data request without a boundary"))?;
+            let parts = parse_multipart(&body, boundary)?;
+            let text = |name: &str| -> Result<Option<String>, Failure> {
+                parts
+                    .iter()
+                    .find(|part| part.name == name)
+                    .map(|part| String::from_utf8(part.data.to_vec()).map_err(|_| Failure::new(400, format!("{} is not UTF-8 text", name))))
+                    .transpose()
+            };
+            let params = text("params")?
+                .map(|params| serde_json::from_str(&params))
+                .transpose()
+                .map_err(|e| Failure::new(400, format!("params: {}", e)))?;
+            let nucleus = save_part(&parts, "nucleus", &dir.0)?;
+            let eosin = save_part(&parts, "eosin", &dir.0)?;
+            (nucleus, eosin, params, text("format")?)
+        } else if content_type.starts_with("application/json") {
+            let request: PathRequest = serde_json::from_slice(&body).map_err(|e| Failure::new(400, format!("request: {}", e)))?;
+            (self.resolve(&request.nucleus)?, self.resolve(&request.eosin)?, request.params, request.format)
+        } else {
+            return Err(Failure::new(
+                415,
+                format!("expected a multipart/form-data or application/json request, got {:?}", content_type),
+            ));
+        };
+        let format = format.as_deref().unwrap_or("png");
+        let (_, extension, content_type) = FORMATS.iter().find(|(name, _, _)| *name == format).ok_or_else(|| {
+            Failure::new(400, format!("unknown format '{}', expected one of: png, tiff, jpeg", format))
+        })?;
+
+        let output = dir.0.join(format!("output.{}", extension));
+        let args = self.request_args(&nucleus, &eosin, &output, params)?;
+        // Uploads are named by their part in messages, not by the temporary directory
+        run_args(&args).map_err(|e| {
+            let failure = Failure::from(e);
+            let prefix = format!("{}{}", dir.0.display(), std::path::MAIN_SEPARATOR);
+            Failure::new(failure.status, failure.message.replace(&prefix, ""))
+        })?;
+        let image = fs::read(&output).map_err(|e| Failure::new(500, format!("{}: {}", output.display(), e)))?;
+        Ok((image, content_type))
+    }
+
+    /// Read the body of a request, refusing those above --max-upload.
+    fn read_body(&self, request: &mut Request) -> Result<Vec<u8>, Failure> {
+        let too_large = || Failure::new(413, format!("the request body exceeds the limit of {} MB", self.max_upload >> 20));
+        if request.body_length().is_some_and(|length| length as u64 > self.max_upload) {
+            return Err(too_large());
+        }
+        let mut body = Vec::new();
+        request
+            .as_reader()
+            .take(self.max_upload + 1)
+            .read_to_end(&mut body)
+            .map_err(|e| Failure::new(400, format!("reading the request body: {}", e)))?;
+        if body.len() as u64 > self.max_upload {
+            return Err(too_large());
+        }
+        Ok(body)
+    }
+
+    /// Path of an input below --root, refusing paths that leave it, also through symbolic links.
+    fn resolve(&self, path: &str) -> Result<PathBuf, Failure> {
+        let Some(root) = &self.root else {
+            return Err(Failure::new(403, "paths are not served without --root, upload the images instead"));
+        };
+        let resolved = fs::canonicalize(root.join(path)).map_err(|e| Failure::new(404, format!("{}: {}", path, e)))?;
+        if !resolved.starts_with(root) {
+            return Err(Failure::new(403, format!("{} is outside the served root", path)));
+        }
+        Ok(resolved)
+    }
+
+    /// The arguments of `serve` with the inputs and output of a request and its parameters, which
+    /// do not replace the options given to `serve`. No provenance file is written.
+    fn request_args(&self, nucleus: &Path, eosin: &Path, output: &Path, params: Option<serde_json::Value>) -> Result<Args, Failure> {
+        let path = |path: &Path| Some(path.to_string_lossy().into_owned());
+        let mut args = Args {
+            nucleus: path(nucleus),
+            eosin: path(eosin),
+            output: path(output),
+            ..self.args.clone()
+        };
+        if let Some(params) = params {
+            config::Config::parse(&params.to_string(), true, "params")?.apply(&mut args.render, self.matches)?;
+        }
+        args.render.provenance = false;
+        args.render.no_provenance = true;
+        Ok(args)
+    }
+}
+
+/// Names of the settings of a parameter file, in the order of the template of
+/// --write-default-config, which names every setting in a commented line `# NAME = VALUE`.
+fn setting_names() -> impl Iterator<Item = &'static str> {
+    config::DEFAULT_CONFIG.lines().filter_map(|line| {
+        let (name, _) = line.strip_prefix("# ")?.split_once(" = ")?;
+        name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_').then_some(name)
+    })
+}
+
+/// A part of a multipart/form-data body.
+struct Part<'a> {
+    name: String,
+    filename: Option<String>,
+    data: &'a [u8],
+}
+
+/// Split a multipart/form-data body into its parts.
+fn parse_multipart<'a>(body: &'a [u8], boundary: &str) -> Result<Vec<Part<'a>>, Failure> {
+    let malformed = |what: &str| Failure::new(400, format!("malformed multipart/form-data body: {}", what));
+    let delimiter = format!("\r\n--{}", boundary);
+    // The first delimiter may start the body without the line break before it
+    let start = find(body, &delimiter.as_bytes()[2..]).ok_or_else(|| malformed("no boundary"))?;
+    let mut rest = &body[start + delimiter.len() - 2..];
+    let mut parts = Vec::new();
+    while !rest.starts_with(b"--") {
+        rest = rest.strip_prefix(b"\r\n").ok_or_else(|| malformed("no line break after a boundary"))?;
+        let header_end = find(rest, b"\r\n\r\n").ok_or_else(|| malformed("no end of the part headers"))?;
+        let headers = String::from_utf8_lossy(&rest[..header_end]);
+        let content = &rest[header_end + 4..];
+        let end = find(content, delimiter.as_bytes()).ok_or_else(|| malformed("no closing boundary"))?;
+        let disposition = headers
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim().eq_ignore_ascii_case("content-disposition").then_some(value)
+            })
+            .ok_or_else(|| malformed("a part without Content-Disposition"))?;
+        let attribute = |key: &str| {
+            disposition.split(';').find_map(|attribute| {
+                let (name, value) = attribute.trim().split_once('=')?;
+                (name == key).then(|| value.trim_matches('"').to_string())
+            })
+        };
+        parts.push(Part {
+            name: attribute("name").ok_or_else(|| malformed("a part without a name"))?,
+            filename: attribute("filename"),
+            data: &content[..end],
+        });
+        rest = &content[end + delimiter.len()..];
+    }
+    Ok(parts)
+}
+
+/// Save the uploaded file of the part `name` into `dir`, keeping the extension of its filename
+/// that some formats are told apart by.
+fn save_part(parts: &[Part], name: &str, dir: &Path) -> Result<PathBuf, Failure> {
+    let part = parts
+        .iter()
+        .find(|part| part.name == name)
+        .ok_or_else(|| Failure::new(400, format!("no {} image in the request", name)))?;
+    let extension = part
+        .filename
+        .as_deref()
+        .and_then(|filename| Path::new(filename).extension()?.to_str())
+        .filter(|extension| extension.bytes().all(|b| b.is_ascii_alphanumeric()))
+        .unwrap_or("tif");
+    let path = dir.join(format!("{}.{}", name, extension));
+    fs::write(&path, part.data).map_err(|e| Failure::new(500, format!("{}: {}", path.display(), e)))?;
+    Ok(path)
+}
+
+/// Position of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
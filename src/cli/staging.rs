@@ -0,0 +1,197 @@
+//! Inputs and outputs at URLs: https:// and s3:// inputs are downloaded into a temporary directory
+//! before the render and read from there, except Zarr stores, which are read object by object, and
+//! an s3:// output is written into the directory and uploaded with the files written next to it,
+//! such as its provenance, once the render succeeds. The directory is deleted afterwards, and by an
+//! interrupt.
+use crate::Args;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use virtualhe::Error;
+
+/// URLs of the local paths their objects were downloaded to or will be uploaded from, which are
+/// recorded in the provenance instead.
+static SOURCES: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// The temporary directory of a run with inputs or an output at URLs, deleted when dropped.
+#[derive(Default)]
+pub(crate) struct Staged {
+    dir: Option<PathBuf>,
+    /// Directory the output is written into, and the URL of the output.
+    #[cfg(feature = "remote")]
+    output: Option<(PathBuf, String)>,
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            virtualhe::atomic::release(dir);
+        }
+    }
+}
+
+/// The URL of a staged local path, or the path itself.
+pub(crate) fn source(path: &str) -> String {
+    let sources = SOURCES.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    sources.iter().find(|(local, _)| local == path).map_or_else(|| path.to_string(), |(_, url)| url.clone())
+}
+
+/// Download the inputs of `args` given as URLs and point `args` at the downloads, and at a local
+/// path for an s3:// output, checking that it does not exist yet without --force.
+pub(crate) fn stage(args: &mut Args) -> Result<Staged, Box<dyn std::error::Error>> {
+    let render = &args.render;
+    // The last positional path is the output, wherever an input left out moved it
+    let output_path = match (&args.output, &args.eosin) {
+        _ if args.stats_only => None,
+        (Some(output), _) => Some(output.clone()),
+        (None, Some(output)) => Some(output.clone()),
+        (None, None) => args.nucleus.clone(),
+    };
+    let positional = [args.nucleus.as_deref(), args.eosin.as_deref(), args.output.as_deref()];
+    let mut inputs: Vec<&str> = positional.into_iter().flatten().filter(|path| Some(*path) != output_path.as_deref()).collect();
+    inputs.extend(render.eosin_inputs.iter().map(|input| input.path.as_str()));
+    inputs.extend(render.channels.iter().map(|channel| channel.path.as_str()));
+    inputs.extend(render.extra_channel.as_deref());
+    let output = output_path.clone().filter(|path| virtualhe::is_url(path));
+    let inputs: Vec<String> = inputs.into_iter().filter(|path| virtualhe::is_url(path)).map(str::to_string).collect();
+    if !cfg!(feature = "remote") && render.retries.is_some() {
+        return Err(Error::InvalidOptions("--retries requires a build with the remote feature".to_string()).into());
+    }
+    if inputs.is_empty() && output.is_none() {
+        return Ok(Staged::default());
+    }
+    if !cfg!(feature = "remote") {
+        let message = "https:// and s3:// paths require a build with the remote feature (cargo build --release --features remote)";
+        return Err(Error::InvalidOptions(message.to_string()).into());
+    }
+    if render.resume {
+        return Err(Error::InvalidOptions("--resume is not available with https:// or s3:// paths".to_string()).into());
+    }
+    #[cfg(feature = "remote")]
+    return stage_remote(args, inputs, output, output_path.as_deref() == Some(virtualhe::STDIO_PATH));
+    #[cfg(not(feature = "remote"))]
+    unreachable!("checked above")
+}
+
+/// Stage the `inputs` and `output` URLs of `args`, printing to stderr when the output image is
+/// written `to_stdout`.
+#[cfg(feature = "remote")]
+fn stage_remote(
+    args: &mut Args,
+    inputs: Vec<String>,
+    output: Option<String>,
+    to_stdout: bool,
+) -> Result<Staged, Box<dyn std::error::Error>> {
+    use super::progress::Progress;
+    use std::path::Path;
+    use virtualhe::remote;
+
+    if let Some(url) = &output {
+        if !url.starts_with("s3://") {
+            return Err(Error::InvalidOptions(format!("outputs can only be uploaded to s3:// URLs, got {}", url)).into());
+        }
+        if !args.render.force {
+            let exists = remote::exists(url).map_err(|e| {
+                Error::InvalidOptions(format!("{}: cannot check whether it exists, use --force to overwrite it: {}", url, e))
+            })?;
+            if exists {
+                return Err(Error::InvalidOptions(format!("{} exists, use --force to overwrite it", url)).into());
+            }
+        }
+    }
+    let dir = std::env::temp_dir().join(format!("virtualhe-{}", std::process::id()));
+    let write_error = |path: &Path, e: std::io::Error| Error::Write {
+        path: path.to_path_buf(),
+        message: format!("{}: {}", path.display(), e),
+    };
+    std::fs::create_dir_all(&dir).map_err(|e| write_error(&dir, e))?;
+    virtualhe::atomic::hold(&dir);
+    let mut staged = Staged {
+        dir: Some(dir.clone()),
+        output: None,
+    };
+
+    // Downloads keep the file name of their object, for the format and the names of the outputs
+    let render = &args.render;
+    let progress = Progress::new(render.quiet || render.verbose > 0, !args.json, to_stdout);
+    let mut local = Vec::new();
+    for url in inputs {
+        if remote::is_zarr(&url) || local.iter().any(|(downloaded, _)| *downloaded == url) {
+            continue;
+        }
+        let path = dir.join(local.len().to_string()).join(file_name(&url));
+        std::fs::create_dir_all(path.parent().expect("in the directory")).map_err(|e| write_error(&dir, e))?;
+        let size = progress.phase(&format!("Downloading {}", file_name(&url)), || remote::download(&url, &path))?;
+        progress.println(format!("Downloaded {} ({})", url, super::checks::format_memory(size)));
+        local.push((url, path.to_string_lossy().into_owned()));
+    }
+    if let Some(url) = output {
+        let path = dir.join("output").join(file_name(&url));
+        std::fs::create_dir_all(path.parent().expect("in the directory")).map_err(|e| write_error(&dir, e))?;
+        staged.output = Some((dir.join("output"), url.clone()));
+        local.push((url, path.to_string_lossy().into_owned()));
+    }
+
+    let replace = |path: &mut String| {
+        if let Some((_, staged)) = local.iter().find(|(url, _)| url == path) {
+            *path = staged.clone();
+        }
+    };
+    for path in [&mut args.nucleus, &mut args.eosin, &mut args.output].into_iter().flatten() {
+        replace(path);
+    }
+    args.render.eosin_inputs.iter_mut().for_each(|input| replace(&mut input.path));
+    args.render.channels.iter_mut().for_each(|channel| replace(&mut channel.path));
+    args.render.extra_channel.iter_mut().for_each(replace);
+    let mut sources = SOURCES.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    sources.extend(local.into_iter().map(|(url, path)| (path, url)));
+    Ok(staged)
+}
+
+/// Upload the output written into the temporary directory of `staged`, with the files written
+/// next to it, to the directory of its URL.
+#[cfg(feature = "remote")]
+pub(crate) fn publish(staged: &Staged, args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    use rayon::prelude::*;
+
+    let Some((dir, url)) = &staged.output else {
+        return Ok(());
+    };
+    let mut files = Vec::new();
+    list_files(dir, &mut files).map_err(|e| Error::Write {
+        path: dir.clone(),
+        message: format!("{}: {}", dir.display(), e),
+    })?;
+    let prefix = &url[..url.rfind('/').map_or(url.len(), |slash| slash + 1)];
+    let render = &args.render;
+    let progress = super::progress::Progress::new(render.quiet || render.verbose > 0, !args.json, false);
+    // The files of a Zarr store are uploaded several at a time
+    progress.phase(&format!("Uploading to {}", prefix), || {
+        files.par_iter().try_for_each(|file| {
+            let key = file.strip_prefix(dir).expect("listed in the directory").to_string_lossy().replace('\\', "/");
+            virtualhe::remote::upload(file, &format!("{}{}", prefix, key))
+        })
+    })?;
+    progress.println(format!("Uploaded to: {}", url));
+    Ok(())
+}
+
+/// The files below `dir`, recursively.
+#[cfg(feature = "remote")]
+fn list_files(dir: &std::path::Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Last segment of a URL, without a query.
+#[cfg(feature = "remote")]
+fn file_name(url: &str) -> &str {
+    let path = url.split(['?', '#']).next().unwrap_or(url).trim_end_matches('/');
+    path.rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or("download")
+}
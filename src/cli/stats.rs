@@ -0,0 +1,110 @@
+//! Statistics of --stats-only: the windows and saturation of each channel of a pair, printed
+//! as text or JSON without rendering an image.
+use super::job::Job;
+use super::render::{check_saturation, load_mask, load_pair, scale_error};
+use crate::Args;
+use serde::Serialize;
+use virtualhe::{ChannelInfo, Error, ScaleOptions};
+
+/// Statistics of one channel printed by --stats-only, intensities in input units.
+#[derive(Serialize)]
+pub(crate) struct ChannelReport<'a> {
+    path: &'a str,
+    width: usize,
+    height: usize,
+    pixel_format: String,
+    input_max: f32,
+    min: f32,
+    max: f32,
+    mean: f32,
+    nan_count: usize,
+    /// Percentiles of the floor and ceiling, not set with a fixed window.
+    floor_percentile: Option<f32>,
+    floor: f32,
+    percentile: Option<f32>,
+    ceiling: f32,
+    saturated_fraction: f32,
+    /// Method of --auto-contrast, and the threshold above which it took the percentiles over the
+    /// tissue values with their fraction of the values.
+    auto_contrast: Option<&'static str>,
+    tissue_threshold: Option<f32>,
+    tissue_fraction: Option<f32>,
+}
+
+/// Statistics of both channels printed by --stats-only.
+#[derive(Serialize)]
+pub(crate) struct StatsReport<'a> {
+    nucleus: ChannelReport<'a>,
+    eosin: ChannelReport<'a>,
+    /// Shift DX,DY applied to the eosin channel, if any.
+    eosin_shift: Option<[f32; 2]>,
+}
+
+/// Print the statistics and scaling thresholds of both channels of a pair without rendering.
+pub(crate) fn print_stats(args: &Args, job: &Job, nucleus_path: &str, eosin_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let progress = &job.progress;
+    let ((nucleus, nucleus_info), (eosin, eosin_info), shift) = load_pair(args, job, progress, nucleus_path, eosin_path)?;
+    let mask = load_mask(args, job, progress, nucleus_path, &nucleus, &eosin)?;
+    let report = |path, image, info: &ChannelInfo, scale: &ScaleOptions| -> Result<_, Error> {
+        let stats = virtualhe::channel_stats_with_mask(image, scale, mask.as_ref()).map_err(|e| scale_error(path, e))?;
+        Ok(ChannelReport {
+            path,
+            width: info.width,
+            height: info.height,
+            pixel_format: info.pixel_format.clone(),
+            input_max: info.input_max,
+            min: stats.min * info.input_max,
+            max: stats.max * info.input_max,
+            mean: stats.mean * info.input_max,
+            nan_count: stats.nan_count,
+            floor_percentile: scale.window.is_none().then(|| scale.floor_percentile.unwrap_or(0.0)),
+            floor: stats.thresholds.floor * info.input_max,
+            percentile: scale.window.is_none().then_some(scale.percentile),
+            ceiling: stats.thresholds.ceiling * info.input_max,
+            saturated_fraction: stats.saturated,
+            auto_contrast: scale.auto_contrast.map(|method| method.name()),
+            tissue_threshold: stats.tissue_threshold.map(|threshold| threshold * info.input_max),
+            tissue_fraction: stats.tissue_fraction,
+        })
+    };
+    let report = progress.phase("Computing percentiles", || -> Result<_, Error> {
+        Ok(StatsReport {
+            nucleus: report(nucleus_path, &nucleus, &nucleus_info, &job.nucleus_scale)?,
+            eosin: report(eosin_path, &eosin, &eosin_info, &job.eosin_scale)?,
+            eosin_shift: shift,
+        })
+    })?;
+    check_saturation(args, nucleus_path, &job.nucleus_scale, report.nucleus.saturated_fraction)?;
+    check_saturation(args, eosin_path, &job.eosin_scale, report.eosin.saturated_fraction)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    for (name, channel) in [("Nucleus", &report.nucleus), ("Eosin", &report.eosin)] {
+        progress.println(format!("{} {}:", name, channel.path));
+        progress.println(format!(
+            "  min {}, max {}, mean {}, {} NaN values",
+            channel.min, channel.max, channel.mean, channel.nan_count
+        ));
+        if let (Some(method), Some(threshold), Some(fraction)) =
+            (channel.auto_contrast, channel.tissue_threshold, channel.tissue_fraction)
+        {
+            progress.println(format!(
+                "  tissue above the {} threshold {}, {:.2}% of the values, which the percentiles are taken over",
+                method,
+                threshold,
+                fraction * 100.0
+            ));
+        }
+        match (channel.floor_percentile, channel.percentile) {
+            (Some(floor_percentile), Some(percentile)) => progress.println(format!(
+                "  floor {} at percentile {}, ceiling {} at percentile {}",
+                channel.floor, floor_percentile, channel.ceiling, percentile
+            )),
+            _ => progress.println(format!("  floor {} and ceiling {} of the fixed range", channel.floor, channel.ceiling)),
+        }
+        progress.println(format!("  {:.4}% of pixels saturate", channel.saturated_fraction * 100.0));
+    }
+    Ok(())
+}
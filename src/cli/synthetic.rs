@@ -0,0 +1,147 @@
+//! Synthetic pairs of images, of the `generate-test-data` subcommand and the benchmarks of
+//! `bench`: round nuclei of varying size and brightness on a jittered grid in the nucleus channel,
+//! over cytoplasm of smoothly varying autofluorescence in the eosin channel, both with noise, so
+//! that the percentiles and colors take the range of real tissue.
+//!
+//! The images depend only on their size and seed: the random values are hashes of the seed and the
+//! pixel coordinates, and the intensities are computed with the basic arithmetic of IEEE floats
+//! only, which all platforms round alike, so that the same seed gives the same files everywhere.
+use image::{ImageBuffer, Luma};
+use ndarray::{Array2, Zip};
+use std::path::Path;
+
+/// Size of the generated images by default.
+const DEFAULT_SIZE: &str = "1024";
+
+/// Edge length in pixels of the cells of the nuclei, one nucleus at most per cell.
+const CELL_SIZE: usize = 24;
+
+/// Edge lengths in pixels of the lattices of the cytoplasm texture, coarse then fine.
+const TEXTURE_SCALES: [usize; 2] = [96, 24];
+
+/// Streams of random values, hashed with the seed so that they are independent.
+const CELLS: u64 = 1;
+const NOISE: u64 = 2;
+const TEXTURE: u64 = 3;
+
+/// Arguments of the `generate-test-data` subcommand.
+#[derive(clap::Args, Debug)]
+pub(crate) struct GenerateArgs {
+    /// Path to save the nucleus channel TIFF.
+    nucleus: String,
+    /// Path to save the eosin channel TIFF.
+    eosin: String,
+    /// Size of the images in pixels, WIDTHxHEIGHT or one edge length for a square.
+    #[arg(long, value_name = "WIDTHxHEIGHT", default_value = DEFAULT_SIZE, value_parser = parse_size)]
+    size: (usize, usize),
+    /// Bit depth of the samples: 8, 16, or 12 for 12bit values in 16bit samples as many cameras write them.
+    #[arg(long, default_value_t = 16, value_parser = parse_bits)]
+    bits: u32,
+    /// Seed of the random nuclei, texture and noise, the same seed gives the same images.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// Parse the size of synthetic images, WIDTHxHEIGHT or one edge length (e.g., 8192x4096).
+pub(crate) fn parse_size(s: &str) -> Result<(usize, usize), String> {
+    let parse = |v: &str| match v.trim().parse::<usize>() {
+        Ok(v) if v > 0 => Ok(v),
+        _ => Err(format!("expected WIDTHxHEIGHT or one edge length of positive integers, got {}", s)),
+    };
+    match s.split_once('x') {
+        Some((width, height)) => Ok((parse(width)?, parse(height)?)),
+        None => parse(s).map(|size| (size, size)),
+    }
+}
+
+/// Parse a bit depth of generated images.
+fn parse_bits(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(bits @ (8 | 12 | 16)) => Ok(bits),
+        _ => Err(format!("expected 8, 12 or 16, got {}", s)),
+    }
+}
+
+/// Write the pair of `generate-test-data`.
+pub(crate) fn generate(args: &GenerateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (width, height) = args.size;
+    let (nucleus, eosin) = pair(width, height, args.seed);
+    write(&nucleus, args.bits, Path::new(&args.nucleus))?;
+    write(&eosin, args.bits, Path::new(&args.eosin))?;
+    println!(
+        "Wrote {} and {}: {} x {} pixels, {}bit, seed {}",
+        args.nucleus, args.eosin, width, height, args.bits, args.seed
+    );
+    Ok(())
+}
+
+/// Nucleus and eosin intensities from 0 to 1 of `width` x `height` pixels.
+pub(crate) fn pair(width: usize, height: usize, seed: u64) -> (Array2<f32>, Array2<f32>) {
+    let mut nucleus = Array2::zeros((height, width));
+    let mut eosin = Array2::zeros((height, width));
+    Zip::indexed(&mut nucleus).and(&mut eosin).par_for_each(|(y, x), nucleus, eosin| {
+        let (cell_x, cell_y) = ((x / CELL_SIZE) as u64, (y / CELL_SIZE) as u64);
+        let cell = hash(&[seed, CELLS, cell_x, cell_y]);
+        let value = |bits: u32| unit(cell >> bits);
+        let center_x = (cell_x as usize * CELL_SIZE) as f32 + CELL_SIZE as f32 * (0.25 + 0.5 * value(0));
+        let center_y = (cell_y as usize * CELL_SIZE) as f32 + CELL_SIZE as f32 * (0.25 + 0.5 * value(16));
+        let radius = 3.0 + 6.0 * value(32);
+        let (dx, dy) = (x as f32 - center_x, y as f32 - center_y);
+        let distance = (dx * dx + dy * dy) / (radius * radius);
+        // Two thirds of the cells hold a nucleus
+        let blob = if value(48) < 0.67 { (1.0 - distance).max(0.0).sqrt() } else { 0.0 };
+        let noise = unit(hash(&[seed, NOISE, x as u64, y as u64]));
+        let texture = 0.7 * texture(seed, TEXTURE_SCALES[0], x, y) + 0.3 * texture(seed, TEXTURE_SCALES[1], x, y);
+        *nucleus = blob * (0.38 + 0.31 * value(40)) + 0.01 * noise;
+        *eosin = 0.06 + 0.25 * texture * (1.0 - 0.6 * blob) + 0.03 * noise;
+    });
+    (nucleus, eosin)
+}
+
+/// Smooth random texture from 0 to 1: random values at the points of a lattice of `scale` pixels,
+/// interpolated with smoothstep weights.
+fn texture(seed: u64, scale: usize, x: usize, y: usize) -> f32 {
+    let (lattice_x, lattice_y) = ((x / scale) as u64, (y / scale) as u64);
+    let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+    let tx = smooth((x % scale) as f32 / scale as f32);
+    let ty = smooth((y % scale) as f32 / scale as f32);
+    let corner = |dx: u64, dy: u64| unit(hash(&[seed, TEXTURE, scale as u64, lattice_x + dx, lattice_y + dy]));
+    let top = corner(0, 0) + (corner(1, 0) - corner(0, 0)) * tx;
+    let bottom = corner(0, 1) + (corner(1, 1) - corner(0, 1)) * tx;
+    top + (bottom - top) * ty
+}
+
+/// Hash of a sequence of values by the finalizer of splitmix64.
+fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0, |hash: u64, &value| {
+        let mut z = (hash ^ value).wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    })
+}
+
+/// The low 16 bits of a hash as a value from 0 to 1.
+fn unit(hash: u64) -> f32 {
+    (hash & 0xffff) as f32 / 65535.0
+}
+
+/// Write intensities from 0 to 1 as a grayscale TIFF of `bits` per sample, 12bit values in 16bit
+/// samples.
+pub(crate) fn write(image: &Array2<f32>, bits: u32, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let (height, width) = image.dim();
+    let max = ((1u32 << bits) - 1) as f32;
+    let saved = if bits == 8 {
+        let samples = image.iter().map(|&v| (v * max).round() as u8).collect();
+        ImageBuffer::<Luma<u8>, Vec<u8>>::from_raw(width as u32, height as u32, samples)
+            .expect("sized by the shape")
+            .save(path)
+    } else {
+        let samples = image.iter().map(|&v| (v * max).round() as u16).collect();
+        ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(width as u32, height as u32, samples)
+            .expect("sized by the shape")
+            .save(path)
+    };
+    saved.map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(())
+}
@@ -0,0 +1,242 @@
+//! Watch mode of --watch: pairs of images written into a directory, e.g. by a microscope during a
+//! scan, are rendered as they arrive. A pair is rendered once both of its files exist and have not
+//! changed for `SETTLE_TIME`, and a pair that fails to decode, as a file still being written may,
+//! is rendered again when its files have changed and settled anew.
+use super::batch::{match_pattern, BatchItem, DEFAULT_OUTPUT_PATTERN};
+use super::job::Job;
+use super::render::render_pair;
+use crate::Args;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+use virtualhe::Error;
+
+/// Time both files of a pair must keep their size and modification time before it is rendered.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Interval of the checks for settled pairs and interrupts between file events.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Renders of a pair after the first that failed to open or decode its files.
+const RETRIES: u32 = 3;
+
+/// Whether a watch is running, so that an interrupt stops it instead of the process.
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+/// Whether an interrupt asked the watch to stop.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Ask a running watch to stop after the pair it renders, from the interrupt handler. Returns
+/// false if no watch is running or it was asked to stop before, then the process exits.
+pub(crate) fn stop() -> bool {
+    if !WATCHING.load(Ordering::SeqCst) || STOPPING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    eprintln!("Stopping the watch after the current pair, interrupt again to abandon it");
+    true
+}
+
+/// A pair of the watched directory waiting for its files to settle.
+struct Pending {
+    item: BatchItem,
+    /// Sizes and modification times of the files at the last check, None before the first.
+    footprint: Option<[Footprint; 2]>,
+    /// Time the footprint was last seen to change.
+    changed: Instant,
+    /// Renders of the pair that failed to open or decode its files.
+    attempts: u32,
+    /// Footprint of the files at the last failed render, which is not tried again.
+    failed: Option<[Footprint; 2]>,
+}
+
+/// Size and latest modification time of a file, or of the files in a directory such as an
+/// OME-Zarr store.
+type Footprint = (u64, Option<SystemTime>);
+
+/// Watch the directory of --watch and render every pair matched by the nucleus and eosin patterns
+/// into the output directory, until interrupted. Pairs whose output exists are skipped, those
+/// already in the directory are rendered first.
+pub(crate) fn run(args: &Args, job: &Job) -> Result<(), Box<dyn std::error::Error>> {
+    let progress = &job.progress;
+    let input_dir = Path::new(args.watch.as_deref().expect("checked by the caller"));
+    let output_dir = Path::new(args.output_dir.as_deref().expect("required by --watch"));
+    fs::read_dir(input_dir).map_err(|e| format!("{}: {}", input_dir.display(), e))?;
+    fs::create_dir_all(output_dir).map_err(|e| format!("{}: {}", output_dir.display(), e))?;
+
+    // Events only trigger a new listing of the directory, the files are checked by polling, so
+    // that the events of the same file need not be told apart
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(|e| format!("{}: {}", input_dir.display(), e))?;
+    watcher
+        .watch(input_dir, RecursiveMode::NonRecursive)
+        .map_err(|e| format!("{}: {}", input_dir.display(), e))?;
+    WATCHING.store(true, Ordering::SeqCst);
+    progress.println(format!("Watching {} for pairs of images, press Ctrl-C to stop", input_dir.display()));
+
+    let mut pending: BTreeMap<String, Pending> = BTreeMap::new();
+    let mut seen = BTreeSet::new();
+    let (mut rendered, mut skipped) = (0, 0);
+    let mut failures = Vec::new();
+    let mut rescan = true;
+    while !STOPPING.load(Ordering::SeqCst) {
+        if rescan {
+            for item in list_pairs(args, input_dir, output_dir)? {
+                if !seen.insert(item.id.clone()) {
+                    continue;
+                }
+                if Path::new(&item.output).exists() {
+                    progress.println(format!("Skipping {}: {} exists", item.id, item.output));
+                    skipped += 1;
+                    continue;
+                }
+                log::debug!("{}: waiting for {} and {} to settle", item.id, item.nucleus, item.eosin);
+                let id = item.id.clone();
+                pending.insert(
+                    id,
+                    Pending {
+                        item,
+                        footprint: None,
+                        changed: Instant::now(),
+                        attempts: 0,
+                        failed: None,
+                    },
+                );
+            }
+            rescan = false;
+        }
+
+        let settled: Vec<String> = pending
+            .iter_mut()
+            .filter_map(|(id, pair)| settle(pair).then(|| id.clone()))
+            .collect();
+        for id in settled {
+            if STOPPING.load(Ordering::SeqCst) {
+                break;
+            }
+            let mut pair = pending.remove(&id).expect("settled pairs are pending");
+            let item = &pair.item;
+            progress.println(format!("Rendering {}", id));
+            let Err(e) = render_pair(args, job, &progress.for_image(&id), &item.nucleus, &item.eosin, &item.output) else {
+                rendered += 1;
+                continue;
+            };
+            // The pair abandoned by a second interrupt stays waiting
+            if matches!(e.downcast_ref::<Error>(), Some(Error::Interrupted(_))) {
+                progress.bars.suspend(|| eprintln!("Abandoned {}: {}", id, e));
+                pending.insert(id, pair);
+                break;
+            }
+            let unreadable = matches!(e.downcast_ref::<Error>(), Some(Error::Open { .. } | Error::Decode { .. }));
+            if unreadable && pair.attempts < RETRIES {
+                pair.attempts += 1;
+                progress.bars.suspend(|| {
+                    eprintln!(
+                        "Failed {}, retrying when its files change ({} of {} retries): {}",
+                        id, pair.attempts, RETRIES, e
+                    )
+                });
+                pair.failed = pair.footprint;
+                pending.insert(id, pair);
+            } else {
+                progress.bars.suspend(|| eprintln!("Failed {}: {}", id, e));
+                failures.push((id, e.to_string()));
+            }
+        }
+
+        // Events of reads, such as those of the renders, do not change the pairs
+        match events.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => rescan |= !matches!(event.kind, EventKind::Access(_)),
+            Ok(Err(e)) => {
+                log::warn!("{}: {}, listing the directory again", input_dir.display(), e);
+                rescan = true;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Err(format!("{}: the watch ended", input_dir.display()).into()),
+        }
+        for event in events.try_iter() {
+            rescan |= !matches!(event, Ok(event) if matches!(event.kind, EventKind::Access(_)));
+        }
+    }
+    WATCHING.store(false, Ordering::SeqCst);
+
+    println!(
+        "Watch stopped: {} rendered, {} failed, {} skipped as their outputs exist, {} waiting",
+        rendered,
+        failures.len(),
+        skipped,
+        pending.len()
+    );
+    for (id, error) in &failures {
+        println!("  {}: {}", id, error);
+    }
+    if virtualhe::interrupt::requested() {
+        return Err(Error::Interrupted(format!("watch interrupted after {} pairs", rendered + failures.len())).into());
+    }
+    if !failures.is_empty() {
+        return Err(format!("{} of {} pairs failed", failures.len(), rendered + failures.len()).into());
+    }
+    Ok(())
+}
+
+/// The pairs of the watched directory whose nucleus and eosin files both exist, in id order.
+fn list_pairs(args: &Args, input_dir: &Path, output_dir: &Path) -> Result<Vec<BatchItem>, Box<dyn std::error::Error>> {
+    let nucleus_pattern = args.nucleus_pattern.as_deref().expect("required by --watch");
+    let eosin_pattern = args.eosin_pattern.as_deref().expect("required by --watch");
+    let output_pattern = args.output_pattern.as_deref().unwrap_or(DEFAULT_OUTPUT_PATTERN);
+    let mut pairs: BTreeMap<String, (Option<String>, Option<String>)> = BTreeMap::new();
+    for entry in fs::read_dir(input_dir).map_err(|e| format!("{}: {}", input_dir.display(), e))? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(id) = match_pattern(nucleus_pattern, &name) {
+            pairs.entry(id).or_default().0 = Some(name.clone());
+        }
+        if let Some(id) = match_pattern(eosin_pattern, &name) {
+            pairs.entry(id).or_default().1 = Some(name);
+        }
+    }
+    Ok(pairs
+        .into_iter()
+        .filter_map(|(id, pair)| match pair {
+            (Some(nucleus), Some(eosin)) => Some(BatchItem {
+                output: output_dir.join(output_pattern.replace("{id}", &id)).to_string_lossy().into_owned(),
+                nucleus: input_dir.join(nucleus).to_string_lossy().into_owned(),
+                eosin: input_dir.join(eosin).to_string_lossy().into_owned(),
+                id,
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Check the files of a pending pair, true once neither has changed for `SETTLE_TIME` and they
+/// differ from those of the last failed render. A file that cannot be read, e.g. as it was moved
+/// away, counts as changing.
+fn settle(pair: &mut Pending) -> bool {
+    let footprint = match (footprint(Path::new(&pair.item.nucleus)), footprint(Path::new(&pair.item.eosin))) {
+        (Some(nucleus), Some(eosin)) => Some([nucleus, eosin]),
+        _ => None,
+    };
+    if footprint.is_none() || footprint != pair.footprint {
+        pair.footprint = footprint;
+        pair.changed = Instant::now();
+        return false;
+    }
+    pair.changed.elapsed() >= SETTLE_TIME && pair.footprint != pair.failed
+}
+
+/// Size and latest modification time of a file, summed over the files of a directory.
+fn footprint(path: &Path) -> Option<Footprint> {
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_dir() {
+        return Some((metadata.len(), metadata.modified().ok()));
+    }
+    let mut total = (0, metadata.modified().ok());
+    for entry in fs::read_dir(path).ok()? {
+        let (size, modified) = footprint(&entry.ok()?.path())?;
+        total = (total.0 + size, total.1.max(modified));
+    }
+    Some(total)
+}
@@ -0,0 +1,467 @@
+//! Zeiss CZI input: one channel of one scene at full resolution, assembled from the subblocks of
+//! the file, so that slides need not be converted to TIFF first.
+//!
+//! A CZI file is a sequence of segments: a file header pointing to the subblock directory and the
+//! XML metadata, the directory listing each subblock with its pixel type, compression and position
+//! along the dimensions X, Y, C (channel), S (scene), M (mosaic tile), Z, T and others, and the
+//! subblocks holding the pixels. The subblocks of the selected channel and scene are placed at
+//! their X and Y positions in the order of their mosaic index, at the first plane of every other
+//! dimension. Subblocks of pyramid levels, stored smaller than the region they cover, are skipped.
+//! Uncompressed and Zstd compressed subblocks of Gray8, Gray16 and Gray32Float pixels are read,
+//! channels are named by the XML metadata.
+use crate::ome::{attribute, elements, unescape};
+use crate::{ChannelSelector, LoadOptions, RawImage};
+use log::debug;
+use ndarray::{s, Array2, ArrayViewMut2};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Size of the header of every segment: its id and the allocated and used sizes of its data.
+const SEGMENT_HEADER: u64 = 32;
+
+/// Size of a directory entry without its dimension entries, and of a dimension entry.
+const ENTRY_SIZE: usize = 32;
+const DIMENSION_SIZE: usize = 20;
+
+/// Read the channel and scene selected by `options` from a CZI file as raw (not normalized)
+/// values.
+pub(crate) fn read(path: &Path, options: &LoadOptions) -> Result<RawImage, Box<dyn std::error::Error>> {
+    read_plane(path, options).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Width and height of the channel and scene selected by `options` from the subblock directory,
+/// None if it cannot be read.
+pub(crate) fn dimensions(path: &Path, options: &LoadOptions) -> Option<(usize, usize)> {
+    let mut czi = Czi::open(path).ok()?;
+    let plane = czi.select(options).ok()?;
+    Some((plane.width, plane.height))
+}
+
+/// Read a plane as `read` does, with errors not yet prefixed by the path.
+fn read_plane(path: &Path, options: &LoadOptions) -> Result<RawImage, String> {
+    let mut czi = Czi::open(path)?;
+    let plane = czi.select(options)?;
+    let (pixel_format, container_max) = match plane.pixel_type {
+        PixelType::Gray8 => ("L8", Some(255.0)),
+        PixelType::Gray16 => ("L16", Some(65535.0)),
+        PixelType::Gray32Float => ("L32F", None),
+    };
+    debug!(
+        "{}: CZI {}x{} {} of channel {}{}, {} subblocks",
+        path.display(),
+        plane.width,
+        plane.height,
+        pixel_format,
+        plane.channel,
+        plane.scene.map_or(String::new(), |scene| format!(" of scene {}", scene)),
+        plane.subblocks.len()
+    );
+    let mut image = Array2::<f32>::zeros((plane.height, plane.width));
+    for subblock in &plane.subblocks {
+        let data = czi.read_data(subblock)?;
+        let (x, y) = (subblock.start('X') - plane.x0, subblock.start('Y') - plane.y0);
+        let (width, height) = (subblock.size('X'), subblock.size('Y'));
+        let region = image.slice_mut(s![y as usize..y as usize + height, x as usize..x as usize + width]);
+        copy_pixels(&data, plane.pixel_type, region);
+    }
+    Ok((image, pixel_format.to_string(), container_max))
+}
+
+/// Supported pixel types of the subblocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PixelType {
+    Gray8,
+    Gray16,
+    Gray32Float,
+}
+
+impl PixelType {
+    fn from_id(id: i32) -> Result<Self, String> {
+        let name = match id {
+            0 => return Ok(PixelType::Gray8),
+            1 => return Ok(PixelType::Gray16),
+            2 => return Ok(PixelType::Gray32Float),
+            3 => "Bgr24",
+            4 => "Bgr48",
+            8 => "Bgr96Float",
+            9 => "Bgra32",
+            10 => "Gray64ComplexFloat",
+            11 => "Bgr192ComplexFloat",
+            12 => "Gray32",
+            13 => "Gray64",
+            _ => return Err(format!("unknown pixel type {}", id)),
+        };
+        Err(format!("pixel type {} is not supported, expected Gray8, Gray16 or Gray32Float", name))
+    }
+
+    fn bytes(self) -> usize {
+        match self {
+            PixelType::Gray8 => 1,
+            PixelType::Gray16 => 2,
+            PixelType::Gray32Float => 4,
+        }
+    }
+}
+
+/// Position of a subblock along a dimension.
+#[derive(Debug, Clone)]
+struct Dimension {
+    name: char,
+    start: i64,
+    size: usize,
+    /// Pixels stored along X and Y, fewer than `size` in pyramid levels.
+    stored_size: usize,
+}
+
+/// A directory entry: where a subblock is, how it is stored and what it covers.
+#[derive(Debug, Clone)]
+struct Subblock {
+    pixel_type: i32,
+    position: u64,
+    file_part: i32,
+    compression: i32,
+    pyramid_type: u8,
+    dimensions: Vec<Dimension>,
+}
+
+impl Subblock {
+    fn dimension(&self, name: char) -> Option<&Dimension> {
+        self.dimensions.iter().find(|dimension| dimension.name == name)
+    }
+
+    /// Start along a dimension, 0 for dimensions the subblock does not have.
+    fn start(&self, name: char) -> i64 {
+        self.dimension(name).map_or(0, |dimension| dimension.start)
+    }
+
+    /// Size along a dimension, 1 for dimensions the subblock does not have.
+    fn size(&self, name: char) -> usize {
+        self.dimension(name).map_or(1, |dimension| dimension.size)
+    }
+
+    /// Whether the subblock holds its region at full resolution rather than a pyramid level.
+    fn is_full_resolution(&self) -> bool {
+        self.pyramid_type == 0
+            && ['X', 'Y'].iter().all(|&name| self.dimension(name).is_some_and(|d| d.size == d.stored_size))
+    }
+}
+
+/// The subblocks of a plane and the region of the image they cover.
+struct Plane {
+    channel: i64,
+    scene: Option<i64>,
+    pixel_type: PixelType,
+    x0: i64,
+    y0: i64,
+    width: usize,
+    height: usize,
+    /// Subblocks in the order they are drawn, later ones over earlier ones.
+    subblocks: Vec<Subblock>,
+}
+
+/// An open CZI file with its subblock directory.
+struct Czi {
+    file: BufReader<File>,
+    subblocks: Vec<Subblock>,
+    metadata_position: u64,
+}
+
+impl Czi {
+    /// Open a CZI file and read its subblock directory.
+    fn open(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let mut file = BufReader::new(file);
+        let header = read_segment(&mut file, 0, "ZISRAWFILE", 80).map_err(|e| format!("not a CZI file: {}", e))?;
+        let directory_position = u64_at(&header, 52);
+        let metadata_position = u64_at(&header, 60);
+        if directory_position == 0 {
+            return Err("the file has no subblock directory".to_string());
+        }
+
+        let directory = read_segment(&mut file, directory_position, "ZISRAWDIRECTORY", 128)?;
+        let count = i32_at(&directory, 0).max(0) as usize;
+        let mut subblocks = Vec::with_capacity(count);
+        for _ in 0..count {
+            subblocks.push(read_entry(&mut file)?);
+        }
+        Ok(Czi {
+            file,
+            subblocks,
+            metadata_position,
+        })
+    }
+
+    /// The subblocks of the channel and scene selected by `options`, at full resolution and the
+    /// first plane of every other dimension.
+    fn select(&mut self, options: &LoadOptions) -> Result<Plane, String> {
+        if options.page.is_some() {
+            return Err("pages cannot be selected from CZI files, select a channel".to_string());
+        }
+        let full: Vec<&Subblock> = self.subblocks.iter().filter(|subblock| subblock.is_full_resolution()).collect();
+        if full.is_empty() {
+            return Err("the file holds no full resolution subblocks".to_string());
+        }
+
+        // Scenes, if there is more than one, must be chosen
+        let scenes: BTreeSet<i64> =
+            full.iter().filter_map(|subblock| subblock.dimension('S')).map(|d| d.start).collect();
+        let list = |values: &BTreeSet<i64>| values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ");
+        let scene = match options.scene {
+            Some(scene) if scenes.contains(&(scene as i64)) => Some(scene as i64),
+            Some(0) if scenes.is_empty() => None,
+            Some(scene) if scenes.is_empty() => {
+                return Err(format!("scene {} is not in the file, it holds one scene", scene))
+            }
+            Some(scene) => {
+                return Err(format!("scene {} is not in the file, it holds the scenes {}", scene, list(&scenes)))
+            }
+            None if scenes.len() > 1 => {
+                let count = scenes.len();
+                return Err(format!("the file holds {} scenes ({}), select one with --scene N", count, list(&scenes)));
+            }
+            None => scenes.first().copied(),
+        };
+        let full: Vec<&Subblock> =
+            full.into_iter().filter(|subblock| scene.is_none_or(|s| subblock.start('S') == s)).collect();
+
+        // Channels are the C indices, named by the XML metadata
+        let channels: BTreeSet<i64> = full.iter().map(|subblock| subblock.start('C')).collect();
+        let channel = match &options.channel {
+            None => *channels.first().expect("subblocks"),
+            Some(ChannelSelector::Index(index)) if channels.contains(&(*index as i64)) => *index as i64,
+            Some(ChannelSelector::Index(index)) => {
+                return Err(format!("channel {} is out of range, the image has the channels {}", index, list(&channels)))
+            }
+            Some(ChannelSelector::Name(name)) => {
+                let names = channel_names(&mut self.file, self.metadata_position)?;
+                match names.iter().find(|(_, n)| n == name) {
+                    Some((index, _)) => *index,
+                    None => {
+                        let names: Vec<&str> = names.iter().map(|(_, name)| name.as_str()).collect();
+                        return Err(format!("no channel named '{}', expected one of: {}", name, names.join(", ")));
+                    }
+                }
+            }
+        };
+        let mut subblocks: Vec<Subblock> =
+            full.into_iter().filter(|subblock| subblock.start('C') == channel).cloned().collect();
+        if subblocks.is_empty() {
+            return Err(format!("channel {} has no subblocks", channel));
+        }
+
+        // Other dimensions, e.g. Z and T, are read at their first plane
+        let others: BTreeSet<char> = subblocks
+            .iter()
+            .flat_map(|subblock| subblock.dimensions.iter().map(|d| d.name))
+            .filter(|name| !matches!(name, 'X' | 'Y' | 'C' | 'S' | 'M'))
+            .collect();
+        for name in others {
+            let first = subblocks.iter().map(|subblock| subblock.start(name)).min().unwrap_or(0);
+            subblocks.retain(|subblock| subblock.start(name) == first);
+        }
+        subblocks.sort_by_key(|subblock| subblock.start('M'));
+
+        if let Some(subblock) = subblocks.iter().find(|subblock| subblock.file_part != 0) {
+            return Err(format!(
+                "subblock at {} is in file part {}, multi-file CZIs are not supported",
+                subblock.position, subblock.file_part
+            ));
+        }
+        let pixel_type = PixelType::from_id(subblocks[0].pixel_type)?;
+        if subblocks.iter().any(|subblock| subblock.pixel_type != subblocks[0].pixel_type) {
+            return Err(format!("the subblocks of channel {} have different pixel types", channel));
+        }
+        let x0 = subblocks.iter().map(|subblock| subblock.start('X')).min().unwrap_or(0);
+        let y0 = subblocks.iter().map(|subblock| subblock.start('Y')).min().unwrap_or(0);
+        let x1 = subblocks.iter().map(|subblock| subblock.start('X') + subblock.size('X') as i64).max().unwrap_or(0);
+        let y1 = subblocks.iter().map(|subblock| subblock.start('Y') + subblock.size('Y') as i64).max().unwrap_or(0);
+        Ok(Plane {
+            channel,
+            scene,
+            pixel_type,
+            x0,
+            y0,
+            width: (x1 - x0) as usize,
+            height: (y1 - y0) as usize,
+            subblocks,
+        })
+    }
+
+    /// Read and decompress the pixels of a subblock.
+    fn read_data(&mut self, subblock: &Subblock) -> Result<Vec<u8>, String> {
+        let at = |e: String| format!("subblock at {}: {}", subblock.position, e);
+        let header = read_segment(&mut self.file, subblock.position, "ZISRAWSUBBLOCK", 16).map_err(at)?;
+        let (metadata_size, data_size) = (i32_at(&header, 0).max(0) as u64, u64_at(&header, 8));
+        // The fixed part holds the sizes and a copy of the directory entry, padded to 256 bytes
+        let entry_size = (ENTRY_SIZE + subblock.dimensions.len() * DIMENSION_SIZE) as u64;
+        let data_position = subblock.position + SEGMENT_HEADER + (16 + entry_size).max(256) + metadata_size;
+        self.file.seek(SeekFrom::Start(data_position)).map_err(|e| at(e.to_string()))?;
+        let mut data = Vec::new();
+        self.file.read_to_end_limited(&mut data, data_size as usize).map_err(at)?;
+
+        let pixel_type = PixelType::from_id(subblock.pixel_type)?;
+        let expected = subblock.size('X') * subblock.size('Y') * pixel_type.bytes();
+        let data = match subblock.compression {
+            0 => data,
+            5 => zstd(&data).map_err(at)?,
+            6 => zstd_with_header(&data, pixel_type).map_err(at)?,
+            compression => {
+                let name = match compression {
+                    1 => "JPEG",
+                    2 => "LZW",
+                    4 => "JPEG XR",
+                    _ => "unknown",
+                };
+                return Err(at(format!(
+                    "unsupported compression {} ({}), expected uncompressed or Zstd subblocks",
+                    name, compression
+                )));
+            }
+        };
+        if data.len() < expected {
+            return Err(at(format!("{} bytes of pixels, expected {}", data.len(), expected)));
+        }
+        Ok(data)
+    }
+}
+
+/// Indices and names of the channels in the XML metadata at `metadata_position`, from the Id and
+/// Name attributes of their Channel elements, the first element of each index.
+fn channel_names(file: &mut BufReader<File>, metadata_position: u64) -> Result<Vec<(i64, String)>, String> {
+    if metadata_position == 0 {
+        return Err("cannot select a channel by name without XML metadata, select it by index".to_string());
+    }
+    let header = read_segment(file, metadata_position, "ZISRAWMETADATA", 256)?;
+    let mut xml = Vec::new();
+    file.read_to_end_limited(&mut xml, i32_at(&header, 0).max(0) as usize)?;
+    let xml = String::from_utf8_lossy(&xml);
+    let mut names: Vec<(i64, String)> = Vec::new();
+    for tag in elements(&xml, "Channel") {
+        let index = attribute(tag, "Id").and_then(|id| id.strip_prefix("Channel:")).and_then(|i| i.parse().ok());
+        if let (Some(index), Some(name)) = (index, attribute(tag, "Name")) {
+            if !names.iter().any(|(i, _)| *i == index) {
+                names.push((index, unescape(name)));
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Read the next entry of the subblock directory.
+fn read_entry(file: &mut BufReader<File>) -> Result<Subblock, String> {
+    let truncated = |e: String| format!("subblock directory: {}", e);
+    let mut entry = Vec::new();
+    file.read_to_end_limited(&mut entry, ENTRY_SIZE).map_err(truncated)?;
+    if &entry[..2] != b"DV" {
+        return Err(format!("unknown directory entry schema {:?}", String::from_utf8_lossy(&entry[..2])));
+    }
+    let mut dimensions = Vec::new();
+    file.read_to_end_limited(&mut dimensions, i32_at(&entry, 28).max(0) as usize * DIMENSION_SIZE).map_err(truncated)?;
+    let dimensions = dimensions
+        .chunks_exact(DIMENSION_SIZE)
+        .map(|d| Dimension {
+            name: d[0] as char,
+            start: i64::from(i32_at(d, 4)),
+            size: i32_at(d, 8).max(0) as usize,
+            stored_size: i32_at(d, 16).max(0) as usize,
+        })
+        .collect();
+    Ok(Subblock {
+        pixel_type: i32_at(&entry, 2),
+        position: u64_at(&entry, 6),
+        file_part: i32_at(&entry, 14),
+        compression: i32_at(&entry, 18),
+        pyramid_type: entry[22],
+        dimensions,
+    })
+}
+
+/// Read the header of the segment at `position`, check its id, and read the first `length` bytes
+/// of its data, leaving the file after them.
+fn read_segment(file: &mut BufReader<File>, position: u64, id: &str, length: usize) -> Result<Vec<u8>, String> {
+    file.seek(SeekFrom::Start(position)).map_err(|e| e.to_string())?;
+    let mut header = [0u8; SEGMENT_HEADER as usize];
+    file.read_exact(&mut header).map_err(|e| format!("segment at {}: {}", position, e))?;
+    let found = String::from_utf8_lossy(&header[..16]).trim_end_matches('\0').to_string();
+    if found != id {
+        return Err(format!("expected a {} segment at {}, found {:?}", id, position, found));
+    }
+    let mut data = vec![0u8; length];
+    file.read_exact(&mut data).map_err(|e| format!("{} segment at {}: {}", id, position, e))?;
+    Ok(data)
+}
+
+/// Reading a given number of bytes, failing if the file ends before.
+trait ReadLimited {
+    fn read_to_end_limited(&mut self, buffer: &mut Vec<u8>, length: usize) -> Result<(), String>;
+}
+
+impl<R: Read> ReadLimited for R {
+    fn read_to_end_limited(&mut self, buffer: &mut Vec<u8>, length: usize) -> Result<(), String> {
+        self.take(length as u64).read_to_end(buffer).map_err(|e| e.to_string())?;
+        if buffer.len() < length {
+            return Err(format!("file ends after {} of {} bytes", buffer.len(), length));
+        }
+        Ok(())
+    }
+}
+
+/// Decompress a Zstd stream.
+fn zstd(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    ruzstd::decoding::StreamingDecoder::new(data)
+        .map_err(|e| e.to_string())?
+        .read_to_end(&mut out)
+        .map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+/// Decompress the Zstd stream of a zstd1 subblock, after a header of its size and chunks, of which
+/// the chunk of type 1 tells whether the low bytes of 16bit pixels are stored before the high bytes.
+fn zstd_with_header(data: &[u8], pixel_type: PixelType) -> Result<Vec<u8>, String> {
+    let size = usize::from(*data.first().ok_or("empty subblock")?);
+    let header = data.get(1..size).ok_or("truncated zstd1 header")?;
+    let mut hi_lo = false;
+    let mut chunks = header;
+    while let Some((&chunk, rest)) = chunks.split_first() {
+        match (chunk, rest.split_first()) {
+            (1, Some((&flags, rest))) => {
+                hi_lo = flags & 1 != 0;
+                chunks = rest;
+            }
+            _ => return Err(format!("unknown zstd1 header chunk {}", chunk)),
+        }
+    }
+    let packed = zstd(&data[size..])?;
+    if !hi_lo || pixel_type != PixelType::Gray16 {
+        return Ok(packed);
+    }
+    let (low, high) = packed.split_at(packed.len() / 2);
+    Ok(low.iter().zip(high).flat_map(|(&low, &high)| [low, high]).collect())
+}
+
+/// Copy the little-endian pixels of a subblock into its region of the image.
+fn copy_pixels(data: &[u8], pixel_type: PixelType, mut region: ArrayViewMut2<f32>) {
+    let width = region.ncols();
+    let bytes = pixel_type.bytes();
+    for (y, mut row) in region.rows_mut().into_iter().enumerate() {
+        let samples = &data[y * width * bytes..(y + 1) * width * bytes];
+        for (v, sample) in row.iter_mut().zip(samples.chunks_exact(bytes)) {
+            *v = match pixel_type {
+                PixelType::Gray8 => sample[0] as f32,
+                PixelType::Gray16 => u16::from_le_bytes([sample[0], sample[1]]) as f32,
+                PixelType::Gray32Float => f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]),
+            };
+        }
+    }
+}
+
+fn i32_at(bytes: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("8 bytes"))
+}
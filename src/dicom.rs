@@ -0,0 +1,597 @@
+//! DICOM output for clinical archives: the rendered image as a VL Whole Slide Microscopy Image
+//! instance, tiled with one frame per tile in the TILED_FULL organization, frames row by row of
+//! tiles from the top left, tiles at the right and bottom edges padded with white.
+//!
+//! Frames are 8bit RGB, 16bit samples are rounded to 8 bits and RGBA samples composited over white
+//! as for Deep Zoom tiles, and stored uncompressed in Explicit VR Little Endian. Pixel data beyond
+//! the 4 GB a native pixel data element holds is encapsulated instead, one fragment per frame, in
+//! the Encapsulated Uncompressed Explicit VR Little Endian transfer syntax. Rows are streamed in from
+//! top to bottom, and only a row of tiles is held in memory.
+//!
+//! The identifying attributes default to anonymized placeholders, and the UIDs are generated under
+//! the 2.25 root of UUID-derived UIDs, which needs no registered organization root.
+use crate::dzi::to_rgb8;
+use crate::tiff_writer::{civil_time, render_time};
+use crate::{ColorEncoding, Error, OutputSample};
+use log::{debug, trace};
+use ndarray::{s, Array3, ArrayView3, Axis};
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Default edge length of the frames in pixels.
+pub const DEFAULT_DICOM_TILE_SIZE: u32 = 256;
+
+/// Patient name and ID written when none is given.
+pub const ANONYMOUS: &str = "ANONYMOUS";
+
+/// Container (slide) and specimen identifier written when none is given.
+pub const UNKNOWN_CONTAINER: &str = "UNKNOWN";
+
+/// SOP class of VL Whole Slide Microscopy Image Storage.
+pub const WSI_SOP_CLASS_UID: &str = "1.2.840.10008.5.1.4.1.1.77.1.6";
+
+/// Transfer syntax of native pixel data.
+pub const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+
+/// Transfer syntax of uncompressed frames encapsulated one per fragment.
+pub const ENCAPSULATED_UNCOMPRESSED: &str = "1.2.840.10008.1.2.1.98";
+
+/// Implementation of the writer recorded in the file meta information, a UUID-derived UID.
+const IMPLEMENTATION_CLASS_UID: &str = "2.25.255689173602687229463776585976694122769";
+
+/// Largest value length of a native element, 0xFFFFFFFF stands for an undefined length.
+const MAX_VALUE_LENGTH: u64 = 0xFFFF_FFFE;
+
+/// Layout and identifying attributes of DICOM outputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DicomOptions {
+    /// Edge length of the frames in pixels.
+    pub tile_size: u32,
+    /// Physical size (x, y) of a pixel in micrometers, recorded as the pixel spacing.
+    pub pixel_size_um: [f32; 2],
+    /// Transfer curve of the samples, recorded in the ICC profile.
+    pub encoding: ColorEncoding,
+    /// Patient name, ANONYMOUS when not set.
+    pub patient_name: Option<String>,
+    /// Patient ID, ANONYMOUS when not set.
+    pub patient_id: Option<String>,
+    /// Study Instance UID, to add the image to an existing study, a new UID when not set.
+    pub study_uid: Option<String>,
+    /// Accession number, empty when not set.
+    pub accession_number: Option<String>,
+    /// Container (slide) identifier, also the specimen identifier, UNKNOWN when not set.
+    pub container_id: Option<String>,
+    /// Always encapsulate the frames, otherwise they are only when the pixel data exceeds 4 GB.
+    pub encapsulated: bool,
+}
+
+impl DicomOptions {
+    /// Options for frames of `tile_size` pixels of `pixel_size_um`, with the placeholders for the
+    /// identifying attributes.
+    pub fn new(tile_size: u32, pixel_size_um: [f32; 2]) -> Self {
+        DicomOptions {
+            tile_size,
+            pixel_size_um,
+            encoding: ColorEncoding::default(),
+            patient_name: None,
+            patient_id: None,
+            study_uid: None,
+            accession_number: None,
+            container_id: None,
+            encapsulated: false,
+        }
+    }
+}
+
+/// Check a UID given on the command line: up to 64 characters of digits and dots, in components
+/// without leading zeros.
+pub fn parse_uid(s: &str) -> Result<String, String> {
+    let valid_component = |component: &str| {
+        !component.is_empty()
+            && component.bytes().all(|byte| byte.is_ascii_digit())
+            && (component == "0" || !component.starts_with('0'))
+    };
+    if s.len() > 64 || !s.split('.').all(valid_component) {
+        return Err(format!("invalid UID '{}', expected up to 64 digits and dots, e.g. 1.2.840.12345", s));
+    }
+    Ok(s.to_string())
+}
+
+/// Check a text attribute given on the command line: up to 64 characters without backslashes,
+/// which separate values, or control characters.
+pub fn parse_text(s: &str) -> Result<String, String> {
+    if s.chars().count() > 64 || s.chars().any(|c| c == '\\' || c.is_control()) {
+        return Err(format!("invalid value '{}', expected up to 64 characters without backslashes", s));
+    }
+    Ok(s.to_string())
+}
+
+/// Write a rendered (row, column, RGB) or (row, column, RGBA) array as a DICOM whole slide image
+/// at `path`.
+pub fn write_dicom<T: OutputSample>(rgb: ArrayView3<T>, path: &Path, options: &DicomOptions) -> Result<(), Error> {
+    let (height, width) = (rgb.shape()[0], rgb.shape()[1]);
+    let size = (u32::try_from(width), u32::try_from(height));
+    let (Ok(width), Ok(height)) = size else {
+        return Err(Error::InvalidOptions(format!("{} x {} pixels are too many for a DICOM image", width, height)));
+    };
+    write_streamed(path, width, height, options, |writer| {
+        // Bands of whole rows of tiles, as a tiled render pushes them
+        let band = options.tile_size.max(1) as usize;
+        for y0 in (0..height as usize).step_by(band) {
+            writer.push_rows(rgb.slice(s![y0..(y0 + band).min(height as usize), .., ..]))?;
+        }
+        Ok(())
+    })
+    .map_err(|e| Error::write(path, e))
+}
+
+/// Write a `width` x `height` DICOM image at `path` whose rows `write` pushes, into a temporary
+/// file renamed onto `path` once complete.
+pub(crate) fn write_streamed<F>(
+    path: &Path,
+    width: u32,
+    height: u32,
+    options: &DicomOptions,
+    write: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(&mut DicomWriter) -> Result<(), Box<dyn std::error::Error>>,
+{
+    crate::atomic::write(path, |temporary| {
+        let mut writer = DicomWriter::create(temporary, width, height, options)?;
+        write(&mut writer)?;
+        writer.finish()
+    })
+}
+
+/// Streams the rows of an image from top to bottom into the frames of a DICOM image.
+pub(crate) struct DicomWriter {
+    file: BufWriter<File>,
+    width: usize,
+    height: usize,
+    tile: usize,
+    encapsulated: bool,
+    /// Rows of the next row of tiles pushed so far.
+    rows: Array3<u8>,
+    /// Rows pushed before them.
+    written: usize,
+}
+
+impl DicomWriter {
+    /// Create the file of a `width` x `height` image at `path` and write its attributes.
+    pub(crate) fn create(
+        path: &Path,
+        width: u32,
+        height: u32,
+        options: &DicomOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if options.tile_size == 0 || options.tile_size > u32::from(u16::MAX) {
+            let message = format!("DICOM tile size must be in [1, 65535], got {}", options.tile_size);
+            return Err(Error::InvalidOptions(message).into());
+        }
+        if options.pixel_size_um.iter().any(|size| !size.is_finite() || *size <= 0.0) {
+            return Err(Error::InvalidOptions("the pixel size of a DICOM image must be positive".to_string()).into());
+        }
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidOptions("a DICOM image cannot be empty".to_string()).into());
+        }
+
+        let tile = u64::from(options.tile_size);
+        let frames = u64::from(width).div_ceil(tile) * u64::from(height).div_ceil(tile);
+        let encapsulated = options.encapsulated || frames * tile * tile * 3 > MAX_VALUE_LENGTH;
+        if tile * tile * 3 > MAX_VALUE_LENGTH {
+            return Err(Error::InvalidOptions(format!("DICOM frames of {} pixels exceed 4 GB", tile)).into());
+        }
+        debug!(
+            "{}: {} frames of {}x{} pixels, {}",
+            path.display(),
+            frames,
+            tile,
+            tile,
+            if encapsulated { "encapsulated" } else { "native" }
+        );
+        let mut file = BufWriter::new(File::create(path)?);
+        let transfer_syntax = if encapsulated { ENCAPSULATED_UNCOMPRESSED } else { EXPLICIT_VR_LITTLE_ENDIAN };
+        let sop_instance_uid = new_uid();
+        file.write_all(&[0; 128])?;
+        file.write_all(b"DICM")?;
+        file.write_all(&file_meta(&sop_instance_uid, transfer_syntax))?;
+        file.write_all(&attributes(width, height, frames, &sop_instance_uid, options).0)?;
+
+        // Native pixel data has a defined length, encapsulated pixel data an empty offset table
+        file.write_all(&tag(0x7FE0, 0x0010))?;
+        file.write_all(b"OB\0\0")?;
+        if encapsulated {
+            file.write_all(&u32::MAX.to_le_bytes())?;
+            file.write_all(&item(0))?;
+        } else {
+            file.write_all(&((frames * tile * tile * 3).next_multiple_of(2) as u32).to_le_bytes())?;
+        }
+        Ok(DicomWriter {
+            file,
+            width: width as usize,
+            height: height as usize,
+            tile: tile as usize,
+            encapsulated,
+            rows: Array3::zeros((0, width as usize, 3)),
+            written: 0,
+        })
+    }
+
+    /// Append the next rows of the image, RGB or RGBA.
+    pub(crate) fn push_rows<T: OutputSample>(&mut self, rows: ArrayView3<T>) -> Result<(), Box<dyn std::error::Error>> {
+        if self.written + self.rows.len_of(Axis(0)) + rows.len_of(Axis(0)) > self.height {
+            return Err(format!("more than the {} rows of the image were pushed", self.height).into());
+        }
+        self.rows.append(Axis(0), to_rgb8(rows).view())?;
+        while self.rows.len_of(Axis(0)) >= self.tile {
+            self.write_tile_row(self.tile)?;
+        }
+        Ok(())
+    }
+
+    /// Write the last row of tiles and end the pixel data.
+    pub(crate) fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let remaining = self.rows.len_of(Axis(0));
+        if remaining > 0 {
+            self.write_tile_row(remaining)?;
+        }
+        if self.written != self.height {
+            return Err(format!("{} rows were pushed, expected {}", self.written, self.height).into());
+        }
+        let frames = self.width.div_ceil(self.tile) * self.height.div_ceil(self.tile);
+        if self.encapsulated {
+            // Sequence delimitation item
+            self.file.write_all(&tag(0xFFFE, 0xE0DD))?;
+            self.file.write_all(&[0; 4])?;
+        } else if frames * self.tile * self.tile * 3 % 2 == 1 {
+            self.file.write_all(&[0])?;
+        }
+        crate::atomic::finish(self.file)?;
+        debug!("{} frames written", frames);
+        Ok(())
+    }
+
+    /// Write the frames of the first `rows` rows, padded with white to whole tiles, in a fragment
+    /// each when encapsulated.
+    fn write_tile_row(&mut self, rows: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let tile = self.tile;
+        trace!("writing the frames of rows {} to {}", self.written, self.written + rows);
+        let mut frame = vec![255u8; tile * tile * 3];
+        for x0 in (0..self.width).step_by(tile) {
+            let x1 = (x0 + tile).min(self.width);
+            frame.fill(255);
+            for (y, row) in self.rows.slice(s![..rows, x0..x1, ..]).outer_iter().enumerate() {
+                let start = y * tile * 3;
+                for (target, &value) in frame[start..start + (x1 - x0) * 3].iter_mut().zip(row.iter()) {
+                    *target = value;
+                }
+            }
+            if self.encapsulated {
+                // Fragments have an even length
+                self.file.write_all(&item(frame.len().next_multiple_of(2) as u32))?;
+                self.file.write_all(&frame)?;
+                if frame.len() % 2 == 1 {
+                    self.file.write_all(&[0])?;
+                }
+            } else {
+                self.file.write_all(&frame)?;
+            }
+        }
+        self.rows = self.rows.slice(s![rows.., .., ..]).to_owned();
+        self.written += rows;
+        Ok(())
+    }
+}
+
+/// Data elements in Explicit VR Little Endian, appended in ascending order of their tags.
+#[derive(Default)]
+struct Elements(Vec<u8>);
+
+impl Elements {
+    /// Append an element with a value of even length.
+    fn element(&mut self, group: u16, element: u16, vr: &[u8; 2], value: &[u8]) -> &mut Self {
+        self.0.extend(tag(group, element));
+        self.0.extend(vr);
+        // These VRs have a reserved field and a 4 byte length
+        if matches!(vr, b"OB" | b"OW" | b"OF" | b"SQ" | b"UT" | b"UN") {
+            self.0.extend([0, 0]);
+            self.0.extend((value.len() as u32).to_le_bytes());
+        } else {
+            self.0.extend((value.len() as u16).to_le_bytes());
+        }
+        self.0.extend(value);
+        self
+    }
+
+    /// Append a string element, padded to an even length with a NUL for UIDs and a space
+    /// otherwise.
+    fn string(&mut self, group: u16, element: u16, vr: &[u8; 2], value: &str) -> &mut Self {
+        let mut bytes = value.as_bytes().to_vec();
+        if bytes.len() % 2 == 1 {
+            bytes.push(if vr == b"UI" { 0 } else { b' ' });
+        }
+        self.element(group, element, vr, &bytes)
+    }
+
+    fn unsigned_short(&mut self, group: u16, element: u16, value: u16) -> &mut Self {
+        self.element(group, element, b"US", &value.to_le_bytes())
+    }
+
+    fn unsigned_long(&mut self, group: u16, element: u16, value: u32) -> &mut Self {
+        self.element(group, element, b"UL", &value.to_le_bytes())
+    }
+
+    fn float(&mut self, group: u16, element: u16, value: f32) -> &mut Self {
+        self.element(group, element, b"FL", &value.to_le_bytes())
+    }
+
+    /// Append a sequence of items of defined length.
+    fn sequence(&mut self, group: u16, element: u16, items: &[Elements]) -> &mut Self {
+        let mut value = Vec::new();
+        for elements in items {
+            value.extend(item(elements.0.len() as u32));
+            value.extend(&elements.0);
+        }
+        self.element(group, element, b"SQ", &value)
+    }
+}
+
+/// A sequence of one item holding `elements`, which are left empty.
+fn single(elements: &mut Elements) -> [Elements; 1] {
+    [std::mem::take(elements)]
+}
+
+/// A code sequence item of a value, coding scheme and meaning.
+fn code(value: &str, scheme: &str, meaning: &str) -> Elements {
+    let mut elements = Elements::default();
+    elements
+        .string(0x0008, 0x0100, b"SH", value)
+        .string(0x0008, 0x0102, b"SH", scheme)
+        .string(0x0008, 0x0104, b"LO", meaning);
+    elements
+}
+
+/// Tag of an element in little endian.
+fn tag(group: u16, element: u16) -> [u8; 4] {
+    let [g0, g1] = group.to_le_bytes();
+    let [e0, e1] = element.to_le_bytes();
+    [g0, g1, e0, e1]
+}
+
+/// Header of an item of `length` bytes.
+fn item(length: u32) -> [u8; 8] {
+    let mut header = [0; 8];
+    header[..4].copy_from_slice(&tag(0xFFFE, 0xE000));
+    header[4..].copy_from_slice(&length.to_le_bytes());
+    header
+}
+
+/// The file meta information group, with its group length.
+fn file_meta(sop_instance_uid: &str, transfer_syntax: &str) -> Vec<u8> {
+    let version = format!("VIRTUALHE_{}", env!("CARGO_PKG_VERSION"));
+    let mut group = Elements::default();
+    group
+        .element(0x0002, 0x0001, b"OB", &[0, 1])
+        .string(0x0002, 0x0002, b"UI", WSI_SOP_CLASS_UID)
+        .string(0x0002, 0x0003, b"UI", sop_instance_uid)
+        .string(0x0002, 0x0010, b"UI", transfer_syntax)
+        .string(0x0002, 0x0012, b"UI", IMPLEMENTATION_CLASS_UID)
+        .string(0x0002, 0x0013, b"SH", &version[..version.len().min(16)]);
+    let mut meta = Elements::default();
+    meta.unsigned_long(0x0002, 0x0000, group.0.len() as u32);
+    meta.0.extend(group.0);
+    meta.0
+}
+
+/// The attributes of the image up to the pixel data: the patient, study, series and equipment,
+/// the whole slide image and its frames, the specimen and the optical path.
+fn attributes(width: u32, height: u32, frames: u64, sop_instance_uid: &str, options: &DicomOptions) -> Elements {
+    let [year, month, day, hour, minute, second] = civil_time(render_time());
+    let (date, time) = (format!("{:04}{:02}{:02}", year, month, day), format!("{:02}{:02}{:02}", hour, minute, second));
+    let study_uid = options.study_uid.clone().unwrap_or_else(new_uid);
+    let container = options.container_id.as_deref().unwrap_or(UNKNOWN_CONTAINER);
+    // Pixel spacing is in millimeters, between rows first
+    let [x_mm, y_mm] = options.pixel_size_um.map(|size| size / 1000.0);
+    let image_type = "DERIVED\\PRIMARY\\VOLUME\\NONE";
+
+    let mut elements = Elements::default();
+    elements
+        .string(0x0008, 0x0005, b"CS", "ISO_IR 192")
+        .string(0x0008, 0x0008, b"CS", image_type)
+        .string(0x0008, 0x0016, b"UI", WSI_SOP_CLASS_UID)
+        .string(0x0008, 0x0018, b"UI", sop_instance_uid)
+        .string(0x0008, 0x0020, b"DA", &date)
+        .string(0x0008, 0x0023, b"DA", &date)
+        .string(0x0008, 0x002A, b"DT", &format!("{}{}", date, time))
+        .string(0x0008, 0x0030, b"TM", &time)
+        .string(0x0008, 0x0033, b"TM", &time)
+        .string(0x0008, 0x0050, b"SH", options.accession_number.as_deref().unwrap_or(""))
+        .string(0x0008, 0x0060, b"CS", "SM")
+        .string(0x0008, 0x0070, b"LO", "virtualhe")
+        .string(0x0008, 0x0090, b"PN", "")
+        .string(0x0008, 0x103E, b"LO", "Virtual H&E")
+        .string(0x0008, 0x9206, b"CS", "VOLUME")
+        .string(0x0010, 0x0010, b"PN", options.patient_name.as_deref().unwrap_or(ANONYMOUS))
+        .string(0x0010, 0x0020, b"LO", options.patient_id.as_deref().unwrap_or(ANONYMOUS))
+        .string(0x0010, 0x0030, b"DA", "")
+        .string(0x0010, 0x0040, b"CS", "")
+        .string(0x0018, 0x1020, b"LO", env!("CARGO_PKG_VERSION"))
+        .string(0x0020, 0x000D, b"UI", &study_uid)
+        .string(0x0020, 0x000E, b"UI", &new_uid())
+        .string(0x0020, 0x0010, b"SH", "")
+        .string(0x0020, 0x0011, b"IS", "1")
+        .string(0x0020, 0x0013, b"IS", "1")
+        .string(0x0020, 0x0052, b"UI", &new_uid())
+        .string(0x0020, 0x1040, b"LO", "")
+        .sequence(0x0020, 0x9221, &single(Elements::default().string(0x0020, 0x9164, b"UI", &new_uid())))
+        .string(0x0020, 0x9311, b"CS", "TILED_FULL")
+        .unsigned_short(0x0028, 0x0002, 3)
+        .string(0x0028, 0x0004, b"CS", "RGB")
+        .unsigned_short(0x0028, 0x0006, 0)
+        .string(0x0028, 0x0008, b"IS", &frames.to_string())
+        .unsigned_short(0x0028, 0x0010, options.tile_size as u16)
+        .unsigned_short(0x0028, 0x0011, options.tile_size as u16)
+        .unsigned_short(0x0028, 0x0100, 8)
+        .unsigned_short(0x0028, 0x0101, 8)
+        .unsigned_short(0x0028, 0x0102, 7)
+        .unsigned_short(0x0028, 0x0103, 0)
+        .string(0x0028, 0x0301, b"CS", "NO")
+        .string(0x0028, 0x2110, b"CS", "00");
+
+    // The specimen is the container, whose preparation is not known
+    let mut specimen = Elements::default();
+    specimen
+        .string(0x0040, 0x0551, b"LO", container)
+        .string(0x0040, 0x0554, b"UI", &new_uid())
+        .sequence(0x0040, 0x0562, &[])
+        .sequence(0x0040, 0x0610, &[]);
+    elements
+        .string(0x0040, 0x0512, b"LO", container)
+        .sequence(0x0040, 0x0513, &[])
+        .sequence(0x0040, 0x0518, &[])
+        .sequence(0x0040, 0x0555, &[])
+        .sequence(0x0040, 0x0560, &[specimen]);
+
+    let mut origin = Elements::default();
+    origin.string(0x0040, 0x072A, b"DS", "0").string(0x0040, 0x073A, b"DS", "0");
+    let mut optical_path = Elements::default();
+    optical_path
+        .sequence(0x0022, 0x0016, &[code("111744", "DCM", "Brightfield illumination")])
+        .element(0x0028, 0x2000, b"OB", &icc_profile(options.encoding))
+        .string(0x0048, 0x0106, b"SH", "1")
+        .sequence(0x0048, 0x0108, &[code("R-102C0", "SRT", "Full Spectrum")]);
+    elements
+        .float(0x0048, 0x0001, width as f32 * x_mm)
+        .float(0x0048, 0x0002, height as f32 * y_mm)
+        .unsigned_long(0x0048, 0x0006, width)
+        .unsigned_long(0x0048, 0x0007, height)
+        .sequence(0x0048, 0x0008, &[origin])
+        .string(0x0048, 0x0010, b"CS", "NO")
+        .string(0x0048, 0x0011, b"CS", "AUTO")
+        .string(0x0048, 0x0012, b"CS", "NO")
+        .string(0x0048, 0x0102, b"DS", "0\\-1\\0\\-1\\0\\0")
+        .sequence(0x0048, 0x0105, &[optical_path])
+        .unsigned_long(0x0048, 0x0302, 1)
+        .unsigned_long(0x0048, 0x0303, 1);
+
+    // Every frame shares the pixel measures, frame type and optical path
+    let spacing = format!("{}\\{}", decimal(y_mm), decimal(x_mm));
+    let mut shared = Elements::default();
+    shared
+        .sequence(0x0028, 0x9110, &single(Elements::default().string(0x0028, 0x0030, b"DS", &spacing)))
+        .sequence(0x0040, 0x0710, &single(Elements::default().string(0x0008, 0x9007, b"CS", image_type)))
+        .sequence(0x0048, 0x0207, &single(Elements::default().string(0x0048, 0x0106, b"SH", "1")));
+    elements.sequence(0x5200, 0x9229, &[shared]);
+    elements
+}
+
+/// A decimal string of at most 16 characters.
+fn decimal(value: f32) -> String {
+    let shortest = value.to_string();
+    if shortest.len() <= 16 {
+        shortest
+    } else {
+        format!("{:.8e}", value)
+    }
+}
+
+/// A new UID under the 2.25 root, the decimal value of a version 4 UUID from 128 random bits.
+fn new_uid() -> String {
+    static CREATED: AtomicU64 = AtomicU64::new(0);
+    let nanoseconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos());
+    let created = CREATED.fetch_add(1, Ordering::Relaxed);
+    let mut bits = 0u128;
+    for half in 0..2u8 {
+        // Hashers of a new RandomState have random keys
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanoseconds);
+        hasher.write_u32(std::process::id());
+        hasher.write_u64(created);
+        hasher.write_u8(half);
+        bits = bits << 64 | u128::from(hasher.finish());
+    }
+    // Version 4 and the variant of RFC 9562
+    bits = bits & !(0xF << 76) | 4 << 76;
+    bits = bits & !(0b11 << 62) | 0b10 << 62;
+    format!("2.25.{}", bits)
+}
+
+/// An ICC version 2 display profile of the sRGB primaries and white point of the samples, with
+/// the sRGB transfer curve or a linear one.
+fn icc_profile(encoding: ColorEncoding) -> Vec<u8> {
+    let fixed = |value: f64| ((value * 65536.0).round() as i32).to_be_bytes();
+    let xyz = |[x, y, z]: [f64; 3]| [b"XYZ \0\0\0\0".as_slice(), &fixed(x), &fixed(y), &fixed(z)].concat();
+    let text = |signature: &[u8; 4], value: &str| {
+        let mut data = [signature.as_slice(), &[0; 4]].concat();
+        if signature == b"desc" {
+            data.extend((value.len() as u32 + 1).to_be_bytes());
+        }
+        data.extend(value.as_bytes());
+        data.push(0);
+        if signature == b"desc" {
+            // Empty Unicode and ScriptCode descriptions
+            data.extend([0; 4 + 4 + 2 + 1 + 67]);
+        }
+        data
+    };
+    // A table of the sRGB curve, none for the identity
+    let curve: Vec<u16> = match encoding {
+        ColorEncoding::Srgb => (0..1024)
+            .map(|i| {
+                let v = f64::from(i) / 1023.0;
+                let linear = if v <= 0.040_45 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) };
+                (linear * 65535.0).round() as u16
+            })
+            .collect(),
+        ColorEncoding::Linear => Vec::new(),
+    };
+    let mut trc = b"curv\0\0\0\0".to_vec();
+    trc.extend((curve.len() as u32).to_be_bytes());
+    trc.extend(curve.iter().flat_map(|value| value.to_be_bytes()));
+
+    // Primaries and white point adapted to the D50 illuminant of the profile connection space
+    let description = match encoding {
+        ColorEncoding::Srgb => "sRGB",
+        ColorEncoding::Linear => "Linear sRGB",
+    };
+    let tags: [(&[u8; 4], Vec<u8>); 7] = [
+        (b"desc", text(b"desc", description)),
+        (b"wtpt", xyz([0.9642, 1.0, 0.8249])),
+        (b"rXYZ", xyz([0.4361, 0.2225, 0.0139])),
+        (b"gXYZ", xyz([0.3851, 0.7169, 0.0971])),
+        (b"bXYZ", xyz([0.1431, 0.0606, 0.7141])),
+        (b"cprt", text(b"text", "No copyright, use freely")),
+        (b"rTRC", trc),
+    ];
+    // The green and blue curves share the data of the red one
+    let count = tags.len() + 2;
+    let mut table = (count as u32).to_be_bytes().to_vec();
+    let mut data = Vec::new();
+    let mut offset = 128 + 4 + 12 * count;
+    for (signature, value) in &tags {
+        let (at, size) = ((offset as u32).to_be_bytes(), (value.len() as u32).to_be_bytes());
+        let entry = [signature.as_slice(), &at, &size].concat();
+        if *signature == b"rTRC" {
+            for shared in [b"gTRC", b"bTRC"] {
+                table.extend([shared.as_slice(), &entry[4..]].concat());
+            }
+        }
+        table.extend(entry);
+        data.extend(value);
+        data.resize(data.len().next_multiple_of(4), 0);
+        offset = 128 + 4 + 12 * count + data.len();
+    }
+
+    let mut header = vec![0u8; 128];
+    header[..4].copy_from_slice(&((128 + table.len() + data.len()) as u32).to_be_bytes());
+    header[8..12].copy_from_slice(&[2, 0x10, 0, 0]);
+    header[12..24].copy_from_slice(b"mntrRGB XYZ ");
+    header[36..40].copy_from_slice(b"acsp");
+    header[68..80].copy_from_slice(&xyz([0.9642, 1.0, 0.8249])[8..]);
+    [header, table, data].concat()
+}
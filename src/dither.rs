@@ -0,0 +1,82 @@
+//! Dithering of the quantization of rendered colors to output samples, so that smooth gradients,
+//! e.g. of eosin over wide regions of cytoplasm, do not show bands of one gray level each at 8
+//! bits, which JPEG compression makes worse still.
+//!
+//! Ordered dithering adds the thresholds of a Bayer matrix to each pixel before rounding down,
+//! depending only on the position of the pixel, so that rows are quantized in parallel and bands of
+//! any multiple of 8 rows, e.g. the tiles of tiled renders, are quantized as the whole image.
+//! Floyd-Steinberg dithering diffuses the rounding error of each sample to the neighbors not yet
+//! quantized, with less visible patterns, and quantizes the rows serially, in serpentine order.
+use crate::OutputSample;
+
+/// Bayer matrix of 8 x 8 pixels, the order in which the pixels of a tile are turned on.
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Quantize the row `y` of RGB `values` in [0, 1] into `samples` by ordered dithering: the level
+/// of each value plus the threshold of its pixel in the Bayer matrix, from 1/128 to 127/128 of a
+/// level, rounded down. The levels of the 64 pixels of a tile of a uniform value average to it
+/// within 1/64 of a level.
+pub(crate) fn ordered_row<T: OutputSample>(y: usize, values: &[f32], samples: &mut [T]) {
+    let thresholds = &BAYER[y % 8];
+    for (x, (pixel, values)) in samples.chunks_exact_mut(3).zip(values.chunks_exact(3)).enumerate() {
+        let threshold = (f32::from(thresholds[x % 8]) + 0.5) / 64.0;
+        for (sample, &v) in pixel.iter_mut().zip(values) {
+            *sample = T::from_level((v * T::MAX + threshold).floor());
+        }
+    }
+}
+
+/// Floyd-Steinberg error diffusion over the rows of an image, quantized in order from the top.
+pub(crate) struct ErrorDiffusion {
+    /// Errors diffused into the row being quantized and the next one, three per pixel with a pixel
+    /// of margin on both sides for the errors diffused past the borders, which are dropped.
+    current: Vec<f32>,
+    next: Vec<f32>,
+}
+
+impl ErrorDiffusion {
+    /// Error diffusion over rows of `cols` RGB pixels.
+    pub(crate) fn new(cols: usize) -> Self {
+        ErrorDiffusion {
+            current: vec![0.0; (cols + 2) * 3],
+            next: vec![0.0; (cols + 2) * 3],
+        }
+    }
+
+    /// Quantize the row `y` of RGB `values` in [0, 1] into `samples`, the rows before it quantized
+    /// already: each value plus the errors diffused into it is rounded to the nearest level, and
+    /// its error is diffused 7/16 to the next pixel of the row and 3/16, 5/16 and 1/16 to the
+    /// pixels below behind, below and ahead. Even rows run from the left and odd rows from the
+    /// right, so that the errors do not drift to one side. The values are clamped to [0, 1] with
+    /// the errors, so that saturated regions do not carry errors into their neighbors, and NaN
+    /// values quantize to 0 without an error.
+    pub(crate) fn row<T: OutputSample>(&mut self, y: usize, values: &[f32], samples: &mut [T]) {
+        let (cols, from_left) = (values.len() / 3, y.is_multiple_of(2));
+        self.next.fill(0.0);
+        for i in 0..cols {
+            let x = if from_left { i } else { cols - 1 - i };
+            // Positions in the rows with margins of the pixel, and of the pixels behind and ahead
+            let (at, behind, ahead) = if from_left { (x + 1, x, x + 2) } else { (x + 1, x + 2, x) };
+            for c in 0..3 {
+                let wanted = (values[x * 3 + c] * T::MAX + self.current[at * 3 + c]).clamp(0.0, T::MAX);
+                let level = wanted.round();
+                samples[x * 3 + c] = T::from_level(level);
+                let error = if wanted.is_nan() { 0.0 } else { wanted - level };
+                self.current[ahead * 3 + c] += error * (7.0 / 16.0);
+                self.next[behind * 3 + c] += error * (3.0 / 16.0);
+                self.next[at * 3 + c] += error * (5.0 / 16.0);
+                self.next[ahead * 3 + c] += error * (1.0 / 16.0);
+            }
+        }
+        std::mem::swap(&mut self.current, &mut self.next);
+    }
+}
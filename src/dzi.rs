@@ -0,0 +1,348 @@
+//! Deep Zoom (DZI) output for web viewers such as OpenSeadragon: an XML descriptor `name.dzi` and
+//! the tiles of every level in `name_files/{level}/{column}_{row}.{format}`, from level 0 of one
+//! pixel up to the full resolution level ceil(log2(max(width, height))), each level the one above
+//! halved and rounded up.
+//!
+//! Tiles are `tile_size` pixels plus `overlap` pixels on each side shared with their neighbors,
+//! fewer at the edges of the level. Rows are streamed in from top to bottom and a row of tiles is
+//! written once the rows it covers are in, so that only a row of tiles per level is held in memory.
+//! Tiles are 8bit RGB, 16bit samples are rounded to 8 bits and RGBA samples composited over white.
+use crate::tiff_writer::downsample;
+use crate::{Error, OutputSample, DEFAULT_JPEG_QUALITY};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder};
+use log::{debug, trace};
+use ndarray::parallel::prelude::*;
+use ndarray::{s, Array3, ArrayView3, Axis, Zip};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+/// Default edge length of the tiles in pixels, without their overlap.
+pub const DEFAULT_DZI_TILE_SIZE: u32 = 254;
+
+/// Default overlap of neighboring tiles in pixels.
+pub const DEFAULT_DZI_OVERLAP: u32 = 1;
+
+/// Image format of the tiles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DziFormat {
+    #[default]
+    Jpeg,
+    Png,
+}
+
+impl DziFormat {
+    /// Name of the format as used on the command line, the extension of the tiles.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DziFormat::Jpeg => "jpg",
+            DziFormat::Png => "png",
+        }
+    }
+}
+
+impl std::str::FromStr for DziFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jpg" | "jpeg" => Ok(DziFormat::Jpeg),
+            "png" => Ok(DziFormat::Png),
+            _ => Err(format!("unknown tile format '{}', expected one of: jpg, png", s)),
+        }
+    }
+}
+
+/// Layout and encoding of Deep Zoom outputs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DziOptions {
+    /// Edge length of the tiles in pixels, without their overlap.
+    pub tile_size: u32,
+    /// Pixels each tile shares with each of its neighbors.
+    pub overlap: u32,
+    /// Image format of the tiles.
+    pub format: DziFormat,
+    /// JPEG quality in [1, 100], only used for JPEG tiles.
+    pub jpeg_quality: u8,
+}
+
+impl Default for DziOptions {
+    fn default() -> Self {
+        DziOptions {
+            tile_size: DEFAULT_DZI_TILE_SIZE,
+            overlap: DEFAULT_DZI_OVERLAP,
+            format: DziFormat::default(),
+            jpeg_quality: DEFAULT_JPEG_QUALITY,
+        }
+    }
+}
+
+/// Path of the directory of tiles of the descriptor at `path`, `name_files` next to `name.dzi`.
+pub fn files_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}_files", stem))
+}
+
+/// Write a rendered (row, column, RGB) or (row, column, RGBA) array as a Deep Zoom image, the
+/// descriptor at `path` and the tiles in the directory of `files_path`.
+pub fn write_dzi<T: OutputSample>(rgb: ArrayView3<T>, path: &Path, options: &DziOptions) -> Result<(), Error> {
+    let (height, width) = (rgb.shape()[0], rgb.shape()[1]);
+    let size = (u32::try_from(width), u32::try_from(height));
+    let (Ok(width), Ok(height)) = size else {
+        return Err(Error::InvalidOptions(format!("{} x {} pixels are too many for a Deep Zoom image", width, height)));
+    };
+    write_streamed(path, width, height, options, |writer| {
+        // Bands of whole rows of tiles, as a tiled render pushes them
+        let band = options.tile_size.max(1) as usize;
+        for y0 in (0..height as usize).step_by(band) {
+            writer.push_rows(rgb.slice(s![y0..(y0 + band).min(height as usize), .., ..]))?;
+        }
+        Ok(())
+    })
+    .map_err(|e| Error::write(path, e))
+}
+
+/// Write a `width` x `height` Deep Zoom image at `path` whose rows `write` pushes, the tiles into a
+/// temporary directory renamed once complete, replacing existing tiles, and then the descriptor.
+pub(crate) fn write_streamed<F>(
+    path: &Path,
+    width: u32,
+    height: u32,
+    options: &DziOptions,
+    write: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(&mut DziWriter) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let files = files_path(path);
+    crate::atomic::write(&files, |temporary| {
+        let mut writer = DziWriter::create(temporary, width, height, options)?;
+        write(&mut writer)?;
+        writer.finish()?;
+        if files.exists() {
+            fs::remove_dir_all(&files)?;
+        }
+        Ok(())
+    })?;
+    crate::atomic::write(path, |temporary| Ok(fs::write(temporary, descriptor(width, height, options))?))
+}
+
+/// The XML descriptor of a `width` x `height` Deep Zoom image.
+fn descriptor(width: u32, height: u32, options: &DziOptions) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">\n\
+         \x20 <Size Width=\"{}\" Height=\"{}\"/>\n\
+         </Image>\n",
+        options.format.name(),
+        options.overlap,
+        options.tile_size,
+        width,
+        height
+    )
+}
+
+/// Streams the rows of an image from top to bottom into the tiles of every level of a Deep Zoom
+/// image.
+pub(crate) struct DziWriter {
+    path: PathBuf,
+    options: DziOptions,
+    /// Levels from the full resolution down to one pixel.
+    levels: Vec<Level>,
+}
+
+/// Rows of one level that are not written or not downsampled yet.
+struct Level {
+    /// Directory of the tiles of the level.
+    directory: PathBuf,
+    height: usize,
+    /// Rows from row `first` on, those of the next row of tiles and the ones after it.
+    rows: Array3<u8>,
+    first: usize,
+    /// Next row of tiles to write.
+    tile_row: usize,
+    /// Rows not yet downsampled into the next level, at most one.
+    pending: Array3<u8>,
+}
+
+impl Level {
+    /// Rows of the level pushed so far.
+    fn received(&self) -> usize {
+        self.first + self.rows.len_of(Axis(0))
+    }
+}
+
+impl DziWriter {
+    /// Create the directories of the tiles of a `width` x `height` image in a new directory at
+    /// `path`.
+    pub(crate) fn create(
+        path: &Path,
+        width: u32,
+        height: u32,
+        options: &DziOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if options.tile_size == 0 {
+            return Err(Error::InvalidOptions("Deep Zoom tile size must be positive".to_string()).into());
+        }
+        if options.format == DziFormat::Jpeg && !(1..=100).contains(&options.jpeg_quality) {
+            let message = format!("JPEG quality must be in [1, 100], got {}", options.jpeg_quality);
+            return Err(Error::InvalidOptions(message).into());
+        }
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidOptions("a Deep Zoom image cannot be empty".to_string()).into());
+        }
+
+        // Levels are halved until a single pixel is left
+        let mut sizes = vec![(width as usize, height as usize)];
+        while let Some(&(w, h)) = sizes.last().filter(|(w, h)| w.max(h) > &1) {
+            sizes.push((w.div_ceil(2), h.div_ceil(2)));
+        }
+        debug!(
+            "{}: {} levels of {} {} tiles, overlap {}",
+            path.display(),
+            sizes.len(),
+            options.tile_size,
+            options.format.name(),
+            options.overlap
+        );
+        fs::create_dir_all(path)?;
+        let mut levels = Vec::with_capacity(sizes.len());
+        for (index, &(w, h)) in sizes.iter().enumerate() {
+            // Level directories count up from the single pixel
+            let directory = path.join((sizes.len() - 1 - index).to_string());
+            fs::create_dir_all(&directory)?;
+            levels.push(Level {
+                directory,
+                height: h,
+                rows: Array3::zeros((0, w, 3)),
+                first: 0,
+                tile_row: 0,
+                pending: Array3::zeros((0, w, 3)),
+            });
+        }
+        Ok(DziWriter {
+            path: path.to_path_buf(),
+            options: *options,
+            levels,
+        })
+    }
+
+    /// Append the next rows of the full resolution image, RGB or RGBA.
+    pub(crate) fn push_rows<T: OutputSample>(&mut self, rows: ArrayView3<T>) -> Result<(), Box<dyn std::error::Error>> {
+        let rows = to_rgb8(rows);
+        self.push(0, rows.view())
+    }
+
+    /// Write the remaining tiles of every level.
+    pub(crate) fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for index in 0..self.levels.len() {
+            // An odd last row is downsampled on its own, as at the edge of the whole image
+            let level = &mut self.levels[index];
+            let pending = std::mem::replace(&mut level.pending, Array3::zeros((0, 0, 3)));
+            if pending.len_of(Axis(0)) > 0 {
+                self.push(index + 1, downsample(pending.view()).view())?;
+            }
+            let level = &self.levels[index];
+            let tile_rows = level.height.div_ceil(self.options.tile_size as usize);
+            if level.received() != level.height || level.tile_row != tile_rows {
+                return Err(format!("level {} has {} rows, expected {}", index, level.received(), level.height).into());
+            }
+        }
+        debug!("{}: {} levels written", self.path.display(), self.levels.len());
+        Ok(())
+    }
+
+    /// Append rows to a level, writing the rows of tiles they complete and passing pairs of rows
+    /// on to the next level.
+    fn push(&mut self, index: usize, rows: ArrayView3<u8>) -> Result<(), Box<dyn std::error::Error>> {
+        let options = self.options;
+        let (tile, overlap) = (options.tile_size as usize, options.overlap as usize);
+        let has_next = index + 1 < self.levels.len();
+        let level = &mut self.levels[index];
+        level.rows.append(Axis(0), rows)?;
+
+        // Row r of tiles covers rows r T - O up to (r + 1) T + O, within the level
+        while level.tile_row < level.height.div_ceil(tile) {
+            let r = level.tile_row;
+            let (y0, y1) = ((r * tile).saturating_sub(overlap), ((r + 1) * tile + overlap).min(level.height));
+            if level.received() < y1 {
+                break;
+            }
+            let band = level.rows.slice(s![y0 - level.first..y1 - level.first, .., ..]);
+            write_tile_row(&level.directory, band, r, &options)?;
+            level.tile_row += 1;
+            let next = ((r + 1) * tile).saturating_sub(overlap).min(level.received());
+            if next > level.first {
+                level.rows = level.rows.slice(s![next - level.first.., .., ..]).to_owned();
+                level.first = next;
+            }
+        }
+        if !has_next {
+            return Ok(());
+        }
+
+        level.pending.append(Axis(0), rows)?;
+        let even = level.pending.len_of(Axis(0)) / 2 * 2;
+        if even == 0 {
+            return Ok(());
+        }
+        let reduced = downsample(level.pending.slice(s![..even, .., ..]));
+        level.pending = level.pending.slice(s![even.., .., ..]).to_owned();
+        self.push(index + 1, reduced.view())
+    }
+}
+
+/// Encode and write the tiles of the row `row` of tiles of a level from the rows they cover,
+/// the tiles in parallel.
+fn write_tile_row(
+    directory: &Path,
+    band: ArrayView3<u8>,
+    row: usize,
+    options: &DziOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (width, tile, overlap) = (band.shape()[1], options.tile_size as usize, options.overlap as usize);
+    trace!("{}: writing row {} of tiles", directory.display(), row);
+    (0..width.div_ceil(tile))
+        .into_par_iter()
+        .try_for_each(|column| -> Result<(), String> {
+            let (x0, x1) = ((column * tile).saturating_sub(overlap), ((column + 1) * tile + overlap).min(width));
+            let pixels = band.slice(s![.., x0..x1, ..]);
+            let (w, h) = (pixels.shape()[1] as u32, pixels.shape()[0] as u32);
+            let data: Vec<u8> = pixels.iter().copied().collect();
+            let path = directory.join(format!("{}_{}.{}", column, row, options.format.name()));
+            let write = || -> Result<(), Box<dyn std::error::Error>> {
+                let mut writer = BufWriter::new(File::create(&path)?);
+                match options.format {
+                    DziFormat::Jpeg => JpegEncoder::new_with_quality(&mut writer, options.jpeg_quality).encode(
+                        &data,
+                        w,
+                        h,
+                        ExtendedColorType::Rgb8,
+                    )?,
+                    DziFormat::Png => PngEncoder::new(&mut writer).write_image(&data, w, h, ExtendedColorType::Rgb8)?,
+                }
+                Ok(crate::atomic::finish(writer)?)
+            };
+            write().map_err(|e| format!("{}: {}", path.display(), e))
+        })?;
+    Ok(())
+}
+
+/// Convert RGB or RGBA rows to 8bit RGB, rounding 16bit samples and compositing RGBA samples over
+/// white, as thumbnails are.
+pub(crate) fn to_rgb8<T: OutputSample>(rows: ArrayView3<T>) -> Array3<u8> {
+    let (height, width, samples) = rows.dim();
+    let max = f64::from(T::MAX);
+    let mut out = Array3::<u8>::zeros((height, width, 3));
+    Zip::from(out.rows_mut()).and(rows.rows()).par_for_each(|mut pixel, source| {
+        // Over white, a sample c with alpha a shows as c a + max (1 - a)
+        let alpha = if samples == 4 { f64::from(source[3].into()) / max } else { 1.0 };
+        for channel in 0..3 {
+            let value = f64::from(source[channel].into()) * alpha + max * (1.0 - alpha);
+            pixel[channel] = if T::BITS == 8 { value.round() as u8 } else { (value * 255.0 / max).round() as u8 };
+        }
+    });
+    out
+}
@@ -0,0 +1,228 @@
+//! Histogram equalization of normalized channels before the percentile scaling, for sections whose
+//! brightness falls off with depth so that no linear window fits both ends: global equalization
+//! remaps the intensities by their cumulative distribution over the image, CLAHE (contrast limited
+//! adaptive histogram equalization) by those of tiles with the counts of each bin clipped to limit
+//! the amplification of noise, interpolated bilinearly between the tile centers.
+//!
+//! The histograms span the intensities from 0 to the maximum of the image, and the cumulative
+//! distributions are interpolated linearly within their bins, so that the remapped intensities
+//! from 0 to 1 are continuous instead of taking the levels of the bins only.
+use ndarray::parallel::prelude::*;
+use ndarray::{s, Array2, ArrayView2, Axis};
+
+/// Edge length in pixels of the tiles of CLAHE by default.
+pub const DEFAULT_CLAHE_TILE_SIZE: usize = 64;
+
+/// Clip limit of CLAHE by default, as a multiple of the mean count of a bin.
+pub const DEFAULT_CLAHE_CLIP_LIMIT: f32 = 2.0;
+
+/// Number of bins of the histogram of global equalization, one per level of 16bit images.
+const GLOBAL_BINS: usize = 65536;
+
+/// Number of bins of the histograms of the tiles of CLAHE, about four pixels per bin in the tiles
+/// of the default size.
+const CLAHE_BINS: usize = 1024;
+
+/// Cumulative distribution of a histogram of `bins` bins from 0 to the maximum intensity, with the
+/// fraction of the values below each bin and below the maximum last.
+struct Mapping {
+    cumulative: Vec<f32>,
+}
+
+impl Mapping {
+    /// Mapping of the histogram `counts`, with the counts above `clip_limit` times the mean count
+    /// of a bin spread evenly over all bins if set. Empty histograms map linearly.
+    fn new(counts: &[u64], clip_limit: Option<f32>) -> Mapping {
+        let bins = counts.len();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return Mapping {
+                cumulative: (0..=bins).map(|bin| bin as f32 / bins as f32).collect(),
+            };
+        }
+        let mut counts: Vec<f64> = counts.iter().map(|&count| count as f64).collect();
+        if let Some(clip_limit) = clip_limit {
+            let clip = f64::from(clip_limit) * total as f64 / bins as f64;
+            let excess: f64 = counts.iter().map(|&count| (count - clip).max(0.0)).sum();
+            counts.iter_mut().for_each(|count| *count = count.min(clip) + excess / bins as f64);
+        }
+        let mut cumulative = Vec::with_capacity(bins + 1);
+        let mut sum = 0.0;
+        cumulative.push(0.0);
+        for count in counts {
+            sum += count;
+            cumulative.push((sum / total as f64) as f32);
+        }
+        Mapping { cumulative }
+    }
+
+    /// Remapped intensity at the fractional bin `position`, NaN stays NaN.
+    fn apply(&self, position: f32) -> f32 {
+        if position.is_nan() {
+            return position;
+        }
+        let bins = self.cumulative.len() - 1;
+        let position = position.clamp(0.0, bins as f32);
+        let bin = (position as usize).min(bins - 1);
+        let (below, above) = (self.cumulative[bin], self.cumulative[bin + 1]);
+        below + (above - below) * (position - bin as f32)
+    }
+}
+
+/// Maximum finite intensity of an image, None if it has none above 0.
+fn max_intensity(image: &Array2<f32>) -> Option<f32> {
+    let max = image.par_iter().copied().filter(|v| v.is_finite()).reduce(|| 0.0, f32::max);
+    (max > 0.0).then_some(max)
+}
+
+/// Add the finite intensities of `values` to the histogram `counts`, each at the bin of the
+/// fractional position `v * scale`.
+fn count<'a>(counts: &mut [u64], values: impl Iterator<Item = &'a f32>, scale: f32) {
+    let last = counts.len() - 1;
+    for &v in values.filter(|v| v.is_finite()) {
+        counts[((v * scale).max(0.0) as usize).min(last)] += 1;
+    }
+}
+
+/// Remap the intensities of an image in place by their cumulative distribution, counted over the
+/// pixels selected by `mask` if given, so that they spread evenly from 0 to 1. NaN stays NaN.
+///
+/// # Panics
+///
+/// Panics if the mask differs in size from the image.
+pub fn equalize(image: &mut Array2<f32>, mask: Option<&Array2<bool>>) {
+    let Some(max) = max_intensity(image) else {
+        return;
+    };
+    let scale = GLOBAL_BINS as f32 / max;
+    // A histogram for a band of rows at a time, a few bands per thread
+    let count_band = |band: ArrayView2<f32>, mask: Option<ArrayView2<bool>>| {
+        let mut counts = vec![0u64; GLOBAL_BINS];
+        match mask {
+            Some(mask) => count(&mut counts, band.iter().zip(mask).filter(|(_, &m)| m).map(|(v, _)| v), scale),
+            None => count(&mut counts, band.iter(), scale),
+        }
+        counts
+    };
+    let merge = |mut counts: Vec<u64>, other: Vec<u64>| {
+        counts.iter_mut().zip(&other).for_each(|(count, other)| *count += other);
+        counts
+    };
+    let rows = image.nrows().div_ceil(4 * rayon::current_num_threads()).max(1);
+    let bands = image.axis_chunks_iter(Axis(0), rows).into_par_iter();
+    let counts = match mask {
+        Some(mask) => {
+            assert_eq!(mask.dim(), image.dim(), "the mask must have the size of the image");
+            bands
+                .zip(mask.axis_chunks_iter(Axis(0), rows))
+                .map(|(band, mask)| count_band(band, Some(mask)))
+                .reduce(|| vec![0u64; GLOBAL_BINS], merge)
+        }
+        None => bands.map(|band| count_band(band, None)).reduce(|| vec![0u64; GLOBAL_BINS], merge),
+    };
+    let mapping = Mapping::new(&counts, None);
+    image.par_mapv_inplace(|v| mapping.apply(v * scale));
+}
+
+/// First pixels and centers of the tiles of `tile_size` pixels along an axis of `len` pixels. The
+/// last tile ends at the border and overlaps the one before it when `len` is not a multiple of the
+/// tile size, so that every tile counts as many pixels, unless the axis is shorter than a tile.
+fn tiles(len: usize, tile_size: usize) -> Vec<(usize, f32)> {
+    (0..len.div_ceil(tile_size))
+        .map(|tile| {
+            let start = (tile * tile_size).min(len.saturating_sub(tile_size));
+            (start, (start + (start + tile_size).min(len) - 1) as f32 / 2.0)
+        })
+        .collect()
+}
+
+/// Indices of the tiles whose centers are around `position` and the weight of the second, the
+/// first or last tile alone before the first and after the last center.
+fn neighbors(centers: &[f32], position: usize) -> (usize, usize, f32) {
+    let position = position as f32;
+    match centers.partition_point(|&center| center <= position) {
+        0 => (0, 0, 0.0),
+        next if next == centers.len() => (next - 1, next - 1, 0.0),
+        next => (next - 1, next, (position - centers[next - 1]) / (centers[next] - centers[next - 1])),
+    }
+}
+
+/// Equalize an image in place by CLAHE: the intensities of each pixel are remapped by the
+/// cumulative distributions of the tiles of `tile_size` pixels around it, weighted bilinearly by
+/// the distances to their centers, with the counts of each bin clipped to `clip_limit` times the
+/// mean count of a bin before, so that the contrast of flat regions, and of their noise, is at
+/// most amplified by about that factor. A clip limit of 1 leaves the intensities almost linear.
+///
+/// When the image is not a multiple of the tile size, the last tiles end at the right and bottom
+/// borders and overlap the tiles before them instead of counting the few pixels left, and the
+/// pixels between the first or last tile centers and the border are remapped by those tiles alone.
+/// NaN stays NaN and is not counted. The distributions of a few rows of tiles at a time are held,
+/// each computed before the rows of its pixels are remapped.
+///
+/// # Panics
+///
+/// Panics if `tile_size` is 0.
+pub fn clahe(image: &mut Array2<f32>, tile_size: usize, clip_limit: f32) {
+    assert!(tile_size > 0, "the tiles must have at least one pixel");
+    let Some(max) = max_intensity(image) else {
+        return;
+    };
+    let scale = CLAHE_BINS as f32 / max;
+    let (rows, cols) = image.dim();
+    let (row_tiles, column_tiles) = (tiles(rows, tile_size), tiles(cols, tile_size));
+    let row_centers: Vec<f32> = row_tiles.iter().map(|&(_, center)| center).collect();
+    let column_centers: Vec<f32> = column_tiles.iter().map(|&(_, center)| center).collect();
+    let column_weights: Vec<(usize, usize, f32)> = (0..cols).map(|x| neighbors(&column_centers, x)).collect();
+    let mappings = |image: &Array2<f32>, tile_row: usize| -> Vec<Mapping> {
+        let y0 = row_tiles[tile_row].0;
+        column_tiles
+            .par_iter()
+            .map(|&(x0, _)| {
+                let tile = image.slice(s![y0..(y0 + tile_size).min(rows), x0..(x0 + tile_size).min(cols)]);
+                let mut counts = vec![0u64; CLAHE_BINS];
+                count(&mut counts, tile.iter(), scale);
+                Mapping::new(&counts, Some(clip_limit))
+            })
+            .collect()
+    };
+
+    // Bands of the rows between two tile centers, remapped by the rows of tiles around them
+    let mut held: Vec<(usize, Vec<Mapping>)> = Vec::new();
+    let mut y = 0;
+    while y < rows {
+        let (above, below, _) = neighbors(&row_centers, y);
+        let end = (y + 1..rows)
+            .find(|&end| {
+                let (next_above, next_below, _) = neighbors(&row_centers, end);
+                (next_above, next_below) != (above, below)
+            })
+            .unwrap_or(rows);
+        // A row of tiles ahead too, as the last one may overlap the rows of the band
+        held.retain(|(tile_row, _)| *tile_row >= above);
+        for tile_row in above..=(below + 1).min(row_tiles.len() - 1) {
+            if held.iter().all(|(held_row, _)| *held_row != tile_row) {
+                held.push((tile_row, mappings(image, tile_row)));
+            }
+        }
+        let held_row = |tile_row: usize| &held.iter().find(|(held_row, _)| *held_row == tile_row).expect("computed").1;
+        let (above_mappings, below_mappings) = (held_row(above), held_row(below));
+        image
+            .slice_mut(s![y..end, ..])
+            .axis_iter_mut(Axis(0))
+            .into_par_iter()
+            .enumerate()
+            .for_each(|(dy, mut row)| {
+                let (_, _, row_weight) = neighbors(&row_centers, y + dy);
+                for (v, &(left, right, column_weight)) in row.iter_mut().zip(&column_weights) {
+                    let position = *v * scale;
+                    let remap = |mappings: &[Mapping]| {
+                        let (left, right) = (mappings[left].apply(position), mappings[right].apply(position));
+                        left + (right - left) * column_weight
+                    };
+                    let (top, bottom) = (remap(above_mappings), remap(below_mappings));
+                    *v = top + (bottom - top) * row_weight;
+                }
+            });
+        y = end;
+    }
+}
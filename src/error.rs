@@ -0,0 +1,149 @@
+//! Error type of the library, with one variant per kind of failure so that callers can tell a
+//! missing input from an unsupported one or from a failed write.
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+/// Error returned by the reading, rendering and saving functions.
+///
+/// Every message names the file it is about.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// An input could not be opened, e.g. because it does not exist.
+    #[error("{}: {source}", path.display())]
+    Open {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// An input could not be decoded.
+    #[error("{message}")]
+    Decode { path: PathBuf, message: String },
+    /// An input or output uses a file format, pixel format or layout that is not supported.
+    #[error("{message}")]
+    UnsupportedFormat { path: PathBuf, message: String },
+    /// The nucleus and eosin images, or one plane of them, differ in size.
+    #[error("{}", shape_mismatch(nucleus, *nucleus_size, eosin, *eosin_size, *plane))]
+    ShapeMismatch {
+        nucleus: PathBuf,
+        /// Width and height of the nucleus image.
+        nucleus_size: (usize, usize),
+        eosin: PathBuf,
+        /// Width and height of the eosin image.
+        eosin_size: (usize, usize),
+        /// Plane of a stack.
+        plane: Option<usize>,
+    },
+    /// An image combined with an input, e.g. its flat-field or an extra channel, differs in size
+    /// from it.
+    #[error("{} is {}x{} but {kind} {} is {}x{}", input.display(), input_size.0, input_size.1, other.display(), other_size.0, other_size.1)]
+    SizeMismatch {
+        input: PathBuf,
+        /// Width and height of the input.
+        input_size: (usize, usize),
+        other: PathBuf,
+        /// Width and height of the other image.
+        other_size: (usize, usize),
+        /// Kind of the other image, e.g. flat-field.
+        kind: &'static str,
+    },
+    /// A channel could not be scaled, e.g. because the NaN policy rejects it.
+    #[error("{message}")]
+    Scale { path: PathBuf, message: String },
+    /// The output could not be written.
+    #[error("{message}")]
+    Write { path: PathBuf, message: String },
+    /// The options cannot be combined or do not fit the output.
+    #[error("{0}")]
+    InvalidOptions(String),
+    /// An interrupt stopped the render, with how far it got, see `interrupt`.
+    #[error("{0}")]
+    Interrupted(String),
+}
+
+impl Error {
+    /// Failure to open the input at `path`.
+    pub(crate) fn open(path: &Path, source: std::io::Error) -> Self {
+        Error::Open {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// Failure to read the input at `path`, errors of this type are kept as they are.
+    pub(crate) fn decode(path: &Path, error: impl Into<Box<dyn std::error::Error>>) -> Self {
+        match error.into().downcast::<Error>() {
+            Ok(error) => *error,
+            Err(error) if is_unsupported(error.as_ref()) => Error::unsupported(path, error),
+            Err(error) => Error::Decode {
+                path: path.to_path_buf(),
+                message: with_path(path, error),
+            },
+        }
+    }
+
+    /// Format or layout of the file at `path` that is not supported.
+    pub(crate) fn unsupported(path: &Path, message: impl Display) -> Self {
+        Error::UnsupportedFormat {
+            path: path.to_path_buf(),
+            message: with_path(path, message),
+        }
+    }
+
+    /// Failure to scale the channel read from `path`.
+    pub(crate) fn scale(path: &Path, message: impl Display) -> Self {
+        Error::Scale {
+            path: path.to_path_buf(),
+            message: with_path(path, message),
+        }
+    }
+
+    /// Failure to write the output at `path`, errors of this type are kept as they are.
+    pub(crate) fn write(path: &Path, error: impl Into<Box<dyn std::error::Error>>) -> Self {
+        match error.into().downcast::<Error>() {
+            Ok(error) => *error,
+            Err(error) if is_unsupported(error.as_ref()) => Error::unsupported(path, error),
+            Err(error) => Error::Write {
+                path: path.to_path_buf(),
+                message: with_path(path, error),
+            },
+        }
+    }
+}
+
+/// Whether an error of the image or tiff crates reports an unsupported format.
+fn is_unsupported(error: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(error.downcast_ref::<image::ImageError>(), Some(image::ImageError::Unsupported(_)))
+        || matches!(error.downcast_ref::<tiff::TiffError>(), Some(tiff::TiffError::UnsupportedError(_)))
+}
+
+/// Prefix a message with the path it is about, unless it names the path already.
+fn with_path(path: &Path, message: impl Display) -> String {
+    let message = message.to_string();
+    let name = if path == Path::new(crate::STDIO_PATH) { "stdin".to_string() } else { path.display().to_string() };
+    if message.contains(&name) {
+        message
+    } else {
+        format!("{}: {}", name, message)
+    }
+}
+
+/// Message of `Error::ShapeMismatch`.
+fn shape_mismatch(
+    nucleus: &Path,
+    (nucleus_width, nucleus_height): (usize, usize),
+    eosin: &Path,
+    (eosin_width, eosin_height): (usize, usize),
+    plane: Option<usize>,
+) -> String {
+    let plane = plane.map(|z| format!("plane {}: ", z)).unwrap_or_default();
+    format!(
+        "{}nucleus {} is {}x{} but eosin {} is {}x{} (use --crop-to-common to crop both to the overlapping region)",
+        plane,
+        nucleus.display(),
+        nucleus_width,
+        nucleus_height,
+        eosin.display(),
+        eosin_width,
+        eosin_height
+    )
+}
@@ -0,0 +1,173 @@
+//! Spatial filters applied to decoded channels before scaling, to suppress noise that would
+//! otherwise show up as speckle in the rendered colors.
+use ndarray::parallel::prelude::*;
+use ndarray::{s, Array2, Axis};
+
+/// Normalized weights of a Gaussian kernel with standard deviation `sigma`, cut off at 3 sigma.
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (3.0 * sigma).ceil() as i64;
+    let weights: Vec<f32> = (-radius..=radius)
+        .map(|x| (-((x * x) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f32 = weights.iter().sum();
+    weights.into_iter().map(|w| w / sum).collect()
+}
+
+/// Blur an image in place with a separable Gaussian filter of standard deviation `sigma` pixels,
+/// clamping at the borders. A sigma of 0 leaves the image unchanged.
+///
+/// Rows and then columns are filtered in parallel. NaN values spread to their neighbors.
+pub fn gaussian_blur(image: &mut Array2<f32>, sigma: f32) {
+    if sigma <= 0.0 || image.is_empty() {
+        return;
+    }
+    let kernel = gaussian_kernel(sigma);
+    let radius = kernel.len() / 2;
+    let (rows, cols) = image.dim();
+
+    // Along the rows, each row on its own
+    image.axis_iter_mut(Axis(0)).into_par_iter().for_each(|mut row| {
+        let source = row.to_vec();
+        for (x, v) in row.iter_mut().enumerate() {
+            *v = kernel
+                .iter()
+                .enumerate()
+                .map(|(i, w)| w * source[(x + i).saturating_sub(radius).min(cols - 1)])
+                .sum();
+        }
+    });
+
+    // Along the columns, as weighted sums of whole rows to keep the access row-major
+    let source = image.clone();
+    image
+        .axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(y, mut row)| {
+            row.fill(0.0);
+            for (i, w) in kernel.iter().enumerate() {
+                row.scaled_add(*w, &source.row((y + i).saturating_sub(radius).min(rows - 1)));
+            }
+        });
+}
+
+/// Replace every pixel by the median of the `size` x `size` window around it, clamping at the
+/// borders, to remove hot pixels. A size of 1 leaves the image unchanged.
+///
+/// Rows are filtered in parallel.
+///
+/// # Panics
+///
+/// Panics if `size` is even.
+pub fn median_filter(image: &mut Array2<f32>, size: usize) {
+    assert!(size % 2 == 1, "median window size must be odd, got {}", size);
+    if size == 1 || image.is_empty() {
+        return;
+    }
+    let radius = size / 2;
+    let (rows, cols) = image.dim();
+    let source = image.clone();
+    image
+        .axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(y, mut row)| {
+            let window_rows: Vec<_> = (0..size)
+                .map(|i| source.row((y + i).saturating_sub(radius).min(rows - 1)))
+                .collect();
+            let mut window = Vec::with_capacity(size * size);
+            for (x, v) in row.iter_mut().enumerate() {
+                window.clear();
+                for window_row in &window_rows {
+                    window.extend((0..size).map(|i| window_row[(x + i).saturating_sub(radius).min(cols - 1)]));
+                }
+                *v = *window.select_nth_unstable_by(size * size / 2, f32::total_cmp).1;
+            }
+        });
+}
+
+/// Average the pixels of `factor` x `factor` blocks into an image 1/`factor` the size, with the
+/// blocks cut off by the right and bottom borders averaging the pixels they cover.
+///
+/// Output rows are computed in parallel. NaN values spread to their block.
+///
+/// # Panics
+///
+/// Panics if `factor` is 0.
+pub fn bin_average(image: &Array2<f32>, factor: usize) -> Array2<f32> {
+    assert!(factor > 0, "downsampling factor must be positive");
+    let (rows, cols) = image.dim();
+    let mut binned = Array2::zeros((rows.div_ceil(factor), cols.div_ceil(factor)));
+    binned
+        .axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(y, mut row)| {
+            let (y0, y1) = (y * factor, (y * factor + factor).min(rows));
+            for source in image.slice(s![y0..y1, ..]).rows() {
+                for (x, v) in source.iter().enumerate() {
+                    row[x / factor] += v;
+                }
+            }
+            for (x, v) in row.iter_mut().enumerate() {
+                let width = (cols - x * factor).min(factor);
+                *v /= ((y1 - y0) * width) as f32;
+            }
+        });
+    binned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Weights of a Gaussian of standard deviation 1 at offsets -3 to 3, normalized over them.
+    fn weights() -> [f32; 7] {
+        let weights = [-3.0f32, -2.0, -1.0, 0.0, 1.0, 2.0, 3.0].map(|x| (-x * x / 2.0).exp());
+        let sum: f32 = weights.iter().sum();
+        weights.map(|w| w / sum)
+    }
+
+    fn assert_close(a: f32, b: f32, at: (usize, usize)) {
+        assert!((a - b).abs() < 1e-6, "{} instead of {} at {:?}", a, b, at);
+    }
+
+    #[test]
+    fn interior_impulse_spreads_as_the_kernel() {
+        let mut image = Array2::zeros((15, 17));
+        image[[7, 8]] = 1.0;
+        gaussian_blur(&mut image, 1.0);
+        let g = weights();
+        for ((y, x), &v) in image.indexed_iter() {
+            let (dy, dx) = (y as i64 - 7, x as i64 - 8);
+            let expected = if dy.abs() <= 3 && dx.abs() <= 3 { g[(dy + 3) as usize] * g[(dx + 3) as usize] } else { 0.0 };
+            assert_close(v, expected, (y, x));
+        }
+        assert_close(image.sum(), 1.0, (0, 0));
+    }
+
+    #[test]
+    fn border_impulse_takes_the_weights_of_the_clamped_pixels() {
+        let mut image = Array2::zeros((10, 10));
+        image[[0, 0]] = 1.0;
+        gaussian_blur(&mut image, 1.0);
+        let g = weights();
+        // A pixel at distance d from the corner gets the weights of the offsets at or beyond it,
+        // which the clamped border repeats the corner pixel for
+        let clamped = |d: usize| if d > 3 { 0.0 } else { g[..=3 - d].iter().sum::<f32>() };
+        for ((y, x), &v) in image.indexed_iter() {
+            assert_close(v, clamped(y) * clamped(x), (y, x));
+        }
+    }
+
+    #[test]
+    fn constant_images_and_zero_sigma_are_unchanged() {
+        let mut image = Array2::from_elem((5, 6), 0.4);
+        gaussian_blur(&mut image, 2.5);
+        image.indexed_iter().for_each(|(at, &v)| assert_close(v, 0.4, at));
+        let mut ramp = Array2::from_shape_fn((4, 4), |(y, x)| (y * 4 + x) as f32);
+        let original = ramp.clone();
+        gaussian_blur(&mut ramp, 0.0);
+        assert_eq!(ramp, original);
+    }
+}
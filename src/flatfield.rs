@@ -0,0 +1,58 @@
+//! Flat-field correction of decoded channels against vignetting of tile-scan acquisitions, with
+//! optional dark-field subtraction: (raw - dark) / (flat - dark), with the flat-field normalized to
+//! a mean of 1 so that the corrected values stay in the units of the input.
+use crate::{read_raw, Error, LoadOptions};
+use log::debug;
+use ndarray::{Array2, Zip};
+use std::path::Path;
+
+/// Smallest normalized flat-field value an input is divided by, so that dead regions of the
+/// flat-field are not amplified to infinity.
+const MIN_FLAT: f32 = 0.01;
+
+/// Apply the flat-field and dark-field correction of `options` to the raw values of the image read
+/// from `path`.
+pub(crate) fn correct(image: &mut Array2<f32>, path: &Path, options: &LoadOptions) -> Result<(), Error> {
+    let dark = match &options.dark_field {
+        Some(dark_path) => Some(read_correction(dark_path, path, image, "dark-field")?),
+        None => None,
+    };
+    if let Some(dark) = &dark {
+        debug!("{}: subtracting dark-field {}", path.display(), options.dark_field.as_ref().expect("read above").display());
+        Zip::from(&mut *image).and(dark).par_for_each(|v, &d| *v -= d);
+    }
+
+    let Some(flat_path) = &options.flat_field else {
+        return Ok(());
+    };
+    let mut flat = read_correction(flat_path, path, image, "flat-field")?;
+    if let Some(dark) = &dark {
+        Zip::from(&mut flat).and(dark).par_for_each(|v, &d| *v -= d);
+    }
+    let (sum, count) = flat
+        .iter()
+        .filter(|v| v.is_finite())
+        .fold((0.0f64, 0usize), |(sum, count), &v| (sum + f64::from(v), count + 1));
+    let mean = if count > 0 { (sum / count as f64) as f32 } else { 0.0 };
+    if mean <= 0.0 {
+        return Err(Error::InvalidOptions(format!("{}: flat-field has no signal", flat_path.display())));
+    }
+    debug!("{}: dividing by flat-field {} with mean {}", path.display(), flat_path.display(), mean);
+    Zip::from(image).and(&flat).par_for_each(|v, &f| *v /= (f / mean).max(MIN_FLAT));
+    Ok(())
+}
+
+/// Read the raw values of the correction image at `correction`, which must have the size of `image`.
+fn read_correction(correction: &Path, path: &Path, image: &Array2<f32>, kind: &'static str) -> Result<Array2<f32>, Error> {
+    let (values, _, _) = read_raw(correction, &LoadOptions::default()).map_err(|e| Error::decode(correction, e))?;
+    if values.dim() != image.dim() {
+        return Err(Error::SizeMismatch {
+            input: path.to_path_buf(),
+            input_size: (image.ncols(), image.nrows()),
+            other: correction.to_path_buf(),
+            other_size: (values.ncols(), values.nrows()),
+            kind,
+        });
+    }
+    Ok(values)
+}
@@ -0,0 +1,273 @@
+//! Rendering on the GPU with a WGSL compute shader, for whole-slide images whose RGB generation
+//! takes minutes on the CPU. Built with the `gpu` cargo feature.
+//!
+//! The scaled channels are uploaded in bands of rows that fit the storage buffer limits of the
+//! device, so that the image size is not limited by the GPU memory. The shader computes the
+//! encoded colors of the color model, which are quantized into the output on the CPU like those
+//! of `render_views_as`.
+use crate::dither::{ordered_row, ErrorDiffusion};
+use crate::{ColorEncoding, Dither, OutputSample, Stain};
+use log::debug;
+use ndarray::{s, Array3, ArrayView2};
+use std::sync::mpsc;
+use tiff::encoder::TiffValue;
+
+/// Largest buffer of a band in bytes, below the limits of the device.
+const MAX_BAND_BYTES: u64 = 256 << 20;
+
+/// Edge length of the square workgroups of the shader.
+const WORKGROUP_SIZE: u32 = 16;
+
+const SHADER: &str = r#"
+struct Dims {
+    width: u32,
+    rows: u32,
+    channels: u32,
+    srgb: u32,
+}
+
+@group(0) @binding(0) var<uniform> dims: Dims;
+// Beta (red, green, blue) and k of every stain
+@group(0) @binding(1) var<storage, read> stains: array<vec4<f32>>;
+// The rows of the band of every channel after each other
+@group(0) @binding(2) var<storage, read> channels: array<f32>;
+@group(0) @binding(3) var<storage, read_write> colors: array<f32>;
+
+fn encode(v: f32) -> f32 {
+    if dims.srgb == 0u {
+        return v;
+    }
+    if v <= 0.0031308 {
+        return 12.92 * v;
+    }
+    return 1.055 * pow(v, 1.0 / 2.4) - 0.055;
+}
+
+@compute @workgroup_size(16, 16)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= dims.width || id.y >= dims.rows {
+        return;
+    }
+    let pixel = id.y * dims.width + id.x;
+    var product = vec3<f32>(1.0);
+    for (var c = 0u; c < dims.channels; c++) {
+        let v = channels[c * dims.width * dims.rows + pixel];
+        let stain = stains[c];
+        product *= exp(-stain.xyz * v * stain.w);
+    }
+    colors[3u * pixel] = encode(product.x);
+    colors[3u * pixel + 1u] = encode(product.y);
+    colors[3u * pixel + 2u] = encode(product.z);
+}
+"#;
+
+/// A GPU device with the rendering pipeline.
+#[derive(Clone)]
+pub struct Gpu {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    /// Largest storage buffer binding in bytes.
+    max_binding: u64,
+    name: String,
+}
+
+impl Gpu {
+    /// Open the default GPU adapter, None if there is none.
+    pub fn new() -> Option<Self> {
+        pollster::block_on(Self::request())
+    }
+
+    async fn request() -> Option<Self> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::new_without_display_handle_from_env());
+        let options = wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        };
+        let adapter = instance.request_adapter(&options).await.ok()?;
+        let limits = adapter.limits();
+        let descriptor = wgpu::DeviceDescriptor {
+            label: Some("virtualhe"),
+            required_limits: limits.clone(),
+            ..Default::default()
+        };
+        let (device, queue) = adapter.request_device(&descriptor).await.ok()?;
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("virtualhe"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("virtualhe"),
+            layout: None,
+            module: &module,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+        let max_binding = limits.max_storage_buffer_binding_size.min(limits.max_buffer_size);
+        Some(Gpu {
+            device,
+            queue,
+            pipeline,
+            max_binding,
+            name: adapter.get_info().name,
+        })
+    }
+
+    /// Name of the adapter.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Generate a virtual image like `render_views_as` on the GPU, with the exponentials computed
+    /// by the shader and the colors read back quantized with `dither`. Err with the message of a
+    /// failed readback.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no channels, if the numbers of channels and stains differ, or if the
+    /// channels have different shapes.
+    pub fn render_views_as<T: OutputSample>(
+        &self,
+        channels: &[ArrayView2<f32>],
+        stains: &[Stain],
+        encoding: ColorEncoding,
+        dither: Dither,
+    ) -> Result<Array3<T>, String> {
+        assert!(!channels.is_empty(), "at least one channel is required");
+        assert_eq!(channels.len(), stains.len(), "every channel needs a stain");
+        let (rows, cols) = channels[0].dim();
+        assert!(channels.iter().all(|c| c.dim() == (rows, cols)), "channels must have the same shape");
+        let mut rgb = Array3::<T>::from_elem((rows, cols, 3), T::default());
+        if rgb.is_empty() {
+            return Ok(rgb);
+        }
+
+        // Rows per band, with the larger of the input and output buffers within the limits
+        let row_bytes = (cols * 4 * channels.len().max(3)) as u64;
+        let max_rows = (WORKGROUP_SIZE * u16::MAX as u32) as usize;
+        let band_rows = ((self.max_binding.min(MAX_BAND_BYTES) / row_bytes) as usize).clamp(1, max_rows).min(rows);
+        debug!("GPU {}: {} bands of {} rows", self.name, rows.div_ceil(band_rows), band_rows);
+
+        let buffer = |label, size: usize, usage| {
+            self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: size as u64,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        let stain_values: Vec<f32> = stains.iter().flat_map(|stain| [stain.beta[0], stain.beta[1], stain.beta[2], stain.k]).collect();
+        let stain_buffer = buffer("stains", stain_values.len() * 4, wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST);
+        self.queue.write_buffer(&stain_buffer, 0, &stain_values.data());
+        let dims = buffer("dims", 16, wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST);
+        let input_size = band_rows * cols * channels.len() * 4;
+        let input = buffer("channels", input_size, wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST);
+        let output_size = band_rows * cols * 3 * 4;
+        let output = buffer("colors", output_size, wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC);
+        let staging = buffer("readback", output_size, wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST);
+
+        let entries: Vec<_> = [&dims, &stain_buffer, &input, &output]
+            .into_iter()
+            .enumerate()
+            .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("virtualhe"),
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        });
+
+        let srgb = u32::from(encoding == ColorEncoding::Srgb);
+        let mut diffusion = ErrorDiffusion::new(cols);
+        for (band, mut rgb_band) in rgb.axis_chunks_iter_mut(ndarray::Axis(0), band_rows).enumerate() {
+            let (y0, height) = (band * band_rows, rgb_band.len_of(ndarray::Axis(0)));
+            let dims_values = [cols as u32, height as u32, channels.len() as u32, srgb];
+            self.queue.write_buffer(&dims, 0, &dims_values.data());
+            for (c, channel) in channels.iter().enumerate() {
+                let values = channel.slice(s![y0..y0 + height, ..]).to_owned();
+                let offset = (c * height * cols * 4) as u64;
+                self.queue.write_buffer(&input, offset, &values.as_slice().expect("owned arrays are contiguous").data());
+            }
+
+            let size = (height * cols * 3 * 4) as u64;
+            let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+            {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+                pass.set_pipeline(&self.pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch_workgroups((cols as u32).div_ceil(WORKGROUP_SIZE), (height as u32).div_ceil(WORKGROUP_SIZE), 1);
+            }
+            encoder.copy_buffer_to_buffer(&output, 0, &staging, 0, size);
+            self.queue.submit([encoder.finish()]);
+
+            let slice = staging.slice(..size);
+            let (sender, receiver) = mpsc::channel();
+            slice.map_async(wgpu::MapMode::Read, move |result| drop(sender.send(result)));
+            self.device.poll(wgpu::PollType::wait_indefinitely()).map_err(|e| e.to_string())?;
+            receiver.recv().map_err(|e| e.to_string())?.map_err(|e| e.to_string())?;
+            {
+                let colors = slice.get_mapped_range().map_err(|e| e.to_string())?;
+                let samples = rgb_band.as_slice_mut().expect("bands of a new array are contiguous");
+                let colors: Vec<f32> =
+                    colors.chunks_exact(4).map(|bytes| f32::from_ne_bytes(bytes.try_into().expect("chunks of 4 bytes"))).collect();
+                let rows = samples.chunks_exact_mut(cols * 3).zip(colors.chunks_exact(cols * 3)).enumerate();
+                for (dy, (samples, colors)) in rows {
+                    match dither {
+                        Dither::None => samples.iter_mut().zip(colors).for_each(|(sample, &v)| *sample = T::quantize(v)),
+                        Dither::Ordered => ordered_row(y0 + dy, colors, samples),
+                        Dither::FloydSteinberg => diffusion.row(y0 + dy, colors, samples),
+                    }
+                }
+            }
+            staging.unmap();
+        }
+        Ok(rgb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array2;
+
+    /// Synthetic channels covering the scaled intensities from 0 to 1, and some beyond.
+    fn channels(count: usize) -> Vec<Array2<f32>> {
+        (0..count)
+            .map(|i| Array2::from_shape_fn((37, 53), |(y, x)| ((x * 7 + y * 13 + i * 29) % 101) as f32 / 90.0))
+            .collect()
+    }
+
+    /// Largest difference between the samples of the GPU and CPU renders of `count` channels.
+    fn max_difference<T: OutputSample + Into<f64>>(gpu: &Gpu, count: usize, encoding: ColorEncoding, dither: Dither) -> f64 {
+        let channels = channels(count);
+        let views: Vec<_> = channels.iter().map(|c| c.view()).collect();
+        let stains: Vec<Stain> = (0..count)
+            .map(|i| Stain { beta: [0.86, 1.0, 0.3].map(|b| b / (i + 1) as f32), k: 2.5 })
+            .collect();
+        let on_gpu = gpu.render_views_as::<T>(&views, &stains, encoding, dither).unwrap();
+        let on_cpu = crate::render_views_as::<T>(&views, &stains, encoding, true, dither).unwrap();
+        assert_eq!(on_gpu.dim(), on_cpu.dim());
+        on_gpu.iter().zip(on_cpu.iter()).map(|(&a, &b)| (Into::<f64>::into(a) - Into::<f64>::into(b)).abs()).fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn gpu_renders_within_one_level_of_the_cpu() {
+        let Some(gpu) = Gpu::new() else {
+            eprintln!("no GPU adapter found, skipping");
+            return;
+        };
+        for count in [1, 2, 4] {
+            for encoding in [ColorEncoding::Srgb, ColorEncoding::Linear] {
+                // Floyd-Steinberg carries differences of one level on to the next pixels
+                for dither in [Dither::None, Dither::Ordered] {
+                    assert!(max_difference::<u8>(&gpu, count, encoding, dither) <= 1.0, "{} channels {:?} {:?}", count, encoding, dither);
+                    assert!(max_difference::<u16>(&gpu, count, encoding, dither) <= 1.0, "{} channels {:?} {:?}", count, encoding, dither);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,991 @@
+//! A reader of the parts of HDF5 files needed for image volumes: groups, string attributes and
+//! planes of 3D datasets of integer or floating point samples, read through a buffer of the file.
+//!
+//! Groups are read both in the format of HDF5 1.6 and 1.8 files, symbol tables indexed by a
+//! B-tree with the names in a local heap, and as the link messages of newer files. Datasets may
+//! be compact, contiguous or chunked, indexed by a B-tree or, in HDF5 1.10 files, within a single
+//! chunk, in implicit order or by a fixed array. Chunks may be compressed with deflate or LZ4 and
+//! shuffled. Dense storage of links and attributes, variable-length types and the other B-trees of
+//! HDF5 1.10 files are not read.
+//!
+//! It is written here rather than taken from the hdf5 crate, which binds the HDF5 C library, so
+//! that Imaris inputs need no libhdf5 at build or run time, e.g. for the static binaries. Every
+//! size, count and address is taken from the file, so each is checked before it is used and
+//! malformed files fail with an error rather than a panic.
+use ndarray::Array2;
+use rayon::prelude::*;
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+/// Signature at the start of the superblock.
+const SIGNATURE: &[u8] = b"\x89HDF\r\n\x1a\n";
+
+/// Message types of object headers.
+const DATASPACE: u16 = 0x1;
+const LINK_INFO: u16 = 0x2;
+const DATATYPE: u16 = 0x3;
+const LINK: u16 = 0x6;
+const LAYOUT: u16 = 0x8;
+const FILTER_PIPELINE: u16 = 0xb;
+const ATTRIBUTE: u16 = 0xc;
+const CONTINUATION: u16 = 0x10;
+const SYMBOL_TABLE: u16 = 0x11;
+const ATTRIBUTE_INFO: u16 = 0x15;
+
+/// Filters of the chunks of a dataset.
+const DEFLATE: u16 = 1;
+const SHUFFLE: u16 = 2;
+const FLETCHER32: u16 = 3;
+const LZ4: u16 = 32004;
+
+/// Largest superblock read, of any version with offsets and lengths of 8 bytes.
+const SUPERBLOCK_SIZE: u64 = 96;
+
+/// An HDF5 file open for reading.
+pub(crate) struct Hdf5 {
+    /// The file, shared by the threads decoding chunks, each read seeking within its buffer.
+    file: Mutex<BufReader<File>>,
+    /// Size of the file in bytes.
+    size: u64,
+    /// Address all other addresses are relative to, where the superblock is.
+    base: u64,
+    offset_size: usize,
+    length_size: usize,
+    /// Address of the object header of the root group.
+    root: u64,
+}
+
+/// The messages of an object header.
+pub(crate) struct Object {
+    messages: Vec<(u16, Vec<u8>)>,
+}
+
+impl Object {
+    fn message(&self, kind: u16) -> Option<&[u8]> {
+        self.messages.iter().find(|(k, _)| *k == kind).map(|(_, data)| data.as_slice())
+    }
+}
+
+/// Type of the samples of a dataset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Sample {
+    Unsigned(usize),
+    Signed(usize),
+    Float(usize),
+}
+
+impl Sample {
+    /// Size of a sample in bytes.
+    pub(crate) fn size(self) -> usize {
+        match self {
+            Sample::Unsigned(size) | Sample::Signed(size) | Sample::Float(size) => size,
+        }
+    }
+
+    /// Name of the type, e.g. u16.
+    pub(crate) fn name(self) -> String {
+        match self {
+            Sample::Unsigned(size) => format!("u{}", size * 8),
+            Sample::Signed(size) => format!("i{}", size * 8),
+            Sample::Float(size) => format!("f{}", size * 8),
+        }
+    }
+}
+
+/// Where the samples of a dataset are stored.
+#[derive(Debug, Clone)]
+enum Layout {
+    /// Within the layout message.
+    Compact(Vec<u8>),
+    Contiguous(u64),
+    Chunked { chunk: Vec<u64>, index: ChunkIndex },
+}
+
+/// How the chunks of a dataset are found.
+#[derive(Debug, Clone, Copy)]
+enum ChunkIndex {
+    BTree(u64),
+    /// One chunk, with its size and filter mask if it is filtered.
+    Single(u64, Option<(u64, u32)>),
+    /// Unfiltered chunks in row-major order from an address.
+    Implicit(u64),
+    FixedArray(u64),
+}
+
+/// A chunk of a dataset: its address, stored size, mask of the filters not applied to it and
+/// offset in samples along every dimension.
+#[derive(Debug, Clone)]
+struct Chunk {
+    address: u64,
+    size: u64,
+    filter_mask: u32,
+    offsets: Vec<u64>,
+}
+
+/// The key, child address and level of an entry of a version 1 B-tree node.
+type Entry = (Vec<u8>, u64, u8);
+
+/// A dataset: the shape and type of its samples and how they are stored.
+#[derive(Debug, Clone)]
+pub(crate) struct Dataset {
+    pub(crate) shape: Vec<u64>,
+    pub(crate) sample: Sample,
+    big_endian: bool,
+    layout: Layout,
+    /// Filter ids and client values, applied in order when writing.
+    filters: Vec<(u16, Vec<u32>)>,
+}
+
+/// Reading the fields of a structure one after the other.
+struct Fields<'a> {
+    data: &'a [u8],
+    at: usize,
+    offset_size: usize,
+    length_size: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self.at.checked_add(length).ok_or("truncated structure")?;
+        let bytes = self.data.get(self.at..end).ok_or("truncated structure")?;
+        self.at += length;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, length: usize) -> Result<(), String> {
+        self.take(length).map(|_| ())
+    }
+
+    fn uint(&mut self, size: usize) -> Result<u64, String> {
+        Ok(self.take(size)?.iter().rev().fold(0, |value, &byte| value << 8 | u64::from(byte)))
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        self.uint(2).map(|v| v as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.uint(4).map(|v| v as u32)
+    }
+
+    fn offset(&mut self) -> Result<u64, String> {
+        self.uint(self.offset_size)
+    }
+
+    fn length(&mut self) -> Result<u64, String> {
+        self.uint(self.length_size)
+    }
+}
+
+impl Hdf5 {
+    /// Open an HDF5 file and read its superblock.
+    pub(crate) fn open(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let size = file.metadata().map_err(|e| e.to_string())?.len();
+        let mut hdf5 = Hdf5 {
+            file: Mutex::new(BufReader::new(file)),
+            size,
+            base: 0,
+            offset_size: 8,
+            length_size: 8,
+            root: 0,
+        };
+        // The superblock is at the start of the file or at a power of two from 512 on
+        let signature = SIGNATURE.len() as u64;
+        hdf5.base = std::iter::once(0)
+            .chain((9..63).map(|bits| 1u64 << bits))
+            .take_while(|&at| at + signature <= size)
+            .find(|&at| hdf5.read(at, signature).is_ok_and(|data| data == SIGNATURE))
+            .ok_or("not an HDF5 file")?;
+        let superblock = hdf5.read(0, SUPERBLOCK_SIZE.min(size - hdf5.base))?;
+        let mut fields = hdf5.parse(&superblock);
+        fields.skip(SIGNATURE.len())?;
+        let version = fields.u8()?;
+        let sizes = |fields: &mut Fields| -> Result<(), String> {
+            fields.offset_size = usize::from(fields.u8()?);
+            fields.length_size = usize::from(fields.u8()?);
+            if !matches!(fields.offset_size, 2 | 4 | 8) || !matches!(fields.length_size, 2 | 4 | 8) {
+                let (offsets, lengths) = (fields.offset_size, fields.length_size);
+                return Err(format!("unsupported sizes of offsets {} and lengths {}", offsets, lengths));
+            }
+            Ok(())
+        };
+        hdf5.root = match version {
+            0 | 1 => {
+                fields.skip(4)?;
+                sizes(&mut fields)?;
+                fields.skip(1 + 4 + 4 + if version == 1 { 4 } else { 0 })?;
+                // Base, free space, end of file and driver addresses, then the root symbol table entry
+                fields.skip(4 * fields.offset_size + fields.offset_size)?;
+                fields.offset()?
+            }
+            2 | 3 => {
+                sizes(&mut fields)?;
+                fields.skip(1 + 3 * fields.offset_size)?;
+                fields.offset()?
+            }
+            _ => return Err(format!("HDF5 superblock version {} is not supported", version)),
+        };
+        (hdf5.offset_size, hdf5.length_size) = (fields.offset_size, fields.length_size);
+        Ok(hdf5)
+    }
+
+    /// Fields of a structure read from the file, e.g. a message.
+    fn parse<'a>(&self, data: &'a [u8]) -> Fields<'a> {
+        Fields {
+            data,
+            at: 0,
+            offset_size: self.offset_size,
+            length_size: self.length_size,
+        }
+    }
+
+    /// Read `length` bytes of the file from `address`, which must lie within the file so that
+    /// nothing is allocated for lengths of malformed files.
+    fn read(&self, address: u64, length: u64) -> Result<Vec<u8>, String> {
+        let beyond = || format!("{} bytes at {} are beyond the end of the file", length, address);
+        let start = self.base.checked_add(address).ok_or_else(beyond)?;
+        let end = start.checked_add(length).filter(|&end| end <= self.size).ok_or_else(beyond)?;
+        let mut data = vec![0; usize::try_from(end - start).map_err(|_| beyond())?];
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        // Seeking relative to the position keeps the buffer when the bytes are in it
+        let position = file.stream_position().map_err(|e| e.to_string())?;
+        file.seek_relative(start as i64 - position as i64).map_err(|e| e.to_string())?;
+        file.read_exact(&mut data).map_err(|e| format!("{} bytes at {}: {}", length, address, e))?;
+        Ok(data)
+    }
+
+    /// Whether an address is the undefined address, all bits set.
+    fn is_undefined(&self, address: u64) -> bool {
+        address == u64::MAX >> (64 - 8 * self.offset_size)
+    }
+
+    /// The object at `path`, names separated by slashes from the root group.
+    pub(crate) fn object(&self, path: &str) -> Result<Object, String> {
+        let mut address = self.root;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let object = self.object_at(address)?;
+            address = match self.links(&object)?.into_iter().find(|(n, _)| n == name) {
+                Some((_, address)) => address,
+                None => return Err(format!("{} not found", path)),
+            };
+        }
+        self.object_at(address)
+    }
+
+    /// Names of the objects of the group at `path`, in the order of the file.
+    pub(crate) fn children(&self, path: &str) -> Result<Vec<String>, String> {
+        let object = self.object(path)?;
+        Ok(self.links(&object)?.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Read the messages of the object header at `address`.
+    fn object_at(&self, address: u64) -> Result<Object, String> {
+        let at = |e: String| format!("object header at {}: {}", address, e);
+        let mut messages = Vec::new();
+        // Blocks of messages as (address, length), the first is in the header
+        let mut blocks = VecDeque::new();
+        let mut continued = HashSet::new();
+        // Version 2 headers start with a signature, version 1 headers are 16 bytes
+        let start = self.read(address, 6).map_err(at)?;
+        let version2 = &start[..4] == b"OHDR";
+        let mut creation_order = false;
+        if version2 {
+            let flags = start[5];
+            creation_order = flags & 0x4 != 0;
+            // Times and attribute storage thresholds are optional
+            let optional = if flags & 0x20 != 0 { 16 } else { 0 } + if flags & 0x10 != 0 { 4 } else { 0 };
+            let size_size = 1 << (flags & 3);
+            let header = self.read(address + 6 + optional, size_size).map_err(at)?;
+            let size = self.parse(&header).uint(size_size as usize).map_err(at)?;
+            blocks.push_back((address + 6 + optional + size_size, size));
+        } else {
+            let header = self.read(address, 16).map_err(at)?;
+            let mut header = self.parse(&header);
+            let version = header.u8().map_err(at)?;
+            if version != 1 {
+                return Err(at(format!("unknown object header version {}", version)));
+            }
+            header.skip(7).map_err(at)?;
+            // Messages start after the header, aligned to 8 bytes
+            let size = header.u32().map_err(at)?;
+            blocks.push_back((address + 16, u64::from(size)));
+        }
+
+        while let Some((start, length)) = blocks.pop_front() {
+            let block = self.read(start, length).map_err(at)?;
+            let mut fields = self.parse(&block);
+            let header_size = if !version2 { 8 } else if creation_order { 6 } else { 4 };
+            while fields.at as u64 + header_size <= length {
+                let (kind, size) = if version2 {
+                    let kind = u16::from(fields.u8().map_err(at)?);
+                    let size = fields.u16().map_err(at)?;
+                    fields.skip(header_size as usize - 3).map_err(at)?;
+                    (kind, size)
+                } else {
+                    let kind = fields.u16().map_err(at)?;
+                    let size = fields.u16().map_err(at)?;
+                    fields.skip(4).map_err(at)?;
+                    (kind, size)
+                };
+                let data = fields.take(usize::from(size)).map_err(at)?;
+                if kind == CONTINUATION {
+                    let mut continuation = self.parse(data);
+                    let (block, length) = (continuation.offset().map_err(at)?, continuation.length().map_err(at)?);
+                    // A continuation back to a block read before would repeat the messages forever
+                    if !continued.insert(block) {
+                        return Err(at(format!("continuation block {} is read twice", block)));
+                    }
+                    if version2 {
+                        // Continuation blocks of version 2 headers start with a signature and end with a checksum
+                        blocks.push_back((block + 4, length.saturating_sub(8)));
+                    } else {
+                        blocks.push_back((block, length));
+                    }
+                } else if kind != 0 {
+                    messages.push((kind, data.to_vec()));
+                }
+            }
+        }
+        Ok(Object { messages })
+    }
+
+    /// Names and object header addresses of the links of a group.
+    fn links(&self, group: &Object) -> Result<Vec<(String, u64)>, String> {
+        if let Some(data) = group.message(SYMBOL_TABLE) {
+            let mut fields = self.parse(data);
+            let (btree, heap) = (fields.offset()?, fields.offset()?);
+            let header = self.read(heap, (8 + 2 * self.length_size + self.offset_size) as u64)?;
+            let mut header = self.parse(&header);
+            if header.take(4)? != b"HEAP" {
+                return Err("expected a local heap".to_string());
+            }
+            header.skip(4)?;
+            let size = header.length()?;
+            header.skip(self.length_size)?;
+            let names = self.read(header.offset()?, size)?;
+            let mut links = Vec::new();
+            self.group_nodes(btree, &names, &mut links, None)?;
+            return Ok(links);
+        }
+        if let Some(data) = group.message(LINK_INFO) {
+            let mut fields = self.parse(data);
+            fields.skip(1)?;
+            let flags = fields.u8()?;
+            fields.skip(if flags & 1 != 0 { 8 } else { 0 })?;
+            if !self.is_undefined(fields.offset()?) {
+                return Err("groups with dense link storage are not supported".to_string());
+            }
+        }
+        let mut links = Vec::new();
+        for (_, data) in group.messages.iter().filter(|(kind, _)| *kind == LINK) {
+            let mut fields = self.parse(data);
+            fields.skip(1)?;
+            let flags = fields.u8()?;
+            let kind = if flags & 0x8 != 0 { fields.u8()? } else { 0 };
+            fields.skip(if flags & 0x4 != 0 { 8 } else { 0 } + if flags & 0x10 != 0 { 1 } else { 0 })?;
+            let length = fields.uint(1 << (flags & 3))? as usize;
+            let name = String::from_utf8_lossy(fields.take(length)?).into_owned();
+            // Soft and external links are not followed
+            if kind == 0 {
+                links.push((name, fields.offset()?));
+            }
+        }
+        Ok(links)
+    }
+
+    /// Collect the entries of the group B-tree node at `address`, with names in the data of the
+    /// local heap `names`.
+    fn group_nodes(
+        &self,
+        address: u64,
+        names: &[u8],
+        links: &mut Vec<(String, u64)>,
+        parent: Option<u8>,
+    ) -> Result<(), String> {
+        for (_, child, level) in self.btree_node(address, 0, self.length_size, parent)? {
+            if level > 0 {
+                self.group_nodes(child, names, links, Some(level))?;
+                continue;
+            }
+            let header = self.read(child, 8)?;
+            let mut header = self.parse(&header);
+            if header.take(4)? != b"SNOD" {
+                return Err(format!("expected a symbol table node at {}", child));
+            }
+            header.skip(2)?;
+            let symbols = u64::from(header.u16()?);
+            let entry_size = 2 * self.offset_size as u64 + 4 + 4 + 16;
+            let entries = self.read(child + 8, symbols * entry_size)?;
+            let mut node = self.parse(&entries);
+            for _ in 0..symbols {
+                let name = node.offset()?;
+                let object = node.offset()?;
+                node.skip(4 + 4 + 16)?;
+                let name = usize::try_from(name).ok().and_then(|name| names.get(name..)).ok_or("name beyond the heap")?;
+                let end = name.iter().position(|&byte| byte == 0).ok_or("unterminated name")?;
+                links.push((String::from_utf8_lossy(&name[..end]).into_owned(), object));
+            }
+        }
+        Ok(())
+    }
+
+    /// Keys, children and level of the entries of a version 1 B-tree node of `node_type`, whose
+    /// level must be below `parent`, the level of the node pointing to it, so that cycles end.
+    fn btree_node(
+        &self,
+        address: u64,
+        node_type: u8,
+        key_size: usize,
+        parent: Option<u8>,
+    ) -> Result<Vec<Entry>, String> {
+        // The signature, type, level, number of entries and sibling addresses, then the entries
+        let header_size = 8 + 2 * self.offset_size as u64;
+        let header = self.read(address, header_size)?;
+        let mut header = self.parse(&header);
+        if header.take(4)? != b"TREE" || header.u8()? != node_type {
+            return Err(format!("expected a B-tree node of type {} at {}", node_type, address));
+        }
+        let level = header.u8()?;
+        if parent.is_some_and(|parent| level >= parent) {
+            return Err(format!("B-tree node at {} of level {} is not below its parent", address, level));
+        }
+        let entries = header.u16()?;
+        let entry_size = (key_size + self.offset_size) as u64;
+        let data = self.read(address + header_size, u64::from(entries) * entry_size)?;
+        let mut node = self.parse(&data);
+        let mut children = Vec::with_capacity(usize::from(entries));
+        for _ in 0..entries {
+            let key = node.take(key_size)?.to_vec();
+            children.push((key, node.offset()?, level));
+        }
+        Ok(children)
+    }
+
+    /// The raw value of the attribute `name` of an object, if it has one.
+    pub(crate) fn attribute(&self, object: &Object, name: &str) -> Result<Option<Vec<u8>>, String> {
+        if let Some(data) = object.message(ATTRIBUTE_INFO) {
+            let mut fields = self.parse(data);
+            fields.skip(1)?;
+            let flags = fields.u8()?;
+            fields.skip(if flags & 1 != 0 { 2 } else { 0 })?;
+            if !self.is_undefined(fields.offset()?) {
+                return Err("objects with dense attribute storage are not supported".to_string());
+            }
+        }
+        for (_, data) in object.messages.iter().filter(|(kind, _)| *kind == ATTRIBUTE) {
+            let mut fields = self.parse(data);
+            let version = fields.u8()?;
+            fields.skip(1)?;
+            let (name_size, datatype_size, dataspace_size) = (fields.u16()?, fields.u16()?, fields.u16()?);
+            if version >= 3 {
+                fields.skip(1)?;
+            }
+            // Version 1 pads the name, datatype and dataspace to multiples of 8 bytes
+            let padded = |size: u16| {
+                if version == 1 {
+                    usize::from(size).next_multiple_of(8)
+                } else {
+                    usize::from(size)
+                }
+            };
+            let found = fields.take(padded(name_size))?;
+            let found = &found[..found.iter().position(|&byte| byte == 0).unwrap_or(found.len())];
+            let datatype = fields.take(padded(datatype_size))?;
+            let dataspace = fields.take(padded(dataspace_size))?;
+            if found != name.as_bytes() {
+                continue;
+            }
+            let mut datatype = self.parse(datatype);
+            datatype.skip(4)?;
+            let element_size = u64::from(datatype.u32()?);
+            let size = self.dataspace(dataspace)?.into_iter().try_fold(element_size, u64::checked_mul);
+            let size = size.and_then(|size| usize::try_from(size).ok()).ok_or("attribute too large")?;
+            return Ok(Some(fields.take(size)?.to_vec()));
+        }
+        Ok(None)
+    }
+
+    /// The attribute `name` of an object as a string, from an array of characters or a fixed-length
+    /// string, with trailing nulls removed.
+    pub(crate) fn string_attribute(&self, object: &Object, name: &str) -> Result<Option<String>, String> {
+        Ok(self
+            .attribute(object, name)?
+            .map(|value| String::from_utf8_lossy(&value).trim_end_matches('\0').to_string()))
+    }
+
+    /// Dimensions of a dataspace message, none for a scalar.
+    fn dataspace(&self, data: &[u8]) -> Result<Vec<u64>, String> {
+        let mut fields = self.parse(data);
+        let version = fields.u8()?;
+        let rank = fields.u8()?;
+        fields.skip(if version == 1 { 6 } else { 2 })?;
+        (0..rank).map(|_| fields.length()).collect()
+    }
+
+    /// The dataset whose object header is `object`.
+    pub(crate) fn dataset(&self, object: &Object) -> Result<Dataset, String> {
+        let missing = |message: &str| format!("not a dataset, it has no {} message", message);
+        let shape = self.dataspace(object.message(DATASPACE).ok_or_else(|| missing("dataspace"))?)?;
+
+        let mut datatype = self.parse(object.message(DATATYPE).ok_or_else(|| missing("datatype"))?);
+        let class = datatype.u8()? & 0xf;
+        let bits = datatype.take(3)?[0];
+        let size = datatype.u32()? as usize;
+        let sample = match (class, size) {
+            (0, 1 | 2 | 4 | 8) if bits & 0x8 != 0 => Sample::Signed(size),
+            (0, 1 | 2 | 4 | 8) => Sample::Unsigned(size),
+            (1, 4 | 8) => Sample::Float(size),
+            (0 | 1, _) => return Err(format!("samples of {} bytes are not supported", size)),
+            _ => return Err(format!("datatype class {} is not supported, expected integers or floating point", class)),
+        };
+        // The sizes of the dataset and of its chunks in bytes fit, so that their parts do as well
+        let fits = |dims: &[u64]| {
+            let bytes = dims.iter().try_fold(size as u64, |bytes, &dim| bytes.checked_mul(dim));
+            bytes.is_some_and(|bytes| usize::try_from(bytes).is_ok())
+        };
+        if !fits(&shape) {
+            return Err(format!("a dataset of {:?} samples is too large", shape));
+        }
+
+        let layout = object.message(LAYOUT).ok_or_else(|| missing("layout"))?;
+        let layout = self.layout(layout, shape.len()).map_err(|e| format!("layout: {}", e))?;
+        if let Layout::Chunked { chunk, .. } = &layout {
+            if chunk.contains(&0) || !fits(chunk) {
+                return Err(format!("layout: chunks of {:?} samples are not supported", chunk));
+            }
+        }
+        let filters = match object.message(FILTER_PIPELINE) {
+            Some(data) => self.filters(data)?,
+            None => Vec::new(),
+        };
+        Ok(Dataset {
+            shape,
+            sample,
+            big_endian: bits & 1 != 0,
+            layout,
+            filters,
+        })
+    }
+
+    /// Parse a layout message of a dataset of `rank` dimensions.
+    fn layout(&self, data: &[u8], rank: usize) -> Result<Layout, String> {
+        let mut fields = self.parse(data);
+        let version = fields.u8()?;
+        match version {
+            1 | 2 => {
+                let dimensionality = fields.u8()?;
+                let class = fields.u8()?;
+                fields.skip(5)?;
+                let address = if class != 0 { fields.offset()? } else { 0 };
+                let dims: Vec<u64> =
+                    (0..dimensionality).map(|_| fields.u32().map(u64::from)).collect::<Result<_, _>>()?;
+                match class {
+                    0 => {
+                        let size = fields.u32()? as usize;
+                        Ok(Layout::Compact(fields.take(size)?.to_vec()))
+                    }
+                    1 => Ok(Layout::Contiguous(address)),
+                    _ => Ok(Layout::Chunked {
+                        chunk: chunk_dims(&dims, rank)?,
+                        index: ChunkIndex::BTree(address),
+                    }),
+                }
+            }
+            3 | 4 => {
+                let class = fields.u8()?;
+                match class {
+                    0 => {
+                        let size = usize::from(fields.u16()?);
+                        Ok(Layout::Compact(fields.take(size)?.to_vec()))
+                    }
+                    1 => Ok(Layout::Contiguous(fields.offset()?)),
+                    2 if version == 3 => {
+                        let dimensionality = fields.u8()?;
+                        let address = fields.offset()?;
+                        let dims: Vec<u64> =
+                            (0..dimensionality).map(|_| fields.u32().map(u64::from)).collect::<Result<_, _>>()?;
+                        Ok(Layout::Chunked {
+                            chunk: chunk_dims(&dims, rank)?,
+                            index: ChunkIndex::BTree(address),
+                        })
+                    }
+                    2 => {
+                        let flags = fields.u8()?;
+                        let dimensionality = fields.u8()?;
+                        let size = usize::from(fields.u8()?);
+                        let dims: Vec<u64> = (0..dimensionality).map(|_| fields.uint(size)).collect::<Result<_, _>>()?;
+                        let index = match fields.u8()? {
+                            1 if flags & 0x2 != 0 => {
+                                let filtered = (fields.length()?, fields.u32()?);
+                                ChunkIndex::Single(fields.offset()?, Some(filtered))
+                            }
+                            1 => ChunkIndex::Single(fields.offset()?, None),
+                            2 => ChunkIndex::Implicit(fields.offset()?),
+                            3 => {
+                                fields.skip(1)?;
+                                ChunkIndex::FixedArray(fields.offset()?)
+                            }
+                            4 => return Err("chunks indexed by an extensible array are not supported".to_string()),
+                            5 => return Err("chunks indexed by a version 2 B-tree are not supported".to_string()),
+                            index => return Err(format!("unknown chunk index type {}", index)),
+                        };
+                        Ok(Layout::Chunked {
+                            chunk: chunk_dims(&dims, rank)?,
+                            index,
+                        })
+                    }
+                    _ => Err(format!("layout class {} is not supported", class)),
+                }
+            }
+            _ => Err(format!("layout message version {} is not supported", version)),
+        }
+    }
+
+    /// Parse a filter pipeline message into filter ids and client values.
+    fn filters(&self, data: &[u8]) -> Result<Vec<(u16, Vec<u32>)>, String> {
+        let mut fields = self.parse(data);
+        let version = fields.u8()?;
+        let count = fields.u8()?;
+        if version == 1 {
+            fields.skip(6)?;
+        }
+        let mut filters = Vec::new();
+        for _ in 0..count {
+            let id = fields.u16()?;
+            let name_length = if version == 1 || id >= 256 { usize::from(fields.u16()?) } else { 0 };
+            fields.skip(2)?;
+            let values = usize::from(fields.u16()?);
+            fields.skip(if version == 1 { name_length.next_multiple_of(8) } else { name_length })?;
+            let values: Vec<u32> = (0..values).map(|_| fields.u32()).collect::<Result<_, _>>()?;
+            if version == 1 && values.len() % 2 == 1 {
+                fields.skip(4)?;
+            }
+            if !matches!(id, DEFLATE | SHUFFLE | FLETCHER32 | LZ4) {
+                let expected = "expected deflate, shuffle, fletcher32 or LZ4";
+                return Err(format!("HDF5 filter {} is not supported, {}", id, expected));
+            }
+            filters.push((id, values));
+        }
+        Ok(filters)
+    }
+
+    /// The chunks of a chunked dataset, with their offsets along its dimensions.
+    fn chunks(&self, dataset: &Dataset, chunk: &[u64], index: ChunkIndex) -> Result<Vec<Chunk>, String> {
+        let rank = dataset.shape.len();
+        let chunk_bytes = chunk.iter().product::<u64>() * dataset.sample.size() as u64;
+        // Chunk positions in row-major order, the order of implicit and fixed array indices
+        let counts: Vec<u64> = dataset.shape.iter().zip(chunk).map(|(&size, &chunk)| size.div_ceil(chunk)).collect();
+        let offsets = |mut i: u64| {
+            let mut offsets = vec![0; rank];
+            for d in (0..rank).rev() {
+                offsets[d] = i % counts[d] * chunk[d];
+                i /= counts[d];
+            }
+            offsets
+        };
+        let count: u64 = counts.iter().product();
+        match index {
+            ChunkIndex::Single(address, filtered) => {
+                let (size, filter_mask) = filtered.unwrap_or((chunk_bytes, 0));
+                Ok(vec![Chunk {
+                    address,
+                    size,
+                    filter_mask,
+                    offsets: vec![0; rank],
+                }])
+            }
+            ChunkIndex::Implicit(address) => (0..count)
+                .map(|i| {
+                    let address = i.checked_mul(chunk_bytes).and_then(|offset| address.checked_add(offset));
+                    Ok(Chunk {
+                        address: address.ok_or("chunks beyond the end of the file")?,
+                        size: chunk_bytes,
+                        filter_mask: 0,
+                        offsets: offsets(i),
+                    })
+                })
+                .collect(),
+            ChunkIndex::BTree(address) => {
+                let mut chunks = Vec::new();
+                self.chunk_nodes(address, rank, &mut chunks, None)?;
+                Ok(chunks)
+            }
+            ChunkIndex::FixedArray(address) => {
+                let entries = self.fixed_array(address, !dataset.filters.is_empty(), count)?;
+                Ok(entries
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, (address, _, _))| !self.is_undefined(*address))
+                    .map(|(i, (address, size, filter_mask))| Chunk {
+                        address,
+                        size: size.unwrap_or(chunk_bytes),
+                        filter_mask,
+                        offsets: offsets(i as u64),
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Collect the chunks of the chunk B-tree node at `address` of a dataset of `rank` dimensions.
+    fn chunk_nodes(
+        &self,
+        address: u64,
+        rank: usize,
+        chunks: &mut Vec<Chunk>,
+        parent: Option<u8>,
+    ) -> Result<(), String> {
+        // Keys hold the chunk size, filter mask and offsets, with one for the sample size
+        for (key, child, level) in self.btree_node(address, 1, 8 + 8 * (rank + 1), parent)? {
+            if level > 0 {
+                self.chunk_nodes(child, rank, chunks, Some(level))?;
+                continue;
+            }
+            let mut key = self.parse(&key);
+            let size = u64::from(key.u32()?);
+            let filter_mask = key.u32()?;
+            let offsets = (0..rank).map(|_| key.uint(8)).collect::<Result<_, _>>()?;
+            chunks.push(Chunk {
+                address: child,
+                size,
+                filter_mask,
+                offsets,
+            });
+        }
+        Ok(())
+    }
+
+    /// Address, size if filtered and filter mask of the `count` chunks of a fixed array index.
+    fn fixed_array(&self, address: u64, filtered: bool, count: u64) -> Result<Vec<(u64, Option<u64>, u32)>, String> {
+        let header = self.read(address, (8 + self.length_size + self.offset_size) as u64)?;
+        let mut header = self.parse(&header);
+        if header.take(4)? != b"FAHD" {
+            return Err(format!("expected a fixed array header at {}", address));
+        }
+        header.skip(2)?;
+        let entry_size = usize::from(header.u8()?);
+        let page_bits = header.u8()?;
+        let entries = header.length()?.min(count);
+        let block = header.offset()?;
+        // Entries hold an address, and a size of at least a byte and a filter mask when filtered
+        let least = self.offset_size + if filtered { 5 } else { 0 };
+        if entry_size < least || entry_size > least + 8 || page_bits >= 64 {
+            let (size, bits) = (entry_size, page_bits);
+            return Err(format!("fixed array at {} has entries of {} bytes in pages of 2^{}", address, size, bits));
+        }
+
+        // Entries are stored in pages with their own checksums if there are more than a page holds,
+        // after a bitmap of the pages and its checksum
+        let page_size = 1u64 << page_bits;
+        let paged = entries > page_size;
+        let pages = entries.div_ceil(page_size);
+        let header_size = 6 + self.offset_size as u64 + if paged { pages.div_ceil(8) + 4 } else { 0 };
+        let checksums = if paged { 4 * (pages - 1) } else { 0 };
+        let size = entries.checked_mul(entry_size as u64).and_then(|size| size.checked_add(header_size + checksums));
+        let data = self.read(block, size.ok_or("fixed array too large")?)?;
+        let mut data = self.parse(&data);
+        if data.take(4)? != b"FADB" {
+            return Err(format!("expected a fixed array data block at {}", block));
+        }
+        data.skip(header_size as usize - 4)?;
+        let mut chunks = Vec::with_capacity(entries as usize);
+        for i in 0..entries {
+            if paged && i > 0 && i % page_size == 0 {
+                data.skip(4)?;
+            }
+            let entry = data.take(entry_size)?;
+            let mut entry = self.parse(entry);
+            let address = entry.offset()?;
+            if filtered {
+                let size = entry.uint(entry_size - self.offset_size - 4)?;
+                chunks.push((address, Some(size), entry.u32()?));
+            } else {
+                chunks.push((address, None, 0));
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Read a chunk and undo the filters applied to it.
+    fn read_chunk(&self, dataset: &Dataset, chunk: &Chunk, chunk_bytes: usize) -> Result<Vec<u8>, String> {
+        let at = |e: String| format!("chunk at {}: {}", chunk.address, e);
+        let mut data = self.read(chunk.address, chunk.size).map_err(at)?;
+        for (i, (id, values)) in dataset.filters.iter().enumerate().rev() {
+            if chunk.filter_mask & (1 << i) != 0 {
+                continue;
+            }
+            data = match *id {
+                DEFLATE => {
+                    // Nothing beyond a chunk is read, however much the data inflates to
+                    let mut out = Vec::with_capacity(chunk_bytes);
+                    let decoder = flate2::read::ZlibDecoder::new(&data[..]);
+                    decoder.take(chunk_bytes as u64).read_to_end(&mut out).map_err(|e| at(e.to_string()))?;
+                    out
+                }
+                SHUFFLE => match values.first().map_or(dataset.sample.size(), |&size| size as usize) {
+                    0 => return Err(at("shuffle of samples of 0 bytes".to_string())),
+                    size => unshuffle(&data, size),
+                },
+                FLETCHER32 => {
+                    data.truncate(data.len().saturating_sub(4));
+                    data
+                }
+                _ => lz4(&data, chunk_bytes).map_err(at)?,
+            };
+        }
+        if data.len() < chunk_bytes {
+            return Err(at(format!("{} bytes, expected {}", data.len(), chunk_bytes)));
+        }
+        Ok(data)
+    }
+
+    /// Read the region `0..height, 0..width` of plane `z` of a 3D dataset, indexed z, y, x.
+    pub(crate) fn read_plane(
+        &self,
+        dataset: &Dataset,
+        z: u64,
+        width: usize,
+        height: usize,
+    ) -> Result<Array2<f32>, String> {
+        let &[depth, rows, columns] = dataset.shape.as_slice() else {
+            return Err(format!("expected a 3D dataset, found {} dimensions", dataset.shape.len()));
+        };
+        if z >= depth || height as u64 > rows || width as u64 > columns {
+            let shape = format!("{}x{}x{}", columns, rows, depth);
+            return Err(format!("plane {} of {}x{} exceeds the {} dataset", z, width, height, shape));
+        }
+        let size = dataset.sample.size();
+        let mut image = Array2::<f32>::zeros((height, width));
+        match &dataset.layout {
+            Layout::Compact(..) | Layout::Contiguous(_) => {
+                let plane = (z * rows * columns) as usize * size;
+                // Rows of the region, from the start of the plane to the end of the last row
+                let length = (height.saturating_sub(1) * columns as usize + width) * size;
+                let read;
+                let samples = match &dataset.layout {
+                    Layout::Compact(samples) => samples.get(plane..).ok_or("truncated dataset")?,
+                    Layout::Contiguous(address) => {
+                        read = self.read(address + plane as u64, length as u64)?;
+                        &read
+                    }
+                    Layout::Chunked { .. } => unreachable!(),
+                };
+                for (y, mut row) in image.rows_mut().into_iter().enumerate() {
+                    let start = y * columns as usize * size;
+                    let samples = samples.get(start..start + width * size).ok_or("truncated dataset")?;
+                    convert(samples, dataset, row.as_slice_mut().expect("contiguous"));
+                }
+            }
+            Layout::Chunked { chunk, index } => {
+                let &[chunk_depth, chunk_rows, chunk_columns] = chunk.as_slice() else {
+                    return Err(format!("expected 3D chunks, found {} dimensions", chunk.len()));
+                };
+                let (chunk_rows, chunk_columns) = (chunk_rows as usize, chunk_columns as usize);
+                // HDF5 limits chunks to 4 GiB
+                let chunk_bytes = [chunk_depth as usize, chunk_rows, chunk_columns]
+                    .iter()
+                    .try_fold(size, |bytes, &dim| bytes.checked_mul(dim))
+                    .filter(|&bytes| bytes <= u32::MAX as usize)
+                    .ok_or_else(|| format!("chunks of {:?} samples exceed 4 GiB", chunk))?;
+                let mut chunks: Vec<Chunk> = self
+                    .chunks(dataset, chunk, *index)?
+                    .into_iter()
+                    .filter(|c| c.offsets[0] <= z && z < c.offsets[0] + chunk_depth)
+                    .filter(|c| c.offsets[1] < height as u64 && c.offsets[2] < width as u64)
+                    .collect();
+                chunks.sort_by_key(|c| (c.offsets[1], c.offsets[2]));
+                // Chunks are decoded a row of chunks at a time, holding one band of chunks in memory
+                for band in chunks.chunk_by(|a, b| a.offsets[1] == b.offsets[1]) {
+                    let decoded: Vec<Vec<u8>> = band
+                        .par_iter()
+                        .map(|c| self.read_chunk(dataset, c, chunk_bytes))
+                        .collect::<Result<_, _>>()?;
+                    for (c, data) in band.iter().zip(decoded) {
+                        let plane = (z - c.offsets[0]) as usize * chunk_rows * chunk_columns * size;
+                        let (y0, x0) = (c.offsets[1] as usize, c.offsets[2] as usize);
+                        let columns = chunk_columns.min(width - x0);
+                        for y in 0..chunk_rows.min(height - y0) {
+                            let start = plane + y * chunk_columns * size;
+                            let mut row = image.row_mut(y0 + y);
+                            let row = &mut row.as_slice_mut().expect("contiguous")[x0..x0 + columns];
+                            convert(&data[start..start + columns * size], dataset, row);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(image)
+    }
+}
+
+/// Chunk size along the `rank` dimensions of a dataset from the dimensions of a layout message,
+/// which may have one more for the sample size.
+fn chunk_dims(dims: &[u64], rank: usize) -> Result<Vec<u64>, String> {
+    dims.get(..rank)
+        .map(<[u64]>::to_vec)
+        .ok_or_else(|| format!("chunks of {} dimensions in a dataset of {}", dims.len(), rank))
+}
+
+/// Convert the samples of a dataset to f32.
+fn convert(samples: &[u8], dataset: &Dataset, out: &mut [f32]) {
+    let size = dataset.sample.size();
+    for (v, sample) in out.iter_mut().zip(samples.chunks_exact(size)) {
+        let mut bytes = [0u8; 8];
+        bytes[..size].copy_from_slice(sample);
+        if dataset.big_endian {
+            bytes[..size].reverse();
+        }
+        let bits = u64::from_le_bytes(bytes);
+        *v = match dataset.sample {
+            Sample::Unsigned(_) => bits as f32,
+            Sample::Signed(size) => ((bits << (64 - 8 * size)) as i64 >> (64 - 8 * size)) as f32,
+            Sample::Float(4) => f32::from_bits(bits as u32),
+            Sample::Float(_) => f64::from_bits(bits) as f32,
+        };
+    }
+}
+
+/// Undo the shuffle filter, which stores the first bytes of all samples, then the second ones, ...
+fn unshuffle(data: &[u8], size: usize) -> Vec<u8> {
+    let count = data.len() / size;
+    let mut out = data.to_vec();
+    for (i, &byte) in data[..count * size].iter().enumerate() {
+        out[i % count * size + i / count] = byte;
+    }
+    out
+}
+
+/// Decompress the LZ4 filter of HDF5: the total size and block size, then each block with its size,
+/// stored as is where compressing it did not make it smaller. `capacity` is the expected size,
+/// reserved instead of the total size when that is larger.
+fn lz4(data: &[u8], capacity: usize) -> Result<Vec<u8>, String> {
+    let uint = |at: usize, size: usize| {
+        data.get(at..at + size)
+            .map(|bytes| bytes.iter().fold(0u64, |value, &byte| value << 8 | u64::from(byte)))
+            .ok_or("truncated LZ4 chunk")
+    };
+    let total = uint(0, 8)? as usize;
+    let block_size = uint(8, 4)? as usize;
+    let mut out = Vec::with_capacity(total.min(capacity));
+    let mut at = 12;
+    while out.len() < total {
+        let size = uint(at, 4)? as usize;
+        let block = data.get(at + 4..).and_then(|data| data.get(..size)).ok_or("truncated LZ4 block")?;
+        let expected = block_size.min(total - out.len());
+        if size == expected {
+            out.extend_from_slice(block);
+        } else {
+            out.extend(lz4_flex::block::decompress(block, expected).map_err(|e| e.to_string())?);
+        }
+        at += 4 + size;
+    }
+    Ok(out)
+}
@@ -0,0 +1,162 @@
+//! Intensity histograms accumulated over images that are rendered one at a time, e.g. tiles cut
+//! from one slide, so that all of them can be scaled with the same thresholds instead of each
+//! with its own percentiles. A histogram holds no pixels and can be saved to skip reading the
+//! images again when they are rendered with other percentiles.
+use crate::{
+    decode_raw, normalize_input, resolve_input_max, Error, InputRange, LoadOptions, NanPolicy, ScaleOptions,
+};
+use log::debug;
+use ndarray::parallel::prelude::*;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Number of bins of a histogram, evenly spaced levels of the normalized intensities from 0 to 1
+/// that hold every 16bit input value in a bin of its own.
+pub const HISTOGRAM_BINS: usize = 65536;
+
+/// Histogram of the normalized intensities of a channel over a set of images.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    /// Input value of full intensity the values are normalized by, the same for all images.
+    pub input_max: f32,
+    /// Number of images added.
+    pub images: usize,
+    /// Number of NaN values, which are not in the bins.
+    pub nan_count: u64,
+    /// Number of values at each of the `HISTOGRAM_BINS` levels, values are rounded to the nearest
+    /// level and those above full intensity count as full intensity.
+    pub counts: Vec<u64>,
+}
+
+impl Histogram {
+    /// Empty histogram of values normalized by `input_max`.
+    pub fn new(input_max: f32) -> Self {
+        Histogram {
+            input_max,
+            images: 0,
+            nan_count: 0,
+            counts: vec![0; HISTOGRAM_BINS],
+        }
+    }
+
+    /// Add the raw values of an image, inverted to `input_max - v` for inverted inputs.
+    pub fn add(&mut self, image: &Array2<f32>, invert: bool) {
+        let (input_max, last) = (self.input_max, (HISTOGRAM_BINS - 1) as f32);
+        let (counts, nan_count) = image
+            .par_iter()
+            .fold(
+                || (vec![0u64; HISTOGRAM_BINS], 0u64),
+                |(mut counts, mut nan_count), &v| {
+                    let v = normalize_input(v, input_max, invert);
+                    if v.is_nan() {
+                        nan_count += 1;
+                    } else {
+                        counts[(v.min(1.0) * last).round() as usize] += 1;
+                    }
+                    (counts, nan_count)
+                },
+            )
+            .reduce(
+                || (vec![0u64; HISTOGRAM_BINS], 0u64),
+                |(mut counts, nan_count), (other, other_nan_count)| {
+                    counts.iter_mut().zip(&other).for_each(|(count, other)| *count += other);
+                    (counts, nan_count + other_nan_count)
+                },
+            );
+        self.counts.iter_mut().zip(&counts).for_each(|(count, other)| *count += other);
+        self.nan_count += nan_count;
+        self.images += 1;
+    }
+
+    /// Window in input units at the percentiles of `options`, selected from the levels as
+    /// `compute_thresholds` selects them from the values of one image. NaN values count as zero
+    /// or are left out by the NaN policy. `path` names the source of the histogram in errors.
+    pub fn window(&self, path: &Path, options: &ScaleOptions) -> Result<[f32; 2], Error> {
+        if self.counts.len() != HISTOGRAM_BINS {
+            return Err(Error::scale(path, format!("histogram has {} bins, expected {}", self.counts.len(), HISTOGRAM_BINS)));
+        }
+        if self.nan_count > 0 && options.nan_policy == NanPolicy::Error {
+            return Err(Error::scale(path, format!("images contain {} NaN values", self.nan_count)));
+        }
+        let zeros = if options.nan_policy == NanPolicy::Zero { self.nan_count } else { 0 };
+        let total = self.counts.iter().sum::<u64>() + zeros;
+        if total == 0 {
+            return Err(Error::scale(path, "images contain no finite values"));
+        }
+        // The value at `rank` of the sorted values is in the first bin whose cumulative count passes
+        // it, NaN values counted as zero come before all others
+        let level = |rank: u64| {
+            let mut cumulative = zeros;
+            let bin = self
+                .counts
+                .iter()
+                .position(|&count| {
+                    cumulative += count;
+                    cumulative > rank
+                })
+                .unwrap_or(HISTOGRAM_BINS - 1);
+            bin as f32 / (HISTOGRAM_BINS - 1) as f32
+        };
+        let level = |percentile: f32| options.percentile_method.select(percentile, total, level);
+        let floor = options.floor_percentile.map_or(0.0, level);
+        let ceiling = level(options.percentile);
+        // Blank images without a floor percentile scale to 0, as in `scale_with`
+        if options.floor_percentile.is_some() && floor >= ceiling {
+            return Err(Error::scale(
+                path,
+                format!(
+                    "floor intensity {} (percentile {}) is not below saturation intensity {} (percentile {})",
+                    floor,
+                    options.floor_percentile.unwrap_or(0.0),
+                    ceiling,
+                    options.percentile
+                ),
+            ));
+        }
+        Ok([floor * self.input_max, ceiling * self.input_max])
+    }
+}
+
+/// Accumulate the histogram of the images at `paths` read with `load`, decoding one image at a
+/// time. The images are normalized by the input maximum of the first one, which must not depend
+/// on its content: integer images of the same bit depth, or a fixed input maximum.
+pub fn accumulate_histogram<P: AsRef<Path>>(paths: &[P], load: &LoadOptions) -> Result<Histogram, Error> {
+    if load.range == InputRange::Auto {
+        return Err(Error::InvalidOptions(
+            "shared histograms need an input maximum that is the same for all images, not their data maximum".to_string(),
+        ));
+    }
+    let mut histogram: Option<(Histogram, Option<f32>)> = None;
+    for path in paths {
+        let path = path.as_ref();
+        let (image, _, container_max) = decode_raw(path, load)?;
+        let is_fixed = matches!(load.range, InputRange::Max(_));
+        if container_max.is_none() && !is_fixed {
+            return Err(Error::InvalidOptions(format!(
+                "{}: floating point images need a fixed input maximum for shared histograms",
+                path.display()
+            )));
+        }
+        let (histogram, first_max) = match &mut histogram {
+            Some(first) => first,
+            None => {
+                let data_max = || image.iter().copied().filter(|v| v.is_finite()).fold(0.0, f32::max);
+                histogram.insert((Histogram::new(resolve_input_max(load, container_max, data_max)?), container_max))
+            }
+        };
+        if !is_fixed && container_max != *first_max {
+            return Err(Error::InvalidOptions(format!(
+                "{}: the bit depth differs from that of {}, shared histograms need one input maximum",
+                path.display(),
+                paths[0].as_ref().display()
+            )));
+        }
+        histogram.add(&image, load.invert);
+        debug!("{}: added to histogram normalized by {}", path.display(), histogram.input_max);
+    }
+    match histogram {
+        Some((histogram, _)) => Ok(histogram),
+        None => Err(Error::InvalidOptions("no images given for the histogram".to_string())),
+    }
+}
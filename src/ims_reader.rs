@@ -0,0 +1,162 @@
+//! Imaris (.ims) input: one z plane of one channel of a resolution level, read from the HDF5
+//! layout of Imaris 5.5 files, so that cleared-tissue volumes need not be exported first.
+//!
+//! The volumes are the datasets `DataSet/ResolutionLevel L/TimePoint T/Channel C/Data`, indexed
+//! z, y, x and padded to whole chunks, with the size of the image in the ImageSizeX, ImageSizeY
+//! and ImageSizeZ attributes of the channel group. Channels are named by the Name attributes of
+//! `DataSetInfo/Channel C`. The first time point is read.
+use crate::hdf5::{Dataset, Hdf5, Sample};
+use crate::{ChannelSelector, LoadOptions, RawImage};
+use log::debug;
+use std::path::Path;
+
+/// Read the z plane and channel selected by `options` from an Imaris file as raw (not normalized)
+/// values.
+pub(crate) fn read(path: &Path, options: &LoadOptions) -> Result<RawImage, Box<dyn std::error::Error>> {
+    read_plane(path, options).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Width and height of the channel selected by `options`, None if it cannot be read.
+pub(crate) fn dimensions(path: &Path, options: &LoadOptions) -> Option<(usize, usize)> {
+    let file = Hdf5::open(path).ok()?;
+    let volume = Volume::select(&file, options).ok()?;
+    Some((volume.width, volume.height))
+}
+
+/// Number of z planes of the channel selected by `options`, for stacks, with errors not prefixed by
+/// the path.
+pub(crate) fn planes(path: &Path, options: &LoadOptions) -> Result<usize, String> {
+    let file = Hdf5::open(path)?;
+    Ok(Volume::select(&file, options)?.depth)
+}
+
+/// Read a plane as `read` does, with errors not yet prefixed by the path.
+fn read_plane(path: &Path, options: &LoadOptions) -> Result<RawImage, String> {
+    let file = Hdf5::open(path)?;
+    let volume = Volume::select(&file, options)?;
+    let z = options.z.unwrap_or(0);
+    if z >= volume.depth {
+        return Err(format!("z plane {} is out of range, the volume has the planes {}", z, range(volume.depth)));
+    }
+    let (pixel_format, container_max) = match volume.dataset.sample {
+        Sample::Unsigned(1) => ("L8", Some(255.0)),
+        Sample::Unsigned(2) => ("L16", Some(65535.0)),
+        Sample::Unsigned(4) => ("L32", Some(u32::MAX as f32)),
+        Sample::Float(4) => ("L32F", None),
+        sample => return Err(format!("{} samples are not supported, expected u8, u16, u32 or f32", sample.name())),
+    };
+    debug!(
+        "{}: Imaris {}x{}x{} {} of channel {} at resolution level {}, plane {}",
+        path.display(),
+        volume.width,
+        volume.height,
+        volume.depth,
+        pixel_format,
+        volume.channel,
+        options.level,
+        z
+    );
+    let image = file.read_plane(&volume.dataset, z as u64, volume.width, volume.height)?;
+    Ok((image, pixel_format.to_string(), container_max))
+}
+
+/// The dataset of a channel at a resolution level and the size of the volume it holds.
+struct Volume {
+    channel: usize,
+    width: usize,
+    height: usize,
+    depth: usize,
+    dataset: Dataset,
+}
+
+impl Volume {
+    /// The volume of the resolution level and channel selected by `options`, at the first time point.
+    fn select(file: &Hdf5, options: &LoadOptions) -> Result<Self, String> {
+        if options.page.is_some() {
+            return Err("pages cannot be selected from Imaris files, select a z plane".to_string());
+        }
+        let levels = indices(file.children("DataSet")?, "ResolutionLevel ");
+        if !levels.contains(&options.level) {
+            return Err(format!(
+                "resolution level {} is out of range, the file has the levels {}",
+                options.level,
+                list(&levels)
+            ));
+        }
+        let time_point = format!("DataSet/ResolutionLevel {}/TimePoint 0", options.level);
+        let channels = indices(file.children(&time_point)?, "Channel ");
+        let channel = match &options.channel {
+            None => *channels.first().ok_or("the file holds no channels")?,
+            Some(ChannelSelector::Index(index)) if channels.contains(index) => *index,
+            Some(ChannelSelector::Index(index)) => {
+                let channels = list(&channels);
+                return Err(format!("channel {} is out of range, the volume has the channels {}", index, channels));
+            }
+            Some(ChannelSelector::Name(name)) => {
+                let names = channel_names(file, &channels)?;
+                match names.iter().find(|(_, n)| n == name) {
+                    Some((index, _)) => *index,
+                    None => {
+                        let names: Vec<&str> = names.iter().map(|(_, name)| name.as_str()).collect();
+                        return Err(format!("no channel named '{}', expected one of: {}", name, names.join(", ")));
+                    }
+                }
+            }
+        };
+
+        let group = format!("{}/Channel {}", time_point, channel);
+        let dataset = file.dataset(&file.object(&format!("{}/Data", group))?)?;
+        let &[depth, height, width] = dataset.shape.as_slice() else {
+            return Err(format!("expected a 3D dataset, found {} dimensions", dataset.shape.len()));
+        };
+        // The datasets are padded to whole chunks, the attributes hold the size of the image
+        let object = file.object(&group)?;
+        let size = |name: &str, padded: u64| -> Result<usize, String> {
+            let size = file.string_attribute(&object, name)?.and_then(|value| value.trim().parse::<u64>().ok());
+            Ok(size.unwrap_or(padded).min(padded) as usize)
+        };
+        Ok(Volume {
+            channel,
+            width: size("ImageSizeX", width)?,
+            height: size("ImageSizeY", height)?,
+            depth: size("ImageSizeZ", depth)?,
+            dataset,
+        })
+    }
+}
+
+/// Indices and names of the channels, from the Name attributes of their `DataSetInfo` groups.
+fn channel_names(file: &Hdf5, channels: &[usize]) -> Result<Vec<(usize, String)>, String> {
+    let mut names = Vec::new();
+    for &channel in channels {
+        let Ok(info) = file.object(&format!("DataSetInfo/Channel {}", channel)) else {
+            continue;
+        };
+        if let Some(name) = file.string_attribute(&info, "Name")? {
+            names.push((channel, name));
+        }
+    }
+    Ok(names)
+}
+
+/// The sorted indices of the groups named `prefix` followed by an index.
+fn indices(names: Vec<String>, prefix: &str) -> Vec<usize> {
+    let mut indices: Vec<usize> =
+        names.iter().filter_map(|name| name.strip_prefix(prefix)).filter_map(|index| index.parse().ok()).collect();
+    indices.sort_unstable();
+    indices
+}
+
+/// A list of indices, as a range if they are consecutive, e.g. 0-3.
+fn list(indices: &[usize]) -> String {
+    match indices {
+        [] => "none".to_string(),
+        [first, .., last] if last - first + 1 == indices.len() => format!("{}-{}", first, last),
+        _ => indices.iter().map(|index| index.to_string()).collect::<Vec<_>>().join(", "),
+    }
+}
+
+/// The planes `0..count` as a list.
+fn range(count: usize) -> String {
+    list(&(0..count).collect::<Vec<_>>())
+}
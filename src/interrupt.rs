@@ -0,0 +1,29 @@
+//! Cancellation of renders by an interrupt: an interrupt handler requests it with `request`, and
+//! tiled and stack renders check it with `check` between the bands of tiles and the planes they
+//! render, failing with `Error::Interrupted`. The temporary file of the output being written is
+//! then deleted as that of any failed write, while outputs completed before are kept.
+use crate::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether an interrupt requested the renders to stop.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Request the renders to stop at their next check. Returns false if requested before.
+pub fn request() -> bool {
+    !REQUESTED.swap(true, Ordering::SeqCst)
+}
+
+/// Whether an interrupt requested the renders to stop.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Fail with `Error::Interrupted` if an interrupt requested the renders to stop, with the message
+/// returned by `describe` telling how far the render got.
+pub fn check(describe: impl FnOnce() -> String) -> Result<(), Error> {
+    if requested() {
+        Err(Error::Interrupted(describe()))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! Journals of resumable tiled renders: a tiled TIFF or OME-Zarr output is written into a partial
+//! file next to the output, the same for every run, and once a band of tiles is flushed to disk a
+//! line recording it is appended to a journal next to the partial file and flushed in turn, so that
+//! a run that dies, e.g. with its node, leaves the bands it completed for `--resume` to keep.
+//!
+//! The first line describes the render, a run with other inputs or settings starts over. Each
+//! following line records the rows completed and, for TIFF outputs, the offsets and byte counts of
+//! the tiles of the band. A line cut short by a crash is dropped with the lines after it.
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// What a journal was written for: the layout of the output and everything its samples depend on.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct Header {
+    /// Version of the tool, as the encoding of the tiles may change between versions.
+    pub(crate) version: String,
+    /// Layout of the output, e.g. tiff, bigtiff or zarr with its chunk size.
+    pub(crate) layout: String,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) tile_size: u32,
+    pub(crate) bits_per_sample: u16,
+    /// Rendering parameters and the options of the channels.
+    pub(crate) settings: String,
+    /// Paths, sizes and modification times of the inputs.
+    pub(crate) inputs: Vec<(String, u64, Option<u64>)>,
+}
+
+impl Header {
+    /// Path, size and modification time in seconds of an input, for `inputs`.
+    pub(crate) fn input(path: &Path) -> (String, u64, Option<u64>) {
+        let metadata = fs::metadata(path).ok();
+        let modified = metadata
+            .as_ref()
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs());
+        (path.display().to_string(), metadata.map_or(0, |metadata| metadata.len()), modified)
+    }
+}
+
+/// A band of tiles flushed to the partial output.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Band {
+    /// Rows of the output completed with the band.
+    pub(crate) rows: u32,
+    /// Offsets and byte counts of the tiles of the band in a TIFF output, left to right.
+    pub(crate) tiles: Vec<[u64; 2]>,
+}
+
+/// A journal open for appending bands.
+pub(crate) struct Journal {
+    file: File,
+    /// Bands recorded so far, in order.
+    pub(crate) bands: Vec<Band>,
+}
+
+impl Journal {
+    /// Start a new journal at `path` for the render described by `header`, replacing any other.
+    pub(crate) fn create(path: &Path, header: &Header) -> Result<Journal, Box<dyn std::error::Error>> {
+        let mut journal = Journal {
+            file: File::create(path)?,
+            bands: Vec::new(),
+        };
+        journal.append(&serde_json::to_string(header)?)?;
+        Ok(journal)
+    }
+
+    /// Open the journal at `path` to continue the render described by `header` after the bands it
+    /// recorded, or None if there is no journal or it was written for another render.
+    pub(crate) fn resume(path: &Path, header: &Header) -> Result<Option<Journal>, Box<dyn std::error::Error>> {
+        let Ok(contents) = fs::read_to_string(path) else {
+            return Ok(None);
+        };
+        // Complete lines only, a line is appended with its newline
+        let mut lines = contents.split_inclusive('\n').take_while(|line| line.ends_with('\n'));
+        match lines.next().and_then(|line| serde_json::from_str::<Header>(line).ok()) {
+            Some(first) if first == *header => {}
+            _ => return Ok(None),
+        }
+        let mut length = contents.find('\n').expect("a complete line") + 1;
+        let mut bands = Vec::new();
+        for line in lines {
+            let Ok(band) = serde_json::from_str::<Band>(line) else {
+                break;
+            };
+            bands.push(band);
+            length += line.len();
+        }
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(length as u64)?;
+        file.sync_data()?;
+        Ok(Some(Journal { file, bands }))
+    }
+
+    /// Record a band, once its data is flushed to disk.
+    pub(crate) fn record(&mut self, band: Band) -> Result<(), Box<dyn std::error::Error>> {
+        self.append(&serde_json::to_string(&band)?)?;
+        self.bands.push(band);
+        Ok(())
+    }
+
+    /// Append a line at the end of the journal and flush it to disk.
+    fn append(&mut self, line: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,2881 @@
+//! Create virtual H&E images from fluorescent microscopy data.
+//!
+//! Based on: Giacomelli MG, et al. Virtual Hematoxylin and Eosin Transillumination
+//! Microscopy Using Epi-Fluorescence Imaging. PLoS One. 2016;11(8):e0159337.
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ExtendedColorType, ImageBuffer, ImageFormat, ImageReader, Pixel, RgbImage};
+use log::debug;
+use ndarray::parallel::prelude::*;
+use ndarray::{s, Array2, Array3, ArrayView2, ArrayView3, Axis, Zip};
+use rayon::slice::ParallelSliceMut;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, Write};
+use std::mem::drop;
+use std::path::{Path, PathBuf};
+use tiff::encoder::TiffValue;
+
+pub mod align;
+pub mod alpha;
+pub mod atomic;
+mod blosc;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "czi")]
+mod czi_reader;
+pub mod dicom;
+mod dither;
+pub mod dzi;
+pub mod equalize;
+mod error;
+pub mod filter;
+mod flatfield;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+#[cfg(feature = "ims")]
+mod hdf5;
+pub mod histogram;
+#[cfg(feature = "ims")]
+mod ims_reader;
+pub mod interrupt;
+mod journal;
+pub mod mask;
+pub mod montage;
+pub mod ome;
+pub mod overlay;
+#[cfg(feature = "python")]
+mod python;
+pub mod quantile;
+mod raw_reader;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod stack;
+pub mod tiled;
+mod tiff_reader;
+mod tiff_writer;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod zarr_reader;
+mod zarr_writer;
+
+pub use error::Error;
+
+/// Beta coefficients from the paper: hematoxylin and eosin, each (red, green, blue).
+pub const DEFAULT_BETA: [[f32; 3]; 2] = [
+    // Hematoxylin: (red, green, blue)
+    [0.860, 1.000, 0.300],
+    // Eosin: (red, green, blue)
+    [0.050, 1.000, 0.544],
+];
+
+/// Beta coefficients of a brown DAB-like stain for an extra channel (red, green, blue), from the
+/// optical densities of DAB in Ruifrok and Johnston 2001.
+pub const DAB_BETA: [f32; 3] = [0.268, 0.570, 0.776];
+
+/// Parameters controlling the color model used by `render`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Params {
+    /// K arbitrary factor to adjust color profile of the hematoxylin (nucleus) channel.
+    pub k_nucleus: f32,
+    /// K arbitrary factor to adjust color profile of the eosin channel.
+    pub k_eosin: f32,
+    /// Beta coefficients: hematoxylin and eosin, each (red, green, blue).
+    pub beta: [[f32; 3]; 2],
+    /// K factor of the extra channel of `render_with_extra_as`.
+    pub k_extra: f32,
+    /// Beta coefficients of the extra channel (red, green, blue).
+    pub beta_extra: [f32; 3],
+    /// Encoding of the computed transmittance in the output samples.
+    pub encoding: ColorEncoding,
+    /// Evaluate every exponential directly instead of interpolating it in lookup tables.
+    pub exact: bool,
+    /// Dithering of the quantization to the output samples.
+    pub dither: Dither,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            k_nucleus: 2.5,
+            k_eosin: 2.5,
+            beta: DEFAULT_BETA,
+            k_extra: 2.5,
+            beta_extra: DAB_BETA,
+            encoding: ColorEncoding::default(),
+            exact: false,
+            dither: Dither::None,
+        }
+    }
+}
+
+impl Params {
+    /// Set the same k for both channels.
+    pub fn with_k(self, k: f32) -> Self {
+        Params {
+            k_nucleus: k,
+            k_eosin: k,
+            ..self
+        }
+    }
+
+    /// The hematoxylin, eosin and extra stains of the parameters.
+    pub fn stains(&self) -> [Stain; 3] {
+        [
+            Stain {
+                beta: self.beta[0],
+                k: self.k_nucleus,
+            },
+            Stain {
+                beta: self.beta[1],
+                k: self.k_eosin,
+            },
+            Stain {
+                beta: self.beta_extra,
+                k: self.k_extra,
+            },
+        ]
+    }
+}
+
+/// Encoding of the linear transmittance values in the rendered output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorEncoding {
+    /// Linear values, viewers that assume sRGB show them darker and more saturated.
+    Linear,
+    /// The sRGB transfer curve, as viewers assume for PNG, TIFF and JPEG images.
+    #[default]
+    Srgb,
+}
+
+impl ColorEncoding {
+    /// Name of the encoding as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorEncoding::Linear => "linear",
+            ColorEncoding::Srgb => "srgb",
+        }
+    }
+
+    /// Encode a linear value in [0, 1].
+    pub fn encode(&self, v: f32) -> f32 {
+        match self {
+            ColorEncoding::Linear => v,
+            ColorEncoding::Srgb if v <= 0.003_130_8 => 12.92 * v,
+            ColorEncoding::Srgb => 1.055 * v.powf(1.0 / 2.4) - 0.055,
+        }
+    }
+}
+
+impl std::str::FromStr for ColorEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(ColorEncoding::Linear),
+            "srgb" => Ok(ColorEncoding::Srgb),
+            _ => Err(format!("unknown color encoding '{}', expected one of: linear, srgb", s)),
+        }
+    }
+}
+
+/// Dithering of the quantization of the rendered colors to the output samples, against the banding
+/// of smooth gradients at 8 bits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dither {
+    /// Round every sample to the nearest level.
+    #[default]
+    None,
+    /// Add the thresholds of an 8 x 8 Bayer matrix before rounding down, each pixel on its own.
+    Ordered,
+    /// Diffuse the rounding error of each sample to its neighbors, row after row.
+    FloydSteinberg,
+}
+
+impl Dither {
+    /// Name of the dithering as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dither::None => "none",
+            Dither::Ordered => "ordered",
+            Dither::FloydSteinberg => "floyd-steinberg",
+        }
+    }
+}
+
+impl std::str::FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Dither::None),
+            "ordered" => Ok(Dither::Ordered),
+            "floyd-steinberg" => Ok(Dither::FloydSteinberg),
+            _ => Err(format!("unknown dithering '{}', expected one of: none, ordered, floyd-steinberg", s)),
+        }
+    }
+}
+
+/// Named presets selecting a beta matrix and a default k.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Coefficients and k from the paper.
+    HeClassic,
+    /// Paper coefficients with a higher k for darker, more saturated renders.
+    HeDark,
+    /// Hematoxylin with a magenta periodic acid-Schiff counterstain.
+    Pas,
+    /// Stronger eosin absorption for dim autofluorescence backgrounds.
+    Fluorescence,
+}
+
+impl Profile {
+    /// All available profiles.
+    pub const ALL: [Profile; 4] = [Profile::HeClassic, Profile::HeDark, Profile::Pas, Profile::Fluorescence];
+
+    /// Name of the profile as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Profile::HeClassic => "he-classic",
+            Profile::HeDark => "he-dark",
+            Profile::Pas => "pas",
+            Profile::Fluorescence => "fluorescence",
+        }
+    }
+
+    /// Short description of the profile.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Profile::HeClassic => "Coefficients and k from Giacomelli et al. 2016",
+            Profile::HeDark => "Paper coefficients with a higher k for darker, more saturated renders",
+            Profile::Pas => "Hematoxylin with a magenta periodic acid-Schiff counterstain",
+            Profile::Fluorescence => "Stronger eosin absorption for dim autofluorescence backgrounds",
+        }
+    }
+
+    /// Rendering parameters for the profile.
+    pub fn params(&self) -> Params {
+        match self {
+            Profile::HeClassic => Params::default(),
+            Profile::HeDark => Params::default().with_k(4.0),
+            Profile::Pas => Params {
+                beta: [DEFAULT_BETA[0], [0.200, 1.000, 0.250]],
+                ..Params::default()
+            },
+            Profile::Fluorescence => Params {
+                beta: [DEFAULT_BETA[0], [0.080, 1.400, 0.760]],
+                ..Params::default()
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Profile::ALL.into_iter().find(|p| p.name() == s).ok_or_else(|| {
+            let names: Vec<&str> = Profile::ALL.iter().map(|p| p.name()).collect();
+            format!("unknown profile '{}', expected one of: {}", s, names.join(", "))
+        })
+    }
+}
+
+/// Color channel of an RGB(A) input image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RgbChannel {
+    Red,
+    Green,
+    Blue,
+}
+
+impl RgbChannel {
+    /// Name of the channel as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RgbChannel::Red => "r",
+            RgbChannel::Green => "g",
+            RgbChannel::Blue => "b",
+        }
+    }
+}
+
+impl std::str::FromStr for RgbChannel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "r" | "red" => Ok(RgbChannel::Red),
+            "g" | "green" => Ok(RgbChannel::Green),
+            "b" | "blue" => Ok(RgbChannel::Blue),
+            _ => Err(format!("unknown channel '{}', expected one of: r, g, b", s)),
+        }
+    }
+}
+
+/// Sample type of headerless raw input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RawSample {
+    U8,
+    #[default]
+    U16,
+    /// 32bit floating point.
+    F32,
+}
+
+impl RawSample {
+    /// Name of the sample type as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RawSample::U8 => "u8",
+            RawSample::U16 => "u16",
+            RawSample::F32 => "f32",
+        }
+    }
+
+    /// Bytes per sample.
+    pub fn bytes(&self) -> usize {
+        match self {
+            RawSample::U8 => 1,
+            RawSample::U16 => 2,
+            RawSample::F32 => 4,
+        }
+    }
+}
+
+impl std::str::FromStr for RawSample {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "u8" => Ok(RawSample::U8),
+            "u16" => Ok(RawSample::U16),
+            "f32" => Ok(RawSample::F32),
+            _ => Err(format!("unknown sample type '{}', expected one of: u8, u16, f32", s)),
+        }
+    }
+}
+
+/// Layout of a headerless raw input: a dense row-major array of `width` x `height` samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawLayout {
+    pub width: usize,
+    pub height: usize,
+    pub sample: RawSample,
+    /// Byte order of multi-byte samples, little endian when false.
+    pub big_endian: bool,
+}
+
+/// Channel of a multichannel TIFF, by index or by a channel name from its OME-XML.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelSelector {
+    /// Zero-based channel index, the page index for TIFFs without OME-XML.
+    Index(usize),
+    /// Channel name from the OME-XML (e.g., DAPI).
+    Name(String),
+}
+
+impl std::str::FromStr for ChannelSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("channel must be an index or a name".to_string());
+        }
+        Ok(s.parse::<usize>().map_or_else(|_| ChannelSelector::Name(s.to_string()), ChannelSelector::Index))
+    }
+}
+
+impl std::fmt::Display for ChannelSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelSelector::Index(index) => write!(f, "{}", index),
+            ChannelSelector::Name(name) => write!(f, "'{}'", name),
+        }
+    }
+}
+
+/// Input value that maps to full intensity when normalizing a decoded image to [0, 1].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum InputRange {
+    /// Container maximum for integer images (255 or 65535), data maximum for floating point images.
+    #[default]
+    Container,
+    /// Effective bit depth of integer data stored in a wider container (e.g., 12bit in 16bit TIFFs),
+    /// narrower containers and floating point data keep their default.
+    Bits(u8),
+    /// Fixed input value.
+    Max(f32),
+    /// Data maximum of each image.
+    Auto,
+}
+
+/// Rectangular region of an image in pixel coordinates, with the origin at the top left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Roi {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Roi {
+    /// Copy the region out of an image, erroring if it does not lie within the image.
+    pub fn crop<T: Clone>(&self, image: &Array2<T>) -> Result<Array2<T>, Error> {
+        let (rows, cols) = image.dim();
+        if self.width == 0 || self.height == 0 || self.x + self.width > cols || self.y + self.height > rows {
+            return Err(Error::InvalidOptions(format!(
+                "region {},{} {}x{} exceeds the {}x{} image",
+                self.x, self.y, self.width, self.height, cols, rows
+            )));
+        }
+        Ok(image.slice(s![self.y..self.y + self.height, self.x..self.x + self.width]).to_owned())
+    }
+
+    /// The blocks of an image downsampled by `factor` with `filter::bin_average` that the region
+    /// covers.
+    pub fn downsampled(&self, factor: usize) -> Roi {
+        let (x, y) = (self.x / factor, self.y / factor);
+        Roi {
+            x,
+            y,
+            width: (self.x + self.width).div_ceil(factor) - x,
+            height: (self.y + self.height).div_ceil(factor) - y,
+        }
+    }
+}
+
+impl std::fmt::Display for Roi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// Options controlling how an input image is read by `load_channel_with`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadOptions {
+    /// Channel to take from RGB(A) images, luma is used when not set.
+    pub rgb_channel: Option<RgbChannel>,
+    /// Input value that maps to 1.0.
+    pub range: InputRange,
+    /// Channel to read from a multichannel TIFF, OME-Zarr, CZI or Imaris image, the first is read when
+    /// not set.
+    pub channel: Option<ChannelSelector>,
+    /// Zero-based page (IFD) to read from a multi-page TIFF, takes precedence over `channel`.
+    pub page: Option<usize>,
+    /// Resolution level to read from a multiscale OME-Zarr or Imaris image, 0 is full resolution.
+    pub level: usize,
+    /// Scene to read from a CZI file, which must be given when the file holds more than one.
+    pub scene: Option<usize>,
+    /// Z plane to read from an Imaris volume, the first is read when not set.
+    pub z: Option<usize>,
+    /// Flat-field image the decoded image is divided by after normalizing it to a mean of 1,
+    /// against vignetting. It must have the size of the image.
+    pub flat_field: Option<PathBuf>,
+    /// Dark-field image subtracted from the decoded image and from the flat-field.
+    pub dark_field: Option<PathBuf>,
+    /// Region the image is cropped to after the flat-field correction, before filtering.
+    pub roi: Option<Roi>,
+    /// Invert the decoded values to `max - v`, with `max` the input value that maps to 1.0, for
+    /// pre-inverted images with a bright background.
+    pub invert: bool,
+    /// Factor the image is downsampled by after cropping, averaging blocks of pixels, 0 and 1 are off.
+    pub downsample: usize,
+    /// Edge length of a median filter applied to the decoded image against hot pixels, must be odd.
+    pub despeckle: Option<usize>,
+    /// Standard deviation in pixels of a Gaussian blur applied to the decoded image after the
+    /// median filter, 0 is off.
+    pub blur_sigma: f32,
+    /// Layout of `.raw` and `.bin` inputs, which have no header to read it from.
+    pub raw: Option<RawLayout>,
+}
+
+/// Details about how an input image was decoded and normalized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelInfo {
+    /// Image width in pixels.
+    pub width: usize,
+    /// Image height in pixels.
+    pub height: usize,
+    /// Pixel format of the decoded image (e.g., L16, Rgb8, L32F).
+    pub pixel_format: String,
+    /// Input value that was mapped to 1.0.
+    pub input_max: f32,
+}
+
+/// Read a greyscale image from disk into an array normalized to [0, 1].
+///
+/// RGB(A) images are converted to luma.
+pub fn load_channel<P: AsRef<Path>>(path: P) -> Result<Array2<f32>, Error> {
+    load_channel_with(path, &LoadOptions::default()).map(|(channel, _)| channel)
+}
+
+/// Read an image from disk into an array normalized to [0, 1] using the given options.
+pub fn load_channel_with<P: AsRef<Path>>(
+    path: P,
+    options: &LoadOptions,
+) -> Result<(Array2<f32>, ChannelInfo), Error> {
+    let path = path.as_ref();
+    let (mut channel, pixel_format, container_max) = decode_raw(path, options)?;
+
+    let input_max = resolve_input_max(options, container_max, || {
+        channel
+            .par_iter()
+            .copied()
+            .filter(|v| v.is_finite())
+            .reduce(|| 0.0, f32::max)
+    })?;
+
+    // Normalize to [0, 1]
+    channel.par_mapv_inplace(|v| normalize_input(v, input_max, options.invert));
+
+    let info = ChannelInfo {
+        width: channel.ncols(),
+        height: channel.nrows(),
+        pixel_format,
+        input_max,
+    };
+    debug!(
+        "{}: decoded {}x{} {}, container maximum {:?}, normalized by {}{}",
+        path.display(),
+        info.width,
+        info.height,
+        info.pixel_format,
+        container_max,
+        info.input_max,
+        if options.invert { ", inverted" } else { "" }
+    );
+    Ok((channel, info))
+}
+
+/// Input value mapped to 1.0 for the range of `options`, `data_max` is only evaluated when the data
+/// maximum is needed.
+///
+/// Inverted integer images without a given range are inverted within the bit depth their data
+/// maximum fits, so that 12bit data in a 16bit container is not inverted into the top of the range.
+/// Err if the bit depth of `InputRange::Bits` is not between 1 and 32.
+pub(crate) fn resolve_input_max(
+    options: &LoadOptions,
+    container_max: Option<f32>,
+    data_max: impl FnOnce() -> f32,
+) -> Result<f32, Error> {
+    if let InputRange::Bits(bits) = options.range {
+        if !(1..=32).contains(&bits) {
+            return Err(Error::InvalidOptions(format!("input bit depth must be between 1 and 32, got {}", bits)));
+        }
+    }
+    let bits_max = |bits: u32| ((1u64 << bits) - 1) as f32;
+    Ok(match (options.range, container_max) {
+        (InputRange::Container, Some(max)) if options.invert => {
+            let bits = (data_max().max(1.0) + 1.0).log2().ceil() as u32;
+            max.min(bits_max(bits.min(32)))
+        }
+        (InputRange::Container, Some(max)) => max,
+        (InputRange::Bits(bits), Some(max)) => max.min(bits_max(bits.into())),
+        (InputRange::Max(max), _) => max,
+        (InputRange::Container | InputRange::Bits(_), None) | (InputRange::Auto, _) => {
+            let max = data_max();
+            if max > 0.0 {
+                max
+            } else {
+                1.0
+            }
+        }
+    })
+}
+
+/// Normalize a raw value by `input_max`.
+///
+/// Negative floating point values are clamped to zero and NaN is kept so that it can be handled
+/// by the scaling NaN policy.
+pub(crate) fn normalize_value(v: f32, input_max: f32) -> f32 {
+    if v < 0.0 {
+        0.0
+    } else {
+        v / input_max
+    }
+}
+
+/// Normalize a raw input value like `normalize_value`, inverting it to `input_max - v` first for
+/// inverted inputs, so that values above the effective bit depth clamp to 0 instead of wrapping.
+pub(crate) fn normalize_input(v: f32, input_max: f32, invert: bool) -> f32 {
+    normalize_value(if invert { input_max - v } else { v }, input_max)
+}
+
+/// Rec. 709 luma of an RGB value.
+pub(crate) fn luma(r: f32, g: f32, b: f32) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// Raw (not normalized) image values, the name of the pixel format, and the container maximum for integer data.
+pub(crate) type RawImage = (Array2<f32>, String, Option<f32>);
+
+/// Decode an image into an array of raw values.
+fn decode_raw(path: &Path, options: &LoadOptions) -> Result<RawImage, Error> {
+    let (mut image, format, container_max) = read_raw(path, options).map_err(|e| Error::decode(path, e))?;
+    flatfield::correct(&mut image, path, options)?;
+    if let Some(roi) = options.roi {
+        debug!("{}: cropping to {}", path.display(), roi);
+        image = roi.crop(&image).map_err(|e| Error::InvalidOptions(format!("{}: {}", path.display(), e)))?;
+    }
+    if options.downsample > 1 {
+        debug!("{}: binning by {}", path.display(), options.downsample);
+        image = filter::bin_average(&image, options.downsample);
+    }
+    if let Some(size) = options.despeckle {
+        if size % 2 == 0 {
+            return Err(Error::InvalidOptions(format!("median filter size must be odd, got {}", size)));
+        }
+        debug!("{}: {}x{} median filter", path.display(), size, size);
+        filter::median_filter(&mut image, size);
+    }
+    if options.blur_sigma > 0.0 {
+        debug!("{}: Gaussian blur with sigma {}", path.display(), options.blur_sigma);
+        filter::gaussian_blur(&mut image, options.blur_sigma);
+    }
+    Ok((image, format, container_max))
+}
+
+/// Read the raw values of an image with the reader of its format, `-` reads it from stdin.
+pub(crate) fn read_raw(path: &Path, options: &LoadOptions) -> Result<RawImage, Box<dyn std::error::Error>> {
+    if path == Path::new(STDIO_PATH) {
+        return read_stdin(path, options);
+    }
+    // OME-Zarr stores are directories read chunk by chunk, and so are those at URLs
+    if zarr_reader::is_zarr(path) {
+        return zarr_reader::read_plane(path, options);
+    }
+    if path.to_str().is_some_and(is_url) {
+        #[cfg(feature = "remote")]
+        return Err(Error::unsupported(path, "only Zarr stores are read from URLs, other inputs are downloaded first").into());
+        #[cfg(not(feature = "remote"))]
+        return Err(Error::unsupported(path, "https:// and s3:// inputs require a build with the remote feature").into());
+    }
+    if raw_reader::is_raw(path) {
+        return raw_reader::read(path, options.raw);
+    }
+    if is_czi(path) {
+        #[cfg(feature = "czi")]
+        return czi_reader::read(path, options);
+        #[cfg(not(feature = "czi"))]
+        return Err(Error::unsupported(path, "CZI input requires a build with the czi feature").into());
+    }
+    if is_ims(path) {
+        #[cfg(feature = "ims")]
+        return ims_reader::read(path, options);
+        #[cfg(not(feature = "ims"))]
+        return Err(Error::unsupported(path, "Imaris input requires a build with the ims feature").into());
+    }
+
+    // Pages and channels of multi-page TIFFs are read with the tiff decoder
+    if options.page.is_some() || options.channel.is_some() {
+        let mut reader = tiff_reader::BandReader::open(path, options)?;
+        let channel = reader.read_rows(0, reader.height, reader.width)?;
+        return Ok((channel, reader.pixel_format.clone(), reader.container_max));
+    }
+
+    // Initialize image reader
+    let mut reader = ImageReader::open(path).map_err(|e| Error::open(path, e))?;
+
+    // Remove file size and memory limits to enable processing of large images
+    reader.no_limits();
+
+    // Uncompressed grayscale TIFFs are read from a memory map, floating point and 32bit grayscale
+    // TIFFs are not supported by the image crate
+    if reader.format() == Some(ImageFormat::Tiff) {
+        if let Some(mapped) = tiff_reader::read_mapped(path)? {
+            return Ok(mapped);
+        }
+        if let Some(decoded) = decode_wide_tiff(BufReader::new(File::open(path)?), path)? {
+            return Ok(decoded);
+        }
+    }
+    image_to_raw(reader.decode()?, path, options)
+}
+
+/// Width and height of an input from its header, of the page or channel selected by `options`,
+/// before cropping and downsampling. None for stdin, OME-Zarr stores and headers that cannot be
+/// read, whose errors are reported when the input is decoded.
+pub fn input_dimensions<P: AsRef<Path>>(path: P, options: &LoadOptions) -> Option<(usize, usize)> {
+    let path = path.as_ref();
+    if path == Path::new(STDIO_PATH) || zarr_reader::is_zarr(path) {
+        return None;
+    }
+    if raw_reader::is_raw(path) {
+        return options.raw.map(|layout| (layout.width, layout.height));
+    }
+    if is_czi(path) {
+        #[cfg(feature = "czi")]
+        return czi_reader::dimensions(path, options);
+        #[cfg(not(feature = "czi"))]
+        return None;
+    }
+    if is_ims(path) {
+        #[cfg(feature = "ims")]
+        return ims_reader::dimensions(path, options);
+        #[cfg(not(feature = "ims"))]
+        return None;
+    }
+    let (width, height) = match ImageFormat::from_path(path) {
+        Ok(ImageFormat::Tiff) => {
+            let reader = tiff_reader::BandReader::open(path, options).ok()?;
+            (reader.width, reader.height)
+        }
+        _ => image::image_dimensions(path).ok()?,
+    };
+    Some((width as usize, height as usize))
+}
+
+/// Whether `path` is an http://, https:// or s3:// URL rather than a local path.
+pub fn is_url(path: &str) -> bool {
+    ["http://", "https://", "s3://"].iter().any(|scheme| path.starts_with(scheme))
+}
+
+/// Whether `path` names a Zeiss CZI file by its extension.
+fn is_czi(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("czi"))
+}
+
+/// Whether `path` names an Imaris file by its extension.
+pub(crate) fn is_ims(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("ims"))
+}
+
+/// Read an image piped to stdin fully into memory and decode it by the format its content starts
+/// with, as there is no extension to go by.
+fn read_stdin(path: &Path, options: &LoadOptions) -> Result<RawImage, Box<dyn std::error::Error>> {
+    if options.page.is_some() || options.channel.is_some() {
+        return Err(Error::unsupported(path, "pages and channels cannot be selected from stdin").into());
+    }
+    let mut data = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut data).map_err(|e| Error::open(path, e))?;
+    debug!("{}: read {} bytes", path.display(), data.len());
+    let mut reader = ImageReader::new(Cursor::new(&data)).with_guessed_format()?;
+    reader.no_limits();
+    match reader.format() {
+        Some(ImageFormat::Tiff) => {
+            if let Some(decoded) = decode_wide_tiff(Cursor::new(&data), path)? {
+                return Ok(decoded);
+            }
+        }
+        Some(_) => {}
+        None => return Err(Error::unsupported(path, "unknown image format").into()),
+    }
+    image_to_raw(reader.decode()?, path, options)
+}
+
+/// Raw values of a decoded grayscale or RGB(A) image.
+fn image_to_raw(image: DynamicImage, path: &Path, options: &LoadOptions) -> Result<RawImage, Box<dyn std::error::Error>> {
+    let pixel_format = format!("{:?}", image.color());
+
+    // Read image into ndarray
+    let (channel, container_max) = match image {
+        DynamicImage::ImageLuma16(image) => (
+            Array2::<f32>::from_shape_vec(
+                (image.height() as usize, image.width() as usize),
+                image.pixels().map(|p| p[0] as f32).collect(),
+            )?,
+            Some(65535.0),
+        ),
+        DynamicImage::ImageLuma8(image) => (
+            Array2::<f32>::from_shape_vec(
+                (image.height() as usize, image.width() as usize),
+                image.pixels().map(|p| p[0] as f32).collect(),
+            )?,
+            Some(255.0),
+        ),
+        DynamicImage::ImageRgb16(image) => (color_to_array(&image, options.rgb_channel)?, Some(65535.0)),
+        DynamicImage::ImageRgba16(image) => (color_to_array(&image, options.rgb_channel)?, Some(65535.0)),
+        DynamicImage::ImageRgb8(image) => (color_to_array(&image, options.rgb_channel)?, Some(255.0)),
+        DynamicImage::ImageRgba8(image) => (color_to_array(&image, options.rgb_channel)?, Some(255.0)),
+        DynamicImage::ImageRgb32F(image) => (color_to_array(&image, options.rgb_channel)?, None),
+        DynamicImage::ImageRgba32F(image) => (color_to_array(&image, options.rgb_channel)?, None),
+        image => {
+            return Err(Error::unsupported(
+                path,
+                format!("unsupported pixel format {:?}, expected grayscale or RGB(A)", image.color()),
+            )
+            .into())
+        }
+    };
+    Ok((channel, pixel_format, container_max))
+}
+
+/// Read 32bit or 64bit grayscale TIFFs directly with the tiff decoder.
+///
+/// Returns `None` for any other layout so the image crate can decode it.
+fn decode_wide_tiff<R: Read + Seek>(reader: R, path: &Path) -> Result<Option<RawImage>, Box<dyn std::error::Error>> {
+    let mut decoder = tiff::decoder::Decoder::new(reader)?.with_limits(tiff::decoder::Limits::unlimited());
+    if !matches!(decoder.colortype()?, tiff::ColorType::Gray(32) | tiff::ColorType::Gray(64)) {
+        return Ok(None);
+    }
+    let (width, height) = decoder.dimensions()?;
+    let shape = (height as usize, width as usize);
+    let (channel, pixel_format, container_max) = match decoder.read_image()? {
+        tiff::decoder::DecodingResult::F32(data) => (Array2::from_shape_vec(shape, data)?, "L32F", None),
+        tiff::decoder::DecodingResult::F64(data) => (
+            Array2::from_shape_vec(shape, data.into_iter().map(|v| v as f32).collect())?,
+            "L64F",
+            None,
+        ),
+        tiff::decoder::DecodingResult::U32(data) => (
+            Array2::from_shape_vec(shape, data.into_iter().map(|v| v as f32).collect())?,
+            "L32",
+            Some(u32::MAX as f32),
+        ),
+        _ => return Err(Error::unsupported(path, "unsupported TIFF sample format").into()),
+    };
+    Ok(Some((channel, pixel_format.to_string(), container_max)))
+}
+
+/// Reduce an RGB(A) image to one channel: either the selected color or Rec. 709 luma.
+fn color_to_array<P>(
+    image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    rgb_channel: Option<RgbChannel>,
+) -> Result<Array2<f32>, ndarray::ShapeError>
+where
+    P: Pixel,
+    P::Subpixel: Into<f32>,
+{
+    Array2::<f32>::from_shape_vec(
+        (image.height() as usize, image.width() as usize),
+        image
+            .pixels()
+            .map(|p| {
+                let c = p.channels();
+                match rgb_channel {
+                    Some(channel) => c[channel as usize].into(),
+                    None => luma(c[0].into(), c[1].into(), c[2].into()),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// How NaN values in an input channel are handled during scaling.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NanPolicy {
+    /// Replace NaN with zero before computing percentiles.
+    #[default]
+    Zero,
+    /// Fail if the channel contains any NaN.
+    Error,
+    /// Exclude NaN from the percentile computation, NaN pixels are set to zero in the output.
+    Ignore,
+}
+
+impl NanPolicy {
+    /// Name of the policy as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            NanPolicy::Zero => "zero",
+            NanPolicy::Error => "error",
+            NanPolicy::Ignore => "ignore",
+        }
+    }
+}
+
+impl std::str::FromStr for NanPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(NanPolicy::Zero),
+            "error" => Ok(NanPolicy::Error),
+            "ignore" => Ok(NanPolicy::Ignore),
+            _ => Err(format!("unknown NaN policy '{}', expected one of: zero, error, ignore", s)),
+        }
+    }
+}
+
+/// How the value at a percentile is taken from the sorted values, with the names of the methods
+/// of `numpy.percentile`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PercentileMethod {
+    /// Interpolate linearly between the two values around the fractional rank
+    /// `percentile / 100 * (n - 1)`, the default of numpy.
+    #[default]
+    Linear,
+    /// The value at the fractional rank rounded down.
+    Lower,
+    /// The value at the fractional rank rounded to the nearest rank, ties to the even one.
+    Nearest,
+    /// The value at the rank `percentile / 100 * n` rounded down, as selected before the
+    /// interpolation, to reproduce earlier renders.
+    Legacy,
+}
+
+impl PercentileMethod {
+    /// Name of the method as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PercentileMethod::Linear => "linear",
+            PercentileMethod::Lower => "lower",
+            PercentileMethod::Nearest => "nearest",
+            PercentileMethod::Legacy => "legacy",
+        }
+    }
+
+    /// Ranks of the two sorted values of `count` values that the value at `percentile` is
+    /// interpolated between, and the weight of the upper one. Percentiles of 100 and above select
+    /// the maximum.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is 0.
+    pub fn ranks(&self, percentile: f32, count: u64) -> (u64, u64, f64) {
+        assert!(count > 0, "no values to select a percentile of");
+        let last = count - 1;
+        if percentile >= 100.0 {
+            return (last, last, 0.0);
+        }
+        // The shortest decimal of the percentile, e.g. 99.999 rather than the f64 of the nearest
+        // f32, so that the ranks are those numpy computes for the percentile as written
+        let rank = || {
+            let percentile = percentile.to_string().parse::<f64>().unwrap_or(f64::from(percentile));
+            (percentile.max(0.0) / 100.0 * last as f64).min(last as f64)
+        };
+        match self {
+            PercentileMethod::Linear => {
+                let rank = rank();
+                let lower = rank.floor() as u64;
+                (lower, (lower + 1).min(last), rank - rank.floor())
+            }
+            PercentileMethod::Lower => {
+                let lower = rank().floor() as u64;
+                (lower, lower, 0.0)
+            }
+            PercentileMethod::Nearest => {
+                let nearest = rank().round_ties_even() as u64;
+                (nearest, nearest, 0.0)
+            }
+            PercentileMethod::Legacy => {
+                let rank = (((percentile / 100.0) * (count as f32)) as u64).min(last);
+                (rank, rank, 0.0)
+            }
+        }
+    }
+
+    /// Value at `percentile` of `count` sorted values, of which `value_at` returns the one at a
+    /// rank.
+    pub(crate) fn select(&self, percentile: f32, count: u64, value_at: impl Fn(u64) -> f32) -> f32 {
+        let (lower, upper, weight) = self.ranks(percentile, count);
+        if upper == lower {
+            return value_at(lower);
+        }
+        PercentileMethod::interpolate(value_at(lower), value_at(upper), weight)
+    }
+
+    /// Value at the fractional rank between the sorted values `lower` and `upper` of `ranks`,
+    /// interpolated as numpy does.
+    pub fn interpolate(lower: f32, upper: f32, weight: f64) -> f32 {
+        let (lower, upper) = (f64::from(lower), f64::from(upper));
+        let difference = upper - lower;
+        // From the nearer value, so that weights of 0 and 1 give the values exactly
+        let value = if weight < 0.5 { lower + difference * weight } else { upper - difference * (1.0 - weight) };
+        value as f32
+    }
+}
+
+impl std::str::FromStr for PercentileMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(PercentileMethod::Linear),
+            "lower" => Ok(PercentileMethod::Lower),
+            "nearest" => Ok(PercentileMethod::Nearest),
+            "legacy" => Ok(PercentileMethod::Legacy),
+            _ => Err(format!("unknown percentile method '{}', expected one of: linear, lower, nearest, legacy", s)),
+        }
+    }
+}
+
+/// Histogram equalization of a channel in `scale_with`, before the percentile scaling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Equalization {
+    /// Remap the intensities by their cumulative distribution over the image, see
+    /// `equalize::equalize`.
+    Global,
+    /// Remap the intensities by the clipped cumulative distributions of the tiles around each pixel,
+    /// see `equalize::clahe`.
+    Clahe {
+        /// Edge length of the tiles in pixels.
+        tile_size: usize,
+        /// Limit of the count of a bin as a multiple of the mean count, at least 1.
+        clip_limit: f32,
+    },
+}
+
+impl Equalization {
+    /// Name of the equalization as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Equalization::Global => "global",
+            Equalization::Clahe { .. } => "clahe",
+        }
+    }
+
+    /// Equalize an image in place, with the distribution of global equalization counted over the
+    /// pixels selected by `mask` if given.
+    pub fn apply(&self, image: &mut Array2<f32>, mask: Option<&Array2<bool>>) {
+        match *self {
+            Equalization::Global => equalize::equalize(image, mask),
+            Equalization::Clahe { tile_size, clip_limit } => equalize::clahe(image, tile_size, clip_limit),
+        }
+    }
+}
+
+impl std::str::FromStr for Equalization {
+    type Err = String;
+
+    /// Parse `global` or `clahe`, the latter with the default tile size and clip limit.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(Equalization::Global),
+            "clahe" => Ok(Equalization::Clahe {
+                tile_size: equalize::DEFAULT_CLAHE_TILE_SIZE,
+                clip_limit: equalize::DEFAULT_CLAHE_CLIP_LIMIT,
+            }),
+            _ => Err(format!("unknown equalization '{}', expected one of: global, clahe", s)),
+        }
+    }
+}
+
+/// Automatic selection of the pixels the percentiles of `scale_with` are taken over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutoContrast {
+    /// The tissue pixels above the Otsu threshold between background and tissue of the values.
+    Otsu,
+}
+
+impl AutoContrast {
+    /// Name of the method as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AutoContrast::Otsu => "otsu",
+        }
+    }
+}
+
+impl std::str::FromStr for AutoContrast {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "otsu" => Ok(AutoContrast::Otsu),
+            _ => Err(format!("unknown automatic contrast '{}', expected one of: otsu", s)),
+        }
+    }
+}
+
+/// Floor percentile of the tissue pixels of `ScaleOptions::with_auto_contrast`.
+pub const AUTO_CONTRAST_FLOOR_PERCENTILE: f32 = 1.0;
+
+/// Saturation percentile of the tissue pixels of `ScaleOptions::with_auto_contrast`.
+pub const AUTO_CONTRAST_PERCENTILE: f32 = 99.9;
+
+/// Options controlling histogram scaling in `scale_with`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScaleOptions {
+    /// Saturation percentile in (0, 100], a value of 100 normalizes to the true maximum.
+    pub percentile: f32,
+    /// Background floor percentile in [0, 100) subtracted before scaling, no floor when not set.
+    pub floor_percentile: Option<f32>,
+    /// How NaN values are handled.
+    pub nan_policy: NanPolicy,
+    /// How the values at the percentiles are taken from the sorted values.
+    pub percentile_method: PercentileMethod,
+    /// Input maximum the values were normalized by, e.g. `ChannelInfo::input_max`, for images
+    /// whose values may be integers divided by it, as those of 8 and 16bit images. The percentiles
+    /// are then taken from a histogram of the integers counted in one pass instead of a sorted copy
+    /// of the values, which they fall back to if a value is not such an integer of at most 16bit,
+    /// e.g. after filtering.
+    pub integer_input_max: Option<f32>,
+    /// Histogram equalization applied before the percentiles or the window, which are then those
+    /// of the equalized intensities from 0 to 1.
+    pub equalize: Option<Equalization>,
+    /// Take the percentiles over the tissue pixels selected by this method only, among those of
+    /// the mask, so that the background does not weigh in.
+    pub auto_contrast: Option<AutoContrast>,
+    /// Fixed window of normalized intensities used instead of the percentiles, so that the
+    /// scaling does not depend on the image content.
+    pub window: Option<Thresholds>,
+    /// Gamma applied as `v^(1 / gamma)` after scaling, values above 1 brighten dim intensities.
+    pub gamma: f32,
+}
+
+impl Default for ScaleOptions {
+    fn default() -> Self {
+        ScaleOptions {
+            percentile: 99.999,
+            floor_percentile: None,
+            nan_policy: NanPolicy::default(),
+            percentile_method: PercentileMethod::default(),
+            integer_input_max: None,
+            equalize: None,
+            auto_contrast: None,
+            window: None,
+            gamma: 1.0,
+        }
+    }
+}
+
+impl ScaleOptions {
+    /// Set the input maximum of images of integer values.
+    pub fn with_integer_input_max(self, input_max: f32) -> Self {
+        ScaleOptions {
+            integer_input_max: Some(input_max),
+            ..self
+        }
+    }
+
+    /// Select the window from the tissue pixels of `method`, from their 1st to their 99.9th
+    /// percentile, the percentiles of the options are replaced.
+    pub fn with_auto_contrast(self, method: AutoContrast) -> Self {
+        ScaleOptions {
+            auto_contrast: Some(method),
+            percentile: AUTO_CONTRAST_PERCENTILE,
+            floor_percentile: Some(AUTO_CONTRAST_FLOOR_PERCENTILE),
+            ..self
+        }
+    }
+}
+
+/// Intensities that were mapped to 0 and 1 by `scale_with`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// Intensity mapped to 0.
+    pub floor: f32,
+    /// Intensity mapped to 1.
+    pub ceiling: f32,
+}
+
+impl Thresholds {
+    /// Scale a value into [0, 1], NaN maps to 0. Every value maps to 0 when the ceiling is not
+    /// above the floor, as for a blank channel.
+    pub fn apply(&self, v: f32) -> f32 {
+        if v.is_nan() || self.ceiling <= self.floor {
+            0.0
+        } else {
+            ((v - self.floor) / (self.ceiling - self.floor)).clamp(0.0, 1.0)
+        }
+    }
+
+    /// Scale a value into [0, 1] and apply the gamma curve `v^(1 / gamma)`, NaN maps to 0.
+    pub fn apply_gamma(&self, v: f32, gamma: f32) -> f32 {
+        let v = self.apply(v);
+        if gamma == 1.0 {
+            v
+        } else {
+            v.powf(1.0 / gamma)
+        }
+    }
+}
+
+/// Copy the finite values of an image into a vector for rank selection.
+fn finite_values(image: &Array2<f32>) -> Vec<f32> {
+    let non_finite = image.par_iter().filter(|v| !v.is_finite()).count();
+    match image.as_slice_memory_order() {
+        Some(values) if non_finite == 0 => values.to_vec(),
+        _ => image.par_iter().copied().filter(|v| v.is_finite()).collect(),
+    }
+}
+
+/// Check that `mask`, if given, has the size of the image.
+fn check_mask(image: &Array2<f32>, mask: Option<&Array2<bool>>) -> Result<(), Box<dyn std::error::Error>> {
+    match mask {
+        Some(mask) if mask.dim() != image.dim() => Err(format!(
+            "mask is {}x{} but the image is {}x{}",
+            mask.ncols(),
+            mask.nrows(),
+            image.ncols(),
+            image.nrows()
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Finite values of the pixels of `image` selected by `mask`, or of all pixels without a mask.
+fn masked_values(image: &Array2<f32>, mask: Option<&Array2<bool>>) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+    let Some(mask) = mask else {
+        return Ok(finite_values(image));
+    };
+    check_mask(image, Some(mask))?;
+    let values = Zip::from(image).and(mask).par_fold(
+        Vec::new,
+        |mut values, &v, &m| {
+            if m && v.is_finite() {
+                values.push(v);
+            }
+            values
+        },
+        |mut a, mut b| {
+            a.append(&mut b);
+            a
+        },
+    );
+    if values.is_empty() {
+        return Err("the mask selects no finite values".into());
+    }
+    Ok(values)
+}
+
+/// Value at `percentile` of `values` by `method`, a value of 100 is the maximum.
+///
+/// Uses selection instead of a full sort, `values` is reordered in the process.
+fn select_percentile(values: &mut [f32], percentile: f32, method: PercentileMethod) -> f32 {
+    let (lower, upper, weight) = method.ranks(percentile, values.len() as u64);
+    let (_, &mut lower_value, above) = values.select_nth_unstable_by(lower as usize, f32::total_cmp);
+    if upper == lower {
+        return lower_value;
+    }
+    // The next sorted value is the smallest of those above the lower one
+    let upper_value = above.iter().copied().min_by(f32::total_cmp).expect("the upper rank is above the lower one");
+    PercentileMethod::interpolate(lower_value, upper_value, weight)
+}
+
+/// Number of integer levels that `scale_with` counts in a histogram, those of 16bit samples.
+const INTEGER_LEVELS: usize = 65536;
+
+/// Histogram of values that are integers divided by an input maximum.
+struct LevelCounts {
+    counts: Vec<u64>,
+    total: u64,
+    input_max: f32,
+}
+
+impl LevelCounts {
+    /// Count the integer levels of the finite values of `image` selected by `mask`, or None if a
+    /// value is not `normalize_value(level, input_max)` of an integer level, or none is selected.
+    fn count(image: &Array2<f32>, mask: Option<&Array2<bool>>, input_max: f32) -> Option<Self> {
+        if mask.is_some_and(|mask| mask.dim() != image.dim()) {
+            return None;
+        }
+        // The value of each level, to check the levels without a division per value
+        let values: Vec<f32> = (0..INTEGER_LEVELS).map(|level| normalize_value(level as f32, input_max)).collect();
+        let add = |counts: &mut [u64], v: f32| {
+            if v.is_finite() {
+                // The nearest level, saturated at the ends, is the one of the value only if it
+                // normalizes to the same value, bit for bit
+                let level = (v * input_max + 0.5) as usize;
+                match values.get(level) {
+                    Some(&value) if value == v => counts[level] += 1,
+                    _ => return None,
+                }
+            }
+            Some(())
+        };
+        // A histogram for a band of rows at a time, a few bands per thread
+        let count_band = |band: ArrayView2<f32>, mask: Option<ArrayView2<bool>>| {
+            let mut counts = vec![0u64; INTEGER_LEVELS];
+            match mask {
+                Some(mask) => band.iter().zip(mask).filter(|(_, &m)| m).try_for_each(|(&v, _)| add(&mut counts, v))?,
+                None => band.iter().try_for_each(|&v| add(&mut counts, v))?,
+            }
+            Some(counts)
+        };
+        let merge = |mut counts: Vec<u64>, other: Vec<u64>| {
+            counts.iter_mut().zip(&other).for_each(|(count, other)| *count += other);
+            Some(counts)
+        };
+        let rows = image.nrows().div_ceil(4 * rayon::current_num_threads()).max(1);
+        let bands = image.axis_chunks_iter(Axis(0), rows).into_par_iter();
+        let counts = match mask {
+            Some(mask) => bands
+                .zip(mask.axis_chunks_iter(Axis(0), rows))
+                .map(|(band, mask)| count_band(band, Some(mask)))
+                .try_reduce(|| vec![0u64; INTEGER_LEVELS], merge)?,
+            None => bands.map(|band| count_band(band, None)).try_reduce(|| vec![0u64; INTEGER_LEVELS], merge)?,
+        };
+        let total = counts.iter().sum();
+        (total > 0).then_some(LevelCounts { counts, total, input_max })
+    }
+
+    /// Value at `percentile` of the counted values by `method`, as `select_percentile` selects it
+    /// from the values.
+    fn percentile(&self, percentile: f32, method: PercentileMethod) -> f32 {
+        let value = |rank: u64| {
+            let mut cumulative = 0;
+            let level = self
+                .counts
+                .iter()
+                .position(|&count| {
+                    cumulative += count;
+                    cumulative > rank
+                })
+                .expect("ranks are below the total count");
+            normalize_value(level as f32, self.input_max)
+        };
+        method.select(percentile, self.total, value)
+    }
+
+    /// Keep only the counts of the tissue levels above the Otsu threshold of the counted values,
+    /// as `keep_tissue` keeps the values.
+    fn keep_tissue(&mut self) -> TissueSplit {
+        let input_max = self.input_max;
+        let level_values = || self.counts.par_iter().enumerate().map(|(level, &count)| (normalize_value(level as f32, input_max), count));
+        let threshold = mask::otsu_threshold_of(level_values);
+        let tissue: u64 = level_values().filter(|&(v, _)| v > threshold).map(|(_, count)| count).sum();
+        if tissue > 0 {
+            self.counts.iter_mut().enumerate().for_each(|(level, count)| {
+                if normalize_value(level as f32, input_max) <= threshold {
+                    *count = 0;
+                }
+            });
+        }
+        let split = TissueSplit {
+            threshold,
+            fraction: tissue as f32 / self.total as f32,
+        };
+        if tissue > 0 {
+            self.total = tissue;
+        }
+        split
+    }
+}
+
+/// Number of bins of the histogram of `KeyCounts`, of the high 16 bits of the sort keys.
+const KEY_BINS: usize = 1 << 16;
+
+/// Key of a finite value whose unsigned order is the order of `f32::total_cmp`.
+fn sort_key(v: f32) -> u32 {
+    let bits = v.to_bits();
+    if bits >> 31 == 1 {
+        !bits
+    } else {
+        bits | 1 << 31
+    }
+}
+
+/// Value of a key of `sort_key`.
+fn from_sort_key(key: u32) -> f32 {
+    f32::from_bits(if key >> 31 == 1 { key & !(1 << 31) } else { !key })
+}
+
+/// Histogram of the high 16 bits of the sort keys of the finite values of an image, in which the
+/// value at a rank is found with a second pass counting the low bits of the values of its bin, so
+/// that percentiles are selected from the image without copying its values.
+struct KeyCounts<'a> {
+    image: &'a Array2<f32>,
+    mask: Option<&'a Array2<bool>>,
+    counts: Vec<u64>,
+    total: u64,
+    /// Histograms of the low 16 bits of the bins counted so far.
+    bins: RefCell<Vec<(usize, Vec<u64>)>>,
+}
+
+impl<'a> KeyCounts<'a> {
+    /// Count the finite values of `image` selected by `mask`, or None if none is selected.
+    fn count(image: &'a Array2<f32>, mask: Option<&'a Array2<bool>>) -> Option<Self> {
+        let counts = Self::histogram(image, mask, |key| Some((key >> 16) as usize));
+        let total = counts.iter().sum();
+        (total > 0).then(|| KeyCounts {
+            image,
+            mask,
+            counts,
+            total,
+            bins: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// Histogram of the bins that `bin` puts the sort keys of the selected finite values into, a
+    /// band of rows at a time, a few bands per thread.
+    fn histogram(image: &Array2<f32>, mask: Option<&Array2<bool>>, bin: impl Fn(u32) -> Option<usize> + Sync) -> Vec<u64> {
+        let add = |counts: &mut Vec<u64>, v: f32| {
+            if let Some(bin) = Some(v).filter(|v| v.is_finite()).and_then(|v| bin(sort_key(v))) {
+                counts[bin] += 1;
+            }
+        };
+        let merge = |mut counts: Vec<u64>, other: Vec<u64>| {
+            counts.iter_mut().zip(&other).for_each(|(count, other)| *count += other);
+            counts
+        };
+        let rows = image.nrows().div_ceil(4 * rayon::current_num_threads()).max(1);
+        let bands = image.axis_chunks_iter(Axis(0), rows).into_par_iter();
+        match mask {
+            Some(mask) => bands
+                .zip(mask.axis_chunks_iter(Axis(0), rows))
+                .map(|(band, mask)| {
+                    let mut counts = vec![0u64; KEY_BINS];
+                    band.iter().zip(mask).filter(|(_, &m)| m).for_each(|(&v, _)| add(&mut counts, v));
+                    counts
+                })
+                .reduce(|| vec![0u64; KEY_BINS], merge),
+            None => bands
+                .map(|band| {
+                    let mut counts = vec![0u64; KEY_BINS];
+                    band.iter().for_each(|&v| add(&mut counts, v));
+                    counts
+                })
+                .reduce(|| vec![0u64; KEY_BINS], merge),
+        }
+    }
+
+    /// Bin of `counts` that holds the value at `rank`, and the rank of the value within the bin.
+    fn find(counts: &[u64], rank: u64) -> (usize, u64) {
+        let mut below = 0;
+        for (bin, &count) in counts.iter().enumerate() {
+            if below + count > rank {
+                return (bin, rank - below);
+            }
+            below += count;
+        }
+        panic!("ranks are below the total count")
+    }
+
+    /// Value at `rank` of the sorted values.
+    fn value(&self, rank: u64) -> f32 {
+        let (high, rank) = Self::find(&self.counts, rank);
+        let mut bins = self.bins.borrow_mut();
+        if !bins.iter().any(|(bin, _)| *bin == high) {
+            let low = Self::histogram(self.image, self.mask, |key| ((key >> 16) as usize == high).then_some((key & 0xffff) as usize));
+            bins.push((high, low));
+        }
+        let low = &bins.iter().find(|(bin, _)| *bin == high).expect("counted above").1;
+        let (low, _) = Self::find(low, rank);
+        from_sort_key((high as u32) << 16 | low as u32)
+    }
+
+    /// Value at `percentile` of the counted values by `method`, as `select_percentile` selects it
+    /// from the values.
+    fn percentile(&self, percentile: f32, method: PercentileMethod) -> f32 {
+        method.select(percentile, self.total, |rank| self.value(rank))
+    }
+}
+
+/// Otsu threshold between background and tissue values and the fraction of the values above it.
+#[derive(Debug, Clone, Copy)]
+struct TissueSplit {
+    threshold: f32,
+    fraction: f32,
+}
+
+/// Keep only the tissue values above the Otsu threshold of `values`, all of them if none is above
+/// it, e.g. of an image of one value.
+fn keep_tissue(values: &mut Vec<f32>) -> TissueSplit {
+    let threshold = mask::otsu_threshold_of(|| values.par_iter().map(|&v| (v, 1)));
+    let count = values.len();
+    let tissue = values.par_iter().filter(|&&v| v > threshold).count();
+    if tissue > 0 {
+        values.retain(|&v| v > threshold);
+    }
+    TissueSplit {
+        threshold,
+        fraction: tissue as f32 / count.max(1) as f32,
+    }
+}
+
+/// Apply in place histogram scaling so that pixels above `percentile` saturate at max intensity.
+///
+/// `percentile` is in (0, 100], a value of 100 normalizes to the true maximum.
+pub fn scale(image: &mut Array2<f32>, percentile: f32) -> Result<Thresholds, Box<dyn std::error::Error>> {
+    scale_with(
+        image,
+        &ScaleOptions {
+            percentile,
+            ..ScaleOptions::default()
+        },
+    )
+}
+
+/// Apply in place window/level scaling: the value at the floor percentile maps to 0 and the value
+/// at the saturation percentile maps to 1, or the bounds of the fixed window if one is set. Values
+/// outside the window are clamped, the gamma of the options is applied in the same pass. With an
+/// equalization, the image is equalized first and the percentiles are those of the result. With
+/// an automatic contrast, the percentiles are those of the tissue pixels only.
+///
+/// Infinite values are excluded from the percentile computation and clamped to the window. With
+/// an integer input maximum, the percentiles of images of integer levels are counted in a
+/// histogram of the levels, and those of other images are selected in passes over a histogram of
+/// their bits, both without copying the values. The scaled image never contains NaN, and a blank
+/// image scales to 0. Returns an error if the floor intensity of a floor percentile is not below
+/// the saturation intensity, or if the NaN policy rejects the image.
+pub fn scale_with(image: &mut Array2<f32>, options: &ScaleOptions) -> Result<Thresholds, Box<dyn std::error::Error>> {
+    scale_with_mask(image, options, None)
+}
+
+/// Apply `scale_with` with the percentiles computed only over the pixels selected by `mask`,
+/// e.g. tissue without the surrounding glass, while the whole image is scaled.
+///
+/// Returns an error if the mask differs in size from the image or selects no finite values.
+pub fn scale_with_mask(
+    image: &mut Array2<f32>,
+    options: &ScaleOptions,
+    mask: Option<&Array2<bool>>,
+) -> Result<Thresholds, Box<dyn std::error::Error>> {
+    let nan_count = image.par_iter().filter(|v| v.is_nan()).count();
+    if nan_count > 0 {
+        match options.nan_policy {
+            NanPolicy::Error => return Err(format!("image contains {} NaN values", nan_count).into()),
+            NanPolicy::Zero => image.par_mapv_inplace(|v| if v.is_nan() { 0.0 } else { v }),
+            NanPolicy::Ignore => {}
+        }
+    }
+
+    if let Some(equalization) = options.equalize {
+        check_mask(image, mask)?;
+        equalization.apply(image, mask);
+        debug!("equalized by {}", equalization.name());
+    }
+
+    // Equalized intensities are no longer integer levels
+    let integer_input_max = options.integer_input_max.filter(|_| options.equalize.is_none());
+    let thresholds = match options.window {
+        Some(window) => window,
+        None => match integer_input_max.and_then(|input_max| LevelCounts::count(image, mask, input_max)) {
+            Some(mut levels) => {
+                debug!("percentiles of {} values counted in {} integer levels", levels.total, INTEGER_LEVELS);
+                if options.auto_contrast.is_some() {
+                    log_tissue(levels.keep_tissue());
+                }
+                thresholds_at(options, |percentile| levels.percentile(percentile, options.percentile_method))?
+            }
+            // The tissue values of an automatic contrast are copied apart
+            None if options.auto_contrast.is_some() => {
+                let mut values = masked_values(image, mask)?;
+                log_tissue(keep_tissue(&mut values));
+                compute_thresholds(&mut values, options)?
+            }
+            None => {
+                check_mask(image, mask)?;
+                let Some(keys) = KeyCounts::count(image, mask) else {
+                    let selected = if mask.is_some() { "the mask selects" } else { "image contains" };
+                    return Err(format!("{} no finite values", selected).into());
+                };
+                thresholds_at(options, |percentile| keys.percentile(percentile, options.percentile_method))?
+            }
+        },
+    };
+    image.par_mapv_inplace(|v| thresholds.apply_gamma(v, options.gamma));
+    Ok(thresholds)
+}
+
+/// Log the tissue values the percentiles of an automatic contrast are taken over.
+fn log_tissue(split: TissueSplit) {
+    debug!(
+        "percentiles of the {:.2}% of the values above the Otsu threshold {}",
+        split.fraction * 100.0,
+        split.threshold
+    );
+}
+
+/// Fraction of the pixels of a scaled image that saturate at 1.
+pub fn saturated_fraction(image: &Array2<f32>) -> f32 {
+    let saturated = image.par_iter().filter(|&&v| v >= 1.0).count();
+    saturated as f32 / image.len().max(1) as f32
+}
+
+/// Transmittance `auto_k` maps the median tissue pixel to in the green channel by default.
+pub const DEFAULT_TARGET_TRANSMITTANCE: f32 = 0.55;
+
+/// Estimate the k of a scaled channel that maps its median tissue pixel to `transmittance` in a
+/// color channel with the beta coefficient `beta`, from exp(-beta * k * median) = transmittance.
+/// Tissue pixels are those above the Otsu threshold of the channel. None if there are none or
+/// their median is 0.
+pub fn auto_k(image: &Array2<f32>, beta: f32, transmittance: f32) -> Option<f32> {
+    let threshold = mask::otsu_threshold(image);
+    let mut tissue: Vec<f32> = image.iter().copied().filter(|v| v.is_finite() && *v > threshold).collect();
+    if tissue.is_empty() || beta <= 0.0 {
+        return None;
+    }
+    let median = select_percentile(&mut tissue, 50.0, PercentileMethod::default());
+    let k = -transmittance.ln() / (beta * median);
+    debug!("k estimate: median tissue intensity {} above threshold {}, k {}", median, threshold, k);
+    k.is_finite().then_some(k)
+}
+
+/// Compute the scaling thresholds for `options` from a set of finite values.
+///
+/// `values` is reordered in the process. Returns an error if `values` is empty or if the floor
+/// intensity of a floor percentile is not below the saturation intensity. A fixed window is
+/// returned as it is.
+pub fn compute_thresholds(values: &mut [f32], options: &ScaleOptions) -> Result<Thresholds, Box<dyn std::error::Error>> {
+    if let Some(window) = options.window {
+        return Ok(window);
+    }
+    if values.is_empty() {
+        return Err("image contains no finite values".into());
+    }
+    thresholds_at(options, |percentile| select_percentile(values, percentile, options.percentile_method))
+}
+
+/// Scaling thresholds at the percentiles of `options`, whose values `value_at` selects.
+///
+/// Without a floor percentile the floor is 0, and a blank channel, e.g. an empty tile of a batch,
+/// gets a ceiling of 0 that scales it to 0 rather than an error.
+pub(crate) fn thresholds_at(
+    options: &ScaleOptions,
+    mut value_at: impl FnMut(f32) -> f32,
+) -> Result<Thresholds, Box<dyn std::error::Error>> {
+    let ceiling = value_at(options.percentile);
+    let Some(floor_percentile) = options.floor_percentile else {
+        return Ok(Thresholds { floor: 0.0, ceiling });
+    };
+    let floor = value_at(floor_percentile);
+    if floor >= ceiling {
+        return Err(format!(
+            "floor intensity {} (percentile {}) is not below saturation intensity {} (percentile {})",
+            floor, floor_percentile, ceiling, options.percentile
+        )
+        .into());
+    }
+    Ok(Thresholds { floor, ceiling })
+}
+
+/// Intensity statistics of a normalized channel and the scaling `scale_with` would apply to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelStats {
+    /// Smallest finite value, NaN values count as 0 with `NanPolicy::Zero`.
+    pub min: f32,
+    /// Largest finite value.
+    pub max: f32,
+    /// Mean of the finite values.
+    pub mean: f32,
+    /// Number of NaN values.
+    pub nan_count: usize,
+    /// Thresholds `scale_with` would apply.
+    pub thresholds: Thresholds,
+    /// Fraction of all pixels at or above the ceiling, which saturate at 1 when scaled.
+    pub saturated: f32,
+    /// Otsu threshold above which the values are tissue with an automatic contrast.
+    pub tissue_threshold: Option<f32>,
+    /// Fraction of the values above the tissue threshold.
+    pub tissue_fraction: Option<f32>,
+}
+
+/// Compute the statistics of a channel and the thresholds for `options` without scaling it.
+///
+/// Returns the same errors as `scale_with`.
+pub fn channel_stats(image: &Array2<f32>, options: &ScaleOptions) -> Result<ChannelStats, Box<dyn std::error::Error>> {
+    channel_stats_with_mask(image, options, None)
+}
+
+/// Compute `channel_stats` over the pixels selected by `mask`, as `scale_with_mask` does. Only
+/// the saturated fraction counts all pixels.
+pub fn channel_stats_with_mask(
+    image: &Array2<f32>,
+    options: &ScaleOptions,
+    mask: Option<&Array2<bool>>,
+) -> Result<ChannelStats, Box<dyn std::error::Error>> {
+    let nan_count = image.par_iter().filter(|v| v.is_nan()).count();
+    if nan_count > 0 && options.nan_policy == NanPolicy::Error {
+        return Err(format!("image contains {} NaN values", nan_count).into());
+    }
+
+    // NaN values are seen as zeros by the percentiles unless they are ignored
+    let mut values = masked_values(image, mask)?;
+    if options.nan_policy == NanPolicy::Zero {
+        let masked_nan_count = match mask {
+            Some(mask) => Zip::from(image).and(mask).fold(0, |count, v, &m| count + usize::from(m && v.is_nan())),
+            None => nan_count,
+        };
+        values.resize(values.len() + masked_nan_count, 0.0);
+    }
+    let (thresholds, tissue) = match options.auto_contrast {
+        Some(_) if options.window.is_none() => {
+            let mut tissue = values.clone();
+            let split = keep_tissue(&mut tissue);
+            (compute_thresholds(&mut tissue, options)?, Some(split))
+        }
+        _ => (compute_thresholds(&mut values, options)?, None),
+    };
+    let (min, max) = values
+        .par_iter()
+        .fold(|| (f32::INFINITY, f32::NEG_INFINITY), |(min, max), &v| (min.min(v), max.max(v)))
+        .reduce(|| (f32::INFINITY, f32::NEG_INFINITY), |a, b| (a.0.min(b.0), a.1.max(b.1)));
+    let mean = values.par_iter().map(|&v| f64::from(v)).sum::<f64>() / values.len() as f64;
+    drop(values);
+
+    let saturated = image.par_iter().filter(|&&v| v >= thresholds.ceiling).count();
+    Ok(ChannelStats {
+        min,
+        max,
+        mean: mean as f32,
+        nan_count,
+        thresholds,
+        saturated: saturated as f32 / image.len() as f32,
+        tissue_threshold: tissue.map(|split| split.threshold),
+        tissue_fraction: tissue.map(|split| split.fraction),
+    })
+}
+
+/// Fixed window in input units that the percentiles of `options` select over all pixels of a set
+/// of images, so that other images can be scaled identically with it.
+///
+/// Returns an error for options with an equalization, whose intensities depend on the image, or
+/// an automatic contrast. The
+/// percentiles are accumulated with `quantile::StreamingQuantiles` as in tiled renders, exact
+/// for integer images and approximate beyond `quantile::DEFAULT_EXACT_LIMIT` other values. Images
+/// are read one at a time.
+pub fn reference_window<P: AsRef<Path>>(
+    paths: &[P],
+    load: &LoadOptions,
+    options: &ScaleOptions,
+) -> Result<[f32; 2], Error> {
+    let Some(first) = paths.first() else {
+        return Err(Error::InvalidOptions("no reference images given".to_string()));
+    };
+    if options.equalize.is_some() {
+        return Err(Error::InvalidOptions("equalized channels have no fixed window in input units".to_string()));
+    }
+    if options.auto_contrast.is_some() {
+        return Err(Error::InvalidOptions("automatic contrast is not supported over several images".to_string()));
+    }
+    let mut sampler = tiled::ThresholdSampler::new();
+    let mut container_max = None;
+    for path in paths {
+        let (image, _, max) = decode_raw(path.as_ref(), load)?;
+        sampler.push(&image);
+        container_max = max;
+    }
+    let percentiles = ScaleOptions {
+        window: None,
+        ..options.clone()
+    };
+    let (input_max, thresholds) = sampler.finish(first.as_ref(), load, &percentiles, container_max)?;
+    debug!(
+        "window of {} images from {}: {} to {}",
+        paths.len(),
+        first.as_ref().display(),
+        thresholds.floor * input_max,
+        thresholds.ceiling * input_max
+    );
+    Ok([thresholds.floor * input_max, thresholds.ceiling * input_max])
+}
+
+/// Subtract the bleed-through between two normalized channels of the same size in one pass:
+/// eosin becomes eosin - a * nucleus and nucleus becomes nucleus - b * eosin for `[a, b]`, both
+/// from the values before the subtraction and clamped to zero. NaN values are kept.
+pub fn subtract_crosstalk(nucleus: &mut Array2<f32>, eosin: &mut Array2<f32>, [a, b]: [f32; 2]) {
+    assert_eq!(nucleus.dim(), eosin.dim(), "channels must have the same size");
+    Zip::from(nucleus).and(eosin).par_for_each(|n, e| {
+        let (nucleus, eosin) = (*n, *e);
+        *e = normalize_value(eosin - a * nucleus, 1.0);
+        *n = normalize_value(nucleus - b * eosin, 1.0);
+    });
+}
+
+/// Combine normalized channels of the same size into their weighted sum, e.g. two acquisition
+/// channels that together make up the eosin signal. NaN values are kept.
+///
+/// # Panics
+///
+/// Panics if there are no channels, if the numbers of channels and weights differ, or if the
+/// channels have different shapes.
+pub fn weighted_sum(channels: &[Array2<f32>], weights: &[f32]) -> Array2<f32> {
+    assert!(!channels.is_empty(), "at least one channel is required");
+    assert_eq!(channels.len(), weights.len(), "every channel needs a weight");
+    let mut sum = Array2::<f32>::zeros(channels[0].dim());
+    for (channel, &weight) in channels.iter().zip(weights) {
+        assert_eq!(channel.dim(), sum.dim(), "channels must have the same shape");
+        Zip::from(&mut sum).and(channel).par_for_each(|s, &v| *s += weight * v);
+    }
+    sum
+}
+
+/// Crop both channels to their common (top left anchored) region.
+pub fn crop_to_common(nucleus: Array2<f32>, eosin: Array2<f32>) -> (Array2<f32>, Array2<f32>) {
+    let rows = nucleus.nrows().min(eosin.nrows());
+    let cols = nucleus.ncols().min(eosin.ncols());
+    (
+        nucleus.slice_move(s![..rows, ..cols]),
+        eosin.slice_move(s![..rows, ..cols]),
+    )
+}
+
+/// Bit depth of the rendered RGB output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputDepth {
+    /// 8 bits per channel.
+    #[default]
+    Eight,
+    /// 16 bits per channel.
+    Sixteen,
+}
+
+impl OutputDepth {
+    /// Bits per channel.
+    pub fn bits(&self) -> u8 {
+        match self {
+            OutputDepth::Eight => 8,
+            OutputDepth::Sixteen => 16,
+        }
+    }
+}
+
+impl std::str::FromStr for OutputDepth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(OutputDepth::Eight),
+            "16" => Ok(OutputDepth::Sixteen),
+            _ => Err(format!("unknown output depth '{}', expected one of: 8, 16", s)),
+        }
+    }
+}
+
+/// Sample type of a rendered RGB image, `u8` for 8bit and `u16` for 16bit output.
+pub trait OutputSample: Copy + Default + Into<u32> + Send + Sync + 'static {
+    /// Bits per sample.
+    const BITS: u16;
+
+    /// Largest level of the sample type, that of the value 1.
+    const MAX: f32;
+
+    /// Quantize a value in [0, 1] to the nearest value of the full range of the sample type, with
+    /// halves rounded up so that the levels are not darker on average than the values, 1 exactly
+    /// `MAX`, and values outside [0, 1] saturating, NaN at 0. Every output depth quantizes alike.
+    fn quantize(v: f32) -> Self {
+        Self::from_level((v * Self::MAX).round())
+    }
+
+    /// Sample of an integer `level`, saturating at 0 and `MAX`.
+    fn from_level(level: f32) -> Self;
+
+    /// Wrap raw row-major RGB or RGBA samples into a dynamic image, see `samples_per_pixel`.
+    fn into_dynamic(width: u32, height: u32, data: Vec<Self>) -> DynamicImage;
+
+    /// View samples as bytes in native byte order, as written by the TIFF encoder.
+    fn as_ne_bytes(samples: &[Self]) -> Cow<'_, [u8]>;
+
+    /// Rounded mean of `count` samples adding up to `sum`.
+    fn from_mean(sum: u32, count: u32) -> Self;
+}
+
+impl OutputSample for u8 {
+    const BITS: u16 = 8;
+    const MAX: f32 = 255.0;
+
+    fn from_level(level: f32) -> Self {
+        level as u8
+    }
+
+    fn into_dynamic(width: u32, height: u32, data: Vec<Self>) -> DynamicImage {
+        let error = "buffer matches the image dimensions";
+        match samples_per_pixel(width, height, data.len()) {
+            4 => DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, data).expect(error)),
+            _ => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, data).expect(error)),
+        }
+    }
+
+    fn as_ne_bytes(samples: &[Self]) -> Cow<'_, [u8]> {
+        samples.data()
+    }
+
+    fn from_mean(sum: u32, count: u32) -> Self {
+        ((sum + count / 2) / count) as u8
+    }
+}
+
+impl OutputSample for u16 {
+    const BITS: u16 = 16;
+    const MAX: f32 = 65535.0;
+
+    fn from_level(level: f32) -> Self {
+        level as u16
+    }
+
+    fn into_dynamic(width: u32, height: u32, data: Vec<Self>) -> DynamicImage {
+        let error = "buffer matches the image dimensions";
+        match samples_per_pixel(width, height, data.len()) {
+            4 => DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, data).expect(error)),
+            _ => DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, data).expect(error)),
+        }
+    }
+
+    fn as_ne_bytes(samples: &[Self]) -> Cow<'_, [u8]> {
+        samples.data()
+    }
+
+    fn from_mean(sum: u32, count: u32) -> Self {
+        ((sum + count / 2) / count) as u16
+    }
+}
+
+/// Generate the virtual H&E image as an 8bit (row, column, RGB) array from scaled nucleus and eosin channels.
+///
+/// # Panics
+///
+/// Panics if the nucleus and eosin channels have different shapes.
+pub fn render(nucleus: Array2<f32>, eosin: Array2<f32>, params: &Params) -> Array3<u8> {
+    render_as(nucleus, eosin, params)
+}
+
+/// Generate the virtual H&E image as a (row, column, RGB) array of the requested sample type, e.g.
+/// `render_as::<u16>` for 16bit output.
+///
+/// # Panics
+///
+/// Panics if the nucleus and eosin channels have different shapes.
+pub fn render_as<T: OutputSample>(nucleus: Array2<f32>, eosin: Array2<f32>, params: &Params) -> Array3<T> {
+    render_with_extra_as(nucleus, eosin, None, params)
+}
+
+/// Evaluate the color model over a `size` x `size` grid of scaled intensities from 0 to 1, with
+/// the nucleus intensity increasing down the rows and the eosin intensity across the columns, as
+/// a (row, column, RGB) lookup table to reproduce the rendering in other software. The colors are
+/// rounded to the nearest level without the dithering of the parameters.
+pub fn color_lut<T: OutputSample>(size: usize, params: &Params) -> Array3<T> {
+    let step = 1.0 / size.saturating_sub(1).max(1) as f32;
+    let nucleus = Array2::from_shape_fn((size, size), |(i, _)| i as f32 * step);
+    let eosin = Array2::from_shape_fn((size, size), |(_, j)| j as f32 * step);
+    let params = Params {
+        dither: Dither::None,
+        ..*params
+    };
+    render_as(nucleus, eosin, &params)
+}
+
+/// Generate the virtual image with an optional third stain, e.g. a marker channel rendered as
+/// brown DAB on top of H&E, whose transmittance multiplies that of hematoxylin and eosin with
+/// `params.k_extra` and `params.beta_extra`. Without the extra channel this is `render_as`.
+///
+/// # Panics
+///
+/// Panics if the channels have different shapes.
+pub fn render_with_extra_as<T: OutputSample>(
+    nucleus: Array2<f32>,
+    eosin: Array2<f32>,
+    extra: Option<Array2<f32>>,
+    params: &Params,
+) -> Array3<T> {
+    assert_eq!(nucleus.dim(), eosin.dim(), "nucleus and eosin channels must have the same shape");
+    if let Some(extra) = &extra {
+        assert_eq!(nucleus.dim(), extra.dim(), "nucleus and extra channels must have the same shape");
+    }
+    let mut channels = vec![nucleus, eosin];
+    channels.extend(extra);
+    let stains = &params.stains()[..channels.len()];
+    render_channels_as(channels, stains, params.encoding, params.exact, params.dither).expect("shapes checked above")
+}
+
+/// Absorption of one stain in the color model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stain {
+    /// Beta coefficients (red, green, blue).
+    pub beta: [f32; 3],
+    /// K factor.
+    pub k: f32,
+}
+
+impl Stain {
+    /// Transmittance exp(-beta * v * k) of the red, green and blue light at scaled intensity `v`.
+    fn transmittance(&self, v: f32) -> [f32; 3] {
+        self.beta.map(|beta| (-beta * v * self.k).exp())
+    }
+}
+
+/// Number of intervals of the lookup tables of exp(-beta * k * v) over scaled intensities from
+/// 0 to 1. Their linear interpolation is within 1e-5 of the exact value up to k * beta = 100.
+const EXP_TABLE_SIZE: usize = 16384;
+
+/// Lookup table of exp(-beta * v * k) of one stain, the red, green and blue values of every
+/// intensity next to each other.
+struct ExpTable {
+    stain: Stain,
+    values: Vec<[f32; 3]>,
+}
+
+impl ExpTable {
+    fn new(stain: Stain) -> Self {
+        let values = (0..=EXP_TABLE_SIZE)
+            .map(|i| stain.transmittance(i as f32 / EXP_TABLE_SIZE as f32))
+            .collect();
+        ExpTable { stain, values }
+    }
+
+    /// Transmittance of the stain at intensity `v`, interpolated in the table for v in [0, 1] and
+    /// computed otherwise.
+    fn get(&self, v: f32) -> [f32; 3] {
+        if !(0.0..=1.0).contains(&v) {
+            return self.stain.transmittance(v);
+        }
+        let position = v * EXP_TABLE_SIZE as f32;
+        let i = (position as usize).min(EXP_TABLE_SIZE - 1);
+        let (t, a, b) = (position - i as f32, self.values[i], self.values[i + 1]);
+        [0, 1, 2].map(|c| a[c] + t * (b[c] - a[c]))
+    }
+}
+
+/// Generate a virtual image from any number of scaled channels, each rendered as the stain at the
+/// same index, e.g. 4 to 6 unmixed stains of spectral imaging. The transmittance of a pixel is the
+/// product of exp(-beta * v * k) over the stains, interpolated in lookup tables unless `exact`,
+/// and quantized with `dither`. Err if the channels have different shapes.
+///
+/// # Panics
+///
+/// Panics if there are no channels or if the numbers of channels and stains differ.
+pub fn render_channels_as<T: OutputSample>(
+    channels: Vec<Array2<f32>>,
+    stains: &[Stain],
+    encoding: ColorEncoding,
+    exact: bool,
+    dither: Dither,
+) -> Result<Array3<T>, Error> {
+    let views: Vec<_> = channels.iter().map(|c| c.view()).collect();
+    render_views_as(&views, stains, encoding, exact, dither)
+}
+
+/// Generate a virtual image like `render_channels_as` from borrowed channels, e.g. to render
+/// single stains of channels that are rendered together as well. Err if the channels have
+/// different shapes.
+///
+/// # Panics
+///
+/// Panics if there are no channels or if the numbers of channels and stains differ.
+pub fn render_views_as<T: OutputSample>(
+    channels: &[ArrayView2<f32>],
+    stains: &[Stain],
+    encoding: ColorEncoding,
+    exact: bool,
+    dither: Dither,
+) -> Result<Array3<T>, Error> {
+    assert!(!channels.is_empty(), "at least one channel is required");
+    assert_eq!(channels.len(), stains.len(), "every channel needs a stain");
+    let (rows, cols) = channels[0].dim();
+    if let Some((index, channel)) = channels.iter().enumerate().find(|(_, c)| c.dim() != (rows, cols)) {
+        return Err(Error::SizeMismatch {
+            input: "channel 0".into(),
+            input_size: (cols, rows),
+            other: index.to_string().into(),
+            other_size: (channel.ncols(), channel.nrows()),
+            kind: "channel",
+        });
+    }
+
+    if exact {
+        return Ok(render_with(channels, encoding, dither, |i, v| stains[i].transmittance(v)));
+    }
+    let tables: Vec<ExpTable> = stains.iter().map(|&stain| ExpTable::new(stain)).collect();
+    Ok(render_with(channels, encoding, dither, |i, v| tables[i].get(v)))
+}
+
+/// Rows of the bands of Floyd-Steinberg dithering, whose colors are computed in parallel before
+/// their errors are diffused serially.
+const DIFFUSION_BAND_ROWS: usize = 64;
+
+/// Render channels of the same shape with the transmittance of the stain of channel i at
+/// intensity v given by `transmittance(i, v)`, quantized with `dither`.
+fn render_with<T: OutputSample, F>(
+    channels: &[ArrayView2<f32>],
+    encoding: ColorEncoding,
+    dither: Dither,
+    transmittance: F,
+) -> Array3<T>
+where
+    F: Fn(usize, f32) -> [f32; 3] + Sync,
+{
+    let (rows, cols) = channels[0].dim();
+    let mut rgb = Array3::<T>::from_elem((rows, cols, 3), T::default());
+    // The products of the stains over the row y of `product`, which run over contiguous slices
+    // without bounds checks so that the compiler can vectorize them
+    let products = |y: usize, product: &mut [f32]| {
+        product.fill(1.0);
+        for (i, channel) in channels.iter().enumerate() {
+            let row = channel.row(y);
+            let row = row.as_slice().map_or_else(|| Cow::Owned(row.to_vec()), Cow::Borrowed);
+            for (p, &v) in product.chunks_exact_mut(3).zip(row.iter()) {
+                let [r, g, b] = transmittance(i, v);
+                p[0] *= r;
+                p[1] *= g;
+                p[2] *= b;
+            }
+        }
+    };
+
+    if dither == Dither::FloydSteinberg {
+        if rgb.is_empty() {
+            return rgb;
+        }
+        // The colors of a band of rows at a time in parallel, then their errors diffused serially
+        let mut diffusion = dither::ErrorDiffusion::new(cols);
+        let mut colors = vec![0.0f32; DIFFUSION_BAND_ROWS.min(rows) * cols * 3];
+        for (band, mut rgb_band) in rgb.axis_chunks_iter_mut(Axis(0), DIFFUSION_BAND_ROWS).enumerate() {
+            let y0 = band * DIFFUSION_BAND_ROWS;
+            let colors = &mut colors[..rgb_band.len_of(Axis(0)) * cols * 3];
+            colors.par_chunks_mut(cols * 3).enumerate().for_each(|(dy, row)| {
+                products(y0 + dy, row);
+                row.iter_mut().for_each(|p| *p = encoding.encode(*p));
+            });
+            let samples = rgb_band.as_slice_mut().expect("bands of a new array are contiguous");
+            let rows = samples.chunks_exact_mut(cols * 3).zip(colors.chunks_exact(cols * 3));
+            for (dy, (samples, colors)) in rows.enumerate() {
+                diffusion.row(y0 + dy, colors, samples);
+            }
+        }
+        return rgb;
+    }
+
+    // Compute RGB pixels in parallel over rows, quantizing each row directly into the output so
+    // that only one RGB buffer is allocated
+    rgb.axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .for_each_init(
+            || vec![0.0f32; cols * 3],
+            |product, (y, mut rgb_row)| {
+                products(y, product);
+                let samples = rgb_row.as_slice_mut().expect("rows of a new array are contiguous");
+                if dither == Dither::Ordered {
+                    product.iter_mut().for_each(|p| *p = encoding.encode(*p));
+                    dither::ordered_row(y, product, samples);
+                    return;
+                }
+                for (sample, &p) in samples.iter_mut().zip(product.iter()) {
+                    *sample = T::quantize(encoding.encode(p));
+                }
+            },
+        );
+    rgb
+}
+
+/// Convert a (row, column, RGB) array into an image buffer.
+pub fn to_image(rgb: &Array3<u8>) -> RgbImage {
+    into_image(rgb.to_owned())
+}
+
+/// Convert a (row, column, RGB) array into an image buffer, reusing its allocation when the array
+/// is in standard layout.
+pub fn into_image(rgb: Array3<u8>) -> RgbImage {
+    let (width, height, data) = into_raw_rgb(rgb);
+    ImageBuffer::from_raw(width, height, data).expect("buffer matches the image dimensions")
+}
+
+/// Take the row-major samples out of a (row, column, RGB) or (row, column, RGBA) array along with
+/// its width and height.
+fn into_raw_rgb<T: Clone>(rgb: Array3<T>) -> (u32, u32, Vec<T>) {
+    let (height, width, samples) = rgb.dim();
+    let rgb = if rgb.is_standard_layout() {
+        rgb
+    } else {
+        rgb.as_standard_layout().into_owned()
+    };
+    let (mut data, offset) = rgb.into_raw_vec_and_offset();
+    data.drain(..offset.unwrap_or(0));
+    data.truncate(height * width * samples);
+    (width as u32, height as u32, data)
+}
+
+/// Samples per pixel of `samples` raw samples of a `width` x `height` image: 4 for RGBA, otherwise 3
+/// for RGB.
+pub(crate) fn samples_per_pixel(width: u32, height: u32, samples: usize) -> usize {
+    if samples == width as usize * height as usize * 4 && samples > 0 {
+        4
+    } else {
+        3
+    }
+}
+
+/// Compression of TIFF outputs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TiffCompression {
+    /// Uncompressed.
+    None,
+    /// LZW.
+    Lzw,
+    /// Deflate (zlib).
+    #[default]
+    Deflate,
+    /// Zstandard, not provided by the tiff encoder in use.
+    Zstd,
+}
+
+impl TiffCompression {
+    /// Name of the compression as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TiffCompression::None => "none",
+            TiffCompression::Lzw => "lzw",
+            TiffCompression::Deflate => "deflate",
+            TiffCompression::Zstd => "zstd",
+        }
+    }
+
+    /// Fail with a clear message if the TIFF encoder cannot write this compression.
+    pub fn check_supported(self) -> Result<(), String> {
+        match self {
+            TiffCompression::Zstd => {
+                Err("zstd compression is not supported by the TIFF encoder, use none, lzw or deflate".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::str::FromStr for TiffCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(TiffCompression::None),
+            "lzw" => Ok(TiffCompression::Lzw),
+            "deflate" => Ok(TiffCompression::Deflate),
+            "zstd" => Ok(TiffCompression::Zstd),
+            _ => Err(format!("unknown compression '{}', expected one of: none, lzw, deflate, zstd", s)),
+        }
+    }
+}
+
+/// Default tile size of pyramidal TIFF outputs in pixels.
+pub const DEFAULT_PYRAMID_TILE_SIZE: u32 = 512;
+
+/// Default JPEG quality used by `save`.
+pub const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+/// Default chunk edge length of OME-Zarr outputs in pixels.
+pub const DEFAULT_ZARR_CHUNK_SIZE: u32 = 1024;
+
+/// Default long edge of thumbnails in pixels.
+pub const DEFAULT_THUMBNAIL_SIZE: usize = 1024;
+
+/// Path standing for stdin as an input and for stdout as an output.
+pub const STDIO_PATH: &str = "-";
+
+/// Format of images written to stdout, which has no file extension to infer it from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFormat {
+    Tiff,
+    Png,
+}
+
+impl StreamFormat {
+    /// Name of the format as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamFormat::Tiff => "tiff",
+            StreamFormat::Png => "png",
+        }
+    }
+
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            StreamFormat::Tiff => ImageFormat::Tiff,
+            StreamFormat::Png => ImageFormat::Png,
+        }
+    }
+}
+
+impl std::str::FromStr for StreamFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tiff" => Ok(StreamFormat::Tiff),
+            "png" => Ok(StreamFormat::Png),
+            _ => Err(format!("unknown format '{}', expected one of: tiff, png", s)),
+        }
+    }
+}
+
+/// Layout and metadata of OME-Zarr outputs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZarrOptions {
+    /// Edge length of the chunks in pixels, each chunk holds all three channels.
+    pub chunk_size: u32,
+    /// Physical size (x, y) of a full resolution pixel in micrometers, omitted when not known.
+    pub pixel_size_um: Option<[f32; 2]>,
+}
+
+impl Default for ZarrOptions {
+    fn default() -> Self {
+        ZarrOptions {
+            chunk_size: DEFAULT_ZARR_CHUNK_SIZE,
+            pixel_size_um: None,
+        }
+    }
+}
+
+/// Options controlling how `save_with` encodes the output image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SaveOptions {
+    /// JPEG quality in [1, 100], only used for JPEG outputs.
+    pub jpeg_quality: u8,
+    /// Compression, only used for TIFF outputs.
+    pub compression: TiffCompression,
+    /// Write a tiled pyramidal BigTIFF with this tile size instead of a flat image, TIFF outputs only.
+    pub pyramid_tile_size: Option<u32>,
+    /// Always write TIFF outputs as BigTIFF, otherwise it is used only when the output may exceed 4 GB.
+    pub bigtiff: bool,
+    /// Write an OME-TIFF with this metadata, TIFF outputs only.
+    pub ome: Option<ome::OmeMetadata>,
+    /// Physical size (x, y) of a pixel in micrometers recorded in the resolution tags of TIFF outputs.
+    pub pixel_size_um: Option<[f32; 2]>,
+    /// Rendering parameters recorded in TIFF outputs as a JSON image description, unless the
+    /// OME-XML of `ome` holds them.
+    pub parameters: Vec<(String, String)>,
+    /// Write an OME-Zarr multiscale group at the output path instead of an image file.
+    pub zarr: Option<ZarrOptions>,
+}
+
+impl Default for SaveOptions {
+    fn default() -> Self {
+        SaveOptions {
+            jpeg_quality: DEFAULT_JPEG_QUALITY,
+            compression: TiffCompression::default(),
+            pyramid_tile_size: None,
+            bigtiff: false,
+            ome: None,
+            pixel_size_um: None,
+            parameters: Vec::new(),
+            zarr: None,
+        }
+    }
+}
+
+/// Shrink a rendered (row, column, RGB) array to an 8bit thumbnail whose long edge is at most
+/// `max_size` pixels, averaging the area every thumbnail pixel covers. Smaller images keep their size.
+/// RGBA arrays are composited over white.
+///
+/// Rows are computed in parallel.
+pub fn thumbnail<T: OutputSample>(rgb: ArrayView3<T>, max_size: usize) -> Array3<u8> {
+    let (height, width) = (rgb.shape()[0], rgb.shape()[1]);
+    let scale = (max_size as f64 / width.max(height).max(1) as f64).min(1.0);
+    let size = |n: usize| ((n as f64 * scale).round() as usize).clamp(1, n.max(1));
+    let (rows, cols) = (size(height), size(width));
+    let max = ((1u64 << T::BITS) - 1) as f64;
+    let to_u8 = 255.0 / max;
+    let alpha = rgb.shape()[2] == 4;
+
+    // Thumbnail pixel (y, x) covers source rows y * height / rows up to (y + 1) * height / rows
+    let mut out = Array3::<u8>::zeros((rows, cols, 3));
+    if height == 0 || width == 0 {
+        return out;
+    }
+    out.axis_iter_mut(Axis(0)).into_par_iter().enumerate().for_each(|(y, mut row)| {
+        let band = rgb.slice(s![y * height / rows..(y + 1) * height / rows, .., ..]);
+        for (x, mut pixel) in row.outer_iter_mut().enumerate() {
+            let block = band.slice(s![.., x * width / cols..(x + 1) * width / cols, ..]);
+            let count = (block.shape()[0] * block.shape()[1]) as f64;
+            for channel in 0..3 {
+                let sum: f64 = if alpha {
+                    // Over white, a sample c with alpha a shows as c a + max (1 - a)
+                    Zip::from(block.slice(s![.., .., channel])).and(block.slice(s![.., .., 3])).fold(0.0, |sum, &c, &a| {
+                        let a = f64::from(a.into()) / max;
+                        sum + f64::from(c.into()) * a + max * (1.0 - a)
+                    })
+                } else {
+                    block.slice(s![.., .., channel]).iter().map(|&v| u64::from(v.into())).sum::<u64>() as f64
+                };
+                pixel[channel] = (sum / count * to_u8).round() as u8;
+            }
+        }
+    });
+    out
+}
+
+/// Save an 8bit or 16bit (row, column, RGB) array to disk, the format is inferred from the file extension.
+/// (row, column, RGBA) arrays are saved with an alpha channel, to PNG and TIFF outputs only.
+pub fn save<T: OutputSample, P: AsRef<Path>>(rgb: Array3<T>, path: P) -> Result<(), Error> {
+    save_with(rgb, path, &SaveOptions::default())
+}
+
+/// Save an 8bit or 16bit (row, column, RGB) array to disk with the given encoder options, the format
+/// is inferred from the file extension unless an OME-Zarr output is requested.
+pub fn save_with<T: OutputSample, P: AsRef<Path>>(
+    rgb: Array3<T>,
+    path: P,
+    options: &SaveOptions,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    write_image(rgb, path, options).map_err(|e| Error::write(path, e))
+}
+
+/// Encode an 8bit or 16bit (row, column, RGB) array in `format` and write it to stdout, for piping
+/// into other tools. The image is encoded in memory first, as TIFF encoding needs to seek.
+pub fn save_to_stdout<T: OutputSample>(rgb: Array3<T>, format: StreamFormat, options: &SaveOptions) -> Result<(), Error> {
+    let path = Path::new("stdout");
+    if options.zarr.is_some() {
+        return Err(Error::InvalidOptions("OME-Zarr output cannot be written to stdout".to_string()));
+    }
+    let mut buffer = Cursor::new(Vec::new());
+    encode_image(rgb, &mut buffer, format.image_format(), path, options).map_err(|e| Error::write(path, e))?;
+    debug!("{}: writing {} bytes", path.display(), buffer.get_ref().len());
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(buffer.get_ref()).and_then(|_| stdout.flush()).map_err(|e| Error::write(path, e))
+}
+
+/// Encode an RGB array with the writer of the output format, files through a temporary file
+/// renamed onto `path` when complete.
+fn write_image<T: OutputSample>(
+    rgb: Array3<T>,
+    path: &Path,
+    options: &SaveOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(zarr) = &options.zarr {
+        if options.pyramid_tile_size.is_some() || options.ome.is_some() {
+            return Err(Error::InvalidOptions(
+                "OME-Zarr output cannot be combined with pyramidal or OME-TIFF output".to_string(),
+            )
+            .into());
+        }
+        if rgb.shape()[2] == 4 {
+            return Err(Error::InvalidOptions("OME-Zarr output cannot hold an alpha channel".to_string()).into());
+        }
+        debug!("{}: OME-Zarr, chunks of {} pixels, {:?} compression", path.display(), zarr.chunk_size, options.compression);
+        return zarr_writer::write_zarr(path, rgb.view(), zarr, options.compression);
+    }
+    atomic::write(path, |temporary| match ImageFormat::from_path(path) {
+        Ok(format @ (ImageFormat::Tiff | ImageFormat::Jpeg)) => {
+            let mut writer = BufWriter::new(File::create(temporary)?);
+            encode_image(rgb, &mut writer, format, path, options)?;
+            Ok(atomic::finish(writer)?)
+        }
+        format => {
+            check_tiff_options(path, None, options)?;
+            debug!("{}: {:?} by the image crate", path.display(), format.as_ref().ok());
+            let (width, height, data) = into_raw_rgb(rgb);
+            let mut writer = BufWriter::new(File::create(temporary)?);
+            T::into_dynamic(width, height, data).write_to(&mut writer, format?)?;
+            Ok(atomic::finish(writer)?)
+        }
+    })
+}
+
+/// Check that pyramidal and OME-TIFF outputs are written as TIFF.
+fn check_tiff_options(path: &Path, format: Option<ImageFormat>, options: &SaveOptions) -> Result<(), Error> {
+    if (options.pyramid_tile_size.is_some() || options.ome.is_some()) && format != Some(ImageFormat::Tiff) {
+        return Err(Error::InvalidOptions(format!(
+            "pyramidal and OME-TIFF outputs require a TIFF path, got {}",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Encode an RGB array in `format` into `writer`, `path` names the output in messages.
+fn encode_image<T: OutputSample, W: Write + Seek>(
+    rgb: Array3<T>,
+    mut writer: W,
+    format: ImageFormat,
+    path: &Path,
+    options: &SaveOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    check_tiff_options(path, Some(format), options)?;
+    if rgb.shape()[2] == 4 && (options.pyramid_tile_size.is_some() || options.ome.is_some()) {
+        return Err(Error::InvalidOptions("pyramidal and OME-TIFF outputs cannot hold an alpha channel".to_string()).into());
+    }
+    if let Some(tile_size) = options.pyramid_tile_size {
+        if options.ome.is_some() {
+            return Err(Error::InvalidOptions("OME-TIFF output cannot be written as a pyramid".to_string()).into());
+        }
+        debug!("{}: pyramidal BigTIFF, tiles of {} pixels, {:?} compression", path.display(), tile_size, options.compression);
+        return tiff_writer::write_pyramid(writer, rgb, tile_size, options);
+    }
+    let (width, height, data) = into_raw_rgb(rgb);
+    match format {
+        ImageFormat::Tiff => tiff_writer::write_rgb(writer, path, width, height, &data, options)?,
+        ImageFormat::Jpeg if samples_per_pixel(width, height, data.len()) == 4 => {
+            return Err(Error::InvalidOptions(format!(
+                "JPEG output cannot hold an alpha channel, use a PNG or TIFF path for RGBA output, got {}",
+                path.display()
+            ))
+            .into())
+        }
+        ImageFormat::Jpeg => {
+            debug!("{}: JPEG, quality {}", path.display(), options.jpeg_quality);
+            // Encode straight from the RGB buffer instead of going through a converted copy
+            let DynamicImage::ImageRgb8(buffer) = T::into_dynamic(width, height, data) else {
+                return Err(Error::InvalidOptions(format!("JPEG output requires 8bit RGB, got {}", path.display())).into());
+            };
+            JpegEncoder::new_with_quality(writer, options.jpeg_quality).encode(
+                buffer.as_raw(),
+                width,
+                height,
+                ExtendedColorType::Rgb8,
+            )?;
+        }
+        format => {
+            debug!("{}: {:?} by the image crate", path.display(), format);
+            T::into_dynamic(width, height, data).write_to(&mut writer, format)?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Path of a file written by a test in the temporary directory.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("virtualhe-test-{}-{}", std::process::id(), name))
+    }
+
+    /// Parameters of the linear color model, without dithering and lookup tables.
+    fn linear_params() -> Params {
+        Params {
+            encoding: ColorEncoding::Linear,
+            exact: true,
+            ..Params::default()
+        }
+    }
+
+    #[test]
+    fn blank_channels_render_white() {
+        let rgb = render(Array2::zeros((3, 4)), Array2::zeros((3, 4)), &Params::default());
+        assert_eq!(rgb.dim(), (3, 4, 3));
+        assert!(rgb.iter().all(|&v| v == 255));
+    }
+
+    #[test]
+    fn stains_multiply_their_transmittance() {
+        let params = linear_params();
+        let nucleus = Array2::from_shape_vec((1, 3), vec![1.0, 0.0, 0.5]).unwrap();
+        let eosin = Array2::from_shape_vec((1, 3), vec![0.0, 1.0, 0.5]).unwrap();
+        let rgb = render_as::<u16>(nucleus, eosin, &params);
+        let [hematoxylin, eosin] = params.beta;
+        for c in 0..3 {
+            let expected = [
+                (-hematoxylin[c] * params.k_nucleus).exp(),
+                (-eosin[c] * params.k_eosin).exp(),
+                (-(hematoxylin[c] * params.k_nucleus + eosin[c] * params.k_eosin) * 0.5).exp(),
+            ];
+            for (x, expected) in expected.into_iter().enumerate() {
+                assert_eq!(rgb[[0, x, c]], (expected * 65535.0).round() as u16, "pixel {} channel {}", x, c);
+            }
+        }
+    }
+
+    #[test]
+    fn extra_stain_darkens_only_where_present() {
+        let params = linear_params();
+        let channel = || Array2::from_shape_vec((1, 2), vec![0.3, 0.3]).unwrap();
+        let extra = Array2::from_shape_vec((1, 2), vec![0.0, 1.0]).unwrap();
+        let with_extra = render_with_extra_as::<u8>(channel(), channel(), Some(extra), &params);
+        let without = render_as::<u8>(channel(), channel(), &params);
+        assert_eq!(with_extra.slice(s![.., 0, ..]), without.slice(s![.., 0, ..]));
+        assert!((0..3).all(|c| with_extra[[0, 1, c]] < without[[0, 1, c]]));
+    }
+
+    #[test]
+    fn four_channels_multiply_their_transmittance() {
+        let stains = [
+            Stain { beta: [0.86, 1.0, 0.3], k: 2.5 },
+            Stain { beta: [0.05, 1.0, 0.544], k: 2.5 },
+            Stain { beta: [0.268, 0.57, 0.776], k: 2.0 },
+            Stain { beta: [0.1, 0.2, 0.9], k: 1.5 },
+        ];
+        let channels: Vec<Array2<f32>> = (0..4)
+            .map(|i| Array2::from_shape_fn((3, 5), |(y, x)| ((x * 3 + y * 5 + i * 7) % 11) as f32 / 10.0))
+            .collect();
+        let rgb = render_channels_as::<u16>(channels.clone(), &stains, ColorEncoding::Linear, true, Dither::None).unwrap();
+        assert_eq!(rgb.dim(), (3, 5, 3));
+        for ((y, x), _) in channels[0].indexed_iter() {
+            for c in 0..3 {
+                let absorbance: f32 = channels.iter().zip(&stains).map(|(channel, stain)| stain.beta[c] * stain.k * channel[[y, x]]).sum();
+                let expected = ((-absorbance).exp() * 65535.0).round() as u16;
+                assert!(rgb[[y, x, c]].abs_diff(expected) <= 1, "pixel {},{} channel {}", x, y, c);
+            }
+        }
+    }
+
+    /// Levels `T::quantize` must give the values at the boundaries of the levels of `T`.
+    fn check_quantize<T: OutputSample>() {
+        let max = T::MAX;
+        let cases = [
+            (0.0, 0.0),
+            (0.49 / max, 0.0),
+            (0.5 / max, 1.0),
+            (1.0 / max, 1.0),
+            (0.5, (max / 2.0).round()),
+            ((max - 0.5) / max, max),
+            (1.0, max),
+            (1.5, max),
+            (-0.5, 0.0),
+            (f32::NAN, 0.0),
+        ];
+        for (value, level) in cases {
+            assert_eq!(T::quantize(value).into() as f32, level, "{}bit, {}", T::BITS, value);
+        }
+    }
+
+    #[test]
+    fn output_samples_round_to_the_nearest_level() {
+        check_quantize::<u8>();
+        check_quantize::<u16>();
+        assert_eq!(u8::quantize(0.0), 0);
+        assert_eq!(u8::quantize(0.5 / 255.0), 1);
+        assert_eq!(u8::quantize(1.0), 255);
+        assert_eq!(u16::quantize(1.0), 65535);
+        assert_eq!(u8::quantize(f32::NAN), 0);
+        assert_eq!(u16::quantize(f32::NAN), 0);
+    }
+
+    #[test]
+    fn lookup_tables_are_within_one_level_of_the_exact_values() {
+        // Every 16-bit level of the scaled intensity over [0, 1], and the table boundaries between them
+        let ramp = Array2::from_shape_fn((1, 4 * 65536 + 1), |(_, x)| x as f32 / (4.0 * 65536.0));
+        for k_beta in [0.05, 0.5, 1.0, 2.5, 10.0, 40.0, 100.0] {
+            let stains = [Stain { beta: [k_beta, k_beta / 2.0, k_beta / 10.0], k: 1.0 }];
+            for encoding in [ColorEncoding::Srgb, ColorEncoding::Linear] {
+                let render = |exact| render_views_as::<u16>(&[ramp.view()], &stains, encoding, exact, Dither::None).unwrap();
+                let (tables, exact) = (render(false), render(true));
+                for (x, (&table, &exact)) in tables.iter().zip(exact.iter()).enumerate() {
+                    assert!(table.abs_diff(exact) <= 1, "k*beta {} {:?} sample {}: {} instead of {}", k_beta, encoding, x, table, exact);
+                }
+                let render = |exact| render_views_as::<u8>(&[ramp.view()], &stains, encoding, exact, Dither::None).unwrap();
+                assert!(render(false).iter().zip(render(true).iter()).all(|(&table, &exact)| table.abs_diff(exact) <= 1));
+            }
+        }
+    }
+
+    #[test]
+    fn channels_of_different_shapes_are_errors() {
+        let stain = Stain { beta: [1.0; 3], k: 1.0 };
+        let mut channels = vec![Array2::zeros((3, 5)); 4];
+        channels[2] = Array2::zeros((3, 4));
+        let result = render_channels_as::<u8>(channels, &[stain; 4], ColorEncoding::Srgb, false, Dither::None);
+        let Err(Error::SizeMismatch { input_size, other, other_size, .. }) = result else {
+            panic!("channels of different shapes rendered");
+        };
+        assert_eq!((input_size, other_size, other), ((5, 3), (4, 3), PathBuf::from("2")));
+    }
+
+    #[test]
+    fn equal_k_factors_render_as_one_k() {
+        let nucleus = Array2::from_shape_fn((8, 8), |(y, x)| (y * 8 + x) as f32 / 63.0);
+        let eosin = nucleus.t().to_owned();
+        let single = Params::default().with_k(1.7);
+        let separate = Params {
+            k_nucleus: 1.7,
+            k_eosin: 1.7,
+            ..Params::default()
+        };
+        let expected = render(nucleus.clone(), eosin.clone(), &single);
+        assert_eq!(render(nucleus.clone(), eosin.clone(), &separate), expected);
+        let different = Params {
+            k_eosin: 3.0,
+            ..separate
+        };
+        assert_ne!(render(nucleus, eosin, &different), expected);
+    }
+
+    #[test]
+    fn float_tiffs_keep_nan_and_clamp_negative_values() {
+        use tiff::encoder::{colortype, TiffEncoder};
+
+        let values = [0.0f32, 1.0, 4.0, f32::NAN, -2.0, 0.5];
+        let path = temp_path("float32.tif");
+        let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+        encoder.write_image::<colortype::Gray32Float>(3, 2, &values).unwrap();
+        drop(encoder);
+
+        let (channel, info) = load_channel_with(&path, &LoadOptions::default()).unwrap();
+        assert_eq!((info.width, info.height, info.pixel_format.as_str()), (3, 2, "L32F"));
+        // Floating point data is normalized by its finite maximum
+        assert_eq!(info.input_max, 4.0);
+        let expected = [0.0, 0.25, 1.0, f32::NAN, 0.0, 0.125];
+        for (&v, expected) in channel.iter().zip(expected) {
+            assert!(v == expected || (v.is_nan() && expected.is_nan()), "{} instead of {}", v, expected);
+        }
+
+        let options = LoadOptions {
+            range: InputRange::Max(8.0),
+            ..LoadOptions::default()
+        };
+        let (channel, info) = load_channel_with(&path, &options).unwrap();
+        assert_eq!(info.input_max, 8.0);
+        assert_eq!(channel[[0, 2]], 0.5);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn double_tiffs_read_as_float() {
+        use tiff::encoder::{colortype, TiffEncoder};
+
+        let path = temp_path("float64.tif");
+        let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+        encoder.write_image::<colortype::Gray64Float>(2, 1, &[-1.0, 2.0]).unwrap();
+        drop(encoder);
+        let (channel, info) = load_channel_with(&path, &LoadOptions::default()).unwrap();
+        assert_eq!(info.pixel_format, "L64F");
+        assert_eq!(channel.as_slice().unwrap(), &[0.0, 1.0]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn input_bits_are_limited_to_32() {
+        let options = |bits| LoadOptions {
+            range: InputRange::Bits(bits),
+            ..LoadOptions::default()
+        };
+        let resolve = |bits| resolve_input_max(&options(bits), Some(65535.0), || 100.0);
+        assert_eq!(resolve(12).unwrap(), 4095.0);
+        assert_eq!(resolve(16).unwrap(), 65535.0);
+        assert_eq!(resolve(32).unwrap(), 65535.0);
+        for bits in [0, 33, 64, u8::MAX] {
+            let error = resolve(bits).unwrap_err();
+            assert!(matches!(error, Error::InvalidOptions(_)), "{} bits: {}", bits, error);
+        }
+        // Floating point images keep their data maximum with a valid bit depth only
+        assert_eq!(resolve_input_max(&options(12), None, || 100.0).unwrap(), 100.0);
+        assert!(resolve_input_max(&options(64), None, || 100.0).is_err());
+    }
+
+    #[test]
+    fn scale_maps_the_percentile_to_one() {
+        let mut image = Array2::from_shape_fn((10, 10), |(y, x)| (y * 10 + x) as f32);
+        let thresholds = scale(&mut image, 100.0).unwrap();
+        assert_eq!(thresholds, Thresholds { floor: 0.0, ceiling: 99.0 });
+        assert_eq!(image[[0, 0]], 0.0);
+        assert_eq!(image[[9, 9]], 1.0);
+        assert_eq!(image[[4, 5]], 45.0 / 99.0);
+    }
+
+    #[test]
+    fn scale_saturates_above_the_percentile_and_below_the_floor() {
+        let mut image = Array2::from_shape_fn((1, 101), |(_, x)| x as f32);
+        let options = ScaleOptions {
+            percentile: 90.0,
+            floor_percentile: Some(10.0),
+            ..ScaleOptions::default()
+        };
+        let thresholds = scale_with(&mut image, &options).unwrap();
+        assert_eq!(thresholds, Thresholds { floor: 10.0, ceiling: 90.0 });
+        assert!(image.iter().take(11).all(|&v| v == 0.0));
+        assert!(image.iter().skip(90).all(|&v| v == 1.0));
+        assert_eq!(image[[0, 50]], 0.5);
+    }
+
+    #[test]
+    fn blank_channels_scale_to_zero() {
+        for integer_input_max in [None, Some(65535.0)] {
+            let mut image = Array2::zeros((100, 100));
+            let options = ScaleOptions {
+                integer_input_max,
+                ..ScaleOptions::default()
+            };
+            let thresholds = scale_with(&mut image, &options).unwrap();
+            assert_eq!(thresholds, Thresholds { floor: 0.0, ceiling: 0.0 });
+            assert!(image.iter().all(|&v| v == 0.0));
+        }
+        // A floor percentile of a blank channel has no window between the floor and the ceiling
+        let options = ScaleOptions {
+            floor_percentile: Some(1.0),
+            ..ScaleOptions::default()
+        };
+        let error = scale_with(&mut Array2::zeros((10, 10)), &options).unwrap_err();
+        assert!(error.to_string().contains("is not below saturation intensity"), "{}", error);
+    }
+
+    #[test]
+    fn nan_and_infinity_never_reach_the_output() {
+        let values = vec![f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 0.0, 0.25, 0.5, 1.0, f32::NAN];
+        let channel = || Array2::from_shape_vec((2, 4), values.clone()).unwrap();
+        let options = ScaleOptions {
+            percentile: 100.0,
+            ..ScaleOptions::default()
+        };
+
+        let mut zero = channel();
+        let thresholds = scale_with(&mut zero, &options).unwrap();
+        // Infinite values are left out of the percentiles and clamped to the window
+        assert_eq!(thresholds, Thresholds { floor: 0.0, ceiling: 1.0 });
+        assert_eq!(zero.as_slice().unwrap(), &[0.0, 1.0, 0.0, 0.0, 0.25, 0.5, 1.0, 0.0]);
+
+        let ignore = ScaleOptions {
+            nan_policy: NanPolicy::Ignore,
+            ..options.clone()
+        };
+        let mut ignored = channel();
+        scale_with(&mut ignored, &ignore).unwrap();
+        assert_eq!(ignored, zero);
+
+        let error = ScaleOptions {
+            nan_policy: NanPolicy::Error,
+            ..options
+        };
+        assert!(scale_with(&mut channel(), &error).is_err());
+
+        // Rendered NaN and infinite pixels are those of the values they were scaled to
+        let rgb = render(zero.clone(), zero.clone(), &Params::default());
+        let finite = Array2::from_shape_vec((2, 4), vec![0.0, 1.0, 0.0, 0.0, 0.25, 0.5, 1.0, 0.0]).unwrap();
+        let expected = render(finite, zero.clone(), &Params::default());
+        assert_eq!(rgb, expected);
+        assert_eq!(rgb.slice(s![0, 0, ..]), rgb.slice(s![0, 3, ..]));
+    }
+
+    /// Pseudo-random values in [0, 1) of a linear congruential generator.
+    fn random_values(count: usize, seed: u64) -> Vec<f32> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 40) as f32 / (1u64 << 24) as f32
+            })
+            .collect()
+    }
+
+    /// Value at `percentile` of `values` by a full sort, as the thresholds were computed before
+    /// they were selected.
+    fn sorted_percentile(values: &[f32], percentile: f32, method: PercentileMethod) -> f32 {
+        let mut sorted: Vec<f32> = values.iter().copied().filter(|v| v.is_finite()).collect();
+        sorted.sort_by(f32::total_cmp);
+        method.select(percentile, sorted.len() as u64, |rank| sorted[rank as usize])
+    }
+
+    #[test]
+    fn selected_thresholds_equal_those_of_a_sort() {
+        // Negative values, signed zeros, repeated values, and non-finite values to leave out
+        let mut values: Vec<f32> = random_values(20000, 7).into_iter().map(|v| (v - 0.3) * 1000.0).collect();
+        values[..500].iter_mut().for_each(|v| *v = (*v * 0.01).round());
+        values[500..520].fill(-0.0);
+        values[520] = f32::INFINITY;
+        values[521] = f32::NEG_INFINITY;
+        values[522] = f32::NAN;
+        let image = Array2::from_shape_vec((100, 200), values.clone()).unwrap();
+        let mask = Array2::from_shape_fn((100, 200), |(y, x)| (x + y) % 3 != 0);
+        let masked: Vec<f32> = image.iter().zip(&mask).filter(|(_, &m)| m).map(|(&v, _)| v).collect();
+
+        let methods = [PercentileMethod::Linear, PercentileMethod::Lower, PercentileMethod::Nearest, PercentileMethod::Legacy];
+        for method in methods {
+            for (floor, percentile) in [(0.0, 100.0), (0.1, 99.9), (1.0, 99.999), (25.0, 50.0), (49.99, 50.01)] {
+                let options = ScaleOptions {
+                    percentile,
+                    floor_percentile: Some(floor),
+                    nan_policy: NanPolicy::Ignore,
+                    percentile_method: method,
+                    ..ScaleOptions::default()
+                };
+                for (mask, values) in [(None, &values), (Some(&mask), &masked)] {
+                    let expected = Thresholds {
+                        floor: sorted_percentile(values, floor, method),
+                        ceiling: sorted_percentile(values, percentile, method),
+                    };
+                    let thresholds = scale_with_mask(&mut image.clone(), &options, mask).unwrap();
+                    assert_eq!(thresholds, expected, "{:?} {}-{} mask {}", method, floor, percentile, mask.is_some());
+                    let mut copied = masked_values(&image, mask).unwrap();
+                    assert_eq!(compute_thresholds(&mut copied, &options).unwrap(), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sort_keys_order_values_as_total_cmp() {
+        let values = [f32::MIN, -1.5, -f32::MIN_POSITIVE, -0.0, 0.0, f32::MIN_POSITIVE, 1.0, f32::MAX];
+        for pair in values.windows(2) {
+            assert!(sort_key(pair[0]) < sort_key(pair[1]), "{} {}", pair[0], pair[1]);
+        }
+        assert!(values.iter().all(|&v| from_sort_key(sort_key(v)).to_bits() == v.to_bits()));
+    }
+
+    #[test]
+    fn scale_applies_the_gamma() {
+        let mut image = Array2::from_shape_vec((1, 3), vec![0.0, 0.25, 1.0]).unwrap();
+        let options = ScaleOptions {
+            percentile: 100.0,
+            gamma: 2.0,
+            ..ScaleOptions::default()
+        };
+        scale_with(&mut image, &options).unwrap();
+        assert_eq!(image.as_slice().unwrap(), &[0.0, 0.5, 1.0]);
+    }
+}
@@ -1,144 +1,1036 @@
-use clap::Parser;
-use image::{ImageReader, DynamicImage, ImageBuffer};
-use ndarray::{Array2, Array3};
-use ndarray::parallel::prelude::*;
-use std::path::Path;
-use std::mem::drop;
-
-/// Command-line arguments for the utility.
+use clap::{CommandFactory, FromArgMatches, Parser};
+use std::fs;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use virtualhe::alpha::AlphaRule;
+use virtualhe::dzi::DziFormat;
+use virtualhe::equalize::{DEFAULT_CLAHE_CLIP_LIMIT, DEFAULT_CLAHE_TILE_SIZE};
+use virtualhe::overlay::{BarColor, Corner};
+use virtualhe::stack::{StackOutput, StackScaling};
+use virtualhe::{
+    AutoContrast, ChannelSelector, ColorEncoding, Dither, Equalization, Error, NanPolicy, OutputDepth, PercentileMethod,
+    Profile, RawSample, RgbChannel, Roi, StreamFormat, TiffCompression,
+};
+
+mod cli;
+
+use cli::job::{self, Job};
+use cli::parse::{
+    parse_alpha, parse_beta, parse_channel, parse_clahe_clip, parse_clahe_tile_size, parse_color, parse_crosstalk,
+    parse_despeckle, parse_downsample, parse_eosin_input, parse_floor_percentile, parse_gamma, parse_input_max, parse_k,
+    parse_memory, parse_pattern, parse_percentile, parse_pixel_size, parse_range, parse_raw_dims, parse_reference,
+    parse_roi, parse_saturation, parse_scale_bar, parse_shift, parse_sigma, parse_synthetic_eosin, parse_thumbnail,
+    parse_tile_size, parse_tolerance, parse_transmittance, ByteOrder, ChannelSpec, EosinInput, InputChannel,
+    Normalization, ResampleTarget, RoiStats, Thumbnail,
+};
+#[cfg(feature = "server")]
+use cli::server;
+use cli::{batch, bench, checks, checksum, config, output, render, staging, stats, synthetic, watch};
+
+/// Edge length of the field of previews in downsampled pixels.
+const DEFAULT_PREVIEW_SIZE: u32 = 512;
+
+/// Pairs of batch runs rendered at the same time.
+const DEFAULT_JOBS: u32 = 2;
+
+/// Intensity steps of the exported color lookup table along each axis.
+const DEFAULT_LUT_SIZE: u32 = 256;
+
+/// Command-line interface: a subcommand, or the arguments of `render` with the batch, statistics and
+/// utility modes selected by flags as before the subcommands.
 #[derive(Parser, Debug)]
-#[command(about = "Make a Virtual H&E Image from Fluorescent Microscopy Images")]
-struct Args {
-    /// Path to the nucleus (hematoxylin) channel image (e.g., nucleus.tif).
+#[command(
+    about = "Make a Virtual H&E Image from Fluorescent Microscopy Images",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    mode: Option<Mode>,
+    #[command(flatten)]
+    args: Args,
+}
+
+/// Subcommands, each but `generate-test-data` and `verify` with the rendering options of `RenderParams`.
+#[derive(clap::Subcommand, Debug)]
+enum Mode {
+    /// Render a virtual H&E image, the default without a subcommand.
+    Render(RenderArgs),
+    /// Render every pair of images in a directory matched by filename patterns.
+    Batch(BatchArgs),
+    /// Decode both channels and print their statistics, scaling thresholds and saturated fraction without rendering.
+    Stats(StatsArgs),
+    /// Render a field at reduced resolution with several k values into one contact sheet, for tuning -k.
+    Preview(PreviewArgs),
+    /// Serve renders over HTTP: POST /render takes two uploaded images or paths below --root with JSON parameters and answers with the rendered image, GET /schema describes the parameters.
+    #[cfg(feature = "server")]
+    Serve(server::ServeArgs),
+    /// Render a synthetic pair of images of a given size several times and print the durations of the rendering phases and the megapixels per second, for comparing performance.
+    Bench(bench::BenchArgs),
+    /// Write a pair of synthetic nucleus and eosin TIFFs, blob-like nuclei and a smooth cytoplasm texture that only depend on the size and seed, for tests and tutorials.
+    GenerateTestData(synthetic::GenerateArgs),
+    /// Check an output rendered with --checksum against its provenance file: the SHA-256 of the file, and that of the samples it decodes to, which a lossless re-encoding keeps. Exits with 1 if either differs.
+    Verify(checksum::VerifyArgs),
+}
+
+/// Arguments of the `render` subcommand.
+#[derive(clap::Args, Debug)]
+struct RenderArgs {
+    /// Path to the nucleus (hematoxylin) channel image (e.g., nucleus.tif), or a multichannel TIFF, OME-Zarr, CZI or Imaris file holding both channels, or the output path with --channel. - reads the image from stdin.
     nucleus: String,
-    /// Path to the eosin channel image (e.g., autof.tif).
+    /// Path to the eosin channel image (e.g., autof.tif), or the output path when reading both channels from one multichannel TIFF, OME-Zarr, CZI or Imaris file or with --eosin.
+    #[arg(required_unless_present_any = ["channels", "no_eosin"])]
+    eosin: Option<String>,
+    /// Path to save the output RGB image (e.g., output.tiff), - writes it to stdout in the --format given.
+    #[arg(required_unless_present_any = ["nucleus_channel", "channels", "eosin_inputs", "no_eosin"])]
+    output: Option<String>,
+    #[command(flatten)]
+    render: RenderParams,
+}
+
+/// Arguments of the `batch` subcommand.
+#[derive(clap::Args, Debug)]
+struct BatchArgs {
+    /// Directory of the nucleus and eosin images.
+    #[arg(value_name = "DIR")]
+    batch_dir: String,
+    /// Filename pattern of the nucleus images, with {id} standing for the part shared by a pair (e.g., {id}_dapi.tif).
+    #[arg(long, value_parser = parse_pattern)]
+    nucleus_pattern: String,
+    /// Filename pattern of the eosin images (e.g., {id}_autof.tif).
+    #[arg(long, value_parser = parse_pattern)]
+    eosin_pattern: String,
+    /// Directory to save the outputs in, created if missing.
+    #[arg(long)]
+    output_dir: String,
+    /// Filename pattern of the outputs [default: {id}.tif].
+    #[arg(long, value_parser = parse_pattern)]
+    output_pattern: Option<String>,
+    /// Keep watching DIR and render the pairs as they arrive, until interrupted with Ctrl-C, see --watch.
+    #[arg(long, conflicts_with_all = ["jobs", "normalize"])]
+    watch: bool,
+    /// Number of pairs rendered at the same time, each with its share of the CPU threads. Every job holds its own images in memory, so raise it only when several fit in RAM.
+    #[arg(long, default_value_t = DEFAULT_JOBS, value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: u32,
+    /// How the images are normalized: image (each by its own percentiles) or global (percentiles over all images, from a first pass over the inputs) [default: image].
+    #[arg(long, value_name = "image|global", value_parser = str::parse::<Normalization>, conflicts_with_all = ["nucleus_range", "eosin_range", "reference", "reference_stats"])]
+    normalize: Option<Normalization>,
+    #[command(flatten)]
+    render: RenderParams,
+}
+
+/// Arguments of the `stats` subcommand.
+#[derive(clap::Args, Debug)]
+struct StatsArgs {
+    /// Path to the nucleus channel image, or a multichannel TIFF, OME-Zarr, CZI or Imaris file holding both channels.
+    nucleus: String,
+    /// Path to the eosin channel image.
+    eosin: Option<String>,
+    /// Print the statistics as JSON.
+    #[arg(long)]
+    json: bool,
+    #[command(flatten)]
+    render: RenderParams,
+}
+
+/// Arguments of the `preview` subcommand.
+#[derive(clap::Args, Debug)]
+struct PreviewArgs {
+    /// Path to the nucleus channel image.
+    nucleus: String,
+    /// Path to the eosin channel image.
     eosin: String,
-    /// Path to save the output RGB image (e.g., output.tiff).
+    /// Path to save the contact sheet (e.g., preview.png).
     output: String,
-    /// K arbitrary factor to adjust color profile of H&E.
-    #[arg(short, default_value="2.5")]
-    k: f32
-}
-
-/// Apply in place histogram scaling to the mut image so that 1 pixel per 100,000 saturates at max intensity.
-fn apply_histogram_scaling(mut image: Array2<f32>, percentile: f32) -> Array2<f32> {
-    let mut sorted: Vec<f32> = image.par_iter().copied().collect();
-    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    let threshold_index = ((percentile / 100.0) * (sorted.len() as f32)) as usize;
-    let max_intensity = sorted[threshold_index.min(sorted.len() - 1)];
-    image.par_mapv_inplace(|v| (v / max_intensity).min(1.0));
-    image
-}
-
-
-/// Generate the virtual H&E RGB image using the given nucleus and eosin channels.
-fn generate_virtual_he(
-    nucleus: Array2<f32>,
-    eosin: Array2<f32>,
-    output_path: &Path,
-    k: f32
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Parameters from the document
-    let beta_values = [
-        // Hematoxylin: (red, green, blue)
-        [0.860, 1.000, 0.300],
-        // Eosin: (red, green, blue)
-        [0.050, 1.000, 0.544],
-    ];
-
-    // Compute RGB channels in parallel
-    let mut rgb = Array3::<f32>::zeros((nucleus.nrows(), nucleus.ncols(), 3));
-
-    rgb.axis_iter_mut(ndarray::Axis(2)).into_par_iter().enumerate().for_each(|(channel, mut plane)| {
-        for ((i, j), elem) in plane.indexed_iter_mut() {
-            *elem = match channel {
-                0 => (-beta_values[0][0] * nucleus[[i, j]] * k).exp() * (-beta_values[1][0] * eosin[[i, j]] * k).exp(),
-                1 => (-beta_values[0][1] * nucleus[[i, j]] * k).exp() * (-beta_values[1][1] * eosin[[i, j]] * k).exp(),
-                2 => (-beta_values[0][2] * nucleus[[i, j]] * k).exp() * (-beta_values[1][2] * eosin[[i, j]] * k).exp(),
-                _ => unreachable!(),
-            };
-        }
-    });
+    /// Factor the inputs are downsampled by, unless --downsample is given.
+    #[arg(long, default_value = "4", value_parser = parse_downsample)]
+    factor: u32,
+    /// Edge length in downsampled pixels of the field cropped from the center of the image, unless --roi is given.
+    #[arg(long, default_value_t = DEFAULT_PREVIEW_SIZE, value_parser = clap::value_parser!(u32).range(1..))]
+    size: u32,
+    /// K values rendered side by side, sets both channels (e.g., 1.5,2.0,2.5,3.0) [default: the k of the color model].
+    #[arg(long, value_name = "K,...", value_delimiter = ',', value_parser = parse_k)]
+    k_values: Vec<f32>,
+    #[command(flatten)]
+    render: RenderParams,
+}
+
+/// Field and k values of the `preview` subcommand.
+#[derive(Debug, Clone, PartialEq)]
+struct Preview {
+    /// Downsampling factor, applied unless --downsample is set.
+    factor: u32,
+    /// Edge length of the center crop in downsampled pixels.
+    size: usize,
+    k_values: Vec<f32>,
+}
 
-    // Release unused objects
-    drop(nucleus);
-    drop(eosin);
+/// Arguments of the invocation without a subcommand, the modes selected by flags.
+#[derive(clap::Args, Debug, Clone)]
+#[command(group(clap::ArgGroup::new("pair_dir").args(["batch_dir", "watch"])))]
+struct Args {
+    /// Path to the nucleus (hematoxylin) channel image (e.g., nucleus.tif), or a multichannel TIFF, OME-Zarr, CZI or Imaris file holding both channels, or the output path with --channel. - reads the image from stdin.
+    #[arg(required_unless_present_any = ["list_profiles", "batch_dir", "watch", "shared_norm", "print_config", "write_default_config", "export_lut"])]
+    nucleus: Option<String>,
+    /// Path to the eosin channel image (e.g., autof.tif), or the output path when reading both channels from one multichannel TIFF, OME-Zarr, CZI or Imaris file or with --eosin.
+    #[arg(required_unless_present_any = ["list_profiles", "batch_dir", "watch", "shared_norm", "print_config", "write_default_config", "export_lut", "stats_only", "channels", "no_eosin"])]
+    eosin: Option<String>,
+    /// Path to save the output RGB image (e.g., output.tiff), - writes it to stdout in the --format given.
+    #[arg(required_unless_present_any = ["list_profiles", "nucleus_channel", "batch_dir", "watch", "shared_norm", "print_config", "write_default_config", "export_lut", "stats_only", "channels", "eosin_inputs", "no_eosin"])]
+    output: Option<String>,
+    /// Render every pair of images in this directory matched by --nucleus-pattern and --eosin-pattern.
+    #[arg(
+        long,
+        requires_all = ["nucleus_pattern", "eosin_pattern", "output_dir"],
+        conflicts_with_all = ["nucleus", "eosin", "output", "nucleus_channel", "eosin_channel"]
+    )]
+    batch_dir: Option<String>,
+    /// Watch this directory and render the pairs matched by --nucleus-pattern and --eosin-pattern as they arrive, once both files exist and have stopped changing, until interrupted with Ctrl-C. Pairs whose output exists are skipped, and a pair that fails to decode, as a file still being written may, is tried again when its files have settled anew.
+    #[arg(
+        long,
+        value_name = "DIR",
+        requires_all = ["nucleus_pattern", "eosin_pattern", "output_dir"],
+        conflicts_with_all = ["nucleus", "eosin", "output", "batch_dir", "nucleus_channel", "eosin_channel", "stats_only", "shared_norm"]
+    )]
+    watch: Option<String>,
+    /// Filename pattern of the nucleus images of --batch-dir or --watch, with {id} standing for the part shared by a pair (e.g., {id}_dapi.tif).
+    #[arg(long, value_parser = parse_pattern, requires = "pair_dir")]
+    nucleus_pattern: Option<String>,
+    /// Filename pattern of the eosin images of --batch-dir or --watch (e.g., {id}_autof.tif).
+    #[arg(long, value_parser = parse_pattern, requires = "pair_dir")]
+    eosin_pattern: Option<String>,
+    /// Directory to save the outputs of --batch-dir or --watch in, created if missing.
+    #[arg(long, requires = "pair_dir")]
+    output_dir: Option<String>,
+    /// Filename pattern of the outputs of --batch-dir or --watch [default: {id}.tif].
+    #[arg(long, value_parser = parse_pattern, requires = "pair_dir")]
+    output_pattern: Option<String>,
+    /// Number of pairs of --batch-dir rendered at the same time, each with its share of the CPU threads. Every job holds its own images in memory, so raise it only when several fit in RAM.
+    #[arg(long, default_value_t = DEFAULT_JOBS, value_parser = clap::value_parser!(u32).range(1..), requires = "batch_dir")]
+    jobs: u32,
+    /// Print the effective rendering options, after merging --config and the command line, as a parameter file and exit.
+    #[arg(long)]
+    print_config: bool,
+    /// Write a commented parameter file with the default options to this path and exit.
+    #[arg(long, value_name = "PATH", conflicts_with = "print_config")]
+    write_default_config: Option<String>,
+    /// Decode both channels and print their statistics, scaling thresholds and saturated fraction without rendering, no output path is given.
+    #[arg(long, conflicts_with_all = ["batch_dir", "tiled", "stack"])]
+    stats_only: bool,
+    /// Print the statistics of --stats-only as JSON.
+    #[arg(long, requires = "stats_only")]
+    json: bool,
+    /// List the available color profiles and exit.
+    #[arg(long)]
+    list_profiles: bool,
+    /// Write the colors of the color model over a grid of nucleus (rows) and eosin (columns) intensities from 0 to 1 to this path and exit, as an RGB image or a CSV table for a .csv path.
+    #[arg(long, value_name = "PATH")]
+    export_lut: Option<String>,
+    /// Number of intensity steps of --export-lut along each axis.
+    #[arg(long, default_value_t = DEFAULT_LUT_SIZE, requires = "export_lut", value_parser = clap::value_parser!(u32).range(2..=4096))]
+    lut_size: u32,
+    /// How --batch-dir normalizes the images: image (each by its own percentiles) or global (percentiles over all images, from a first pass over the inputs) [default: image].
+    #[arg(long, value_name = "image|global", value_parser = str::parse::<Normalization>, requires = "batch_dir", conflicts_with_all = ["nucleus_range", "eosin_range", "reference", "reference_stats"])]
+    normalize: Option<Normalization>,
+    /// Render the tiles listed in this file with shared thresholds, so that the mosaic of the outputs has no seams: a first pass accumulates the histograms of both channels over all tiles, reading one tile at a time, and the percentiles of the histograms scale every tile. Each line holds the nucleus, eosin and output paths of a tile separated by whitespace (tabs when a path has spaces), relative to the directory of the file. Blank lines and lines starting with # are skipped.
+    #[arg(
+        long,
+        value_name = "LIST",
+        conflicts_with_all = ["nucleus", "eosin", "output", "batch_dir", "nucleus_channel", "eosin_channel", "stats_only", "nucleus_range", "eosin_range", "reference", "reference_stats", "no_normalize"]
+    )]
+    shared_norm: Option<String>,
+    /// Save the histograms of the first pass of --shared-norm to this JSON file.
+    #[arg(long, value_name = "PATH", requires = "shared_norm")]
+    save_norm: Option<String>,
+    /// Read the histograms of --shared-norm from a file written by --save-norm instead of the first pass, e.g. to render the tiles again with other percentiles.
+    #[arg(long, value_name = "PATH", requires = "shared_norm", conflicts_with = "save_norm")]
+    load_norm: Option<String>,
+    /// Contact sheet of the `preview` subcommand.
+    #[arg(skip)]
+    preview: Option<Preview>,
+    /// Service of the `serve` subcommand, also set for the renders of its requests.
+    #[cfg(feature = "server")]
+    #[arg(skip)]
+    serve: Option<server::Serve>,
+    /// Benchmark of the `bench` subcommand.
+    #[arg(skip)]
+    bench: Option<bench::Bench>,
+    #[command(flatten)]
+    render: RenderParams,
+}
 
-    // Normalize the RGB values to [0, 255] for uint8
-    let rgb_uint8 = rgb.mapv(|v| (v * 255.0).min(255.0) as u8);
+impl Args {
+    /// Whether several pairs are rendered, from --batch-dir, --watch or --shared-norm.
+    fn is_batch(&self) -> bool {
+        self.batch_dir.is_some() || self.watch.is_some() || self.shared_norm.is_some()
+    }
 
-    // Release unused objects
-    drop(rgb);
+    /// Whether the arguments are those of `serve` or of the render of one of its requests.
+    fn is_serving(&self) -> bool {
+        #[cfg(feature = "server")]
+        return self.serve.is_some();
+        #[cfg(not(feature = "server"))]
+        false
+    }
 
-    // Save the RGB image
-    let mut output_image = ImageBuffer::new(rgb_uint8.shape()[1] as u32, rgb_uint8.shape()[0] as u32);
-    for (x, y, pixel) in output_image.enumerate_pixels_mut() {
-        let r = rgb_uint8[[y as usize, x as usize, 0]];
-        let g = rgb_uint8[[y as usize, x as usize, 1]];
-        let b = rgb_uint8[[y as usize, x as usize, 2]];
-        *pixel = image::Rgb([r, g, b]);
+    /// Arguments of a subcommand, with none of the modes selected.
+    fn new(render: RenderParams) -> Self {
+        Args {
+            nucleus: None,
+            eosin: None,
+            output: None,
+            batch_dir: None,
+            watch: None,
+            nucleus_pattern: None,
+            eosin_pattern: None,
+            output_dir: None,
+            output_pattern: None,
+            jobs: DEFAULT_JOBS,
+            normalize: None,
+            shared_norm: None,
+            save_norm: None,
+            load_norm: None,
+            print_config: false,
+            write_default_config: None,
+            stats_only: false,
+            json: false,
+            list_profiles: false,
+            export_lut: None,
+            lut_size: DEFAULT_LUT_SIZE,
+            preview: None,
+            #[cfg(feature = "server")]
+            serve: None,
+            bench: None,
+            render,
+        }
     }
+}
 
-    // Release unused objects
-    drop(rgb_uint8);
+impl Mode {
+    /// The arguments of the subcommand as those of the invocation without one.
+    fn into_args(self) -> Args {
+        match self {
+            Mode::Render(render) => Args {
+                nucleus: Some(render.nucleus),
+                eosin: render.eosin,
+                output: render.output,
+                ..Args::new(render.render)
+            },
+            Mode::Batch(batch) if batch.watch => Args {
+                watch: Some(batch.batch_dir),
+                nucleus_pattern: Some(batch.nucleus_pattern),
+                eosin_pattern: Some(batch.eosin_pattern),
+                output_dir: Some(batch.output_dir),
+                output_pattern: batch.output_pattern,
+                ..Args::new(batch.render)
+            },
+            Mode::Batch(batch) => Args {
+                batch_dir: Some(batch.batch_dir),
+                nucleus_pattern: Some(batch.nucleus_pattern),
+                eosin_pattern: Some(batch.eosin_pattern),
+                output_dir: Some(batch.output_dir),
+                output_pattern: batch.output_pattern,
+                jobs: batch.jobs,
+                normalize: batch.normalize,
+                ..Args::new(batch.render)
+            },
+            Mode::Stats(stats) => Args {
+                nucleus: Some(stats.nucleus),
+                eosin: stats.eosin,
+                stats_only: true,
+                json: stats.json,
+                ..Args::new(stats.render)
+            },
+            Mode::Preview(preview) => Args {
+                nucleus: Some(preview.nucleus),
+                eosin: Some(preview.eosin),
+                output: Some(preview.output),
+                preview: Some(Preview {
+                    factor: preview.factor,
+                    size: preview.size as usize,
+                    k_values: preview.k_values,
+                }),
+                ..Args::new(preview.render)
+            },
+            #[cfg(feature = "server")]
+            Mode::Serve(serve) => serve.into_args(),
+            Mode::Bench(bench) => bench.into_args(),
+            Mode::GenerateTestData(_) => unreachable!("test data is generated without rendering arguments"),
+            Mode::Verify(_) => unreachable!("outputs are verified without rendering arguments"),
+        }
+    }
+}
 
-    // Save Image
-    output_image.save(output_path)?;
-    Ok(())
+/// Rendering options shared by all subcommands.
+#[derive(clap::Args, Debug, Clone)]
+#[group(skip)]
+struct RenderParams {
+    /// Input channel rendered as a stain with beta coefficients R,G,B and factor K, repeated for each channel (e.g., 4 to 6 unmixed stains of spectral imaging). Replaces the nucleus and eosin inputs, only the output path is given. Every channel is scaled on its own with --percentile and --floor-percentile, without --gamma-nucleus, --gamma-eosin or --equalize, and all must have the same size.
+    #[arg(
+        long = "channel",
+        value_name = "PATH:R,G,B:K",
+        value_parser = parse_channel,
+        conflicts_with_all = ["tiled", "stack", "extra_channel", "nucleus_channel", "eosin_channel", "reference", "reference_stats"]
+    )]
+    channels: Vec<ChannelSpec>,
+    /// Eosin input with an optional weight as PATH[:WEIGHT], repeated to combine several acquisition channels (e.g., autofluorescence and a stromal stain) into the eosin signal as their weighted sum before scaling. Weights are given for all inputs or for none, which weighs them equally. Replaces the eosin positional argument, the inputs must have the same size.
+    #[arg(
+        long = "eosin",
+        value_name = "PATH[:WEIGHT]",
+        value_parser = parse_eosin_input,
+        conflicts_with_all = ["tiled", "stack", "channels", "reference"]
+    )]
+    eosin_inputs: Vec<EosinInput>,
+    /// Tissue mask whose nonzero pixels are the only ones the percentiles are computed over, while the whole image is scaled, so that the normalization of whole-slide scans does not depend on the amount of glass in view. It must have the size of the inputs.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["tiled", "stack", "channels", "no_eosin"])]
+    mask: Option<String>,
+    /// Compute the tissue mask of --mask with an Otsu threshold of the eosin channel.
+    #[arg(long, conflicts_with_all = ["mask", "tiled", "stack", "channels", "no_eosin"])]
+    auto_mask: bool,
+    /// Render the nucleus channel alone as hematoxylin on a white background, for quick anatomy checks of DAPI-only data. The eosin input is left out, as it is with - as the eosin path.
+    #[arg(long, conflicts_with_all = ["tiled", "stack", "channels", "eosin_inputs", "extra_channel", "eosin_channel"])]
+    no_eosin: bool,
+    /// Flat pink tint in place of the missing eosin channel of --no-eosin, as constant:V with V the scaled eosin intensity in [0, 1] (e.g., constant:0.2), so that the result still reads like tissue.
+    #[arg(long, value_name = "constant:V", value_parser = parse_synthetic_eosin)]
+    synthetic_eosin: Option<f32>,
+    /// Parameter file in TOML, or JSON with a .json extension, setting rendering options under the names of their flags (see --write-default-config). Flags given on the command line take precedence.
+    #[arg(long, value_name = "PATH")]
+    config: Option<String>,
+    /// Number of CPU threads used for processing [default: RAYON_NUM_THREADS if set, otherwise all cores].
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+    /// Memory a render may use, e.g. 8G or 512M (K, M, G and T are powers of 1024). A whole-image render estimated to need more is rendered tile by tile as with --tiled, where the options allow it, and fails before decoding otherwise. The pairs of a batch rendered at the same time share it.
+    #[arg(long, value_name = "SIZE", value_parser = parse_memory)]
+    max_memory: Option<u64>,
+    /// Log every processing step to stderr, -vv also logs the progress of the tile, row and plane loops. Progress bars are hidden while logging.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Do not show progress bars, which are also hidden when stderr is not a terminal.
+    #[arg(long)]
+    quiet: bool,
+    /// Channel of a multichannel (OME-)TIFF, OME-Zarr, CZI or Imaris file to use as nucleus, by index or channel name (e.g., 0 or DAPI).
+    #[arg(long, value_parser = str::parse::<ChannelSelector>)]
+    nucleus_channel: Option<ChannelSelector>,
+    /// Channel of a multichannel (OME-)TIFF, OME-Zarr, CZI or Imaris file to use as eosin, by index or channel name.
+    #[arg(long, value_parser = str::parse::<ChannelSelector>)]
+    eosin_channel: Option<ChannelSelector>,
+    /// Zero-based page of a multi-page TIFF to use as nucleus.
+    #[arg(long, conflicts_with = "nucleus_channel")]
+    nucleus_page: Option<usize>,
+    /// Zero-based page of a multi-page TIFF to use as eosin.
+    #[arg(long, conflicts_with = "eosin_channel")]
+    eosin_page: Option<usize>,
+    /// Dimensions WIDTHxHEIGHT of headerless .raw or .bin inputs, dense row-major arrays of --raw-dtype samples.
+    #[arg(long, value_name = "WxH", value_parser = parse_raw_dims, conflicts_with_all = ["tiled", "stack"])]
+    raw_dims: Option<(usize, usize)>,
+    /// Sample type of .raw or .bin inputs: u8, u16 or f32.
+    #[arg(long, value_name = "u8|u16|f32", default_value = "u16", value_parser = str::parse::<RawSample>, requires = "raw_dims")]
+    raw_dtype: RawSample,
+    /// Byte order of multi-byte samples of .raw or .bin inputs: le (little endian) or be (big endian).
+    #[arg(long, value_name = "le|be", default_value = "le", value_parser = str::parse::<ByteOrder>, requires = "raw_dims")]
+    raw_endian: ByteOrder,
+    /// Resolution level of multiscale OME-Zarr and Imaris inputs, 0 is full resolution.
+    #[arg(long, visible_alias = "ims-level", default_value_t = 0)]
+    level: usize,
+    /// Zero-based scene of CZI inputs, required when a file holds more than one, whose scenes the error lists. CZI inputs require a build with the czi feature (cargo build --release --features czi).
+    #[arg(long, value_name = "N")]
+    scene: Option<usize>,
+    /// Zero-based z plane of Imaris inputs, whose planes the error lists when it is out of range [default: 0]. --stack renders every plane instead. Imaris inputs require a build with the ims feature (cargo build --release --features ims).
+    #[arg(long, value_name = "N", conflicts_with = "stack")]
+    z: Option<usize>,
+    /// Channel to use if the nucleus image is RGB(A): r, g or b [default: convert to grayscale].
+    #[arg(long, value_name = "r|g|b", value_parser = str::parse::<RgbChannel>)]
+    nucleus_rgb_channel: Option<RgbChannel>,
+    /// Channel to use if the eosin image is RGB(A): r, g or b [default: convert to grayscale].
+    #[arg(long, value_name = "r|g|b", value_parser = str::parse::<RgbChannel>)]
+    eosin_rgb_channel: Option<RgbChannel>,
+    /// Input value that maps to full intensity [default: 255 or 65535 for integer images, data maximum for float images].
+    #[arg(long, value_parser = parse_input_max, conflicts_with_all = ["input_bits", "auto_range"])]
+    input_max: Option<f32>,
+    /// Effective bit depth of integer inputs, e.g. 12 for 12bit cameras saving 16bit TIFFs.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=32), conflicts_with = "auto_range")]
+    input_bits: Option<u8>,
+    /// Normalize each input by its actual maximum value instead of the container maximum.
+    #[arg(long)]
+    auto_range: bool,
+    /// Exchange the roles of the two positional inputs, for pairs given as EOSIN NUCLEUS.
+    #[arg(long, conflicts_with_all = ["channels", "no_eosin", "eosin_inputs"])]
+    swap_channels: bool,
+    /// Invert the nucleus image to max - v before the bit depth normalization, for pre-inverted images with a bright background. The max is the input value that maps to full intensity (--input-max, --input-bits or --auto-range), without those the bit depth the data maximum fits, so that 12bit data in a 16bit container is inverted within 12 bits.
+    #[arg(long)]
+    invert_nucleus: bool,
+    /// Invert the eosin image to max - v before the bit depth normalization.
+    #[arg(long)]
+    invert_eosin: bool,
+    /// Crop both channels to their overlapping region instead of failing when their sizes differ.
+    #[arg(long)]
+    crop_to_common: bool,
+    /// Resample channels of different resolutions bilinearly onto a common grid instead of failing when their sizes differ: nucleus or eosin (the grid of that channel), or WxH (both channels, e.g. 2048x1536).
+    #[arg(long, value_name = "nucleus|eosin|WxH", value_parser = str::parse::<ResampleTarget>, conflicts_with_all = ["crop_to_common", "tiled", "stack", "channels", "no_eosin"])]
+    resample_to: Option<ResampleTarget>,
+    /// Largest difference in percent between the aspect ratios of the channels that --resample-to accepts, larger ones indicate different fields of view.
+    #[arg(long, value_name = "PERCENT", default_value = "1", value_parser = parse_tolerance)]
+    aspect_tolerance: f32,
+    /// Process TIFF inputs tile by tile with global normalization and write a tiled TIFF, for images that do not fit in memory.
+    #[arg(long)]
+    tiled: bool,
+    /// Render every plane of multi-page nucleus and eosin TIFF stacks, or of the channels of Imaris volumes selected with --nucleus-channel and --eosin-channel, with matching plane counts.
+    #[arg(long, conflicts_with_all = ["tiled", "nucleus_page", "eosin_page"])]
+    stack: bool,
+    /// How --stack writes the planes: multipage (one multi-page TIFF) or series (output_z0000.tiff, ...) [default: multipage].
+    #[arg(long, value_name = "multipage|series", value_parser = str::parse::<StackOutput>, requires = "stack")]
+    stack_output: Option<StackOutput>,
+    /// How --stack normalizes the planes: plane (each plane on its own) or global (percentiles over the whole volume) [default: plane].
+    #[arg(long, value_name = "plane|global", value_parser = str::parse::<StackScaling>, requires = "stack")]
+    stack_scaling: Option<StackScaling>,
+    /// Write a tiled pyramidal BigTIFF with 2x downsampled levels for whole-slide viewers.
+    #[arg(long, conflicts_with = "tiled")]
+    pyramid: bool,
+    /// Write an OME-TIFF with the image dimensions, pixel size, and rendering parameters in OME-XML.
+    #[arg(long, conflicts_with = "pyramid")]
+    ome: bool,
+    /// Write an OME-Zarr (NGFF) multiscale group with 2x downsampled levels at the output path, for browser-based viewers.
+    #[arg(long, conflicts_with_all = ["pyramid", "ome", "bigtiff"])]
+    output_zarr: bool,
+    /// Edge length of the chunks of --output-zarr in pixels, each chunk holds all three channels [default: 1024].
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), requires = "output_zarr")]
+    zarr_chunk_size: Option<u32>,
+    /// Physical pixel size of the inputs in micrometers as X or X,Y, recorded in the resolution tags of TIFF outputs, by --ome and by --output-zarr and multiplied by --downsample [default: from the OME-XML or resolution tags of the nucleus TIFF]. A warning is printed when the eosin TIFF has a different pixel size.
+    #[arg(long, value_name = "X[,Y]", value_parser = parse_pixel_size)]
+    pixel_size_um: Option<[f32; 2]>,
+    /// Always write a BigTIFF, BigTIFF is otherwise chosen automatically when the output may exceed 4 GB.
+    #[arg(long)]
+    bigtiff: bool,
+    /// Directory to also write each stain rendered alone against white into, as hematoxylin.tiff and eosin.tiff (and extra.tiff with --extra-channel), from the same scaled channels as the composite, for checking the color balance. With --batch-dir the names start with the file stem of the output (e.g., slide1_hematoxylin.tiff).
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["tiled", "stack", "channels", "no_eosin"])]
+    save_components: Option<String>,
+    /// Also write an 8bit PNG or JPEG preview of the rendered image, area-averaged down to a long edge of MAXDIM pixels [default: 1024]. {name} in the path is replaced by the file stem of the output, as it must be with --batch-dir (e.g., thumbs/{name}.jpg).
+    #[arg(long, value_name = "PATH[:MAXDIM]", value_parser = parse_thumbnail, conflicts_with_all = ["tiled", "stack"])]
+    thumbnail: Option<Thumbnail>,
+    /// Also write a Deep Zoom image of the rendered image into DIR for web viewers such as OpenSeadragon: <name>.dzi, named after the file stem of the output, and the tiles of every level in <name>_files/. Tiles are 8bit, RGBA images are composited over white. With --tiled the tiles are written from the same bands as the output.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["stack", "resume"])]
+    output_dzi: Option<String>,
+    /// Edge length of the --output-dzi tiles in pixels, without their overlap [default: 254].
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), requires = "output_dzi")]
+    dzi_tile_size: Option<u32>,
+    /// Pixels each --output-dzi tile shares with each of its neighbors [default: 1].
+    #[arg(long, requires = "output_dzi")]
+    dzi_overlap: Option<u32>,
+    /// Format of the --output-dzi tiles: jpg, with the quality of --jpeg-quality, or png [default: jpg].
+    #[arg(long, value_name = "jpg|png", value_parser = str::parse::<DziFormat>, requires = "output_dzi")]
+    dzi_format: Option<DziFormat>,
+    /// Also write the rendered image into DIR as a DICOM VL Whole Slide Microscopy Image for clinical archives: <name>.dcm, named after the file stem of the output, uncompressed and tiled with one frame per tile. Needs the pixel size of the inputs or --pixel-size-um for the pixel spacing. Samples are 8bit, RGBA images are composited over white. With --tiled the frames are written from the same bands as the output.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["stack", "resume"])]
+    output_dicom: Option<String>,
+    /// Edge length of the --output-dicom frames in pixels [default: 256].
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=65535), requires = "output_dicom")]
+    dicom_tile_size: Option<u32>,
+    /// Patient name of --output-dicom, e.g. Doe^Jane [default: ANONYMOUS].
+    #[arg(long, value_name = "NAME", value_parser = virtualhe::dicom::parse_text, requires = "output_dicom")]
+    patient_name: Option<String>,
+    /// Patient ID of --output-dicom [default: ANONYMOUS].
+    #[arg(long, value_name = "ID", value_parser = virtualhe::dicom::parse_text, requires = "output_dicom")]
+    patient_id: Option<String>,
+    /// Study Instance UID of --output-dicom, to add the image to an existing study [default: a new UID for every image].
+    #[arg(long, value_name = "UID", value_parser = virtualhe::dicom::parse_uid, requires = "output_dicom")]
+    study_uid: Option<String>,
+    /// Accession number of --output-dicom [default: empty].
+    #[arg(long, value_name = "NUMBER", value_parser = virtualhe::dicom::parse_text, requires = "output_dicom")]
+    accession_number: Option<String>,
+    /// Container (slide) identifier of --output-dicom, also recorded as the specimen identifier [default: UNKNOWN].
+    #[arg(long, value_name = "ID", value_parser = virtualhe::dicom::parse_text, requires = "output_dicom")]
+    container_id: Option<String>,
+    /// Draw a scale bar of LENGTH_UM micrometers labelled with its length into a corner of the rendered image: top-left, top-right, bottom-left or bottom-right [default: bottom-right]. Needs the pixel size of the inputs or --pixel-size-um, and is scaled with --downsample. Thickness, margin and label grow with the image size.
+    #[arg(long, value_name = "LENGTH_UM[:CORNER]", value_parser = parse_scale_bar, conflicts_with_all = ["tiled", "stack"])]
+    scale_bar: Option<(f32, Corner)>,
+    /// Color of --scale-bar, black or white, outlined in the other color [default: black].
+    #[arg(long, value_name = "COLOR", requires = "scale_bar")]
+    scale_bar_color: Option<BarColor>,
+    /// GeoJSON file of polygons in pixel coordinates of the inputs (e.g., regions exported from QuPath) whose outlines are drawn onto the rendered image. MultiPolygons and holes are supported, other geometries are skipped, and the polygons are moved and scaled with --roi and --downsample.
+    #[arg(long, value_name = "GEOJSON", conflicts_with_all = ["tiled", "stack"])]
+    annotations: Option<String>,
+    /// 8bit color of the --annotations outlines and fills as R,G,B [default: 0,255,0].
+    #[arg(long, value_name = "R,G,B", value_parser = parse_color, requires = "annotations")]
+    annotation_color: Option<[u8; 3]>,
+    /// Width of the --annotations outlines in output pixels [default: 2].
+    #[arg(long, value_name = "PX", value_parser = clap::value_parser!(u32).range(1..), requires = "annotations")]
+    annotation_width: Option<u32>,
+    /// Also fill the --annotations polygons with their color at this opacity in [0, 1] (e.g., 0.25 for a translucent fill).
+    #[arg(long, value_name = "ALPHA", value_parser = parse_alpha, requires = "annotations")]
+    annotation_fill: Option<f32>,
+    /// Write an RGBA PNG or TIFF whose alpha channel makes the glass transparent, for compositing the image over other layers of a figure. JPEG outputs have no alpha channel.
+    #[arg(long, conflicts_with_all = ["tiled", "stack", "pyramid", "output_zarr", "ome"])]
+    rgba: bool,
+    /// How the alpha channel of --rgba is derived: luminance (white is transparent and stained pixels are as opaque as they are dark, with the colors unmixed from white so that the image over white looks unchanged) or mask (the tissue of --mask or --auto-mask is opaque, everything else transparent) [default: luminance].
+    #[arg(long, value_name = "luminance|mask", value_parser = str::parse::<AlphaRule>, requires = "rgba")]
+    alpha_rule: Option<AlphaRule>,
+    /// Edge length of tiles in pixels, must be a multiple of 16 [default: 2048 for --tiled, 512 for --pyramid].
+    #[arg(long, value_parser = parse_tile_size)]
+    tile_size: Option<u32>,
+    /// Bits per channel of the output image: 8, or 16 for TIFF and PNG outputs.
+    #[arg(long, value_name = "8|16", default_value = "8", value_parser = str::parse::<OutputDepth>)]
+    output_depth: OutputDepth,
+    /// Encoding of the output colors: srgb (the sRGB curve viewers assume) or linear (the transmittance values as they are, darker and more saturated in viewers).
+    #[arg(long, value_name = "linear|srgb", default_value = "srgb", value_parser = str::parse::<ColorEncoding>)]
+    color_encoding: ColorEncoding,
+    /// Evaluate the exponentials of the color model directly instead of interpolating them in lookup tables, slower and within a fraction of a gray level of the default.
+    #[arg(long)]
+    exact: bool,
+    /// Dithering of the quantization of the colors to the output samples, against the banding of smooth gradients at 8 bits: none, ordered (an 8x8 Bayer matrix, computed for each pixel on its own) or floyd-steinberg (error diffusion, row after row, not available with --tiled).
+    #[arg(long, value_name = "none|ordered|floyd-steinberg", default_value = "none", value_parser = str::parse::<Dither>)]
+    dither: Dither,
+    /// Generate the RGB image on the GPU in bands of rows, falling back to the CPU with a warning without a GPU adapter. Requires a build with the gpu feature (cargo build --release --features gpu).
+    #[arg(long, conflicts_with_all = ["tiled", "stack"])]
+    gpu: bool,
+    /// Format of the output written to stdout with - as the output path: tiff or png. Lines are printed to stderr instead.
+    #[arg(long, value_name = "tiff|png", value_parser = str::parse::<StreamFormat>, conflicts_with_all = ["tiled", "stack", "output_zarr"])]
+    format: Option<StreamFormat>,
+    /// Overwrite existing outputs, which are kept with an error otherwise.
+    #[arg(long)]
+    force: bool,
+    /// Continue an earlier run that was interrupted or died: skip the pairs of a batch whose output and provenance file exist and record the same inputs, not modified since, and settings, and continue a tiled render from the journal of its partial output, keeping the partial output when the render fails or is interrupted. Outputs that are not complete are replaced.
+    #[arg(long)]
+    resume: bool,
+    /// Create the missing directories of the output and thumbnail paths, which are an error otherwise.
+    #[arg(long)]
+    create_dirs: bool,
+    /// Number of times a request for an https:// or s3:// input or output that fails on the network, times out or gets a server error is retried, waiting 1, 2, 4... seconds in between [default: 3]. Requires a build with the remote feature.
+    #[arg(long, value_name = "N")]
+    retries: Option<u32>,
+    /// Write a provenance file <output>.json next to each output, on by default except for previews and output to stdout. It holds a JSON object with schema_version (1, incremented when a field changes meaning or is removed), tool, version, output, inputs (role, path, sha256, and for the rendered channels width, height, pixel_format, bits_per_sample, input_max, floor_percentile, percentile, floor and ceiling in input units, auto_contrast, and gamma), color_model (color_encoding, exact, dither, and stains with name, k and beta), checksum (of --checksum, null without), settings (the effective settings as printed by --print-config), phases (name and seconds) and total_seconds.
+    #[arg(long, overrides_with = "no_provenance")]
+    provenance: bool,
+    /// Do not write the provenance file of --provenance.
+    #[arg(long)]
+    no_provenance: bool,
+    /// Compute the SHA-256 of the samples of the output before encoding, which does not depend on the byte layout of the file, and of the encoded file, print both and record them in the provenance file with the size and bit depth of the samples, for `virtualhe verify`.
+    #[arg(long, conflicts_with_all = ["tiled", "stack", "output_zarr"])]
+    checksum: bool,
+    /// Quality of JPEG outputs, in 1..=100 [default: 90].
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: Option<u8>,
+    /// Compression of TIFF outputs: none, lzw, deflate, or zstd [default: deflate].
+    #[arg(long, value_name = "none|lzw|deflate|zstd", value_parser = str::parse::<TiffCompression>)]
+    compression: Option<TiffCompression>,
+    /// Color profile preset selecting the beta matrix and k (see --list-profiles).
+    #[arg(long, default_value = "he-classic", value_parser = str::parse::<Profile>)]
+    profile: Profile,
+    /// K arbitrary factor to adjust color profile of H&E, sets both channels [default: from profile, 2.5 for he-classic]. The color saturates as k times the scaled intensity grows, with --no-normalize data that does not reach 1 needs a higher k.
+    #[arg(short, value_parser = parse_k)]
+    k: Option<f32>,
+    /// K factor for the nucleus (hematoxylin) channel, overrides -k.
+    #[arg(long, value_parser = parse_k)]
+    k_nucleus: Option<f32>,
+    /// K factor for the eosin channel, overrides -k.
+    #[arg(long, value_parser = parse_k)]
+    k_eosin: Option<f32>,
+    /// Estimate the k of each channel from its scaled intensities, so that its median tissue pixel (above an Otsu threshold) renders at --target-transmittance in the green channel. A channel with --k-nucleus or --k-eosin keeps that k.
+    #[arg(long, conflicts_with_all = ["k", "tiled", "stack", "channels", "no_eosin"])]
+    auto_k: bool,
+    /// Green transmittance in (0, 1) that --auto-k maps the median tissue pixel of each channel to [default: 0.55].
+    #[arg(long, value_parser = parse_transmittance)]
+    target_transmittance: Option<f32>,
+    /// Saturation percentile used to scale both channels, in (0, 100] where 100 is the true maximum.
+    #[arg(long, default_value = "99.999", value_parser = parse_percentile)]
+    percentile: f32,
+    /// Saturation percentile for the nucleus channel, overrides --percentile.
+    #[arg(long, value_parser = parse_percentile)]
+    percentile_nucleus: Option<f32>,
+    /// Saturation percentile for the eosin channel, overrides --percentile.
+    #[arg(long, value_parser = parse_percentile)]
+    percentile_eosin: Option<f32>,
+    /// Background floor percentile subtracted from both channels before scaling, in [0, 100).
+    #[arg(long, value_parser = parse_floor_percentile)]
+    floor_percentile: Option<f32>,
+    /// Background floor percentile for the nucleus channel, overrides --floor-percentile.
+    #[arg(long, value_parser = parse_floor_percentile)]
+    floor_percentile_nucleus: Option<f32>,
+    /// Background floor percentile for the eosin channel, overrides --floor-percentile.
+    #[arg(long, value_parser = parse_floor_percentile)]
+    floor_percentile_eosin: Option<f32>,
+    /// Flat-field image of the nucleus channel (e.g., of a uniform fluorescent slide) the nucleus image is divided by before scaling, against the brightness grid of vignetted tile scans. It is normalized to a mean of 1 and must have the size of the input.
+    #[arg(long, value_name = "PATH", conflicts_with = "tiled")]
+    flatfield_nucleus: Option<String>,
+    /// Flat-field image of the eosin channel the eosin image is divided by before scaling.
+    #[arg(long, value_name = "PATH", conflicts_with = "tiled")]
+    flatfield_eosin: Option<String>,
+    /// Dark-field image (e.g., with the shutter closed) subtracted from both inputs and flat-fields before the flat-field division.
+    #[arg(long, value_name = "PATH", conflicts_with = "tiled")]
+    darkfield: Option<String>,
+    /// Translation DX,DY in pixels applied to the eosin channel to register it onto the nucleus channel, e.g. of sequential scans whose offset renders purple and pink fringes at nucleus boundaries. Fractional shifts are interpolated bilinearly, pixels shifted in from outside are 0.
+    #[arg(long, value_name = "DX,DY", value_parser = parse_shift, allow_hyphen_values = true, conflicts_with_all = ["tiled", "stack", "channels", "no_eosin"])]
+    shift_eosin: Option<[f32; 2]>,
+    /// Estimate the integer shift of --shift-eosin within 32 pixels by maximizing the normalized cross-correlation of the channels.
+    #[arg(long, conflicts_with_all = ["shift_eosin", "tiled", "stack", "channels", "no_eosin"])]
+    auto_align: bool,
+    /// Region of interest as X,Y,WIDTH,HEIGHT in pixels of the input images that both channels are cropped to after decoding, before any scaling.
+    #[arg(long, value_name = "X,Y,WIDTH,HEIGHT", value_parser = parse_roi, conflicts_with = "tiled")]
+    roi: Option<Roi>,
+    /// Pixels the percentiles of --roi are computed over: roi (the cropped region) or full (the whole image, with the region cropped after scaling) [default: roi].
+    #[arg(long, value_name = "roi|full", value_parser = str::parse::<RoiStats>, requires = "roi", conflicts_with = "stack")]
+    roi_stats: Option<RoiStats>,
+    /// Bleed-through coefficients A,B between the normalized channels subtracted before scaling: eosin becomes eosin - A*nucleus and nucleus becomes nucleus - B*eosin, clamped to 0, e.g. 0.15,0 against DAPI bleeding into the autofluorescence channel, which renders nuclei pink.
+    #[arg(long, value_name = "A,B", default_value = "0,0", value_parser = parse_crosstalk, conflicts_with_all = ["tiled", "stack", "reference", "channels", "no_eosin"])]
+    crosstalk: [f32; 2],
+    /// Edge length of a median filter, 3 or 5, applied to the input channels before scaling to remove hot pixels that would render as dark dots.
+    #[arg(long, value_name = "3|5", value_parser = parse_despeckle, conflicts_with = "tiled")]
+    despeckle: Option<usize>,
+    /// Apply --despeckle to only this channel: nucleus or eosin [default: both].
+    #[arg(long, value_name = "nucleus|eosin", value_parser = str::parse::<InputChannel>, requires = "despeckle")]
+    despeckle_channel: Option<InputChannel>,
+    /// Standard deviation in pixels of a Gaussian blur applied to the nucleus channel before scaling, against shot noise, 0 is off.
+    #[arg(long, value_name = "SIGMA", default_value = "0", value_parser = parse_sigma, conflicts_with = "tiled")]
+    blur_nucleus: f32,
+    /// Standard deviation in pixels of a Gaussian blur applied to the eosin channel before scaling, against pink speckle from shot noise, 0 is off.
+    #[arg(long, value_name = "SIGMA", default_value = "0", value_parser = parse_sigma, conflicts_with = "tiled")]
+    blur_eosin: f32,
+    /// Factor both channels are downsampled by right after decoding, averaging blocks of N x N pixels, for quick previews and overview images of whole slides [default: 1].
+    #[arg(long, value_name = "N", value_parser = parse_downsample, conflicts_with = "tiled")]
+    downsample: Option<u32>,
+    /// Fixed intensity window of the nucleus channel in input units (e.g., 100,4000) used instead of the percentiles, for quantitative comparisons between images. Values outside the window clamp to 0 and 1.
+    #[arg(long, value_name = "MIN,MAX", value_parser = parse_range, conflicts_with_all = ["percentile_nucleus", "floor_percentile_nucleus"])]
+    nucleus_range: Option<[f32; 2]>,
+    /// Fixed intensity window of the eosin channel in input units, used instead of the percentiles.
+    #[arg(long, value_name = "MIN,MAX", value_parser = parse_range, conflicts_with_all = ["percentile_eosin", "floor_percentile_eosin"])]
+    eosin_range: Option<[f32; 2]>,
+    /// Nucleus and eosin reference images as NUCLEUS,EOSIN whose percentiles select fixed ranges applied to every image of the run, for consistent scaling of serial sections.
+    #[arg(long, value_name = "NUCLEUS,EOSIN", value_parser = parse_reference, conflicts_with_all = ["nucleus_range", "eosin_range", "reference_stats"])]
+    reference: Option<(String, String)>,
+    /// Statistics written by --stats-only --json whose floors and ceilings are applied as fixed ranges to every image of the run.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["nucleus_range", "eosin_range"])]
+    reference_stats: Option<String>,
+    /// Gamma of the nucleus channel applied as v^(1/gamma) after scaling, above 1 brightens dim signal without blowing out bright signal.
+    #[arg(long, default_value = "1.0", value_parser = parse_gamma)]
+    gamma_nucleus: f32,
+    /// Gamma of the eosin channel applied as v^(1/gamma) after scaling, e.g. 2.2 to bring out dim parenchyma next to bright collagen.
+    #[arg(long, default_value = "1.0", value_parser = parse_gamma)]
+    gamma_eosin: f32,
+    /// Equalize the histograms of the channels after the bit depth normalization, before the percentile scaling, for sections whose brightness falls off with depth so that no linear window fits: global remaps the intensities by their cumulative distribution over the image (over the --mask if given), clahe by those of the tiles around each pixel with the counts of the histograms clipped (--clahe-tile-size, --clahe-clip). The percentiles then select the window of the equalized intensities from 0 to 1.
+    #[arg(long, value_name = "global|clahe", value_parser = str::parse::<Equalization>, conflicts_with_all = ["tiled", "stack", "nucleus_range", "eosin_range", "reference", "reference_stats"])]
+    equalize: Option<Equalization>,
+    /// Apply --equalize to only this channel: nucleus or eosin [default: both].
+    #[arg(long, value_name = "nucleus|eosin", value_parser = str::parse::<InputChannel>, requires = "equalize")]
+    equalize_channel: Option<InputChannel>,
+    /// Edge length in pixels of the tiles of --equalize clahe. The last tiles end at the image borders, overlapping the ones before them.
+    #[arg(long, value_name = "PX", default_value_t = DEFAULT_CLAHE_TILE_SIZE, value_parser = parse_clahe_tile_size, requires = "equalize")]
+    clahe_tile_size: usize,
+    /// Clip limit of --equalize clahe as a multiple of the mean count of a histogram bin, at least 1: higher limits equalize more strongly and amplify the noise of flat regions more.
+    #[arg(long, value_name = "LIMIT", default_value_t = DEFAULT_CLAHE_CLIP_LIMIT, value_parser = parse_clahe_clip, requires = "equalize")]
+    clahe_clip: f32,
+    /// Render the decoded channels as they are after the bit depth normalization (--input-max, --input-bits), without percentile scaling, for inputs that are normalized upstream. Channels with a fixed range keep it. Dim unscaled data renders pale, which a higher -k compensates.
+    #[arg(long, conflicts_with_all = [
+        "percentile", "percentile_nucleus", "percentile_eosin", "floor_percentile", "floor_percentile_nucleus",
+        "floor_percentile_eosin", "reference", "reference_stats"
+    ])]
+    no_normalize: bool,
+    /// Select the window of each channel automatically instead of by --percentile and --floor-percentile: otsu takes the pixels above the Otsu threshold between background and tissue of the channel (among those of --mask or --auto-mask if given, so that glass never weighs in), and scales the channel from the 1st to the 99.9th percentile of these tissue pixels. The thresholds and windows are reported by --stats-only.
+    #[arg(long, value_name = "otsu", value_parser = str::parse::<AutoContrast>, conflicts_with_all = [
+        "percentile", "percentile_nucleus", "percentile_eosin", "floor_percentile", "floor_percentile_nucleus",
+        "floor_percentile_eosin", "nucleus_range", "eosin_range", "reference", "reference_stats", "no_normalize", "tiled", "stack"
+    ])]
+    auto_contrast: Option<AutoContrast>,
+    /// How NaN pixels are handled: zero (replace with 0), error (fail), or ignore (exclude from percentiles).
+    #[arg(long, value_name = "zero|error|ignore", default_value = "zero", value_parser = str::parse::<NanPolicy>)]
+    nan_policy: NanPolicy,
+    /// How the values at the percentiles are taken from the sorted intensities: linear (interpolated between the two nearest ranks, as numpy.percentile), lower, nearest (the rank below or nearest the fractional rank), or legacy (the rank selected by earlier versions, to reproduce their renders).
+    #[arg(long, value_name = "linear|lower|nearest|legacy", default_value = "linear", value_parser = str::parse::<PercentileMethod>)]
+    percentile_method: PercentileMethod,
+    /// Warn when more than this percentage of the pixels of a channel saturate after scaling.
+    #[arg(long, value_name = "PERCENT", default_value = "1", value_parser = parse_saturation)]
+    saturation_warning: f32,
+    /// Fail when more than this percentage of the pixels of a channel saturate after scaling (e.g., 0.5%).
+    #[arg(long, value_name = "PERCENT", value_parser = parse_saturation)]
+    strict_saturation: Option<f32>,
+    /// Hematoxylin beta coefficients as red,green,blue [default: from profile].
+    #[arg(long, value_name = "R,G,B", value_parser = parse_beta)]
+    beta_hematoxylin: Option<[f32; 3]>,
+    /// Eosin beta coefficients as red,green,blue [default: from profile].
+    #[arg(long, value_name = "R,G,B", value_parser = parse_beta)]
+    beta_eosin: Option<[f32; 3]>,
+    /// Third fluorescence channel (e.g., a marker) rendered as a brown DAB-like stain on top of H&E, for virtual IHC. It is scaled with --percentile and --floor-percentile and must have the size of the nucleus image.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["tiled", "stack"])]
+    extra_channel: Option<String>,
+    /// Beta coefficients of the extra channel as red,green,blue [default: 0.268,0.57,0.776 for DAB].
+    #[arg(long, value_name = "R,G,B", value_parser = parse_beta, requires = "extra_channel")]
+    extra_beta: Option<[f32; 3]>,
+    /// K factor of the extra channel [default: 2.5].
+    #[arg(long, value_parser = parse_k, requires = "extra_channel")]
+    extra_k: Option<f32>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Parse command-line arguments
-    let args = Args::parse();
-
-    // Initialize image reader
-    let mut nucleus_image = ImageReader::open(&args.nucleus)?;
-    let mut eosin_image = ImageReader::open(&args.eosin)?;
-
-    // Remove file size and memory limits to enable processing of large images
-    nucleus_image.no_limits();
-    eosin_image.no_limits();
-
-    // Decode the image
-    let nucleus_image = nucleus_image.decode()?;
-    let eosin_image = eosin_image.decode()?;
-
-    // Read images into ndarray
-    println!("Reading {}", &args.nucleus);
-    let mut nucleus = match nucleus_image {
-        DynamicImage::ImageLuma16(image) => Array2::<f32>::from_shape_vec(
-            (image.height() as usize, image.width() as usize),
-            image.pixels().map(|p| p[0] as f32 / 65535.0).collect(),
-        )?,
-        DynamicImage::ImageLuma8(image) => Array2::<f32>::from_shape_vec(
-            (image.height() as usize, image.width() as usize),
-            image.pixels().map(|p| p[0] as f32 / 255.0).collect(),
-        )?,
-        _ => panic!("Nucleus image must be grayscale!"),
-    };
+/// Process exit code of an error: 2 for invalid options as for invalid arguments rejected by clap,
+/// then one code per kind of library error, 130 for an interrupt as shells report SIGINT, and 1
+/// for anything else.
+fn exit_code(error: &(dyn std::error::Error + 'static)) -> u8 {
+    match error.downcast_ref::<Error>() {
+        Some(Error::Interrupted(_)) => INTERRUPTED,
+        Some(Error::InvalidOptions(_)) => 2,
+        Some(Error::Open { .. }) => 3,
+        Some(Error::Decode { .. }) => 4,
+        Some(Error::UnsupportedFormat { .. }) => 5,
+        Some(Error::ShapeMismatch { .. } | Error::SizeMismatch { .. }) => 6,
+        Some(Error::Scale { .. }) => 7,
+        Some(Error::Write { .. }) => 8,
+        None => 1,
+    }
+}
+
+/// Exit code of an interrupted run.
+const INTERRUPTED: u8 = 130;
+
+/// Whether the run checks for interrupts, so that the first one stops its render at the next check
+/// instead of exiting the process.
+static CANCELLABLE: AtomicBool = AtomicBool::new(false);
 
-    println!("Reading {}", &args.eosin);
-    let mut eosin = match eosin_image {
-        DynamicImage::ImageLuma16(image) => Array2::<f32>::from_shape_vec(
-            (image.height() as usize, image.width() as usize),
-            image.pixels().map(|p| p[0] as f32 / 65535.0).collect(),
-        )?,
-        DynamicImage::ImageLuma8(image) => Array2::<f32>::from_shape_vec(
-            (image.height() as usize, image.width() as usize),
-            image.pixels().map(|p| p[0] as f32 / 255.0).collect(),
-        )?,
-        _ => panic!("Eosin image must be grayscale!"),
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(exit_code(e.as_ref()))
+        }
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    // Parse command-line arguments, a parameter file fills in the options they leave out
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let matches = matches.subcommand().map_or(&matches, |(_, matches)| matches);
+    let mode = match cli.mode {
+        Some(Mode::GenerateTestData(generate)) => return synthetic::generate(&generate),
+        Some(Mode::Verify(verify)) => return checksum::verify(&verify),
+        mode => mode,
     };
+    let mut args = mode.map_or(cli.args, Mode::into_args);
+    checks::check_modes(&args)?;
+    if let Some(path) = &args.write_default_config {
+        fs::write(path, config::DEFAULT_CONFIG).map_err(|e| Error::Write {
+            path: path.into(),
+            message: format!("{}: {}", path, e),
+        })?;
+        println!("Wrote default parameter file to {}", path);
+        return Ok(());
+    }
+    if let Some(path) = args.render.config.clone() {
+        config::Config::load(&path)?.apply(&mut args.render, matches)?;
+    }
+    if let Some(preview) = &args.preview {
+        args.render.downsample.get_or_insert(preview.factor);
+    }
+    if args.print_config {
+        print!("{}", config::effective(&args.render));
+        return Ok(());
+    }
 
-    // Apply histogram scaling
-    println!{"Scaling channels"}
-    nucleus = apply_histogram_scaling(nucleus, 99.999);
-    eosin = apply_histogram_scaling(eosin, 99.999);
+    // - as the eosin path stands for --no-eosin, with the output path after it
+    if args.eosin.as_deref() == Some("-") {
+        let render = &args.render;
+        if render.tiled || render.stack || args.stats_only || render.extra_channel.is_some() || render.eosin_channel.is_some() {
+            return Err(Error::InvalidOptions(
+                "nucleus-only rendering is not available with --tiled, --stack, --stats-only, --extra-channel or --eosin-channel"
+                    .to_string(),
+            )
+            .into());
+        }
+        args.render.no_eosin = true;
+        args.eosin = args.output.take();
+    }
+    if args.render.synthetic_eosin.is_some() && !args.render.no_eosin {
+        return Err(Error::InvalidOptions("--synthetic-eosin requires --no-eosin or - as the eosin path".to_string()).into());
+    }
+
+    if args.list_profiles {
+        for profile in Profile::ALL {
+            let params = profile.params();
+            println!("{:<14} {}", profile.name(), profile.description());
+            println!(
+                "{:<14} k={}/{} hematoxylin={:?} eosin={:?}",
+                "", params.k_nucleus, params.k_eosin, params.beta[0], params.beta[1]
+            );
+        }
+        return Ok(());
+    }
 
-    // Generate virtual H&E image
-    println!{"Calculating and Saving vH&E"}
-    generate_virtual_he(nucleus, eosin, Path::new(&args.output),args.k)?;
-    println!("Virtual H&E image saved to: {}", args.output);
+    // RUST_LOG overrides the level set by -v
+    let level = match args.render.verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new().filter_level(level).parse_default_env().init();
+    // Outputs are renamed into place when complete, an interrupt deletes the incomplete ones. A
+    // watch stops after the pair it renders and a render at its next check, unless interrupted
+    // again, a server or benchmark at once
+    if let Err(e) = ctrlc::set_handler(|| {
+        if watch::stop() {
+            return;
+        }
+        if CANCELLABLE.load(Ordering::SeqCst) && virtualhe::interrupt::request() {
+            eprintln!("Interrupting the render at its next check, interrupt again to stop now");
+            return;
+        }
+        virtualhe::atomic::remove_pending();
+        std::process::exit(INTERRUPTED.into());
+    }) {
+        log::warn!("Interrupts cannot be handled, an interrupt may leave temporary files behind: {}", e);
+    }
+
+    // The thread pool is configured before any parallel work runs, rayon reads RAYON_NUM_THREADS otherwise
+    if let Some(threads) = args.render.threads {
+        rayon::ThreadPoolBuilder::new().num_threads(threads as usize).build_global()?;
+    }
 
+    #[cfg(feature = "server")]
+    if let Some(serve) = &args.serve {
+        return server::run(&args, serve, matches);
+    }
+    if let Some(bench) = &args.bench {
+        return bench::run(&args, bench);
+    }
+    // Inputs at URLs are downloaded before the render can be interrupted at its checks, so that an
+    // interrupt stops a download at once
+    #[cfg(feature = "remote")]
+    virtualhe::remote::set_retries(args.render.retries.unwrap_or(virtualhe::remote::DEFAULT_RETRIES));
+    let staged = staging::stage(&mut args)?;
+    CANCELLABLE.store(true, Ordering::SeqCst);
+    run_args(&args)?;
+    #[cfg(feature = "remote")]
+    staging::publish(&staged, &args)?;
+    drop(staged);
     Ok(())
 }
+
+/// Run the mode the arguments select once the process is set up, for the command line or for a
+/// request of `serve`.
+fn run_args(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(path) = &args.export_lut {
+        return output::export_lut(args, path);
+    }
+    let paths = job::paths(args)?;
+    checks::check_args(args, paths.as_ref())?;
+    let job = Job::new(args, paths.as_ref())?;
+    match paths {
+        Some((_, _, output_path)) if !args.render.channels.is_empty() => {
+            render::render_channels(args, &job, &job.progress, &output_path)
+        }
+        Some((nucleus_path, _, output_path)) if args.render.no_eosin => {
+            render::render_nucleus_only(args, &job, &job.progress, &nucleus_path, &output_path)
+        }
+        Some((nucleus_path, eosin_path, _)) if args.stats_only => {
+            stats::print_stats(args, &job, &nucleus_path, &eosin_path)
+        }
+        Some((nucleus_path, eosin_path, output_path)) if args.bench.is_some() => {
+            let bench = args.bench.as_ref().expect("checked by the guard");
+            bench::render(args, &job, bench, &nucleus_path, &eosin_path, &output_path)
+        }
+        Some((nucleus_path, eosin_path, output_path)) => {
+            render::render_pair(args, &job, &job.progress, &nucleus_path, &eosin_path, &output_path)
+        }
+        None if args.shared_norm.is_some() => batch::run_shared_norm(args, &job),
+        None if args.watch.is_some() => watch::run(args, &job),
+        None => batch::run_batch(args, &job),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a command line into the subcommand, if any, and the arguments of the invocation.
+    fn parse(line: &str) -> Result<(Option<Mode>, Args), clap::Error> {
+        let matches = Cli::command().try_get_matches_from(std::iter::once("virtualhe").chain(line.split_whitespace()))?;
+        let cli = Cli::from_arg_matches(&matches)?;
+        Ok((cli.mode, cli.args))
+    }
+
+    /// Arguments of a command line as `run` checks them, of the subcommand if any.
+    fn parse_args(line: &str) -> Args {
+        let (mode, args) = parse(line).unwrap_or_else(|e| panic!("{}: {}", line, e));
+        mode.map_or(args, Mode::into_args)
+    }
+
+    /// Message of the mode check of a command line that clap accepts.
+    fn mode_error(line: &str) -> String {
+        checks::check_modes(&parse_args(line)).expect_err(line).to_string()
+    }
+
+    #[test]
+    fn legacy_positional_arguments_render_a_pair() {
+        let args = parse_args("nucleus.tif eosin.tif output.tif --percentile 99.5");
+        assert_eq!(args.nucleus.as_deref(), Some("nucleus.tif"));
+        assert_eq!(args.eosin.as_deref(), Some("eosin.tif"));
+        assert_eq!(args.output.as_deref(), Some("output.tif"));
+        assert_eq!(args.render.percentile, 99.5);
+        assert!(!args.is_batch() && !args.stats_only && args.preview.is_none() && args.bench.is_none());
+        checks::check_modes(&args).unwrap();
+        assert!(parse("nucleus.tif eosin.tif").is_err());
+        assert_eq!(parse_args("--channel a.tif:1,1,1:2 output.tif").nucleus.as_deref(), Some("output.tif"));
+    }
+
+    #[test]
+    fn render_subcommand_takes_the_legacy_arguments() {
+        let legacy = parse_args("nucleus.tif eosin.tif output.tif --gamma-eosin 2.2");
+        let render = parse_args("render nucleus.tif eosin.tif output.tif --gamma-eosin 2.2");
+        assert_eq!((&render.nucleus, &render.eosin, &render.output), (&legacy.nucleus, &legacy.eosin, &legacy.output));
+        assert_eq!(render.render.gamma_eosin, legacy.render.gamma_eosin);
+        assert!(parse("render nucleus.tif").is_err());
+    }
+
+    #[test]
+    fn batch_subcommand_sets_the_batch_arguments() {
+        let args = parse_args("batch in --nucleus-pattern {id}_n.tif --eosin-pattern {id}_e.tif --output-dir out --jobs 3");
+        assert_eq!((args.batch_dir.as_deref(), args.watch.as_deref()), (Some("in"), None));
+        assert_eq!((args.nucleus_pattern.as_deref(), args.eosin_pattern.as_deref()), (Some("{id}_n.tif"), Some("{id}_e.tif")));
+        assert_eq!((args.output_dir.as_deref(), args.jobs), (Some("out"), 3));
+        assert!(args.is_batch());
+        let watch = parse_args("batch in --nucleus-pattern {id}_n.tif --eosin-pattern {id}_e.tif --output-dir out --watch");
+        assert_eq!((watch.batch_dir.as_deref(), watch.watch.as_deref()), (None, Some("in")));
+        assert!(parse("batch in --nucleus-pattern {id}_n.tif --output-dir out").is_err());
+        assert!(parse("batch in --nucleus-pattern {id}_n.tif --eosin-pattern {id}_e.tif --output-dir out --watch --jobs 2").is_err());
+    }
+
+    #[test]
+    fn stats_preview_and_bench_subcommands_select_their_modes() {
+        let stats = parse_args("stats nucleus.tif eosin.tif --json");
+        assert!(stats.stats_only && stats.json && stats.output.is_none());
+        let preview = parse_args("preview nucleus.tif eosin.tif sheet.png --factor 2 --k-values 1.5,2.5");
+        let expected = Preview { factor: 2, size: DEFAULT_PREVIEW_SIZE as usize, k_values: vec![1.5, 2.5] };
+        assert_eq!((preview.preview, preview.output.as_deref()), (Some(expected), Some("sheet.png")));
+        assert!(parse_args("bench --size 64x32 --iterations 2").bench.is_some());
+        assert!(matches!(parse("generate-test-data n.tif e.tif --size 64"), Ok((Some(Mode::GenerateTestData(_)), _))));
+        assert!(matches!(parse("verify output.tif"), Ok((Some(Mode::Verify(_)), _))));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn serve_subcommand_selects_serving() {
+        let args = parse_args("serve --listen 127.0.0.1:0 --max-renders 2");
+        assert!(args.is_serving() && args.nucleus.is_none());
+    }
+
+    #[test]
+    fn subcommands_conflict_with_the_arguments_of_the_invocation() {
+        assert!(parse("--stats-only render nucleus.tif eosin.tif output.tif").is_err());
+        assert!(parse("nucleus.tif eosin.tif output.tif --batch-dir in").is_err());
+        assert!(parse("nucleus.tif eosin.tif output.tif --tiled --stats-only").is_err());
+    }
+
+    #[test]
+    fn modes_reject_the_options_they_cannot_apply() {
+        let batch = "batch in --nucleus-pattern {id}_n.tif --eosin-pattern {id}_e.tif --output-dir out";
+        assert_eq!(mode_error(&format!("{} --extra-channel cd3.tif", batch)), "--extra-channel is not available in batch runs");
+        assert_eq!(mode_error(&format!("{} --swap-channels", batch)), "--swap-channels is not available in batch runs");
+        assert_eq!(
+            mode_error("--batch-dir in --nucleus-pattern {id}_n.tif --eosin-pattern {id}_e.tif --output-dir out --format png"),
+            "--format is not available in batch runs"
+        );
+        assert_eq!(mode_error("stats nucleus.tif eosin.tif --rgba"), "--rgba is not available with statistics");
+        assert_eq!(mode_error("nucleus.tif eosin.tif --stats-only --thumbnail t.png"), "--thumbnail is not available with statistics");
+        assert_eq!(mode_error("preview nucleus.tif eosin.tif sheet.png --tiled"), "--tiled is not available in previews");
+        assert_eq!(mode_error("preview nucleus.tif eosin.tif sheet.png --output-depth 16"), "--output-depth 16 is not available in previews");
+        assert_eq!(mode_error("bench --mask mask.tif"), "--mask is not available in benchmarks");
+        assert_eq!(mode_error(&format!("{} --normalize global --no-normalize", batch)), "--no-normalize is not available with --normalize");
+        assert!(checks::check_modes(&parse_args("preview nucleus.tif eosin.tif sheet.png --gpu")).is_ok());
+    }
+}
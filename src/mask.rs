@@ -0,0 +1,81 @@
+//! Tissue masks that restrict the percentile computation of the scaling to tissue pixels, so that
+//! the normalization of whole-slide scans does not depend on how much empty glass is in view.
+use crate::{read_raw, Error, LoadOptions};
+use log::debug;
+use ndarray::parallel::prelude::*;
+use ndarray::Array2;
+use std::path::Path;
+
+/// Number of histogram bins of `otsu_threshold`.
+const OTSU_BINS: usize = 256;
+
+/// Read a mask image, in which every nonzero pixel is tissue.
+pub fn read_mask<P: AsRef<Path>>(path: P) -> Result<Array2<bool>, Error> {
+    let path = path.as_ref();
+    let (values, _, _) = read_raw(path, &LoadOptions::default()).map_err(|e| Error::decode(path, e))?;
+    let mask = values.mapv(|v| v != 0.0 && !v.is_nan());
+    debug!("{}: mask selects {} of {} pixels", path.display(), mask.iter().filter(|&&m| m).count(), mask.len());
+    Ok(mask)
+}
+
+/// Downsample a mask by `factor` as `filter::bin_average` does an image, keeping the blocks of
+/// which at least half the pixels are tissue.
+pub fn downsample_mask(mask: &Array2<bool>, factor: usize) -> Array2<bool> {
+    let fraction = crate::filter::bin_average(&mask.mapv(f32::from), factor);
+    fraction.mapv(|f| f >= 0.5)
+}
+
+/// Threshold between background and foreground of an image by Otsu's method, which maximizes the
+/// between-class variance of a histogram of the finite values. Returns the smallest value if the
+/// image holds fewer than two distinct values.
+pub fn otsu_threshold(image: &Array2<f32>) -> f32 {
+    otsu_threshold_of(|| image.par_iter().map(|&v| (v, 1)))
+}
+
+/// `otsu_threshold` of the values of `weighted`, each counted as many times as its weight, e.g.
+/// the levels of a histogram, which gives the threshold of the values themselves.
+pub(crate) fn otsu_threshold_of<I: ParallelIterator<Item = (f32, u64)>>(weighted: impl Fn() -> I) -> f32 {
+    let finite = || weighted().filter(|(v, weight)| v.is_finite() && *weight > 0);
+    let (min, max) = finite()
+        .fold(|| (f32::INFINITY, f32::NEG_INFINITY), |(min, max), (v, _)| (min.min(v), max.max(v)))
+        .reduce(|| (f32::INFINITY, f32::NEG_INFINITY), |a, b| (a.0.min(b.0), a.1.max(b.1)));
+    if min >= max {
+        return min;
+    }
+
+    // Histogram of the finite values over [min, max]
+    let width = (max - min) / OTSU_BINS as f32;
+    let bin = |v: f32| (((v - min) / width) as usize).min(OTSU_BINS - 1);
+    let histogram = finite()
+        .fold(|| vec![0u64; OTSU_BINS], |mut histogram, (v, weight)| {
+            histogram[bin(v)] += weight;
+            histogram
+        })
+        .reduce(|| vec![0u64; OTSU_BINS], |a, b| a.iter().zip(&b).map(|(a, b)| a + b).collect());
+
+    // Between-class variance of every split after bin `t`
+    let total: u64 = histogram.iter().sum();
+    let sum: f64 = histogram.iter().enumerate().map(|(i, &n)| i as f64 * n as f64).sum();
+    let (mut background, mut background_sum) = (0u64, 0.0f64);
+    let (mut best, mut best_variance) = (0, -1.0);
+    for (t, &n) in histogram.iter().enumerate().take(OTSU_BINS - 1) {
+        background += n;
+        background_sum += t as f64 * n as f64;
+        let foreground = total - background;
+        if background == 0 || foreground == 0 {
+            continue;
+        }
+        let background_mean = background_sum / background as f64;
+        let foreground_mean = (sum - background_sum) / foreground as f64;
+        let variance = background as f64 * foreground as f64 * (background_mean - foreground_mean).powi(2);
+        if variance > best_variance {
+            (best, best_variance) = (t, variance);
+        }
+    }
+    min + (best + 1) as f32 * width
+}
+
+/// Mask of the pixels of `image` above `threshold`.
+pub fn threshold_mask(image: &Array2<f32>, threshold: f32) -> Array2<bool> {
+    image.mapv(|v| v > threshold)
+}
@@ -0,0 +1,56 @@
+//! Contact sheets tiling 8bit RGB renders of one field side by side, each labelled with the
+//! parameter it was rendered with, for comparing parameters in one image.
+use crate::overlay::{glyph, ADVANCE};
+use ndarray::{s, Array3};
+
+/// White gap between the tiles and around the sheet in pixels.
+const GAP: usize = 4;
+
+/// Size in pixels of a pixel of the label font.
+const LABEL_SCALE: usize = 2;
+
+/// Tile equally sized (row, column, RGB) images row by row into a sheet of `columns` columns on
+/// white, drawing the label of each tile into its top left corner in the font of `overlay`, which
+/// has digits, `.`, `-`, `=`, `k`, `m` and `µ`; other characters are left blank.
+///
+/// # Panics
+///
+/// Panics if there are no tiles or if the tiles differ in size.
+pub fn contact_sheet(tiles: &[(String, Array3<u8>)], columns: usize) -> Array3<u8> {
+    assert!(!tiles.is_empty(), "at least one tile is required");
+    let (height, width) = (tiles[0].1.shape()[0], tiles[0].1.shape()[1]);
+    assert!(tiles.iter().all(|(_, tile)| tile.shape()[..2] == [height, width]), "tiles must have the same size");
+    let columns = columns.clamp(1, tiles.len());
+    let rows = tiles.len().div_ceil(columns);
+
+    let mut sheet = Array3::from_elem((GAP + rows * (height + GAP), GAP + columns * (width + GAP), 3), 255u8);
+    for (i, (label, tile)) in tiles.iter().enumerate() {
+        let (y, x) = (GAP + i / columns * (height + GAP), GAP + i % columns * (width + GAP));
+        let mut cell = sheet.slice_mut(s![y..y + height, x..x + width, ..]);
+        cell.assign(tile);
+        draw_label(&mut cell, label);
+    }
+    sheet
+}
+
+/// Draw `label` in black on a white box into the top left corner of a tile, clipped to the tile.
+fn draw_label(tile: &mut ndarray::ArrayViewMut3<u8>, label: &str) {
+    let (height, width) = (tile.shape()[0], tile.shape()[1]);
+    let advance = ADVANCE * LABEL_SCALE;
+    let (box_height, box_width) = ((5 + 2) * LABEL_SCALE, label.chars().count() * advance + LABEL_SCALE);
+    tile.slice_mut(s![..box_height.min(height), ..box_width.min(width), ..]).fill(255);
+    for (i, c) in label.chars().enumerate() {
+        let Some(glyph) = glyph(c) else {
+            continue;
+        };
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in (0..3).filter(|column| bits & (0b100 >> column) != 0) {
+                let y = (row + 1) * LABEL_SCALE;
+                let x = LABEL_SCALE + i * advance + column * LABEL_SCALE;
+                if y + LABEL_SCALE <= height && x + LABEL_SCALE <= width {
+                    tile.slice_mut(s![y..y + LABEL_SCALE, x..x + LABEL_SCALE, ..]).fill(0);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! OME-TIFF metadata: channel lookup in multichannel inputs and OME-XML for RGB outputs.
+//!
+//! The output OME-XML block describes a single interleaved RGB plane stored in the first IFD,
+//! following the 2016-06 OME schema, with the rendering parameters recorded as a key-value
+//! MapAnnotation.
+use crate::ChannelSelector;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use tiff::decoder::ifd::Value;
+use tiff::decoder::Decoder;
+use tiff::tags::{ResolutionUnit, Tag};
+
+/// Metadata embedded in OME-TIFF outputs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OmeMetadata {
+    /// Physical size (x, y) of a pixel in micrometers, omitted when not known.
+    pub pixel_size_um: Option<[f32; 2]>,
+    /// Key-value pairs recorded in a MapAnnotation on the image, e.g. the rendering parameters.
+    pub annotations: Vec<(String, String)>,
+}
+
+/// Read the physical pixel size (x, y) in micrometers of a TIFF, from the PhysicalSizeX and
+/// PhysicalSizeY of its OME-XML or else from its resolution tags.
+///
+/// Returns `None` for non-TIFF files and for TIFFs with neither a physical size nor an inch or
+/// centimeter resolution. A missing y size is taken to equal the x size.
+pub fn pixel_size_from_tiff<P: AsRef<Path>>(path: P) -> Option<[f32; 2]> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path).ok()?)).ok()?;
+    let description = decoder.get_tag_ascii_string(Tag::ImageDescription).ok();
+    if let Some(size) = description.as_deref().and_then(physical_size) {
+        return Some(size);
+    }
+    let unit = decoder.find_tag_unsigned::<u16>(Tag::ResolutionUnit).ok()??;
+    let um_per_unit = match ResolutionUnit::from_u16(unit)? {
+        ResolutionUnit::Inch => 25_400.0,
+        ResolutionUnit::Centimeter => 10_000.0,
+        _ => return None,
+    };
+    let mut size = |tag| match decoder.find_tag(tag).ok()?? {
+        Value::Rational(n, d) if n > 0 && d > 0 => Some((um_per_unit * d as f64 / n as f64) as f32),
+        _ => None,
+    };
+    let x = size(Tag::XResolution)?;
+    Some([x, size(Tag::YResolution).unwrap_or(x)])
+}
+
+/// PhysicalSizeX and PhysicalSizeY of the first image of OME-XML in micrometers.
+fn physical_size(xml: &str) -> Option<[f32; 2]> {
+    let pixels = elements(xml, "Pixels").next()?;
+    let size = |axis: char| {
+        let value: f32 = attribute(pixels, &format!("PhysicalSize{}", axis))?.parse().ok()?;
+        // Sizes are in micrometers unless another unit is given
+        let um_per_unit = match attribute(pixels, &format!("PhysicalSize{}Unit", axis)) {
+            None | Some("\u{b5}m" | "&#181;m" | "&#xB5;m" | "um") => 1.0,
+            Some("nm") => 1e-3,
+            Some("mm") => 1e3,
+            Some("cm") => 1e4,
+            Some("m") => 1e6,
+            Some(_) => return None,
+        };
+        Some(value * um_per_unit).filter(|size| size.is_finite() && *size > 0.0)
+    };
+    let x = size('X')?;
+    Some([x, size('Y').unwrap_or(x)])
+}
+
+/// Page (IFD) holding the first plane of a channel of a TIFF, given the ImageDescription of its
+/// first page.
+///
+/// With OME-XML the page follows from the DimensionOrder and sizes of the first image, and channels
+/// can be selected by name. Without it, a channel index is used as the page index.
+pub(crate) fn channel_page(description: Option<&str>, channel: &ChannelSelector) -> Result<usize, String> {
+    let Some((xml, pixels)) = description.and_then(|xml| Some((xml, elements(xml, "Pixels").next()?))) else {
+        return match channel {
+            ChannelSelector::Index(index) => Ok(*index),
+            ChannelSelector::Name(name) => Err(format!(
+                "cannot select channel '{}' by name without OME-XML channel names, select it by index",
+                name
+            )),
+        };
+    };
+
+    // Only the channels and TIFF data of the first image are considered
+    let start = xml.find(pixels).unwrap_or(0);
+    let xml = &xml[start..xml[start..].find("Pixels>").map_or(xml.len(), |end| start + end)];
+    let names: Vec<String> = elements(xml, "Channel")
+        .map(|tag| attribute(tag, "Name").map(unescape).unwrap_or_default())
+        .collect();
+    let size = |dimension: char| {
+        attribute(pixels, &format!("Size{}", dimension))
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(1)
+    };
+    let channels = if names.is_empty() { size('C') } else { names.len() };
+
+    let index = match channel {
+        ChannelSelector::Index(index) if *index < channels => *index,
+        ChannelSelector::Index(index) => {
+            return Err(format!("channel {} is out of range, the image has {} channels", index, channels))
+        }
+        ChannelSelector::Name(name) => names.iter().position(|n| n == name).ok_or_else(|| {
+            format!("no channel named '{}', expected one of: {}", name, names.join(", "))
+        })?,
+    };
+
+    // Planes are stored in DimensionOrder, fastest varying first, so the stride of C is the product
+    // of the sizes of the dimensions before it
+    let order = attribute(pixels, "DimensionOrder").unwrap_or("XYCZT");
+    let stride: usize = order.chars().skip(2).take_while(|d| *d != 'C').map(size).product();
+    let first_ifd = elements(xml, "TiffData")
+        .next()
+        .and_then(|tag| attribute(tag, "IFD"))
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    Ok(first_ifd + index * stride)
+}
+
+/// Start tags of all elements with local name `name`, ignoring namespace prefixes.
+pub(crate) fn elements<'a>(xml: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    xml.match_indices('<').filter_map(move |(start, _)| {
+        let tag = &xml[start + 1..];
+        let end = tag.find('>')?;
+        let tag = &tag[..end];
+        let element = tag.split(|c: char| c.is_whitespace() || c == '/').next()?;
+        (element.rsplit(':').next() == Some(name)).then_some(tag)
+    })
+}
+
+/// Raw value of an attribute in a start tag.
+pub(crate) fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(position) = rest.find(name) {
+        let preceded_by_space = rest[..position].ends_with(char::is_whitespace);
+        rest = &rest[position + name.len()..];
+        let value = rest.trim_start();
+        if preceded_by_space && value.starts_with('=') {
+            let value = value[1..].trim_start();
+            let quote = value.chars().next()?;
+            if quote == '"' || quote == '\'' {
+                let value = &value[1..];
+                return value.find(quote).map(|end| &value[..end]);
+            }
+        }
+    }
+    None
+}
+
+/// Resolve the predefined XML entities.
+pub(crate) fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Build the OME-XML for a single-plane interleaved RGB image with `bits` bits per sample.
+pub(crate) fn ome_xml(metadata: &OmeMetadata, width: u32, height: u32, bits: u16) -> String {
+    let physical_size = metadata
+        .pixel_size_um
+        .map(|[x, y]| format!(r#" PhysicalSizeX="{x}" PhysicalSizeY="{y}""#))
+        .unwrap_or_default();
+    let mut xml = format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            r#"<OME xmlns="http://www.openmicroscopy.org/Schemas/OME/2016-06" "#,
+            r#"xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" "#,
+            r#"xsi:schemaLocation="http://www.openmicroscopy.org/Schemas/OME/2016-06 "#,
+            r#"http://www.openmicroscopy.org/Schemas/OME/2016-06/ome.xsd" "#,
+            r#"Creator="virtualhe {version}">"#,
+            r#"<Image ID="Image:0" Name="Virtual H&amp;E">"#,
+            r#"<Pixels ID="Pixels:0" DimensionOrder="XYCZT" Type="uint{bits}" SignificantBits="{bits}" "#,
+            r#"Interleaved="true" SizeX="{width}" SizeY="{height}" SizeC="3" SizeZ="1" SizeT="1"{physical_size}>"#,
+            r#"<Channel ID="Channel:0:0" SamplesPerPixel="3"><LightPath/></Channel>"#,
+            r#"<TiffData IFD="0" PlaneCount="1"/>"#,
+            r#"</Pixels>"#,
+        ),
+        version = env!("CARGO_PKG_VERSION"),
+        bits = bits,
+        width = width,
+        height = height,
+        physical_size = physical_size,
+    );
+    if metadata.annotations.is_empty() {
+        xml.push_str("</Image></OME>");
+        return xml;
+    }
+
+    xml.push_str(r#"<AnnotationRef ID="Annotation:0"/></Image>"#);
+    xml.push_str(r#"<StructuredAnnotations><MapAnnotation ID="Annotation:0" Namespace="virtualhe"><Value>"#);
+    for (key, value) in &metadata.annotations {
+        xml.push_str(&format!(r#"<M K="{}">{}</M>"#, escape(key), escape(value)));
+    }
+    xml.push_str("</Value></MapAnnotation></StructuredAnnotations></OME>");
+    xml
+}
+
+/// Escape text for XML attributes and content, non-ASCII characters become character references
+/// because TIFF ASCII tags cannot hold them.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c if c.is_ascii() => escaped.push(c),
+            c => escaped.push_str(&format!("&#{};", c as u32)),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,436 @@
+//! Drawing onto rendered (row, column, RGB) images of any output depth: scale bars, labelled in a
+//! 3x5 pixel font that also labels the contact sheets of `montage`, and polygon annotations read
+//! from GeoJSON, e.g. regions exported from QuPath.
+use crate::{Error, OutputSample};
+use log::debug;
+use ndarray::{s, Array3};
+use serde_json::Value;
+use std::path::Path;
+
+/// Glyphs of the label font, 3 columns by 5 rows with the bits of each row from left to right.
+const GLYPHS: [(char, [u8; 5]); 16] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b010, 0b010, 0b010]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+    ('k', [0b100, 0b101, 0b110, 0b101, 0b101]),
+    ('m', [0b000, 0b110, 0b111, 0b101, 0b101]),
+    ('µ', [0b000, 0b101, 0b101, 0b111, 0b100]),
+];
+
+/// Horizontal distance between the glyphs of the label font in font pixels, a glyph and a space.
+pub(crate) const ADVANCE: usize = 4;
+
+/// Rows of the glyph of `c` in the label font, None for characters it does not have, which are
+/// left blank.
+pub(crate) fn glyph(c: char) -> Option<[u8; 5]> {
+    GLYPHS.iter().find(|(g, _)| *g == c).map(|(_, rows)| *rows)
+}
+
+/// Corner of the image a scale bar is drawn into.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+impl Corner {
+    /// Name of the corner as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Corner::TopLeft => "top-left",
+            Corner::TopRight => "top-right",
+            Corner::BottomLeft => "bottom-left",
+            Corner::BottomRight => "bottom-right",
+        }
+    }
+}
+
+impl std::str::FromStr for Corner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "top-left" => Ok(Corner::TopLeft),
+            "top-right" => Ok(Corner::TopRight),
+            "bottom-left" => Ok(Corner::BottomLeft),
+            "bottom-right" => Ok(Corner::BottomRight),
+            _ => Err(format!(
+                "unknown corner '{}', expected one of: top-left, top-right, bottom-left, bottom-right",
+                s
+            )),
+        }
+    }
+}
+
+/// Color of a scale bar and its label, outlined in the other color.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BarColor {
+    #[default]
+    Black,
+    White,
+}
+
+impl BarColor {
+    /// Name of the color as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BarColor::Black => "black",
+            BarColor::White => "white",
+        }
+    }
+}
+
+impl std::str::FromStr for BarColor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "black" => Ok(BarColor::Black),
+            "white" => Ok(BarColor::White),
+            _ => Err(format!("unknown color '{}', expected one of: black, white", s)),
+        }
+    }
+}
+
+/// A scale bar of a physical length, labelled with it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScaleBar {
+    /// Length of the bar in micrometers.
+    pub length_um: f32,
+    pub corner: Corner,
+    pub color: BarColor,
+}
+
+/// Draw a scale bar into a corner of a (row, column, RGB) image with pixels `pixel_size_um` wide,
+/// with its length in micrometers as a label above the bar (below it in the top corners). The
+/// thickness of the bar, its margin to the image border and the size of the label grow with the
+/// short edge of the image, and bar and label are outlined for contrast on any background.
+///
+/// Fails if the bar and its margins do not fit into the image.
+pub fn draw_scale_bar<T: OutputSample>(rgb: &mut Array3<T>, bar: &ScaleBar, pixel_size_um: f32) -> Result<(), Error> {
+    let (height, width) = (rgb.shape()[0], rgb.shape()[1]);
+    let short = height.min(width);
+    let thickness = (short / 150).max(2);
+    let margin = (short / 40).max(4);
+    let outline = (thickness / 3).max(1);
+    let scale = (thickness / 2).max(1);
+
+    let length = (bar.length_um / pixel_size_um).round();
+    let label = format!("{} µm", bar.length_um);
+    let label_width = label.chars().count() * ADVANCE * scale - scale;
+    let (label_height, gap) = (5 * scale, 2 * scale);
+    let box_height = thickness + gap + label_height;
+    if length < 1.0 || length as usize + 2 * margin > width || box_height + 2 * margin > height {
+        return Err(Error::InvalidOptions(format!(
+            "a scale bar of {} um is {} pixels at a pixel size of {} um, which does not fit into the {}x{} image",
+            bar.length_um, length, pixel_size_um, width, height
+        )));
+    }
+    let length = length as usize;
+
+    let x = match bar.corner {
+        Corner::TopLeft | Corner::BottomLeft => margin,
+        Corner::TopRight | Corner::BottomRight => width - margin - length,
+    };
+    let (bar_y, label_y) = match bar.corner {
+        Corner::TopLeft | Corner::TopRight => (margin, margin + thickness + gap),
+        Corner::BottomLeft | Corner::BottomRight => (height - margin - thickness, height - margin - box_height),
+    };
+    // The label is centered over the bar but kept inside the margins
+    let max_x = width.saturating_sub(margin + label_width).max(margin);
+    let label_x = (x + length / 2).saturating_sub(label_width / 2).clamp(margin, max_x);
+
+    let mut rects = vec![(bar_y, x, thickness, length)];
+    for (i, c) in label.chars().enumerate() {
+        let Some(rows) = glyph(c) else {
+            continue;
+        };
+        for (row, bits) in rows.iter().enumerate() {
+            for column in (0..3).filter(|column| bits & (0b100 >> column) != 0) {
+                rects.push((label_y + row * scale, label_x + (i * ADVANCE + column) * scale, scale, scale));
+            }
+        }
+    }
+
+    let (black, white) = (T::quantize(0.0), T::quantize(1.0));
+    let (ink, background) = match bar.color {
+        BarColor::Black => (black, white),
+        BarColor::White => (white, black),
+    };
+    // The outlines of all shapes are drawn first so that they do not cover neighboring glyph pixels
+    for &(y, x, h, w) in &rects {
+        fill(rgb, y.saturating_sub(outline), x.saturating_sub(outline), h + 2 * outline, w + 2 * outline, background);
+    }
+    for &(y, x, h, w) in &rects {
+        fill(rgb, y, x, h, w, ink);
+    }
+    Ok(())
+}
+
+/// Fill an `h` by `w` rectangle at row `y` and column `x` with an opaque gray value, clipped to the
+/// image.
+fn fill<T: OutputSample>(rgb: &mut Array3<T>, y: usize, x: usize, h: usize, w: usize, value: T) {
+    let (height, width) = (rgb.shape()[0], rgb.shape()[1]);
+    let (y1, x1) = ((y + h).min(height), (x + w).min(width));
+    if y < y1 && x < x1 {
+        rgb.slice_mut(s![y..y1, x..x1, ..3]).fill(value);
+        rgb.slice_mut(s![y..y1, x..x1, 3..]).fill(T::quantize(1.0));
+    }
+}
+
+/// A polygon in pixel coordinates (x, y): its exterior ring followed by its holes. Rings are closed
+/// implicitly, the last position need not repeat the first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polygon {
+    pub rings: Vec<Vec<[f64; 2]>>,
+}
+
+impl Polygon {
+    /// The polygon with every position mapped by `f`, e.g. into the frame of a cropped output.
+    pub fn map(&self, f: impl Fn([f64; 2]) -> [f64; 2]) -> Polygon {
+        Polygon {
+            rings: self.rings.iter().map(|ring| ring.iter().map(|&position| f(position)).collect()).collect(),
+        }
+    }
+}
+
+/// How `draw_polygons` draws polygons.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PolygonStyle {
+    /// 8bit RGB color of the outlines and fills.
+    pub color: [u8; 3],
+    /// Width of the outlines in pixels.
+    pub width: u32,
+    /// Opacity in [0, 1] of the fill of the polygons, None leaves them unfilled.
+    pub fill: Option<f32>,
+}
+
+/// Read the polygons of a GeoJSON file: a FeatureCollection, a Feature, a geometry or an array of
+/// them, as exported by QuPath. Polygons, MultiPolygons and those in GeometryCollections are read,
+/// other geometries (e.g. the points of point annotations) are skipped.
+pub fn read_geojson<P: AsRef<Path>>(path: P) -> Result<Vec<Polygon>, Error> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path).map_err(|source| Error::open(path, source))?;
+    let invalid = |message: String| Error::Decode {
+        path: path.to_path_buf(),
+        message: format!("{}: invalid GeoJSON: {}", path.display(), message),
+    };
+    let value: Value = serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+    let mut polygons = Vec::new();
+    let skipped = collect_polygons(&value, "$", &mut polygons).map_err(invalid)?;
+    debug!("{}: {} polygons, {} other geometries skipped", path.display(), polygons.len(), skipped);
+    Ok(polygons)
+}
+
+/// Append the polygons of the GeoJSON value at `location`, a JSON path such as
+/// `$.features[2].geometry`, to `polygons`, returning the number of other geometries skipped.
+fn collect_polygons(value: &Value, location: &str, polygons: &mut Vec<Polygon>) -> Result<usize, String> {
+    if let Value::Array(items) = value {
+        return collect_all(items, location, "", polygons);
+    }
+    let member = |name: &str| value.get(name).ok_or_else(|| format!("{} has no {} member", location, name));
+    let array = |name: &str| member(name)?.as_array().ok_or_else(|| format!("{}.{} is not an array", location, name));
+    match value.get("type").and_then(Value::as_str) {
+        Some("FeatureCollection") => collect_all(array("features")?, location, ".features", polygons),
+        Some("Feature") => match member("geometry")? {
+            Value::Null => Ok(0),
+            geometry => collect_polygons(geometry, &format!("{}.geometry", location), polygons),
+        },
+        Some("GeometryCollection") => collect_all(array("geometries")?, location, ".geometries", polygons),
+        Some("Polygon") => {
+            polygons.push(polygon(member("coordinates")?, &format!("{}.coordinates", location))?);
+            Ok(0)
+        }
+        Some("MultiPolygon") => {
+            for (i, coordinates) in array("coordinates")?.iter().enumerate() {
+                polygons.push(polygon(coordinates, &format!("{}.coordinates[{}]", location, i))?);
+            }
+            Ok(0)
+        }
+        Some("Point" | "MultiPoint" | "LineString" | "MultiLineString") => Ok(1),
+        Some(kind) => Err(format!("{} has the unknown type '{}'", location, kind)),
+        None => Err(format!("{} is not a GeoJSON object with a type", location)),
+    }
+}
+
+/// Collect the polygons of the `items` of the array `suffix` of `location`.
+fn collect_all(items: &[Value], location: &str, suffix: &str, polygons: &mut Vec<Polygon>) -> Result<usize, String> {
+    let mut skipped = 0;
+    for (i, item) in items.iter().enumerate() {
+        skipped += collect_polygons(item, &format!("{}{}[{}]", location, suffix, i), polygons)?;
+    }
+    Ok(skipped)
+}
+
+/// Parse the coordinates of a Polygon at `location`, an array of rings of [x, y] positions.
+fn polygon(coordinates: &Value, location: &str) -> Result<Polygon, String> {
+    let invalid = || format!("{} is not an array of rings of [x, y] positions", location);
+    let rings = coordinates.as_array().ok_or_else(invalid)?;
+    let rings = rings
+        .iter()
+        .map(|ring| {
+            ring.as_array()
+                .ok_or_else(invalid)?
+                .iter()
+                .map(|position| match position.as_array().map(Vec::as_slice) {
+                    Some([x, y, ..]) => Ok([x.as_f64().ok_or_else(invalid)?, y.as_f64().ok_or_else(invalid)?]),
+                    _ => Err(invalid()),
+                })
+                .collect()
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Polygon { rings })
+}
+
+/// Draw polygons onto a (row, column, RGB) or (row, column, RGBA) image: the translucent fills of
+/// all polygons first, then their outlines. Parts outside the image are clipped.
+pub fn draw_polygons<T: OutputSample>(rgb: &mut Array3<T>, polygons: &[Polygon], style: &PolygonStyle) {
+    let color = style.color.map(|c| f32::from(c) / 255.0);
+    if let Some(alpha) = style.fill.filter(|alpha| *alpha > 0.0) {
+        for polygon in polygons {
+            fill_polygon(rgb, polygon, color, alpha.min(1.0));
+        }
+    }
+    let ink = [color[0], color[1], color[2], 1.0].map(T::quantize);
+    let brush = brush(style.width.max(1));
+    for ring in polygons.iter().flat_map(|polygon| &polygon.rings) {
+        for (i, &start) in ring.iter().enumerate() {
+            let end = ring[(i + 1) % ring.len()];
+            draw_segment(rgb, start, end, &brush, ink);
+        }
+    }
+}
+
+/// Offsets (row, column) of the pixels of a round brush drawing lines `width` pixels wide.
+fn brush(width: u32) -> Vec<(isize, isize)> {
+    let radius = f64::from(width) / 2.0;
+    let reach = radius.ceil() as isize;
+    // Even widths are centered between pixels, so that a width of 2 covers 2 pixels and not 3
+    let center = if width.is_multiple_of(2) { 0.5 } else { 0.0 };
+    let mut offsets = Vec::new();
+    for dy in -reach..=reach {
+        for dx in -reach..=reach {
+            let (y, x) = (dy as f64 + center, dx as f64 + center);
+            if (y * y + x * x).sqrt() <= radius {
+                offsets.push((dy, dx));
+            }
+        }
+    }
+    offsets
+}
+
+/// Stamp the brush along a segment between positions (x, y), clipped to the image.
+fn draw_segment<T: OutputSample>(
+    rgb: &mut Array3<T>,
+    start: [f64; 2],
+    end: [f64; 2],
+    brush: &[(isize, isize)],
+    ink: [T; 4],
+) {
+    let (height, width) = (rgb.shape()[0], rgb.shape()[1]);
+    // Segments are clipped to the image and the reach of the brush beyond it
+    let margin = brush.iter().map(|&(dy, dx)| dy.abs().max(dx.abs())).max().unwrap_or(0) as f64 + 1.0;
+    let bounds = ([-margin, -margin], [width as f64 + margin, height as f64 + margin]);
+    let Some((start, end)) = clip_segment(start, end, bounds.0, bounds.1) else {
+        return;
+    };
+    let steps = (end[0] - start[0]).abs().max((end[1] - start[1]).abs()).ceil() as usize;
+    for step in 0..=steps {
+        let t = if steps == 0 { 0.0 } else { step as f64 / steps as f64 };
+        // Positions are continuous, pixel (row, column) covers [column, column + 1) x [row, row + 1)
+        let x = (start[0] + t * (end[0] - start[0])).floor() as isize;
+        let y = (start[1] + t * (end[1] - start[1])).floor() as isize;
+        for &(dy, dx) in brush {
+            let (row, column) = (y + dy, x + dx);
+            if row >= 0 && column >= 0 && (row as usize) < height && (column as usize) < width {
+                for (channel, &value) in ink.iter().enumerate().take(rgb.shape()[2]) {
+                    rgb[[row as usize, column as usize, channel]] = value;
+                }
+            }
+        }
+    }
+}
+
+/// Clip a segment to the rectangle from `min` to `max` (Liang-Barsky), None if it lies outside.
+fn clip_segment(start: [f64; 2], end: [f64; 2], min: [f64; 2], max: [f64; 2]) -> Option<([f64; 2], [f64; 2])> {
+    let delta = [end[0] - start[0], end[1] - start[1]];
+    let (mut t0, mut t1) = (0.0f64, 1.0f64);
+    for axis in 0..2 {
+        for (p, q) in [(-delta[axis], start[axis] - min[axis]), (delta[axis], max[axis] - start[axis])] {
+            if p == 0.0 {
+                if q < 0.0 {
+                    return None;
+                }
+            } else if p < 0.0 {
+                t0 = t0.max(q / p);
+            } else {
+                t1 = t1.min(q / p);
+            }
+        }
+    }
+    let at = |t: f64| [start[0] + t * delta[0], start[1] + t * delta[1]];
+    (t0 <= t1).then(|| (at(t0), at(t1)))
+}
+
+/// Blend `color` into the pixels of an image whose centers lie inside a polygon by the even-odd
+/// rule, so that holes are left out, with opacity `alpha`. Pixels of RGBA images are composited
+/// over with their own alpha.
+fn fill_polygon<T: OutputSample>(rgb: &mut Array3<T>, polygon: &Polygon, color: [f32; 3], alpha: f32) {
+    let (height, width) = (rgb.shape()[0], rgb.shape()[1]);
+    let max = ((1u32 << T::BITS) - 1) as f32;
+    let has_alpha = rgb.shape()[2] == 4;
+    let edges: Vec<([f64; 2], [f64; 2])> = polygon
+        .rings
+        .iter()
+        .flat_map(|ring| (0..ring.len()).map(move |i| (ring[i], ring[(i + 1) % ring.len()])))
+        .collect();
+    let (top, bottom) = edges.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(top, bottom), (a, b)| {
+        (top.min(a[1]).min(b[1]), bottom.max(a[1]).max(b[1]))
+    });
+    if edges.is_empty() || bottom < 0.0 || top >= height as f64 {
+        return;
+    }
+    let rows = (top.max(0.0) as usize)..(bottom.ceil().min(height as f64) as usize);
+
+    let mut crossings = Vec::new();
+    for row in rows {
+        let y = row as f64 + 0.5;
+        crossings.clear();
+        for (a, b) in &edges {
+            // Edges are half-open in y, so that a vertex on the scanline is crossed once
+            if (a[1] <= y) != (b[1] <= y) {
+                crossings.push(a[0] + (y - a[1]) / (b[1] - a[1]) * (b[0] - a[0]));
+            }
+        }
+        crossings.sort_by(f64::total_cmp);
+        for span in crossings.chunks_exact(2) {
+            // Pixels whose center x + 0.5 lies in the span
+            let first = (span[0] - 0.5).ceil().max(0.0) as usize;
+            let last = ((span[1] - 0.5).ceil().min(width as f64)).max(0.0) as usize;
+            for column in first..last {
+                let below = if has_alpha { rgb[[row, column, 3]].into() as f32 / max } else { 1.0 };
+                let opacity = alpha + below * (1.0 - alpha);
+                for (channel, &c) in color.iter().enumerate() {
+                    let value = rgb[[row, column, channel]].into() as f32 / max;
+                    rgb[[row, column, channel]] = T::quantize((c * alpha + value * below * (1.0 - alpha)) / opacity);
+                }
+                if has_alpha {
+                    rgb[[row, column, 3]] = T::quantize(opacity);
+                }
+            }
+        }
+    }
+}
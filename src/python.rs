@@ -0,0 +1,179 @@
+//! Python module of the python feature, built with maturin as the `virtualhe-py` package and
+//! imported as `virtualhe`, so that pipelines in Python render arrays they hold in memory instead
+//! of writing them to TIFF files for the command line tool.
+//!
+//! The inputs are read in place from the numpy arrays, whatever their strides, into the normalized
+//! channels the rendering works on, and the rendered array is handed to numpy without a copy. The
+//! GIL is released while the channels are normalized, scaled and rendered.
+use crate::{normalize_value, resolve_input_max, scale_with, LoadOptions, Params, ScaleOptions, DEFAULT_BETA};
+use ndarray::parallel::prelude::*;
+use ndarray::{Array2, ArrayView2, Zip};
+use numpy::{PyArray3, PyReadonlyArray2, PyUntypedArray, PyUntypedArrayMethods};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+/// A 2D numpy array of one of the supported dtypes.
+enum Input<'py> {
+    U8(PyReadonlyArray2<'py, u8>),
+    U16(PyReadonlyArray2<'py, u16>),
+    U32(PyReadonlyArray2<'py, u32>),
+    F32(PyReadonlyArray2<'py, f32>),
+    F64(PyReadonlyArray2<'py, f64>),
+}
+
+/// The data of an `Input`, borrowed into the threads that release the GIL.
+enum View<'a> {
+    U8(ArrayView2<'a, u8>),
+    U16(ArrayView2<'a, u16>),
+    U32(ArrayView2<'a, u32>),
+    F32(ArrayView2<'a, f32>),
+    F64(ArrayView2<'a, f64>),
+}
+
+impl<'py> Input<'py> {
+    /// The `name` input of `render`, a 2D array of unsigned integers or floats.
+    fn extract(name: &str, object: &Bound<'py, PyAny>) -> PyResult<Self> {
+        let array = object
+            .cast::<PyUntypedArray>()
+            .map_err(|_| PyValueError::new_err(format!("{}: expected a numpy array, got {}", name, object.get_type())))?;
+        if array.ndim() != 2 {
+            return Err(PyValueError::new_err(format!(
+                "{}: expected a 2D array of one channel, got {} dimensions",
+                name,
+                array.ndim()
+            )));
+        }
+        if let Ok(array) = object.extract() {
+            Ok(Input::U8(array))
+        } else if let Ok(array) = object.extract() {
+            Ok(Input::U16(array))
+        } else if let Ok(array) = object.extract() {
+            Ok(Input::U32(array))
+        } else if let Ok(array) = object.extract() {
+            Ok(Input::F32(array))
+        } else if let Ok(array) = object.extract() {
+            Ok(Input::F64(array))
+        } else {
+            Err(PyValueError::new_err(format!(
+                "{}: unsupported dtype {}, expected uint8, uint16, uint32, float32 or float64",
+                name,
+                array.dtype()
+            )))
+        }
+    }
+
+    fn view(&self) -> View<'_> {
+        match self {
+            Input::U8(array) => View::U8(array.as_array()),
+            Input::U16(array) => View::U16(array.as_array()),
+            Input::U32(array) => View::U32(array.as_array()),
+            Input::F32(array) => View::F32(array.as_array()),
+            Input::F64(array) => View::F64(array.as_array()),
+        }
+    }
+}
+
+impl View<'_> {
+    /// Width and height.
+    fn size(&self) -> (usize, usize) {
+        let (rows, columns) = match self {
+            View::U8(view) => view.dim(),
+            View::U16(view) => view.dim(),
+            View::U32(view) => view.dim(),
+            View::F32(view) => view.dim(),
+            View::F64(view) => view.dim(),
+        };
+        (columns, rows)
+    }
+
+    /// The channel normalized as a decoded image of the same sample type: integers by the maximum
+    /// of their type, floats by their data maximum.
+    fn normalized(&self) -> Array2<f32> {
+        match self {
+            View::U8(view) => normalized(*view, f32::from, Some(f32::from(u8::MAX))),
+            View::U16(view) => normalized(*view, f32::from, Some(f32::from(u16::MAX))),
+            View::U32(view) => normalized(*view, |v| v as f32, Some(u32::MAX as f32)),
+            View::F32(view) => normalized(*view, |v| v, None),
+            View::F64(view) => normalized(*view, |v| v as f32, None),
+        }
+    }
+}
+
+/// Normalize the values of `view`, converted by `value`, by the input maximum of the defaults of
+/// `LoadOptions` for images whose sample type has the maximum `container_max`.
+fn normalized<T: Copy + Send + Sync>(view: ArrayView2<T>, value: impl Fn(T) -> f32 + Sync, container_max: Option<f32>) -> Array2<f32> {
+    let data_max = || view.into_par_iter().map(|&v| value(v)).filter(|v| v.is_finite()).reduce(|| 0.0, f32::max);
+    let input_max =
+        resolve_input_max(&LoadOptions::default(), container_max, data_max).expect("the default input range resolves");
+    Zip::from(view).par_map_collect(|&v| normalize_value(value(v), input_max))
+}
+
+/// render(nucleus, eosin, k=2.5, betas=None, percentiles=(99.999, 99.999))
+/// --
+///
+/// Render a virtual H&E image from 2D nucleus and eosin arrays of the same shape, of dtype
+/// uint8, uint16, uint32, float32 or float64, scaled by the saturation percentiles of both
+/// channels as the command line does. `betas` holds the beta coefficients of hematoxylin and
+/// eosin as ((r, g, b), (r, g, b)), the he-classic profile by default. Returns an HxWx3 uint8
+/// array, and raises ValueError for invalid arrays or parameters.
+#[pyfunction]
+#[pyo3(signature = (nucleus, eosin, k = 2.5, betas = None, percentiles = (99.999, 99.999)))]
+fn render<'py>(
+    py: Python<'py>,
+    nucleus: &Bound<'py, PyAny>,
+    eosin: &Bound<'py, PyAny>,
+    k: f32,
+    betas: Option<[[f32; 3]; 2]>,
+    percentiles: (f32, f32),
+) -> PyResult<Bound<'py, PyArray3<u8>>> {
+    if !k.is_finite() || k < 0.0 {
+        return Err(PyValueError::new_err(format!("k must be non-negative, got {}", k)));
+    }
+    for percentile in [percentiles.0, percentiles.1] {
+        if !(percentile > 0.0 && percentile <= 100.0) {
+            return Err(PyValueError::new_err(format!("percentile must be in (0, 100], got {}", percentile)));
+        }
+    }
+    let beta = betas.unwrap_or(DEFAULT_BETA);
+    if beta.iter().flatten().any(|v| !v.is_finite() || *v < 0.0) {
+        return Err(PyValueError::new_err(format!("beta values must be non-negative, got {:?}", beta)));
+    }
+    let params = Params {
+        beta,
+        ..Params::default().with_k(k)
+    };
+
+    let (nucleus, eosin) = (Input::extract("nucleus", nucleus)?, Input::extract("eosin", eosin)?);
+    let (nucleus, eosin) = (nucleus.view(), eosin.view());
+    let (nucleus_size, eosin_size) = (nucleus.size(), eosin.size());
+    if nucleus_size != eosin_size {
+        return Err(PyValueError::new_err(format!(
+            "nucleus is {}x{} but eosin is {}x{}",
+            nucleus_size.0, nucleus_size.1, eosin_size.0, eosin_size.1
+        )));
+    }
+    let rgb = py.detach(|| -> Result<_, String> {
+        let mut channels = [(nucleus.normalized(), percentiles.0, "nucleus"), (eosin.normalized(), percentiles.1, "eosin")];
+        for (channel, percentile, name) in &mut channels {
+            let options = ScaleOptions {
+                percentile: *percentile,
+                ..ScaleOptions::default()
+            };
+            scale_with(channel, &options).map_err(|e| format!("{}: {}", name, e))?;
+        }
+        let [(nucleus, _, _), (eosin, _, _)] = channels;
+        Ok(crate::render(nucleus, eosin, &params))
+    });
+    Ok(PyArray3::from_owned_array(py, rgb.map_err(PyValueError::new_err)?))
+}
+
+/// Virtual H&E images from fluorescence microscopy channels.
+#[pymodule]
+#[pyo3(name = "virtualhe")]
+fn python_module(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    // Without numpy the import fails here rather than the first render
+    module.py().import("numpy")?;
+    module.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    module.add_function(wrap_pyfunction!(render, module)?)?;
+    Ok(())
+}
@@ -0,0 +1,284 @@
+//! Percentiles of values streamed in chunks, e.g. the bands of tiles of a tiled render or the
+//! planes of a stack, that are never all in memory: `update` adds a chunk, and `finalize` selects
+//! a percentile of all values added so far.
+//!
+//! Integers from 0 to 65535, as decoded from 8 and 16bit images, are counted in a histogram, and
+//! their percentiles are exact. Other values are kept up to a limit, `DEFAULT_EXACT_LIMIT` by
+//! default, so that the percentiles of smaller images are exact as well, and beyond it they are
+//! merged into a t-digest (Dunning and Ertl 2019, "Computing extremely accurate quantiles using
+//! t-digests") of a few thousand centroids.
+//!
+//! The centroids of the t-digest hold fewer values towards the ends of the distribution, so that
+//! the value at a percentile `p` is at most about `100 * pi * sqrt(q * (1 - q)) / COMPRESSION`
+//! percentage points of rank away from the exact one, for `q = p / 100`: 0.016 points at the
+//! median, 0.002 at 99 and 0.0001 at 99.999, where the saturation percentiles are, and usually much
+//! less as the values of a centroid are interpolated between its neighbors.
+use crate::PercentileMethod;
+use std::f64::consts::PI;
+
+/// Number of non-integer values kept as they are by default, beyond which they are merged into a
+/// t-digest.
+pub const DEFAULT_EXACT_LIMIT: usize = 1 << 24;
+
+/// Compression of the t-digest, which holds at most about half as many centroids.
+pub const COMPRESSION: f64 = 10_000.0;
+
+/// Number of integer levels counted exactly, those of 16bit samples.
+const INTEGER_LEVELS: usize = 65536;
+
+/// Number of values added to a t-digest before they are merged into its centroids.
+const DIGEST_BUFFER: usize = 5 * COMPRESSION as usize;
+
+/// Streamed percentiles of the finite values added in chunks.
+#[derive(Debug, Clone)]
+pub struct StreamingQuantiles {
+    values: Values,
+    exact_limit: usize,
+    count: u64,
+    max: f32,
+    nan_count: u64,
+    /// Number of negative and positive infinite values.
+    infinite_counts: [u64; 2],
+}
+
+/// The values added so far, in the most exact form their number allows.
+#[derive(Debug, Clone)]
+enum Values {
+    /// Counts of the integers from 0 to 65535, while every value is one of them.
+    Integers(Vec<u64>),
+    /// The values themselves, sorted when a percentile is selected.
+    Exact { values: Vec<f32>, sorted: bool },
+    /// A t-digest of the values.
+    Digest(Digest),
+}
+
+impl Default for StreamingQuantiles {
+    fn default() -> Self {
+        StreamingQuantiles::with_exact_limit(DEFAULT_EXACT_LIMIT)
+    }
+}
+
+impl StreamingQuantiles {
+    /// Empty accumulator that keeps up to `DEFAULT_EXACT_LIMIT` non-integer values exactly.
+    pub fn new() -> Self {
+        StreamingQuantiles::default()
+    }
+
+    /// Empty accumulator that keeps up to `exact_limit` non-integer values exactly before it
+    /// merges them into a t-digest.
+    pub fn with_exact_limit(exact_limit: usize) -> Self {
+        StreamingQuantiles {
+            values: Values::Integers(vec![0; INTEGER_LEVELS]),
+            exact_limit,
+            count: 0,
+            max: f32::NEG_INFINITY,
+            nan_count: 0,
+            infinite_counts: [0; 2],
+        }
+    }
+
+    /// Add the values of a chunk. NaN and infinite values are counted but not added.
+    pub fn update<'a>(&mut self, chunk: impl IntoIterator<Item = &'a f32>) {
+        for &v in chunk {
+            if v.is_nan() {
+                self.nan_count += 1;
+                continue;
+            }
+            if v.is_infinite() {
+                self.infinite_counts[usize::from(v > 0.0)] += 1;
+                continue;
+            }
+            self.count += 1;
+            self.max = self.max.max(v);
+            if let Values::Integers(counts) = &mut self.values {
+                // Saturating casts, the value is an integer level only if it converts back to itself
+                let level = v as u32 as usize;
+                if level < INTEGER_LEVELS && level as f32 == v {
+                    counts[level] += 1;
+                    continue;
+                }
+                self.values = Values::from_counts(counts, self.exact_limit);
+            }
+            match &mut self.values {
+                Values::Exact { values, sorted } if values.len() < self.exact_limit => {
+                    values.push(v);
+                    *sorted = false;
+                }
+                Values::Exact { values, .. } => {
+                    let mut digest = Digest::default();
+                    values.iter().for_each(|&v| digest.add(f64::from(v), 1));
+                    digest.add(f64::from(v), 1);
+                    self.values = Values::Digest(digest);
+                }
+                Values::Digest(digest) => digest.add(f64::from(v), 1),
+                Values::Integers(_) => unreachable!("replaced by the first non-integer value"),
+            }
+        }
+    }
+
+    /// Number of finite values added.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Largest finite value added, negative infinity if none was added.
+    pub fn max(&self) -> f32 {
+        self.max
+    }
+
+    /// Number of NaN values of the chunks.
+    pub fn nan_count(&self) -> u64 {
+        self.nan_count
+    }
+
+    /// Number of negative and positive infinite values of the chunks.
+    pub fn infinite_counts(&self) -> [u64; 2] {
+        self.infinite_counts
+    }
+
+    /// Whether the percentiles are exact, as no values were merged into a t-digest.
+    pub fn is_exact(&self) -> bool {
+        !matches!(self.values, Values::Digest(_))
+    }
+
+    /// Value at `percentile` of the finite values added so far by `method`, None if none was
+    /// added. More chunks can be added afterwards.
+    pub fn finalize(&mut self, percentile: f32, method: PercentileMethod) -> Option<f32> {
+        (self.count > 0).then(|| {
+            self.prepare();
+            method.select(percentile, self.count, |rank| self.value_at(rank))
+        })
+    }
+
+    /// Sort the exact values and merge the buffer of the t-digest, for `value_at`.
+    pub(crate) fn prepare(&mut self) {
+        match &mut self.values {
+            Values::Exact { values, sorted } if !*sorted => {
+                values.sort_unstable_by(f32::total_cmp);
+                *sorted = true;
+            }
+            Values::Digest(digest) => digest.merge(),
+            _ => {}
+        }
+    }
+
+    /// Value at `rank` of the sorted finite values after `prepare`, estimated by the t-digest.
+    pub(crate) fn value_at(&self, rank: u64) -> f32 {
+        match &self.values {
+            Values::Integers(counts) => {
+                let mut cumulative = 0;
+                let level = counts
+                    .iter()
+                    .position(|&count| {
+                        cumulative += count;
+                        cumulative > rank
+                    })
+                    .expect("ranks are below the count");
+                level as f32
+            }
+            Values::Exact { values, .. } => values[rank as usize],
+            Values::Digest(digest) => digest.value_at(rank as f64 + 0.5, self.max) as f32,
+        }
+    }
+}
+
+impl Values {
+    /// The values of the integer `counts` once a value is not an integer level.
+    fn from_counts(counts: &[u64], exact_limit: usize) -> Values {
+        let count: u64 = counts.iter().sum();
+        let levels = counts.iter().enumerate().filter(|(_, &count)| count > 0);
+        if count < exact_limit as u64 {
+            let mut values = Vec::with_capacity(count as usize + 1);
+            for (level, &count) in levels {
+                values.extend(std::iter::repeat_n(level as f32, count as usize));
+            }
+            Values::Exact { values, sorted: true }
+        } else {
+            let mut digest = Digest::default();
+            levels.for_each(|(level, &count)| digest.add(level as f64, count));
+            Values::Digest(digest)
+        }
+    }
+}
+
+/// Merging t-digest: centroids of the mean and number of neighboring values, sorted by mean, and
+/// a buffer of values not yet merged into them.
+#[derive(Debug, Clone, Default)]
+struct Digest {
+    centroids: Vec<(f64, u64)>,
+    buffer: Vec<(f64, u64)>,
+    min: f64,
+}
+
+impl Digest {
+    fn add(&mut self, value: f64, count: u64) {
+        if self.centroids.is_empty() && self.buffer.is_empty() {
+            self.min = value;
+        }
+        self.min = self.min.min(value);
+        self.buffer.push((value, count));
+        if self.buffer.len() >= DIGEST_BUFFER {
+            self.merge();
+        }
+    }
+
+    /// Merge the buffer into the centroids, each of which holds at most the values of a step of 1
+    /// of the scale function `k(q) = COMPRESSION / (2 pi) * asin(2q - 1)` of their ranks.
+    fn merge(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut all = std::mem::take(&mut self.centroids);
+        all.append(&mut self.buffer);
+        all.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+        let total = all.iter().map(|&(_, count)| count).sum::<u64>() as f64;
+        let k = |q: f64| COMPRESSION / (2.0 * PI) * (2.0 * q - 1.0).asin();
+        // The scale function is defined up to k(1) = COMPRESSION / 4
+        let q_limit = |k: f64| if k >= COMPRESSION / 4.0 { 1.0 } else { ((k * 2.0 * PI / COMPRESSION).sin() + 1.0) / 2.0 };
+
+        let mut merged = Vec::with_capacity(COMPRESSION as usize);
+        let mut all = all.into_iter();
+        let mut current = all.next().expect("the buffer is not empty");
+        let mut before = 0.0;
+        let mut limit = q_limit(k(0.0) + 1.0) * total;
+        for (mean, count) in all {
+            if before + (current.1 + count) as f64 <= limit {
+                let merged_count = current.1 + count;
+                current.0 += (mean - current.0) * count as f64 / merged_count as f64;
+                current.1 = merged_count;
+            } else {
+                before += current.1 as f64;
+                merged.push(current);
+                limit = q_limit(k(before / total) + 1.0) * total;
+                current = (mean, count);
+            }
+        }
+        merged.push(current);
+        self.centroids = merged;
+    }
+
+    /// Value at the fractional rank `rank` from 0 to the number of values, interpolated between
+    /// the means of neighboring centroids at the ranks of their centers, and towards the minimum
+    /// and `max` beyond the first and last.
+    fn value_at(&self, rank: f64, max: f32) -> f64 {
+        let mut before = 0.0;
+        let mut previous = (0.0, self.min);
+        for &(mean, count) in &self.centroids {
+            let center = before + count as f64 / 2.0;
+            if rank < center {
+                // A single value is exact
+                if count == 1 && rank >= before {
+                    return mean;
+                }
+                let (previous_center, previous_mean) = previous;
+                let t = (rank - previous_center) / (center - previous_center);
+                return previous_mean + (mean - previous_mean) * t.clamp(0.0, 1.0);
+            }
+            before += count as f64;
+            previous = (center, mean);
+        }
+        let (last_center, last_mean) = previous;
+        let t = (rank - last_center) / (before - last_center);
+        last_mean + (f64::from(max) - last_mean) * t.clamp(0.0, 1.0)
+    }
+}
@@ -0,0 +1,62 @@
+//! Headerless raw input: a dense row-major array of 8bit, 16bit or 32bit float samples with the
+//! dimensions given by the caller, as handed off by acquisition pipelines.
+use crate::{Error, RawImage, RawLayout, RawSample};
+use log::debug;
+use ndarray::Array2;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// Whether `path` names a raw input by its `.raw` or `.bin` extension.
+pub(crate) fn is_raw(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("raw") || extension.eq_ignore_ascii_case("bin"))
+}
+
+/// Read a raw input with the given layout, row by row so that the file is not held in memory
+/// next to the converted image.
+pub(crate) fn read(path: &Path, layout: Option<RawLayout>) -> Result<RawImage, Box<dyn std::error::Error>> {
+    let Some(layout) = layout else {
+        return Err(Error::unsupported(path, "raw input without dimensions, set them with --raw-dims").into());
+    };
+    let file = File::open(path).map_err(|e| Error::open(path, e))?;
+    let (width, height, bytes) = (layout.width, layout.height, layout.sample.bytes());
+    let expected = (width * height * bytes) as u64;
+    let length = file.metadata().map_err(|e| Error::open(path, e))?.len();
+    if length != expected {
+        return Err(format!(
+            "expected {} bytes for {}x{} {} samples, the file has {} bytes",
+            expected,
+            width,
+            height,
+            layout.sample.name(),
+            length
+        )
+        .into());
+    }
+    let order = if layout.big_endian { "big" } else { "little" };
+    debug!("{}: raw {}x{} {} samples, {} endian", path.display(), width, height, layout.sample.name(), order);
+
+    let mut reader = BufReader::new(file);
+    let mut image = Array2::<f32>::zeros((height, width));
+    let mut buffer = vec![0u8; width * bytes];
+    for mut row in image.rows_mut() {
+        reader.read_exact(&mut buffer)?;
+        for (v, sample) in row.iter_mut().zip(buffer.chunks_exact(bytes)) {
+            *v = match (layout.sample, layout.big_endian) {
+                (RawSample::U8, _) => sample[0] as f32,
+                (RawSample::U16, false) => u16::from_le_bytes([sample[0], sample[1]]) as f32,
+                (RawSample::U16, true) => u16::from_be_bytes([sample[0], sample[1]]) as f32,
+                (RawSample::F32, false) => f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]),
+                (RawSample::F32, true) => f32::from_be_bytes([sample[0], sample[1], sample[2], sample[3]]),
+            };
+        }
+    }
+    let (pixel_format, container_max) = match layout.sample {
+        RawSample::U8 => ("L8", Some(255.0)),
+        RawSample::U16 => ("L16", Some(65535.0)),
+        RawSample::F32 => ("L32F", None),
+    };
+    Ok((image, pixel_format.to_string(), container_max))
+}
@@ -0,0 +1,467 @@
+//! Remote inputs and outputs: objects at http(s):// and s3:// URLs, downloaded into local files or,
+//! for Zarr stores, read object by object as their chunks are needed, and files uploaded to s3://
+//! URLs.
+//!
+//! S3 requests are signed with AWS Signature Version 4 by the credentials of the standard chain:
+//! AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN, or else those of the profile
+//! AWS_PROFILE (default `default`) in the shared credentials and config files. Without credentials
+//! requests are sent unsigned, as public buckets allow. The region is that of AWS_REGION,
+//! AWS_DEFAULT_REGION or the profile (default us-east-1), and AWS_ENDPOINT_URL_S3 or
+//! AWS_ENDPOINT_URL address an S3 compatible store by path instead of AWS.
+//!
+//! Requests that fail on the network, time out or get a server error are retried `set_retries`
+//! times, waiting 1, 2, 4... seconds in between, and downloads continue where they stopped.
+use crate::tiff_writer::civil_time;
+use crate::Error;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+use ureq::http::{self, Response};
+use ureq::{Agent, Body};
+
+/// Default number of retries of a failed request.
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Size of the parts of multipart uploads, and the largest file uploaded in one request.
+const PART_SIZE: u64 = 64 << 20;
+
+/// Longest wait between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Number of retries of a failed request.
+static RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_RETRIES);
+
+/// Set the number of times a request failing on the network or with a server error is retried.
+pub fn set_retries(retries: u32) {
+    RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// Whether the URL of a remote input names a Zarr store, by its `.zarr` extension, as there are no
+/// directories to look into.
+pub fn is_zarr(url: &str) -> bool {
+    url.trim_end_matches('/').to_ascii_lowercase().ends_with(".zarr")
+}
+
+/// Download the object at `url` into a new file at `path`, returning its size.
+pub fn download(url: &str, path: &Path) -> Result<u64, Error> {
+    let file = File::create(path).map_err(|e| Error::open(path, e))?;
+    let mut file = BufWriter::new(file);
+    let mut written = 0;
+    with_retries(url, || {
+        // A retry continues from the bytes written, or starts over if the server sends them all
+        let range = format!("bytes={}-", written);
+        let headers = if written > 0 { vec![("range", range)] } else { Vec::new() };
+        let mut response = send("GET", url, &[], &headers, &[])?;
+        if written > 0 && response.status() != http::StatusCode::PARTIAL_CONTENT {
+            debug!("{}: the server ignored the range, downloading again from the start", url);
+            file.seek(SeekFrom::Start(0)).and_then(|_| file.get_ref().set_len(0)).map_err(Failure::Permanent)?;
+            written = 0;
+        }
+        let mut body = response.body_mut().as_reader();
+        let mut buffer = vec![0; 1 << 20];
+        loop {
+            match body.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    file.write_all(&buffer[..n]).map_err(Failure::Permanent)?;
+                    written += n as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(Failure::Transient(format!("after {} bytes: {}", written, e))),
+            }
+        }
+    })
+    .and_then(|()| file.flush())
+    .map_err(|e| Error::open(Path::new(url), e))?;
+    debug!("{}: downloaded {} bytes to {}", url, written, path.display());
+    Ok(written)
+}
+
+/// Read the whole object at `url`, failing with `ErrorKind::NotFound` when it does not exist as
+/// `fs::read` does for a missing file.
+pub(crate) fn read_object(url: &str) -> io::Result<Vec<u8>> {
+    // Keys joined as paths on Windows have backslashes
+    let url = url.replace('\\', "/");
+    with_retries(&url, || {
+        let mut response = send("GET", &url, &[], &[], &[])?;
+        let mut data = Vec::new();
+        match response.body_mut().as_reader().read_to_end(&mut data) {
+            Ok(_) => Ok(data),
+            Err(e) => Err(Failure::Transient(e.to_string())),
+        }
+    })
+}
+
+/// Whether the object at `url` exists.
+pub fn exists(url: &str) -> io::Result<bool> {
+    match with_retries(url, || send("HEAD", url, &[], &[], &[])) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Upload the file at `path` to the S3 object at `url`, replacing it, in one request up to 64 MiB
+/// and in a multipart upload of parts of that size beyond.
+pub fn upload(path: &Path, url: &str) -> Result<(), Error> {
+    let write_error = |message: String| Error::Write {
+        path: PathBuf::from(url),
+        message: format!("{}: {}", url, message),
+    };
+    if !url.starts_with("s3://") {
+        return Err(write_error("outputs can only be uploaded to s3:// URLs".to_string()));
+    }
+    let mut file = File::open(path).map_err(|e| Error::open(path, e))?;
+    let size = file.metadata().map_err(|e| Error::open(path, e))?.len();
+    if size <= PART_SIZE {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|e| Error::open(path, e))?;
+        with_retries(url, || send("PUT", url, &[], &[], &data)).map_err(|e| write_error(e.to_string()))?;
+    } else {
+        upload_parts(&mut file, size, url).map_err(|e| write_error(e.to_string()))?;
+    }
+    debug!("{}: uploaded {} bytes from {}", url, size, path.display());
+    Ok(())
+}
+
+/// Upload `size` bytes of `file` to `url` in a multipart upload, aborted when a part fails.
+fn upload_parts(file: &mut File, size: u64, url: &str) -> io::Result<()> {
+    let created = with_retries(url, || {
+        let mut response = send("POST", url, &[("uploads", "")], &[], &[])?;
+        Ok(response.body_mut().read_to_string().unwrap_or_default())
+    })?;
+    let upload_id = xml_value(&created, "UploadId").ok_or_else(|| io::Error::other("no upload ID in the response"))?;
+    let parts = size.div_ceil(PART_SIZE);
+    let result = (|| {
+        let mut etags = Vec::new();
+        let mut data = Vec::new();
+        for part in 1..=parts {
+            data.clear();
+            (&mut *file).take(PART_SIZE).read_to_end(&mut data)?;
+            let number = part.to_string();
+            let query = [("partNumber", number.as_str()), ("uploadId", upload_id.as_str())];
+            let response = with_retries(url, || send("PUT", url, &query, &[], &data))?;
+            let etag = response.headers().get("etag").and_then(|etag| etag.to_str().ok());
+            etags.push(etag.ok_or_else(|| io::Error::other(format!("no ETag for part {}", part)))?.to_string());
+            debug!("{}: uploaded part {} of {}", url, part, parts);
+        }
+        let mut complete = "<CompleteMultipartUpload>".to_string();
+        for (part, etag) in etags.iter().enumerate() {
+            complete += &format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", part + 1, etag);
+        }
+        complete += "</CompleteMultipartUpload>";
+        with_retries(url, || {
+            let mut response = send("POST", url, &[("uploadId", upload_id.as_str())], &[], complete.as_bytes())?;
+            // Completion reports late failures with an error document and status 200
+            let body = response.body_mut().read_to_string().unwrap_or_default();
+            match xml_value(&body, "Code") {
+                Some(code) => Err(Failure::Transient(format!("completing the upload: {}", code))),
+                None => Ok(()),
+            }
+        })
+    })();
+    if result.is_err() {
+        // The parts uploaded so far are stored, and billed, until the upload is aborted
+        if let Err(e) = send("DELETE", url, &[("uploadId", upload_id.as_str())], &[], &[]) {
+            warn!("{}: the failed upload {} could not be aborted: {}", url, upload_id, e.message());
+        }
+    }
+    result
+}
+
+/// Why a request failed, transient failures are retried.
+enum Failure {
+    /// A network failure, timeout or server error.
+    Transient(String),
+    Permanent(io::Error),
+}
+
+impl Failure {
+    fn message(&self) -> String {
+        match self {
+            Failure::Transient(message) => message.clone(),
+            Failure::Permanent(e) => e.to_string(),
+        }
+    }
+}
+
+impl From<ureq::Error> for Failure {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Io(_)
+            | ureq::Error::Timeout(_)
+            | ureq::Error::HostNotFound
+            | ureq::Error::ConnectionFailed
+            | ureq::Error::Protocol(_)
+            | ureq::Error::BodyStalled => Failure::Transient(e.to_string()),
+            e => Failure::Permanent(io::Error::other(e.to_string())),
+        }
+    }
+}
+
+/// Run `attempt` until it succeeds, fails for good, or has failed transiently once more than the
+/// retries, waiting twice as long after each failure.
+fn with_retries<T>(url: &str, mut attempt: impl FnMut() -> Result<T, Failure>) -> io::Result<T> {
+    let retries = RETRIES.load(Ordering::Relaxed);
+    let mut retry = 0;
+    loop {
+        match attempt() {
+            Ok(value) => return Ok(value),
+            Err(Failure::Permanent(e)) => return Err(e),
+            Err(Failure::Transient(message)) if retry < retries => {
+                let delay = Duration::from_secs(1 << retry.min(5)).min(MAX_BACKOFF);
+                warn!("{}: {}, retrying in {}s ({} of {})", url, message, delay.as_secs(), retry + 1, retries);
+                std::thread::sleep(delay);
+                retry += 1;
+            }
+            Err(Failure::Transient(message)) if retries > 0 => {
+                return Err(io::Error::other(format!("{}, after {} retries", message, retries)));
+            }
+            Err(Failure::Transient(message)) => return Err(io::Error::other(message)),
+        }
+    }
+}
+
+/// The agent shared by all requests, which checks no status codes so that `check_status` tells
+/// the failures to retry.
+fn agent() -> &'static Agent {
+    static AGENT: OnceLock<Agent> = OnceLock::new();
+    AGENT.get_or_init(|| {
+        let config = Agent::config_builder()
+            .http_status_as_error(false)
+            .user_agent(concat!("virtualhe/", env!("CARGO_PKG_VERSION")))
+            .timeout_connect(Some(Duration::from_secs(30)))
+            .timeout_recv_response(Some(Duration::from_secs(120)))
+            .build();
+        Agent::new_with_config(config)
+    })
+}
+
+/// Send a request for the object at an http(s):// or s3:// URL with the `query` parameters, signed
+/// for S3 objects when there are credentials, and check its status.
+fn send(
+    method: &str,
+    url: &str,
+    query: &[(&str, &str)],
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> Result<Response<Body>, Failure> {
+    let mut query: Vec<(String, String)> = query.iter().map(|(key, value)| (encode(key, true), encode(value, true))).collect();
+    query.sort();
+    let query = query.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join("&");
+    let (mut target, mut signed) = match url.strip_prefix("s3://") {
+        Some(object) => s3_request(method, object, &query, body)?,
+        None => (url.to_string(), Vec::new()),
+    };
+    if !query.is_empty() {
+        target += if target.contains('?') { "&" } else { "?" };
+        target += &query;
+    }
+    signed.extend(headers.iter().map(|(name, value)| (name.to_string(), value.clone())));
+    let mut request = http::Request::builder().method(method).uri(&target);
+    for (name, value) in &signed {
+        request = request.header(name, value);
+    }
+    let invalid = |e: http::Error| Failure::Permanent(io::Error::other(e.to_string()));
+    // Requests without a body send none, rather than an empty one
+    let response = match method {
+        "PUT" | "POST" => agent().run(request.body(body).map_err(invalid)?)?,
+        _ => agent().run(request.body(()).map_err(invalid)?)?,
+    };
+    check_status(response)
+}
+
+/// Fail on a status that is not a success, transiently on timeouts, throttling and server errors.
+fn check_status(mut response: Response<Body>) -> Result<Response<Body>, Failure> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    // S3 errors name their code and message in XML
+    let body = response.body_mut().with_config().limit(1 << 16).read_to_string().unwrap_or_default();
+    let message = match (xml_value(&body, "Code"), xml_value(&body, "Message")) {
+        (Some(code), Some(message)) => format!("HTTP {} {}: {}", status.as_u16(), code, message),
+        _ => format!("HTTP {}", status),
+    };
+    match status.as_u16() {
+        404 => Err(Failure::Permanent(io::Error::new(ErrorKind::NotFound, message))),
+        401 | 403 => Err(Failure::Permanent(io::Error::new(ErrorKind::PermissionDenied, message))),
+        408 | 429 | 500..=599 => Err(Failure::Transient(message)),
+        _ => Err(Failure::Permanent(io::Error::other(message))),
+    }
+}
+
+/// Text of the first `<name>` element of an XML document.
+fn xml_value(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}", name))?;
+    Some(xml[start..end].to_string())
+}
+
+/// Credentials signing S3 requests.
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+/// Credentials, region and endpoint of S3 requests, from the environment and the profile.
+struct S3Config {
+    credentials: Option<Credentials>,
+    region: String,
+    /// Endpoint of an S3 compatible store, whose buckets are addressed by path.
+    endpoint: Option<String>,
+}
+
+/// The S3 configuration, read on the first S3 request.
+fn s3_config() -> &'static S3Config {
+    static CONFIG: OnceLock<S3Config> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let home = env("HOME").or_else(|| env("USERPROFILE")).map(PathBuf::from).unwrap_or_default();
+        let profile = env("AWS_PROFILE").unwrap_or_else(|| "default".to_string());
+        let file = |variable: &str, name: &str| env(variable).map(PathBuf::from).unwrap_or_else(|| home.join(".aws").join(name));
+        // The credentials file takes precedence over the config file, which may hold credentials too
+        let mut settings = read_profile(&file("AWS_CONFIG_FILE", "config"), &profile, true);
+        settings.extend(read_profile(&file("AWS_SHARED_CREDENTIALS_FILE", "credentials"), &profile, false));
+        let credentials = match (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY")) {
+            (Some(access_key), Some(secret_key)) => Some(Credentials {
+                access_key,
+                secret_key,
+                session_token: env("AWS_SESSION_TOKEN"),
+            }),
+            _ => match (settings.get("aws_access_key_id"), settings.get("aws_secret_access_key")) {
+                (Some(access_key), Some(secret_key)) => Some(Credentials {
+                    access_key: access_key.clone(),
+                    secret_key: secret_key.clone(),
+                    session_token: settings.get("aws_session_token").cloned(),
+                }),
+                _ => None,
+            },
+        };
+        let region = env("AWS_REGION")
+            .or_else(|| env("AWS_DEFAULT_REGION"))
+            .or_else(|| settings.get("region").cloned())
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = env("AWS_ENDPOINT_URL_S3").or_else(|| env("AWS_ENDPOINT_URL"));
+        match &credentials {
+            Some(credentials) => debug!("S3 requests signed for {} in {}", credentials.access_key, region),
+            None => debug!("no AWS credentials, S3 requests are sent unsigned"),
+        }
+        S3Config {
+            credentials,
+            region,
+            endpoint: endpoint.map(|endpoint| endpoint.trim_end_matches('/').to_string()),
+        }
+    })
+}
+
+/// Settings of a profile in an AWS shared credentials file, in sections `[name]`, or in a config
+/// file, in sections `[profile name]` and `[default]`. Empty when the file does not exist.
+fn read_profile(path: &Path, profile: &str, config: bool) -> HashMap<String, String> {
+    let section = if config && profile != "default" { format!("profile {}", profile) } else { profile.to_string() };
+    let mut settings = HashMap::new();
+    let mut selected = false;
+    for line in std::fs::read_to_string(path).unwrap_or_default().lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            selected = name.trim() == section;
+        } else if let Some((key, value)) = line.split_once('=').filter(|_| selected) {
+            settings.insert(key.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    settings
+}
+
+/// The URL and headers of a request for the S3 object `bucket/key`, with the encoded `query`,
+/// signed with AWS Signature Version 4 when there are credentials.
+fn s3_request(method: &str, object: &str, query: &str, body: &[u8]) -> Result<(String, Vec<(String, String)>), Failure> {
+    let config = s3_config();
+    let (bucket, key) = object.split_once('/').unwrap_or((object, ""));
+    if bucket.is_empty() || key.is_empty() {
+        let message = format!("s3://{}: expected s3://BUCKET/KEY", object);
+        return Err(Failure::Permanent(io::Error::new(ErrorKind::InvalidInput, message)));
+    }
+    let key = encode(key, false);
+    // Buckets with dots do not match the certificate of their virtual host
+    let (host, path, scheme) = match &config.endpoint {
+        Some(endpoint) => {
+            let (scheme, host) = endpoint.split_once("://").unwrap_or(("https", endpoint));
+            (host.to_string(), format!("/{}/{}", bucket, key), scheme)
+        }
+        None if bucket.contains('.') => (format!("s3.{}.amazonaws.com", config.region), format!("/{}/{}", bucket, key), "https"),
+        None => (format!("{}.s3.{}.amazonaws.com", bucket, config.region), format!("/{}", key), "https"),
+    };
+    let url = format!("{}://{}{}", scheme, host, path);
+    let Some(credentials) = &config.credentials else {
+        return Ok((url, Vec::new()));
+    };
+
+    let [year, month, day, hour, minute, second] = civil_time(SystemTime::now());
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let timestamp = format!("{}T{:02}{:02}{:02}Z", date, hour, minute, second);
+    let payload = hex(&Sha256::digest(body));
+    let mut headers = vec![
+        ("host".to_string(), host),
+        ("x-amz-content-sha256".to_string(), payload.clone()),
+        ("x-amz-date".to_string(), timestamp.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token".to_string(), token.clone()));
+    }
+    let names = headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(";");
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+    let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query, canonical_headers, names, payload);
+    let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", timestamp, scope, hex(&Sha256::digest(canonical_request)));
+    let mut key = hmac(format!("AWS4{}", credentials.secret_key).as_bytes(), date.as_bytes());
+    for part in [config.region.as_str(), "s3", "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key, scope, names, signature
+    );
+    // The host header is set from the URL
+    headers.remove(0);
+    headers.push(("authorization".to_string(), authorization));
+    Ok((url, headers))
+}
+
+/// Percent-encode all but the unreserved characters, and slashes unless `slash`.
+fn encode(value: &str, slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if !slash => encoded.push('/'),
+            _ => encoded += &format!("%{:02X}", byte),
+        }
+    }
+    encoded
+}
+
+/// HMAC-SHA256 of `data` with `key`.
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let inner = Sha256::new().chain_update(block.map(|byte| byte ^ 0x36)).chain_update(data).finalize();
+    Sha256::new().chain_update(block.map(|byte| byte ^ 0x5c)).chain_update(inner).finalize().to_vec()
+}
+
+/// Lowercase hex of bytes.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
@@ -0,0 +1,280 @@
+//! Z-stack processing: every plane of multi-page nucleus and eosin TIFFs, or of the channels of
+//! Imaris volumes, is rendered, into one multi-page RGB TIFF or a numbered series of images.
+//!
+//! Planes are scaled either independently, or with thresholds estimated over the whole volume so
+//! that the intensity does not flicker through the stack.
+use crate::interrupt;
+use crate::tiff_reader::count_pages;
+use crate::tiled::ThresholdSampler;
+use crate::{
+    crop_to_common, decode_raw, is_ims, load_channel_with, normalize_input, render_as, save_with, scale_with,
+    tiff_writer, Error, LoadOptions, OutputDepth, OutputSample, Params, SaveOptions, ScaleOptions, Thresholds,
+};
+use image::ImageFormat;
+use log::{debug, trace};
+use ndarray::{Array2, Array3};
+use std::path::{Path, PathBuf};
+
+/// How the planes of a stack are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StackOutput {
+    /// One multi-page RGB TIFF with a page per plane.
+    #[default]
+    Multipage,
+    /// One image per plane, numbered `output_z0000.tiff`, `output_z0001.tiff`, ...
+    Series,
+}
+
+impl std::str::FromStr for StackOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "multipage" => Ok(StackOutput::Multipage),
+            "series" => Ok(StackOutput::Series),
+            _ => Err(format!("unknown stack output '{}', expected one of: multipage, series", s)),
+        }
+    }
+}
+
+/// How the planes of a stack are normalized.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StackScaling {
+    /// Input maximum and percentiles of each plane.
+    #[default]
+    Plane,
+    /// Input maximum and percentiles of the whole volume.
+    Global,
+}
+
+impl std::str::FromStr for StackScaling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plane" => Ok(StackScaling::Plane),
+            "global" => Ok(StackScaling::Global),
+            _ => Err(format!("unknown stack scaling '{}', expected one of: plane, global", s)),
+        }
+    }
+}
+
+/// One input channel of a stack render.
+#[derive(Debug, Clone)]
+pub struct StackChannel<'a> {
+    /// Path to a multi-page TIFF with one page per plane, or to an Imaris volume.
+    pub path: &'a Path,
+    /// How each plane is read, the page, or z plane of an Imaris volume, is set per plane.
+    pub load: &'a LoadOptions,
+    /// How each plane, or the whole volume, is scaled.
+    pub scale: &'a ScaleOptions,
+}
+
+/// Options for `render_stack`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StackOptions {
+    /// Multi-page TIFF or numbered series output.
+    pub output: StackOutput,
+    /// Per-plane or whole-volume normalization.
+    pub scaling: StackScaling,
+    /// Crop both channels to their overlapping region instead of failing when their sizes differ.
+    pub crop_to_common: bool,
+    /// Bit depth of the output images.
+    pub output_depth: OutputDepth,
+}
+
+/// Path of plane `z` of a numbered series, e.g. `output_z0003.tiff` for `output.tiff`.
+pub fn series_path(output_path: &Path, z: usize) -> PathBuf {
+    let stem = output_path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output_path.extension() {
+        Some(extension) => format!("{}_z{:04}.{}", stem, z, extension.to_string_lossy()),
+        None => format!("{}_z{:04}", stem, z),
+    };
+    output_path.with_file_name(name)
+}
+
+/// Render a virtual H&E image for every plane of two multi-page TIFF stacks or Imaris volumes.
+///
+/// Multi-page output is written to `output_path`, series output to `series_path(output_path, z)`
+/// with the save options applied to each image. Returns the number of planes.
+pub fn render_stack(
+    nucleus: &StackChannel,
+    eosin: &StackChannel,
+    params: &Params,
+    output_path: &Path,
+    options: &StackOptions,
+    save_options: &SaveOptions,
+) -> Result<usize, Error> {
+    if options.output == StackOutput::Multipage {
+        if ImageFormat::from_path(output_path).ok() != Some(ImageFormat::Tiff) {
+            return Err(Error::InvalidOptions(format!(
+                "multi-page stack output requires a TIFF path, got {}",
+                output_path.display()
+            )));
+        }
+        if save_options.pyramid_tile_size.is_some() || save_options.ome.is_some() || save_options.zarr.is_some() {
+            return Err(Error::InvalidOptions(
+                "pyramidal, OME-TIFF and OME-Zarr outputs of a stack require series output".to_string(),
+            ));
+        }
+    }
+
+    if nucleus.scale.equalize.is_some() || eosin.scale.equalize.is_some() {
+        return Err(Error::InvalidOptions("histogram equalization is not supported by stack rendering".to_string()));
+    }
+    if nucleus.scale.auto_contrast.is_some() || eosin.scale.auto_contrast.is_some() {
+        return Err(Error::InvalidOptions("automatic contrast is not supported by stack rendering".to_string()));
+    }
+
+    // Pages of TIFF stacks are read whatever the channel, only Imaris volumes have channels
+    let selects_channel = |channel: &&StackChannel| channel.load.channel.is_some() && !is_ims(channel.path);
+    if let Some(channel) = [nucleus, eosin].into_iter().find(selects_channel) {
+        return Err(Error::InvalidOptions(format!(
+            "{}: channels of stacks can only be selected from Imaris volumes, TIFF stacks are read page by page",
+            channel.path.display()
+        )));
+    }
+
+    // Check that the stacks line up before any processing starts
+    let planes = count_planes(nucleus)?;
+    let eosin_planes = count_planes(eosin)?;
+    if planes != eosin_planes {
+        return Err(Error::Decode {
+            path: eosin.path.to_path_buf(),
+            message: format!(
+                "nucleus stack {} has {} planes but eosin stack {} has {}",
+                nucleus.path.display(),
+                planes,
+                eosin.path.display(),
+                eosin_planes
+            ),
+        });
+    }
+
+    // Whole-volume normalization is estimated in a first pass over all planes
+    let global = match options.scaling {
+        StackScaling::Plane => None,
+        StackScaling::Global => Some((volume_thresholds(nucleus, planes)?, volume_thresholds(eosin, planes)?)),
+    };
+
+    debug!("rendering {} planes with {:?} scaling", planes, options.scaling);
+    let next_plane = |z| {
+        interrupt::check(|| format!("{}: interrupted after {} of {} planes", output_path.display(), z, planes))?;
+        trace!("rendering plane {} of {}", z, planes);
+        let (nucleus_plane, eosin_plane) = match global {
+            None => (scaled_plane(nucleus, z)?, scaled_plane(eosin, z)?),
+            Some((nucleus_global, eosin_global)) => (
+                normalized_plane(nucleus, z, nucleus_global)?,
+                normalized_plane(eosin, z, eosin_global)?,
+            ),
+        };
+        if nucleus_plane.dim() == eosin_plane.dim() {
+            Ok((nucleus_plane, eosin_plane))
+        } else if options.crop_to_common {
+            Ok(crop_to_common(nucleus_plane, eosin_plane))
+        } else {
+            Err(Error::ShapeMismatch {
+                nucleus: nucleus.path.to_path_buf(),
+                nucleus_size: (nucleus_plane.ncols(), nucleus_plane.nrows()),
+                eosin: eosin.path.to_path_buf(),
+                eosin_size: (eosin_plane.ncols(), eosin_plane.nrows()),
+                plane: Some(z),
+            }
+            .into())
+        }
+    };
+    match options.output_depth {
+        OutputDepth::Eight => write_stack::<u8, _>(output_path, planes, params, options, save_options, next_plane),
+        OutputDepth::Sixteen => write_stack::<u16, _>(output_path, planes, params, options, save_options, next_plane),
+    }
+    .map_err(|e| Error::write(output_path, e))?;
+    Ok(planes)
+}
+
+/// Number of planes of a stack: its pages, or the z planes of an Imaris volume.
+fn count_planes(channel: &StackChannel) -> Result<usize, Error> {
+    if !is_ims(channel.path) {
+        return count_pages(channel.path);
+    }
+    #[cfg(feature = "ims")]
+    return crate::ims_reader::planes(channel.path, channel.load).map_err(|e| Error::decode(channel.path, e));
+    #[cfg(not(feature = "ims"))]
+    return Err(Error::unsupported(channel.path, "Imaris input requires a build with the ims feature"));
+}
+
+/// Load options reading plane `z` of a channel.
+fn plane_options(channel: &StackChannel, z: usize) -> LoadOptions {
+    if is_ims(channel.path) {
+        return LoadOptions {
+            z: Some(z),
+            ..channel.load.clone()
+        };
+    }
+    LoadOptions {
+        page: Some(z),
+        ..channel.load.clone()
+    }
+}
+
+/// Read and scale plane `z` of a channel on its own.
+fn scaled_plane(channel: &StackChannel, z: usize) -> Result<Array2<f32>, Error> {
+    let (mut plane, info) = load_channel_with(channel.path, &plane_options(channel, z))?;
+    let scale = channel.scale.clone().with_integer_input_max(info.input_max);
+    scale_with(&mut plane, &scale).map_err(|e| Error::scale(channel.path, format!("plane {}: {}", z, e)))?;
+    Ok(plane)
+}
+
+/// Read plane `z` of a channel and apply the whole-volume input maximum and thresholds.
+fn normalized_plane(
+    channel: &StackChannel,
+    z: usize,
+    (input_max, thresholds): (f32, Thresholds),
+) -> Result<Array2<f32>, Error> {
+    let (mut plane, _, _) = decode_raw(channel.path, &plane_options(channel, z))?;
+    plane.par_mapv_inplace(|v| thresholds.apply_gamma(normalize_input(v, input_max, channel.load.invert), channel.scale.gamma));
+    Ok(plane)
+}
+
+/// Compute the input maximum and scaling thresholds of a channel over all planes of its stack.
+fn volume_thresholds(channel: &StackChannel, planes: usize) -> Result<(f32, Thresholds), Error> {
+    let mut sampler = ThresholdSampler::new();
+    let mut container_max = None;
+    for z in 0..planes {
+        interrupt::check(|| {
+            let path = channel.path.display();
+            format!("{}: interrupted computing the thresholds, after {} of {} planes", path, z, planes)
+        })?;
+        let (plane, _, max) = decode_raw(channel.path, &plane_options(channel, z))?;
+        sampler.push(&plane);
+        container_max = max;
+    }
+    sampler.finish(channel.path, channel.load, channel.scale, container_max)
+}
+
+/// Render the planes requested from `next_plane` with samples of type `T` and write them.
+fn write_stack<T, F>(
+    output_path: &Path,
+    planes: usize,
+    params: &Params,
+    options: &StackOptions,
+    save_options: &SaveOptions,
+    mut next_plane: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: OutputSample,
+    F: FnMut(usize) -> Result<(Array2<f32>, Array2<f32>), Box<dyn std::error::Error>>,
+{
+    let mut next_rgb = |z| -> Result<Array3<T>, Box<dyn std::error::Error>> {
+        let (nucleus, eosin) = next_plane(z)?;
+        Ok(render_as::<T>(nucleus, eosin, params))
+    };
+    match options.output {
+        StackOutput::Multipage => tiff_writer::write_rgb_pages(output_path, planes, save_options, next_rgb),
+        StackOutput::Series => {
+            for z in 0..planes {
+                save_with(next_rgb(z)?, series_path(output_path, z), save_options)?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! Band-wise reading of grayscale and RGB(A) TIFF images, shared by the tiled renderer and by page
+//! selection of multi-page inputs, and memory-mapped reading of uncompressed grayscale TIFFs.
+use crate::{luma, ome, Error, LoadOptions, RawImage, RgbChannel};
+use ndarray::parallel::prelude::*;
+use ndarray::{s, Array2, Axis};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use tiff::decoder::{ChunkType, Decoder, DecodingResult, Limits};
+use tiff::tags::{PlanarConfiguration, SampleFormat, Tag};
+
+/// Reads bands of rows from a striped or tiled TIFF as raw (not normalized) values, decoding only
+/// the chunks that intersect the requested rows.
+pub(crate) struct BandReader {
+    path: PathBuf,
+    decoder: Decoder<BufReader<File>>,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    chunk_width: u32,
+    chunk_height: u32,
+    chunks_across: u32,
+    samples_per_pixel: usize,
+    rgb_channel: Option<RgbChannel>,
+    /// Pixel format of the page (e.g., L16, Rgb8, L32F).
+    pub(crate) pixel_format: String,
+    /// Maximum value of integer samples, `None` for floating point data.
+    pub(crate) container_max: Option<f32>,
+    // Last decoded row of chunks and its index
+    cached: Option<(u32, Array2<f32>)>,
+}
+
+impl BandReader {
+    /// Open the page of a TIFF selected by the page or channel in `options`, or its first page.
+    pub(crate) fn open(path: &Path, options: &LoadOptions) -> Result<Self, Error> {
+        Self::open_page(path, options).map_err(|e| Error::decode(path, e))
+    }
+
+    fn open_page(path: &Path, options: &LoadOptions) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = BufReader::new(File::open(path).map_err(|e| Error::open(path, e))?);
+        let mut decoder = Decoder::new(file)
+            .map_err(|e| format!("{}: expected a TIFF input: {}", path.display(), e))?
+            .with_limits(Limits::unlimited());
+
+        // Channels are resolved against the OME-XML of the first page, if any
+        let page = match (options.page, &options.channel) {
+            (Some(page), _) => Some(page),
+            (None, Some(channel)) => {
+                let description = decoder.get_tag_ascii_string(Tag::ImageDescription).ok();
+                let page = ome::channel_page(description.as_deref(), channel);
+                Some(page.map_err(|e| format!("{}: {}", path.display(), e))?)
+            }
+            (None, None) => None,
+        };
+        if let Some(page) = page {
+            if decoder.seek_to_image(page).is_err() {
+                let pages = count_pages(path)?;
+                return Err(match &options.channel {
+                    Some(channel) if options.page.is_none() => format!(
+                        "{}: channel {} is page {} but the file has {} pages",
+                        path.display(),
+                        channel,
+                        page,
+                        pages
+                    ),
+                    _ => format!("{}: page {} is out of range, the file has {} pages", path.display(), page, pages),
+                }
+                .into());
+            }
+        }
+
+        let (width, height) = decoder.dimensions()?;
+        let float = decoder.find_tag_unsigned::<u16>(Tag::SampleFormat)? == Some(SampleFormat::IEEEFP.to_u16());
+        let (samples_per_pixel, bits, name) = match decoder.colortype()? {
+            tiff::ColorType::Gray(bits) => (1, bits, "L"),
+            tiff::ColorType::RGB(bits) => (3, bits, "Rgb"),
+            tiff::ColorType::RGBA(bits) => (4, bits, "Rgba"),
+            colortype => return Err(Error::unsupported(path, format!("unsupported color type {:?}", colortype)).into()),
+        };
+        if decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)? == Some(PlanarConfiguration::Planar.to_u16())
+            && samples_per_pixel > 1
+        {
+            return Err(Error::unsupported(path, "planar configuration is not supported").into());
+        }
+        let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+        let chunks_across = match decoder.get_chunk_type() {
+            ChunkType::Strip => 1,
+            ChunkType::Tile => width.div_ceil(chunk_width),
+        };
+        Ok(BandReader {
+            path: path.to_path_buf(),
+            decoder,
+            width,
+            height,
+            chunk_width,
+            chunk_height,
+            chunks_across,
+            samples_per_pixel,
+            rgb_channel: options.rgb_channel,
+            pixel_format: format!("{}{}{}", name, bits, if float { "F" } else { "" }),
+            container_max: if float { None } else { Some(((1u64 << bits) - 1) as f32) },
+            cached: None,
+        })
+    }
+
+    /// Raw values of rows `y0..y1`, limited to the first `width` columns.
+    pub(crate) fn read_rows(&mut self, y0: u32, y1: u32, width: u32) -> Result<Array2<f32>, Error> {
+        let mut band = Array2::<f32>::zeros(((y1 - y0) as usize, width as usize));
+        let mut y = y0;
+        while y < y1 {
+            let index = y / self.chunk_height;
+            let chunk_y0 = index * self.chunk_height;
+            let chunk_rows = self.chunk_row(index)?;
+            let take = y1.min(chunk_y0 + chunk_rows.nrows() as u32) - y;
+            band.slice_mut(s![(y - y0) as usize..(y - y0 + take) as usize, ..]).assign(&chunk_rows.slice(s![
+                (y - chunk_y0) as usize..(y - chunk_y0 + take) as usize,
+                ..width as usize
+            ]));
+            y += take;
+        }
+        Ok(band)
+    }
+
+    /// Raw values of the `index`th row of chunks across the full image width.
+    fn chunk_row(&mut self, index: u32) -> Result<&Array2<f32>, Error> {
+        if !matches!(self.cached, Some((cached, _)) if cached == index) {
+            let rows = self.chunk_height.min(self.height - index * self.chunk_height);
+            let mut chunk_rows = Array2::<f32>::zeros((rows as usize, self.width as usize));
+            for chunk_x in 0..self.chunks_across {
+                let chunk = index * self.chunks_across + chunk_x;
+                let (data_width, _) = self.decoder.chunk_data_dimensions(chunk);
+                let values = decoding_result_to_f32(self.decoder.read_chunk(chunk).map_err(|e| Error::decode(&self.path, e))?);
+                let x0 = (chunk_x * self.chunk_width) as usize;
+                let row_len = data_width as usize * self.samples_per_pixel;
+                for (r, row) in values.chunks_exact(row_len).enumerate() {
+                    for (c, pixel) in row.chunks_exact(self.samples_per_pixel).enumerate() {
+                        chunk_rows[[r, x0 + c]] = match (self.samples_per_pixel, self.rgb_channel) {
+                            (1, _) => pixel[0],
+                            (_, Some(channel)) => pixel[channel as usize],
+                            (_, None) => luma(pixel[0], pixel[1], pixel[2]),
+                        };
+                    }
+                }
+            }
+            self.cached = Some((index, chunk_rows));
+        }
+        Ok(&self.cached.as_ref().unwrap().1)
+    }
+}
+
+/// Read an uncompressed striped 8bit or 16bit grayscale TIFF by mapping it into memory and
+/// converting its rows in parallel, without decoding a copy of the samples first.
+///
+/// Returns `None` for any other layout, headers the tiff decoder rejects, or strips that do not
+/// fit the file, so that the image is decoded as usual and its errors are those of the decoder.
+pub(crate) fn read_mapped(path: &Path) -> Result<Option<RawImage>, Box<dyn std::error::Error>> {
+    let file = File::open(path).map_err(|e| Error::open(path, e))?;
+    let Ok(Some(layout)) = strip_layout(&file) else {
+        return Ok(None);
+    };
+    let StripLayout { width, height, bits, rows_per_strip, offsets, counts } = layout;
+
+    // SAFETY: the map is only read, a file that is truncated while it is read makes the process
+    // fail, as for any memory-mapped input
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    let row_bytes = width * bits as usize / 8;
+    let strips = height.div_ceil(rows_per_strip);
+    let fits = |strip: usize| {
+        let rows = rows_per_strip.min(height - strip * rows_per_strip);
+        let (offset, count) = (offsets[strip] as usize, counts[strip] as usize);
+        count >= rows * row_bytes && offset.checked_add(rows * row_bytes).is_some_and(|end| end <= map.len())
+    };
+    if offsets.len() != strips || counts.len() != strips || !(0..strips).all(fits) {
+        return Ok(None);
+    }
+    log::debug!("{}: memory-mapped, {} strips of {} rows", path.display(), strips, rows_per_strip);
+
+    let big_endian = map.starts_with(b"MM");
+    let mut image = Array2::<f32>::zeros((height, width));
+    image
+        .axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(y, mut row)| {
+            let start = offsets[y / rows_per_strip] as usize + (y % rows_per_strip) * row_bytes;
+            let bytes = &map[start..start + row_bytes];
+            if bits == 8 {
+                row.iter_mut().zip(bytes).for_each(|(v, &b)| *v = b as f32);
+                return;
+            }
+            for (v, b) in row.iter_mut().zip(bytes.chunks_exact(2)) {
+                let sample = [b[0], b[1]];
+                *v = if big_endian { u16::from_be_bytes(sample) } else { u16::from_le_bytes(sample) } as f32;
+            }
+        });
+    let container_max = ((1u32 << bits) - 1) as f32;
+    Ok(Some((image, format!("L{}", bits), Some(container_max))))
+}
+
+/// Layout of an uncompressed striped 8bit or 16bit grayscale TIFF.
+struct StripLayout {
+    width: usize,
+    height: usize,
+    bits: u8,
+    rows_per_strip: usize,
+    offsets: Vec<u64>,
+    counts: Vec<u64>,
+}
+
+/// Layout of the first page of the TIFF `file`, None for any other layout.
+fn strip_layout(file: &File) -> tiff::TiffResult<Option<StripLayout>> {
+    let mut decoder = Decoder::new(BufReader::new(file))?.with_limits(Limits::unlimited());
+    let bits = match decoder.colortype()? {
+        tiff::ColorType::Gray(bits @ (8 | 16)) => bits,
+        _ => return Ok(None),
+    };
+    let uncompressed = decoder.find_tag_unsigned::<u16>(Tag::Compression)?.unwrap_or(1) == 1;
+    let black_is_zero = decoder.find_tag_unsigned::<u16>(Tag::PhotometricInterpretation)? == Some(1);
+    let unsigned = decoder.find_tag_unsigned::<u16>(Tag::SampleFormat)?.unwrap_or(1) == SampleFormat::Uint.to_u16();
+    if !uncompressed || !black_is_zero || !unsigned || !matches!(decoder.get_chunk_type(), ChunkType::Strip) {
+        return Ok(None);
+    }
+    let (width, height) = decoder.dimensions()?;
+    let (width, height) = (width as usize, height as usize);
+    let rows_per_strip = decoder.find_tag_unsigned::<usize>(Tag::RowsPerStrip)?.unwrap_or(height);
+    Ok(Some(StripLayout {
+        width,
+        height,
+        bits,
+        rows_per_strip: rows_per_strip.clamp(1, height.max(1)),
+        offsets: decoder.get_tag_u64_vec(Tag::StripOffsets)?,
+        counts: decoder.get_tag_u64_vec(Tag::StripByteCounts)?,
+    }))
+}
+
+/// Number of pages (IFDs) in a TIFF.
+pub(crate) fn count_pages(path: &Path) -> Result<usize, Error> {
+    let file = BufReader::new(File::open(path).map_err(|e| Error::open(path, e))?);
+    let decode = |e| Error::decode(path, format!("expected a TIFF input: {}", e));
+    let mut decoder = Decoder::new(file).map_err(decode)?.with_limits(Limits::unlimited());
+    let mut pages = 1;
+    while decoder.more_images() {
+        decoder.next_image().map_err(decode)?;
+        pages += 1;
+    }
+    Ok(pages)
+}
+
+/// Convert decoded TIFF samples of any type to f32.
+fn decoding_result_to_f32(result: DecodingResult) -> Vec<f32> {
+    match result {
+        DecodingResult::U8(v) => v.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::U16(v) => v.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::U32(v) => v.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::U64(v) => v.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::F32(v) => v,
+        DecodingResult::F64(v) => v.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::I8(v) => v.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::I16(v) => v.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::I32(v) => v.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::I64(v) => v.into_iter().map(|v| v as f32).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ImageReader;
+
+    /// Path of a file written by a test in the temporary directory.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("virtualhe-test-{}-{}", std::process::id(), name))
+    }
+
+    /// An uncompressed grayscale TIFF of `samples` with strips of `rows_per_strip` rows, stored in
+    /// the file in reverse order so that their offsets are followed.
+    fn striped_tiff(samples: &Array2<u16>, bits: u16, big_endian: bool, rows_per_strip: usize) -> Vec<u8> {
+        let short = |v: u16| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+        let long = |v: u32| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+        let (height, width) = samples.dim();
+        let strips = height.div_ceil(rows_per_strip);
+        let strip_bytes = |strip: usize| rows_per_strip.min(height - strip * rows_per_strip) * width * bits as usize / 8;
+
+        // Header, the IFD of 9 entries, the offsets and byte counts of the strips, then the strips
+        let arrays = 8 + 2 + 9 * 12 + 4;
+        let data = if strips == 1 { arrays } else { arrays + 8 * strips };
+        let mut offsets = vec![0; strips];
+        let mut end = data;
+        for strip in (0..strips).rev() {
+            offsets[strip] = end;
+            end += strip_bytes(strip);
+        }
+        let mut tiff = if big_endian { b"MM\0\x2a".to_vec() } else { b"II\x2a\0".to_vec() };
+        tiff.extend(long(8));
+        tiff.extend(short(9));
+        let mut entry = |tag: u16, kind: u16, count: usize, value: u32| {
+            tiff.extend(short(tag));
+            tiff.extend(short(kind));
+            tiff.extend(long(count as u32));
+            match (kind, count) {
+                (3, 1) => tiff.extend(short(value as u16).into_iter().chain([0, 0])),
+                _ => tiff.extend(long(value)),
+            }
+        };
+        entry(256, 4, 1, width as u32);
+        entry(257, 4, 1, height as u32);
+        entry(258, 3, 1, bits as u32);
+        entry(259, 3, 1, 1);
+        entry(262, 3, 1, 1);
+        entry(273, 4, strips, if strips == 1 { offsets[0] as u32 } else { arrays as u32 });
+        entry(277, 3, 1, 1);
+        entry(278, 4, 1, rows_per_strip as u32);
+        entry(279, 4, strips, if strips == 1 { strip_bytes(0) as u32 } else { (arrays + 4 * strips) as u32 });
+        tiff.extend(long(0));
+        if strips > 1 {
+            offsets.iter().for_each(|&offset| tiff.extend(long(offset as u32)));
+            (0..strips).for_each(|strip| tiff.extend(long(strip_bytes(strip) as u32)));
+        }
+        for strip in (0..strips).rev() {
+            let rows = samples.slice(s![strip * rows_per_strip..(strip * rows_per_strip + rows_per_strip).min(height), ..]);
+            for &v in rows.iter() {
+                match bits {
+                    8 => tiff.push(v as u8),
+                    _ => tiff.extend(short(v)),
+                }
+            }
+        }
+        tiff
+    }
+
+    #[test]
+    fn mapped_tiffs_read_as_the_decoded_image() {
+        for bits in [8, 16] {
+            let samples = Array2::from_shape_fn((23, 17), |(y, x)| ((x * 131 + y * 977) % (1 << bits)) as u16);
+            for big_endian in [false, true] {
+                for rows_per_strip in [23, 5, 1] {
+                    let name = format!("mapped-{}-{}-{}.tif", bits, big_endian, rows_per_strip);
+                    let path = temp_path(&name);
+                    std::fs::write(&path, striped_tiff(&samples, bits, big_endian, rows_per_strip)).unwrap();
+                    let mapped = read_mapped(&path).unwrap().expect("mapped");
+                    let decoded = ImageReader::open(&path).unwrap().decode().unwrap();
+                    let decoded = crate::image_to_raw(decoded, &path, &LoadOptions::default()).unwrap();
+                    std::fs::remove_file(&path).unwrap();
+                    assert_eq!(mapped, decoded, "{}", name);
+                    assert_eq!(mapped.0, samples.mapv(f32::from), "{}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn headers_the_decoder_rejects_fall_back_to_it() {
+        let path = temp_path("mapped-truncated.tif");
+        let samples = Array2::from_shape_fn((4, 4), |(y, x)| (x + y) as u16);
+        std::fs::write(&path, &striped_tiff(&samples, 8, false, 4)[..20]).unwrap();
+        let mapped = read_mapped(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(mapped, Ok(None)));
+    }
+}
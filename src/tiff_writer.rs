@@ -0,0 +1,499 @@
+//! RGB TIFF encoding with configurable compression, shared by the whole-image, tiled, pyramidal and
+//! multi-page stack outputs.
+use crate::ome::{ome_xml, OmeMetadata};
+use crate::{into_raw_rgb, samples_per_pixel, Error, OutputSample, SaveOptions, TiffCompression};
+use log::{debug, trace};
+use ndarray::parallel::prelude::*;
+use ndarray::{s, Array3, ArrayView3, Axis};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tiff::encoder::compression::{CompressionAlgorithm, Compressor, Deflate, Lzw, Uncompressed};
+use tiff::encoder::{DirectoryEncoder, Rational, TiffEncoder, TiffKind};
+use tiff::tags::{CompressionMethod, PhotometricInterpretation, PlanarConfiguration, ResolutionUnit, SampleFormat, Tag};
+
+/// Target size of uncompressed strips in bytes, matching the tiff crate's own encoder.
+const STRIP_BYTES: usize = 1_000_000;
+
+/// Pyramid levels are added until the long edge is at most this many pixels.
+const PYRAMID_MIN_EDGE: usize = 1024;
+
+/// Headroom over the uncompressed image size for TIFF structures and data that does not compress,
+/// as a fraction (1/n) of the image size.
+const PROJECTED_HEADROOM: u64 = 8;
+
+/// Whether an image with `samples` samples of type `T` may not fit in a standard TIFF, whose 32bit
+/// offsets limit files to 4 GB.
+pub(crate) fn needs_bigtiff<T: OutputSample>(samples: u64) -> bool {
+    let bytes = samples * std::mem::size_of::<T>() as u64;
+    bytes + bytes / PROJECTED_HEADROOM > u64::from(u32::MAX)
+}
+
+/// Fail unless the tile size is a positive multiple of 16, as required by the TIFF specification.
+pub(crate) fn check_tile_size(tile_size: u32) -> Result<(), Box<dyn std::error::Error>> {
+    if tile_size == 0 || !tile_size.is_multiple_of(16) {
+        return Err(Error::InvalidOptions(format!("tile size must be a positive multiple of 16, got {}", tile_size)).into());
+    }
+    Ok(())
+}
+
+/// Compressor for each strip or tile, fails for methods the tiff encoder does not provide.
+pub(crate) fn compressor(compression: TiffCompression) -> Result<Compressor, Box<dyn std::error::Error>> {
+    compression.check_supported().map_err(Error::InvalidOptions)?;
+    Ok(match compression {
+        TiffCompression::None => Compressor::Uncompressed(Uncompressed),
+        TiffCompression::Lzw => Compressor::Lzw(Lzw),
+        TiffCompression::Deflate => Compressor::Deflate(Deflate::default()),
+        TiffCompression::Zstd => unreachable!("zstd is rejected by check_supported"),
+    })
+}
+
+/// Compress and write one strip or tile, returning its offset and byte count, which fail once they
+/// do not fit the offsets of the TIFF kind.
+pub(crate) fn write_chunk<T: OutputSample, W: Write + Seek, K: TiffKind>(
+    directory: &mut DirectoryEncoder<W, K>,
+    compressor: &mut Compressor,
+    samples: &[T],
+) -> Result<[u64; 2], Box<dyn std::error::Error>> {
+    let mut compressed = Vec::new();
+    compressor.write_to(&mut compressed, &T::as_ne_bytes(samples))?;
+    let offset = directory.write_data(&compressed[..])?;
+    K::convert_offset(offset)?;
+    Ok([offset, compressed.len() as u64])
+}
+
+/// Offsets or byte counts of the strips or tiles, of the TIFF kind.
+fn convert<K: TiffKind>(chunks: &[[u64; 2]], index: usize) -> Result<Vec<K::OffsetType>, Box<dyn std::error::Error>> {
+    Ok(chunks.iter().map(|chunk| K::convert_offset(chunk[index])).collect::<Result<_, _>>()?)
+}
+
+/// Cut a band of at most `tile` rows of an RGB image into full-size tiles, padding edge tiles with
+/// zeros, and write them left to right, adding their offsets and byte counts to `tiles`.
+pub(crate) fn write_band_tiles<T: OutputSample, W: Write + Seek, K: TiffKind>(
+    directory: &mut DirectoryEncoder<W, K>,
+    compressor: &mut Compressor,
+    band: ArrayView3<T>,
+    tile: usize,
+    tiles: &mut Vec<[u64; 2]>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let width = band.shape()[1];
+    for x0 in (0..width).step_by(tile) {
+        let mut data = vec![T::default(); tile * tile * 3];
+        let x1 = (x0 + tile).min(width);
+        for (row, pixels) in band.outer_iter().enumerate() {
+            let src = pixels.slice(s![x0..x1, ..]);
+            let dst = &mut data[row * tile * 3..(row * tile + (x1 - x0)) * 3];
+            for (d, v) in dst.iter_mut().zip(src.iter()) {
+                *d = *v;
+            }
+        }
+        tiles.push(write_chunk(directory, compressor, &data)?);
+    }
+    Ok(())
+}
+
+/// Write the tile layout tags of a tiled image with the offsets and byte counts of its tiles.
+pub(crate) fn write_tile_tags<W: Write + Seek, K: TiffKind>(
+    directory: &mut DirectoryEncoder<W, K>,
+    tile_size: u32,
+    tiles: &[[u64; 2]],
+) -> Result<(), Box<dyn std::error::Error>> {
+    directory.write_tag(Tag::TileWidth, tile_size)?;
+    directory.write_tag(Tag::TileLength, tile_size)?;
+    directory.write_tag(Tag::TileOffsets, K::convert_slice(&convert::<K>(tiles, 0)?))?;
+    directory.write_tag(Tag::TileByteCounts, K::convert_slice(&convert::<K>(tiles, 1)?))?;
+    Ok(())
+}
+
+/// Metadata recorded in the tags of an image.
+#[derive(Debug, Default)]
+pub(crate) struct ImageMetadata<'a> {
+    /// OME-XML metadata of the image description.
+    pub(crate) ome: Option<&'a OmeMetadata>,
+    /// Physical size (x, y) of a pixel in micrometers, written as resolution tags.
+    pub(crate) pixel_size_um: Option<[f32; 2]>,
+    /// Rendering parameters of the image description without OME-XML.
+    pub(crate) parameters: &'a [(String, String)],
+}
+
+impl<'a> ImageMetadata<'a> {
+    /// The metadata of `options`.
+    fn of(options: &'a SaveOptions) -> Self {
+        ImageMetadata {
+            ome: options.ome.as_ref(),
+            pixel_size_um: options.pixel_size_um,
+            parameters: &options.parameters,
+        }
+    }
+}
+
+/// Write the tags describing an RGB image, or an RGBA image with unassociated alpha for 4
+/// `samples`, that are common to strip and tile layouts, with the Software and DateTime of the
+/// render, the time of SOURCE_DATE_EPOCH when set for reproducible outputs, and the resolution of
+/// the pixel size. The image description
+/// is OME-XML when `metadata` has it, and otherwise a JSON object of the rendering parameters
+/// unless there are none.
+pub(crate) fn write_rgb_tags<T: OutputSample, W: Write + Seek, K: TiffKind>(
+    directory: &mut DirectoryEncoder<W, K>,
+    width: u32,
+    height: u32,
+    samples: u16,
+    compression: TiffCompression,
+    metadata: &ImageMetadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let method = match compression {
+        TiffCompression::None => CompressionMethod::None,
+        TiffCompression::Lzw => CompressionMethod::LZW,
+        TiffCompression::Deflate => CompressionMethod::Deflate,
+        TiffCompression::Zstd => unreachable!("zstd is rejected by check_supported"),
+    };
+    directory.write_tag(Tag::ImageWidth, width)?;
+    directory.write_tag(Tag::ImageLength, height)?;
+    let count = usize::from(samples);
+    directory.write_tag(Tag::BitsPerSample, &vec![T::BITS; count][..])?;
+    directory.write_tag(Tag::SampleFormat, &vec![SampleFormat::Uint.to_u16(); count][..])?;
+    directory.write_tag(Tag::Compression, method.to_u16())?;
+    directory.write_tag(Tag::PhotometricInterpretation, PhotometricInterpretation::RGB.to_u16())?;
+    directory.write_tag(Tag::SamplesPerPixel, samples)?;
+    if samples == 4 {
+        // Unassociated alpha, the colors are not premultiplied
+        directory.write_tag(Tag::ExtraSamples, 2u16)?;
+    }
+    directory.write_tag(Tag::PlanarConfiguration, PlanarConfiguration::Chunky.to_u16())?;
+    directory.write_tag(Tag::Software, concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")))?;
+    directory.write_tag(Tag::DateTime, &*tiff_datetime(render_time()))?;
+    if let Some([x, y]) = metadata.pixel_size_um {
+        directory.write_tag(Tag::ResolutionUnit, ResolutionUnit::Centimeter.to_u16())?;
+        directory.write_tag(Tag::XResolution, pixels_per_centimeter(x))?;
+        directory.write_tag(Tag::YResolution, pixels_per_centimeter(y))?;
+    }
+    if let Some(ome) = metadata.ome {
+        directory.write_tag(Tag::ImageDescription, &*ome_xml(ome, width, height, T::BITS))?;
+    } else if !metadata.parameters.is_empty() {
+        let parameters = metadata.parameters.iter().map(|(key, value)| (key.as_str(), value.as_str()));
+        directory.write_tag(Tag::ImageDescription, &*serde_json::to_string(&parameters.collect::<BTreeMap<_, _>>())?)?;
+    }
+    Ok(())
+}
+
+/// TIFF resolution of a pixel size in micrometers, in thousandths of a pixel per centimeter.
+fn pixels_per_centimeter(size_um: f32) -> Rational {
+    Rational {
+        n: (1e7 / f64::from(size_um)).round().clamp(1.0, f64::from(u32::MAX)) as u32,
+        d: 1000,
+    }
+}
+
+/// Time of the render written to the outputs: that of SOURCE_DATE_EPOCH in seconds since 1970, as
+/// tools that write reproducible files read it, or else the current time.
+pub(crate) fn render_time() -> SystemTime {
+    match std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|epoch| epoch.trim().parse().ok()) {
+        Some(seconds) => UNIX_EPOCH + Duration::from_secs(seconds),
+        None => SystemTime::now(),
+    }
+}
+
+/// TIFF DateTime of `time` in UTC, as YYYY:MM:DD HH:MM:SS.
+fn tiff_datetime(time: SystemTime) -> String {
+    let [year, month, day, hour, minute, second] = civil_time(time);
+    format!("{:04}:{:02}:{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second)
+}
+
+/// Year, month, day, hour, minute and second of `time` in UTC.
+pub(crate) fn civil_time(time: SystemTime) -> [u64; 6] {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    let (days, second) = (seconds / 86_400, seconds % 86_400);
+    // Civil date of a day count since 1970-01-01, in 400 year eras of 146097 days starting on March 1
+    let shifted = days + 719_468;
+    let (era, day_of_era) = (shifted / 146_097, shifted % 146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    [year, month, day, second / 3600, second / 60 % 60, second % 60]
+}
+
+/// Write row-major RGB samples as a stripped TIFF, switching to BigTIFF when forced by the options or
+/// when the projected file size exceeds the 4 GB limit of standard TIFF. `path` names the output in
+/// log messages.
+pub(crate) fn write_rgb<T: OutputSample, W: Write + Seek>(
+    writer: W,
+    path: &Path,
+    width: u32,
+    height: u32,
+    data: &[T],
+    options: &SaveOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bigtiff = options.bigtiff || needs_bigtiff::<T>(data.len() as u64);
+    debug!("{}: {}, {:?} compression, {}bit RGB", path.display(), tiff_kind(bigtiff, options), options.compression, T::BITS);
+    if bigtiff {
+        write_strips(&mut TiffEncoder::new_big(writer)?, width, height, data, options)
+    } else {
+        write_strips(&mut TiffEncoder::new(writer)?, width, height, data, options)
+    }
+}
+
+/// Write the RGB pages returned by `next_page` for pages `0..pages` as a multi-page stripped TIFF,
+/// switching to BigTIFF when forced by the options or when the pages, projected from the size of
+/// the first, may exceed 4 GB.
+pub(crate) fn write_rgb_pages<T, F>(
+    path: &Path,
+    pages: usize,
+    options: &SaveOptions,
+    mut next_page: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: OutputSample,
+    F: FnMut(usize) -> Result<Array3<T>, Box<dyn std::error::Error>>,
+{
+    let first = next_page(0)?;
+    let bigtiff = options.bigtiff || needs_bigtiff::<T>(first.len() as u64 * pages as u64);
+    debug!(
+        "{}: multi-page {} with {} pages, {:?} compression, {}bit RGB",
+        path.display(),
+        tiff_kind(bigtiff, options),
+        pages,
+        options.compression,
+        T::BITS
+    );
+    crate::atomic::write(path, |temporary| {
+        let mut writer = BufWriter::new(File::create(temporary)?);
+        if bigtiff {
+            write_pages(&mut TiffEncoder::new_big(&mut writer)?, first, pages, options, next_page)?;
+        } else {
+            write_pages(&mut TiffEncoder::new(&mut writer)?, first, pages, options, next_page)?;
+        }
+        Ok(crate::atomic::finish(writer)?)
+    })
+}
+
+/// Name of the TIFF variant written, for logging.
+fn tiff_kind(bigtiff: bool, options: &SaveOptions) -> &'static str {
+    match (bigtiff, options.ome.is_some()) {
+        (false, false) => "TIFF",
+        (true, false) => "BigTIFF",
+        (false, true) => "OME-TIFF",
+        (true, true) => "OME-TIFF (BigTIFF)",
+    }
+}
+
+/// Write `first` and the remaining pages returned by `next_page` as stripped images.
+fn write_pages<T, F, W, K>(
+    tiff: &mut TiffEncoder<W, K>,
+    first: Array3<T>,
+    pages: usize,
+    options: &SaveOptions,
+    mut next_page: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: OutputSample,
+    F: FnMut(usize) -> Result<Array3<T>, Box<dyn std::error::Error>>,
+    W: Write + Seek,
+    K: TiffKind,
+{
+    let mut page = first;
+    for index in 1..=pages {
+        let (width, height, data) = into_raw_rgb(page);
+        trace!("writing page {} of {}", index, pages);
+        write_strips(tiff, width, height, &data, options)?;
+        if index == pages {
+            break;
+        }
+        page = next_page(index)?;
+    }
+    Ok(())
+}
+
+/// Write row-major RGB samples as one stripped image.
+fn write_strips<T: OutputSample, W: Write + Seek, K: TiffKind>(
+    tiff: &mut TiffEncoder<W, K>,
+    width: u32,
+    height: u32,
+    data: &[T],
+    options: &SaveOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut compressor = compressor(options.compression)?;
+    let samples = samples_per_pixel(width, height, data.len());
+    let row_samples = width as usize * samples;
+    let rows_per_strip = STRIP_BYTES.div_ceil(row_samples * std::mem::size_of::<T>()).max(1);
+
+    let mut directory = tiff.new_directory()?;
+    let mut strips = Vec::new();
+    for strip in data.chunks(rows_per_strip * row_samples) {
+        strips.push(write_chunk(&mut directory, &mut compressor, strip)?);
+    }
+
+    let metadata = ImageMetadata::of(options);
+    write_rgb_tags::<T, _, _>(&mut directory, width, height, samples as u16, options.compression, &metadata)?;
+    directory.write_tag(Tag::RowsPerStrip, u32::try_from(rows_per_strip)?)?;
+    directory.write_tag(Tag::StripOffsets, K::convert_slice(&convert::<K>(&strips, 0)?))?;
+    directory.write_tag(Tag::StripByteCounts, K::convert_slice(&convert::<K>(&strips, 1)?))?;
+    directory.finish()?;
+    Ok(())
+}
+
+/// Write a (row, column, RGB) array as a tiled pyramidal BigTIFF, the full resolution image followed
+/// by successive 2x box-filtered levels until the long edge is at most `PYRAMID_MIN_EDGE` pixels.
+/// The rendering parameters of the options are recorded in the full resolution level.
+pub(crate) fn write_pyramid<T: OutputSample, W: Write + Seek>(
+    writer: W,
+    rgb: Array3<T>,
+    tile_size: u32,
+    options: &SaveOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    check_tile_size(tile_size)?;
+    let compression = options.compression;
+    let mut compressor = compressor(compression)?;
+    let tile = tile_size as usize;
+    let mut tiff = TiffEncoder::new_big(writer)?;
+
+    let mut level = rgb;
+    let mut metadata = ImageMetadata::of(options);
+    let mut reduced = false;
+    loop {
+        let (height, width) = (level.shape()[0], level.shape()[1]);
+        trace!("writing pyramid level {}x{}", width, height);
+        let mut directory = tiff.new_directory()?;
+        let mut tiles = Vec::new();
+        for y0 in (0..height).step_by(tile) {
+            let band = level.slice(s![y0..(y0 + tile).min(height), .., ..]);
+            write_band_tiles(&mut directory, &mut compressor, band, tile, &mut tiles)?;
+        }
+
+        // Reduced resolution levels are marked so viewers do not treat them as separate images
+        directory.write_tag(Tag::NewSubfileType, if reduced { 1u32 } else { 0u32 })?;
+        let size = (u32::try_from(width)?, u32::try_from(height)?);
+        write_rgb_tags::<T, _, _>(&mut directory, size.0, size.1, 3, compression, &metadata)?;
+        write_tile_tags(&mut directory, tile_size, &tiles)?;
+        directory.finish()?;
+
+        if width.max(height) <= PYRAMID_MIN_EDGE {
+            return Ok(());
+        }
+        level = downsample(level.view());
+        metadata = ImageMetadata {
+            ome: None,
+            pixel_size_um: metadata.pixel_size_um.map(|size| size.map(|size| size * 2.0)),
+            parameters: &[],
+        };
+        reduced = true;
+    }
+}
+
+/// Halve an RGB image by averaging 2x2 blocks, edge blocks of odd-sized images average the pixels
+/// they cover.
+pub(crate) fn downsample<T: OutputSample>(rgb: ArrayView3<T>) -> Array3<T> {
+    let (height, width) = (rgb.shape()[0], rgb.shape()[1]);
+    let mut out = Array3::<T>::from_elem((height.div_ceil(2), width.div_ceil(2), 3), T::default());
+    out.axis_iter_mut(Axis(0)).into_par_iter().enumerate().for_each(|(y, mut row)| {
+        let rows = rgb.slice(s![2 * y..(2 * y + 2).min(height), .., ..]);
+        for (x, mut pixel) in row.outer_iter_mut().enumerate() {
+            let block = rows.slice(s![.., 2 * x..(2 * x + 2).min(width), ..]);
+            let count = (block.shape()[0] * block.shape()[1]) as u32;
+            for channel in 0..3 {
+                let sum: u32 = block.slice(s![.., .., channel]).iter().map(|&v| v.into()).sum();
+                pixel[channel] = T::from_mean(sum, count);
+            }
+        }
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TiffCompression;
+    use ndarray::Array3;
+    use std::path::PathBuf;
+    use tiff::decoder::{Decoder, DecodingResult};
+
+    /// Path of a file written by a test in the temporary directory.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("virtualhe-test-{}-{}", std::process::id(), name))
+    }
+
+    /// An RGB image whose samples differ across rows, columns and channels.
+    fn gradient<T: OutputSample + From<u8>>(height: usize, width: usize) -> Array3<T> {
+        Array3::from_shape_fn((height, width, 3), |(y, x, c)| T::from(((x * 7 + y * 3 + c * 50) % 256) as u8))
+    }
+
+    #[test]
+    fn bigtiff_is_needed_beyond_4_gb() {
+        let limit = u64::from(u32::MAX);
+        assert!(!needs_bigtiff::<u8>(1 << 20));
+        assert!(!needs_bigtiff::<u8>(limit / 9 * 8));
+        assert!(needs_bigtiff::<u8>(limit / 9 * 8 + 8));
+        assert!(needs_bigtiff::<u16>(limit / 2));
+        assert!(!needs_bigtiff::<u16>(limit / 3));
+    }
+
+    #[test]
+    fn forced_bigtiff_reopens_with_the_pixels_written() {
+        for compression in [TiffCompression::None, TiffCompression::Deflate] {
+            let options = SaveOptions {
+                bigtiff: true,
+                compression,
+                ..SaveOptions::default()
+            };
+            let path = temp_path(&format!("bigtiff-{:?}.tif", compression));
+            let rgb = gradient::<u16>(37, 53);
+            crate::save_with(rgb.clone(), &path, &options).unwrap();
+
+            let bytes = std::fs::read(&path).unwrap();
+            // A BigTIFF header has version 43 where a standard TIFF has 42
+            assert_eq!(u16::from_le_bytes([bytes[2], bytes[3]]), 43);
+            let mut decoder = Decoder::new(File::open(&path).unwrap()).unwrap();
+            assert_eq!(decoder.dimensions().unwrap(), (53, 37));
+            let DecodingResult::U16(samples) = decoder.read_image().unwrap() else {
+                panic!("expected 16bit samples");
+            };
+            assert_eq!(samples, rgb.into_raw_vec_and_offset().0);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn parameter_tags_reopen_as_written() {
+        let parameters = vec![("k_nucleus".to_string(), "2.5".to_string()), ("gamma".to_string(), "1".to_string())];
+        for pyramid_tile_size in [None, Some(16)] {
+            let options = SaveOptions {
+                pyramid_tile_size,
+                parameters: parameters.clone(),
+                ..SaveOptions::default()
+            };
+            let path = temp_path(&format!("tags-{:?}.tif", pyramid_tile_size));
+            let before = tiff_datetime(render_time());
+            crate::save_with(gradient::<u8>(40, 40), &path, &options).unwrap();
+            let after = tiff_datetime(render_time());
+
+            let mut decoder = Decoder::new(File::open(&path).unwrap()).unwrap();
+            let description = decoder.get_tag_ascii_string(Tag::ImageDescription).unwrap();
+            assert_eq!(description, r#"{"gamma":"1","k_nucleus":"2.5"}"#);
+            let software = decoder.get_tag_ascii_string(Tag::Software).unwrap();
+            assert_eq!(software, format!("virtualhe {}", env!("CARGO_PKG_VERSION")));
+            let datetime = decoder.get_tag_ascii_string(Tag::DateTime).unwrap();
+            assert!(before <= datetime && datetime <= after, "{} not within {} and {}", datetime, before, after);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn tiff_datetimes_are_utc_civil_times() {
+        assert_eq!(tiff_datetime(UNIX_EPOCH), "1970:01:01 00:00:00");
+        assert_eq!(tiff_datetime(UNIX_EPOCH + Duration::from_secs(951_827_696)), "2000:02:29 12:34:56");
+        assert_eq!(tiff_datetime(UNIX_EPOCH + Duration::from_secs(4_107_542_399)), "2100:02:28 23:59:59");
+    }
+
+    #[test]
+    fn small_tiffs_are_standard_tiffs() {
+        let path = temp_path("standard.tif");
+        crate::save_with(gradient::<u8>(4, 4), &path, &SaveOptions::default()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(u16::from_le_bytes([bytes[2], bytes[3]]), 42);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
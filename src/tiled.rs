@@ -0,0 +1,596 @@
+//! Tiled processing of TIFF images that do not fit in memory.
+//!
+//! Global scaling thresholds are estimated from a streamed pass over each input, then the image is
+//! rendered band by band and written incrementally into a tiled TIFF or an OME-Zarr group, so every
+//! tile shares the same normalization and there are no seams.
+use crate::dicom::{self, DicomOptions, DicomWriter};
+use crate::dzi::{self, DziOptions, DziWriter};
+use crate::interrupt;
+use crate::journal::{Band, Header, Journal};
+use crate::quantile::StreamingQuantiles;
+use crate::{
+    normalize_input, render_as, resolve_input_max, thresholds_at, Dither, Error, LoadOptions, NanPolicy, OutputDepth,
+    OutputSample, Params, ScaleOptions, Thresholds, TiffCompression, ZarrOptions,
+};
+use crate::ome::OmeMetadata;
+use crate::tiff_reader::BandReader;
+use crate::tiff_writer::{
+    check_tile_size, compressor, needs_bigtiff, write_band_tiles, write_rgb_tags, write_tile_tags, ImageMetadata,
+};
+use crate::zarr_writer::{write_resumable_store, ZarrWriter};
+use log::{debug, trace};
+use ndarray::{Array2, Array3};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use tiff::encoder::compression::Compressor;
+use tiff::encoder::{TiffEncoder, TiffKind};
+
+/// Default edge length of processing and output tiles in pixels.
+pub const DEFAULT_TILE_SIZE: u32 = 2048;
+
+/// Bytes of zeros passed to the encoder at a time for the tiles kept by a resumed render.
+const SKIP_BUFFER: usize = 1 << 20;
+
+/// One input channel of a tiled render.
+#[derive(Debug, Clone)]
+pub struct TiledChannel<'a> {
+    /// Path to a TIFF image.
+    pub path: &'a Path,
+    /// How the image is read.
+    pub load: &'a LoadOptions,
+    /// How the image is scaled.
+    pub scale: &'a ScaleOptions,
+}
+
+/// Options for `render_tiled`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TiledOptions {
+    /// Edge length of processing and output tiles in pixels, must be a multiple of 16.
+    pub tile_size: u32,
+    /// Crop both channels to their overlapping region instead of failing when their sizes differ.
+    pub crop_to_common: bool,
+    /// Bit depth of the output TIFF.
+    pub output_depth: OutputDepth,
+    /// Compression of the output TIFF tiles.
+    pub compression: TiffCompression,
+    /// Always write a BigTIFF, otherwise it is used only when the output may exceed 4 GB.
+    pub bigtiff: bool,
+    /// Write an OME-TIFF with this metadata.
+    pub ome: Option<OmeMetadata>,
+    /// Physical size (x, y) of a pixel in micrometers recorded in the resolution tags.
+    pub pixel_size_um: Option<[f32; 2]>,
+    /// Rendering parameters recorded as a JSON image description, unless the OME-XML holds them.
+    pub parameters: Vec<(String, String)>,
+    /// Write an OME-Zarr multiscale group instead of a tiled TIFF.
+    pub zarr: Option<ZarrOptions>,
+    /// Continue the partial output left by an earlier run with the same inputs and settings from
+    /// its journal, and keep the partial output when the render fails or is interrupted.
+    pub resume: bool,
+    /// Also write a Deep Zoom image with these options at this path, from the same bands.
+    pub dzi: Option<(PathBuf, DziOptions)>,
+    /// Also write a DICOM whole slide image with these options at this path, from the same bands.
+    pub dicom: Option<(PathBuf, DicomOptions)>,
+}
+
+impl Default for TiledOptions {
+    fn default() -> Self {
+        TiledOptions {
+            tile_size: DEFAULT_TILE_SIZE,
+            crop_to_common: false,
+            output_depth: OutputDepth::default(),
+            compression: TiffCompression::default(),
+            bigtiff: false,
+            ome: None,
+            pixel_size_um: None,
+            parameters: Vec::new(),
+            zarr: None,
+            resume: false,
+            dzi: None,
+            dicom: None,
+        }
+    }
+}
+
+/// Render a virtual H&E image from two TIFF inputs tile by tile into a tiled RGB TIFF.
+///
+/// The output is written into a partial file next to it, with a journal of the bands of tiles
+/// flushed to disk, and renamed onto the output once complete. With `TiledOptions::resume` the
+/// bands recorded by the journal of an earlier run are kept, see `journal`.
+///
+/// Returns the global scaling thresholds of the nucleus and eosin channels.
+pub fn render_tiled(
+    nucleus: &TiledChannel,
+    eosin: &TiledChannel,
+    params: &Params,
+    output_path: &Path,
+    options: &TiledOptions,
+) -> Result<[Thresholds; 2], Error> {
+    check_tile_size(options.tile_size).map_err(|e| Error::InvalidOptions(e.to_string()))?;
+    options.compression.check_supported().map_err(Error::InvalidOptions)?;
+    // The bands kept by a resumed render are not rendered again for the tiles of other levels
+    if options.resume && options.dzi.is_some() {
+        return Err(Error::InvalidOptions("a Deep Zoom image cannot be written by a resumed render".to_string()));
+    }
+    if options.resume && options.dicom.is_some() {
+        return Err(Error::InvalidOptions("a DICOM image cannot be written by a resumed render".to_string()));
+    }
+    if nucleus.load.blur_sigma > 0.0 || eosin.load.blur_sigma > 0.0 {
+        return Err(Error::InvalidOptions("blurring is not supported by tiled rendering".to_string()));
+    }
+    if [nucleus.load, eosin.load].iter().any(|load| load.flat_field.is_some() || load.dark_field.is_some()) {
+        return Err(Error::InvalidOptions("flat-field correction is not supported by tiled rendering".to_string()));
+    }
+    if nucleus.load.despeckle.is_some() || eosin.load.despeckle.is_some() {
+        return Err(Error::InvalidOptions("median filtering is not supported by tiled rendering".to_string()));
+    }
+    if nucleus.load.roi.is_some() || eosin.load.roi.is_some() {
+        return Err(Error::InvalidOptions("cropping to a region of interest is not supported by tiled rendering".to_string()));
+    }
+    if nucleus.load.downsample > 1 || eosin.load.downsample > 1 {
+        return Err(Error::InvalidOptions("downsampling is not supported by tiled rendering".to_string()));
+    }
+    if nucleus.scale.equalize.is_some() || eosin.scale.equalize.is_some() {
+        return Err(Error::InvalidOptions("histogram equalization is not supported by tiled rendering".to_string()));
+    }
+    if nucleus.scale.auto_contrast.is_some() || eosin.scale.auto_contrast.is_some() {
+        return Err(Error::InvalidOptions("automatic contrast is not supported by tiled rendering".to_string()));
+    }
+    // Ordered dithering depends on the position in the band only, which the multiples of 16 rows
+    // of the tiles keep, but errors are not diffused across bands
+    if params.dither == Dither::FloydSteinberg {
+        return Err(Error::InvalidOptions(
+            "Floyd-Steinberg dithering is not supported by tiled rendering, use ordered dithering".to_string(),
+        ));
+    }
+    let mut nucleus_reader = BandReader::open(nucleus.path, nucleus.load)?;
+    let mut eosin_reader = BandReader::open(eosin.path, eosin.load)?;
+
+    // Check that the channels line up before any processing starts
+    let (width, height) = if (nucleus_reader.width, nucleus_reader.height) == (eosin_reader.width, eosin_reader.height) {
+        (nucleus_reader.width, nucleus_reader.height)
+    } else if options.crop_to_common {
+        (
+            nucleus_reader.width.min(eosin_reader.width),
+            nucleus_reader.height.min(eosin_reader.height),
+        )
+    } else {
+        return Err(Error::ShapeMismatch {
+            nucleus: nucleus.path.to_path_buf(),
+            nucleus_size: (nucleus_reader.width as usize, nucleus_reader.height as usize),
+            eosin: eosin.path.to_path_buf(),
+            eosin_size: (eosin_reader.width as usize, eosin_reader.height as usize),
+            plane: None,
+        });
+    };
+
+    debug!("rendering {}x{} in tiles of {}", width, height, options.tile_size);
+
+    // First pass: global normalization
+    let (nucleus_max, nucleus_thresholds) = global_thresholds(&mut nucleus_reader, nucleus, width, height, options.tile_size)?;
+    let (eosin_max, eosin_thresholds) = global_thresholds(&mut eosin_reader, eosin, width, height, options.tile_size)?;
+
+    // Second pass: scale, render and write band by band
+    let (nucleus_gamma, eosin_gamma) = (nucleus.scale.gamma, eosin.scale.gamma);
+    let (nucleus_invert, eosin_invert) = (nucleus.load.invert, eosin.load.invert);
+    let kept = if options.resume { "the partial output is kept for --resume" } else { "nothing was saved" };
+    let mut next_band = |y0, y1| {
+        interrupt::check(|| format!("{}: interrupted after {} of {} rows, {}", output_path.display(), y0, height, kept))?;
+        let mut nucleus = nucleus_reader.read_rows(y0, y1, width)?;
+        nucleus.par_mapv_inplace(|v| nucleus_thresholds.apply_gamma(normalize_input(v, nucleus_max, nucleus_invert), nucleus_gamma));
+        let mut eosin = eosin_reader.read_rows(y0, y1, width)?;
+        eosin.par_mapv_inplace(|v| eosin_thresholds.apply_gamma(normalize_input(v, eosin_max, eosin_invert), eosin_gamma));
+        Ok((nucleus, eosin))
+    };
+    let header = Header {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        layout: String::new(),
+        width,
+        height,
+        tile_size: options.tile_size,
+        bits_per_sample: 0,
+        settings: format!(
+            "{:?} {:?} {:?} {:?} {:?} {}",
+            params, nucleus.load, nucleus.scale, eosin.load, eosin.scale, options.crop_to_common
+        ),
+        inputs: vec![Header::input(nucleus.path), Header::input(eosin.path)],
+    };
+    match options.output_depth {
+        OutputDepth::Eight => write_tiled_rgb::<u8, _>(output_path, header, options, params, &mut next_band),
+        OutputDepth::Sixteen => write_tiled_rgb::<u16, _>(output_path, header, options, params, &mut next_band),
+    }
+    .map_err(|e| Error::write(output_path, e))?;
+
+    Ok([nucleus_thresholds, eosin_thresholds])
+}
+
+/// Compute the input maximum and scaling thresholds of a channel from a streamed pass.
+///
+/// Integer images, and others with up to `quantile::DEFAULT_EXACT_LIMIT` pixels, get exact
+/// percentiles, so the thresholds match the whole-image path.
+fn global_thresholds(
+    reader: &mut BandReader,
+    channel: &TiledChannel,
+    width: u32,
+    height: u32,
+    tile_size: u32,
+) -> Result<(f32, Thresholds), Error> {
+    let mut sampler = ThresholdSampler::new();
+    for y0 in (0..height).step_by(tile_size as usize) {
+        interrupt::check(|| {
+            format!("{}: interrupted computing the thresholds, after {} of {} rows", channel.path.display(), y0, height)
+        })?;
+        trace!("{}: sampling rows {}..{} of {}", channel.path.display(), y0, (y0 + tile_size).min(height), height);
+        sampler.push(&reader.read_rows(y0, (y0 + tile_size).min(height), width)?);
+    }
+    sampler.finish(channel.path, channel.load, channel.scale, reader.container_max)
+}
+
+/// Streamed input maximum and scaling thresholds of a channel from raw values.
+#[derive(Default)]
+pub(crate) struct ThresholdSampler {
+    quantiles: StreamingQuantiles,
+}
+
+impl ThresholdSampler {
+    pub(crate) fn new() -> Self {
+        ThresholdSampler::default()
+    }
+
+    /// Add the next raw values.
+    pub(crate) fn push(&mut self, values: &Array2<f32>) {
+        self.quantiles.update(values);
+    }
+
+    /// Resolve the input maximum and compute the scaling thresholds of the normalized values.
+    pub(crate) fn finish(
+        mut self,
+        path: &Path,
+        load: &LoadOptions,
+        scale: &ScaleOptions,
+        container_max: Option<f32>,
+    ) -> Result<(f32, Thresholds), Error> {
+        let quantiles = &mut self.quantiles;
+        let input_max = resolve_input_max(load, container_max, || quantiles.max().max(0.0))?;
+        let nan_count = quantiles.nan_count();
+        if nan_count > 0 && scale.nan_policy == NanPolicy::Error {
+            return Err(Error::scale(path, format!("image contains {} NaN values", nan_count)));
+        }
+        // The normalized values in order are the zeros of NaN values and of the infinite values
+        // that clamp to zero, then the finite values, in reverse for inverted inputs
+        let [negative, positive] = quantiles.infinite_counts();
+        let nan_zeros = if scale.nan_policy == NanPolicy::Zero { nan_count } else { 0 };
+        let zeros = nan_zeros + if load.invert { positive } else { negative };
+        let count = quantiles.count();
+        let total = zeros + count;
+        if scale.window.is_none() && total == 0 {
+            return Err(Error::scale(path, "image contains no finite values"));
+        }
+        quantiles.prepare();
+        let quantiles = &self.quantiles;
+        let value_at = |rank: u64| match rank.checked_sub(zeros) {
+            None => 0.0,
+            Some(rank) if load.invert => normalize_input(quantiles.value_at(count - 1 - rank), input_max, true),
+            Some(rank) => normalize_input(quantiles.value_at(rank), input_max, false),
+        };
+        let thresholds = match scale.window {
+            Some(window) => window,
+            None => thresholds_at(scale, |percentile| scale.percentile_method.select(percentile, total, value_at))
+                .map_err(|e| Error::scale(path, e))?,
+        };
+        debug!(
+            "{}: normalized by {}, {} values{}, floor {} and ceiling {} (intensities {} and {})",
+            path.display(),
+            input_max,
+            total,
+            if quantiles.is_exact() { "" } else { " in a t-digest" },
+            thresholds.floor,
+            thresholds.ceiling,
+            thresholds.floor * input_max,
+            thresholds.ceiling * input_max
+        );
+        Ok((input_max, thresholds))
+    }
+}
+
+/// Write an RGB tiled TIFF, or an OME-Zarr group, with samples of type `T`, requesting the scaled
+/// nucleus and eosin rows `y0..y1` of each band of tiles from `next_band`, through the partial file
+/// and journal of the render described by `header`, and the Deep Zoom image of `TiledOptions::dzi`
+/// and DICOM image of `TiledOptions::dicom` from the same bands.
+fn write_tiled_rgb<T, F>(
+    path: &Path,
+    header: Header,
+    options: &TiledOptions,
+    params: &Params,
+    mut next_band: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: OutputSample,
+    F: FnMut(u32, u32) -> Result<(Array2<f32>, Array2<f32>), Box<dyn std::error::Error>>,
+{
+    let mut render_band = |y0, y1| -> Result<Array3<T>, Box<dyn std::error::Error>> {
+        let (nucleus, eosin) = next_band(y0, y1)?;
+        Ok(render_as::<T>(nucleus, eosin, params))
+    };
+    if options.dzi.is_none() && options.dicom.is_none() {
+        return write_tiled_output(path, header, options, render_band);
+    }
+    let (width, height) = (header.width, header.height);
+    with_dzi(options, width, height, |mut dzi| {
+        with_dicom(options, width, height, |mut dicom| {
+            write_tiled_output(path, header, options, |y0, y1| {
+                let band = render_band(y0, y1)?;
+                if let Some(dzi) = dzi.as_mut() {
+                    dzi.push_rows(band.view())?;
+                }
+                if let Some(dicom) = dicom.as_mut() {
+                    dicom.push_rows(band.view())?;
+                }
+                Ok(band)
+            })
+        })
+    })
+}
+
+/// Call `write` with the writer of the Deep Zoom image of `TiledOptions::dzi`, if any.
+fn with_dzi<F>(options: &TiledOptions, width: u32, height: u32, write: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(Option<&mut DziWriter>) -> Result<(), Box<dyn std::error::Error>>,
+{
+    match &options.dzi {
+        Some((path, dzi_options)) => dzi::write_streamed(path, width, height, dzi_options, |dzi| write(Some(dzi))),
+        None => write(None),
+    }
+}
+
+/// Call `write` with the writer of the DICOM image of `TiledOptions::dicom`, if any.
+fn with_dicom<F>(options: &TiledOptions, width: u32, height: u32, write: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(Option<&mut DicomWriter>) -> Result<(), Box<dyn std::error::Error>>,
+{
+    match &options.dicom {
+        Some((path, dicom_options)) => {
+            dicom::write_streamed(path, width, height, dicom_options, |dicom| write(Some(dicom)))
+        }
+        None => write(None),
+    }
+}
+
+/// Write an RGB tiled TIFF, or an OME-Zarr group, of the bands of tiles `render_band` renders for
+/// the rows `y0..y1`, through the partial file and journal of the render described by `header`.
+fn write_tiled_output<T, F>(
+    path: &Path,
+    header: Header,
+    options: &TiledOptions,
+    render_band: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: OutputSample,
+    F: FnMut(u32, u32) -> Result<Array3<T>, Box<dyn std::error::Error>>,
+{
+    if let Some(zarr) = &options.zarr {
+        return write_zarr_bands(path, header, options, zarr, render_band);
+    }
+    let (width, height) = (header.width, header.height);
+    let compressor = compressor(options.compression)?;
+    let tile = u64::from(options.tile_size);
+    let samples = u64::from(width).div_ceil(tile) * u64::from(height).div_ceil(tile) * tile * tile * 3;
+    let bigtiff = options.bigtiff || needs_bigtiff::<T>(samples);
+    debug!(
+        "{}: tiled {}{}, {:?} compression, {}bit RGB",
+        path.display(),
+        if bigtiff { "BigTIFF" } else { "TIFF" },
+        if options.ome.is_some() { " with OME-XML" } else { "" },
+        options.compression,
+        T::BITS
+    );
+    let header = Header {
+        layout: format!("{} {:?}", if bigtiff { "bigtiff" } else { "tiff" }, options.compression),
+        bits_per_sample: T::BITS,
+        ..header
+    };
+    crate::atomic::write_resumable(path, options.resume, |partial, journal_path| {
+        // The bands of the journal are kept if they are complete and the partial file holds them
+        let tiles_across = width.div_ceil(options.tile_size) as usize;
+        let resumed = if options.resume { Journal::resume(journal_path, &header)? } else { None };
+        let resumed = resumed.and_then(|journal| {
+            let bands = &journal.bands;
+            let end = bands.last().and_then(|band| band.tiles.last()).map_or(0, |&[offset, length]| offset + length);
+            let complete = bands.iter().enumerate().all(|(index, band)| {
+                band.tiles.len() == tiles_across && band.rows == ((index as u32 + 1) * options.tile_size).min(height)
+            });
+            let file = OpenOptions::new().read(true).write(true).open(partial).ok()?;
+            (complete && file.metadata().ok()?.len() >= end).then_some((journal, file))
+        });
+        let (journal, file) = match resumed {
+            Some(resumed) => resumed,
+            None => (Journal::create(journal_path, &header)?, File::create(partial)?),
+        };
+        let bands = &journal.bands;
+        let kept = match (bands.first(), bands.last()) {
+            (Some(first), Some(last)) => first.tiles[0][0]..last.tiles[last.tiles.len() - 1].iter().sum(),
+            _ => 0..0,
+        };
+        if !bands.is_empty() {
+            debug!("{}: resuming after {} of {} rows", path.display(), bands[bands.len() - 1].rows, height);
+        }
+        file.set_len(kept.end)?;
+        let mut resume = Resume { file: &file, journal };
+        let writer = Resumed {
+            inner: &file,
+            position: 0,
+            kept,
+        };
+        if bigtiff {
+            let mut tiff = TiffEncoder::new_big(writer)?;
+            write_tiles(&mut tiff, &header, options, compressor, render_band, &mut resume)?;
+        } else {
+            let mut tiff = TiffEncoder::new(writer)?;
+            write_tiles(&mut tiff, &header, options, compressor, render_band, &mut resume)?;
+        }
+        Ok(file.sync_all()?)
+    })
+}
+
+/// Render the bands of tiles of one image and stream them into an OME-Zarr group, through the
+/// partial store and journal of the render described by `header`.
+fn write_zarr_bands<T, F>(
+    path: &Path,
+    header: Header,
+    options: &TiledOptions,
+    zarr: &ZarrOptions,
+    mut render_band: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: OutputSample,
+    F: FnMut(u32, u32) -> Result<Array3<T>, Box<dyn std::error::Error>>,
+{
+    debug!("{}: OME-Zarr, chunks of {} pixels, {:?} compression", path.display(), zarr.chunk_size, options.compression);
+    let (width, height, tile_size) = (header.width, header.height, options.tile_size);
+    let header = Header {
+        layout: format!("zarr {} {:?}", zarr.chunk_size, options.compression),
+        bits_per_sample: T::BITS,
+        ..header
+    };
+    write_resumable_store(path, options.resume, |partial, journal_path| {
+        // The rows of full resolution chunks recorded by the journal are kept, and those of the
+        // lower levels written again from them
+        let resumed = if options.resume { Journal::resume(journal_path, &header)? } else { None };
+        let (mut journal, kept) = match resumed {
+            Some(journal) if partial.is_dir() => {
+                let kept = journal.bands.last().map_or(0, |band| band.rows);
+                (journal, kept)
+            }
+            _ => {
+                if partial.exists() {
+                    fs::remove_dir_all(partial)?;
+                }
+                (Journal::create(journal_path, &header)?, 0)
+            }
+        };
+        let writer = ZarrWriter::<T>::create(partial, width, height, zarr, options.compression)?;
+        let mut writer = writer.resumed(kept as usize);
+        // Bands are rendered again from the one holding the first row that is not kept, as
+        // ordered dithering depends on the position of the rows in their band
+        let start = kept / tile_size * tile_size;
+        if kept > 0 {
+            debug!("{}: resuming after {} of {} rows", path.display(), kept, height);
+            writer.replay(start as usize)?;
+        }
+        let mut recorded = writer.written();
+        for y0 in (start..height).step_by(tile_size as usize) {
+            trace!("rendering rows {}..{} of {}", y0, (y0 + tile_size).min(height), height);
+            writer.push_rows(render_band(y0, (y0 + tile_size).min(height))?.view())?;
+            if writer.written() > recorded {
+                recorded = writer.written();
+                journal.record(Band {
+                    rows: recorded as u32,
+                    tiles: Vec::new(),
+                })?;
+            }
+        }
+        writer.finish()
+    })
+}
+
+/// The partial file of a resumable tiled TIFF with its journal.
+struct Resume<'a> {
+    file: &'a File,
+    journal: Journal,
+}
+
+/// Writer of a resumed partial file that skips the bytes in `kept`, which hold the tiles of an
+/// earlier run, so that an encoder writing the file from the start continues after them.
+struct Resumed<W> {
+    inner: W,
+    position: u64,
+    kept: Range<u64>,
+}
+
+impl<W: Write + Seek> Write for Resumed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = if self.kept.contains(&self.position) {
+            let skipped = buf.len().min(usize::try_from(self.kept.end - self.position).unwrap_or(usize::MAX));
+            self.inner.seek(SeekFrom::Start(self.position + skipped as u64))?;
+            skipped
+        } else if self.position < self.kept.start {
+            let len = buf.len().min(usize::try_from(self.kept.start - self.position).unwrap_or(usize::MAX));
+            self.inner.write(&buf[..len])?
+        } else {
+            self.inner.write(buf)?
+        };
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for Resumed<W> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(position)?;
+        Ok(self.position)
+    }
+}
+
+/// Render and write the bands of tiles of one image, after the bands recorded by the journal of
+/// `resume`, recording each band once the partial file is flushed.
+fn write_tiles<T, F, W, K>(
+    tiff: &mut TiffEncoder<W, K>,
+    header: &Header,
+    options: &TiledOptions,
+    mut compressor: Compressor,
+    mut render_band: F,
+    resume: &mut Resume,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: OutputSample,
+    F: FnMut(u32, u32) -> Result<Array3<T>, Box<dyn std::error::Error>>,
+    W: Write + Seek,
+    K: TiffKind,
+{
+    let (width, height, tile_size) = (header.width, header.height, options.tile_size);
+    let tile = tile_size as usize;
+    let mut directory = tiff.new_directory()?;
+    let mut tiles: Vec<[u64; 2]> = resume.journal.bands.iter().flat_map(|band| band.tiles.iter().copied()).collect();
+
+    // The kept tiles are written again as zeros that the partial file skips
+    if let (Some(&[first, _]), Some(&[offset, length])) = (tiles.first(), tiles.last()) {
+        let zeros = vec![0u8; SKIP_BUFFER];
+        if directory.write_data(&zeros[..0])? != first {
+            return Err(format!("the tiles of the journal do not start after the header, at {}", first).into());
+        }
+        let mut position = first;
+        while position < offset + length {
+            let len = zeros.len().min(usize::try_from(offset + length - position)?);
+            position = directory.write_data(&zeros[..len])? + len as u64;
+        }
+    }
+
+    let start = resume.journal.bands.last().map_or(0, |band| band.rows);
+    for y0 in (start..height).step_by(tile) {
+        trace!("rendering rows {}..{} of {}", y0, (y0 + tile_size).min(height), height);
+        let y1 = (y0 + tile_size).min(height);
+        let band = render_band(y0, y1)?;
+        let first = tiles.len();
+        write_band_tiles(&mut directory, &mut compressor, band.view(), tile, &mut tiles)?;
+        resume.file.sync_data()?;
+        resume.journal.record(Band {
+            rows: y1,
+            tiles: tiles[first..].to_vec(),
+        })?;
+    }
+
+    let metadata = ImageMetadata {
+        ome: options.ome.as_ref(),
+        pixel_size_um: options.pixel_size_um,
+        parameters: &options.parameters,
+    };
+    write_rgb_tags::<T, _, _>(&mut directory, width, height, 3, options.compression, &metadata)?;
+    write_tile_tags(&mut directory, tile_size, &tiles)?;
+    directory.finish()?;
+    Ok(())
+}
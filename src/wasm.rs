@@ -0,0 +1,140 @@
+//! WebAssembly module of the wasm feature, for previews in the browser: images decoded by the
+//! page, e.g. into an `ImageData` or with a TIFF library, are rendered into RGBA pixels for a
+//! canvas, so that k can be tuned without a server.
+//!
+//! Built for wasm32-unknown-unknown, which has no threads: the parallel iterators of the rendering
+//! run on the calling thread, as rayon falls back to it when no thread can be spawned.
+use crate::{normalize_value, resolve_input_max, scale_with, LoadOptions, Params, ScaleOptions, DEFAULT_BETA};
+use ndarray::parallel::prelude::*;
+use ndarray::{ArrayView2, Zip};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::Clamped;
+
+/// Parameters of `renderU8` and `renderU16`, the defaults of the command line when created.
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderParams {
+    /// K factor of both channels, non-negative.
+    pub k: f32,
+    /// Saturation percentile of the nucleus channel in (0, 100].
+    #[wasm_bindgen(js_name = nucleusPercentile)]
+    pub nucleus_percentile: f32,
+    /// Saturation percentile of the eosin channel in (0, 100].
+    #[wasm_bindgen(js_name = eosinPercentile)]
+    pub eosin_percentile: f32,
+    betas: Vec<f32>,
+}
+
+impl Default for RenderParams {
+    fn default() -> Self {
+        let percentile = ScaleOptions::default().percentile;
+        RenderParams {
+            k: 2.5,
+            nucleus_percentile: percentile,
+            eosin_percentile: percentile,
+            betas: DEFAULT_BETA.concat(),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl RenderParams {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        RenderParams::default()
+    }
+
+    /// Beta coefficients of hematoxylin then eosin, each red, green and blue.
+    #[wasm_bindgen(getter)]
+    pub fn betas(&self) -> Vec<f32> {
+        self.betas.clone()
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_betas(&mut self, betas: Vec<f32>) {
+        self.betas = betas;
+    }
+}
+
+/// Render 8bit nucleus and eosin channels of `width` x `height` pixels in row order, normalized by
+/// 255, into RGBA pixels in row order for `new ImageData(rgba, width, height)`.
+#[wasm_bindgen(js_name = renderU8)]
+pub fn render_u8(
+    nucleus: &[u8],
+    eosin: &[u8],
+    width: usize,
+    height: usize,
+    params: &RenderParams,
+) -> Result<Clamped<Vec<u8>>, JsError> {
+    render_rgba([nucleus, eosin], width, height, params, f32::from(u8::MAX)).map(Clamped).map_err(|e| JsError::new(&e))
+}
+
+/// Render 16bit nucleus and eosin channels like `renderU8`, normalized by 65535.
+#[wasm_bindgen(js_name = renderU16)]
+pub fn render_u16(
+    nucleus: &[u16],
+    eosin: &[u16],
+    width: usize,
+    height: usize,
+    params: &RenderParams,
+) -> Result<Clamped<Vec<u8>>, JsError> {
+    render_rgba([nucleus, eosin], width, height, params, f32::from(u16::MAX)).map(Clamped).map_err(|e| JsError::new(&e))
+}
+
+/// Validate the parameters, then normalize, scale and render the channels into RGBA pixels.
+fn render_rgba<T: Copy + Into<f32> + Sync>(
+    channels: [&[T]; 2],
+    width: usize,
+    height: usize,
+    params: &RenderParams,
+    container_max: f32,
+) -> Result<Vec<u8>, String> {
+    if !params.k.is_finite() || params.k < 0.0 {
+        return Err(format!("k must be non-negative, got {}", params.k));
+    }
+    for percentile in [params.nucleus_percentile, params.eosin_percentile] {
+        if !(percentile > 0.0 && percentile <= 100.0) {
+            return Err(format!("percentile must be in (0, 100], got {}", percentile));
+        }
+    }
+    let beta = match params.betas[..] {
+        [hr, hg, hb, er, eg, eb] => [[hr, hg, hb], [er, eg, eb]],
+        _ => return Err(format!("betas must hold 6 values, got {}", params.betas.len())),
+    };
+    if beta.iter().flatten().any(|v| !v.is_finite() || *v < 0.0) {
+        return Err(format!("beta values must be non-negative, got {:?}", beta));
+    }
+    let render_params = Params {
+        beta,
+        ..Params::default().with_k(params.k)
+    };
+
+    let mut scaled = Vec::with_capacity(2);
+    for ((channel, percentile), name) in channels
+        .into_iter()
+        .zip([params.nucleus_percentile, params.eosin_percentile])
+        .zip(["nucleus", "eosin"])
+    {
+        let view = ArrayView2::from_shape((height, width), channel)
+            .map_err(|_| format!("{} holds {} values, not {} x {}", name, channel.len(), width, height))?;
+        let data_max = || view.into_par_iter().map(|&v| v.into()).reduce(|| 0.0, f32::max);
+        let input_max = resolve_input_max(&LoadOptions::default(), Some(container_max), data_max)
+            .map_err(|e| e.to_string())?;
+        let mut channel = Zip::from(view).par_map_collect(|&v| normalize_value(v.into(), input_max));
+        let options = ScaleOptions {
+            percentile,
+            ..ScaleOptions::default()
+        };
+        scale_with(&mut channel, &options).map_err(|e| format!("{}: {}", name, e))?;
+        scaled.push(channel);
+    }
+    let eosin = scaled.pop().expect("both channels are scaled");
+    let nucleus = scaled.pop().expect("both channels are scaled");
+    let rgb = crate::render(nucleus, eosin, &render_params);
+    Ok(rgb
+        .as_slice()
+        .expect("rendered in standard layout")
+        .chunks_exact(3)
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], u8::MAX])
+        .collect())
+}
@@ -0,0 +1,526 @@
+//! OME-Zarr (NGFF) input: one channel of a multiscale image at a chosen resolution level, read
+//! chunk by chunk.
+//!
+//! Zarr v2 (NGFF up to 0.4) and v3 (NGFF 0.5) stores without sharding are supported, images with
+//! more than one time point or z plane are read at the first of each.
+use crate::{blosc, ChannelSelector, LoadOptions, RawImage};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use log::debug;
+use ndarray::parallel::prelude::*;
+use ndarray::{s, Array2, Axis};
+use serde_json::Value;
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+/// Whether `path` is a Zarr store rather than an image file.
+pub(crate) fn is_zarr(path: &Path) -> bool {
+    #[cfg(feature = "remote")]
+    if let Some(url) = path.to_str().filter(|path| crate::is_url(path)) {
+        return crate::remote::is_zarr(url);
+    }
+    path.is_dir() && [".zattrs", ".zgroup", "zarr.json"].iter().any(|name| path.join(name).is_file())
+}
+
+/// Read the channel and resolution level selected by `options` from an OME-Zarr image as raw
+/// (not normalized) values.
+pub(crate) fn read_plane(path: &Path, options: &LoadOptions) -> Result<RawImage, Box<dyn std::error::Error>> {
+    read_plane_at(path, options).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Read a plane as `read_plane` does, with errors not yet prefixed by the path.
+fn read_plane_at(path: &Path, options: &LoadOptions) -> Result<RawImage, String> {
+    let (image, attributes) = image_group(path)?;
+    let multiscales = attributes
+        .get("multiscales")
+        .and_then(|m| m.get(0))
+        .ok_or("no OME-Zarr multiscales metadata")?;
+
+    // Axes are objects since NGFF 0.4, names before, and implied as tczyx in the first versions
+    let axes: Vec<String> = match multiscales.get("axes").and_then(Value::as_array) {
+        Some(axes) => axes
+            .iter()
+            .map(|axis| axis.get("name").unwrap_or(axis).as_str().unwrap_or_default().to_lowercase())
+            .collect(),
+        None => ["t", "c", "z", "y", "x"].iter().map(|a| a.to_string()).collect(),
+    };
+    let datasets = multiscales.get("datasets").and_then(Value::as_array).ok_or("multiscales without datasets")?;
+    let level = options.level;
+    let dataset = datasets
+        .get(level)
+        .and_then(|d| d.get("path"))
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("level {} is out of range, the image has {} levels", level, datasets.len()))?;
+    let array = ZarrArray::open(&image.join(dataset))?;
+    if array.shape.len() != axes.len() {
+        return Err(format!("array has {} dimensions but {} axes", array.shape.len(), axes.len()));
+    }
+
+    // The channel index is resolved against the omero channel labels
+    let axis = |name: &str| axes.iter().position(|a| a == name);
+    let (Some(y_axis), Some(x_axis)) = (axis("y"), axis("x")) else {
+        return Err(format!("expected y and x axes, got {}", axes.join("")));
+    };
+    let channels = axis("c").map_or(1, |c| array.shape[c] as usize);
+    let channel = match &options.channel {
+        None => 0,
+        Some(ChannelSelector::Index(index)) if *index < channels => *index,
+        Some(ChannelSelector::Index(index)) => {
+            return Err(format!("channel {} is out of range, the image has {} channels", index, channels));
+        }
+        Some(ChannelSelector::Name(name)) => {
+            let labels: Vec<&str> = attributes
+                .get("omero")
+                .and_then(|o| o.get("channels"))
+                .and_then(Value::as_array)
+                .map(|channels| channels.iter().map(|c| c.get("label").and_then(Value::as_str).unwrap_or_default()).collect())
+                .unwrap_or_default();
+            labels.iter().position(|label| label == name).ok_or_else(|| {
+                format!("no channel named '{}', expected one of: {}", name, labels.join(", "))
+            })?
+        }
+    };
+    let mut origin = vec![0u64; axes.len()];
+    if let Some(c) = axis("c") {
+        origin[c] = channel as u64;
+    }
+
+    debug!(
+        "{}: level {} array {} with shape {:?}, chunks {:?}, {:?}, {:?} compression, reading channel {}",
+        path.display(),
+        level,
+        dataset,
+        array.shape,
+        array.chunks,
+        array.data_type,
+        array.compression,
+        channel
+    );
+    let plane = array.read_plane(&origin, y_axis, x_axis)?;
+    let pixel_format = array.data_type.pixel_format();
+    Ok((plane, pixel_format, array.data_type.container_max()))
+}
+
+/// Directory and attributes of the image group of an OME-Zarr store, the store itself or, for
+/// bioformats2raw layouts, its first image.
+fn image_group(path: &Path) -> Result<(PathBuf, Value), String> {
+    let attributes = group_attributes(path)?;
+    if attributes.get("multiscales").is_none() && attributes.get("bioformats2raw.layout").is_some() {
+        let image = path.join("0");
+        let attributes = group_attributes(&image)?;
+        return Ok((image, attributes));
+    }
+    Ok((path.to_path_buf(), attributes))
+}
+
+/// Attributes of a v2 or v3 group, with NGFF 0.5 metadata taken out of its `ome` namespace.
+fn group_attributes(path: &Path) -> Result<Value, String> {
+    if let Some(v3) = read_json(&path.join("zarr.json"))? {
+        let attributes = v3.get("attributes").cloned().unwrap_or(Value::Null);
+        return Ok(attributes.get("ome").cloned().unwrap_or(attributes));
+    }
+    Ok(read_json(&path.join(".zattrs"))?.unwrap_or(Value::Null))
+}
+
+/// Parse a JSON file, `None` when it does not exist.
+fn read_json(path: &Path) -> Result<Option<Value>, String> {
+    match read_file(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+/// Read a file of a store, or the object of a store at a URL.
+fn read_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    #[cfg(feature = "remote")]
+    if let Some(url) = path.to_str().filter(|path| crate::is_url(path)) {
+        return crate::remote::read_object(url);
+    }
+    fs::read(path)
+}
+
+/// Sample type of a Zarr array.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DataType {
+    U8,
+    U16,
+    U32,
+    F32,
+    F64,
+}
+
+impl DataType {
+    /// Parse a v2 NumPy type string (e.g., `<u2`) into the type and whether it is little-endian.
+    fn from_v2(dtype: &str) -> Result<(Self, bool), String> {
+        let (order, kind) = dtype.split_at(dtype.len().min(1));
+        let data_type = Self::from_name(match kind {
+            "u1" => "uint8",
+            "u2" => "uint16",
+            "u4" => "uint32",
+            "f4" => "float32",
+            "f8" => "float64",
+            _ => kind,
+        })
+        .map_err(|_| format!("unsupported data type '{}'", dtype))?;
+        Ok((data_type, order != ">"))
+    }
+
+    /// Parse a v3 data type name (e.g., `uint16`).
+    fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "uint8" => Ok(DataType::U8),
+            "uint16" => Ok(DataType::U16),
+            "uint32" => Ok(DataType::U32),
+            "float32" => Ok(DataType::F32),
+            "float64" => Ok(DataType::F64),
+            _ => Err(format!("unsupported data type '{}', expected unsigned integer or float", name)),
+        }
+    }
+
+    fn bytes(self) -> usize {
+        match self {
+            DataType::U8 => 1,
+            DataType::U16 => 2,
+            DataType::U32 | DataType::F32 => 4,
+            DataType::F64 => 8,
+        }
+    }
+
+    /// Name of the pixel format, as reported for TIFF inputs (e.g., L16, L32F).
+    fn pixel_format(self) -> String {
+        let float = matches!(self, DataType::F32 | DataType::F64);
+        format!("L{}{}", self.bytes() * 8, if float { "F" } else { "" })
+    }
+
+    /// Maximum value of integer samples, `None` for floating point data.
+    fn container_max(self) -> Option<f32> {
+        match self {
+            DataType::F32 | DataType::F64 => None,
+            _ => Some(((1u64 << (self.bytes() * 8)) - 1) as f32),
+        }
+    }
+
+    /// Decode one sample.
+    fn decode(self, bytes: &[u8], little_endian: bool) -> f32 {
+        macro_rules! decode {
+            ($type:ty) => {{
+                let bytes = bytes.try_into().expect("sample of the type size");
+                (if little_endian { <$type>::from_le_bytes(bytes) } else { <$type>::from_be_bytes(bytes) }) as f32
+            }};
+        }
+        match self {
+            DataType::U8 => bytes[0] as f32,
+            DataType::U16 => decode!(u16),
+            DataType::U32 => decode!(u32),
+            DataType::F32 => decode!(f32),
+            DataType::F64 => decode!(f64),
+        }
+    }
+}
+
+/// Byte-level compression of chunks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    None,
+    Blosc,
+    Zlib,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn from_id(id: &str) -> Result<Self, String> {
+        match id {
+            "blosc" => Ok(Compression::Blosc),
+            "zlib" => Ok(Compression::Zlib),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unsupported compressor '{}', expected one of: blosc, zlib, gzip, zstd", id)),
+        }
+    }
+
+    fn decompress(self, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        let mut out = Vec::new();
+        match self {
+            Compression::None => return Ok(data),
+            Compression::Blosc => return blosc::decompress(&data),
+            Compression::Zlib => ZlibDecoder::new(&data[..]).read_to_end(&mut out).map_err(|e| e.to_string())?,
+            Compression::Gzip => GzDecoder::new(&data[..]).read_to_end(&mut out).map_err(|e| e.to_string())?,
+            Compression::Zstd => ruzstd::decoding::StreamingDecoder::new(&data[..])
+                .map_err(|e| e.to_string())?
+                .read_to_end(&mut out)
+                .map_err(|e| e.to_string())?,
+        };
+        Ok(out)
+    }
+}
+
+/// Metadata of a Zarr array needed to read its chunks.
+#[derive(Debug)]
+struct ZarrArray {
+    path: PathBuf,
+    shape: Vec<u64>,
+    chunks: Vec<u64>,
+    data_type: DataType,
+    little_endian: bool,
+    compression: Compression,
+    fill_value: f32,
+    /// Prefix and separator of chunk keys, e.g. `c` and `/` for `c/0/1/2`.
+    key_prefix: &'static str,
+    separator: String,
+}
+
+impl ZarrArray {
+    /// Read the v3 `zarr.json` or v2 `.zarray` metadata of an array.
+    fn open(path: &Path) -> Result<Self, String> {
+        if let Some(v3) = read_json(&path.join("zarr.json"))? {
+            return Self::from_v3(path, &v3);
+        }
+        let v2 = read_json(&path.join(".zarray"))?.ok_or_else(|| format!("{}: no Zarr array", path.display()))?;
+        Self::from_v2(path, &v2)
+    }
+
+    /// Check that there is a chunk size of at least 1 for every dimension, and that chunks hold
+    /// fewer samples than can be addressed.
+    fn checked(self) -> Result<Self, String> {
+        if self.chunks.len() != self.shape.len() {
+            return Err(format!(
+                "chunk shape {:?} has {} dimensions but the shape {:?} has {}",
+                self.chunks,
+                self.chunks.len(),
+                self.shape,
+                self.shape.len()
+            ));
+        }
+        if self.chunks.contains(&0) {
+            return Err(format!("chunk shape {:?} has an empty dimension", self.chunks));
+        }
+        let samples = self.chunks.iter().try_fold(self.data_type.bytes() as u64, |bytes, &c| bytes.checked_mul(c));
+        if samples.is_none_or(|bytes| usize::try_from(bytes).is_err()) {
+            return Err(format!("chunk shape {:?} is too large", self.chunks));
+        }
+        Ok(self)
+    }
+
+    fn from_v2(path: &Path, metadata: &Value) -> Result<Self, String> {
+        if metadata.get("order").and_then(Value::as_str) == Some("F") {
+            return Err("Fortran ordered arrays are not supported".to_string());
+        }
+        if metadata.get("filters").is_some_and(|f| !f.is_null()) {
+            return Err("arrays with filters are not supported".to_string());
+        }
+        let dtype = metadata.get("dtype").and_then(Value::as_str).ok_or("array without dtype")?;
+        let (data_type, little_endian) = DataType::from_v2(dtype)?;
+        let compression = match metadata.get("compressor") {
+            None | Some(Value::Null) => Compression::None,
+            Some(compressor) => Compression::from_id(compressor.get("id").and_then(Value::as_str).unwrap_or_default())?,
+        };
+        ZarrArray {
+            path: path.to_path_buf(),
+            shape: u64_list(metadata.get("shape"))?,
+            chunks: u64_list(metadata.get("chunks"))?,
+            data_type,
+            little_endian,
+            compression,
+            fill_value: fill_value(metadata.get("fill_value")),
+            key_prefix: "",
+            separator: metadata
+                .get("dimension_separator")
+                .and_then(Value::as_str)
+                .unwrap_or(".")
+                .to_string(),
+        }
+        .checked()
+    }
+
+    fn from_v3(path: &Path, metadata: &Value) -> Result<Self, String> {
+        let data_type = DataType::from_name(metadata.get("data_type").and_then(Value::as_str).unwrap_or_default())?;
+        let chunks = metadata
+            .get("chunk_grid")
+            .and_then(|grid| grid.get("configuration"))
+            .and_then(|configuration| configuration.get("chunk_shape"));
+        let key_encoding = metadata.get("chunk_key_encoding");
+        let v2_keys = key_encoding.and_then(|k| k.get("name")).and_then(Value::as_str) == Some("v2");
+        let separator = key_encoding
+            .and_then(|k| k.get("configuration"))
+            .and_then(|c| c.get("separator"))
+            .and_then(Value::as_str)
+            .unwrap_or(if v2_keys { "." } else { "/" });
+
+        // Codecs are applied in order on write, an array to bytes codec followed by compression
+        let mut little_endian = true;
+        let mut compression = Compression::None;
+        for codec in metadata.get("codecs").and_then(Value::as_array).into_iter().flatten() {
+            let name = codec.get("name").and_then(Value::as_str).unwrap_or_default();
+            match name {
+                "bytes" => {
+                    let endian = codec.get("configuration").and_then(|c| c.get("endian")).and_then(Value::as_str);
+                    little_endian = endian != Some("big");
+                }
+                "sharding_indexed" => return Err("sharded arrays are not supported".to_string()),
+                _ => compression = Compression::from_id(name)?,
+            }
+        }
+        ZarrArray {
+            path: path.to_path_buf(),
+            shape: u64_list(metadata.get("shape"))?,
+            chunks: u64_list(chunks)?,
+            data_type,
+            little_endian,
+            compression,
+            fill_value: fill_value(metadata.get("fill_value")),
+            key_prefix: if v2_keys { "" } else { "c" },
+            separator: separator.to_string(),
+        }
+        .checked()
+    }
+
+    /// Read the 2D plane spanned by the y and x axes through `origin`, decoding the chunks that
+    /// intersect it in parallel, each copied into the plane as soon as it is decoded.
+    fn read_plane(&self, origin: &[u64], y_axis: usize, x_axis: usize) -> Result<Array2<f32>, String> {
+        let (height, width) = (self.shape[y_axis] as usize, self.shape[x_axis] as usize);
+        let (chunk_height, chunk_width) = (self.chunks[y_axis] as usize, self.chunks[x_axis] as usize);
+        let mut plane = Array2::<f32>::zeros((height, width));
+        plane
+            .axis_chunks_iter_mut(Axis(0), chunk_height)
+            .into_par_iter()
+            .enumerate()
+            .try_for_each(|(cy, mut band)| {
+                band.axis_chunks_iter_mut(Axis(1), chunk_width).into_par_iter().enumerate().try_for_each(|(cx, mut area)| {
+                    let mut index: Vec<u64> = origin.iter().zip(&self.chunks).map(|(o, c)| o / c).collect();
+                    index[y_axis] = cy as u64;
+                    index[x_axis] = cx as u64;
+                    let block = self.read_chunk_plane(&index, origin, y_axis, x_axis)?;
+                    // Chunks at the edges of the array extend past it
+                    let (rows, cols) = area.dim();
+                    area.assign(&block.slice(s![..rows, ..cols]));
+                    Ok::<(), String>(())
+                })
+            })?;
+        Ok(plane)
+    }
+
+    /// Decode the y/x plane through `origin` of the chunk at grid position `index`, filled with
+    /// the fill value when the chunk was never written.
+    fn read_chunk_plane(&self, index: &[u64], origin: &[u64], y_axis: usize, x_axis: usize) -> Result<Array2<f32>, String> {
+        let (rows, cols) = (self.chunks[y_axis] as usize, self.chunks[x_axis] as usize);
+        let key: Vec<String> = index.iter().map(u64::to_string).collect();
+        let mut key = key.join(&self.separator);
+        if !self.key_prefix.is_empty() {
+            key = format!("{}{}{}", self.key_prefix, self.separator, key);
+        }
+        let path = self.path.join(&key);
+        let data = match read_file(&path) {
+            Ok(data) => self.compression.decompress(data).map_err(|e| format!("chunk {}: {}", key, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Array2::from_elem((rows, cols), self.fill_value)),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+
+        // Chunks are stored in C order, with the same shape at the edges of the array
+        let bytes = self.data_type.bytes();
+        let expected = self.chunks.iter().product::<u64>() as usize * bytes;
+        if data.len() != expected {
+            return Err(format!("chunk {} has {} bytes, expected {}", key, data.len(), expected));
+        }
+        let mut strides = vec![1usize; self.chunks.len()];
+        for axis in (0..self.chunks.len().saturating_sub(1)).rev() {
+            strides[axis] = strides[axis + 1] * self.chunks[axis + 1] as usize;
+        }
+        let offset: usize = origin
+            .iter()
+            .zip(&self.chunks)
+            .zip(&strides)
+            .enumerate()
+            .filter(|(axis, _)| *axis != y_axis && *axis != x_axis)
+            .map(|(_, ((o, c), stride))| (o % c) as usize * stride)
+            .sum();
+        Ok(Array2::from_shape_fn((rows, cols), |(y, x)| {
+            let start = (offset + y * strides[y_axis] + x * strides[x_axis]) * bytes;
+            self.data_type.decode(&data[start..start + bytes], self.little_endian)
+        }))
+    }
+}
+
+/// Parse a list of non-negative integers, e.g. a shape.
+fn u64_list(value: Option<&Value>) -> Result<Vec<u64>, String> {
+    value
+        .and_then(Value::as_array)
+        .and_then(|values| values.iter().map(Value::as_u64).collect())
+        .ok_or_else(|| "array metadata without a valid shape or chunk shape".to_string())
+}
+
+/// Value of unwritten chunks, numbers or the special float names, 0 when not set.
+fn fill_value(value: Option<&Value>) -> f32 {
+    match value {
+        Some(Value::Number(number)) => number.as_f64().unwrap_or(0.0) as f32,
+        Some(Value::String(name)) => match name.as_str() {
+            "NaN" => f32::NAN,
+            "Infinity" => f32::INFINITY,
+            "-Infinity" => f32::NEG_INFINITY,
+            _ => 0.0,
+        },
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SaveOptions, ZarrOptions};
+    use ndarray::Array3;
+    use serde_json::json;
+
+    #[test]
+    fn malformed_chunk_shapes_are_errors() {
+        let v2 = |chunks: Value| json!({"chunks": chunks, "dtype": "<u2", "shape": [2, 40, 56], "zarr_format": 2});
+        let v3 = |chunks: Value| {
+            json!({
+                "chunk_grid": {"name": "regular", "configuration": {"chunk_shape": chunks}},
+                "data_type": "uint16",
+                "shape": [2, 40, 56],
+                "zarr_format": 3
+            })
+        };
+        let path = Path::new("image.zarr/0");
+        for (chunks, message) in [
+            (json!([16, 16]), "has 2 dimensions but the shape [2, 40, 56] has 3"),
+            (json!([1, 16, 16, 16]), "has 4 dimensions"),
+            (json!([1, 0, 16]), "has an empty dimension"),
+            (json!([u64::MAX, 2, 16]), "is too large"),
+        ] {
+            for result in [ZarrArray::from_v2(path, &v2(chunks.clone())), ZarrArray::from_v3(path, &v3(chunks.clone()))] {
+                let error = result.unwrap_err();
+                assert!(error.contains(message), "{}: {}", chunks, error);
+            }
+        }
+        assert!(ZarrArray::from_v2(path, &v2(json!([1, 16, 16]))).is_ok());
+        assert!(ZarrArray::from_v3(path, &v3(json!([1, 16, 16]))).is_ok());
+    }
+
+    #[test]
+    fn planes_are_assembled_from_edge_chunks() {
+        let path = std::env::temp_dir().join(format!("virtualhe-test-{}-planes.zarr", std::process::id()));
+        let rgb = Array3::from_shape_fn((40, 57, 3), |(y, x, c)| ((x * 3 + y * 5 + c * 70) % 256) as u8);
+        let options = SaveOptions {
+            zarr: Some(ZarrOptions {
+                chunk_size: 16,
+                pixel_size_um: None,
+            }),
+            ..SaveOptions::default()
+        };
+        crate::save_with(rgb.clone(), &path, &options).unwrap();
+        for channel in 0..3 {
+            let options = LoadOptions {
+                channel: Some(ChannelSelector::Index(channel)),
+                ..LoadOptions::default()
+            };
+            let (plane, _, container_max) = read_plane(&path, &options).unwrap();
+            assert_eq!(container_max, Some(255.0));
+            assert_eq!(plane, rgb.slice(s![.., .., channel]).mapv(f32::from));
+        }
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
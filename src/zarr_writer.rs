@@ -0,0 +1,397 @@
+//! OME-Zarr (NGFF 0.4) output: the RGB image and its 2x downsampled levels as chunked Zarr v2
+//! arrays with axes cyx, written band by band so that only a row of chunks per level is held in
+//! memory.
+use crate::tiff_writer::downsample;
+use crate::zarr_reader::is_zarr;
+use crate::{Error, OutputSample, TiffCompression, ZarrOptions};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use log::{debug, trace};
+use ndarray::parallel::prelude::*;
+use ndarray::{s, Array3, ArrayView3, Axis};
+use serde_json::json;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Write a (row, column, RGB) array as an OME-Zarr multiscale group.
+pub(crate) fn write_zarr<T: OutputSample>(
+    path: &Path,
+    rgb: ArrayView3<T>,
+    options: &ZarrOptions,
+    compression: TiffCompression,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (height, width) = (rgb.shape()[0], rgb.shape()[1]);
+    let size = (u32::try_from(width)?, u32::try_from(height)?);
+    write_store(path, |temporary| {
+        let mut writer = ZarrWriter::create(temporary, size.0, size.1, options, compression)?;
+        let chunk = options.chunk_size as usize;
+        for y0 in (0..height).step_by(chunk) {
+            writer.push_rows(rgb.slice(s![y0..(y0 + chunk).min(height), .., ..]))?;
+        }
+        writer.finish()
+    })
+}
+
+/// Write an OME-Zarr store at `path` by calling `write` with a temporary path, as `atomic::write`
+/// does, replacing an existing store once it succeeds.
+pub(crate) fn write_store<F>(path: &Path, write: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(&Path) -> Result<(), Box<dyn std::error::Error>>,
+{
+    if path.exists() && !is_zarr(path) {
+        return Err(format!("{} exists and is not a Zarr store", path.display()).into());
+    }
+    crate::atomic::write(path, |temporary| {
+        write(temporary)?;
+        if path.exists() {
+            fs::remove_dir_all(path)?;
+        }
+        Ok(())
+    })
+}
+
+/// Write an OME-Zarr store at `path` by calling `write` with the paths of a partial store and its
+/// journal, as `atomic::write_resumable` does, replacing an existing store once it succeeds.
+pub(crate) fn write_resumable_store<F>(path: &Path, resume: bool, write: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(&Path, &Path) -> Result<(), Box<dyn std::error::Error>>,
+{
+    if path.exists() && !is_zarr(path) {
+        return Err(format!("{} exists and is not a Zarr store", path.display()).into());
+    }
+    crate::atomic::write_resumable(path, resume, |partial, journal| {
+        write(partial, journal)?;
+        if path.exists() {
+            fs::remove_dir_all(path)?;
+        }
+        Ok(())
+    })
+}
+
+/// Streams the rows of an RGB image from top to bottom into an OME-Zarr multiscale group, with
+/// levels down to the size of one chunk.
+pub(crate) struct ZarrWriter<T: OutputSample> {
+    path: PathBuf,
+    chunk: usize,
+    compression: TiffCompression,
+    levels: Vec<Level<T>>,
+    /// Rows of the full resolution level already written by an earlier run, which are not written
+    /// again, see `resumed`.
+    kept: usize,
+    /// Whether each chunk is flushed to disk once written.
+    sync: bool,
+}
+
+/// Rows of one resolution level that are not written or not downsampled yet.
+struct Level<T> {
+    height: usize,
+    /// Rows of the next row of chunks, starting at row `written`.
+    rows: Array3<T>,
+    written: usize,
+    /// Rows not yet downsampled into the next level, at most one.
+    pending: Array3<T>,
+}
+
+impl<T: OutputSample> ZarrWriter<T> {
+    /// Create the group and array metadata of a `width` x `height` image in a new directory at
+    /// `path`.
+    pub(crate) fn create(
+        path: &Path,
+        width: u32,
+        height: u32,
+        options: &ZarrOptions,
+        compression: TiffCompression,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let compressor = match compression {
+            TiffCompression::None => serde_json::Value::Null,
+            TiffCompression::Deflate => json!({ "id": "zlib", "level": 6 }),
+            TiffCompression::Lzw | TiffCompression::Zstd => {
+                return Err(Error::InvalidOptions("OME-Zarr output supports none or deflate compression".to_string()).into())
+            }
+        };
+        if options.chunk_size == 0 {
+            return Err(Error::InvalidOptions("chunk size must be positive".to_string()).into());
+        }
+
+        // Levels are halved until the long edge fits in one chunk
+        let chunk = options.chunk_size as usize;
+        let mut sizes = vec![(width as usize, height as usize)];
+        while let Some(&(w, h)) = sizes.last().filter(|(w, h)| w.max(h) > &chunk) {
+            sizes.push((w.div_ceil(2), h.div_ceil(2)));
+        }
+
+        debug!("{}: {} levels down to {}x{}", path.display(), sizes.len(), sizes[sizes.len() - 1].0, sizes[sizes.len() - 1].1);
+        fs::create_dir_all(path)?;
+        fs::write(path.join(".zgroup"), json!({ "zarr_format": 2 }).to_string())?;
+        fs::write(path.join(".zattrs"), attributes::<T>(sizes.len(), options).to_string())?;
+        let dtype = format!("{}u{}", if cfg!(target_endian = "little") { "<" } else { ">" }, T::BITS / 8);
+        for (index, (w, h)) in sizes.iter().enumerate() {
+            let array = json!({
+                "zarr_format": 2,
+                "shape": [3, h, w],
+                "chunks": [3, chunk, chunk],
+                "dtype": dtype,
+                "compressor": compressor,
+                "fill_value": 0,
+                "order": "C",
+                "filters": null,
+                "dimension_separator": "/",
+            });
+            fs::create_dir_all(path.join(index.to_string()))?;
+            fs::write(path.join(index.to_string()).join(".zarray"), array.to_string())?;
+        }
+
+        let levels = sizes
+            .iter()
+            .map(|&(w, h)| Level {
+                height: h,
+                rows: Array3::from_elem((0, w, 3), T::default()),
+                written: 0,
+                pending: Array3::from_elem((0, w, 3), T::default()),
+            })
+            .collect();
+        Ok(ZarrWriter {
+            path: path.to_path_buf(),
+            chunk,
+            compression,
+            levels,
+            kept: 0,
+            sync: false,
+        })
+    }
+
+    /// The writer continuing a store whose first `kept` rows of the full resolution level, whole
+    /// rows of chunks, were written by an earlier run, flushing each chunk to disk so that the rows
+    /// it reports as written survive a crash. The kept rows are pushed again, e.g. by `replay`, to
+    /// downsample them into the other levels, but not written.
+    pub(crate) fn resumed(self, kept: usize) -> Self {
+        ZarrWriter {
+            kept,
+            sync: true,
+            ..self
+        }
+    }
+
+    /// Rows of the full resolution level written to the store, or kept from an earlier run.
+    pub(crate) fn written(&self) -> usize {
+        self.levels[0].written
+    }
+
+    /// Push the first `rows` rows of the full resolution level again, read back from the chunks
+    /// kept from an earlier run.
+    pub(crate) fn replay(&mut self, rows: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let (chunk, width) = (self.chunk, self.levels[0].rows.shape()[1]);
+        if rows > self.kept {
+            return Err(format!("cannot replay {} rows, {} are kept", rows, self.kept).into());
+        }
+        for y0 in (0..rows).step_by(chunk) {
+            let band = read_chunk_row::<T>(&self.path.join("0"), chunk, self.compression, width, y0 / chunk)?;
+            self.push(0, band.slice(s![..chunk.min(rows - y0), .., ..]))?;
+        }
+        Ok(())
+    }
+
+    /// Append the next rows of the full resolution image.
+    pub(crate) fn push_rows(&mut self, rows: ArrayView3<T>) -> Result<(), Box<dyn std::error::Error>> {
+        self.push(0, rows)
+    }
+
+    /// Write the remaining rows of every level.
+    pub(crate) fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for index in 0..self.levels.len() {
+            // An odd last row is downsampled on its own, as at the edge of the whole image
+            let level = &mut self.levels[index];
+            let pending = std::mem::replace(&mut level.pending, Array3::from_elem((0, 0, 3), T::default()));
+            if pending.len_of(Axis(0)) > 0 {
+                self.push(index + 1, downsample(pending.view()).view())?;
+            }
+
+            let level = &mut self.levels[index];
+            if level.rows.len_of(Axis(0)) > 0 {
+                let rows = std::mem::replace(&mut level.rows, Array3::from_elem((0, 0, 3), T::default()));
+                if index > 0 || level.written >= self.kept {
+                    let array = self.path.join(index.to_string());
+                    let cy = level.written / self.chunk;
+                    write_chunk_row(&array, self.chunk, self.compression, rows.view(), cy, self.sync)?;
+                }
+                level.written += rows.len_of(Axis(0));
+            }
+            if level.written != level.height {
+                return Err(format!("level {} has {} rows, expected {}", index, level.written, level.height).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Append rows to a level, writing full rows of chunks and passing pairs of rows on to the
+    /// next level.
+    fn push(&mut self, index: usize, rows: ArrayView3<T>) -> Result<(), Box<dyn std::error::Error>> {
+        let chunk = self.chunk;
+        let has_next = index + 1 < self.levels.len();
+        let level = &mut self.levels[index];
+        level.rows.append(Axis(0), rows)?;
+        while level.rows.len_of(Axis(0)) >= chunk {
+            let rows = level.rows.slice(s![..chunk, .., ..]);
+            if index > 0 || level.written >= self.kept {
+                let array = self.path.join(index.to_string());
+                write_chunk_row(&array, chunk, self.compression, rows, level.written / chunk, self.sync)?;
+            }
+            level.written += chunk;
+            level.rows = level.rows.slice(s![chunk.., .., ..]).to_owned();
+        }
+        if !has_next {
+            return Ok(());
+        }
+
+        level.pending.append(Axis(0), rows)?;
+        let even = level.pending.len_of(Axis(0)) / 2 * 2;
+        if even == 0 {
+            return Ok(());
+        }
+        let reduced = downsample(level.pending.slice(s![..even, .., ..]));
+        level.pending = level.pending.slice(s![even.., .., ..]).to_owned();
+        self.push(index + 1, reduced.view())
+    }
+}
+
+/// Write a band of at most `chunk` rows as the row of chunks `cy`, padding edge chunks with zeros,
+/// compressing and writing the chunks in parallel, and flushing them to disk if `sync`.
+fn write_chunk_row<T: OutputSample>(
+    array: &Path,
+    chunk: usize,
+    compression: TiffCompression,
+    rows: ArrayView3<T>,
+    cy: usize,
+    sync: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let width = rows.shape()[1];
+    trace!("{}: writing chunk row {}", array.display(), cy);
+    let directory = array.join("0").join(cy.to_string());
+    fs::create_dir_all(&directory)?;
+    (0..width.div_ceil(chunk))
+        .into_par_iter()
+        .try_for_each(|cx| -> Result<(), String> {
+            // Chunks hold all three channels, in C order (channel, row, column)
+            let mut data = vec![T::default(); 3 * chunk * chunk];
+            let x0 = cx * chunk;
+            for (y, row) in rows.outer_iter().enumerate() {
+                for (x, pixel) in row.slice(s![x0..(x0 + chunk).min(width), ..]).outer_iter().enumerate() {
+                    for channel in 0..3 {
+                        data[(channel * chunk + y) * chunk + x] = pixel[channel];
+                    }
+                }
+            }
+            let bytes = T::as_ne_bytes(&data);
+            let encoded = match compression {
+                TiffCompression::Deflate => {
+                    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                    encoder.write_all(&bytes).and_then(|_| encoder.finish()).map_err(|e| e.to_string())?
+                }
+                _ => bytes.into_owned(),
+            };
+            let path = directory.join(cx.to_string());
+            let write = || -> std::io::Result<()> {
+                let mut file = File::create(&path)?;
+                file.write_all(&encoded)?;
+                if sync {
+                    file.sync_data()?;
+                }
+                Ok(())
+            };
+            write().map_err(|e| format!("{}: {}", path.display(), e))
+        })?;
+    Ok(())
+}
+
+/// Read the row of chunks `cy` of an array of `width` columns back as a band of `chunk` rows, the
+/// padding of edge chunks included.
+fn read_chunk_row<T: OutputSample>(
+    array: &Path,
+    chunk: usize,
+    compression: TiffCompression,
+    width: usize,
+    cy: usize,
+) -> Result<Array3<T>, Box<dyn std::error::Error>> {
+    let directory = array.join("0").join(cy.to_string());
+    let mut band = Array3::from_elem((chunk, width, 3), T::default());
+    let bytes_per_sample = usize::from(T::BITS / 8);
+    for cx in 0..width.div_ceil(chunk) {
+        let path = directory.join(cx.to_string());
+        let mut bytes = Vec::new();
+        let mut file = File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        match compression {
+            TiffCompression::Deflate => ZlibDecoder::new(file).read_to_end(&mut bytes),
+            _ => file.read_to_end(&mut bytes),
+        }
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let expected = 3 * chunk * chunk * bytes_per_sample;
+        if bytes.len() != expected {
+            return Err(format!("{}: {} bytes, expected {}", path.display(), bytes.len(), expected).into());
+        }
+        let x0 = cx * chunk;
+        let sample = |index: usize| {
+            let bytes = &bytes[index * bytes_per_sample..(index + 1) * bytes_per_sample];
+            T::from_level(match bytes {
+                [byte] => f32::from(*byte),
+                _ => f32::from(u16::from_ne_bytes([bytes[0], bytes[1]])),
+            })
+        };
+        for (y, mut row) in band.outer_iter_mut().enumerate() {
+            for (x, mut pixel) in row.slice_mut(s![x0..(x0 + chunk).min(width), ..]).outer_iter_mut().enumerate() {
+                for channel in 0..3 {
+                    pixel[channel] = sample((channel * chunk + y) * chunk + x);
+                }
+            }
+        }
+    }
+    Ok(band)
+}
+
+/// NGFF multiscales and omero rendering metadata of an RGB image with `levels` levels.
+fn attributes<T: OutputSample>(levels: usize, options: &ZarrOptions) -> serde_json::Value {
+    let unit = options.pixel_size_um.map(|_| "micrometer");
+    let [size_x, size_y] = options.pixel_size_um.unwrap_or([1.0, 1.0]).map(f64::from);
+    let space = |name| match unit {
+        Some(unit) => json!({ "name": name, "type": "space", "unit": unit }),
+        None => json!({ "name": name, "type": "space" }),
+    };
+    let datasets: Vec<_> = (0..levels)
+        .map(|level| {
+            let factor = f64::from(1u32 << level);
+            json!({
+                "path": level.to_string(),
+                "coordinateTransformations": [{ "type": "scale", "scale": [1.0, size_y * factor, size_x * factor] }],
+            })
+        })
+        .collect();
+    let max = (1u32 << T::BITS) - 1;
+    let channels: Vec<_> = [("red", "FF0000"), ("green", "00FF00"), ("blue", "0000FF")]
+        .iter()
+        .map(|(label, color)| {
+            json!({
+                "label": label,
+                "color": color,
+                "active": true,
+                "window": { "min": 0, "max": max, "start": 0, "end": max },
+            })
+        })
+        .collect();
+    json!({
+        "multiscales": [{
+            "version": "0.4",
+            "name": "Virtual H&E",
+            "axes": [{ "name": "c", "type": "channel" }, space("y"), space("x")],
+            "datasets": datasets,
+            "type": "mean",
+            "metadata": { "method": "2x2 box filter", "creator": format!("virtualhe {}", env!("CARGO_PKG_VERSION")) },
+        }],
+        "omero": {
+            "name": "Virtual H&E",
+            "version": "0.4",
+            "channels": channels,
+            "rdefs": { "model": "color" },
+        },
+    })
+}
@@ -0,0 +1,67 @@
+/*
+ * Test of the C interface of the capi feature, linked against the shared library:
+ *
+ *   cargo build --release --features capi
+ *   cc -Wall -Wextra -Werror -Iinclude tests/capi/render.c -Ltarget/release -lvirtualhe \
+ *       -Wl,-rpath,target/release -o target/capi_render
+ *   target/capi_render
+ *
+ * Exits with 0 if all checks pass and prints the failed checks otherwise.
+ */
+#include "virtualhe.h"
+
+#include <stdio.h>
+
+#define WIDTH 64
+#define HEIGHT 32
+
+static int failures = 0;
+
+static void check(int passed, const char *what) {
+    if (!passed) {
+        const char *message = vhe_last_error_message();
+        fprintf(stderr, "FAILED: %s (last error: %s)\n", what, message ? message : "none");
+        failures++;
+    }
+}
+
+int main(void) {
+    static float nucleus[WIDTH * HEIGHT], eosin[WIDTH * HEIGHT];
+    static uint8_t rgb[WIDTH * HEIGHT * 3];
+    for (size_t y = 0; y < HEIGHT; y++) {
+        for (size_t x = 0; x < WIDTH; x++) {
+            nucleus[y * WIDTH + x] = (float)x;
+            eosin[y * WIDTH + x] = (float)y;
+        }
+    }
+
+    check(vhe_last_error_message() == NULL, "no error message before a failed call");
+    VheParams params = vhe_default_params();
+    check(params.k == 2.5f && params.percentiles[0] == 99.999f, "default parameters");
+
+    check(vhe_render(nucleus, eosin, WIDTH, HEIGHT, &params, rgb) == VHE_STATUS_OK, "render");
+    // Without nucleus nor eosin the first pixel is white, with both the last is darker in all channels
+    const uint8_t *first = rgb, *last = rgb + (WIDTH * HEIGHT - 1) * 3;
+    check(first[0] == 255 && first[1] == 255 && first[2] == 255, "white background");
+    check(last[0] < 255 && last[1] < 255 && last[2] < 255, "stained pixel");
+    // Hematoxylin absorbs more red than eosin: the last pixel of the first row is darker in red than
+    // the first of the last row
+    check(rgb[(WIDTH - 1) * 3] < rgb[(HEIGHT - 1) * WIDTH * 3], "hematoxylin darker in red than eosin");
+
+    check(vhe_render(nucleus, eosin, WIDTH, HEIGHT, NULL, rgb) == VHE_STATUS_OK, "render with default parameters");
+
+    check(vhe_render(NULL, eosin, WIDTH, HEIGHT, &params, rgb) == VHE_STATUS_NULL_POINTER, "null nucleus");
+    check(vhe_last_error_message() != NULL, "error message of a null pointer");
+
+    VheParams invalid = params;
+    invalid.k = -1.0f;
+    check(vhe_render(nucleus, eosin, WIDTH, HEIGHT, &invalid, rgb) == VHE_STATUS_INVALID_PARAMS, "negative k");
+    invalid = params;
+    invalid.percentiles[1] = 0.0f;
+    check(vhe_render(nucleus, eosin, WIDTH, HEIGHT, &invalid, rgb) == VHE_STATUS_INVALID_PARAMS, "zero percentile");
+
+    if (failures == 0) {
+        printf("All checks passed\n");
+    }
+    return failures == 0 ? 0 : 1;
+}
@@ -0,0 +1,44 @@
+#!/bin/sh
+# End-to-end test of the command line tool on data of generate-test-data: renders synthetic pairs
+# with several options and compares the SHA-256 of the inputs and outputs with those recorded in
+# reference.sha256, so that any change of the generated data or of the rendered pixels and their
+# encoding is noticed. Run from the repository after building the tool:
+#
+#   cargo build --release
+#   tests/cli/render.sh [path/to/virtualhe]
+#
+# Exits with 0 if all files match, and otherwise lists the files that differ and prints the hashes
+# of the build, which replace those of reference.sha256 after an intended change.
+set -eu
+
+virtualhe=$(realpath "${1:-target/release/virtualhe}")
+reference=$(realpath "$(dirname "$0")/reference.sha256")
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+cd "$work"
+# TIFF outputs are dated by SOURCE_DATE_EPOCH instead of the time of the render
+export SOURCE_DATE_EPOCH=0
+
+# Inputs: 16, 12 and 8bit pairs with their own seeds
+"$virtualhe" generate-test-data nucleus16.tif eosin16.tif --size 256x192 --seed 1 > /dev/null
+"$virtualhe" generate-test-data nucleus12.tif eosin12.tif --size 200x120 --bits 12 --seed 2 > /dev/null
+"$virtualhe" generate-test-data nucleus8.tif eosin8.tif --size 128 --bits 8 --seed 3 > /dev/null
+
+render() {
+    "$virtualhe" --quiet --no-provenance "$@" > /dev/null
+}
+render nucleus16.tif eosin16.tif default.png
+render nucleus16.tif eosin16.tif depth16.tif --output-depth 16
+render nucleus12.tif eosin12.tif bits12.tif --input-bits 12 --percentile 99.5
+render nucleus8.tif eosin8.tif k3.png -k 3.0
+
+sha256sum nucleus16.tif eosin16.tif nucleus12.tif eosin12.tif nucleus8.tif eosin8.tif \
+    default.png depth16.tif bits12.tif k3.png > actual.sha256
+if ! differing=$(sha256sum --check --quiet "$reference" 2>&1); then
+    echo "Files that differ from the reference:"
+    echo "$differing"
+    echo "Hashes of this build:"
+    cat actual.sha256
+    exit 1
+fi
+echo "All files match the reference"
@@ -0,0 +1,62 @@
+//! Harness of the integration tests that write gradients of several sizes in a tiled format and
+//! check the outputs. Each test gives its case and how to write and check an output, and the
+//! harness runs it with the sample type of its depth.
+use ndarray::Array3;
+use std::path::{Path, PathBuf};
+use virtualhe::OutputSample;
+
+/// Size of an image, the tiles and samples it is written with, and the settings of its format.
+pub struct Case<F> {
+    pub width: usize,
+    pub height: usize,
+    pub tile_size: u32,
+    pub sixteen_bit: bool,
+    pub settings: F,
+}
+
+/// Writing and checking the outputs of a format.
+pub trait Format: Sized {
+    /// Directory the outputs are written into, below the temporary directory of the tests.
+    const OUTPUT_DIR: &'static str;
+    /// Extension of the outputs.
+    const EXTENSION: &'static str;
+
+    /// Whether the image has an alpha channel.
+    fn rgba(&self) -> bool {
+        false
+    }
+
+    /// End of the name of the output, after its size, tiles and depth.
+    fn suffix(&self) -> String;
+
+    /// Write `rgb`, the image of `case`, to `path` and check the output, returning the problems.
+    fn write_and_check<T: OutputSample>(case: &Case<Self>, rgb: &Array3<T>, path: &Path) -> Vec<String>;
+}
+
+/// A gradient, different in every channel, and half transparent in places with alpha.
+fn image<T: OutputSample, F: Format>(case: &Case<F>) -> Array3<T> {
+    let samples = if case.settings.rgba() { 4 } else { 3 };
+    Array3::from_shape_fn((case.height, case.width, samples), |(y, x, c)| {
+        let level = if c == 3 { (x * 11 + y * 5) % 256 } else { (x * 7 + y * 3 + c * 50) % 256 };
+        T::from_level(level as f32 * (T::MAX / 255.0))
+    })
+}
+
+/// Write the image of a case and check its output, returning its name and the problems.
+fn run<T: OutputSample, F: Format>(case: &Case<F>) -> (String, Vec<String>) {
+    let name = format!("{}x{}-{}-{}bit{}", case.width, case.height, case.tile_size, T::BITS, case.settings.suffix());
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(F::OUTPUT_DIR);
+    if let Err(e) = std::fs::create_dir_all(&directory) {
+        return (name, vec![format!("{}: {}", directory.display(), e)]);
+    }
+    let path = directory.join(format!("{}.{}", name, F::EXTENSION));
+    let problems = F::write_and_check(case, &image::<T, F>(case), &path);
+    (name, problems)
+}
+
+/// Write the image of `case` with the sample type of its depth and check its output, failing with
+/// the problems.
+pub fn check<F: Format>(case: Case<F>) {
+    let (name, problems) = if case.sixteen_bit { run::<u16, F>(&case) } else { run::<u8, F>(&case) };
+    assert!(problems.is_empty(), "{}:\n{}", name, problems.join("\n"));
+}
@@ -0,0 +1,299 @@
+#![cfg(feature = "czi")]
+//! Test of the CZI reader: small CZI files are written with the segments of the format, subblocks
+//! of two channels in a mosaic of tiles, with a pyramid level and a second z plane to skip, several
+//! scenes, and uncompressed, Zstd and Zstd with the high and low bytes of 16bit pixels stored apart,
+//! and every channel read by index and by name must hold the pixels written. Run with
+//!
+//!   cargo test --features czi --test czi
+//!
+//! Files with several scenes must fail without a scene, listing them, and subblocks compressed
+//! with JPEG XR must fail with an error that names the compression.
+use ndarray::Array2;
+use std::path::{Path, PathBuf};
+use virtualhe::{ChannelSelector, InputRange, LoadOptions};
+
+/// Size of the image, of two tiles side by side that overlap by 4 columns.
+const WIDTH: usize = 36;
+const HEIGHT: usize = 12;
+const TILE_WIDTH: usize = 20;
+
+/// Channel names of the XML metadata.
+const NAMES: [&str; 2] = ["DAPI", "AF488"];
+
+/// Pixel type and compression of the subblocks of a file.
+#[derive(Clone, Copy)]
+struct Encoding {
+    pixel_type: i32,
+    compression: i32,
+}
+
+/// A subblock to write: its position along the dimensions and its pixels.
+struct Subblock {
+    /// Name, start and size of each dimension, X and Y with their stored size.
+    dimensions: Vec<(char, i32, i32, i32)>,
+    data: Vec<u8>,
+}
+
+/// Value of pixel (y, x) of a channel of a scene, within 8 bits and never 7 or 0.
+fn value(scene: usize, channel: usize, y: usize, x: usize) -> u16 {
+    ((x * 5 + y * 11 + channel * 60 + scene * 30) % 240 + 10) as u16
+}
+
+/// The image of a channel of a scene, normalized by 255.
+fn expected(scene: usize, channel: usize) -> Array2<f32> {
+    Array2::from_shape_fn((HEIGHT, WIDTH), |(y, x)| f32::from(value(scene, channel, y, x)) / 255.0)
+}
+
+/// Uncompressed samples of the columns `x0..x0 + width` of a channel, those from `covered` on
+/// replaced by a value that must not show as a later tile is drawn over them.
+fn samples(scene: usize, channel: usize, x0: usize, width: usize, pixel_type: i32, covered: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for y in 0..HEIGHT {
+        for x in x0..x0 + width {
+            let v = if x < covered { value(scene, channel, y, x) } else { 7 };
+            match pixel_type {
+                0 => bytes.push(v as u8),
+                _ => bytes.extend(v.to_le_bytes()),
+            }
+        }
+    }
+    bytes
+}
+
+/// Compress uncompressed samples as `encoding` stores them.
+fn compress(bytes: Vec<u8>, encoding: Encoding) -> Vec<u8> {
+    let zstd = |bytes: &[u8]| ruzstd::encoding::compress_to_vec(bytes, ruzstd::encoding::CompressionLevel::Fastest);
+    match encoding.compression {
+        5 => zstd(&bytes),
+        6 => {
+            // The low bytes of all pixels, then the high bytes, after a header of 3 bytes with the
+            // chunk of type 1 flagging it
+            let (low, high): (Vec<u8>, Vec<u8>) = bytes.chunks_exact(2).map(|pair| (pair[0], pair[1])).unzip();
+            let mut data = vec![3, 1, 1];
+            data.extend(zstd(&[low, high].concat()));
+            data
+        }
+        _ => bytes,
+    }
+}
+
+/// Subblocks of a mosaic of two tiles per channel for each scene, starting at X 100 and Y 50, with
+/// the second tile listed first but drawn over the first where they overlap, and a pyramid level
+/// and a second z plane of other values.
+fn subblocks(scenes: usize, encoding: Encoding) -> Vec<Subblock> {
+    let bytes = if encoding.pixel_type == 0 { 1 } else { 2 };
+    let x1 = WIDTH - TILE_WIDTH;
+    let mut subblocks = Vec::new();
+    for scene in 0..scenes {
+        let s = (scenes > 1).then_some(scene);
+        for channel in 0..2 {
+            let dimensions = |x: usize, width: usize, stored: usize, z: i32, m: i32| {
+                let mut dimensions = vec![
+                    ('X', 100 + x as i32, width as i32, stored as i32),
+                    ('Y', 50, HEIGHT as i32, (HEIGHT * stored / width) as i32),
+                    ('C', channel as i32, 1, 1),
+                    ('Z', z, 1, 1),
+                    ('M', m, 1, 1),
+                ];
+                dimensions.extend(s.map(|s| ('S', s as i32, 1, 1)));
+                dimensions
+            };
+            let tile = |x0, covered| {
+                compress(samples(scene, channel, x0, TILE_WIDTH, encoding.pixel_type, covered), encoding)
+            };
+            subblocks.push(Subblock {
+                dimensions: dimensions(x1, TILE_WIDTH, TILE_WIDTH, 0, 1),
+                data: tile(x1, WIDTH),
+            });
+            subblocks.push(Subblock {
+                dimensions: dimensions(0, TILE_WIDTH, TILE_WIDTH, 0, 0),
+                data: tile(0, x1),
+            });
+            subblocks.push(Subblock {
+                dimensions: dimensions(0, TILE_WIDTH, TILE_WIDTH, 1, 0),
+                data: tile(0, 0),
+            });
+            subblocks.push(Subblock {
+                dimensions: dimensions(0, WIDTH, WIDTH / 2, 0, 0),
+                data: compress(vec![0; WIDTH / 2 * HEIGHT / 2 * bytes], encoding),
+            });
+        }
+    }
+    subblocks
+}
+
+/// A segment with its 32 byte header.
+fn segment(id: &str, data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0u8; 16];
+    bytes[..id.len()].copy_from_slice(id.as_bytes());
+    bytes.extend((data.len() as i64).to_le_bytes());
+    bytes.extend((data.len() as i64).to_le_bytes());
+    bytes.extend(data);
+    bytes
+}
+
+/// A directory entry of a subblock at `position`.
+fn entry(subblock: &Subblock, encoding: Encoding, position: u64) -> Vec<u8> {
+    let mut bytes = b"DV".to_vec();
+    bytes.extend(encoding.pixel_type.to_le_bytes());
+    bytes.extend(position.to_le_bytes());
+    bytes.extend(0i32.to_le_bytes());
+    bytes.extend(encoding.compression.to_le_bytes());
+    bytes.extend([0u8; 6]);
+    bytes.extend((subblock.dimensions.len() as i32).to_le_bytes());
+    for &(name, start, size, stored) in &subblock.dimensions {
+        bytes.extend([name as u8, 0, 0, 0]);
+        bytes.extend(start.to_le_bytes());
+        bytes.extend(size.to_le_bytes());
+        bytes.extend((start as f32).to_le_bytes());
+        bytes.extend(stored.to_le_bytes());
+    }
+    bytes
+}
+
+/// Write a CZI file of subblocks and XML metadata naming the channels.
+fn write_czi(path: &Path, subblocks: &[Subblock], encoding: Encoding) -> std::io::Result<()> {
+    let file_header = 32 + 80;
+    let channel = |(i, name)| format!("<Channel Id=\"Channel:{}\" Name=\"{}\"/>", i, name);
+    let channels: String = NAMES.iter().enumerate().map(channel).collect();
+    let xml = format!(
+        "<ImageDocument><Metadata><Information><Image><Dimensions><Channels>{}</Channels></Dimensions></Image>\
+         </Information></Metadata></ImageDocument>",
+        channels
+    );
+    let mut metadata = vec![0u8; 256];
+    metadata[..4].copy_from_slice(&(xml.len() as i32).to_le_bytes());
+    metadata.extend(xml.as_bytes());
+    let metadata = segment("ZISRAWMETADATA", &metadata);
+
+    let mut body = Vec::new();
+    let mut entries = Vec::new();
+    for subblock in subblocks {
+        let position = (file_header + metadata.len() + body.len()) as u64;
+        let entry = entry(subblock, encoding, position);
+        let mut data = Vec::new();
+        data.extend(0i32.to_le_bytes());
+        data.extend(0i32.to_le_bytes());
+        data.extend((subblock.data.len() as i64).to_le_bytes());
+        data.extend(&entry);
+        data.resize(256.max(16 + entry.len()), 0);
+        data.extend(&subblock.data);
+        body.extend(segment("ZISRAWSUBBLOCK", &data));
+        entries.extend(entry);
+    }
+    let mut directory = (subblocks.len() as i32).to_le_bytes().to_vec();
+    directory.extend([0u8; 124]);
+    directory.extend(entries);
+    let directory_position = (file_header + metadata.len() + body.len()) as u64;
+
+    let mut header = vec![0u8; 80];
+    header[..4].copy_from_slice(&1i32.to_le_bytes());
+    header[52..60].copy_from_slice(&directory_position.to_le_bytes());
+    header[60..68].copy_from_slice(&(file_header as u64).to_le_bytes());
+    let mut bytes = segment("ZISRAWFILE", &header);
+    bytes.extend(metadata);
+    bytes.extend(body);
+    bytes.extend(segment("ZISRAWDIRECTORY", &directory));
+    std::fs::write(path, bytes)
+}
+
+/// Whether an image read holds the pixels of the image written.
+fn matches(image: &Array2<f32>, expected: &Array2<f32>) -> bool {
+    image.dim() == expected.dim() && image.iter().zip(expected).all(|(a, b)| (a - b).abs() < 1e-6)
+}
+
+/// Options reading a channel of a scene normalized by 255, for 8 and 16bit pixels alike.
+fn options(channel: ChannelSelector, scene: Option<usize>) -> LoadOptions {
+    LoadOptions {
+        channel: Some(channel),
+        scene,
+        range: InputRange::Max(255.0),
+        ..LoadOptions::default()
+    }
+}
+
+/// Encoding of pixels of `pixel_type` compressed with `compression`.
+fn encoding(pixel_type: i32, compression: i32) -> Encoding {
+    Encoding {
+        pixel_type,
+        compression,
+    }
+}
+
+/// Write a file named `name` of `scenes` scenes, returning its path.
+fn written(name: &str, scenes: usize, encoding: Encoding) -> PathBuf {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("czi");
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join(format!("{}.czi", name));
+    write_czi(&path, &subblocks(scenes, encoding), encoding).unwrap();
+    path
+}
+
+/// Read every channel of every scene of a file by index and by name and compare it with the
+/// image written.
+fn check_channels(name: &str, scenes: usize, encoding: Encoding) {
+    let path = written(name, scenes, encoding);
+    let mut failures = Vec::new();
+    for scene in 0..scenes {
+        let s = (scenes > 1).then_some(scene);
+        for (channel, channel_name) in NAMES.iter().enumerate() {
+            for selector in [ChannelSelector::Index(channel), ChannelSelector::Name(channel_name.to_string())] {
+                let options = options(selector.clone(), s);
+                let result = match virtualhe::load_channel_with(&path, &options) {
+                    Ok((image, _)) if matches(&image, &expected(scene, channel)) => Ok(()),
+                    Ok((image, _)) => Err(format!("{}x{} pixels differ", image.ncols(), image.nrows())),
+                    Err(e) => Err(e.to_string()),
+                };
+                let dimensions = virtualhe::input_dimensions(&path, &options);
+                if let Err(e) = result.and_then(|()| match dimensions {
+                    Some((WIDTH, HEIGHT)) => Ok(()),
+                    other => Err(format!("dimensions {:?} from the directory", other)),
+                }) {
+                    failures.push(format!("scene {}, channel {}: {}", scene, selector, e));
+                }
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// Read a file that must fail with an error containing `expected`.
+fn check_error(name: &str, scenes: usize, encoding: Encoding, scene: Option<usize>, expected: &str) {
+    let path = written(name, scenes, encoding);
+    match virtualhe::load_channel_with(&path, &options(ChannelSelector::Index(0), scene)) {
+        Ok(_) => panic!("{} read without an error", path.display()),
+        Err(e) => assert!(e.to_string().contains(expected), "{} instead of {}", e, expected),
+    }
+}
+
+#[test]
+fn uncompressed_16bit_channels_read_as_written() {
+    check_channels("gray16", 1, encoding(1, 0));
+}
+
+#[test]
+fn zstd_8bit_channels_read_as_written() {
+    check_channels("gray8-zstd", 1, encoding(0, 5));
+}
+
+#[test]
+fn zstd_channels_with_the_bytes_apart_read_as_written() {
+    check_channels("gray16-zstd-hilo", 1, encoding(1, 6));
+}
+
+#[test]
+fn channels_of_every_scene_read_as_written() {
+    check_channels("scenes", 3, encoding(1, 0));
+}
+
+#[test]
+fn files_of_several_scenes_need_one() {
+    let unselected = "holds 3 scenes (0, 1, 2), select one with --scene";
+    check_error("scenes-unselected", 3, encoding(1, 0), None, unselected);
+    check_error("scene-missing", 3, encoding(1, 0), Some(5), "scene 5 is not in the file");
+}
+
+#[test]
+fn jpeg_xr_subblocks_are_not_supported() {
+    check_error("jpeg-xr", 1, encoding(1, 4), None, "unsupported compression JPEG XR");
+}
@@ -0,0 +1,388 @@
+//! Round trip test of DICOM outputs: images of several sizes, some not a multiple of the tiles,
+//! 8bit, 16bit and RGBA, are written natively and encapsulated, parsed again as Explicit VR Little
+//! Endian data sets, and must be VL Whole Slide Microscopy Images in the TILED_FULL organization
+//! with the tile grid, pixel spacing and identifying attributes they were written with, their
+//! elements in ascending order. Run with
+//!
+//!   cargo test --test dicom
+//!
+//! The frames, reassembled row by row of tiles, must hold the pixels of the image, 16bit images
+//! rounded to 8 bits and RGBA images composited over white, and white beyond its edges.
+use common::{Case, Format};
+use ndarray::Array3;
+use std::path::Path;
+use virtualhe::dicom::DicomOptions;
+use virtualhe::OutputSample;
+
+mod common;
+
+/// How the image of a case is written.
+struct Dicom {
+    rgba: bool,
+    encapsulated: bool,
+    /// Identifying attributes given instead of the placeholders.
+    identified: bool,
+}
+
+/// Case of an image of `width` x `height` pixels written in frames of `tile_size`.
+fn case(width: usize, height: usize, tile_size: u32, sixteen_bit: bool, settings: Dicom) -> Case<Dicom> {
+    Case {
+        width,
+        height,
+        tile_size,
+        sixteen_bit,
+        settings,
+    }
+}
+
+/// Native 8bit RGB samples with the placeholders of the identifying attributes.
+const NATIVE: Dicom = Dicom {
+    rgba: false,
+    encapsulated: false,
+    identified: false,
+};
+
+/// A parsed data element.
+struct Element {
+    tag: (u16, u16),
+    vr: [u8; 2],
+    value: Value,
+}
+
+enum Value {
+    Bytes(Vec<u8>),
+    /// Items of a sequence.
+    Items(Vec<Vec<Element>>),
+    /// Fragments of encapsulated pixel data, after the offset table.
+    Fragments(Vec<Vec<u8>>),
+}
+
+/// Reading the elements of a data set one after the other.
+struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], String> {
+        let bytes = self.data.get(self.at..self.at + count).ok_or(format!("truncated at {}", self.at))?;
+        self.at += count;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Parse elements up to the end of the data, checking that their tags ascend.
+    fn elements(&mut self) -> Result<Vec<Element>, String> {
+        let mut elements: Vec<Element> = Vec::new();
+        while self.at < self.data.len() {
+            let tag = (self.u16()?, self.u16()?);
+            let vr: [u8; 2] = self.take(2)?.try_into().unwrap();
+            let length = if matches!(&vr, b"OB" | b"OW" | b"OF" | b"SQ" | b"UT" | b"UN") {
+                self.take(2)?;
+                self.u32()?
+            } else {
+                u32::from(self.u16()?)
+            };
+            if let Some(previous) = elements.last().filter(|previous| previous.tag >= tag) {
+                let previous = format!("{:04X},{:04X}", previous.tag.0, previous.tag.1);
+                return Err(format!("element {:04X},{:04X} after {}", tag.0, tag.1, previous));
+            }
+            let value = if length == u32::MAX {
+                Value::Fragments(self.fragments()?)
+            } else if length % 2 == 1 {
+                return Err(format!("element {:04X},{:04X} has an odd length {}", tag.0, tag.1, length));
+            } else if &vr == b"SQ" {
+                let mut items = Vec::new();
+                let mut reader = Reader {
+                    data: self.take(length as usize)?,
+                    at: 0,
+                };
+                while reader.at < reader.data.len() {
+                    if (reader.u16()?, reader.u16()?) != (0xFFFE, 0xE000) {
+                        return Err(format!("expected an item in {:04X},{:04X}", tag.0, tag.1));
+                    }
+                    let length = reader.u32()? as usize;
+                    items.push(Reader { data: reader.take(length)?, at: 0 }.elements()?);
+                }
+                Value::Items(items)
+            } else {
+                Value::Bytes(self.take(length as usize)?.to_vec())
+            };
+            elements.push(Element { tag, vr, value });
+        }
+        Ok(elements)
+    }
+
+    /// Parse the items of encapsulated pixel data up to the sequence delimiter, without the offset
+    /// table.
+    fn fragments(&mut self) -> Result<Vec<Vec<u8>>, String> {
+        let mut fragments = Vec::new();
+        loop {
+            let tag = (self.u16()?, self.u16()?);
+            let length = self.u32()? as usize;
+            match tag {
+                (0xFFFE, 0xE000) => fragments.push(self.take(length)?.to_vec()),
+                (0xFFFE, 0xE0DD) => break,
+                _ => return Err(format!("unexpected {:04X},{:04X} in encapsulated pixel data", tag.0, tag.1)),
+            }
+        }
+        if fragments.is_empty() {
+            return Err("encapsulated pixel data without an offset table".to_string());
+        }
+        fragments.remove(0);
+        Ok(fragments)
+    }
+}
+
+/// The element of a tag in a data set.
+fn find(elements: &[Element], tag: (u16, u16)) -> Result<&Element, String> {
+    elements.iter().find(|element| element.tag == tag).ok_or(format!("no element {:04X},{:04X}", tag.0, tag.1))
+}
+
+/// The value of a string element without its padding.
+fn string(elements: &[Element], tag: (u16, u16)) -> Result<String, String> {
+    match &find(elements, tag)?.value {
+        Value::Bytes(bytes) => Ok(String::from_utf8_lossy(bytes).trim_end_matches(['\0', ' ']).to_string()),
+        _ => Err(format!("element {:04X},{:04X} is not a string", tag.0, tag.1)),
+    }
+}
+
+/// The value of a US or UL element.
+fn unsigned(elements: &[Element], tag: (u16, u16)) -> Result<u32, String> {
+    let element = find(elements, tag)?;
+    match (&element.value, &element.vr) {
+        (Value::Bytes(bytes), b"US") if bytes.len() == 2 => Ok(u32::from(u16::from_le_bytes([bytes[0], bytes[1]]))),
+        (Value::Bytes(bytes), b"UL") if bytes.len() == 4 => Ok(u32::from_le_bytes(bytes[..4].try_into().unwrap())),
+        _ => Err(format!("element {:04X},{:04X} is not an unsigned number", tag.0, tag.1)),
+    }
+}
+
+/// The items of a sequence element.
+fn items(elements: &[Element], tag: (u16, u16)) -> Result<&[Vec<Element>], String> {
+    match &find(elements, tag)?.value {
+        Value::Items(items) => Ok(items),
+        _ => Err(format!("element {:04X},{:04X} is not a sequence", tag.0, tag.1)),
+    }
+}
+
+/// The 8bit RGB pixel the frames must hold for a pixel of the image.
+fn expected<T: OutputSample>(pixel: &[T]) -> [u8; 3] {
+    let max = f64::from(T::MAX);
+    let alpha = pixel.get(3).map_or(1.0, |&alpha| f64::from(alpha.into()) / max);
+    std::array::from_fn(|c| {
+        let value = f64::from(pixel[c].into()) * alpha + max * (1.0 - alpha);
+        (value * 255.0 / max).round() as u8
+    })
+}
+
+fn is_uid(uid: &str) -> bool {
+    uid.len() <= 64 && uid.split('.').all(|c| !c.is_empty() && c.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Check the DICOM image of `case` written to `path`, returning the problems.
+fn check<T: OutputSample>(
+    case: &Case<Dicom>,
+    rgb: &Array3<T>,
+    options: &DicomOptions,
+    path: &Path,
+) -> Result<Vec<String>, String> {
+    let mut problems = Vec::new();
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    if data.get(128..132) != Some(b"DICM".as_slice()) {
+        return Err("no DICM prefix after the preamble".to_string());
+    }
+    let elements = Reader { data: &data[132..], at: 0 }.elements()?;
+    let (meta, dataset): (Vec<_>, Vec<_>) = elements.into_iter().partition(|element| element.tag.0 == 0x0002);
+    let group_length = meta.iter().skip(1).map(|e| match &e.value {
+        Value::Bytes(bytes) => 8 + if matches!(&e.vr, b"OB") { 4 } else { 0 } + bytes.len() as u32,
+        _ => 0,
+    });
+    if unsigned(&meta, (0x0002, 0x0000))? != group_length.sum::<u32>() {
+        problems.push("the group length of the file meta information is wrong".to_string());
+    }
+
+    let tile = case.tile_size as usize;
+    let (columns, rows) = (case.width.div_ceil(tile), case.height.div_ceil(tile));
+    let transfer_syntax = match case.settings.encapsulated {
+        true => virtualhe::dicom::ENCAPSULATED_UNCOMPRESSED,
+        false => virtualhe::dicom::EXPLICIT_VR_LITTLE_ENDIAN,
+    };
+    let mut expect = |what: &str, value: String, expected: String| {
+        if value != expected {
+            problems.push(format!("{} is {}, expected {}", what, value, expected));
+        }
+    };
+    expect("the transfer syntax", string(&meta, (0x0002, 0x0010))?, transfer_syntax.to_string());
+    expect("the SOP class", string(&meta, (0x0002, 0x0002))?, virtualhe::dicom::WSI_SOP_CLASS_UID.to_string());
+    expect("the SOP class", string(&dataset, (0x0008, 0x0016))?, virtualhe::dicom::WSI_SOP_CLASS_UID.to_string());
+    expect("the SOP instance", string(&dataset, (0x0008, 0x0018))?, string(&meta, (0x0002, 0x0003))?);
+    expect("the modality", string(&dataset, (0x0008, 0x0060))?, "SM".to_string());
+    expect("the photometric interpretation", string(&dataset, (0x0028, 0x0004))?, "RGB".to_string());
+    expect("the organization", string(&dataset, (0x0020, 0x9311))?, "TILED_FULL".to_string());
+    expect("the samples per pixel", unsigned(&dataset, (0x0028, 0x0002))?.to_string(), "3".to_string());
+    expect("the bits allocated", unsigned(&dataset, (0x0028, 0x0100))?.to_string(), "8".to_string());
+    expect("the frame rows", unsigned(&dataset, (0x0028, 0x0010))?.to_string(), tile.to_string());
+    expect("the frame columns", unsigned(&dataset, (0x0028, 0x0011))?.to_string(), tile.to_string());
+    expect("the frames", string(&dataset, (0x0028, 0x0008))?, (columns * rows).to_string());
+    expect("the total columns", unsigned(&dataset, (0x0048, 0x0006))?.to_string(), case.width.to_string());
+    expect("the total rows", unsigned(&dataset, (0x0048, 0x0007))?.to_string(), case.height.to_string());
+    let identity = |value: &Option<String>, placeholder: &str| value.clone().unwrap_or(placeholder.to_string());
+    let anonymous = virtualhe::dicom::ANONYMOUS;
+    expect("the patient name", string(&dataset, (0x0010, 0x0010))?, identity(&options.patient_name, anonymous));
+    expect("the patient ID", string(&dataset, (0x0010, 0x0020))?, identity(&options.patient_id, anonymous));
+    expect("the accession number", string(&dataset, (0x0008, 0x0050))?, identity(&options.accession_number, ""));
+    let container = identity(&options.container_id, virtualhe::dicom::UNKNOWN_CONTAINER);
+    expect("the container", string(&dataset, (0x0040, 0x0512))?, container.clone());
+    let specimen = items(&dataset, (0x0040, 0x0560))?;
+    expect("the specimen", string(&specimen[0], (0x0040, 0x0551))?, container);
+    let study = string(&dataset, (0x0020, 0x000D))?;
+    if let Some(study_uid) = &options.study_uid {
+        expect("the study", study.clone(), study_uid.clone());
+    }
+    let series = string(&dataset, (0x0020, 0x000E))?;
+    for (name, uid) in [("study", study), ("series", series), ("SOP instance", string(&meta, (0x0002, 0x0003))?)] {
+        if !is_uid(&uid) {
+            problems.push(format!("the {} UID {} is not a UID", name, uid));
+        }
+    }
+
+    // Pixel spacing is in millimeters between rows, then between columns
+    let shared = items(&dataset, (0x5200, 0x9229))?;
+    let measures = items(&shared[0], (0x0028, 0x9110))?;
+    let spacing = string(&measures[0], (0x0028, 0x0030))?;
+    let spacing: Vec<f32> = spacing.split('\\').filter_map(|value| value.parse().ok()).collect();
+    let [x, y] = options.pixel_size_um.map(|size| size / 1000.0);
+    if spacing.len() != 2 || (spacing[0] - y).abs() > 1e-6 * y || (spacing[1] - x).abs() > 1e-6 * x {
+        problems.push(format!("the pixel spacing is {:?}, expected {} and {}", spacing, y, x));
+    }
+    let optical_path = items(&dataset, (0x0048, 0x0105))?;
+    // The size of the profile and its signature are in its header
+    let is_profile = |icc: &[u8]| icc.len() >= 132 && icc[..4] == (icc.len() as u32).to_be_bytes() && &icc[36..40] == b"acsp";
+    if !matches!(&find(&optical_path[0], (0x0028, 0x2000))?.value, Value::Bytes(icc) if is_profile(icc)) {
+        problems.push("the ICC profile is not one".to_string());
+    }
+
+    // Frames row by row of tiles, each row of a frame the tile size, white beyond the image
+    let frame_size = tile * tile * 3;
+    let frames: Vec<Vec<u8>> = match &find(&dataset, (0x7FE0, 0x0010))?.value {
+        // Fragments are padded to an even length
+        Value::Fragments(fragments) => {
+            fragments.iter().map(|fragment| fragment[..frame_size.min(fragment.len())].to_vec()).collect()
+        }
+        Value::Bytes(bytes) => bytes.chunks_exact(frame_size).map(<[u8]>::to_vec).collect(),
+        Value::Items(_) => return Err("the pixel data is a sequence".to_string()),
+    };
+    if frames.len() != columns * rows || frames.iter().any(|frame| frame.len() != frame_size) {
+        problems.push(format!("{} frames of {} pixels, expected {} x {}", frames.len(), tile, columns, rows));
+        return Ok(problems);
+    }
+    let mut mismatches = 0;
+    for (index, frame) in frames.iter().enumerate() {
+        let (x0, y0) = (index % columns * tile, index / columns * tile);
+        for (offset, pixel) in frame.chunks_exact(3).enumerate() {
+            let (x, y) = (x0 + offset % tile, y0 + offset / tile);
+            let expected = if x < case.width && y < case.height {
+                expected(rgb.slice(ndarray::s![y, x, ..]).as_slice().unwrap())
+            } else {
+                [255; 3]
+            };
+            mismatches += usize::from(pixel != expected);
+        }
+    }
+    if mismatches > 0 {
+        problems.push(format!("{} pixels of the frames differ from the image", mismatches));
+    }
+    Ok(problems)
+}
+
+impl Format for Dicom {
+    const OUTPUT_DIR: &'static str = "dicom";
+    const EXTENSION: &'static str = "dcm";
+
+    fn rgba(&self) -> bool {
+        self.rgba
+    }
+
+    fn suffix(&self) -> String {
+        format!("{}{}", if self.rgba { "-rgba" } else { "" }, if self.encapsulated { "-encapsulated" } else { "" })
+    }
+
+    fn write_and_check<T: OutputSample>(case: &Case<Self>, rgb: &Array3<T>, path: &Path) -> Vec<String> {
+        let mut options = DicomOptions {
+            encapsulated: case.settings.encapsulated,
+            ..DicomOptions::new(case.tile_size, [0.25, 0.5])
+        };
+        if case.settings.identified {
+            options.patient_name = Some("Doe^Jane".to_string());
+            options.patient_id = Some("P-0042".to_string());
+            options.study_uid = Some("1.2.826.0.1.3680043.10.1234.1".to_string());
+            options.accession_number = Some("A123".to_string());
+            options.container_id = Some("S24-1234 A1".to_string());
+        }
+        match virtualhe::dicom::write_dicom(rgb.view(), path, &options) {
+            Ok(()) => check(case, rgb, &options, path).unwrap_or_else(|e| vec![e]),
+            Err(e) => vec![e.to_string()],
+        }
+    }
+}
+
+#[test]
+fn frames_that_do_not_divide_the_image() {
+    common::check(case(300, 200, 64, false, NATIVE));
+}
+
+#[test]
+fn encapsulated_frames_with_identifying_attributes() {
+    let settings = Dicom {
+        encapsulated: true,
+        identified: true,
+        ..NATIVE
+    };
+    common::check(case(300, 200, 64, false, settings));
+}
+
+#[test]
+fn sixteen_bit_images_round_to_8_bits() {
+    let settings = Dicom {
+        identified: true,
+        ..NATIVE
+    };
+    common::check(case(257, 31, 100, true, settings));
+}
+
+#[test]
+fn rgba_images_are_composited_over_white() {
+    let settings = Dicom {
+        rgba: true,
+        encapsulated: true,
+        ..NATIVE
+    };
+    common::check(case(100, 130, 16, false, settings));
+}
+
+#[test]
+fn one_frame_of_the_image_size() {
+    common::check(case(3, 3, 3, false, NATIVE));
+    let settings = Dicom {
+        rgba: true,
+        encapsulated: true,
+        ..NATIVE
+    };
+    common::check(case(3, 3, 3, true, settings));
+}
+
+#[test]
+fn one_pixel_in_a_frame_of_white() {
+    let settings = Dicom {
+        identified: true,
+        ..NATIVE
+    };
+    common::check(case(1, 1, 256, false, settings));
+}
@@ -0,0 +1,85 @@
+//! Test of the dithered quantization of the rendered colors to 8 bits: smooth gradients of both
+//! channels, a few gray levels across, are rendered with every dithering and each sample and the
+//! mean of each tile of 8 x 8 pixels must stay within 1 level of the colors before quantization,
+//! taken from a 16bit render without dithering. Run with
+//!
+//!   cargo test --test dither
+//!
+//! Ordered dithering depends on the position of a pixel only, so a band of the image starting at a
+//! multiple of 8 rows must quantize as the rows of the whole image do, as tiled renders rely on.
+use ndarray::{s, Array2, Array3, ArrayView2, Axis};
+use virtualhe::{Dither, Params};
+
+/// Size of the image, not a multiple of the tiles and of several bands of error diffusion.
+const WIDTH: usize = 203;
+const HEIGHT: usize = 157;
+
+/// Edge length of the tiles whose means are compared.
+const TILE: usize = 8;
+
+/// Difference in levels from the colors that is accepted, of a sample and of the mean of a tile.
+const TOLERANCE: f64 = 1.0;
+
+/// Gradients over a few levels of the output, where quantization to 8 bits shows bands: the eosin
+/// intensity across the columns and the nucleus intensity down the rows.
+fn channels() -> (Array2<f32>, Array2<f32>) {
+    let nucleus = Array2::from_shape_fn((HEIGHT, WIDTH), |(y, _)| 0.1 + 0.02 * y as f32 / HEIGHT as f32);
+    let eosin =
+        Array2::from_shape_fn((HEIGHT, WIDTH), |(y, x)| 0.3 + 0.03 * x as f32 / WIDTH as f32 + 0.001 * y as f32);
+    (nucleus, eosin)
+}
+
+/// The colors of the gradients in levels of 8bit samples, from a 16bit render.
+fn colors() -> Array3<f64> {
+    let (nucleus, eosin) = channels();
+    virtualhe::render_as::<u16>(nucleus, eosin, &Params::default()).mapv(|v| f64::from(v) * 255.0 / 65535.0)
+}
+
+/// The samples and the means of the tiles of renders with every dithering must be within
+/// `TOLERANCE` of the colors.
+#[test]
+fn dithered_renders_are_within_a_level_of_the_colors() {
+    let colors = colors();
+    for dither in [Dither::None, Dither::Ordered, Dither::FloydSteinberg] {
+        let (nucleus, eosin) = channels();
+        let params = Params {
+            dither,
+            ..Params::default()
+        };
+        let rgb = virtualhe::render_as::<u8>(nucleus, eosin, &params).mapv(f64::from);
+        let sample_difference = rgb.iter().zip(&colors).map(|(v, color)| (v - color).abs()).fold(0.0, f64::max);
+        let mut tile_difference = 0.0f64;
+        for y in (0..HEIGHT).step_by(TILE) {
+            for x in (0..WIDTH).step_by(TILE) {
+                let tile = s![y..(y + TILE).min(HEIGHT), x..(x + TILE).min(WIDTH), ..];
+                for (rendered, color) in rgb.slice(tile).axis_iter(Axis(2)).zip(colors.slice(tile).axis_iter(Axis(2))) {
+                    let mean = |tile: ArrayView2<f64>| tile.sum() / tile.len() as f64;
+                    tile_difference = tile_difference.max((mean(rendered) - mean(color)).abs());
+                }
+            }
+        }
+        assert!(
+            sample_difference <= TOLERANCE && tile_difference <= TOLERANCE,
+            "{}: samples within {:.3} levels, tile means within {:.3} levels",
+            dither.name(),
+            sample_difference,
+            tile_difference
+        );
+    }
+}
+
+/// The ordered dithering of a band of the rows from a multiple of 8 must be that of the rows of the
+/// whole image.
+#[test]
+fn ordered_dithering_of_a_band_is_that_of_the_whole_image() {
+    let (nucleus, eosin) = channels();
+    let params = Params {
+        dither: Dither::Ordered,
+        ..Params::default()
+    };
+    let y0 = 64;
+    let (band_nucleus, band_eosin) = (nucleus.slice(s![y0.., ..]).to_owned(), eosin.slice(s![y0.., ..]).to_owned());
+    let band = virtualhe::render_as::<u8>(band_nucleus, band_eosin, &params);
+    let whole = virtualhe::render_as::<u8>(nucleus, eosin, &params);
+    assert_eq!(band, whole.slice(s![y0.., .., ..]));
+}
@@ -0,0 +1,146 @@
+//! Test of the layout of Deep Zoom outputs against the DZI format: images of several sizes, some
+//! not a multiple of the tiles or of a power of two, are written with several tile sizes and
+//! overlaps, and there must be a level for each halving from the full size down to one pixel, a
+//! tile for each tile position of each level and no other, and every tile must be the tile size
+//! plus the overlap on each side that has a neighbor, cut at the edges of its level. Run with
+//!
+//!   cargo test --test dzi
+//!
+//! The tiles of the full resolution level, PNG, must hold the pixels of the image they cover, 16bit
+//! images rounded to 8 bits.
+use common::{Case, Format};
+use ndarray::{s, Array3};
+use std::path::Path;
+use virtualhe::dzi::{DziFormat, DziOptions};
+use virtualhe::OutputSample;
+
+mod common;
+
+/// Overlap of the tiles of a case.
+struct Dzi {
+    overlap: u32,
+}
+
+/// Case of an image of `width` x `height` pixels written in tiles of `tile_size` with `overlap`.
+fn case(width: usize, height: usize, tile_size: u32, overlap: u32, sixteen_bit: bool) -> Case<Dzi> {
+    Case {
+        width,
+        height,
+        tile_size,
+        sixteen_bit,
+        settings: Dzi { overlap },
+    }
+}
+
+/// Check the levels and tiles of the image of `case` written to `path`, returning the problems.
+fn check<T: OutputSample>(case: &Case<Dzi>, rgb: &Array3<T>, path: &Path) -> Vec<String> {
+    let mut problems = Vec::new();
+    let descriptor = std::fs::read_to_string(path).unwrap_or_default();
+    let size = format!("<Size Width=\"{}\" Height=\"{}\"/>", case.width, case.height);
+    let layout = format!("Format=\"png\" Overlap=\"{}\" TileSize=\"{}\"", case.settings.overlap, case.tile_size);
+    if !descriptor.contains(&size) || !descriptor.contains(&layout) {
+        problems.push(format!("descriptor does not record {} and {}:\n{}", size, layout, descriptor));
+    }
+
+    // Level max is the full size, ceil(log2(max(width, height))), each level below it halved
+    let max_level = (case.width.max(case.height) as f64).log2().ceil() as u32;
+    let files = virtualhe::dzi::files_path(path);
+    let levels = std::fs::read_dir(&files).map_or(0, |entries| entries.count()) as u32;
+    if levels != max_level + 1 {
+        problems.push(format!("{} levels instead of {}", levels, max_level + 1));
+    }
+    let (tile, overlap) = (case.tile_size as usize, case.settings.overlap as usize);
+    for level in 0..=max_level {
+        let scale = 1usize << (max_level - level);
+        let (width, height) = (case.width.div_ceil(scale), case.height.div_ceil(scale));
+        let directory = files.join(level.to_string());
+        let (columns, rows) = (width.div_ceil(tile), height.div_ceil(tile));
+        let count = std::fs::read_dir(&directory).map_or(0, |entries| entries.count());
+        if count != columns * rows {
+            problems.push(format!("level {}: {} tiles instead of {} x {}", level, count, columns, rows));
+        }
+        for row in 0..rows {
+            for column in 0..columns {
+                let (x0, x1) = ((column * tile).saturating_sub(overlap), ((column + 1) * tile + overlap).min(width));
+                let (y0, y1) = ((row * tile).saturating_sub(overlap), ((row + 1) * tile + overlap).min(height));
+                let tile_path = directory.join(format!("{}_{}.png", column, row));
+                let Ok(decoded) = image::open(&tile_path) else {
+                    problems.push(format!("{} cannot be read", tile_path.display()));
+                    continue;
+                };
+                let decoded = decoded.to_rgb8();
+                if decoded.dimensions() != ((x1 - x0) as u32, (y1 - y0) as u32) {
+                    problems.push(format!(
+                        "{}: {:?} pixels instead of {} x {}",
+                        tile_path.display(),
+                        decoded.dimensions(),
+                        x1 - x0,
+                        y1 - y0
+                    ));
+                    continue;
+                }
+                if level != max_level {
+                    continue;
+                }
+                let to_u8 = |v: T| (f64::from(v.into()) * 255.0 / f64::from(T::MAX)).round() as u8;
+                let expected: Vec<u8> = rgb.slice(s![y0..y1, x0..x1, ..]).iter().map(|&v| to_u8(v)).collect();
+                if decoded.as_raw() != &expected {
+                    problems.push(format!("{}: the pixels differ from the image", tile_path.display()));
+                }
+            }
+        }
+    }
+    problems
+}
+
+impl Format for Dzi {
+    const OUTPUT_DIR: &'static str = "dzi";
+    const EXTENSION: &'static str = "dzi";
+
+    fn suffix(&self) -> String {
+        format!("-overlap{}", self.overlap)
+    }
+
+    fn write_and_check<T: OutputSample>(case: &Case<Self>, rgb: &Array3<T>, path: &Path) -> Vec<String> {
+        let options = DziOptions {
+            tile_size: case.tile_size,
+            overlap: case.settings.overlap,
+            format: DziFormat::Png,
+            ..DziOptions::default()
+        };
+        match virtualhe::dzi::write_dzi(rgb.view(), path, &options) {
+            Ok(()) => check(case, rgb, path),
+            Err(e) => vec![e.to_string()],
+        }
+    }
+}
+
+#[test]
+fn tiles_that_do_not_divide_the_image() {
+    common::check(case(300, 200, 64, 1, false));
+}
+
+#[test]
+fn one_row_of_tiles_not_a_power_of_two() {
+    common::check(case(257, 31, 254, 1, false));
+}
+
+#[test]
+fn sixteen_bit_images_round_to_8_bits() {
+    common::check(case(100, 130, 16, 3, true));
+}
+
+#[test]
+fn powers_of_two_without_overlap() {
+    common::check(case(256, 256, 128, 0, false));
+}
+
+#[test]
+fn one_pixel() {
+    common::check(case(1, 1, 254, 1, false));
+}
+
+#[test]
+fn overlaps_as_large_as_the_tiles() {
+    common::check(case(5, 1, 2, 2, false));
+}
@@ -0,0 +1,192 @@
+//! Golden image regression test of the library: renders the fixture pairs of `golden/fixtures`,
+//! written by `generate-test-data`, through the public pipeline of loading, scaling and rendering,
+//! and compares the rendered pixels with the golden PNGs of `golden`. The pixels are compared rather
+//! than the encoded files, so that a change of an encoder does not fail the test. Run with
+//!
+//!   cargo test --test golden
+//!
+//! A pixel differs when one of its samples is off by more than `VIRTUALHE_GOLDEN_TOLERANCE` (2 by
+//! default), and a case fails when more than `VIRTUALHE_GOLDEN_MAX_PIXELS` pixels differ (none by
+//! default). The rendered image of a failed case and a diff image, the golden image dimmed with the
+//! differing pixels in red, are written to `target/golden`. After an intended change of the
+//! rendering, `VIRTUALHE_BLESS=1` writes the rendered images as the new golden images.
+use image::{Rgb, RgbImage};
+use std::path::Path;
+use virtualhe::{ColorEncoding, Equalization, LoadOptions, Params, Profile, ScaleOptions};
+
+/// A fixture pair scaled and rendered with the given options.
+struct Case {
+    name: &'static str,
+    /// Suffix of the fixture files, e.g. `16` for `nucleus16.tif` and `eosin16.tif`.
+    fixture: &'static str,
+    scale: ScaleOptions,
+    params: Params,
+}
+
+/// Largest difference of a sample of a pixel that is not counted by default.
+const DEFAULT_TOLERANCE: u8 = 2;
+
+fn cases() -> Vec<Case> {
+    let mut cases: Vec<Case> = Profile::ALL
+        .into_iter()
+        .map(|profile| Case {
+            name: profile.name(),
+            fixture: "16",
+            scale: ScaleOptions::default(),
+            params: profile.params(),
+        })
+        .collect();
+    cases.extend([
+        Case {
+            name: "linear",
+            fixture: "16",
+            scale: ScaleOptions::default(),
+            params: Params {
+                encoding: ColorEncoding::Linear,
+                ..Params::default()
+            },
+        },
+        Case {
+            name: "exact",
+            fixture: "16",
+            scale: ScaleOptions::default(),
+            params: Params {
+                exact: true,
+                ..Params::default()
+            },
+        },
+        Case {
+            name: "k-split",
+            fixture: "16",
+            scale: ScaleOptions::default(),
+            params: Params {
+                k_nucleus: 1.5,
+                k_eosin: 3.5,
+                ..Params::default()
+            },
+        },
+        Case {
+            name: "8bit",
+            fixture: "8",
+            scale: ScaleOptions::default(),
+            params: Params::default(),
+        },
+        Case {
+            name: "equalize-global",
+            fixture: "16",
+            scale: ScaleOptions {
+                equalize: Some(Equalization::Global),
+                ..ScaleOptions::default()
+            },
+            params: Params::default(),
+        },
+        // Tiles that do not divide the fixture, so that the last ones overlap
+        Case {
+            name: "equalize-clahe",
+            fixture: "16",
+            scale: ScaleOptions {
+                equalize: Some(Equalization::Clahe {
+                    tile_size: 40,
+                    clip_limit: 2.0,
+                }),
+                ..ScaleOptions::default()
+            },
+            params: Params::default(),
+        },
+    ]);
+    cases
+}
+
+/// Load, scale and render the fixture pair of `case` as the command line does.
+fn render(dir: &Path, case: &Case) -> Result<RgbImage, Box<dyn std::error::Error>> {
+    let load = |channel: &str| -> Result<_, Box<dyn std::error::Error>> {
+        let path = dir.join("fixtures").join(format!("{}{}.tif", channel, case.fixture));
+        let (mut image, _) = virtualhe::load_channel_with(path, &LoadOptions::default())?;
+        virtualhe::scale_with(&mut image, &case.scale)?;
+        Ok(image)
+    };
+    let (nucleus, eosin) = (load("nucleus")?, load("eosin")?);
+    Ok(virtualhe::into_image(virtualhe::render(nucleus, eosin, &case.params)))
+}
+
+/// Number of pixels of `rendered` with a sample off by more than `tolerance` from `golden`, and
+/// the diff image.
+fn compare(rendered: &RgbImage, golden: &RgbImage, tolerance: u8) -> (u64, RgbImage) {
+    let mut differing = 0;
+    let diff = RgbImage::from_fn(golden.width(), golden.height(), |x, y| {
+        let (a, b) = (rendered.get_pixel(x, y), golden.get_pixel(x, y));
+        if a.0.iter().zip(b.0).any(|(&a, b)| a.abs_diff(b) > tolerance) {
+            differing += 1;
+            Rgb([255, 0, 0])
+        } else {
+            Rgb(b.0.map(|v| 128 + v / 2))
+        }
+    });
+    (differing, diff)
+}
+
+/// Value of the environment variable `name`, or `default` if unset.
+fn setting<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+    match std::env::var(name) {
+        Ok(value) => value.trim().parse().map_err(|_| format!("{}: invalid value '{}'", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Render every case and compare it with its golden image, or write it as the golden image with
+/// `VIRTUALHE_BLESS=1`, returning the failures.
+fn run() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dir = root.join("tests").join("golden");
+    let output = root.join("target").join("golden");
+    let bless = setting("VIRTUALHE_BLESS", 0u8)? != 0;
+    let tolerance = setting("VIRTUALHE_GOLDEN_TOLERANCE", DEFAULT_TOLERANCE)?;
+    let max_pixels = setting("VIRTUALHE_GOLDEN_MAX_PIXELS", 0u64)?;
+
+    let mut failures = Vec::new();
+    for case in cases() {
+        let rendered = render(&dir, &case).map_err(|e| format!("{}: {}", case.name, e))?;
+        let golden_path = dir.join(format!("{}.png", case.name));
+        if bless {
+            rendered.save(&golden_path).map_err(|e| format!("{}: {}", golden_path.display(), e))?;
+            println!("{}: blessed {}", case.name, golden_path.display());
+            continue;
+        }
+        let failure = match image::open(&golden_path) {
+            Err(e) => Some(format!("{}: {}", golden_path.display(), e)),
+            Ok(golden) => {
+                let golden = golden.to_rgb8();
+                if golden.dimensions() != rendered.dimensions() {
+                    Some(format!("rendered {:?} pixels, golden {:?}", rendered.dimensions(), golden.dimensions()))
+                } else {
+                    let (differing, diff) = compare(&rendered, &golden, tolerance);
+                    if differing > max_pixels {
+                        std::fs::create_dir_all(&output)?;
+                        let diff_path = output.join(format!("{}.diff.png", case.name));
+                        diff.save(&diff_path).map_err(|e| format!("{}: {}", diff_path.display(), e))?;
+                        Some(format!("{} pixels differ by more than {}, see {}", differing, tolerance, diff_path.display()))
+                    } else {
+                        None
+                    }
+                }
+            }
+        };
+        if let Some(failure) = failure {
+            std::fs::create_dir_all(&output)?;
+            let rendered_path = output.join(format!("{}.png", case.name));
+            rendered.save(&rendered_path).map_err(|e| format!("{}: {}", rendered_path.display(), e))?;
+            failures.push(format!("{}: {}", case.name, failure));
+        }
+    }
+    Ok(failures)
+}
+
+#[test]
+fn renders_match_the_golden_images() {
+    let failures = run().unwrap_or_else(|e| panic!("{}", e));
+    assert!(
+        failures.is_empty(),
+        "golden images differ, rerun with VIRTUALHE_BLESS=1 after an intended change:\n{}",
+        failures.join("\n")
+    );
+}
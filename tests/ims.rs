@@ -0,0 +1,691 @@
+#![cfg(feature = "ims")]
+//! Test of the Imaris reader: small Imaris files are written with the HDF5 structures of the files
+//! of HDF5 1.8, symbol tables and version 1 object headers with chunks indexed by a B-tree and
+//! compressed with deflate after the shuffle filter or contiguous samples, and of HDF5 1.10, link
+//! messages and version 2 object headers with LZ4 compressed chunks indexed by a paged fixed
+//! array. Every z plane of every channel of every resolution level, read by index and by name, must
+//! hold the voxels written, cut to the image size the attributes record. Run with
+//!
+//!   cargo test --features ims --test ims
+//!
+//! Channels, z planes and levels out of range must fail with an error listing the ones there are,
+//! and a stack of two channels must render every plane. Files with unsupported sample sizes, chunks
+//! of no samples or of fewer dimensions than the dataset, malformed chunk indices and truncated
+//! files must fail with an error, and files with any bytes overwritten must not panic.
+use ndarray::Array2;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use virtualhe::stack::{StackChannel, StackOptions};
+use virtualhe::{ChannelSelector, InputRange, LoadOptions, Params, SaveOptions, ScaleOptions};
+
+/// Size of the full resolution level, not a multiple of the chunks, and of the chunks.
+const WIDTH: usize = 20;
+const HEIGHT: usize = 13;
+const DEPTH: usize = 5;
+const CHUNK: [usize; 3] = [2, 8, 8];
+const LEVELS: usize = 2;
+
+/// Channel names of `DataSetInfo`.
+const NAMES: [&str; 2] = ["DAPI", "Autofluorescence"];
+
+/// Undefined address of HDF5.
+const UNDEFINED: u64 = u64::MAX;
+
+/// Type of the samples of a file.
+#[derive(Clone, Copy, PartialEq)]
+enum Sample {
+    U8,
+    U16,
+    F32,
+}
+
+/// How the datasets of a file are stored.
+#[derive(Clone, Copy, PartialEq)]
+enum Storage {
+    /// Chunks shuffled and compressed with deflate, indexed by a B-tree.
+    Deflate,
+    Contiguous,
+    /// Chunks compressed with LZ4, indexed by a paged fixed array.
+    Lz4,
+}
+
+/// Layout of a file: the version of its structures, its samples and storage.
+#[derive(Clone, Copy)]
+struct Layout {
+    name: &'static str,
+    /// HDF5 1.10 structures instead of those of HDF5 1.8.
+    new_format: bool,
+    sample: Sample,
+    storage: Storage,
+}
+
+const DEFLATE: Layout = Layout {
+    name: "hdf5-1.8-u16-deflate",
+    new_format: false,
+    sample: Sample::U16,
+    storage: Storage::Deflate,
+};
+
+const CONTIGUOUS: Layout = Layout {
+    name: "hdf5-1.8-u8-contiguous",
+    new_format: false,
+    sample: Sample::U8,
+    storage: Storage::Contiguous,
+};
+
+const LZ4: Layout = Layout {
+    name: "hdf5-1.10-f32-lz4",
+    new_format: true,
+    sample: Sample::F32,
+    storage: Storage::Lz4,
+};
+
+/// Value of voxel (z, y, x) of a channel at a resolution level, within 8 bits and never 0.
+fn value(level: usize, channel: usize, z: usize, y: usize, x: usize) -> u16 {
+    ((x * 7 + y * 13 + z * 31 + channel * 50 + level * 90) % 250 + 1) as u16
+}
+
+/// Width, height and depth of a resolution level, halved in x and y at each level.
+fn size(level: usize) -> [usize; 3] {
+    [WIDTH.div_ceil(1 << level), HEIGHT.div_ceil(1 << level), DEPTH]
+}
+
+/// Plane `z` of a channel at a resolution level, normalized by 255.
+fn expected(level: usize, channel: usize, z: usize) -> Array2<f32> {
+    let [width, height, _] = size(level);
+    Array2::from_shape_fn((height, width), |(y, x)| f32::from(value(level, channel, z, y, x)) / 255.0)
+}
+
+/// An object header message: its type and data.
+type Message = (u16, Vec<u8>);
+
+/// A file written from its start, objects before the groups that link them.
+struct Writer {
+    bytes: Vec<u8>,
+    layout: Layout,
+}
+
+impl Writer {
+    /// Start a file, leaving room for the superblock.
+    fn new(layout: Layout) -> Self {
+        Writer {
+            bytes: vec![0; if layout.new_format { 48 } else { 96 }],
+            layout,
+        }
+    }
+
+    /// Append bytes at an address aligned to 8 bytes and return it.
+    fn push(&mut self, data: &[u8]) -> u64 {
+        self.bytes.resize(self.bytes.len().next_multiple_of(8), 0);
+        let address = self.bytes.len() as u64;
+        self.bytes.extend(data);
+        address
+    }
+
+    /// Write an object header with `messages`. Checksums, not verified by the reader, are left 0.
+    fn object(&mut self, messages: &[Message]) -> u64 {
+        let mut header = Vec::new();
+        if self.layout.new_format {
+            let mut body = Vec::new();
+            for (kind, data) in messages {
+                body.push(*kind as u8);
+                body.extend((data.len() as u16).to_le_bytes());
+                body.push(0);
+                body.extend(data);
+            }
+            header.extend(b"OHDR");
+            header.extend([2, 2]);
+            header.extend((body.len() as u32).to_le_bytes());
+            header.extend(body);
+            header.extend([0; 4]);
+        } else {
+            let mut body = Vec::new();
+            for (kind, data) in messages {
+                let padded = data.len().next_multiple_of(8);
+                body.extend(kind.to_le_bytes());
+                body.extend((padded as u16).to_le_bytes());
+                body.extend([0; 4]);
+                body.extend(data);
+                body.resize(body.len().next_multiple_of(8), 0);
+            }
+            header.extend([1, 0]);
+            header.extend((messages.len() as u16).to_le_bytes());
+            header.extend(1u32.to_le_bytes());
+            header.extend((body.len() as u32).to_le_bytes());
+            header.extend([0; 4]);
+            header.extend(body);
+        }
+        self.push(&header)
+    }
+
+    /// A dataspace message of `dims`.
+    fn dataspace(&self, dims: &[usize]) -> Vec<u8> {
+        let rank = dims.len() as u8;
+        let mut data = if self.layout.new_format { vec![2, rank, 0, 1] } else { vec![1, rank, 0, 0, 0, 0, 0, 0] };
+        for &dim in dims {
+            data.extend((dim as u64).to_le_bytes());
+        }
+        data
+    }
+
+    /// An attribute message holding `value` as an array of characters, as Imaris writes them.
+    fn attribute(&self, name: &str, value: &str) -> Message {
+        let mut datatype = vec![0x13, 0, 0, 0];
+        datatype.extend(1u32.to_le_bytes());
+        let dataspace = self.dataspace(&[value.len()]);
+        let mut name = name.as_bytes().to_vec();
+        name.push(0);
+        let mut data = if self.layout.new_format { vec![3, 0] } else { vec![1, 0] };
+        data.extend((name.len() as u16).to_le_bytes());
+        data.extend((datatype.len() as u16).to_le_bytes());
+        data.extend((dataspace.len() as u16).to_le_bytes());
+        if self.layout.new_format {
+            data.push(0);
+        }
+        for field in [name, datatype, dataspace] {
+            data.extend(&field);
+            if !self.layout.new_format {
+                data.resize(data.len().next_multiple_of(8), 0);
+            }
+        }
+        data.extend(value.as_bytes());
+        (0xc, data)
+    }
+
+    /// Write a group linking `children` with string attributes.
+    fn group(&mut self, children: &[(String, u64)], attributes: &[(&str, String)]) -> u64 {
+        let mut messages: Vec<Message> = attributes.iter().map(|(name, value)| self.attribute(name, value)).collect();
+        if self.layout.new_format {
+            // Link info without dense storage, group info, and a link message per child
+            let mut info = vec![0, 0];
+            info.extend(UNDEFINED.to_le_bytes());
+            info.extend(UNDEFINED.to_le_bytes());
+            messages.push((0x2, info));
+            messages.push((0xa, vec![0, 0]));
+            for (name, address) in children {
+                let mut link = vec![1, 0, name.len() as u8];
+                link.extend(name.as_bytes());
+                link.extend(address.to_le_bytes());
+                messages.push((0x6, link));
+            }
+            return self.object(&messages);
+        }
+
+        // A symbol table: the names in a local heap, the entries in a node of a B-tree
+        let mut children = children.to_vec();
+        children.sort();
+        let mut heap = vec![0u8; 8];
+        let mut offsets = Vec::new();
+        for (name, _) in &children {
+            offsets.push(heap.len() as u64);
+            heap.extend(name.as_bytes());
+            heap.push(0);
+            heap.resize(heap.len().next_multiple_of(8), 0);
+        }
+        let heap_data = self.push(&heap);
+        let mut header = b"HEAP\0\0\0\0".to_vec();
+        header.extend((heap.len() as u64).to_le_bytes());
+        header.extend(UNDEFINED.to_le_bytes());
+        header.extend(heap_data.to_le_bytes());
+        let heap = self.push(&header);
+
+        let mut node = b"SNOD\x01\0".to_vec();
+        node.extend((children.len() as u16).to_le_bytes());
+        for ((_, address), offset) in children.iter().zip(&offsets) {
+            node.extend(offset.to_le_bytes());
+            node.extend(address.to_le_bytes());
+            node.extend([0; 24]);
+        }
+        let node = self.push(&node);
+
+        let mut tree = b"TREE\0\0".to_vec();
+        tree.extend(1u16.to_le_bytes());
+        tree.extend(UNDEFINED.to_le_bytes());
+        tree.extend(UNDEFINED.to_le_bytes());
+        tree.extend(0u64.to_le_bytes());
+        tree.extend(node.to_le_bytes());
+        tree.extend(offsets.last().copied().unwrap_or(0).to_le_bytes());
+        let tree = self.push(&tree);
+
+        let mut table = tree.to_le_bytes().to_vec();
+        table.extend(heap.to_le_bytes());
+        messages.push((0x11, table));
+        self.object(&messages)
+    }
+
+    /// Write the dataset of a channel at a resolution level, padded to whole chunks.
+    fn dataset(&mut self, level: usize, channel: usize) -> u64 {
+        let [width, height, depth] = size(level);
+        let shape = [
+            depth.next_multiple_of(CHUNK[0]),
+            height.next_multiple_of(CHUNK[1]),
+            width.next_multiple_of(CHUNK[2]),
+        ];
+        let sample = self.layout.sample;
+        let sample_size = match sample {
+            Sample::U8 => 1,
+            Sample::U16 => 2,
+            Sample::F32 => 4,
+        };
+        // Samples of a region of the volume, 0 beyond the image
+        let samples = |offsets: [usize; 3], extent: [usize; 3]| {
+            let mut bytes = Vec::new();
+            for z in offsets[0]..offsets[0] + extent[0] {
+                for y in offsets[1]..offsets[1] + extent[1] {
+                    for x in offsets[2]..offsets[2] + extent[2] {
+                        let v = if z < depth && y < height && x < width { value(level, channel, z, y, x) } else { 0 };
+                        match sample {
+                            Sample::U8 => bytes.push(v as u8),
+                            Sample::U16 => bytes.extend(v.to_le_bytes()),
+                            Sample::F32 => bytes.extend(f32::from(v).to_le_bytes()),
+                        }
+                    }
+                }
+            }
+            bytes
+        };
+
+        let mut messages = vec![(0x1, self.dataspace(&shape))];
+        let mut datatype = match self.layout.sample {
+            Sample::F32 => vec![0x11, 0x20, 0x1f, 0],
+            _ => vec![0x10, 0, 0, 0],
+        };
+        datatype.extend((sample_size as u32).to_le_bytes());
+        datatype.extend(0u16.to_le_bytes());
+        datatype.extend((sample_size as u16 * 8).to_le_bytes());
+        if self.layout.sample == Sample::F32 {
+            datatype.extend([23, 8, 0, 23]);
+            datatype.extend(127u32.to_le_bytes());
+        }
+        messages.push((0x3, datatype));
+
+        if self.layout.storage == Storage::Contiguous {
+            let data = samples([0; 3], shape);
+            let address = self.push(&data);
+            let mut layout = vec![3, 1];
+            layout.extend(address.to_le_bytes());
+            layout.extend((data.len() as u64).to_le_bytes());
+            messages.push((0x8, layout));
+            return self.object(&messages);
+        }
+
+        // Chunks in row-major order, with their offsets and stored sizes
+        let mut chunks = Vec::new();
+        for z in (0..shape[0]).step_by(CHUNK[0]) {
+            for y in (0..shape[1]).step_by(CHUNK[1]) {
+                for x in (0..shape[2]).step_by(CHUNK[2]) {
+                    let data = compress(samples([z, y, x], CHUNK), self.layout.storage, sample_size);
+                    chunks.push(([z, y, x], self.push(&data), data.len()));
+                }
+            }
+        }
+        let mut chunk_dims = Vec::new();
+        for dim in CHUNK.iter().chain([sample_size].iter()) {
+            chunk_dims.extend((*dim as u32).to_le_bytes());
+        }
+        if self.layout.storage == Storage::Deflate {
+            let mut tree = b"TREE\x01\0".to_vec();
+            tree.extend((chunks.len() as u16).to_le_bytes());
+            tree.extend(UNDEFINED.to_le_bytes());
+            tree.extend(UNDEFINED.to_le_bytes());
+            let key = |tree: &mut Vec<u8>, size: usize, offsets: [usize; 3]| {
+                tree.extend((size as u32).to_le_bytes());
+                tree.extend(0u32.to_le_bytes());
+                for offset in offsets.iter().chain([0].iter()) {
+                    tree.extend((*offset as u64).to_le_bytes());
+                }
+            };
+            for &(offsets, address, size) in &chunks {
+                key(&mut tree, size, offsets);
+                tree.extend(address.to_le_bytes());
+            }
+            key(&mut tree, 0, shape);
+            let tree = self.push(&tree);
+            let mut layout = vec![3, 2, 4];
+            layout.extend(tree.to_le_bytes());
+            layout.extend(chunk_dims);
+            messages.push((0x8, layout));
+            // Shuffle, then deflate at level 6, each with one client value
+            let mut pipeline = vec![1, 2, 0, 0, 0, 0, 0, 0];
+            for (id, value) in [(2u16, sample_size as u32), (1, 6)] {
+                pipeline.extend(id.to_le_bytes());
+                pipeline.extend([0, 0, 0, 0]);
+                pipeline.extend(1u16.to_le_bytes());
+                pipeline.extend(value.to_le_bytes());
+                pipeline.extend([0; 4]);
+            }
+            messages.push((0xb, pipeline));
+        } else {
+            // A fixed array of 4 entries per page: address, stored size and filter mask of each chunk
+            let page_bits = 2;
+            let entries: Vec<u8> = chunks
+                .iter()
+                .flat_map(|&(_, address, size)| [address.to_le_bytes(), u64::from(size as u32).to_le_bytes()].concat())
+                .collect();
+            let header_address = self.bytes.len().next_multiple_of(8) as u64;
+            let mut header = b"FAHD\0\x01".to_vec();
+            header.extend([16, page_bits]);
+            header.extend((chunks.len() as u64).to_le_bytes());
+            header.extend((header_address + 32).to_le_bytes());
+            header.extend([0; 4]);
+            header.resize(32, 0);
+            let mut block = b"FADB\0\x01".to_vec();
+            block.extend(header_address.to_le_bytes());
+            if chunks.len() > 1 << page_bits {
+                let pages = chunks.len().div_ceil(1 << page_bits);
+                block.extend(vec![0xff; pages.div_ceil(8)]);
+                block.extend([0; 4]);
+            }
+            for page in entries.chunks(16 << page_bits) {
+                block.extend(page);
+                block.extend([0; 4]);
+            }
+            header.extend(block);
+            let address = self.push(&header);
+            assert_eq!(address, header_address);
+            let mut layout = vec![4, 2, 0, 4, 4];
+            layout.extend(chunk_dims);
+            layout.extend([3, page_bits]);
+            layout.extend(address.to_le_bytes());
+            messages.push((0x8, layout));
+            let mut pipeline = vec![2, 1];
+            pipeline.extend(32004u16.to_le_bytes());
+            pipeline.extend([0; 6]);
+            messages.push((0xb, pipeline));
+        }
+        self.object(&messages)
+    }
+
+    /// Write the superblock pointing to the root group and return the file.
+    fn finish(mut self, root: u64) -> Vec<u8> {
+        let end = (self.bytes.len() as u64).to_le_bytes();
+        let mut superblock = b"\x89HDF\r\n\x1a\n".to_vec();
+        if self.layout.new_format {
+            superblock.extend([2, 8, 8, 0]);
+            superblock.extend(0u64.to_le_bytes());
+            superblock.extend(UNDEFINED.to_le_bytes());
+            superblock.extend(end);
+            superblock.extend(root.to_le_bytes());
+            superblock.extend([0; 4]);
+        } else {
+            superblock.extend([0, 0, 0, 0, 0, 8, 8, 0]);
+            superblock.extend(4u16.to_le_bytes());
+            superblock.extend(16u16.to_le_bytes());
+            superblock.extend(0u32.to_le_bytes());
+            superblock.extend(0u64.to_le_bytes());
+            superblock.extend(UNDEFINED.to_le_bytes());
+            superblock.extend(end);
+            superblock.extend(UNDEFINED.to_le_bytes());
+            superblock.extend(0u64.to_le_bytes());
+            superblock.extend(root.to_le_bytes());
+            superblock.extend([0; 24]);
+        }
+        self.bytes[..superblock.len()].copy_from_slice(&superblock);
+        self.bytes
+    }
+}
+
+/// Store the samples of a chunk as `storage` does.
+fn compress(samples: Vec<u8>, storage: Storage, sample_size: usize) -> Vec<u8> {
+    match storage {
+        Storage::Deflate => {
+            // The first bytes of all samples, then the second ones, ...
+            let count = samples.len() / sample_size;
+            let shuffled: Vec<u8> = (0..samples.len()).map(|i| samples[i % count * sample_size + i / count]).collect();
+            let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(6));
+            encoder.write_all(&shuffled).expect("in memory");
+            encoder.finish().expect("in memory")
+        }
+        // The total size and block size, then one block with its size, stored as is if LZ4 does
+        // not make it smaller
+        _ => {
+            let compressed = lz4_flex::block::compress(&samples);
+            let block = if compressed.len() < samples.len() { compressed } else { samples.clone() };
+            let mut data = (samples.len() as u64).to_be_bytes().to_vec();
+            data.extend((samples.len() as u32).to_be_bytes());
+            data.extend((block.len() as u32).to_be_bytes());
+            data.extend(block);
+            data
+        }
+    }
+}
+
+/// Write an Imaris file of two channels at two resolution levels.
+fn write_ims(path: &Path, layout: Layout) -> std::io::Result<()> {
+    let mut writer = Writer::new(layout);
+    let mut levels = Vec::new();
+    for level in 0..LEVELS {
+        let [width, height, depth] = size(level);
+        let mut channels = Vec::new();
+        for channel in 0..NAMES.len() {
+            let data = writer.dataset(level, channel);
+            let attributes = [
+                ("ImageSizeX", width.to_string()),
+                ("ImageSizeY", height.to_string()),
+                ("ImageSizeZ", depth.to_string()),
+            ];
+            channels.push((format!("Channel {}", channel), writer.group(&[("Data".to_string(), data)], &attributes)));
+        }
+        let time_point = writer.group(&channels, &[]);
+        let level_group = writer.group(&[("TimePoint 0".to_string(), time_point)], &[]);
+        levels.push((format!("ResolutionLevel {}", level), level_group));
+    }
+    let dataset = writer.group(&levels, &[]);
+    let mut infos = Vec::new();
+    for (channel, name) in NAMES.iter().enumerate() {
+        let attributes = [("Name", name.to_string()), ("Description", String::new())];
+        infos.push((format!("Channel {}", channel), writer.group(&[], &attributes)));
+    }
+    let info = writer.group(&infos, &[]);
+    let children = [("DataSet".to_string(), dataset), ("DataSetInfo".to_string(), info)];
+    let root = writer.group(&children, &[("ImarisVersion", "5.5.0".to_string())]);
+    std::fs::write(path, writer.finish(root))
+}
+
+/// Options reading a z plane of a channel at a resolution level, normalized by 255.
+fn options(channel: ChannelSelector, level: usize, z: Option<usize>) -> LoadOptions {
+    LoadOptions {
+        channel: Some(channel),
+        level,
+        z,
+        range: InputRange::Max(255.0),
+        ..LoadOptions::default()
+    }
+}
+
+/// Whether an image read holds the pixels of the image written.
+fn matches(image: &Array2<f32>, expected: &Array2<f32>) -> bool {
+    image.dim() == expected.dim() && image.iter().zip(expected).all(|(a, b)| (a - b).abs() < 1e-6)
+}
+
+/// Directory the files of a test are written into.
+fn output_dir(test: &str) -> PathBuf {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("ims").join(test);
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// Write the file of `layout` into the directory of `test`, returning its path.
+fn written(test: &str, layout: Layout) -> PathBuf {
+    let path = output_dir(test).join(format!("{}.ims", layout.name));
+    write_ims(&path, layout).unwrap();
+    path
+}
+
+/// Read every plane of every channel and level of a file by index and by name and compare it with
+/// the volume written.
+fn check_volume(layout: Layout) {
+    let path = written("volume", layout);
+    let mut failures = Vec::new();
+    for level in 0..LEVELS {
+        for (channel, name) in NAMES.iter().enumerate() {
+            for selector in [ChannelSelector::Index(channel), ChannelSelector::Name(name.to_string())] {
+                for z in 0..DEPTH {
+                    let options = options(selector.clone(), level, Some(z));
+                    let failure = match virtualhe::load_channel_with(&path, &options) {
+                        Ok((image, _)) if matches(&image, &expected(level, channel, z)) => None,
+                        Ok((image, _)) => Some(format!("{}x{} pixels differ", image.ncols(), image.nrows())),
+                        Err(e) => Some(e.to_string()),
+                    };
+                    let [width, height, _] = size(level);
+                    let failure = failure.or_else(|| match virtualhe::input_dimensions(&path, &options) {
+                        Some(dimensions) if dimensions == (width, height) => None,
+                        other => Some(format!("dimensions {:?} from the headers", other)),
+                    });
+                    if let Some(failure) = failure {
+                        failures.push(format!("level {}, channel {}, plane {}: {}", level, selector, z, failure));
+                    }
+                }
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// Read a file with options that must fail with an error containing `expected`.
+fn check_error(path: &Path, options: &LoadOptions, expected: &str) {
+    match virtualhe::load_channel_with(path, options) {
+        Ok(_) => panic!("{} read without an error", path.display()),
+        Err(e) => assert!(e.to_string().contains(expected), "{} instead of {}", e, expected),
+    }
+}
+
+/// Copy of the file of `layout` named `name`, with `edit` applied to the bytes of every match of
+/// `pattern`, given the bytes of the file and the position of the match.
+fn corrupt(layout: Layout, name: &str, pattern: &[u8], edit: impl Fn(&mut [u8], usize)) -> PathBuf {
+    let mut bytes = std::fs::read(written(name, layout)).unwrap();
+    let matches: Vec<usize> =
+        (0..bytes.len().saturating_sub(pattern.len())).filter(|&at| bytes[at..].starts_with(pattern)).collect();
+    assert!(!matches.is_empty(), "{}: pattern not found", name);
+    for at in matches {
+        edit(&mut bytes, at);
+    }
+    let corrupted = output_dir(name).join("corrupted.ims");
+    std::fs::write(&corrupted, bytes).unwrap();
+    corrupted
+}
+
+/// The datatype of the u16 samples of the deflate file.
+const DATATYPE: [u8; 12] = [0x10, 0, 0, 0, 2, 0, 0, 0, 0, 0, 16, 0];
+
+/// The chunk dimensions of the deflate file, with the sample size.
+fn chunk_dims() -> Vec<u8> {
+    CHUNK.iter().chain([2].iter()).flat_map(|&dim| (dim as u32).to_le_bytes()).collect()
+}
+
+#[test]
+fn chunks_indexed_by_a_btree_read_as_written() {
+    check_volume(DEFLATE);
+}
+
+#[test]
+fn contiguous_samples_read_as_written() {
+    check_volume(CONTIGUOUS);
+}
+
+#[test]
+fn chunks_indexed_by_a_fixed_array_read_as_written() {
+    check_volume(LZ4);
+}
+
+#[test]
+fn selections_out_of_range_fail_listing_the_ones_there_are() {
+    let path = written("out-of-range", DEFLATE);
+    let index = ChannelSelector::Index;
+    let channels = "channel 2 is out of range, the volume has the channels 0-1";
+    check_error(&path, &options(index(2), 0, None), channels);
+    check_error(&path, &options(index(0), 0, Some(5)), "z plane 5 is out of range, the volume has the planes 0-4");
+    let levels = "resolution level 2 is out of range, the file has the levels 0-1";
+    check_error(&path, &options(index(0), 2, None), levels);
+    let names = "no channel named 'GFP', expected one of: DAPI, Autofluorescence";
+    check_error(&path, &options(ChannelSelector::Name("GFP".to_string()), 0, None), names);
+}
+
+#[test]
+fn stacks_render_every_plane() {
+    let path = written("stack", DEFLATE);
+    let output = output_dir("stack").join("stack.tif");
+    let (nucleus, eosin) = (options(ChannelSelector::Index(0), 0, None), options(ChannelSelector::Index(1), 0, None));
+    let scale = ScaleOptions::default();
+    let planes = virtualhe::stack::render_stack(
+        &StackChannel {
+            path: &path,
+            load: &nucleus,
+            scale: &scale,
+        },
+        &StackChannel {
+            path: &path,
+            load: &eosin,
+            scale: &scale,
+        },
+        &Params::default(),
+        &output,
+        &StackOptions::default(),
+        &SaveOptions::default(),
+    );
+    assert_eq!(planes.unwrap(), DEPTH);
+}
+
+#[test]
+fn unsupported_sample_sizes_fail() {
+    let path = corrupt(DEFLATE, "sample-size", &DATATYPE, |bytes, at| bytes[at + 4] = 3);
+    check_error(&path, &options(ChannelSelector::Index(0), 0, None), "samples of 3 bytes are not supported");
+}
+
+#[test]
+fn chunks_of_no_samples_fail() {
+    let path = corrupt(DEFLATE, "empty-chunks", &chunk_dims(), |bytes, at| bytes[at] = 0);
+    check_error(&path, &options(ChannelSelector::Index(0), 0, None), "chunks of [0, 8, 8] samples are not supported");
+}
+
+#[test]
+fn chunks_of_fewer_dimensions_than_the_dataset_fail() {
+    // The dimensionality of the layout message before the B-tree address
+    let path = corrupt(DEFLATE, "chunk-dimensions", &chunk_dims(), |bytes, at| bytes[at - 9] = 2);
+    check_error(&path, &options(ChannelSelector::Index(0), 0, None), "chunks of 2 dimensions in a dataset of 3");
+}
+
+#[test]
+fn fixed_arrays_of_unexpected_entries_fail() {
+    let path = corrupt(LZ4, "fixed-array", b"FAHD\0\x01", |bytes, at| bytes[at + 6] = 4);
+    check_error(&path, &options(ChannelSelector::Index(0), 0, None), "has entries of 4 bytes");
+}
+
+/// The root group is written last, so a file cut anywhere misses a structure the reader needs.
+#[test]
+fn truncated_files_fail() {
+    for layout in [DEFLATE, CONTIGUOUS, LZ4] {
+        let test = format!("truncated-{}", layout.name);
+        let bytes = std::fs::read(written(&test, layout)).unwrap();
+        let path = output_dir(&test).join("truncated.ims");
+        for length in (0..bytes.len()).step_by(61) {
+            std::fs::write(&path, &bytes[..length]).unwrap();
+            let options = options(ChannelSelector::Index(0), 0, None);
+            assert!(virtualhe::load_channel_with(&path, &options).is_err(), "{} cut at {} read", layout.name, length);
+        }
+        std::fs::write(&path, &bytes[..bytes.len() * 3 / 4]).unwrap();
+        check_error(&path, &options(ChannelSelector::Index(0), 0, None), "beyond the end of the file");
+    }
+}
+
+/// Any field, an address, a size or a count, may hold any value in a corrupt file: reading must
+/// then fail or read some pixels, but never panic or allocate what the file cannot hold.
+#[test]
+fn corrupt_files_do_not_panic() {
+    for layout in [DEFLATE, CONTIGUOUS, LZ4] {
+        let test = format!("corrupt-{}", layout.name);
+        let bytes = std::fs::read(written(&test, layout)).unwrap();
+        let path = output_dir(&test).join("corrupt.ims");
+        for at in (0..bytes.len()).step_by(4) {
+            for value in [0x00, 0xff] {
+                let mut corrupted = bytes.clone();
+                let end = (at + 4).min(bytes.len());
+                corrupted[at..end].fill(value);
+                std::fs::write(&path, &corrupted).unwrap();
+                for level in 0..LEVELS {
+                    let _ = virtualhe::load_channel_with(&path, &options(ChannelSelector::Index(1), level, Some(1)));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Test of the percentiles of the library against the values `numpy.percentile` computes with the
+//! same method for arrays of several sizes, so that the thresholds match those of Python
+//! prototypes. Run with
+//!
+//!   cargo test --release --test percentile
+//!
+//! The arrays hold multiples of powers of two out of order, which are exact in f32, and the
+//! expected values are those of numpy for the same arrays in float64. The thresholds counted in a
+//! histogram for images of integer levels, with and without automatic contrast, are compared with
+//! those of sorting the values too, which must be the same bit for bit, and so are those of the
+//! streaming accumulator of `virtualhe::quantile` for integer values and for float values up to
+//! its exact limit. Beyond the limit, the ranks of the values its t-digest estimates must be within
+//! the documented bound of those of the percentiles.
+use ndarray::{s, Array2};
+use std::f64::consts::PI;
+use virtualhe::quantile::{StreamingQuantiles, COMPRESSION};
+use virtualhe::{AutoContrast, NanPolicy, PercentileMethod, ScaleOptions};
+
+/// Relative difference of a computed value from the value of numpy that is accepted, of the
+/// rounding of an f64 to f32.
+const TOLERANCE: f64 = 1e-6;
+
+/// An array and the values of numpy at some of its percentiles.
+struct Case {
+    name: &'static str,
+    values: Vec<f32>,
+    /// Method, percentile and the value of numpy.
+    expected: Vec<(PercentileMethod, f32, f64)>,
+}
+
+fn cases() -> Vec<Case> {
+    use PercentileMethod::{Legacy, Linear, Lower, Nearest};
+    vec![
+        Case {
+            name: "2 values",
+            values: vec![0.75, 0.25],
+            expected: vec![
+                (Linear, 25.0, 0.375),
+                (Linear, 50.0, 0.5),
+                (Linear, 100.0, 0.75),
+                (Lower, 99.0, 0.25),
+                (Nearest, 50.0, 0.25),
+            ],
+        },
+        Case {
+            name: "5 values",
+            values: vec![0.125, 0.5, 0.25, 1.0, 0.375],
+            expected: vec![
+                (Linear, 50.0, 0.375),
+                (Linear, 62.5, 0.4375),
+                (Linear, 90.0, 0.8),
+                (Linear, 99.999, 0.99998),
+                (Lower, 62.5, 0.375),
+                (Lower, 90.0, 0.5),
+                (Nearest, 62.5, 0.375),
+                (Nearest, 87.5, 1.0),
+                (Nearest, 90.0, 1.0),
+                (Legacy, 50.0, 0.375),
+                (Legacy, 90.0, 1.0),
+            ],
+        },
+        Case {
+            name: "10 values",
+            values: (0..10).map(|i| ((i * 3) % 10) as f32 / 16.0).collect(),
+            expected: vec![
+                (Linear, 33.3, 0.1873125),
+                (Linear, 50.0, 0.28125),
+                (Linear, 99.0, 0.556875),
+                (Lower, 99.0, 0.5),
+                (Nearest, 50.0, 0.25),
+                (Nearest, 99.0, 0.5625),
+            ],
+        },
+        Case {
+            name: "1000 values",
+            values: (0..1000).map(|i| ((i * 7919) % 1000) as f32 / 1024.0).collect(),
+            expected: vec![
+                (Linear, 0.5, 0.0048779296875),
+                (Linear, 50.0, 0.48779296875),
+                (Linear, 99.9, 0.9746103515625),
+                (Linear, 99.999, 0.975576181640625),
+                (Lower, 99.999, 0.974609375),
+                (Nearest, 99.999, 0.9755859375),
+                (Legacy, 50.0, 0.48828125),
+            ],
+        },
+    ]
+}
+
+#[test]
+fn percentiles_match_numpy() {
+    let mut failures = Vec::new();
+    for Case { name, values, expected } in cases() {
+        for (method, percentile, value) in expected {
+            let options = ScaleOptions {
+                percentile,
+                percentile_method: method,
+                ..ScaleOptions::default()
+            };
+            let computed = match virtualhe::compute_thresholds(&mut values.clone(), &options) {
+                Ok(thresholds) => f64::from(thresholds.ceiling),
+                Err(e) => {
+                    failures.push(format!("{}, {} {}: {}", name, method.name(), percentile, e));
+                    continue;
+                }
+            };
+            if (computed - value).abs() > TOLERANCE * value.abs().max(1.0) {
+                failures.push(format!("{}, {} {}: {} instead of {}", name, method.name(), percentile, computed, value));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// Image of `width` x `height` random integers up to `max_level` normalized by `input_max`, with
+/// a NaN every `nan_every` pixels if set.
+fn integer_image(width: usize, height: usize, max_level: u32, input_max: f32, nan_every: Option<usize>) -> Array2<f32> {
+    // A linear congruential generator is random enough for the order of the values
+    let mut state = 0x2545f4914f6cdd1d_u64 ^ (width * height) as u64;
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        match nan_every {
+            Some(every) if (y * width + x).is_multiple_of(every) => f32::NAN,
+            _ => ((state >> 33) % u64::from(max_level + 1)) as f32 / input_max,
+        }
+    })
+}
+
+/// The thresholds counted in a histogram of integer levels must be those of sorting the values, for
+/// images of several sizes and bit depths, with and without masks, NaN values and the tissue of
+/// automatic contrast.
+#[test]
+fn counted_thresholds_match_sorted_ones() {
+    let images = [
+        ("1 pixel", integer_image(1, 1, 255, 255.0, None)),
+        ("8bit", integer_image(37, 23, 255, 255.0, None)),
+        ("12bit", integer_image(200, 120, 4095, 4095.0, Some(97))),
+        ("16bit", integer_image(256, 256, 65535, 65535.0, None)),
+        ("16bit by 12bit", integer_image(128, 64, 65535, 4095.0, Some(13))),
+    ];
+    let input_maxes = [255.0, 255.0, 4095.0, 65535.0, 4095.0];
+    let methods = [
+        PercentileMethod::Linear,
+        PercentileMethod::Lower,
+        PercentileMethod::Nearest,
+        PercentileMethod::Legacy,
+    ];
+    let percentiles = [(50.0, None), (99.0, Some(1.0)), (99.999, Some(10.0)), (100.0, Some(0.0))];
+    let mut failures = Vec::new();
+    for ((name, image), input_max) in images.iter().zip(input_maxes) {
+        let mask = Array2::from_shape_fn(image.dim(), |(y, x)| (x + 2 * y) % 3 != 0);
+        for method in methods {
+            for (percentile, floor_percentile) in percentiles {
+                for nan_policy in [NanPolicy::Zero, NanPolicy::Ignore] {
+                    let masks = [None, Some(&mask)].into_iter();
+                    for (mask, auto_contrast) in masks.flat_map(|m| [(m, None), (m, Some(AutoContrast::Otsu))]) {
+                        let options = ScaleOptions {
+                            percentile,
+                            floor_percentile,
+                            nan_policy,
+                            percentile_method: method,
+                            auto_contrast,
+                            ..ScaleOptions::default()
+                        };
+                        let scale = |options: &ScaleOptions| {
+                            virtualhe::scale_with_mask(&mut image.clone(), options, mask).map(|t| [t.floor, t.ceiling])
+                        };
+                        let sorted = scale(&options).map_err(|e| e.to_string());
+                        let integer_options = options.clone().with_integer_input_max(input_max);
+                        let counted = scale(&integer_options).map_err(|e| e.to_string());
+                        let same = match (&sorted, &counted) {
+                            (Ok(sorted), Ok(counted)) => sorted.map(f32::to_bits) == counted.map(f32::to_bits),
+                            (sorted, counted) => sorted == counted,
+                        };
+                        if !same {
+                            failures.push(format!(
+                                "{}, {} {} {:?}, {:?} NaN, mask {}, auto contrast {}: counted {:?} instead of {:?}",
+                                name,
+                                method.name(),
+                                percentile,
+                                floor_percentile,
+                                nan_policy,
+                                mask.is_some(),
+                                auto_contrast.is_some(),
+                                counted,
+                                sorted
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// Image of `width` x `height` random floats from 0 to 1, denser towards 0 as the intensities of
+/// fluorescence images are, with a NaN every `nan_every` pixels if set.
+fn float_image(width: usize, height: usize, nan_every: Option<usize>) -> Array2<f32> {
+    let mut image = integer_image(width, height, 65535, 65535.0, nan_every);
+    image.mapv_inplace(|v| v * v * (1.0 + v) / 2.0);
+    image
+}
+
+/// The values of `image` streamed in bands of `rows` rows.
+fn stream(image: &Array2<f32>, rows: usize, mut quantiles: StreamingQuantiles) -> StreamingQuantiles {
+    for y in (0..image.nrows()).step_by(rows) {
+        quantiles.update(image.slice(s![y..(y + rows).min(image.nrows()), ..]));
+    }
+    quantiles
+}
+
+/// Finite values of `image`, sorted.
+fn sorted_finite(image: &Array2<f32>) -> Vec<f32> {
+    let mut values: Vec<f32> = image.iter().copied().filter(|v| v.is_finite()).collect();
+    values.sort_unstable_by(f32::total_cmp);
+    values
+}
+
+/// The percentiles of the streaming accumulator must be those of sorting the values bit for bit
+/// while they are exact, and within the documented bound of rank otherwise.
+#[test]
+fn streamed_percentiles_match_sorted_ones() {
+    let methods = [
+        PercentileMethod::Linear,
+        PercentileMethod::Lower,
+        PercentileMethod::Nearest,
+        PercentileMethod::Legacy,
+    ];
+    let percentiles = [1.0, 50.0, 99.0, 99.9, 99.999, 100.0];
+    let mut failures = Vec::new();
+
+    let mut exact_images = vec![
+        ("8bit", integer_image(37, 23, 255, 1.0, None)),
+        ("16bit", integer_image(1000, 700, 65535, 1.0, Some(101))),
+        ("float", float_image(300, 200, Some(7))),
+    ];
+    // A float image with integer values at first, which are moved out of the histogram
+    let mut mixed = integer_image(200, 100, 4095, 1.0, None);
+    mixed.slice_mut(s![50.., ..]).mapv_inplace(|v| v / 7.0);
+    exact_images.push(("integers then float", mixed));
+    for (name, image) in &exact_images {
+        let mut quantiles = stream(image, 16, StreamingQuantiles::new());
+        let sorted = sorted_finite(image);
+        for method in methods {
+            for percentile in percentiles {
+                let options = ScaleOptions {
+                    percentile,
+                    percentile_method: method,
+                    ..ScaleOptions::default()
+                };
+                let expected = virtualhe::compute_thresholds(&mut sorted.clone(), &options).map(|t| t.ceiling).ok();
+                let streamed = quantiles.finalize(percentile, method);
+                if !quantiles.is_exact() || streamed.map(f32::to_bits) != expected.map(f32::to_bits) {
+                    failures.push(format!(
+                        "{}, {} {}: streamed {:?} instead of {:?}",
+                        name,
+                        method.name(),
+                        percentile,
+                        streamed,
+                        expected
+                    ));
+                }
+            }
+        }
+    }
+
+    // Ranks of the values the t-digest estimates, in percentage points from the percentiles
+    let sizes = [("1M float", float_image(1000, 1000, None)), ("4M float", float_image(2048, 2048, Some(1009)))];
+    for (name, image) in &sizes {
+        let mut quantiles = stream(image, 37, StreamingQuantiles::with_exact_limit(0));
+        let sorted = sorted_finite(image);
+        let count = sorted.len() as f64;
+        for percentile in [1.0, 50.0, 99.0, 99.9, 99.999] {
+            let Some(estimate) = quantiles.finalize(percentile, PercentileMethod::Linear) else {
+                failures.push(format!("{}, {}: no estimate", name, percentile));
+                continue;
+            };
+            // The estimate is at any percentile from that of the values below it to that of the
+            // values up to it, and a rank of one value is the resolution of the percentiles
+            let below = sorted.partition_point(|&v| v < estimate) as f64 / count * 100.0;
+            let up_to = sorted.partition_point(|&v| v <= estimate) as f64 / count * 100.0;
+            let error = (below - f64::from(percentile)).max(f64::from(percentile) - up_to).max(0.0);
+            let q = f64::from(percentile) / 100.0;
+            let bound = 100.0 * PI * (q * (1.0 - q)).sqrt() / COMPRESSION + 100.0 / count;
+            if quantiles.is_exact() || error > bound {
+                failures.push(format!("{}, {}: {} points of rank off, more than {}", name, percentile, error, bound));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
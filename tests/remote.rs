@@ -0,0 +1,395 @@
+#![cfg(feature = "remote")]
+//! Test of remote inputs and outputs against a local server that answers as S3 does, by path:
+//! objects must download whole, also when the first request gets a server error and when the
+//! connection drops halfway through the body, which must be continued with a range request, and
+//! an OME-Zarr store at an http:// and an s3:// URL must read the channels of the store on disk.
+//! Uploads must store the file as written, in one signed request and in a multipart upload beyond
+//! 64 MiB. Run with
+//!
+//!   cargo test --features remote --test remote
+//!
+//! A missing object must fail as not found without retries, a server error must fail once the
+//! retries are spent, and a multipart upload whose part is refused must be aborted.
+use ndarray::Array3;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use virtualhe::{remote, ChannelSelector, LoadOptions, SaveOptions, ZarrOptions};
+
+/// Bucket of the objects, and credentials the requests must be signed with.
+const BUCKET: &str = "slides";
+const ACCESS_KEY: &str = "AKIDEXAMPLE";
+
+/// Size of a file uploaded in parts, a part and a half of 64 MiB.
+const LARGE_SIZE: usize = 96 << 20;
+
+/// A request received by the server.
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+/// Objects and requests of the server. Objects whose key holds `flaky` fail with 503 on their
+/// first GET, `truncated` send half their body on their first GET and drop the connection,
+/// `unavailable` always fail with 503 and parts 2 of `refused` are refused with 400.
+#[derive(Default)]
+struct Store {
+    objects: HashMap<String, Vec<u8>>,
+    parts: HashMap<(String, String), Vec<u8>>,
+    /// Method, path and query of each request, and its authorization header.
+    requests: Vec<(String, String, String, Option<String>)>,
+    /// Path and range header of each range request.
+    ranges: Vec<(String, String)>,
+}
+
+/// The bytes of an object of `size` bytes.
+fn content(size: usize, seed: usize) -> Vec<u8> {
+    (0..size).map(|i| ((i * 7 + i / 251 + seed * 13) % 256) as u8).collect()
+}
+
+/// Read a request from `reader`, `None` when the connection is closed.
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+    let length = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    Some(Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+        body,
+    })
+}
+
+/// Write a response with `body`, or only the first `sent` bytes of it.
+fn respond(stream: &mut TcpStream, status: &str, headers: &[(&str, String)], body: &[u8], sent: usize) -> std::io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n", status, body.len());
+    for (name, value) in headers {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    head += "\r\n";
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&body[..sent.min(body.len())])?;
+    stream.flush()
+}
+
+/// Answer the requests of a connection until it is closed, or dropped to truncate a body.
+fn serve(stream: TcpStream, store: Arc<Mutex<Store>>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+    while let Some(request) = read_request(&mut reader) {
+        let mut store = store.lock().unwrap();
+        let key = request.path.trim_start_matches('/').to_string();
+        let first = !store.requests.iter().any(|(method, path, _, _)| *method == request.method && *path == request.path);
+        let authorization = request.headers.get("authorization").cloned();
+        store.requests.push((request.method.clone(), request.path.clone(), request.query.clone(), authorization));
+        let query: HashMap<&str, &str> =
+            request.query.split('&').filter(|p| !p.is_empty()).map(|p| p.split_once('=').unwrap_or((p, ""))).collect();
+        let unavailable = b"<Error><Code>SlowDown</Code><Message>Please reduce your request rate.</Message></Error>";
+        if key.contains("unavailable") || (key.contains("flaky") && first) {
+            respond(&mut stream, "503 Service Unavailable", &[], unavailable, usize::MAX)?;
+            continue;
+        }
+        let not_found = b"<Error><Code>NoSuchKey</Code><Message>The specified key does not exist.</Message></Error>";
+        match request.method.as_str() {
+            "GET" | "HEAD" => {
+                let Some(object) = store.objects.get(&key).cloned() else {
+                    respond(&mut stream, "404 Not Found", &[], not_found, usize::MAX)?;
+                    continue;
+                };
+                if let Some(range) = request.headers.get("range") {
+                    store.ranges.push((request.path.clone(), range.clone()));
+                }
+                let start = match request.headers.get("range").and_then(|range| range.strip_prefix("bytes=")) {
+                    Some(range) => range.trim_end_matches('-').parse().unwrap_or(0),
+                    None => 0,
+                };
+                let (status, body) = match start {
+                    0 => ("200 OK", object.clone()),
+                    _ => ("206 Partial Content", object[start..].to_vec()),
+                };
+                let range = format!("bytes {}-{}/{}", start, object.len() - 1, object.len());
+                let headers = if start > 0 { vec![("Content-Range", range)] } else { Vec::new() };
+                if request.method == "HEAD" {
+                    let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", object.len());
+                    stream.write_all(head.as_bytes())?;
+                } else if key.contains("truncated") && first {
+                    respond(&mut stream, status, &headers, &body, body.len() / 2)?;
+                    return Ok(());
+                } else {
+                    respond(&mut stream, status, &headers, &body, usize::MAX)?;
+                }
+            }
+            "PUT" => match (query.get("partNumber"), query.get("uploadId")) {
+                (Some(&"2"), Some(_)) if key.contains("refused") => {
+                    let refused = b"<Error><Code>InvalidPart</Code><Message>Refused.</Message></Error>";
+                    respond(&mut stream, "400 Bad Request", &[], refused, usize::MAX)?;
+                }
+                (Some(part), Some(_)) => {
+                    store.parts.insert((key, part.to_string()), request.body);
+                    respond(&mut stream, "200 OK", &[("ETag", format!("\"etag-{}\"", part))], &[], 0)?;
+                }
+                _ => {
+                    store.objects.insert(key, request.body);
+                    respond(&mut stream, "200 OK", &[("ETag", "\"etag\"".to_string())], &[], 0)?;
+                }
+            },
+            "POST" if query.contains_key("uploads") => {
+                let created = format!("<InitiateMultipartUploadResult><UploadId>upload-{}</UploadId></InitiateMultipartUploadResult>", key);
+                respond(&mut stream, "200 OK", &[], created.as_bytes(), usize::MAX)?;
+            }
+            "POST" => {
+                // The parts are joined in the order of the completion request
+                let complete = String::from_utf8_lossy(&request.body).into_owned();
+                let mut object = Vec::new();
+                for part in complete.split("<PartNumber>").skip(1) {
+                    let number = &part[..part.find('<').unwrap_or(0)];
+                    object.extend(store.parts.get(&(key.clone(), number.to_string())).cloned().unwrap_or_default());
+                }
+                store.objects.insert(key, object);
+                respond(&mut stream, "200 OK", &[], b"<CompleteMultipartUploadResult/>", usize::MAX)?;
+            }
+            "DELETE" => {
+                store.parts.retain(|(part_key, _), _| *part_key != key);
+                respond(&mut stream, "204 No Content", &[], &[], 0)?;
+            }
+            _ => respond(&mut stream, "405 Method Not Allowed", &[], &[], 0)?,
+        }
+    }
+    Ok(())
+}
+
+/// Start the server on a free port, returning its address.
+fn start(store: Arc<Mutex<Store>>) -> std::io::Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?.to_string();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let store = Arc::clone(&store);
+            std::thread::spawn(move || drop(serve(stream, store)));
+        }
+    });
+    Ok(address)
+}
+
+/// The server with its store, started by the first test, to which the S3 configuration points.
+fn server() -> &'static (String, Arc<Mutex<Store>>) {
+    static SERVER: OnceLock<(String, Arc<Mutex<Store>>)> = OnceLock::new();
+    SERVER.get_or_init(|| {
+        std::fs::create_dir_all(output_dir().join("uploads")).unwrap();
+        let store = Arc::new(Mutex::new(Store::default()));
+        let address = start(Arc::clone(&store)).expect("cannot start the server");
+        // The S3 configuration is read on the first request, and must not come from the profile
+        let missing = output_dir().join("missing");
+        std::env::set_var("AWS_ENDPOINT_URL", format!("http://{}", address));
+        std::env::set_var("AWS_ACCESS_KEY_ID", ACCESS_KEY);
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY");
+        std::env::set_var("AWS_REGION", "us-east-1");
+        std::env::set_var("AWS_CONFIG_FILE", &missing);
+        std::env::set_var("AWS_SHARED_CREDENTIALS_FILE", &missing);
+        remote::set_retries(2);
+        (address, store)
+    })
+}
+
+/// Directory the files are written into.
+fn output_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("remote")
+}
+
+/// Store `expected` as `key`, download it from an s3:// URL and check that the file holds it.
+fn check_download(key: &str, expected: &[u8]) {
+    let (_, store) = server();
+    store.lock().unwrap().objects.insert(format!("{}/{}", BUCKET, key), expected.to_vec());
+    let path = output_dir().join(key);
+    let size = remote::download(&format!("s3://{}/{}", BUCKET, key), &path).unwrap();
+    let downloaded = std::fs::read(&path).unwrap();
+    assert_eq!(size, expected.len() as u64, "{}", key);
+    assert!(downloaded == expected, "{}: {} bytes in the file, expected {}", key, downloaded.len(), expected.len());
+}
+
+/// Check that downloading `key` fails with an error that contains `message` after `requests`
+/// GET requests.
+fn check_download_error(key: &str, message: &str, requests: usize) {
+    let (_, store) = server();
+    let path = output_dir().join(key);
+    let error = remote::download(&format!("s3://{}/{}", BUCKET, key), &path).unwrap_err();
+    let path = format!("/{}/{}", BUCKET, key);
+    let sent = store.lock().unwrap().requests.iter().filter(|(method, p, _, _)| method == "GET" && *p == path).count();
+    assert!(error.to_string().contains(message), "{}: '{}' does not contain '{}'", key, error, message);
+    assert_eq!(sent, requests, "{}: requests sent", key);
+}
+
+/// Upload a file of `size` bytes to `key` and check that the object holds it.
+fn check_upload(key: &str, size: usize) {
+    let (_, store) = server();
+    let data = content(size, 3);
+    let path = output_dir().join(key);
+    std::fs::write(&path, &data).unwrap();
+    remote::upload(&path, &format!("s3://{}/{}", BUCKET, key)).unwrap();
+    let store = store.lock().unwrap();
+    let object = store.objects.get(&format!("{}/{}", BUCKET, key)).expect("no object after the upload");
+    assert!(*object == data, "{}: the object holds {} bytes, {} were uploaded", key, object.len(), size);
+}
+
+#[test]
+fn objects_download_whole() {
+    check_download("small.bin", &content(1000, 0));
+    check_download("flaky.bin", &content(5000, 1));
+}
+
+#[test]
+fn dropped_downloads_continue_with_a_range_request() {
+    check_download("truncated.bin", &content(3 << 20, 2));
+    let resumed = format!("bytes={}-", 3 << 19);
+    let (_, store) = server();
+    let ranges = &store.lock().unwrap().ranges;
+    assert!(
+        ranges.iter().any(|(path, range)| path.ends_with("/truncated.bin") && *range == resumed),
+        "the download was not continued with the range {}: {:?}",
+        resumed,
+        ranges
+    );
+}
+
+#[test]
+fn missing_objects_fail_without_retries() {
+    check_download_error("missing.bin", "NoSuchKey", 1);
+}
+
+#[test]
+fn server_errors_fail_once_the_retries_are_spent() {
+    check_download_error("unavailable.bin", "SlowDown", 3);
+}
+
+#[test]
+fn small_files_upload_in_one_request() {
+    check_upload("uploads/small.png", 1 << 16);
+}
+
+#[test]
+fn large_files_upload_in_parts() {
+    check_upload("uploads/large.tif", LARGE_SIZE);
+}
+
+#[test]
+fn refused_parts_abort_the_upload() {
+    let (_, store) = server();
+    let key = "uploads/refused.tif";
+    let path = output_dir().join(key);
+    std::fs::write(&path, content(LARGE_SIZE, 4)).unwrap();
+    let error = remote::upload(&path, &format!("s3://{}/{}", BUCKET, key)).unwrap_err();
+    let store = store.lock().unwrap();
+    let path = format!("/{}/{}", BUCKET, key);
+    let aborted = store.requests.iter().any(|(method, p, query, _)| {
+        method == "DELETE" && *p == path && query.contains("uploadId")
+    });
+    assert!(error.to_string().contains("InvalidPart"), "{}", error);
+    assert!(aborted, "the upload was not aborted");
+}
+
+#[test]
+fn exists_tells_stored_objects_from_missing_ones() {
+    let (_, store) = server();
+    store.lock().unwrap().objects.insert(format!("{}/present.bin", BUCKET), content(10, 5));
+    assert!(remote::exists(&format!("s3://{}/present.bin", BUCKET)).unwrap());
+    assert!(!remote::exists(&format!("s3://{}/nothing.bin", BUCKET)).unwrap());
+}
+
+/// Each channel of an OME-Zarr store, put into the bucket and under `public` as `store.zarr`, must
+/// read at an s3:// and an http:// URL as the channel read from disk.
+#[test]
+fn zarr_stores_read_as_on_disk() {
+    let (address, store) = server();
+    let path = output_dir().join("store.zarr");
+    drop(std::fs::remove_dir_all(&path));
+    let rgb = Array3::from_shape_fn((40, 56, 3), |(y, x, c)| ((x * 3 + y * 5 + c * 70) % 256) as u8);
+    let options = SaveOptions {
+        zarr: Some(ZarrOptions {
+            chunk_size: 16,
+            pixel_size_um: None,
+        }),
+        ..SaveOptions::default()
+    };
+    virtualhe::save_with(rgb, &path, &options).unwrap();
+    let mut files = Vec::new();
+    let mut dirs = vec![path.clone()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let file = entry.path();
+            if file.is_dir() {
+                dirs.push(file);
+            } else {
+                files.push(file);
+            }
+        }
+    }
+    {
+        let mut store = store.lock().unwrap();
+        for file in files {
+            let key = file.strip_prefix(output_dir()).unwrap().to_string_lossy().replace('\\', "/");
+            let data = std::fs::read(&file).unwrap();
+            store.objects.insert(format!("public/{}", key), data.clone());
+            store.objects.insert(format!("{}/{}", BUCKET, key), data);
+        }
+    }
+
+    for url in [format!("http://{}/public/store.zarr", address), format!("s3://{}/store.zarr", BUCKET)] {
+        for channel in 0..3 {
+            let options = LoadOptions {
+                channel: Some(ChannelSelector::Index(channel)),
+                ..LoadOptions::default()
+            };
+            let (expected, _) = virtualhe::load_channel_with(&path, &options).unwrap();
+            let (image, _) = virtualhe::load_channel_with(&url, &options).unwrap_or_else(|e| panic!("{}", e));
+            assert!(image == expected, "{} channel {} differs from the store on disk", url, channel);
+        }
+    }
+}
+
+#[test]
+fn s3_requests_are_signed_and_http_requests_not() {
+    let (address, store) = server();
+    {
+        let mut store = store.lock().unwrap();
+        store.objects.insert(format!("{}/signed.bin", BUCKET), content(100, 6));
+        store.objects.insert("public/unsigned.bin".to_string(), content(100, 7));
+    }
+    remote::download(&format!("s3://{}/signed.bin", BUCKET), &output_dir().join("signed.bin")).unwrap();
+    remote::download(&format!("http://{}/public/unsigned.bin", address), &output_dir().join("unsigned.bin")).unwrap();
+    let store = store.lock().unwrap();
+    let scope = format!("AWS4-HMAC-SHA256 Credential={}/", ACCESS_KEY);
+    let signed = |authorization: &Option<String>| {
+        authorization.as_ref().is_some_and(|a| a.starts_with(&scope) && a.contains("/us-east-1/s3/aws4_request"))
+    };
+    let s3 = store.requests.iter().filter(|(_, path, _, _)| path.starts_with(&format!("/{}/", BUCKET)));
+    let unsigned = s3.clone().filter(|(_, _, _, authorization)| !signed(authorization)).count();
+    let public = store.requests.iter().filter(|(_, path, _, _)| path.starts_with("/public/"));
+    let signed_public = public.filter(|(_, _, _, authorization)| authorization.is_some()).count();
+    assert_eq!(unsigned, 0, "of {} S3 requests", s3.count());
+    assert_eq!(signed_public, 0, "HTTP requests signed");
+}
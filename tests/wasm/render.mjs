@@ -0,0 +1,77 @@
+// Test of the WebAssembly module of the wasm feature, run with node:
+//
+//   cargo build --release --lib --target wasm32-unknown-unknown --features wasm
+//   wasm-bindgen --target nodejs --out-dir target/wasm target/wasm32-unknown-unknown/release/virtualhe.wasm
+//   node tests/wasm/render.mjs
+//
+// Exits with 0 if all checks pass and prints the failed checks otherwise.
+import { createRequire } from "node:module";
+
+const { RenderParams, renderU8, renderU16 } = createRequire(import.meta.url)("../../target/wasm/virtualhe.js");
+
+const width = 64, height = 32;
+let failures = 0;
+
+function check(passed, what) {
+    if (!passed) {
+        console.error(`FAILED: ${what}`);
+        failures++;
+    }
+}
+
+function throws(render, what) {
+    try {
+        render();
+        check(false, what);
+    } catch (e) {
+        check(e instanceof Error && e.message.length > 0, `${what}: ${e}`);
+    }
+}
+
+const nucleus = new Uint16Array(width * height), eosin = new Uint16Array(width * height);
+for (let y = 0; y < height; y++) {
+    for (let x = 0; x < width; x++) {
+        nucleus[y * width + x] = x * 1000;
+        eosin[y * width + x] = y * 2000;
+    }
+}
+
+const params = new RenderParams();
+check(params.k === 2.5 && Math.abs(params.nucleusPercentile - 99.999) < 1e-4, "default parameters");
+check(params.betas.length === 6, "default betas");
+
+const rgba = renderU16(nucleus, eosin, width, height, params);
+check(rgba instanceof Uint8ClampedArray && rgba.length === width * height * 4, "RGBA pixels for ImageData");
+// Without nucleus nor eosin the first pixel is white, with both the last is darker in all channels
+check([0, 1, 2, 3].every((i) => rgba[i] === 255), "white opaque background");
+const last = (width * height - 1) * 4;
+check([0, 1, 2].every((i) => rgba[last + i] < 255) && rgba[last + 3] === 255, "stained opaque pixel");
+// Hematoxylin absorbs more red than eosin: the last pixel of the first row is darker in red than the
+// first of the last row
+check(rgba[(width - 1) * 4] < rgba[(height - 1) * width * 4], "hematoxylin darker in red than eosin");
+
+// A higher k darkens the stains
+params.k = 5.0;
+const darker = renderU16(nucleus, eosin, width, height, params);
+check(darker[last] < rgba[last], "higher k renders darker");
+params.k = 2.5;
+
+// 8bit channels with the same relative intensities render alike
+const nucleus8 = Uint8Array.from(nucleus, (v) => v >> 8), eosin8 = Uint8Array.from(eosin, (v) => v >> 8);
+const rgba8 = renderU8(nucleus8, eosin8, width, height, params);
+check(rgba8.every((v, i) => Math.abs(v - rgba[i]) <= 2), "8bit channels render as 16bit ones");
+
+throws(() => renderU16(nucleus, eosin.subarray(1), width, height, params), "channel of the wrong size");
+params.k = -1.0;
+throws(() => renderU16(nucleus, eosin, width, height, params), "negative k");
+params.k = 2.5;
+params.eosinPercentile = 0.0;
+throws(() => renderU16(nucleus, eosin, width, height, params), "zero percentile");
+params.eosinPercentile = 99.999;
+params.betas = [1, 2, 3];
+throws(() => renderU16(nucleus, eosin, width, height, params), "betas of the wrong length");
+
+if (failures === 0) {
+    console.log("All checks passed");
+}
+process.exit(failures === 0 ? 0 : 1);